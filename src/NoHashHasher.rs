@@ -146,6 +146,7 @@ impl<T> Default for NoHashHasher<T> {
     }
 }
 
+#[allow(clippy::non_canonical_clone_impl)]
 impl<T> Clone for NoHashHasher<T> {
     #[cfg(debug_assertions)]
     fn clone(&self) -> Self {
@@ -0,0 +1,346 @@
+// Client of the HTTP API, used by the CLI commands that inspect a running node
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use tungstenite::stream::MaybeTlsStream;
+
+use crate::api::{BlockRepr, Compaction, Hash, ManaPrice, StateUsage, Stats, SyncSummary};
+use crate::hvm;
+
+// Address of the API of a node running on this machine
+pub const DEFAULT_API_URL : &str = "http://127.0.0.1:8000";
+
+// Answer envelope of every endpoint, as built by `ok_json` and `error_json`
+#[derive(Deserialize)]
+struct Answer<T> {
+  status: String,
+  data: Option<T>,
+  error: Option<serde_json::Value>,
+}
+
+// Block info, as read back from `/blocks`
+#[derive(Debug, Deserialize)]
+pub struct BlockView {
+  pub block: BlockRepr,
+  pub hash: Hash,
+  pub height: u64,
+  pub results: Option<Vec<hvm::StatementResult>>,
+  pub payout: Option<String>,
+  pub extra_data: Option<String>,
+  pub state_hash: Option<Hash>,
+}
+
+// Node events, as JSON, read from `/events`
+pub struct EventStream {
+  socket: tungstenite::WebSocket<MaybeTlsStream<std::net::TcpStream>>,
+}
+
+impl EventStream {
+  // Waits for the next event. Fails once the node closes the connection.
+  pub fn next(&mut self) -> Result<serde_json::Value, String> {
+    loop {
+      match self.socket.read().map_err(|err| format!("Lost the event stream: {}", err))? {
+        tungstenite::Message::Text(text) => {
+          return serde_json::from_str(&text).map_err(|err| format!("Unexpected event: {}", err));
+        }
+        tungstenite::Message::Close(_) => return Err("The node closed the event stream.".to_string()),
+        _ => continue,
+      }
+    }
+  }
+}
+
+// Function info, as read back from `/functions`
+#[derive(Debug, Deserialize)]
+pub struct FuncView {
+  pub hash: Option<Hash>, // missing on older nodes
+  pub func: hvm::Func,
+}
+
+// Functions deployed with a code, as read back from `/code`
+#[derive(Debug, Deserialize)]
+pub struct CodeView {
+  pub hash: Hash,
+  pub names: Vec<String>,
+  pub func: hvm::Func,
+}
+
+// A page of functions, as read back from `/state/export`. The entries are kept as JSON.
+#[derive(Debug, Deserialize)]
+pub struct ExportPage {
+  pub tick: u128,
+  pub next: Option<String>,
+  pub entries: Vec<serde_json::Value>,
+}
+
+// A statement of the longest chain, as read back from `/statements`. The statement is kept as JSON.
+#[derive(Debug, Deserialize)]
+pub struct StatementView {
+  pub height: u64,
+  pub block: Hash,
+  pub position: usize,
+  pub hash: Hash,
+  pub statement: serde_json::Value,
+}
+
+// A statement waiting on the pool, as read back from `/pool`. The statement is kept as JSON.
+#[derive(Debug, Deserialize)]
+pub struct PoolView {
+  pub hash: Hash,
+  pub transaction: Hash,
+  pub statement: serde_json::Value,
+  pub signer: Option<String>,
+  pub status: serde_json::Value,
+  pub local: bool,
+}
+
+// Ticks a node may be behind the one it replaces before a warning is printed
+const HEIGHT_TOLERANCE : u128 = 2;
+
+// A client of one or more nodes. They're tried in order, starting from the one that last
+// answered, so scripts keep working while a node is down. Only nodes that can't be reached are
+// skipped: one that answers with an error isn't, as another would likely give the same answer, and
+// a request that was sent may have had effects.
+pub struct ApiClient {
+  urls: Vec<String>,
+  current: AtomicUsize,         // index of the node in use
+  height: Mutex<Option<u128>>,  // tick of the node in use, once known
+  http: reqwest::blocking::Client,
+}
+
+impl ApiClient {
+  pub fn new(url: &str) -> Self {
+    ApiClient::with_nodes(&[url.to_string()])
+  }
+
+  // A client of several nodes, falling over from each to the next
+  pub fn with_nodes(urls: &[String]) -> Self {
+    assert!(!urls.is_empty(), "no node to connect to");
+    ApiClient {
+      urls: urls.iter().map(|url| url.trim_end_matches('/').to_string()).collect(),
+      current: AtomicUsize::new(0),
+      height: Mutex::new(None),
+      http: reqwest::blocking::Client::new(),
+    }
+  }
+
+  // URL of the node in use
+  pub fn url(&self) -> &str {
+    &self.urls[self.current.load(Ordering::Relaxed)]
+  }
+
+  // Gets an endpoint, returning its data. Not found is `Ok(None)`.
+  pub fn get<T: DeserializeOwned>(&self, path: &str) -> Result<Option<T>, String> {
+    self.request(path, |url| self.http.get(url))
+  }
+
+  // Gets an endpoint with query parameters, which are escaped
+  pub fn get_query<T: DeserializeOwned>(&self, path: &str, query: &[(&str, String)]) -> Result<Option<T>, String> {
+    self.request(path, |url| self.http.get(url).query(query))
+  }
+
+  // Posts a body to an endpoint, returning its data
+  pub fn post<T: DeserializeOwned>(&self, path: &str, body: String) -> Result<Option<T>, String> {
+    self.request(path, |url| self.http.post(url).body(body.clone()))
+  }
+
+  fn request<T: DeserializeOwned>(
+    &self,
+    path: &str,
+    build: impl Fn(&str) -> reqwest::blocking::RequestBuilder,
+  ) -> Result<Option<T>, String> {
+    let mut index = self.current.load(Ordering::Relaxed);
+    if self.urls.len() > 1 && self.height.lock().unwrap().is_none() {
+      self.note_height(index);
+    }
+    let mut tried = 1;
+    let response = loop {
+      let url = format!("{}{}", self.urls[index], path);
+      match build(&url).send() {
+        Ok(response) => break response,
+        Err(err) if err.is_connect() && tried < self.urls.len() => {
+          let next = (index + 1) % self.urls.len();
+          eprintln!("Couldn't reach {}: {}. Trying {}.", self.urls[index], err, self.urls[next]);
+          index = next;
+          tried += 1;
+          self.current.store(index, Ordering::Relaxed);
+          self.note_height(index);
+        }
+        Err(err) => return Err(format!("Couldn't reach {}: {}", url, err)),
+      }
+    };
+    let url = format!("{}{}", self.urls[index], path);
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+      return Ok(None);
+    }
+    let text = response.text().map_err(|err| format!("Couldn't read the answer from {}: {}", url, err))?;
+    // States can nest deeper than serde_json allows by default
+    let mut json = serde_json::Deserializer::from_str(&text);
+    json.disable_recursion_limit();
+    let answer = Answer::<T>::deserialize(&mut json).map_err(|err| format!("Unexpected answer from {}: {}", url, err))?;
+    match (answer.status.as_str(), answer.error) {
+      ("ok", _) => Ok(answer.data),
+      (_, Some(error)) => Err(format!("{} failed: {}", url, error)),
+      (status, None) => Err(format!("{} failed with status `{}`.", url, status)),
+    }
+  }
+
+  // Reads the tick of a node that's starting to be used, warning if it's too far from the one of
+  // the node it replaces, as its answers may then differ. Nodes that don't answer are left for the
+  // request itself to report.
+  fn note_height(&self, index: usize) {
+    let url = format!("{}/tick", self.urls[index]);
+    let tick = self.http.get(&url).send().ok()
+      .and_then(|response| response.json::<Answer<Stats>>().ok())
+      .and_then(|answer| answer.data)
+      .map(|stats| stats.tick);
+    let Some(tick) = tick else { return };
+    let mut height = self.height.lock().unwrap();
+    if let Some(last) = *height {
+      if last.abs_diff(tick) > HEIGHT_TOLERANCE {
+        eprintln!("Warning: {} is at tick {}, and the previous node was at {}. Its answers may differ.", self.urls[index], tick, last);
+      }
+    }
+    *height = Some(tick);
+  }
+
+  // Subscribes to the node's events
+  pub fn subscribe(&self) -> Result<EventStream, String> {
+    let url = format!("{}/events", self.url());
+    let url = match url.split_once("://") {
+      Some(("https", rest)) => format!("wss://{}", rest),
+      Some((_, rest)) => format!("ws://{}", rest),
+      None => format!("ws://{}", url),
+    };
+    let (socket, _) = tungstenite::connect(&url).map_err(|err| format!("Couldn't subscribe to {}: {}", url, err))?;
+    Ok(EventStream { socket })
+  }
+
+  pub fn get_block<T: DeserializeOwned>(&self, hash: &Hash) -> Result<Option<T>, String> {
+    self.get(&format!("/blocks/{}", hash))
+  }
+
+  pub fn get_block_at<T: DeserializeOwned>(&self, height: u64) -> Result<Option<T>, String> {
+    self.get(&format!("/blocks/height/{}", height))
+  }
+
+  pub fn get_function(&self, name: &str, at: Option<u64>) -> Result<Option<FuncView>, String> {
+    let query = at.map(|height| format!("?at={}", height)).unwrap_or_default();
+    self.get(&format!("/functions/{}{}", name, query))
+  }
+
+  // Gets the functions deployed with the code of a hash, at the tip
+  pub fn get_code(&self, hash: &Hash) -> Result<Option<CodeView>, String> {
+    self.get(&format!("/code/{}", hash))
+  }
+
+  // Gets a page of the functions and their states, at the tip, starting after a name
+  pub fn export_state(&self, after: Option<&str>, limit: Option<usize>) -> Result<Option<ExportPage>, String> {
+    let mut query = vec![];
+    if let Some(after) = after {
+      query.push(format!("after={}", after));
+    }
+    if let Some(limit) = limit {
+      query.push(format!("limit={}", limit));
+    }
+    self.get(&format!("/state/export?{}", query.join("&")))
+  }
+
+  // Runs statements on top of the tip and undoes them, returning their results
+  pub fn test_code(&self, code: String) -> Result<Vec<hvm::StatementResult>, String> {
+    Ok(self.post("/code/test", code)?.unwrap_or_default())
+  }
+
+  // Adds statements to the pool, returning whether each was accepted. They're dropped if not mined
+  // by the `expires` height.
+  pub fn send_code(&self, code: String, expires: Option<u64>) -> Result<Vec<Result<(), serde_json::Value>>, String> {
+    let query = expires.map(|height| format!("?expires={}", height)).unwrap_or_default();
+    Ok(self.post(&format!("/code/send{}", query), code)?.unwrap_or_default())
+  }
+
+  // Finds the statements of the longest chain matching a filter (see `query.rs`), oldest first
+  pub fn get_statements(&self, filter: &str, limit: usize) -> Result<Option<Vec<StatementView>>, String> {
+    self.get_query("/statements", &[("filter", filter.to_string()), ("limit", limit.to_string())])
+  }
+
+  // Lists the statements waiting on the pool, only the ones a subject signed if given
+  pub fn get_pool(&self, signer: Option<&str>) -> Result<Option<Vec<PoolView>>, String> {
+    let query: Vec<(&str, String)> = signer.map(|signer| ("signer", signer.to_string())).into_iter().collect();
+    self.get_query("/pool", &query)
+  }
+
+  // Gets how far the node is from its peers' chain
+  pub fn get_sync(&self) -> Result<Option<SyncSummary>, String> {
+    self.get("/sync")
+  }
+
+  // Gets the base mana price of the next block, and how it's moving
+  pub fn get_mana_price(&self) -> Result<Option<ManaPrice>, String> {
+    self.get("/mana")
+  }
+
+  // Has the node compact its heap now
+  pub fn compact(&self) -> Result<Option<Compaction>, String> {
+    self.post("/debug/compact", String::new())
+  }
+
+  // Gets the state of a function, at the tip or right after the block at a height
+  pub fn get_state<T: DeserializeOwned>(&self, name: &str, at: Option<u64>) -> Result<Option<T>, String> {
+    let query = at.map(|height| format!("?at={}", height)).unwrap_or_default();
+    self.get(&format!("/functions/{}/state{}", name, query))
+  }
+
+  // Gets the size of the state of a function, and the size limit of saved states
+  pub fn get_state_usage(&self, name: &str) -> Result<Option<StateUsage>, String> {
+    self.get(&format!("/functions/{}/usage", name))
+  }
+}
+
+// A node that a local chain forked from, at a height. Each function is fetched from it once.
+pub struct Fork {
+  client: ApiClient,
+  height: u64,
+  fetched: Mutex<HashMap<u128, Option<hvm::UpstreamFunc>>>,
+}
+
+impl Fork {
+  // Checks that the node has a block at `height`, and returns it
+  pub fn new(url: &str, height: u64) -> Result<(Fork, BlockView), String> {
+    let client = ApiClient::new(url);
+    let block: BlockView = client.get_block_at(height)?.ok_or_else(|| format!("{} has no block at height {}.", url, height))?;
+    Ok((Fork { client, height, fetched: Mutex::new(HashMap::new()) }, block))
+  }
+
+  fn fetch(&self, name: &str) -> Result<Option<hvm::UpstreamFunc>, String> {
+    let Some(view) = self.client.get_function(name, Some(self.height))? else { return Ok(None) };
+    let state = self.client.get_state(name, Some(self.height))?;
+    Ok(Some((view.func, state)))
+  }
+}
+
+impl hvm::Upstream for Fork {
+  fn get_function(&self, name: u128) -> Option<hvm::UpstreamFunc> {
+    if let Some(fetched) = self.fetched.lock().unwrap().get(&name) {
+      return fetched.clone();
+    }
+    let name_txt = hvm::u128_to_name(name);
+    match self.fetch(&name_txt) {
+      Ok(fetched) => {
+        if fetched.is_some() {
+          eprintln!("Fetched {} from the fork.", name_txt);
+        }
+        self.fetched.lock().unwrap().insert(name, fetched.clone());
+        fetched
+      }
+      // Not remembered, so it's tried again by the next statement mentioning it
+      Err(err) => {
+        eprintln!("Couldn't fetch {} from the fork: {}", name_txt, err);
+        None
+      }
+    }
+  }
+}
@@ -0,0 +1,377 @@
+// GraphQL
+// =======
+
+// A GraphQL facade over the same data as the JSON API, built with the `graphql` feature. Queries
+// are posted to `/graphql`, and `GET /graphql` answers the schema. Fields that lead to other
+// objects (a statement's block, a block's parent, a namespace's functions) are resolved only when
+// asked for, so a client gets what it needs in one request, and the node does no more work than
+// that. Like the JSON API, reads at the tip come from the state view, and the rest from the node.
+
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+
+use async_graphql::{Context, EmptyMutation, EmptySubscription, Error, Object, Result, Schema};
+use primitive_types::U256;
+use warp::{body, path, post, Filter, Rejection, Reply};
+
+use crate::api::http::{hex_to_u256, name_to_u128_safe};
+use crate::api::{ask, BlockInfo, Hash, NodeRequest, StatementEntry};
+use crate::hvm::{self, StatementInfo, StatementResult, StatementUsage, Term};
+use crate::query::{self, StatementKind, StatementMeta};
+use crate::runtime::StateReader;
+
+// Most blocks listed by `blocks`
+const MAX_BLOCKS : usize = 100;
+
+pub type ApiSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+// What resolvers read from
+struct Api {
+  node_query_tx: SyncSender<NodeRequest>,
+  state: StateReader,
+}
+
+impl Api {
+  async fn block(&self, hash: U256) -> Option<Block> {
+    let info = ask(self.node_query_tx.clone(), |tx| NodeRequest::GetBlock { hash, tx }).await;
+    info.map(Block::new)
+  }
+}
+
+fn api<'a>(ctx: &Context<'a>) -> &'a Api {
+  ctx.data_unchecked::<Api>()
+}
+
+pub fn schema(node_query_tx: SyncSender<NodeRequest>, state: StateReader) -> ApiSchema {
+  Schema::build(QueryRoot, EmptyMutation, EmptySubscription).data(Api { node_query_tx, state }).finish()
+}
+
+pub fn routes(node_query_tx: SyncSender<NodeRequest>, state: StateReader) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+  let schema = schema(node_query_tx, state);
+  let sdl = schema.sdl();
+  let execute = post().and(path!("graphql")).and(body::json()).then(move |request: async_graphql::Request| {
+    let schema = schema.clone();
+    async move { warp::reply::json(&schema.execute(request).await) }
+  });
+  let get_schema = warp::get().and(path!("graphql")).map(move || sdl.clone());
+  execute.or(get_schema)
+}
+
+fn read_name(name: &str) -> Result<u128> {
+  name_to_u128_safe(name).ok_or_else(|| Error::new(format!("Invalid name: '{}'", name)))
+}
+
+// Queries
+// -------
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+  /// The block at the tip of the longest chain
+  async fn tip(&self, ctx: &Context<'_>) -> Option<Block> {
+    let blocks = ask(api(ctx).node_query_tx.clone(), |tx| NodeRequest::GetBlocks { range: (-1, -1), tx }).await;
+    blocks.into_iter().next().map(Block::new)
+  }
+
+  async fn block(&self, ctx: &Context<'_>, hash: String) -> Result<Option<Block>> {
+    let hash = hex_to_u256(hash.strip_prefix("0x").unwrap_or(&hash))?;
+    Ok(api(ctx).block(hash).await)
+  }
+
+  /// The block of the longest chain at a height
+  async fn block_at(&self, ctx: &Context<'_>, height: u64) -> Option<Block> {
+    let info = ask(api(ctx).node_query_tx.clone(), |tx| NodeRequest::GetBlockAt { height, tx }).await;
+    info.map(Block::new)
+  }
+
+  /// The latest blocks of the longest chain, oldest first
+  async fn blocks(&self, ctx: &Context<'_>, #[graphql(default = 10)] last: usize) -> Vec<Block> {
+    let range = (-(last.clamp(1, MAX_BLOCKS) as i64), -1);
+    let blocks = ask(api(ctx).node_query_tx.clone(), |tx| NodeRequest::GetBlocks { range, tx }).await;
+    blocks.into_iter().map(Block::new).collect()
+  }
+
+  /// Statements of the longest chain matching a filter, as in `GET /statements`
+  async fn statements(&self, ctx: &Context<'_>, filter: Option<String>, limit: Option<usize>) -> Result<Vec<ChainStatement>> {
+    let filter = filter.as_deref().map(query::parse_filter).transpose()?;
+    let limit = limit.unwrap_or(query::DEFAULT_QUERY_LIMIT).min(query::MAX_QUERY_LIMIT);
+    let entries = ask(api(ctx).node_query_tx.clone(), |tx| NodeRequest::GetStatements { filter: filter.clone(), limit, tx }).await;
+    Ok(entries.into_iter().map(ChainStatement).collect())
+  }
+
+  async fn function(&self, ctx: &Context<'_>, name: String) -> Result<Option<Function>> {
+    let name = read_name(&name)?;
+    Ok(api(ctx).state.view().get_func(name).map(|_| Function(name)))
+  }
+
+  /// Names that have a state
+  async fn functions(&self, ctx: &Context<'_>) -> Vec<Function> {
+    let mut names: Vec<u128> = api(ctx).state.view().get_functions().into_iter().collect();
+    names.sort_unstable();
+    names.into_iter().map(Function).collect()
+  }
+
+  /// A registered namespace
+  async fn name(&self, ctx: &Context<'_>, name: String) -> Result<Option<Namespace>> {
+    let name = read_name(&name)?;
+    let owner = api(ctx).state.view().get_owner(name);
+    Ok(owner.map(|owner| Namespace { name, owner }))
+  }
+
+  async fn names(&self, ctx: &Context<'_>) -> Vec<Namespace> {
+    let view = api(ctx).state.view();
+    let mut names: Vec<u128> = view.get_namespaces().into_iter().collect();
+    names.sort_unstable();
+    names.into_iter().filter_map(|name| Some(Namespace { name, owner: view.get_owner(name)? })).collect()
+  }
+}
+
+// Blocks
+// ------
+
+pub struct Block(Arc<BlockInfo>);
+
+impl Block {
+  fn new(info: BlockInfo) -> Self {
+    Block(Arc::new(info))
+  }
+}
+
+#[Object]
+impl Block {
+  async fn hash(&self) -> String {
+    self.0.hash.to_string()
+  }
+
+  async fn height(&self) -> u64 {
+    self.0.height
+  }
+
+  async fn time(&self) -> String {
+    self.0.block.time.to_string()
+  }
+
+  /// Name paid by this block, if its miner set one
+  async fn payout(&self) -> Option<&str> {
+    self.0.payout.as_deref()
+  }
+
+  /// Hex of the extra data its miner tagged this block with, if any
+  async fn extra_data(&self) -> Option<&str> {
+    self.0.extra_data.as_deref()
+  }
+
+  /// Hash of the state right after this block, if it was computed
+  async fn state_hash(&self) -> Option<String> {
+    self.0.state_hash.as_ref().map(Hash::to_string)
+  }
+
+  async fn parent(&self, ctx: &Context<'_>) -> Option<Block> {
+    api(ctx).block(self.0.block.prev.clone().into()).await
+  }
+
+  async fn statements(&self) -> Vec<ChainStatement> {
+    let statements = self.0.content.iter().enumerate().map(|(position, statement)| StatementEntry {
+      height: self.0.height,
+      block: self.0.hash.clone(),
+      position,
+      hash: U256::from_big_endian(&hvm::hash_statement(statement).0).into(),
+      statement: statement.clone(),
+    });
+    statements.map(ChainStatement).collect()
+  }
+
+  /// Results of the block's statements, in order, if the block was computed
+  async fn receipts(&self) -> Option<Vec<Receipt>> {
+    let usage = |position| self.0.usage.as_ref().and_then(|usage| usage.get(position)).copied();
+    self.0.results.as_ref().map(|results| results.iter().enumerate().map(|(i, result)| Receipt(result.clone(), usage(i))).collect())
+  }
+}
+
+// Statements
+// ----------
+
+pub struct ChainStatement(StatementEntry);
+
+#[Object(name = "Statement")]
+impl ChainStatement {
+  async fn hash(&self) -> String {
+    self.0.hash.to_string()
+  }
+
+  async fn height(&self) -> u64 {
+    self.0.height
+  }
+
+  /// Position among the block's statements
+  async fn position(&self) -> usize {
+    self.0.position
+  }
+
+  /// `fun`, `ctr`, `run` or `reg`
+  async fn kind(&self) -> &str {
+    match StatementMeta::new(&self.0.statement).kind {
+      StatementKind::Fun => "fun",
+      StatementKind::Ctr => "ctr",
+      StatementKind::Run => "run",
+      StatementKind::Reg => "reg",
+      StatementKind::Rot => "rot",
+    }
+  }
+
+  /// The name it defines or registers
+  async fn name(&self) -> Option<String> {
+    StatementMeta::new(&self.0.statement).name.map(hvm::u128_to_name)
+  }
+
+  /// Functions it defines or calls
+  async fn functions(&self) -> Vec<String> {
+    StatementMeta::new(&self.0.statement).funs.into_iter().map(hvm::u128_to_name).collect()
+  }
+
+  async fn code(&self) -> String {
+    hvm::view_statement(&self.0.statement)
+  }
+
+  async fn block(&self, ctx: &Context<'_>) -> Option<Block> {
+    api(ctx).block(self.0.block.clone().into()).await
+  }
+
+  async fn receipt(&self, ctx: &Context<'_>) -> Option<Receipt> {
+    let block = api(ctx).block(self.0.block.clone().into()).await?;
+    let result = block.0.results.as_ref()?.get(self.0.position)?.clone();
+    let usage = block.0.usage.as_ref().and_then(|usage| usage.get(self.0.position)).copied();
+    Some(Receipt(result, usage))
+  }
+}
+
+// What running a statement did, and what it took, if known
+pub struct Receipt(StatementResult, Option<StatementUsage>);
+
+impl Receipt {
+  fn run_info<T>(&self, get: impl Fn(&Term, u128, u128, i128, u128) -> T) -> Option<T> {
+    match &self.0 {
+      Ok(StatementInfo::Run { done_term, used_mana, refunded_mana, size_diff, end_size }) => {
+        Some(get(done_term, *used_mana, *refunded_mana, *size_diff, *end_size))
+      }
+      _ => None,
+    }
+  }
+}
+
+#[Object]
+impl Receipt {
+  async fn ok(&self) -> bool {
+    self.0.is_ok()
+  }
+
+  async fn error(&self) -> Option<&str> {
+    self.0.as_ref().err().map(|err| err.err.as_str())
+  }
+
+  /// The term a `run` statement ended with
+  async fn done(&self) -> Option<String> {
+    self.run_info(|done, _, _, _, _| hvm::view_term(done))
+  }
+
+  async fn used_mana(&self) -> Option<String> {
+    self.run_info(|_, used, _, _, _| used.to_string())
+  }
+
+  async fn refunded_mana(&self) -> Option<String> {
+    self.run_info(|_, _, refunded, _, _| refunded.to_string())
+  }
+
+  async fn size_diff(&self) -> Option<String> {
+    self.run_info(|_, _, _, diff, _| diff.to_string())
+  }
+
+  async fn end_size(&self) -> Option<String> {
+    self.run_info(|_, _, _, _, end| end.to_string())
+  }
+
+  /// What the statement took to run on this node, hooks included
+  async fn usage(&self) -> Option<Usage> {
+    self.1.map(Usage)
+  }
+}
+
+pub struct Usage(StatementUsage);
+
+#[Object]
+impl Usage {
+  async fn rewrites(&self) -> String {
+    self.0.rwts.to_string()
+  }
+
+  async fn mana(&self) -> String {
+    self.0.mana.to_string()
+  }
+
+  async fn size_diff(&self) -> String {
+    self.0.size.to_string()
+  }
+
+  /// Wall-clock time, in microseconds
+  async fn time_us(&self) -> u64 {
+    self.0.time.as_micros() as u64
+  }
+}
+
+// State
+// -----
+
+pub struct Function(u128);
+
+#[Object]
+impl Function {
+  async fn name(&self) -> String {
+    hvm::u128_to_name(self.0)
+  }
+
+  /// The function's rules, at the tip or right after the block at height `at`
+  async fn rules(&self, ctx: &Context<'_>, at: Option<u64>) -> Result<Option<Vec<String>>> {
+    let name = self.0;
+    let func = match at {
+      Some(at) => ask(api(ctx).node_query_tx.clone(), |tx| NodeRequest::GetFunction { name, at: Some(at), tx }).await?.map(|info| info.func),
+      None => api(ctx).state.view().get_func(name).map(|func| func.func),
+    };
+    Ok(func.map(|func| func.rules.iter().map(|rule| format!("{} = {}", hvm::view_term(&rule.lhs), hvm::view_term(&rule.rhs))).collect()))
+  }
+
+  /// The function's state, at the tip or right after the block at height `at`
+  async fn state(&self, ctx: &Context<'_>, at: Option<u64>) -> Result<Option<String>> {
+    let name = self.0;
+    let state = match at {
+      Some(at) => ask(api(ctx).node_query_tx.clone(), |tx| NodeRequest::GetState { name, at: Some(at), tx }).await?,
+      None => api(ctx).state.view().get_state(name),
+    };
+    Ok(state.as_ref().map(hvm::view_term))
+  }
+}
+
+pub struct Namespace {
+  name: u128,
+  owner: u128,
+}
+
+#[Object(name = "Name")]
+impl Namespace {
+  async fn name(&self) -> String {
+    hvm::u128_to_name(self.name)
+  }
+
+  async fn owner(&self) -> String {
+    format!("#x{:0>30x}", self.owner)
+  }
+
+  /// Functions with state under this namespace
+  async fn functions(&self, ctx: &Context<'_>) -> Vec<Function> {
+    let prefix = format!("{}.", hvm::u128_to_name(self.name));
+    let mut names: Vec<u128> = api(ctx).state.view().get_functions().into_iter().collect();
+    names.retain(|name| hvm::u128_to_name(*name).starts_with(&prefix));
+    names.sort_unstable();
+    names.into_iter().map(Function).collect()
+  }
+}
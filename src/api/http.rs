@@ -4,21 +4,41 @@
 #![warn(unused_variables)]
 #![warn(clippy::style)]
 #![allow(clippy::let_and_return)]
+use std::collections::BTreeMap;
 use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
 
 use serde_json::json;
 use tokio::net::TcpListener;
-use tokio::sync::oneshot;
+use tokio::sync::broadcast;
+use futures_util::{SinkExt, StreamExt};
 use tokio_stream::wrappers::TcpListenerStream;
 use warp::hyper::StatusCode;
 use warp::reply::{self, Reply};
 use warp::{body, path, post, Filter};
 use warp::{reject, Rejection};
 
+use crate::crypto;
 use crate::hvm;
-use crate::api::NodeRequest;
+use crate::api::{ask, openapi, v1, CodeInfo, ComposedCall, Decoded, ExportedFunc, FuncInfo, NodeEvent, NodeRequest, StateUsage, Versions};
+use crate::query;
+use crate::runtime::StateReader;
+use crate::bits;
 use crate::util::U256;
 
+// Number of heights with competing blocks listed at `/forks`
+const FORKS_LISTED : usize = 64;
+
+// Heights listed by `/blocks/blooms` at once
+const MAX_BLOOMS : u64 = 1000;
+
+// Term patterns a subscriber of `/events` can register at once
+const MAX_PATTERNS : usize = 32;
+
+// Functions exported by a page of `/state/export`, by default and at most
+const EXPORT_PAGE : usize = 256;
+const EXPORT_PAGE_MAX : usize = 4096;
+
 // Util
 // ====
 
@@ -37,6 +57,36 @@ pub fn hex_to_u256(hex: &str) -> Result<U256, String> {
   }
 }
 
+// Hexadecimal string to a 32-byte hash
+pub fn hex_to_hash(hex: &str) -> Result<[u8; 32], String> {
+  let bytes = hex::decode(hex).map_err(|_| format!("Invalid hexadecimal string: '{}'", hex))?;
+  bytes.try_into().map_err(|_| format!("Invalid hexadecimal string: {}", hex))
+}
+
+// Reads bytes written as hexadecimal (with or without `0x`) or, failing that, as base64
+pub fn decode_bytes(text: &str) -> Result<Vec<u8>, String> {
+  let text = text.trim();
+  if let Ok(bytes) = hex::decode(text.strip_prefix("0x").unwrap_or(text)) {
+    return Ok(bytes);
+  }
+  base64::decode(text).map_err(|_| "Expected hexadecimal or base64 bytes".to_string())
+}
+
+// Parses a serialized statement or block
+pub fn decode_payload(bytes: &[u8], kind: PayloadKind) -> Result<Decoded, String> {
+  let bits = crate::util::bytes_to_bitvec(bytes);
+  match kind {
+    PayloadKind::Statement => {
+      let statement = bits::deserialized_statement(&bits).ok_or("Not a serialized statement")?;
+      Ok(Decoded::statement(statement))
+    }
+    PayloadKind::Block => {
+      let block = bits::deserialized_block(&bits).ok_or("Not a serialized block")?;
+      Ok(Decoded::block(&block))
+    }
+  }
+}
+
 fn ok_json<T>(data: T) -> warp::reply::Json
 where
   T: serde::Serialize,
@@ -86,6 +136,123 @@ impl From<String> for InvalidParameter {
 
 impl reject::Reject for InvalidParameter {}
 
+// Query parameters
+// ================
+
+#[derive(Debug, serde::Deserialize)]
+struct StateQuery {
+  /// Block height to read the state or code at (defaults to the tip)
+  at: Option<u64>,
+  /// With `1`, the state is returned as code laid out over lines, instead of as a JSON term
+  pretty: Option<u8>,
+  /// Columns the state is laid out in, with `pretty` (defaults to 80)
+  width: Option<usize>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct BloomsQuery {
+  /// First height listed (defaults to the latest `MAX_BLOOMS` blocks)
+  from: Option<u64>,
+  /// Last height listed (defaults to the tip)
+  to: Option<u64>,
+  /// Only the blocks that may touch this name
+  name: Option<String>,
+  /// Only the blocks that may emit events of this constructor
+  event: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct StatementsQuery {
+  /// Filter expression, see `query.rs` (defaults to every statement)
+  filter: Option<String>,
+  /// Maximum number of statements returned
+  limit: Option<usize>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct NamesQuery {
+  /// Kind of the names listed: `ctr`, `fun` or `reg` (defaults to every kind)
+  kind: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct PoolQuery {
+  /// Subject (`#x...`) whose signed statements are listed (defaults to every statement)
+  signer: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ExportQuery {
+  /// Name the page starts after, as given by `next` on the previous page (defaults to the first)
+  after: Option<String>,
+  /// Maximum number of functions in the page
+  limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadKind {
+  #[default]
+  Statement,
+  Block,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct DecodeQuery {
+  /// What the payload is (defaults to a statement)
+  #[serde(default)]
+  kind: PayloadKind,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SendQuery {
+  /// Block height after which the statements are dropped, if not mined
+  expires: Option<u64>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ComposeBody {
+  /// Name of the function to call
+  function: String,
+  /// What it's called with: a term as code, e.g. `"{Add #1}"`, or as JSON
+  action: serde_json::Value,
+  /// Hexadecimal public key of who will sign the call
+  signer: String,
+  /// Max mana price it bids; the base price of the next block if left out
+  price: Option<u128>,
+}
+
+// Builds the unsigned statement of `POST /compose`, checking it against the function at the tip
+fn compose_call(body: ComposeBody, state: &StateReader) -> Result<ComposedCall, String> {
+  let name = name_to_u128_safe(&body.function).ok_or_else(|| format!("Invalid function name: '{}'", body.function))?;
+  let view = state.view();
+  let func = view.get_func(name).ok_or_else(|| format!("Function '{}' isn't deployed", body.function))?.func;
+  match func.rules.first().map(|rule| &rule.lhs) {
+    Some(hvm::Term::Fun { args, .. }) if args.len() == 1 => {}
+    Some(hvm::Term::Fun { args, .. }) => {
+      return Err(format!("Function '{}' takes {} arguments, not an action", body.function, args.len()));
+    }
+    _ => return Err(format!("Function '{}' has no rules", body.function)),
+  }
+  let action = match body.action {
+    serde_json::Value::String(code) => {
+      let (rest, term) = hvm::read_term(&code).map_err(|err| format!("Invalid action: {}", err.erro))?;
+      if !rest.trim().is_empty() {
+        return Err(format!("Unexpected input after the action: '{}'", rest.trim()));
+      }
+      term
+    }
+    json => serde_json::from_value(json).map_err(|err| format!("Invalid action: {}", err))?,
+  };
+  let digits = body.signer.strip_prefix("0x").unwrap_or(&body.signer);
+  let signer = hex::decode(digits).ok().and_then(|bytes| secp256k1::PublicKey::from_slice(&bytes).ok());
+  let signer = signer.ok_or_else(|| format!("Invalid public key: '{}'", body.signer))?;
+  let statement = hvm::call_statement(name, vec![action], Some(body.price.unwrap_or(view.mana_price)))?;
+  // Nodes on the default limits wouldn't take it, so it'd never be mined
+  hvm::check_statement(&statement, hvm::DEFAULT_STATEMENT_LIMITS).map_err(hvm::show_statement_rejection)?;
+  Ok(ComposedCall::new(&statement, &signer))
+}
+
 // API
 // ===
 
@@ -102,26 +269,124 @@ async fn handle_rejection(err: Rejection) -> Result<impl Reply, std::convert::In
   }
 }
 
-pub fn http_api_loop(node_query_sender: SyncSender<NodeRequest>) {
+pub fn http_api_loop(node_query_sender: SyncSender<NodeRequest>, events: broadcast::Sender<Arc<NodeEvent>>, state: StateReader) {
   let runtime = tokio::runtime::Runtime::new().unwrap();
 
   runtime.block_on(async move {
-    api_serve(node_query_sender).await;
+    api_serve(node_query_sender, events, state).await;
   });
 }
 
-async fn api_serve(node_query_sender: SyncSender<NodeRequest>) {
-  async fn ask<T>(
-    node_query_tx: SyncSender<NodeRequest>,
-    f: impl Fn(oneshot::Sender<T>) -> NodeRequest,
-  ) -> T {
-    let (tx, rx) = oneshot::channel();
-    let request = f(tx);
-    node_query_tx.send(request).unwrap();
-    let result = rx.await.expect("Node query channel closed");
-    result
+// Messages a subscriber of `/events` sends to register term patterns, and drop them by id
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Subscription {
+  Subscribe(String),
+  Unsubscribe(usize),
+}
+
+// Registers or drops a pattern of a subscriber, answering what was done. New patterns take the
+// first id that was dropped, so the ids stay under MAX_PATTERNS.
+fn subscribe(text: &str, patterns: &mut Vec<Option<hvm::Term>>) -> String {
+  let request = serde_json::from_str(text).map_err(|err| format!("Invalid message: {}", err));
+  let registered = patterns.iter().filter(|pattern| pattern.is_some()).count();
+  match request {
+    Ok(Subscription::Subscribe(_)) if registered >= MAX_PATTERNS => {
+      json!({ "event": "Error", "message": format!("At most {} patterns can be registered.", MAX_PATTERNS) })
+    }
+    Ok(Subscription::Subscribe(code)) => match query::parse_pattern(&code) {
+      Ok(pattern) => {
+        let shown = hvm::view_term(&pattern);
+        let id = patterns.iter().position(Option::is_none).unwrap_or(patterns.len());
+        if id == patterns.len() {
+          patterns.push(None);
+        }
+        patterns[id] = Some(pattern);
+        json!({ "event": "Subscribed", "id": id, "pattern": shown })
+      }
+      Err(err) => json!({ "event": "Error", "message": err }),
+    },
+    Ok(Subscription::Unsubscribe(id)) => match patterns.get_mut(id).and_then(Option::take) {
+      Some(_) => json!({ "event": "Unsubscribed", "id": id }),
+      None => json!({ "event": "Error", "message": format!("No pattern of id {}.", id) }),
+    },
+    Err(err) => json!({ "event": "Error", "message": err }),
+  }.to_string()
+}
+
+// Matches the terms a block produced against the patterns of a subscriber
+fn pattern_matches(event: &NodeEvent, patterns: &[Option<hvm::Term>]) -> Vec<String> {
+  let NodeEvent::Terms { hash, height, results, emitted } = event else { return vec![] };
+  let mut found = vec![];
+  for (id, pattern) in patterns.iter().enumerate() {
+    let Some(pattern) = pattern else { continue };
+    for (position, term) in results {
+      for (term, binds) in query::find_matches(pattern, term) {
+        found.push(json!({ "event": "Match", "id": id, "block": hash, "height": height, "source": "result", "position": position, "term": term, "binds": binds }));
+      }
+    }
+    for (emitter, term) in emitted {
+      for (term, binds) in query::find_matches(pattern, term) {
+        found.push(json!({ "event": "Match", "id": id, "block": hash, "height": height, "source": "event", "emitter": emitter, "term": term, "binds": binds }));
+      }
+    }
+  }
+  found.into_iter().map(|found| found.to_string()).collect()
+}
+
+// Forwards node events to a WebSocket subscriber, as JSON, until it leaves. The subscriber can send
+// `{"subscribe": "<pattern>"}` to be sent, as `Match` events, where the results and emitted events
+// of computed blocks match a term pattern (see `query.rs`), and `{"unsubscribe": <id>}` to stop.
+async fn send_events(socket: warp::ws::WebSocket, mut events: broadcast::Receiver<Arc<NodeEvent>>) {
+  let (mut sink, mut stream) = socket.split();
+  let mut patterns = vec![]; // by id, `None` once dropped, until taken again
+  loop {
+    let sent = tokio::select! {
+      message = stream.next() => match message {
+        Some(Ok(message)) if message.is_close() => break,
+        Some(Ok(message)) => match message.to_str() {
+          Ok(text) => vec![subscribe(text, &mut patterns)],
+          Err(_) => continue,
+        },
+        Some(Err(_)) | None => break,
+      },
+      event = events.recv() => match event {
+        Ok(event) => match &*event {
+          NodeEvent::Terms { .. } => pattern_matches(&event, &patterns),
+          _ => vec![serde_json::to_string(&*event).unwrap()],
+        },
+        // Subscribers that missed events must rebuild their view
+        Err(broadcast::error::RecvError::Lagged(missed)) => vec![json!({ "event": "Lagged", "missed": missed }).to_string()],
+        Err(broadcast::error::RecvError::Closed) => break,
+      },
+    };
+    for json in sent {
+      if sink.send(warp::ws::Message::text(json)).await.is_err() {
+        return;
+      }
+    }
   }
+}
+
+async fn api_serve(node_query_sender: SyncSender<NodeRequest>, events: broadcast::Sender<Arc<NodeEvent>>, state: StateReader) {
+  let app = api_routes(node_query_sender, events, state);
+
+  let listener_v4 = TcpListener::bind("0.0.0.0:8000").await.unwrap();
+  // let listener_v6 = TcpListener::bind("[::]:8000").await.unwrap();
+  let listener = TcpListenerStream::new(listener_v4)
+    // .merge(TcpListenerStream::new(listener_v6))
+    ;
 
+  warp::serve(app).run_incoming(listener).await;
+}
+
+// The endpoints of the API: the latest ones, unprefixed, and the same ones under the prefix of each
+// version they're still compatible with (see `v1.rs`)
+pub fn api_routes(
+  node_query_sender: SyncSender<NodeRequest>,
+  events: broadcast::Sender<Arc<NodeEvent>>,
+  state: StateReader,
+) -> impl Filter<Extract = (impl Reply,), Error = std::convert::Infallible> + Clone {
   let root = warp::path::end().map(|| "UP");
 
   // TODO: macro to wrap those clones
@@ -135,6 +400,159 @@ async fn api_serve(node_query_sender: SyncSender<NodeRequest>) {
     }
   });
 
+  let query_tx = node_query_sender.clone();
+  let get_mana = path!("mana").then(move || {
+    let query_tx = query_tx.clone();
+    async move {
+      let price = ask(query_tx, |tx| NodeRequest::GetManaPrice { tx }).await;
+      ok_json(price)
+    }
+  });
+
+  let query_tx = node_query_sender.clone();
+  let get_state_hash = path!("state" / "hash").then(move || {
+    let query_tx = query_tx.clone();
+    async move {
+      let state_hash = ask(query_tx, |tx| NodeRequest::GetStateHash { tx }).await;
+      ok_json(state_hash)
+    }
+  });
+
+  // == Peers ==
+
+  let query_tx = node_query_sender.clone();
+  let get_peers = path!("peers").then(move || {
+    let query_tx = query_tx.clone();
+    async move {
+      let peers = ask(query_tx, |tx| NodeRequest::GetPeers { tx }).await;
+      ok_json(peers)
+    }
+  });
+
+  let query_tx = node_query_sender.clone();
+  let get_metrics = path!("metrics").then(move || {
+    let query_tx = query_tx.clone();
+    async move {
+      let metrics = ask(query_tx, |tx| NodeRequest::GetMetrics { tx }).await;
+      ok_json(metrics)
+    }
+  });
+
+  let query_tx = node_query_sender.clone();
+  let get_sync = path!("sync").then(move || {
+    let query_tx = query_tx.clone();
+    async move {
+      let sync = ask(query_tx, |tx| NodeRequest::GetSync { tx }).await;
+      ok_json(sync)
+    }
+  });
+
+  let query_tx = node_query_sender.clone();
+  let get_miners = path!("miners").then(move || {
+    let query_tx = query_tx.clone();
+    async move {
+      let miners = ask(query_tx, |tx| NodeRequest::GetMiners { tx }).await;
+      ok_json(miners)
+    }
+  });
+
+  let query_tx = node_query_sender.clone();
+  let get_upgrades = path!("upgrades").then(move || {
+    let query_tx = query_tx.clone();
+    async move {
+      let upgrades = ask(query_tx, |tx| NodeRequest::GetUpgrades { tx }).await;
+      ok_json(upgrades)
+    }
+  });
+
+  let query_tx = node_query_sender.clone();
+  let get_mining_stats = path!("stats" / "mining").then(move || {
+    let query_tx = query_tx.clone();
+    async move {
+      let stats = ask(query_tx, |tx| NodeRequest::GetMiningStats { tx }).await;
+      ok_json(stats)
+    }
+  });
+
+  let query_tx = node_query_sender.clone();
+  let get_forks = path!("forks").then(move || {
+    let query_tx = query_tx.clone();
+    async move {
+      let forks = ask(query_tx, |tx| NodeRequest::GetForks { count: FORKS_LISTED, tx }).await;
+      ok_json(forks)
+    }
+  });
+
+  let query_tx = node_query_sender.clone();
+  let get_pool = path!("pool").and(warp::query::<PoolQuery>()).and_then(move |params: PoolQuery| {
+    let query_tx = query_tx.clone();
+    async move {
+      let signer = match params.signer.as_deref().map(|signer| crypto::Name::read(signer).ok_or(signer)).transpose() {
+        Ok(signer) => signer.map(|signer| signer.0),
+        Err(signer) => return Err(reject::custom(InvalidParameter { name: Some("signer".to_string()), message: format!("Invalid subject: '{}'", signer) })),
+      };
+      let entries = ask(query_tx, |tx| NodeRequest::GetPool { signer, tx }).await;
+      Ok(ok_json(entries))
+    }
+  });
+
+  let query_tx = node_query_sender.clone();
+  let get_pool_status = path!("pool" / String).and_then(move |hash_hex: String| {
+    let query_tx = query_tx.clone();
+    async move {
+      let hash_hex = hash_hex.strip_prefix("0x").unwrap_or(&hash_hex);
+      match hex_to_u256(hash_hex) {
+        Ok(hash) => match ask(query_tx, |tx| NodeRequest::GetPoolStatus { hash, tx }).await {
+          Some(status) => Ok(ok_json(status)),
+          None => Err(reject::not_found()),
+        },
+        Err(err) => Err(reject::custom(InvalidParameter::from(format!("Invalid transaction hash: '{}'", err)))),
+      }
+    }
+  });
+
+  // == Mining ==
+
+  let query_tx = node_query_sender.clone();
+  let get_mining = path!("mine").then(move || {
+    let query_tx = query_tx.clone();
+    async move {
+      let mining = ask(query_tx, |tx| NodeRequest::GetMining { tx }).await;
+      ok_json(mining)
+    }
+  });
+
+  let query_tx = node_query_sender.clone();
+  let mine_start = post().and(path!("mine" / "start")).then(move || {
+    let query_tx = query_tx.clone();
+    async move {
+      let mining = ask(query_tx, |tx| NodeRequest::SetMining { active: Some(true), intensity: None, tx }).await;
+      ok_json(mining)
+    }
+  });
+
+  let query_tx = node_query_sender.clone();
+  let mine_stop = post().and(path!("mine" / "stop")).then(move || {
+    let query_tx = query_tx.clone();
+    async move {
+      let mining = ask(query_tx, |tx| NodeRequest::SetMining { active: Some(false), intensity: None, tx }).await;
+      ok_json(mining)
+    }
+  });
+
+  let query_tx = node_query_sender.clone();
+  let mine_intensity = post().and(path!("mine" / "intensity" / u8)).and_then(move |intensity: u8| {
+    let query_tx = query_tx.clone();
+    async move {
+      if !(1 ..= 100).contains(&intensity) {
+        return Err(reject::custom(InvalidParameter::from("Intensity must be from 1 to 100".to_string())));
+      }
+      let mining = ask(query_tx, |tx| NodeRequest::SetMining { active: None, intensity: Some(intensity), tx }).await;
+      Ok(ok_json(mining))
+    }
+  });
+
+  let mining_router = mine_start.or(mine_stop).or(mine_intensity).or(get_mining);
 
   // == Blocks ==
 
@@ -169,17 +587,68 @@ async fn api_serve(node_query_sender: SyncSender<NodeRequest>) {
 
   let get_block_go = get_block().and(path!()).map(ok_json);
 
+  let query_tx = node_query_sender.clone();
+  let get_block_at = path!("blocks" / "height" / u64).then(move |height: u64| {
+    let query_tx = query_tx.clone();
+    async move {
+      let block = ask(query_tx, |tx| NodeRequest::GetBlockAt { height, tx }).await;
+      ok_json(block)
+    }
+  });
+
+  let query_tx = node_query_sender.clone();
+  let get_blooms = path!("blocks" / "blooms").and(warp::query::<BloomsQuery>()).and_then(move |params: BloomsQuery| {
+    let query_tx = query_tx.clone();
+    async move {
+      let read_name = |param: &str, name: Option<String>| match name.map(|name| name_to_u128_safe(&name).ok_or(name)).transpose() {
+        Ok(name) => Ok(name),
+        Err(name) => Err(reject::custom(InvalidParameter { name: Some(param.to_string()), message: format!("Invalid name: '{}'.", name) })),
+      };
+      let name = read_name("name", params.name)?;
+      let event = read_name("event", params.event)?;
+      let to = match params.to {
+        Some(to) => to,
+        None => ask(query_tx.clone(), |tx| NodeRequest::GetStateHash { tx }).await.height,
+      };
+      let from = params.from.unwrap_or_else(|| (to + 1).saturating_sub(MAX_BLOOMS));
+      if from > to || to - from >= MAX_BLOOMS {
+        let message = format!("Invalid range: expected from 1 to {} heights.", MAX_BLOOMS);
+        return Err(reject::custom(InvalidParameter { name: Some("from".to_string()), message }));
+      }
+      let blooms = ask(query_tx, |tx| NodeRequest::GetBlooms { range: (from, to), name, event, tx }).await;
+      Ok(ok_json(blooms))
+    }
+  });
+
   let blocks_router = get_blocks //
+    .or(get_block_at)
+    .or(get_blooms)
     .or(get_block_go);
 
-  // == Functions ==
+  // == Statements ==
 
   let query_tx = node_query_sender.clone();
-  let get_functions = path!("functions").then(move || {
+  let get_statements = path!("statements").and(warp::query::<StatementsQuery>()).and_then(move |params: StatementsQuery| {
     let query_tx = query_tx.clone();
     async move {
-      let functions = ask(query_tx, |tx| NodeRequest::GetFunctions { tx }).await;
-      let functions: Vec<u128> = functions.into_iter().map(|x| x as u128).collect();
+      let filter = match params.filter.as_deref().map(query::parse_filter).transpose() {
+        Ok(filter) => filter,
+        Err(err) => return Err(reject::custom(InvalidParameter { name: Some("filter".to_string()), message: err })),
+      };
+      let limit = params.limit.unwrap_or(query::DEFAULT_QUERY_LIMIT).min(query::MAX_QUERY_LIMIT);
+      let statements = ask(query_tx, |tx| NodeRequest::GetStatements { filter: filter.clone(), limit, tx }).await;
+      Ok(ok_json(statements))
+    }
+  });
+
+  // == Functions ==
+
+  // Reads at the tip are answered from the last committed state, so they don't wait for the node
+  let reader = state.clone();
+  let get_functions = path!("functions").then(move || {
+    let reader = reader.clone();
+    async move {
+      let functions: Vec<u128> = reader.view().get_functions().into_iter().collect();
       let functions = u128_names_to_strings(&functions);
       ok_json(functions)
     }
@@ -196,34 +665,148 @@ async fn api_serve(node_query_sender: SyncSender<NodeRequest>) {
     });
 
   let query_tx = node_query_sender.clone();
-  let get_function = get_function_base.and(path!()).and_then(move |name: u128| {
+  let reader = state.clone();
+  let get_function = get_function_base.and(path!()).and(warp::query::<StateQuery>()).and_then(move |name: u128, query: StateQuery| {
     let query_tx = query_tx.clone();
+    let reader = reader.clone();
     async move {
-      let function = ask(query_tx, |tx| NodeRequest::GetFunction { name, tx }).await;
-      if let Some(function) = function {
-        Ok(ok_json(function))
-      } else {
-        Err(reject::not_found())
+      // `null` if there's no such function, like the state below
+      let function = match query.at {
+        Some(at) => ask(query_tx, |tx| NodeRequest::GetFunction { name, at: Some(at), tx }).await.map_err(|err| reject::custom(InvalidParameter::from(err)))?,
+        None => reader.view().get_func(name).map(|func| FuncInfo::new(name, func.func)),
+      };
+      Ok::<_, Rejection>(ok_json(function))
+    }
+  });
+
+  let query_tx = node_query_sender.clone();
+  let reader = state.clone();
+  let get_function_state = get_function_base
+    .and(path!("state"))
+    .and(warp::query::<StateQuery>())
+    .and_then(move |name: u128, query: StateQuery| {
+      let query_tx = query_tx.clone();
+      let reader = reader.clone();
+      async move {
+        // A name without state is `null`, as a not found rejection would lose to the other routes'
+        let state = match query.at {
+          Some(at) => ask(query_tx, |tx| NodeRequest::GetState { name, at: Some(at), tx }).await.map_err(|err| reject::custom(InvalidParameter::from(err)))?,
+          None => reader.view().get_state(name),
+        };
+        if query.pretty.unwrap_or(0) != 0 {
+          let layout = hvm::Layout { width: query.width.unwrap_or(hvm::Layout::default().width), ..hvm::Layout::default() };
+          return Ok::<_, Rejection>(ok_json(state.map(|state| hvm::view_term_pretty(&state, &layout, None))));
+        }
+        Ok::<_, Rejection>(ok_json(state))
       }
+    });
+
+  // How much of the state size limit a function uses, at the tip
+  let reader = state.clone();
+  let get_function_usage = get_function_base.and(path!("usage")).and_then(move |name: u128| {
+    let reader = reader.clone();
+    async move {
+      let view = reader.view();
+      let usage = view.get_state_size(name).map(|size| StateUsage { size: size as u64, limit: view.state_limit as u64 });
+      Ok::<_, Rejection>(ok_json(usage))
     }
   });
 
+  // Names deployed on the chain, with their arities and code hashes, at the tip
   let query_tx = node_query_sender.clone();
-  let get_function_state = get_function_base.and(path!("state")).and_then(move |name: u128| {
+  let get_names = path!("names").and(warp::query::<NamesQuery>()).and_then(move |params: NamesQuery| {
     let query_tx = query_tx.clone();
     async move {
-      let state = ask(query_tx, |tx| NodeRequest::GetState { name, tx }).await;
-      if let Some(state) = state {
-        Ok(ok_json(state))
+      let kind = match params.kind.as_deref().map(str::parse::<hvm::NameKind>).transpose() {
+        Ok(kind) => kind,
+        Err(err) => return Err(reject::custom(InvalidParameter { name: Some("kind".to_string()), message: err })),
+      };
+      let names = ask(query_tx, |tx| NodeRequest::GetNames { kind, tx }).await;
+      Ok(ok_json(names))
+    }
+  });
+
+  // Arities of the constructors and functions deployed on the chain, by name, at the tip
+  let query_tx = node_query_sender.clone();
+  let get_arities = path!("arities").and_then(move || {
+    let query_tx = query_tx.clone();
+    async move {
+      let names = ask(query_tx, |tx| NodeRequest::GetNames { kind: None, tx }).await;
+      let arities: BTreeMap<String, u64> = names.into_iter().filter_map(|entry| Some((entry.name, entry.arity?))).collect();
+      Ok::<_, Rejection>(ok_json(arities))
+    }
+  });
+
+  // Functions deployed with the same code, under any name, at the tip
+  let reader = state.clone();
+  let get_code = path!("code" / String).and_then(move |hash_hex: String| {
+    let reader = reader.clone();
+    async move {
+      let hash_hex = hash_hex.strip_prefix("0x").unwrap_or(&hash_hex);
+      let hash = match hex_to_u256(hash_hex) {
+        Ok(hash) => hash,
+        Err(err) => return Err(reject::custom(InvalidParameter::from(format!("Invalid code hash: '{}'", err)))),
+      };
+      let view = reader.view();
+      let names = view.get_code_names(&hash);
+      let code = names.first().and_then(|name| view.get_func(*name)).map(|func| CodeInfo {
+        hash: hash.into(),
+        names: u128_names_to_strings(&names),
+        func: func.func,
+      });
+      Ok(ok_json(code))
+    }
+  });
+
+  // Every function, with its code hash and state, at the tip, a page at a time in name order. The
+  // page is written as each function is serialized, so big states aren't built up in memory.
+  let reader = state.clone();
+  let get_state_export = path!("state" / "export").and(warp::query::<ExportQuery>()).and_then(move |query: ExportQuery| {
+    let reader = reader.clone();
+    async move {
+      let after = match query.after {
+        Some(name) => match name_to_u128_safe(&name) {
+          Some(name) => Some(name),
+          None => return Err(reject::custom(InvalidParameter::from(format!("Invalid function name: '{}'", name)))),
+        },
+        None => None,
+      };
+      let limit = query.limit.unwrap_or(EXPORT_PAGE).clamp(1, EXPORT_PAGE_MAX);
+      let view = reader.view();
+      let mut names = view.get_function_page(after, limit + 1);
+      let next = if names.len() > limit {
+        names.truncate(limit);
+        names.last().map(|name| hvm::u128_to_name(*name))
       } else {
-        Err(reject::not_found())
-      }
+        None
+      };
+      let head = format!(r#"{{"status":"ok","data":{{"tick":{},"next":{},"entries":["#, view.tick, json!(next));
+      let entries = names.into_iter().enumerate().map(move |(i, name)| {
+        let entry = ExportedFunc {
+          name: hvm::u128_to_name(name),
+          hash: view.get_code_hash(name).unwrap_or_default().into(),
+          state: view.get_state(name),
+        };
+        format!("{}{}", if i == 0 { "" } else { "," }, serde_json::to_string(&entry).unwrap())
+      });
+      let body = std::iter::once(head).chain(entries).chain(std::iter::once("]}}".to_string()));
+      let body = futures_util::stream::iter(body.map(Ok::<_, std::convert::Infallible>));
+      let reply = warp::http::Response::builder()
+        .header("Content-Type", "application/json")
+        .body(warp::hyper::Body::wrap_stream(body))
+        .unwrap();
+      Ok(reply)
     }
   });
 
   let functions_router = get_functions //
     .or(get_function) //
-    .or(get_function_state);
+    .or(get_names) //
+    .or(get_arities) //
+    .or(get_function_state) //
+    .or(get_function_usage) //
+    .or(get_code) //
+    .or(get_state_export);
 
   // == Interact ==
   let interact_base = path!("code" / ..);
@@ -245,13 +828,14 @@ async fn api_serve(node_query_sender: SyncSender<NodeRequest>) {
   );
 
   let query_tx = node_query_sender.clone();
-  let interact_send = post().and(interact_base).and(path!("send")).and(body::bytes()).and_then(
-    move |code: warp::hyper::body::Bytes| {
+  let interact_send = post().and(interact_base).and(path!("send")).and(warp::query::<SendQuery>()).and(body::bytes()).and_then(
+    move |query: SendQuery, code: warp::hyper::body::Bytes| {
       let query_tx = query_tx.clone();
       async move {
         let code = String::from_utf8(code.to_vec());
+        let expires = query.expires.map(|height| height as u128);
         if let Ok(code) = code {
-          let res = ask(query_tx, |tx| NodeRequest::PostCode { code: code.clone(), tx }).await;
+          let res = ask(query_tx, |tx| NodeRequest::PostCode { code: code.clone(), expires, tx }).await;
           match res {
             Ok(res) => Ok(ok_json(res)),
             Err(err) => Err(reject::custom(InvalidParameter::from(err))), // TODO change this type?
@@ -277,19 +861,98 @@ async fn api_serve(node_query_sender: SyncSender<NodeRequest>) {
     }
   });
 
-  let interact_router = interact_test.or(interact_send).or(interact_run);
+  let interact_decode = post().and(path!("decode")).and(warp::query::<DecodeQuery>()).and(body::bytes()).and_then(
+    move |query: DecodeQuery, text: warp::hyper::body::Bytes| async move {
+      let text = String::from_utf8(text.to_vec()).map_err(|_| "Expected text".to_string());
+      let decoded = text.and_then(|text| decode_bytes(&text)).and_then(|bytes| decode_payload(&bytes, query.kind));
+      match decoded {
+        Ok(decoded) => Ok(ok_json(decoded)),
+        Err(err) => Err(reject::custom(InvalidParameter::from(err))),
+      }
+    },
+  );
+
+  // Builds calls for clients that can't parse code, like hardware wallets
+  let reader = state.clone();
+  let interact_compose = post().and(path!("compose")).and(body::json()).and_then(move |body: ComposeBody| {
+    let reader = reader.clone();
+    async move {
+      match compose_call(body, &reader) {
+        Ok(composed) => Ok(ok_json(composed)),
+        Err(err) => Err(reject::custom(InvalidParameter::from(err))),
+      }
+    }
+  });
+
+  let interact_router = interact_test.or(interact_send).or(interact_run).or(interact_decode).or(interact_compose);
+
+  // == Debug ==
+
+  let query_tx = node_query_sender.clone();
+  let debug_reexecute = post().and(path!("debug" / "reexecute" / String)).and_then(move |hash_hex: String| {
+    let query_tx = query_tx.clone();
+    async move {
+      let hash_hex = hash_hex.strip_prefix("0x").unwrap_or(&hash_hex);
+      match hex_to_hash(hash_hex) {
+        Ok(hash) => {
+          let reexecution = ask(query_tx, |tx| NodeRequest::Reexecute { hash: crypto::Hash(hash), tx }).await;
+          let reexecution = reexecution.map_err(|err| reject::custom(InvalidParameter::from(err)))?;
+          if let Some(reexecution) = reexecution {
+            Ok(ok_json(reexecution))
+          } else {
+            Err(reject::not_found())
+          }
+        }
+        Err(err) => {
+          Err(reject::custom(InvalidParameter::from(format!("Invalid statement hash: '{}'", err))))
+        }
+      }
+    }
+  });
+
+  let query_tx = node_query_sender.clone();
+  let debug_rewrites = path!("debug" / "rewrites" / String).and_then(move |hash_hex: String| {
+    let query_tx = query_tx.clone();
+    async move {
+      let hash_hex = hash_hex.strip_prefix("0x").unwrap_or(&hash_hex);
+      let hash = hex_to_u256(hash_hex).map_err(|err| reject::custom(InvalidParameter::from(format!("Invalid block hash: '{}'", err))))?;
+      match ask(query_tx, |tx| NodeRequest::CountRewrites { hash, tx }).await.map_err(|err| reject::custom(InvalidParameter::from(err)))? {
+        Some(stats) => Ok(warp::reply::with_header(stats.to_csv(), "Content-Type", "text/csv")),
+        None => Err(reject::not_found()),
+      }
+    }
+  });
+
+  let query_tx = node_query_sender.clone();
+  let debug_compact = post().and(path!("debug" / "compact")).then(move || {
+    let query_tx = query_tx.clone();
+    async move {
+      let compaction = ask(query_tx, |tx| NodeRequest::Compact { tx }).await;
+      ok_json(compaction)
+    }
+  });
+
+  let debug_router = debug_reexecute.or(debug_rewrites).or(debug_compact);
 
   // ==
 
-  let app = root.or(get_tick).or(blocks_router).or(functions_router).or(interact_router);
-  let app = app.recover(handle_rejection);
-  let app = app.map(|reply| warp::reply::with_header(reply, "Access-Control-Allow-Origin", "*"));
+  // == Events ==
 
-  let listener_v4 = TcpListener::bind("0.0.0.0:8000").await.unwrap();
-  // let listener_v6 = TcpListener::bind("[::]:8000").await.unwrap();
-  let listener = TcpListenerStream::new(listener_v4)
-    // .merge(TcpListenerStream::new(listener_v6))
-    ;
+  let events_ws = path!("events").and(warp::ws()).map(move |ws: warp::ws::Ws| {
+    let events = events.subscribe();
+    ws.on_upgrade(move |socket| send_events(socket, events))
+  });
 
-  warp::serve(app).run_incoming(listener).await;
+  // == Versions ==
+
+  let get_versions = path!("versions").map(|| ok_json(Versions::current()));
+  let get_openapi = path!("openapi.json").map(|| warp::reply::json(&openapi::document()));
+  let get_docs = path!("docs").map(|| warp::reply::html(openapi::SWAGGER_UI));
+
+  let latest = root.or(get_tick).or(get_mana).or(get_state_hash).or(get_peers).or(get_metrics).or(get_sync).or(get_miners).or(get_upgrades).or(get_mining_stats).or(get_forks).or(get_pool).or(get_pool_status).or(mining_router).or(blocks_router).or(get_statements).or(functions_router).or(interact_router).or(debug_router).or(events_ws).or(get_versions).or(get_openapi).or(get_docs);
+  let app = latest.clone().or(v1::routes(latest));
+  #[cfg(feature = "graphql")]
+  let app = app.or(crate::api::graphql::routes(node_query_sender.clone(), state.clone()));
+  let app = app.recover(handle_rejection);
+  app.map(|reply| warp::reply::with_header(reply, "Access-Control-Allow-Origin", "*"))
 }
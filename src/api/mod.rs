@@ -1,5 +1,10 @@
+pub mod client;
+#[cfg(feature = "graphql")]
+pub mod graphql;
 pub mod http;
+pub mod openapi;
 pub mod serialization;
+pub mod v1;
 
 use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Display};
@@ -9,6 +14,10 @@ use primitive_types::U256;
 use tokio::sync::oneshot;
 use serde::{Deserialize, Serialize};
 
+use crate::bits;
+use crate::crypto;
+use crate::net;
+use crate::query;
 use crate::node;
 use crate::hvm;
 
@@ -75,11 +84,10 @@ impl TryFrom<String> for Hash {
     let rest = value.strip_prefix("0x");
     let hex_str = rest.ok_or("Missing `0x` prefix from hash hex string.")?;
     let bytes = hex::decode(hex_str).map_err(|e| e.to_string())?;
-    if bytes.len() != 64 {
+    if bytes.len() != 32 {
       return Err("Hash hex string must be 64 hex digits long.".to_string());
     }
-    let bytes = &bytes[0..64];
-    let value = U256::from_big_endian(bytes);
+    let value = U256::from_big_endian(&bytes);
     Ok(Hash { value })
   }
 }
@@ -110,6 +118,199 @@ pub struct Stats {
   pub tick: u128,
 }
 
+#[derive(Debug, Serialize)]
+pub struct PeerInfo {
+  pub address: String,
+  pub seen_at: u128,
+  pub active: bool,
+  pub traffic: node::Traffic, // traffic exchanged with this peer
+}
+
+#[derive(Debug, Serialize)]
+pub struct Metrics {
+  pub tick: u128,
+  pub active_peers: usize,
+  pub peer_bandwidth: Option<u128>, // bandwidth cap per peer, in bytes per second
+  pub traffic: node::Traffic,       // traffic with all peers
+  pub traffic_by_kind: Vec<(String, node::Traffic)>,
+  pub forks: ForkSummary,
+  pub replay: Option<ReplaySummary>, // if replay verification is enabled
+  pub shadow: Option<ShadowSummary>, // if shadow execution is enabled
+  pub watchdog: Option<WatchdogSummary>, // if blocks have an execution budget
+  pub disk: DiskSummary,                 // free space of the data directory
+  pub precheck: PrecheckSummary,         // transactions left out of the blocks mined
+  pub sync: SyncSummary,                 // header-first sync, while the node is far behind
+  pub net: net::NetStats,            // messages queued and dropped by the networking
+  pub usage: node::UsageStats,       // what the statements of computed blocks took to run
+}
+
+#[derive(Debug, Serialize)]
+pub struct ForkSummary {
+  pub stale_blocks: u64,      // valid blocks left out of the longest chain
+  pub stale_rate: f64,        // share of the valid blocks that are stale
+  pub competing_heights: u64, // heights with more than one valid block
+  pub reorgs: u64,
+  pub deepest_reorg: u64,     // most blocks dropped from the chain by a reorg
+  pub fork_choice: &'static str, // rule picking the tip, see `ForkChoice`
+}
+
+#[derive(Debug, Serialize)]
+pub struct PrecheckSummary {
+  pub checks: u64,                        // candidate blocks run before mining them
+  pub excluded: u64,                      // transactions that failed there, and were left out
+  pub failures: Vec<PrecheckFailureInfo>, // the latest ones, newest last
+  pub preexecutions: u64,                 // runs of the pool ahead of time, see `node::PoolPreexecution`
+  pub groups: u64,                        // of independent statements, in the latest of them
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncSummary {
+  pub syncing: bool,                // whether headers of a chain ahead are being followed
+  pub headers: u64,                 // height of the best header, or of the tip when not syncing
+  pub bodies: u64,                  // height of the newest block downloaded on the way there
+  pub executed: u64,                // height of the last block run
+  pub peers_best: Option<u64>,      // highest tip gossiped by an active peer, if its height is known
+  pub blocks_per_sec: Option<f64>,  // blocks run per second, over the last minute
+  pub eta_secs: Option<u64>,        // time left to run up to the best height known, at that speed
+  pub downloaded: u64,              // blocks downloaded, waiting for their ancestors
+  pub requested: u64,               // body requests not answered yet
+}
+
+#[derive(Debug, Serialize)]
+pub struct PrecheckFailureInfo {
+  pub transaction: Hash,
+  pub height: u64, // of the candidate block
+  pub err: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReplaySummary {
+  pub checks: u64,
+  pub divergences: u64,       // replays that reached a state other than the live one
+  pub last_from: Option<u64>, // ticks replayed by the latest check
+  pub last_to: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ShadowSummary {
+  pub candidate: String,
+  pub blocks: u64,                  // blocks run on both runtimes
+  pub divergences: u64,             // blocks after which the states differed
+  pub height: Option<u64>,          // of the latest block compared
+  pub last_divergence: Option<u64>, // height of the latest divergence
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiskSummary {
+  pub free: Option<u64>, // bytes free at the last check, if they could be read
+  pub min_free: u64,     // bytes below which the node stops mining and taking blocks
+  pub low: bool,         // whether it stopped
+}
+
+#[derive(Debug, Serialize)]
+pub struct WatchdogSummary {
+  pub budget_ms: u64,
+  pub halted_block: Option<Hash>,      // block that ran past the budget, halting the node
+  pub halted_height: Option<u64>,
+  pub halted_elapsed_ms: Option<u64>,  // time it ran for before being aborted
+}
+
+#[derive(Debug, Serialize)]
+pub struct PoolInfo {
+  pub status: node::PoolStatus,
+  pub local: bool,                 // submitted through this node's API, so rebroadcast until mined
+  pub broadcasts: u64,             // times this node sent it to peers
+  pub last_broadcast: Option<u128>,
+}
+
+// A statement waiting on the pool, as listed by `GET /pool`
+#[derive(Debug, Serialize)]
+pub struct PoolEntry {
+  pub hash: Hash,        // of the statement, as in `/statements`
+  pub transaction: Hash, // of the transaction holding it, as in `/pool/<hash>`
+  pub statement: hvm::Statement,
+  pub signer: Option<String>, // subject that signed it, if signed
+  pub status: node::PoolStatus,
+  pub local: bool,
+  pub estimate: Option<node::PoolEstimate>, // what it's expected to do in the next block, if pre-executed
+}
+
+// Why a statement posted to the node wasn't put on its pool
+#[derive(Debug, Clone)]
+pub enum PostRejection {
+  Invalid(hvm::StatementRejection), // over the statement limits, so no block can hold it
+  Policy(String),                   // refused by the local policy of the node (see `policy.rs`)
+}
+
+// Events sent to subscribers of `/events`
+#[derive(Debug, Serialize)]
+#[serde(tag = "event")]
+pub enum NodeEvent {
+  // The longest chain got a new tip, and the state was computed up to it
+  Tip {
+    hash: Hash,
+    height: u64,
+    state_hash: Hash, // hash of the state right after the tip
+  },
+  // The tip moved to another branch
+  Reorg {
+    old_tip: Hash,
+    new_tip: Hash,
+    fork_height: u64,             // height of the last block both branches share
+    disconnected: Vec<Hash>,      // blocks dropped from the chain, newest first
+    connected: Vec<Hash>,         // blocks added to the chain, oldest first
+    returned: Vec<hvm::Statement>, // statements of the dropped blocks put back on the pool
+  },
+  // Replaying blocks from a snapshot reached a state other than the live one
+  ReplayDivergence {
+    from: u64,      // tick of the snapshot replayed from
+    to: u64,        // tick both states are at
+    live: Hash,     // state hash of the live runtime
+    replayed: Hash, // state hash of the replay
+  },
+  // The shadow runtime reached a state other than the live one after a block
+  ShadowDivergence {
+    candidate: String, // configuration of the shadow runtime
+    height: u64,
+    block: Hash,
+    live: Hash,        // state hash of the live runtime
+    shadow: Hash,      // state hash of the shadow runtime
+  },
+  // A block ran past the execution budget and was aborted, halting the node at its parent
+  BlockTimeout {
+    hash: Hash,
+    height: u64,
+    elapsed_ms: u64, // time it ran for before being aborted
+    budget_ms: u64,
+  },
+  // A computed block produced terms: results of `run` statements, or emitted events. Sent before
+  // the tip it leads to, if any. Subscribers of `/events` are sent the matches of their patterns
+  // instead (see `query.rs`).
+  Terms {
+    hash: Hash,
+    height: u64,
+    results: Vec<(usize, hvm::Term)>,   // by position of the statement in the block
+    emitted: Vec<(String, hvm::Term)>, // with the names that emitted them
+  },
+}
+
+// Valid blocks competing at a height
+#[derive(Debug, Serialize)]
+pub struct ForkInfo {
+  pub height: u64,
+  pub blocks: Vec<Hash>,
+  pub kept: Option<Hash>, // the one on the longest chain, if any
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManaPrice {
+  pub base_price: u128, // base mana price of the next block, the least its runs can bid
+  pub used: u128,       // mana used by the tip block
+  pub target: u128,     // mana usage per block that keeps the price stable
+  pub limit: u128,      // maximum mana per block
+  pub burned: u128,     // mana fees burned so far
+}
+
 impl Into<String> for &node::Transaction {
   fn into(self) -> String {
     hex::encode(&self.data)
@@ -118,7 +319,9 @@ impl Into<String> for &node::Transaction {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BlockRepr {
+  #[serde(with = "serialization::u128_as_string")]
   pub time: u128, // block timestamp
+  #[serde(with = "serialization::u128_as_string")]
   pub meta: u128, // block metadata
   pub prev: Hash, // previous block (32 bytes)
   pub body: Vec<String>, // block contents (1280 bytes) 
@@ -137,20 +340,248 @@ impl From<&node::Block> for BlockRepr {
   }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize)]
 pub struct BlockInfo {
   pub block: BlockRepr,
-  pub hash: U256,
+  pub hash: Hash,
   pub height: u64,
   pub content: Vec<hvm::Statement>,
   pub results: Option<Vec<hvm::StatementResult>>,
+  pub usage: Option<Vec<hvm::StatementUsage>>, // what each statement took to run, if the block was computed
+  pub payout: Option<String>, // name paid by this block, if its miner set one
+  pub extra_data: Option<String>, // hex of the extra data its miner tagged it with, if any
+  pub state_hash: Option<Hash>, // hash of the state right after this block, if it was computed
+  pub bloom: Option<query::BlockBloom>, // of the names it touched and the events it emitted, if it was computed
+}
+
+// The blooms of a block of the longest chain, as listed by `GET /blocks/blooms`
+#[derive(Debug, Serialize)]
+pub struct BloomEntry {
+  pub height: u64,
+  pub hash: Hash,
+  pub bloom: query::BlockBloom,
+}
+
+// Fingerprint of the state at the tip. Nodes that agree on it agree on the whole state.
+#[derive(Debug, Serialize)]
+pub struct StateHash {
+  pub height: u64,
+  pub block: Hash,
+  pub hash: Hash,
+}
+
+// Blocks of the longest chain paying a name
+#[derive(Debug, Serialize)]
+pub struct MinerInfo {
+  pub name: String,
+  pub blocks: u64,
+  pub last_height: u64, // height of the latest of these blocks
+}
+
+// A consensus rule change, as listed by `GET /upgrades`
+#[derive(Debug, Serialize)]
+pub struct UpgradeInfo {
+  pub feature: String,
+  pub about: String,
+  pub bit: u8,       // bit miners signal it with
+  pub height: u64,   // first block it applies to
+  pub active: bool,  // whether it applies to the next block
+  pub signals: u64,  // blocks of the window signaling it
+  pub window: u64,   // latest blocks of the chain the signals are counted over
+}
+
+// Estimated hash rate of the network, and where the difficulty is headed, as in `GET /stats/mining`
+#[derive(Debug, Serialize)]
+pub struct MiningStats {
+  pub height: u64,
+  pub difficulty: u128, // expected hashes per block, after the tip
+  pub window: u64,      // blocks the estimates are averaged over
+  pub block_time: u128, // average time between them, in milliseconds
+  pub hash_rate: u128,  // hashes per second of the whole network
+  pub retarget: Retarget,
+}
+
+// Prediction of the next difficulty change
+#[derive(Debug, Serialize)]
+pub struct Retarget {
+  pub height: u64,       // of the block the difficulty changes on
+  pub blocks_left: u64,  // until that block
+  pub period_time: u128, // predicted time the period before it takes, in milliseconds
+  pub difficulty: u128,  // predicted difficulty after it
 }
 
 #[derive(Debug)]
 pub struct FuncInfo {
+  pub hash: Hash, // of the code, the same under any name (see `hvm::hash_func`)
   pub func: hvm::Func,
 }
 
+impl FuncInfo {
+  pub fn new(name: u128, func: hvm::Func) -> FuncInfo {
+    let hash = U256::from_big_endian(&hvm::hash_func(name, &func).0).into();
+    FuncInfo { hash, func }
+  }
+}
+
+// Size of a function's state against the limit of saved states, both serialized, in bytes, as in
+// `GET /functions/{name}/usage`. A state over the limit was saved before it, and its function can
+// still save smaller ones.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateUsage {
+  pub size: u64,
+  pub limit: u64,
+}
+
+// The functions deployed with a code, as found by `GET /code/{hash}`
+#[derive(Debug, Serialize)]
+pub struct CodeInfo {
+  pub hash: Hash,
+  pub names: Vec<String>, // in name order
+  pub func: hvm::Func,    // as deployed under the first name
+}
+
+// A function with its code hash and state, as exported by `GET /state/export`
+#[derive(Debug, Serialize)]
+pub struct ExportedFunc {
+  pub name: String,
+  pub hash: Hash,
+  pub state: Option<hvm::Term>,
+}
+
+// A name deployed on the chain, as listed by `GET /names`
+#[derive(Debug, Serialize)]
+pub struct NameEntry {
+  pub name: String,
+  pub kind: &'static str,  // `ctr`, `fun` or `reg`
+  pub arity: Option<u64>,  // of functions and constructors
+  pub hash: Option<Hash>,  // of the code of functions
+}
+
+impl From<hvm::NameInfo> for NameEntry {
+  fn from(info: hvm::NameInfo) -> Self {
+    NameEntry {
+      name: hvm::u128_to_name(info.name),
+      kind: info.kind.show(),
+      arity: info.arity.map(|arity| arity as u64),
+      hash: info.hash.map(Hash::from),
+    }
+  }
+}
+
+// A statement of the longest chain, as found by `GET /statements`
+#[derive(Debug, Serialize)]
+pub struct StatementEntry {
+  pub height: u64,
+  pub block: Hash,
+  pub position: usize, // among the block's statements
+  pub hash: Hash,
+  pub statement: hvm::Statement,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Compaction {
+  pub tick: u128,  // tick the heap was compacted at
+  pub moved: u128, // words moved to the start of the memory
+}
+
+#[derive(Debug, Serialize)]
+pub struct Reexecution {
+  pub block: Hash,
+  pub height: u64,
+  pub statement: hvm::Statement,
+  pub result: hvm::StatementResult,
+  pub trace: Vec<String>,
+}
+
+// A serialized statement or block, parsed back by `POST /decode`
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Decoded {
+  Statement {
+    hash: Hash,
+    statement: hvm::Statement,
+    text: String, // the statement as source code, with its signature
+  },
+  Block {
+    hash: Hash,
+    block: BlockRepr,
+    content: Vec<hvm::Statement>,
+    text: Vec<String>,
+  },
+}
+
+impl Decoded {
+  pub fn statement(statement: hvm::Statement) -> Self {
+    let hash = U256::from_big_endian(&hvm::hash_statement(&statement).0).into();
+    let text = hvm::view_statement(&statement);
+    Decoded::Statement { hash, statement, text }
+  }
+
+  pub fn block(block: &node::Block) -> Self {
+    let content: Vec<hvm::Statement> = node::extract_transactions(&block.body).iter().filter_map(node::Transaction::to_statement).collect();
+    let text = content.iter().map(hvm::view_statement).collect();
+    Decoded::Block { hash: block.hash.into(), block: block.into(), content, text }
+  }
+}
+
+// An unsigned call built by `POST /compose`, ready to be signed by its signer: the signature is
+// over `digest`, then it's posted as `hex` with the signature set
+#[derive(Debug, Serialize)]
+pub struct ComposedCall {
+  pub hex: String,      // the serialized statement, without a signature
+  pub hash: Hash,       // of the statement, as `hvm::hash_statement`
+  pub digest: Hash,     // of its sign payload, on `network`
+  pub network: u64,
+  pub signer: String,   // subject the call runs as, once signed
+  pub address: String,  // of the signer
+  pub text: String,     // the statement as source code
+}
+
+impl ComposedCall {
+  pub fn new(statement: &hvm::Statement, signer: &secp256k1::PublicKey) -> Self {
+    let bytes = bits::serialized_statement(statement).to_bytes();
+    let hash = U256::from_big_endian(&hvm::hash_statement(statement).0).into();
+    let digest = U256::from_big_endian(&hvm::sign_hash(statement).0).into();
+    ComposedCall {
+      hex: hex::encode(bytes),
+      hash,
+      digest,
+      network: hvm::NETWORK_ID,
+      signer: crypto::Name::from_public_key(signer).show(),
+      address: crypto::Address::from_public_key(signer).show(),
+      text: hvm::view_statement(statement),
+    }
+  }
+}
+
+// Versions
+// --------
+
+// Header with the version of the API that answered, on versioned endpoints (see `v1.rs`)
+pub const VERSION_HEADER : &str = "Kindelia-Api-Version";
+
+// An endpoint of a version of the API, as documented in its module
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Endpoint {
+  pub method: &'static str,
+  pub path: &'static str,     // with `<param>` segments, then `?param` names of the query
+  pub about: &'static str,
+  pub response: &'static str, // schema of the data, in the types of this module
+}
+
+// Versions of the API the node serves, as listed by `/versions`
+#[derive(Debug, Serialize)]
+pub struct Versions {
+  pub latest: u32,         // the one served without a prefix
+  pub supported: Vec<u32>, // the ones served under `/v<version>/`
+}
+
+impl Versions {
+  pub fn current() -> Self {
+    Versions { latest: v1::VERSION, supported: vec![v1::VERSION] }
+  }
+}
+
 type RequestAnswer<T> = oneshot::Sender<T>;
 
 // Node Internal API
@@ -160,24 +591,97 @@ pub enum NodeRequest {
   GetStats {
     tx: RequestAnswer<Stats>,
   },
+  GetStateHash {
+    tx: RequestAnswer<StateHash>,
+  },
+  GetManaPrice {
+    tx: RequestAnswer<ManaPrice>,
+  },
+  GetPeers {
+    tx: RequestAnswer<Vec<PeerInfo>>,
+  },
+  GetMetrics {
+    tx: RequestAnswer<Metrics>,
+  },
+  GetSync {
+    tx: RequestAnswer<SyncSummary>,
+  },
+  GetPoolStatus {
+    hash: U256,
+    tx: RequestAnswer<Option<PoolInfo>>,
+  },
+  GetPool {
+    signer: Option<u128>, // only statements signed by this subject
+    tx: RequestAnswer<Vec<PoolEntry>>,
+  },
+  GetForks {
+    count: usize,
+    tx: RequestAnswer<Vec<ForkInfo>>,
+  },
+  GetMining {
+    tx: RequestAnswer<node::Mining>,
+  },
+  SetMining {
+    active: Option<bool>,
+    intensity: Option<u8>,
+    tx: RequestAnswer<node::Mining>,
+  },
+  GetMiners {
+    tx: RequestAnswer<Vec<MinerInfo>>,
+  },
+  GetUpgrades {
+    tx: RequestAnswer<Vec<UpgradeInfo>>,
+  },
+  GetMiningStats {
+    tx: RequestAnswer<MiningStats>,
+  },
   GetBlock {
     hash: U256,
     tx: RequestAnswer<Option<BlockInfo>>,
   },
+  GetBlockAt {
+    height: u64,
+    tx: RequestAnswer<Option<BlockInfo>>,
+  },
   GetBlocks {
     range: (i64, i64),
     tx: RequestAnswer<Vec<BlockInfo>>,
   },
-  GetFunctions {
-    tx: RequestAnswer<HashSet<u128>>,
-  },
   GetFunction {
     name: u128,
-    tx: RequestAnswer<Option<FuncInfo>>,
+    at: Option<u64>,
+    tx: RequestAnswer<Result<Option<FuncInfo>, String>>, // fails for heights the node doesn't answer about
   },
   GetState {
     name: u128,
-    tx: RequestAnswer<Option<hvm::Term>>,
+    at: Option<u64>,
+    tx: RequestAnswer<Result<Option<hvm::Term>, String>>,
+  },
+  GetNames {
+    kind: Option<hvm::NameKind>,
+    tx: RequestAnswer<Vec<NameEntry>>,
+  },
+  GetBlooms {
+    range: (u64, u64),
+    name: Option<u128>,  // only the blocks that may touch this name
+    event: Option<u128>, // only the blocks that may emit this event
+    tx: RequestAnswer<Vec<BloomEntry>>,
+  },
+  GetStatements {
+    filter: Option<query::Filter>,
+    limit: usize,
+    tx: RequestAnswer<Vec<StatementEntry>>,
+  },
+  Reexecute {
+    hash: crate::crypto::Hash,
+    tx: RequestAnswer<Result<Option<Reexecution>, String>>,
+  },
+  CountRewrites {
+    hash: U256,
+    tx: RequestAnswer<Result<Option<hvm::RewriteStats>, String>>,
+  },
+  Compact {
+    tx: RequestAnswer<Compaction>,
   },
   /// deprecated
   TestCode {
@@ -187,7 +691,8 @@ pub enum NodeRequest {
   /// deprecated
   PostCode {
     code: String,
-    tx: RequestAnswer<Result<(), String>>,
+    expires: Option<u128>, // height after which the statements are evicted from the pool
+    tx: RequestAnswer<Result<Vec<Result<(), PostRejection>>, String>>,
   },
   Run {
     hex: String,
@@ -195,13 +700,13 @@ pub enum NodeRequest {
   },
 }
 
-// async fn ask<T>(
-//   node_query_tx: SyncSender<NodeRequest>,
-//   f: impl Fn(oneshot::Sender<T>) -> NodeRequest,
-// ) -> T {
-//   let (tx, rx) = oneshot::channel();
-//   let request = f(tx);
-//   node_query_tx.send(request).unwrap();
-//   let result = rx.await.expect("Node query channel closed");
-//   result
-// }
+pub async fn ask<T>(
+  node_query_tx: SyncSender<NodeRequest>,
+  f: impl Fn(oneshot::Sender<T>) -> NodeRequest,
+) -> T {
+  let (tx, rx) = oneshot::channel();
+  let request = f(tx);
+  node_query_tx.send(request).unwrap();
+  let result = rx.await.expect("Node query channel closed");
+  result
+}
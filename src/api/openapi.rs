@@ -0,0 +1,212 @@
+// OpenAPI
+// =======
+
+// The node describes its API as an OpenAPI 3 document, served at `/openapi.json`, with a Swagger UI
+// at `/docs`. The document is built from the endpoint table of the current version (see `v1.rs`),
+// so it changes along with the routes, and SDKs can be generated from it.
+//
+// Responses are described by the schemas of the table, in a small notation:
+//
+//   Stats                   a type of `api/mod.rs`, referenced as a component
+//   string, number, null    JSON values
+//   [T]                     arrays of T
+//   {a: T, b: U}            objects with these fields
+//   {<key>: T}              objects with any keys, of values T
+//   T | U                   either
+//
+// Responses that end in `, as text` aren't JSON, and are described by their text only.
+
+use serde_json::{json, Map, Value};
+
+use super::{v1, Endpoint};
+
+const OPENAPI_VERSION : &str = "3.0.3";
+
+// Swagger UI page, which loads the document from `/openapi.json`
+pub const SWAGGER_UI : &str = r##"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <title>Kindelia API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>
+"##;
+
+// Reads a schema of the endpoint table, returning it and the components it references
+pub fn read_schema(text: &str) -> Option<(Value, Vec<String>)> {
+  let mut refs = vec![];
+  let (rest, schema) = read_union(text.trim(), &mut refs)?;
+  if !rest.trim().is_empty() {
+    return None;
+  }
+  Some((schema, refs))
+}
+
+fn read_union<'a>(text: &'a str, refs: &mut Vec<String>) -> Option<(&'a str, Value)> {
+  let mut options = vec![];
+  let mut nullable = false;
+  let mut text = text;
+  loop {
+    let (rest, option) = read_single(text.trim_start(), refs)?;
+    match option {
+      None => nullable = true,
+      Some(option) => options.push(option),
+    }
+    match rest.trim_start().strip_prefix('|') {
+      Some(rest) => text = rest,
+      None => {
+        text = rest;
+        break;
+      }
+    }
+  }
+  let mut schema = match options.len() {
+    0 => json!({}),
+    1 => options.remove(0),
+    _ => json!({ "oneOf": options }),
+  };
+  if nullable {
+    // References can't take siblings, so they're wrapped
+    if schema.get("$ref").is_some() {
+      schema = json!({ "allOf": [schema] });
+    }
+    schema["nullable"] = json!(true);
+  }
+  Some((text, schema))
+}
+
+// A schema without alternatives, or `None` for `null`
+fn read_single<'a>(text: &'a str, refs: &mut Vec<String>) -> Option<(&'a str, Option<Value>)> {
+  if let Some(rest) = text.strip_prefix('[') {
+    let (rest, items) = read_union(rest, refs)?;
+    let rest = rest.trim_start().strip_prefix(']')?;
+    return Some((rest, Some(json!({ "type": "array", "items": items }))));
+  }
+  if let Some(mut rest) = text.strip_prefix('{') {
+    let mut properties = Map::new();
+    let mut values = None;
+    loop {
+      rest = rest.trim_start();
+      if let Some(after) = rest.strip_prefix('}') {
+        let schema = match values {
+          Some(values) => json!({ "type": "object", "additionalProperties": values }),
+          None => json!({ "type": "object", "properties": properties }),
+        };
+        return Some((after, Some(schema)));
+      }
+      let (after, key) = read_word(rest.strip_prefix(',').unwrap_or(rest).trim_start());
+      let (after, value) = read_union(after.trim_start().strip_prefix(':')?, refs)?;
+      if key.starts_with('<') && key.ends_with('>') {
+        values = Some(value);
+      } else if !key.is_empty() {
+        properties.insert(key.to_string(), value);
+      } else {
+        return None;
+      }
+      rest = after;
+    }
+  }
+  let (rest, word) = read_word(text);
+  let schema = match word {
+    "" => return None,
+    "null" => None,
+    "string" => Some(json!({ "type": "string" })),
+    "number" => Some(json!({ "type": "number" })),
+    name => {
+      refs.push(name.to_string());
+      Some(json!({ "$ref": format!("#/components/schemas/{}", name) }))
+    }
+  };
+  Some((rest, schema))
+}
+
+fn read_word(text: &str) -> (&str, &str) {
+  let len = text.find(|chr: char| !(chr.is_ascii_alphanumeric() || chr == '_' || chr == '<' || chr == '>')).unwrap_or(text.len());
+  (&text[len ..], &text[.. len])
+}
+
+// Parameters of a path, and the path as OpenAPI writes it: `/blocks/<hash>?at` is `/blocks/{hash}`,
+// with `hash` in the path and `at` in the query
+fn read_path(path: &str) -> (String, Vec<Value>) {
+  let (path, query) = path.split_once('?').unwrap_or((path, ""));
+  let mut params = vec![];
+  let mut segments = vec![];
+  for segment in path.split('/') {
+    match segment.strip_prefix('<').and_then(|segment| segment.strip_suffix('>')) {
+      Some(name) => {
+        let kind = if matches!(name, "height" | "percent") { "integer" } else { "string" };
+        params.push(json!({ "name": name, "in": "path", "required": true, "schema": { "type": kind } }));
+        segments.push(format!("{{{}}}", name));
+      }
+      None => segments.push(segment.to_string()),
+    }
+  }
+  for name in query.split('&').filter(|name| !name.is_empty()) {
+    params.push(json!({ "name": name, "in": "query", "required": false, "schema": { "type": "string" } }));
+  }
+  (segments.join("/"), params)
+}
+
+fn operation(endpoint: &Endpoint, params: Vec<Value>, refs: &mut Vec<String>) -> Value {
+  let response = match endpoint.response.strip_suffix(", as text") {
+    Some(text) => json!({ "description": text, "content": { "text/plain": { "schema": { "type": "string" } } } }),
+    None => match read_schema(endpoint.response) {
+      Some((data, used)) => {
+        refs.extend(used);
+        let envelope = json!({
+          "type": "object",
+          "properties": { "status": { "type": "string", "enum": ["ok"] }, "data": data },
+        });
+        json!({ "description": endpoint.response, "content": { "application/json": { "schema": envelope } } })
+      }
+      None => json!({ "description": endpoint.response }),
+    },
+  };
+  json!({
+    "summary": endpoint.about,
+    "parameters": params,
+    "responses": { "200": response, "default": { "$ref": "#/components/responses/Error" } },
+  })
+}
+
+// The OpenAPI document of the current version of the API, served under its prefix
+pub fn document() -> Value {
+  let mut paths = Map::new();
+  let mut refs = vec![];
+  for endpoint in v1::ENDPOINTS {
+    let (path, params) = read_path(endpoint.path);
+    let operation = operation(endpoint, params, &mut refs);
+    let item = paths.entry(path).or_insert_with(|| json!({}));
+    item[endpoint.method.to_lowercase()] = operation;
+  }
+  refs.sort();
+  refs.dedup();
+  let schemas: Map<String, Value> = refs.into_iter().map(|name| {
+    let schema = json!({ "description": format!("`{}`, as serialized by the node", name) });
+    (name, schema)
+  }).collect();
+  json!({
+    "openapi": OPENAPI_VERSION,
+    "info": { "title": "Kindelia node API", "version": v1::VERSION.to_string() },
+    "servers": [{ "url": format!("/v{}", v1::VERSION) }],
+    "paths": paths,
+    "components": {
+      "schemas": schemas,
+      "responses": {
+        "Error": {
+          "description": "The request failed",
+          "content": { "application/json": { "schema": {
+            "type": "object",
+            "properties": { "status": { "type": "string", "enum": ["error"] }, "error": {} },
+          } } },
+        },
+      },
+    },
+  })
+}
@@ -1,9 +1,10 @@
 use serde::ser::{SerializeStruct, SerializeStructVariant};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
-use super::{BlockInfo, FuncInfo, Stats};
-use crate::hvm::{self, u128_to_name, Func, Rule, Statement, StatementErr, StatementInfo, Term};
-use crate::node::Block;
+use super::{BlockInfo, FuncInfo, Hash, PostRejection, Stats};
+use crate::hvm::{self, u128_to_name, Func, Rule, Statement, StatementErr, StatementInfo, StatementRejection, StatementUsage, Term};
+use crate::node::{Block, Mining, PoolEstimate, PoolStatus, SlowStatement, Traffic, UsageStats};
+use crate::query::{BlockBloom, Bloom};
 use crate::util::U256;
 
 // Util
@@ -34,6 +35,20 @@ pub fn hex_to_u256(hex: &str) -> Result<U256, String> {
 // HVM
 // ===
 
+// Writes a u128 as a decimal string, since JSON numbers can't hold 128 bits
+pub mod u128_as_string {
+  use serde::{Deserialize, Deserializer, Serializer};
+
+  pub fn serialize<S: Serializer>(value: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&value.to_string())
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    super::read_json_num(&text)
+  }
+}
+
 pub fn name_to_u128_safe(name: &str) -> Option<u128> {
   if name.len() > 20 {
     None
@@ -69,22 +84,75 @@ impl Serialize for StatementInfo {
         s.serialize_field("args", &u128_names_to_strings(args))?;
         s.end()
       }
-      StatementInfo::Run { done_term, used_mana, size_diff, end_size } => {
+      StatementInfo::Run { done_term, used_mana, refunded_mana, size_diff, end_size } => {
         let code = 2;
-        let mut s = serializer.serialize_struct_variant("StatementInfo", code, "Run", 4)?;
+        let mut s = serializer.serialize_struct_variant("StatementInfo", code, "Run", 5)?;
         s.serialize_field("done_term", &done_term)?;
         s.serialize_field("used_mana", &used_mana.to_string())?;
+        s.serialize_field("refunded_mana", &refunded_mana.to_string())?;
         s.serialize_field("size_diff", &size_diff.to_string())?;
         s.serialize_field("end_size", &end_size.to_string())?;
         s.end()
       }
-      StatementInfo::Reg { .. } => {
-        panic!("TODO"); // TODO: serialize `reg`
+      StatementInfo::Reg { name, ownr } => {
+        let code = 3;
+        let mut s = serializer.serialize_struct_variant("StatementInfo", code, "Reg", 2)?;
+        s.serialize_field("name", &u128_to_name(*name))?;
+        s.serialize_field("ownr", &format!("#x{:0>30x}", ownr))?;
+        s.end()
+      }
+      StatementInfo::Rot { name, succ, tick } => {
+        let code = 4;
+        let mut s = serializer.serialize_struct_variant("StatementInfo", code, "Rot", 3)?;
+        s.serialize_field("name", &u128_to_name(*name))?;
+        s.serialize_field("succ", &format!("#x{:0>30x}", succ))?;
+        s.serialize_field("tick", &tick.to_string())?;
+        s.end()
       }
     }
   }
 }
 
+// Mirrors the JSON form of `StatementInfo`, for clients reading it back
+#[derive(Deserialize)]
+enum StatementInfoRepr {
+  Ctr { name: String, args: Vec<String> },
+  Fun { name: String, args: Vec<String> },
+  Run { done_term: Term, used_mana: String, refunded_mana: String, size_diff: String, end_size: String },
+  Reg { name: String, ownr: String },
+  Rot { name: String, succ: String, tick: String },
+}
+
+impl<'de> Deserialize<'de> for StatementInfo {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    let names = |names: Vec<String>| names.iter().map(|name| read_json_name(name)).collect::<Result<Vec<_>, _>>();
+    let info = match StatementInfoRepr::deserialize(deserializer)? {
+      StatementInfoRepr::Ctr { name, args } => StatementInfo::Ctr { name: read_json_name(&name)?, args: names(args)? },
+      StatementInfoRepr::Fun { name, args } => StatementInfo::Fun { name: read_json_name(&name)?, args: names(args)? },
+      StatementInfoRepr::Run { done_term, used_mana, refunded_mana, size_diff, end_size } => StatementInfo::Run {
+        done_term,
+        used_mana: read_json_num(&used_mana)?,
+        refunded_mana: read_json_num(&refunded_mana)?,
+        size_diff: read_json_num(&size_diff)?,
+        end_size: read_json_num(&end_size)?,
+      },
+      StatementInfoRepr::Reg { name, ownr } => {
+        let ownr = ownr.strip_prefix("#x").and_then(|hex| u128::from_str_radix(hex, 16).ok());
+        StatementInfo::Reg { name: read_json_name(&name)?, ownr: ownr.ok_or_else(|| serde::de::Error::custom("invalid owner"))? }
+      }
+      StatementInfoRepr::Rot { name, succ, tick } => {
+        let succ = succ.strip_prefix("#x").and_then(|hex| u128::from_str_radix(hex, 16).ok());
+        let succ = succ.ok_or_else(|| serde::de::Error::custom("invalid successor"))?;
+        StatementInfo::Rot { name: read_json_name(&name)?, succ, tick: read_json_num(&tick)? }
+      }
+    };
+    Ok(info)
+  }
+}
+
 impl Serialize for StatementErr {
   fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
   where
@@ -96,17 +164,208 @@ impl Serialize for StatementErr {
   }
 }
 
+impl<'de> Deserialize<'de> for StatementErr {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    #[derive(Deserialize)]
+    struct StatementErrRepr {
+      err: String,
+    }
+    let StatementErrRepr { err } = StatementErrRepr::deserialize(deserializer)?;
+    Ok(StatementErr { err })
+  }
+}
+
+impl Serialize for StatementRejection {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    match self {
+      StatementRejection::TooLarge { size, limit } => {
+        let code = 0;
+        let mut s = serializer.serialize_struct_variant("StatementRejection", code, "TooLarge", 3)?;
+        s.serialize_field("size", size)?;
+        s.serialize_field("limit", limit)?;
+        s.serialize_field("err", &hvm::show_statement_rejection(*self))?;
+        s.end()
+      }
+      StatementRejection::TooManyRules { rules, limit } => {
+        let code = 1;
+        let mut s = serializer.serialize_struct_variant("StatementRejection", code, "TooManyRules", 3)?;
+        s.serialize_field("rules", rules)?;
+        s.serialize_field("limit", limit)?;
+        s.serialize_field("err", &hvm::show_statement_rejection(*self))?;
+        s.end()
+      }
+      StatementRejection::TooManyFields { arity, limit } => {
+        let code = 2;
+        let mut s = serializer.serialize_struct_variant("StatementRejection", code, "TooManyFields", 3)?;
+        s.serialize_field("arity", arity)?;
+        s.serialize_field("limit", limit)?;
+        s.serialize_field("err", &hvm::show_statement_rejection(*self))?;
+        s.end()
+      }
+    }
+  }
+}
+
+impl Serialize for PostRejection {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    match self {
+      PostRejection::Invalid(rejection) => rejection.serialize(serializer),
+      PostRejection::Policy(err) => {
+        let code = 3;
+        let mut s = serializer.serialize_struct_variant("StatementRejection", code, "Policy", 1)?;
+        s.serialize_field("err", err)?;
+        s.end()
+      }
+    }
+  }
+}
+
 impl Serialize for FuncInfo {
   fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
   where
     S: serde::Serializer,
   {
-    let mut s = serializer.serialize_struct("FuncInfo", 1)?;
+    let mut s = serializer.serialize_struct("FuncInfo", 2)?;
+    s.serialize_field("hash", &self.hash)?;
     s.serialize_field("func", &self.func)?;
     s.end()
   }
 }
 
+impl Serialize for Bloom {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    serializer.serialize_str(&format!("0x{}", hex::encode(self.0)))
+  }
+}
+
+impl Serialize for BlockBloom {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    let mut s = serializer.serialize_struct("BlockBloom", 2)?;
+    s.serialize_field("names", &self.names)?;
+    s.serialize_field("events", &self.events)?;
+    s.end()
+  }
+}
+
+impl Serialize for Traffic {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    let mut s = serializer.serialize_struct("Traffic", 5)?;
+    s.serialize_field("sent_bytes", &self.sent_bytes)?;
+    s.serialize_field("sent_count", &self.sent_count)?;
+    s.serialize_field("recv_bytes", &self.recv_bytes)?;
+    s.serialize_field("recv_count", &self.recv_count)?;
+    s.serialize_field("dropped", &self.dropped)?;
+    s.end()
+  }
+}
+
+impl Serialize for StatementUsage {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    let mut s = serializer.serialize_struct("StatementUsage", 4)?;
+    s.serialize_field("rewrites", &self.rwts.to_string())?;
+    s.serialize_field("mana", &self.mana.to_string())?;
+    s.serialize_field("size_diff", &self.size.to_string())?;
+    s.serialize_field("time_us", &(self.time.as_micros() as u64))?;
+    s.end()
+  }
+}
+
+impl Serialize for SlowStatement {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    let mut s = serializer.serialize_struct("SlowStatement", 4)?;
+    s.serialize_field("height", &self.height)?;
+    s.serialize_field("position", &self.position)?;
+    s.serialize_field("functions", &u128_names_to_strings(&self.funs))?;
+    s.serialize_field("usage", &self.usage)?;
+    s.end()
+  }
+}
+
+impl Serialize for UsageStats {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    let mut s = serializer.serialize_struct("UsageStats", 6)?;
+    s.serialize_field("blocks", &self.blocks)?;
+    s.serialize_field("statements", &self.statements)?;
+    s.serialize_field("rewrites", &self.rwts.to_string())?;
+    s.serialize_field("mana", &self.mana.to_string())?;
+    s.serialize_field("time_us", &(self.time.as_micros() as u64))?;
+    s.serialize_field("slowest", &self.slowest)?;
+    s.end()
+  }
+}
+
+impl Serialize for Mining {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    let mut s = serializer.serialize_struct("Mining", 2)?;
+    s.serialize_field("active", &self.active)?;
+    s.serialize_field("intensity", &self.intensity)?;
+    s.end()
+  }
+}
+
+impl Serialize for PoolStatus {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    match self {
+      PoolStatus::Pending { expires } => {
+        let mut s = serializer.serialize_struct_variant("PoolStatus", 0, "Pending", 1)?;
+        s.serialize_field("expires", expires)?;
+        s.end()
+      }
+      PoolStatus::Expired { at } => {
+        let mut s = serializer.serialize_struct_variant("PoolStatus", 1, "Expired", 2)?;
+        s.serialize_field("at", at)?;
+        s.serialize_field("err", &format!("Not mined in time: dropped from the pool at height {}.", at))?;
+        s.end()
+      }
+    }
+  }
+}
+
+impl Serialize for PoolEstimate {
+  fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+  where
+    S: serde::Serializer,
+  {
+    let mut s = serializer.serialize_struct("PoolEstimate", 2)?;
+    s.serialize_field("mana", &self.mana.to_string())?;
+    s.serialize_field("ok", &self.ok)?;
+    s.end()
+  }
+}
+
 impl serde::Serialize for Block {
   fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
   where
@@ -130,12 +389,17 @@ impl serde::Serialize for Statement {
   {
     match self {
       // TODO: serialize sign
-      Statement::Fun { name, args, func, init, sign: _ } => {
-        let mut s = serializer.serialize_struct_variant("Statement", 0, "Fun", 4)?;
+      Statement::Fun { name, args, func, init, mana, strict, pure, uses, sign: _ } => {
+        let mut s = serializer.serialize_struct_variant("Statement", 0, "Fun", 8)?;
         s.serialize_field("name", &u128_to_name(*name))?;
         s.serialize_field("args", &u128_names_to_strings(args))?;
         s.serialize_field("func", func)?;
         s.serialize_field("init", init)?;
+        s.serialize_field("mana", &mana.map(|mana| mana.to_string()))?;
+        s.serialize_field("strict", strict)?;
+        s.serialize_field("pure", pure)?;
+        let uses: Vec<(String, Hash)> = uses.iter().map(|(used, code)| (u128_to_name(*used), Hash::from(*code))).collect();
+        s.serialize_field("uses", &uses)?;
         s.end()
       }
       // TODO: serialize sign
@@ -146,14 +410,26 @@ impl serde::Serialize for Statement {
         s.end()
       }
       // TODO: serialize sign
-      Statement::Run { expr, sign: _ } => {
-        let mut s = serializer.serialize_struct_variant("Statement", 2, "Run", 1)?;
+      Statement::Run { expr, price, sign: _ } => {
+        let mut s = serializer.serialize_struct_variant("Statement", 2, "Run", 2)?;
         s.serialize_field("body", expr)?;
+        s.serialize_field("price", &price.map(|price| price.to_string()))?;
+        s.end()
+      }
+      // TODO: serialize sign
+      Statement::Reg { name, ownr, sign: _ } => {
+        let mut s = serializer.serialize_struct_variant("Statement", 3, "Reg", 2)?;
+        s.serialize_field("name", &u128_to_name(*name))?;
+        s.serialize_field("ownr", &format!("#x{:0>30x}", ownr))?;
         s.end()
       }
-      // TODO: serialize
-      Statement::Reg { .. } => {
-        panic!("TODO");
+      // TODO: serialize sign
+      Statement::Rot { name, succ, delay, sign: _ } => {
+        let mut s = serializer.serialize_struct_variant("Statement", 4, "Rot", 3)?;
+        s.serialize_field("name", &u128_to_name(*name))?;
+        s.serialize_field("succ", &format!("#x{:0>30x}", succ))?;
+        s.serialize_field("delay", &delay.to_string())?;
+        s.end()
       }
     }
   }
@@ -170,6 +446,29 @@ impl serde::Serialize for Func {
   }
 }
 
+// Mirrors the JSON form of `Func`, for clients reading it back
+#[derive(Deserialize)]
+struct FuncRepr {
+  rules: Vec<RuleRepr>,
+}
+
+#[derive(Deserialize)]
+struct RuleRepr {
+  lhs: Term,
+  rhs: Term,
+}
+
+impl<'de> Deserialize<'de> for Func {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    let func = FuncRepr::deserialize(deserializer)?;
+    let rules = func.rules.into_iter().map(|rule| Rule { lhs: rule.lhs, rhs: rule.rhs }).collect();
+    Ok(Func { rules })
+  }
+}
+
 impl serde::Serialize for Rule {
   fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
   where
@@ -240,3 +539,48 @@ impl serde::Serialize for Term {
     }
   }
 }
+
+// Mirrors the JSON form of `Term`, for clients reading it back
+#[derive(Deserialize)]
+enum TermRepr {
+  Var { name: String },
+  Dup { nam0: String, nam1: String, expr: Box<Term>, body: Box<Term> },
+  Lam { name: String, body: Box<Term> },
+  App { func: Box<Term>, argm: Box<Term> },
+  Ctr { name: String, args: Vec<Term> },
+  Fun { name: String, args: Vec<Term> },
+  Num { numb: String },
+  Op2 { oper: String, val0: Box<Term>, val1: Box<Term> },
+}
+
+impl<'de> Deserialize<'de> for Term {
+  fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+  where
+    D: serde::Deserializer<'de>,
+  {
+    let term = match TermRepr::deserialize(deserializer)? {
+      TermRepr::Var { name } => Term::Var { name: read_json_name(&name)? },
+      TermRepr::Dup { nam0, nam1, expr, body } => Term::Dup { nam0: read_json_name(&nam0)?, nam1: read_json_name(&nam1)?, expr, body },
+      TermRepr::Lam { name, body } => Term::Lam { name: read_json_name(&name)?, body },
+      TermRepr::App { func, argm } => Term::App { func, argm },
+      TermRepr::Ctr { name, args } => Term::Ctr { name: read_json_name(&name)?, args },
+      TermRepr::Fun { name, args } => Term::Fun { name: read_json_name(&name)?, args },
+      TermRepr::Num { numb } => Term::Num { numb: read_json_num(&numb)? },
+      TermRepr::Op2 { oper, val0, val1 } => Term::Op2 { oper: read_json_num(&oper)?, val0, val1 },
+    };
+    Ok(term)
+  }
+}
+
+// Reads a name written by `u128_to_name`
+fn read_json_name<E: serde::de::Error>(name: &str) -> Result<u128, E> {
+  if name.len() > 20 || !name.chars().all(hvm::is_name_char) {
+    return Err(E::custom(format!("invalid name: `{}`", name)));
+  }
+  Ok(hvm::name_to_u128(name))
+}
+
+// Reads a number written as a decimal string, since JSON numbers can't hold 128 bits
+fn read_json_num<T: std::str::FromStr, E: serde::de::Error>(numb: &str) -> Result<T, E> {
+  numb.parse().map_err(|_| E::custom(format!("invalid number: `{}`", numb)))
+}
@@ -0,0 +1,83 @@
+// API Version 1
+// =============
+
+// The endpoints of the HTTP API are served under `/v1/`, e.g. `/v1/blocks/height/10`, with a
+// `Kindelia-Api-Version: 1` header. Tooling should use these, as they're kept compatible across node
+// upgrades:
+//
+// - Fields may be added to responses, and endpoints and optional query parameters may be added, so
+//   clients must ignore fields they don't know.
+// - Fields, endpoints and parameters aren't removed or renamed, and don't change type or meaning.
+// - Changes that break these rules go into a new version, with its own module next to this one.
+//   The older version keeps being served, with handlers that adapt the new responses back to its
+//   schemas, for at least one release after the new one.
+//
+// The same endpoints are served without a prefix too. Those are the latest version, whichever it
+// is, and may change on upgrades. `/versions` lists the versions the node serves.
+//
+// The responses are wrapped as `{"status": "ok", "data": ...}`, or `{"status": "error", "error":
+// ...}` on failures, and `data` has the schema listed below, in the notation of `openapi.rs`,
+// which serves them as an OpenAPI document. Types are those of `api/mod.rs`, serialized as in
+// `api/serialization.rs`.
+
+use warp::reply::Reply;
+use warp::{Filter, Rejection};
+
+use super::Endpoint;
+
+pub const VERSION : u32 = 1;
+
+// Endpoints of this version, with the schemas of their responses
+pub const ENDPOINTS : &[Endpoint] = &[
+  Endpoint { method: "GET", path: "/", about: "Liveness check", response: "\"UP\", as text" },
+  Endpoint { method: "GET", path: "/tick", about: "Stats of the node", response: "Stats" },
+  Endpoint { method: "GET", path: "/mana", about: "Base mana price of the next block, and the fees burned so far", response: "ManaPrice" },
+  Endpoint { method: "GET", path: "/state/hash", about: "Hash of the runtime state at the tip", response: "StateHash" },
+  Endpoint { method: "GET", path: "/peers", about: "Peers the node talks to", response: "[PeerInfo]" },
+  Endpoint { method: "GET", path: "/metrics", about: "Counters of the node", response: "Metrics" },
+  Endpoint { method: "GET", path: "/sync", about: "How far the node is from its peers", response: "SyncSummary" },
+  Endpoint { method: "GET", path: "/miners", about: "Payouts of the recent blocks", response: "[MinerInfo]" },
+  Endpoint { method: "GET", path: "/upgrades", about: "Consensus rule changes, and the miners signaling them", response: "[UpgradeInfo]" },
+  Endpoint { method: "GET", path: "/stats/mining", about: "Hash rate and next retarget", response: "MiningStats" },
+  Endpoint { method: "GET", path: "/forks", about: "Heights with competing blocks", response: "[ForkInfo]" },
+  Endpoint { method: "GET", path: "/pool?signer", about: "Statements waiting to be mined", response: "[PoolEntry]" },
+  Endpoint { method: "GET", path: "/pool/<hash>", about: "Status of a posted statement", response: "PoolInfo" },
+  Endpoint { method: "GET", path: "/mine", about: "Whether the node mines, and how hard", response: "Mining" },
+  Endpoint { method: "POST", path: "/mine/start", about: "Starts mining", response: "Mining" },
+  Endpoint { method: "POST", path: "/mine/stop", about: "Stops mining", response: "Mining" },
+  Endpoint { method: "POST", path: "/mine/intensity/<percent>", about: "Sets how hard the node mines", response: "Mining" },
+  Endpoint { method: "GET", path: "/blocks", about: "The latest blocks of the chain", response: "[BlockInfo]" },
+  Endpoint { method: "GET", path: "/blocks/<hash>", about: "A block", response: "BlockInfo | null" },
+  Endpoint { method: "GET", path: "/blocks/height/<height>", about: "The block of the chain at a height", response: "BlockInfo | null" },
+  Endpoint { method: "GET", path: "/blocks/blooms?from&to&name&event", about: "Blooms of the blocks that may match", response: "[BloomEntry]" },
+  Endpoint { method: "GET", path: "/statements?filter&limit", about: "Statements of the chain matching a filter", response: "[StatementEntry]" },
+  Endpoint { method: "GET", path: "/functions", about: "Names of the deployed functions", response: "[string]" },
+  Endpoint { method: "GET", path: "/functions/<name>?at", about: "A function", response: "FuncInfo | null" },
+  Endpoint { method: "GET", path: "/functions/<name>/state?at&pretty&width", about: "State of a function", response: "Term | string | null" },
+  Endpoint { method: "GET", path: "/functions/<name>/usage", about: "Size of the state of a function, and its limit", response: "StateUsage | null" },
+  Endpoint { method: "GET", path: "/names?kind", about: "Deployed names", response: "[NameEntry]" },
+  Endpoint { method: "GET", path: "/arities", about: "Arities of the deployed names", response: "{<name>: number}" },
+  Endpoint { method: "GET", path: "/code/<hash>", about: "Functions deployed with a code", response: "CodeInfo | null" },
+  Endpoint { method: "GET", path: "/state/export?after&limit", about: "A page of every function and its state", response: "{tick: number, next: string | null, entries: [ExportedFunc]}" },
+  Endpoint { method: "POST", path: "/code/test", about: "Runs statements on top of the tip, then undoes them", response: "[StatementResult]" },
+  Endpoint { method: "POST", path: "/code/send?expires", about: "Posts statements to the pool", response: "[{Ok: null} | {Err: PostRejection}]" },
+  Endpoint { method: "GET", path: "/run/<hex>", about: "Runs a serialized statement on top of the tip", response: "StatementResult" },
+  Endpoint { method: "POST", path: "/decode?kind", about: "Parses a serialized statement or block", response: "Decoded" },
+  Endpoint { method: "POST", path: "/compose", about: "Builds the unsigned statement of a call", response: "ComposedCall" },
+  Endpoint { method: "POST", path: "/debug/reexecute/<hash>", about: "Runs a block again, comparing its results", response: "Reexecution | null" },
+  Endpoint { method: "GET", path: "/debug/rewrites/<hash>", about: "Rewrites a block performs, by kind", response: "rewrite,count,share CSV, as text" },
+  Endpoint { method: "POST", path: "/debug/compact", about: "Compacts the heap now, instead of waiting for the next periodic compaction", response: "Compaction" },
+  Endpoint { method: "GET", path: "/events", about: "Node events, over a WebSocket", response: "NodeEvent messages" },
+  Endpoint { method: "GET", path: "/versions", about: "Versions of the API the node serves", response: "Versions" },
+  Endpoint { method: "GET", path: "/openapi.json", about: "This table, as an OpenAPI document", response: "OpenAPI 3 document, as text" },
+  Endpoint { method: "GET", path: "/docs", about: "Swagger UI of the OpenAPI document", response: "HTML page, as text" },
+];
+
+// Serves the latest endpoints under `/v1/`, while they're compatible with this version
+pub fn routes<F, R>(latest: F) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone
+where
+  F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+  R: Reply,
+{
+  warp::path("v1").and(latest).map(|reply| warp::reply::with_header(reply, super::VERSION_HEADER, VERSION.to_string()))
+}
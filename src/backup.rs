@@ -0,0 +1,217 @@
+// Backups
+// =======
+
+// The node can back up its data directory as the chain grows, so a broken disk or a bad upgrade
+// is undone by restoring the last backup, instead of syncing again from scratch. Backups are set
+// in the profile config (see `profile.rs`):
+//
+//   { "backup": { "every": 1000, "keep": 5, "compress": true, "level": 9, "dir": "/mnt/backups" } }
+//
+// - `every`: backs up each time the executed chain reaches a multiple of this many blocks
+// - `keep`: how many backups are kept; older ones are deleted (default 3)
+// - `compress`: compresses them with zstd (default false)
+// - `level`: zstd level they're compressed at, from 1 to 22 (default 3)
+// - `dir`: where they're written (default `backups/`, in the profile)
+//
+// A backup is a tar archive of `VERSION` and `state/` (see `datadir.rs`), named after the height it
+// was taken at, e.g. `backup-000000012000.tar.zst`. It's written to a temporary file that is
+// renamed once complete, so a crash never leaves a half-written backup behind. The node writes it
+// between two steps of its main loop, when the files agree with each other, and skips backups while
+// it's syncing.
+//
+// `kindelia node restore-backup <file>` replaces the data of the node with a backup. The node must
+// be stopped. A backup whose saved state fails the heap check (see `hvm::check_heap`) is refused,
+// and one of an older layout is migrated when the node starts.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::datadir;
+use crate::hvm;
+
+// First bytes of a zstd frame, which tell compressed backups apart
+const ZSTD_MAGIC : [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+fn default_keep() -> usize {
+  3
+}
+
+fn default_level() -> i32 {
+  hvm::HEAP_COMPRESSION_LEVEL
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackupConfig {
+  pub every: u64,
+  #[serde(default = "default_keep")]
+  pub keep: usize,
+  #[serde(default)]
+  pub compress: bool,
+  #[serde(default = "default_level")]
+  pub level: i32,
+  pub dir: Option<PathBuf>,
+}
+
+impl BackupConfig {
+  pub fn check(&self) -> Result<(), String> {
+    if self.every == 0 {
+      return Err("Invalid backup config: `every` must be at least 1.".to_string());
+    }
+    if self.keep == 0 {
+      return Err("Invalid backup config: `keep` must be at least 1.".to_string());
+    }
+    if !(1 ..= 22).contains(&self.level) {
+      return Err("Invalid backup config: `level` must be from 1 to 22.".to_string());
+    }
+    Ok(())
+  }
+}
+
+// When the node backs up, and where
+pub struct BackupSchedule {
+  pub config: BackupConfig,
+  pub dir: PathBuf,
+  pub last: Option<u128>, // height of the last backup, or of the chain when the node started
+}
+
+impl BackupSchedule {
+  // `data` is the data directory, where backups go by default
+  pub fn new(config: BackupConfig, data: &Path) -> BackupSchedule {
+    let dir = config.dir.clone().unwrap_or_else(|| data.join("backups"));
+    BackupSchedule { config, dir, last: None }
+  }
+
+  // Is a backup due at this height? It is when the chain reached a multiple of `every` since the
+  // last one. The first height seen is where the node started, which isn't backed up again.
+  pub fn due(&mut self, height: u128) -> bool {
+    let every = self.config.every as u128;
+    match self.last {
+      Some(last) if height / every > last / every => true,
+      Some(_) => false,
+      None => {
+        self.last = Some(height);
+        false
+      }
+    }
+  }
+
+  // Backs up the data directory at a height, then deletes the backups beyond `keep`
+  pub fn run(&mut self, data: &Path, height: u128) -> std::io::Result<PathBuf> {
+    self.last = Some(height);
+    let level = if self.config.compress { Some(self.config.level) } else { None };
+    let file = write_backup(data, &self.dir, height, level)?;
+    for old in list_backups(&self.dir)?.into_iter().skip(self.config.keep) {
+      std::fs::remove_file(old)?;
+    }
+    Ok(file)
+  }
+}
+
+fn is_backup_name(name: &str) -> bool {
+  name.starts_with("backup-") && (name.ends_with(".tar") || name.ends_with(".tar.zst"))
+}
+
+// Writes the archive of a data directory
+fn write_archive<W: Write>(out: W, data: &Path) -> std::io::Result<W> {
+  let mut archive = tar::Builder::new(out);
+  let version = data.join("VERSION");
+  if version.exists() {
+    archive.append_path_with_name(version, "VERSION")?;
+  }
+  archive.append_dir_all("state", data.join("state"))?;
+  archive.into_inner()
+}
+
+// Backs up a data directory into `dir`, compressed at a zstd `level`, if any, returning the file
+// written
+pub fn write_backup(data: &Path, dir: &Path, height: u128, level: Option<i32>) -> std::io::Result<PathBuf> {
+  std::fs::create_dir_all(dir)?;
+  let name = format!("backup-{:012}.tar{}", height, if level.is_some() { ".zst" } else { "" });
+  let temp = dir.join(format!(".{}.tmp", name));
+  let result = (|| {
+    let out = BufWriter::new(File::create(&temp)?);
+    let out = match level {
+      Some(level) => write_archive(zstd::Encoder::new(out, level)?, data)?.finish()?,
+      None        => write_archive(out, data)?,
+    };
+    out.into_inner().map_err(|err| err.into_error())?.sync_all()
+  })();
+  if let Err(err) = result {
+    std::fs::remove_file(&temp).ok();
+    return Err(err);
+  }
+  let file = dir.join(name);
+  std::fs::rename(&temp, &file)?;
+  Ok(file)
+}
+
+// Backups in a directory, newest first
+pub fn list_backups(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+  let mut backups = vec![];
+  for entry in std::fs::read_dir(dir)? {
+    let entry = entry?;
+    if entry.file_name().to_str().map_or(false, is_backup_name) {
+      backups.push((entry.metadata()?.modified()?, entry.path()));
+    }
+  }
+  backups.sort();
+  Ok(backups.into_iter().rev().map(|(_, path)| path).collect())
+}
+
+// Replaces the data of a node with a backup, returning how many blocks it has. The backup is
+// unpacked next to the data first, so a bad one leaves the data as it was.
+pub fn restore_backup(file: &Path, data: &Path) -> Result<usize, String> {
+  let mut input = BufReader::new(File::open(file).map_err(|err| format!("Couldn't open the backup {:?}: {}", file, err))?);
+  let mut magic = [0u8; 4];
+  let compressed = input.read_exact(&mut magic).is_ok() && magic == ZSTD_MAGIC;
+  let input = File::open(file).map_err(|err| format!("Couldn't open the backup {:?}: {}", file, err))?;
+  let temp = data.join(".restore.tmp");
+  std::fs::remove_dir_all(&temp).ok();
+  let unpacked = if compressed {
+    zstd::Decoder::new(input).and_then(|input| tar::Archive::new(input).unpack(&temp))
+  } else {
+    tar::Archive::new(input).unpack(&temp)
+  };
+  let result = unpacked.map_err(|err| format!("Invalid backup {:?}: {}", file, err)).and_then(|_| swap_in(&temp, data));
+  std::fs::remove_dir_all(&temp).ok();
+  result
+}
+
+// Moves an unpacked backup into the data directory
+fn swap_in(temp: &Path, data: &Path) -> Result<usize, String> {
+  let state = temp.join("state");
+  if !state.is_dir() {
+    return Err("Invalid backup: it has no `state` directory.".to_string());
+  }
+  let version = datadir::read_version(temp)?;
+  if version > datadir::DATA_VERSION {
+    return Err(format!("The backup is of version {}, newer than this node's ({}). Upgrade the node.", version, datadir::DATA_VERSION));
+  }
+  let blocks = std::fs::read_dir(state.join("blocks")).map(|entries| entries.count()).unwrap_or(0);
+  let heaps = state.join("heaps");
+  if heaps.join("_keeps_").exists() {
+    let faults = hvm::check_saved_state(&heaps).map_err(|err| format!("Invalid backup: its state can't be loaded: {}", err))?;
+    if let Some(fault) = faults.first() {
+      return Err(format!("Invalid backup: its state is corrupted ({} faults). {}", faults.len(), hvm::show_heap_fault(fault)));
+    }
+  }
+  let failed = |err: std::io::Error| format!("Couldn't restore the backup into {:?}: {}", data, err);
+  let old = data.join("state.old");
+  std::fs::remove_dir_all(&old).ok();
+  if data.join("state").exists() {
+    std::fs::rename(data.join("state"), &old).map_err(failed)?;
+  }
+  std::fs::rename(&state, data.join("state")).map_err(failed)?;
+  // Backups taken before the directory was versioned have no `VERSION`, and are read as version 0
+  if temp.join("VERSION").exists() {
+    std::fs::rename(temp.join("VERSION"), data.join("VERSION")).map_err(failed)?;
+  } else {
+    std::fs::remove_file(data.join("VERSION")).ok();
+  }
+  std::fs::remove_dir_all(&old).ok();
+  Ok(blocks)
+}
@@ -0,0 +1,206 @@
+// Chain Benchmark
+// ===============
+
+// `kindelia bench chain` estimates what a chain can take, to guide the choice of its parameters
+// (body size, mana and size limits). It runs a synthetic workload through the same steps a node
+// takes for each block: statements are serialized into a body, which is built into a block, run
+// on the runtime, and its state hashed. Nothing is mined, and no peers are involved.
+//
+// The workload mixes two kinds of statements, as `bank` says:
+//
+// - bank-like: signed transfers of the reference token (`example/token.kdl`) between a few
+//   accounts, deployed and funded before the measured blocks;
+// - counter-like: unsigned calls to the genesis `Count` contract.
+//
+// Statements that don't fit in a body are left out, as a miner would, so the report tells how
+// many each block actually took, and why the ones that failed did (usually the size limit).
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use rand::{Rng, SeedableRng};
+
+use crate::crypto::Account;
+use crate::hvm::{self, Statement, StatementResult, BLOCK_MANA_LIMIT};
+use crate::node::{self, NodeRng, Transaction, ZERO_HASH};
+use crate::util::bitvec_to_bytes;
+
+const TOKEN : &str = include_str!("../example/token.kdl");
+
+// Accounts sending each other tokens, and how much each starts with
+pub const BENCH_ACCOUNTS : u8 = 8;
+const BENCH_FUNDS : u128 = 1_000_000_000;
+
+#[derive(Debug, Clone)]
+pub struct ChainBench {
+  pub statements_per_block: usize,
+  pub blocks: usize,
+  pub bank: u8, // percentage of bank-like statements, the rest being counter-like
+  pub seed: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ChainReport {
+  pub blocks: usize,
+  pub requested: usize, // statements generated
+  pub included: usize,  // statements that fit in the bodies
+  pub errors: BTreeMap<String, usize>, // included statements that failed, by error
+  pub elapsed: Duration,
+  pub body_bytes: usize,
+  pub mana: Vec<u128>,  // used by each block
+  pub size_start: i128, // state size before the first block, in 64-bit words
+  pub size_end: i128,
+}
+
+impl ChainReport {
+  pub fn blocks_per_sec(&self) -> f64 {
+    self.blocks as f64 / self.elapsed.as_secs_f64()
+  }
+
+  pub fn statements_per_sec(&self) -> f64 {
+    self.included as f64 / self.elapsed.as_secs_f64()
+  }
+
+  pub fn mana_avg(&self) -> u128 {
+    self.mana.iter().sum::<u128>() / std::cmp::max(self.mana.len() as u128, 1)
+  }
+
+  pub fn mana_max(&self) -> u128 {
+    self.mana.iter().copied().max().unwrap_or(0)
+  }
+
+  pub fn size_growth(&self) -> i128 {
+    self.size_end - self.size_start
+  }
+
+  // The report, as `--output json` prints it
+  pub fn to_json(&self) -> serde_json::Value {
+    serde_json::json!({
+      "blocks": self.blocks,
+      "requested": self.requested,
+      "included": self.included,
+      "errors": self.errors,
+      "elapsed_ms": self.elapsed.as_millis(),
+      "body_bytes": self.body_bytes,
+      "blocks_per_sec": self.blocks_per_sec(),
+      "statements_per_sec": self.statements_per_sec(),
+      "mana_avg": self.mana_avg(),
+      "mana_max": self.mana_max(),
+      "size_start": self.size_start,
+      "size_end": self.size_end,
+    })
+  }
+}
+
+impl fmt::Display for ChainReport {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let blocks = std::cmp::max(self.blocks, 1);
+    let per_block = |total: usize| total as f64 / blocks as f64;
+    let of_limit = |mana: u128| 100.0 * mana as f64 / BLOCK_MANA_LIMIT as f64;
+    writeln!(f, "blocks:      {}", self.blocks)?;
+    writeln!(f, "statements:  {:.1} per block included, of {:.1} generated", per_block(self.included), per_block(self.requested))?;
+    writeln!(f, "failed:      {}", self.errors.values().sum::<usize>())?;
+    for (err, count) in &self.errors {
+      writeln!(f, "  {:>9}  {}", count, err)?;
+    }
+    writeln!(f, "body:        {:.0} bytes per block, of {}", per_block(self.body_bytes), node::MAX_BODY_SIZE)?;
+    writeln!(f, "throughput:  {:.1} blocks/s, {:.1} statements/s", self.blocks_per_sec(), self.statements_per_sec())?;
+    writeln!(f, "mana:        {} per block ({:.2}% of the limit), {} at most ({:.2}%)", self.mana_avg(), of_limit(self.mana_avg()), self.mana_max(), of_limit(self.mana_max()))?;
+    write!(f, "state:       {} words, growing {} ({:.1} per block)", self.size_end, self.size_growth(), self.size_growth() as f64 / blocks as f64)
+  }
+}
+
+pub fn bench_accounts() -> Vec<Account> {
+  (1 ..= BENCH_ACCOUNTS).map(|i| Account::from_private_key(&[i; 32])).collect()
+}
+
+// Runs the benchmark on a fresh runtime stored at `path`
+pub fn bench_chain(bench: &ChainBench, path: &PathBuf) -> Result<ChainReport, String> {
+  if bench.bank > 100 {
+    return Err(format!("Invalid share of bank statements: {}%.", bench.bank));
+  }
+  let mut rng = NodeRng::seed_from_u64(bench.seed);
+  let accounts = bench_accounts();
+  let mut rt = setup_runtime(path, &accounts)?;
+
+  let mut report = ChainReport { blocks: bench.blocks, size_start: rt.get_size(), ..ChainReport::default() };
+  let mut prev = ZERO_HASH();
+  for _ in 0 .. bench.blocks {
+    // Generated and signed outside of the measured time, as a pool would have them ready
+    let statements: Vec<Statement> = (0 .. bench.statements_per_block).map(|_| {
+      if rng.gen_range(0 .. 100) < bench.bank {
+        let from = &accounts[rng.gen_range(0 .. accounts.len())];
+        let to = &accounts[rng.gen_range(0 .. accounts.len())];
+        transfer(from, to, rng.gen_range(1 ..= 100))
+      } else {
+        count()
+      }
+    }).collect();
+    report.requested += statements.len();
+
+    let start = Instant::now();
+    let transactions: Vec<Transaction> = statements.iter().map(|statement| {
+      Transaction::new(bitvec_to_bytes(&crate::bits::serialized_statement(statement)))
+    }).collect();
+    let body = node::transactions_to_body(transactions.iter(), None);
+    let block = node::new_block(prev, (rt.get_tick() + 1) * node::TIME_PER_BLOCK, 0, body);
+    let results = node::execute_block(&mut rt, &block, true);
+    rt.tick();
+    node::get_state_hash(&rt);
+    report.elapsed += start.elapsed();
+
+    report.included += results.len();
+    for err in results.iter().filter_map(|result| result.as_ref().err()) {
+      *report.errors.entry(err.err.clone()).or_default() += 1;
+    }
+    report.body_bytes += block.body.data.len();
+    report.mana.push(node::get_results_mana(&results));
+    prev = block.hash;
+  }
+  report.size_end = rt.get_size();
+  Ok(report)
+}
+
+// Deploys the token and funds the accounts, without blocks, as its code doesn't fit in a body.
+// The first account mints all the funds, becoming the token's minter, and hands them out.
+fn setup_runtime(path: &PathBuf, accounts: &[Account]) -> Result<hvm::Runtime, String> {
+  let mut rt = hvm::init_runtime(Some(path));
+  let check = |results: Vec<StatementResult>| -> Result<(), String> {
+    match results.into_iter().find_map(Result::err) {
+      Some(err) => Err(format!("The benchmark setup failed: {}", err.err)),
+      None => Ok(()),
+    }
+  };
+  check(rt.run_statements_from_code(TOKEN, true))?;
+  rt.tick();
+  let total = BENCH_FUNDS * accounts.len() as u128;
+  let mut statements = vec![sign(&accounts[0], &format!("run {{ ask x = (Call 'Token' [{{TokenMint #{}}}]); (Done x) }}", total))];
+  statements.extend(accounts[1 ..].iter().map(|to| transfer(&accounts[0], to, BENCH_FUNDS)));
+  for statement in statements {
+    check(vec![rt.run_statement(&statement, true)])?;
+    rt.tick();
+  }
+  // Skips the blocks the setup would have taken to fit, so the measured ones start on their own
+  let setup_ticks = rt.get_size() / (hvm::BLOCK_BITS_LIMIT / 128) + 1;
+  rt.set_tick(std::cmp::max(rt.get_tick(), setup_ticks as u128));
+  Ok(rt)
+}
+
+fn read_statement(code: &str) -> Statement {
+  hvm::read_statements(code).expect("Invalid benchmark statement.").1.remove(0)
+}
+
+fn sign(account: &Account, code: &str) -> Statement {
+  let statement = read_statement(code);
+  hvm::set_sign(&statement, account.sign(&hvm::sign_hash(&statement)))
+}
+
+fn transfer(from: &Account, to: &Account, amount: u128) -> Statement {
+  sign(from, &format!("run {{ ask x = (Call 'Token' [{{TokenSend #x{:0>30x} #{}}}]); (Done x) }}", to.name.0, amount))
+}
+
+fn count() -> Statement {
+  read_statement("run { ask (Call 'Count' [{Inc}]); (Done #0) }")
+}
@@ -7,6 +7,7 @@ use crate::node::*;
 use crate::util::*;
 
 use primitive_types::U256;
+use rayon::prelude::*;
 
 type Names = HashMap<u128, u128>;
 
@@ -16,8 +17,17 @@ type Names = HashMap<u128, u128>;
 // A number with a known amount of bits
 
 pub fn serialize_fixlen(size: u128, value: &U256, bits: &mut BitVec, names: &mut Names) {
-  for i in 0 .. size {
-    bits.push((value >> i).low_u128() & 1 == 1);
+  bits.reserve(size as usize);
+  // Most numbers fit a u128, which is much cheaper to shift than a U256
+  if size <= 128 {
+    let value = value.low_u128();
+    for i in 0 .. size {
+      bits.push((value >> i) & 1 == 1);
+    }
+  } else {
+    for i in 0 .. size {
+      bits.push((value >> i).low_u128() & 1 == 1);
+    }
   }
 }
 
@@ -158,6 +168,45 @@ pub fn deserialize_list<T>(deserialize_one: impl Fn(&BitVec, &mut u128, &mut Nam
   Some(result)
 }
 
+// Many elements, serialized in parallel
+
+// Below this many values, handing them to the thread pool costs more than it saves
+const PARALLEL_MIN : usize = 32;
+
+// Serializes each value on its own names table, returning their bits in order. Large batches are
+// split among the threads of rayon's global pool, which outlives the calls, so this only suits
+// values whose encoding doesn't depend on the others.
+pub fn serialized_each<T: Sync>(serialize_one: fn(&T, &mut BitVec, &mut Names), values: &[T]) -> Vec<BitVec> {
+  return serialized_each_on(rayon::current_num_threads(), serialize_one, values);
+}
+
+// Same as `serialized_each`, but splits the values in at most `threads` batches
+pub fn serialized_each_on<T: Sync>(threads: usize, serialize_one: fn(&T, &mut BitVec, &mut Names), values: &[T]) -> Vec<BitVec> {
+  let serialize_all = |values: &[T]| {
+    values.iter().map(|x| {
+      let mut bits = BitVec::new();
+      serialize_one(x, &mut bits, &mut HashMap::new());
+      bits
+    }).collect::<Vec<_>>()
+  };
+  if values.len() < PARALLEL_MIN || threads <= 1 {
+    return serialize_all(values);
+  }
+  let chunk = std::cmp::max(values.len().div_ceil(threads), PARALLEL_MIN / 2);
+  let batches = values.par_chunks(chunk).map(serialize_all).collect::<Vec<_>>();
+  return batches.into_iter().flatten().collect();
+}
+
+// Writes a list of already serialized values, with the same encoding as `serialize_list`
+pub fn serialize_list_of(mut values: Vec<BitVec>, bits: &mut BitVec) {
+  bits.reserve(values.iter().map(|x| x.len() + 1).sum::<usize>() + 1);
+  for x in &mut values {
+    bits.push(true);
+    bits.append(x);
+  }
+  bits.push(false);
+}
+
 // Many elements, known length
 
 pub fn serialize_vector<T>(serialize_one: impl Fn(&T, &mut BitVec, &mut Names) -> (), size: u128, data: &[T], bits: &mut BitVec, names: &mut Names) {
@@ -179,6 +228,11 @@ pub fn deserialize_vector<T>(deserialize_one: impl Fn(&BitVec, &mut u128, &mut N
 
 // An address
 
+// An onion address is written as an IPv6 address on the OnionCat prefix, with port 0, followed by
+// its key and port. Nodes that don't know onion addresses can't read it, so it's only sent to
+// onion peers.
+const ONION_MARKER : [u16; 8] = [0xfd87, 0xd87e, 0xeb43, 0, 0, 0, 0, 0];
+
 pub fn serialize_address(address: &Address, bits: &mut BitVec, names: &mut Names) {
   match address {
     Address::IPv4 { val0, val1, val2, val3, port } => {
@@ -189,11 +243,23 @@ pub fn serialize_address(address: &Address, bits: &mut BitVec, names: &mut Names
       serialize_fixlen(8, &u256(*val3 as u128), bits, names);
       serialize_fixlen(16, &u256(*port as u128), bits, names);
     }
+    Address::IPv6 { segs, port } => {
+      bits.push(true);
+      for seg in segs {
+        serialize_fixlen(16, &u256(*seg as u128), bits, names);
+      }
+      serialize_fixlen(16, &u256(*port as u128), bits, names);
+    }
+    Address::Onion { key, port } => {
+      serialize_address(&Address::IPv6 { segs: ONION_MARKER, port: 0 }, bits, names);
+      serialize_bytes(32, key, bits, names);
+      serialize_fixlen(16, &u256(*port as u128), bits, names);
+    }
   }
 }
 
 pub fn deserialize_address(bits: &BitVec, index: &mut u128, names: &mut Names) -> Option<Address> {
-  if bits[*index as usize] as u128 == 0 {
+  if !bits.get(*index as usize)? {
     *index = *index + 1;
     let val0 = deserialize_fixlen(8, bits, index, names)?.low_u128() as u8;
     let val1 = deserialize_fixlen(8, bits, index, names)?.low_u128() as u8;
@@ -202,7 +268,18 @@ pub fn deserialize_address(bits: &BitVec, index: &mut u128, names: &mut Names) -
     let port = deserialize_fixlen(16, bits, index, names)?.low_u128() as u16;
     return Some(Address::IPv4 { val0, val1, val2, val3, port });
   } else {
-    return None;
+    *index = *index + 1;
+    let mut segs = [0; 8];
+    for seg in &mut segs {
+      *seg = deserialize_fixlen(16, bits, index, names)?.low_u128() as u16;
+    }
+    let port = deserialize_fixlen(16, bits, index, names)?.low_u128() as u16;
+    if (segs, port) == (ONION_MARKER, 0) {
+      let key = deserialize_bytes(32, bits, index, names)?.try_into().ok()?;
+      let port = deserialize_fixlen(16, bits, index, names)?.low_u128() as u16;
+      return Some(Address::Onion { key, port });
+    }
+    return Some(Address::IPv6 { segs, port });
   }
 }
 
@@ -220,7 +297,7 @@ pub fn deserialized_address(bits: &BitVec) -> Option<Address> {
 
 pub fn serialize_peer(peer: &Peer, bits: &mut BitVec, names: &mut Names) {
   serialize_address(&peer.address, bits, names);
-  serialize_fixlen(48, &u256(peer.seen_at as u128), bits, names);
+  serialize_fixlen(48, &u256(peer.seen_at), bits, names);
 }
 
 pub fn deserialize_peer(bits: &BitVec, index: &mut u128, names: &mut Names) -> Option<Peer> {
@@ -263,6 +340,21 @@ pub fn deserialize_block(bits: &BitVec, index: &mut u128, names: &mut Names) ->
   return Some(new_block(prev, time, meta, body));
 }
 
+pub fn serialize_header(header: &BlockHeader, bits: &mut BitVec, names: &mut Names) {
+  serialize_fixlen(256, &header.hash, bits, names);
+  serialize_fixlen(256, &header.prev, bits, names);
+  serialize_fixlen(128, &u256(header.time), bits, names);
+  serialize_fixlen(128, &u256(header.meta), bits, names);
+}
+
+pub fn deserialize_header(bits: &BitVec, index: &mut u128, names: &mut Names) -> Option<BlockHeader> {
+  let hash = deserialize_fixlen(256, bits, index, names)?;
+  let prev = deserialize_fixlen(256, bits, index, names)?;
+  let time = deserialize_fixlen(128, bits, index, names)?.low_u128();
+  let meta = deserialize_fixlen(128, bits, index, names)?.low_u128();
+  return Some(BlockHeader { hash, prev, time, meta });
+}
+
 pub fn serialized_block(block: &Block) -> BitVec {
   let mut bits = BitVec::new();
   serialize_block(block, &mut bits, &mut HashMap::new());
@@ -289,8 +381,11 @@ pub fn serialize_bytes(size: u128, bytes: &[u8], bits: &mut BitVec, names: &mut
   if size as usize != bytes.len() {
     panic!("Incorrect serialize_bytes size.");
   }
+  bits.reserve(bytes.len() * 8);
   for byte in bytes {
-    serialize_fixlen(8, &u256(*byte as u128), bits, names);
+    for i in 0 .. 8 {
+      bits.push((byte >> i) & 1 == 1);
+    }
   }
 }
 
@@ -310,7 +405,8 @@ pub fn serialize_message(message: &Message, bits: &mut BitVec, names: &mut Names
     Message::NoticeTheseBlocks { gossip, blocks, peers } => {
       serialize_fixlen(4, &u256(0), bits, names);
       serialize_fixlen(1, &u256(*gossip as u128), bits, names);
-      serialize_list(serialize_block, &blocks, bits, names);
+      // Blocks don't use the names table, so they can be serialized apart
+      serialize_list_of(serialized_each(serialize_block, blocks), bits);
       serialize_list(serialize_peer, peers, bits, names);
     }
     Message::GiveMeThatBlock { bhash } => {
@@ -326,6 +422,14 @@ pub fn serialize_message(message: &Message, bits: &mut BitVec, names: &mut Names
         serialize_bytes(trans.data.len() as u128, &trans.data, bits, names);
       }
     }
+    Message::GiveMeTheseHeaders { bhash } => {
+      serialize_fixlen(4, &u256(3), bits, names);
+      serialize_hash(bhash, bits, names);
+    }
+    Message::NoticeTheseHeaders { headers } => {
+      serialize_fixlen(4, &u256(4), bits, names);
+      serialize_list(serialize_header, headers, bits, names);
+    }
   }
 }
 
@@ -347,6 +451,14 @@ pub fn deserialize_message(bits: &BitVec, index: &mut u128, names: &mut Names) -
       let data = deserialize_bytes(size, bits, index, names)?;
       Some(Message::PleaseMineThisTransaction { trans: Transaction::new(data) })
     }
+    3 => {
+      let bhash = deserialize_hash(bits, index, names)?;
+      Some(Message::GiveMeTheseHeaders { bhash })
+    }
+    4 => {
+      let headers = deserialize_list(deserialize_header, bits, index, names)?;
+      Some(Message::NoticeTheseHeaders { headers })
+    }
     _ => None
   }
 }
@@ -361,6 +473,41 @@ pub fn deserialized_message(bits: &BitVec) -> Option<Message> {
   deserialize_message(bits, &mut 0, &mut HashMap::new())
 }
 
+// An Oper
+
+// Operations up to `!=` take the 4 bits after the tag of an Op2, as they always did. The ones
+// added after it don't fit there, so their Op2 is written with the tag of a variable and an empty
+// name, which no variable has, then 4 bits with how far after `!=` the operation is. Terms using
+// the first 16 operations keep their bits, and so the hashes of the statements holding them.
+
+// First operation written as an extended Op2
+const EXTENDED_OPER : u128 = NEQ + 1;
+
+pub fn serialize_oper(oper: u128, bits: &mut BitVec, names: &mut Names) {
+  if oper < EXTENDED_OPER {
+    serialize_fixlen(4, &u256(oper), bits, names);
+  } else {
+    serialize_fixlen(4, &u256(oper - EXTENDED_OPER), bits, names);
+  }
+}
+
+pub fn deserialize_oper(extended: bool, bits: &BitVec, index: &mut u128, names: &mut Names) -> Option<u128> {
+  let oper = deserialize_fixlen(4, bits, index, names)?.low_u128();
+  if !extended {
+    return Some(oper);
+  }
+  let oper = EXTENDED_OPER + oper;
+  if oper > BSW {
+    return None;
+  }
+  return Some(oper);
+}
+
+// Whether the name at the index is empty, which marks an extended Op2
+fn is_empty_name(bits: &BitVec, index: u128) -> bool {
+  bits.get(index as usize) == Some(false) && bits.get(index as usize + 1) == Some(false)
+}
+
 // A Term
 
 // TODO: avoid recursion here; important for checksum functionality
@@ -402,8 +549,14 @@ pub fn serialize_term(term: &Term, bits: &mut BitVec, names: &mut Names) {
       serialize_number(&u256(*numb), bits, names);
     }
     Term::Op2 { oper, val0, val1 } => {
-      serialize_fixlen(3, &u256(7), bits, names);
-      serialize_fixlen(4, &u256(*oper as u128), bits, names);
+      if *oper < EXTENDED_OPER {
+        serialize_fixlen(3, &u256(7), bits, names);
+      } else {
+        serialize_fixlen(3, &u256(0), bits, names);
+        bits.push(false); // uncompressed name
+        bits.push(false); // with no characters
+      }
+      serialize_oper(*oper, bits, names);
       serialize_term(val0, bits, names);
       serialize_term(val1, bits, names);
     }
@@ -414,6 +567,13 @@ pub fn deserialize_term(bits: &BitVec, index: &mut u128, names: &mut Names) -> O
   let tag = deserialize_fixlen(3, bits, index, names)?;
   //println!("- tag.: {} {:?}", tag, bits.clone().split_off(*index as usize));
   match tag.low_u128() {
+    0 if is_empty_name(bits, *index) => {
+      *index += 2;
+      let oper = deserialize_oper(true, bits, index, names)?;
+      let val0 = Box::new(deserialize_term(bits, index, names)?);
+      let val1 = Box::new(deserialize_term(bits, index, names)?);
+      Some(Term::Op2 { oper, val0, val1 })
+    }
     0 => {
       let name = deserialize_name(bits, index, names)?;
       Some(Term::Var { name })
@@ -453,7 +613,7 @@ pub fn deserialize_term(bits: &BitVec, index: &mut u128, names: &mut Names) -> O
       Some(Term::Num { numb })
     }
     7 => {
-      let oper = deserialize_fixlen(4, bits, index, names)?.low_u128();
+      let oper = deserialize_oper(false, bits, index, names)?;
       let val0 = Box::new(deserialize_term(bits, index, names)?);
       let val1 = Box::new(deserialize_term(bits, index, names)?);
       Some(Term::Op2 { oper, val0, val1 })
@@ -536,14 +696,61 @@ pub fn deserialize_sign(bits: &BitVec, index: &mut u128, names: &mut Names) -> O
 
 // A Statement
 
+// The index of a strict argument of a `fun`
+pub fn serialize_strict_arg(index: &u128, bits: &mut BitVec, names: &mut Names) {
+  serialize_fixlen(8, &u256(*index), bits, names);
+}
+
+pub fn deserialize_strict_arg(bits: &BitVec, index: &mut u128, names: &mut Names) -> Option<u128> {
+  deserialize_fixlen(8, bits, index, names).map(|index| index.low_u128())
+}
+
+// A function used by a `fun`, with the hash of its code
+pub fn serialize_use(used: &(u128, U256), bits: &mut BitVec, names: &mut Names) {
+  serialize_name(&used.0, bits, names);
+  serialize_fixlen(256, &used.1, bits, names);
+}
+
+pub fn deserialize_use(bits: &BitVec, index: &mut u128, names: &mut Names) -> Option<(u128, U256)> {
+  let name = deserialize_name(bits, index, names)?;
+  let code = deserialize_fixlen(256, bits, index, names)?;
+  Some((name, code))
+}
+
 pub fn serialize_statement(statement: &Statement, bits: &mut BitVec, names: &mut Names) {
   match statement {
-    Statement::Fun { name, args, func, init, sign } => {
-      serialize_fixlen(4, &u256(0), bits, names);
+    // A `fun` with a call limit, strict arguments or declared pure uses its own tag, so the
+    // encoding of other `fun`s is unchanged. Pure ones always encode both options, and ones that
+    // pin used code encode every option.
+    Statement::Fun { name, args, func, init, mana, strict, pure, uses, sign } => {
+      let tag = match (!uses.is_empty(), *pure, mana.is_some(), !strict.is_empty()) {
+        (true, _, _, _) => 8,
+        (false, true, _, _) => 7,
+        (false, false, false, false) => 0,
+        (false, false, true, false) => 4,
+        (false, false, false, true) => 5,
+        (false, false, true, true) => 6,
+      };
+      serialize_fixlen(4, &u256(tag), bits, names);
       serialize_name(name, bits, names);
       serialize_list(serialize_name, args, bits, names);
       serialize_func(func, bits, names);
       serialize_term(init, bits, names);
+      if tag == 8 {
+        serialize_fixlen(1, &u256(*pure as u128), bits, names);
+      }
+      if tag >= 7 {
+        serialize_fixlen(1, &u256(mana.is_some() as u128), bits, names);
+      }
+      if let Some(mana) = mana {
+        serialize_fixlen(128, &u256(*mana), bits, names);
+      }
+      if !strict.is_empty() || tag >= 7 {
+        serialize_list(serialize_strict_arg, strict, bits, names);
+      }
+      if tag == 8 {
+        serialize_list(serialize_use, uses, bits, names);
+      }
       serialize_sign(sign, bits, names);
     }
     Statement::Ctr { name, args, sign } => {
@@ -552,9 +759,14 @@ pub fn serialize_statement(statement: &Statement, bits: &mut BitVec, names: &mut
       serialize_list(serialize_name, args, bits, names);
       serialize_sign(sign, bits, names);
     }
-    Statement::Run { expr, sign } => {
-      serialize_fixlen(4, &u256(2), bits, names);
+    // A `run` that bids a max mana price uses its own tag, so the encoding of other `run`s is
+    // unchanged
+    Statement::Run { expr, price, sign } => {
+      serialize_fixlen(4, &u256(if price.is_some() { 10 } else { 2 }), bits, names);
       serialize_term(expr, bits, names);
+      if let Some(price) = price {
+        serialize_fixlen(128, &u256(*price), bits, names);
+      }
       serialize_sign(sign, bits, names);
     }
     Statement::Reg { name, ownr, sign } => {
@@ -563,19 +775,31 @@ pub fn serialize_statement(statement: &Statement, bits: &mut BitVec, names: &mut
       serialize_fixlen(128, &u256(*ownr), bits, names);
       serialize_sign(sign, bits, names);
     }
+    Statement::Rot { name, succ, delay, sign } => {
+      serialize_fixlen(4, &u256(9), bits, names);
+      serialize_name(name, bits, names);
+      serialize_fixlen(128, &u256(*succ), bits, names);
+      serialize_fixlen(128, &u256(*delay), bits, names);
+      serialize_sign(sign, bits, names);
+    }
   }
 }
 
 pub fn deserialize_statement(bits: &BitVec, index: &mut u128, names: &mut Names) -> Option<Statement> {
   let tag = deserialize_fixlen(4, bits, index, names)?.low_u128();
   match tag {
-    0 => {
+    0 | 4 | 5 | 6 | 7 | 8 => {
       let name = deserialize_name(bits, index, names)?;
       let args = deserialize_list(deserialize_name, bits, index, names)?;
       let func = deserialize_func(bits, index, names)?;
       let init = deserialize_term(bits, index, names)?;
+      let pure = if tag == 8 { deserialize_fixlen(1, bits, index, names)?.low_u128() == 1 } else { tag == 7 };
+      let has_mana = if tag >= 7 { deserialize_fixlen(1, bits, index, names)?.low_u128() == 1 } else { tag == 4 || tag == 6 };
+      let mana = if has_mana { Some(deserialize_fixlen(128, bits, index, names)?.low_u128()) } else { None };
+      let strict = if tag >= 5 { deserialize_list(deserialize_strict_arg, bits, index, names)? } else { vec![] };
+      let uses = if tag == 8 { deserialize_list(deserialize_use, bits, index, names)? } else { vec![] };
       let sign = deserialize_sign(bits, index, names)?;
-      Some(Statement::Fun { name, args, func, init, sign })
+      Some(Statement::Fun { name, args, func, init, mana, strict, pure, uses, sign })
     }
     1 => {
       let name = deserialize_name(bits, index, names)?;
@@ -583,10 +807,11 @@ pub fn deserialize_statement(bits: &BitVec, index: &mut u128, names: &mut Names)
       let sign = deserialize_sign(bits, index, names)?;
       Some(Statement::Ctr { name, args, sign })
     }
-    2 => {
+    2 | 10 => {
       let expr = deserialize_term(bits, index, names)?;
+      let price = if tag == 10 { Some(deserialize_fixlen(128, bits, index, names)?.low_u128()) } else { None };
       let sign = deserialize_sign(bits, index, names)?;
-      Some(Statement::Run { expr, sign })
+      Some(Statement::Run { expr, price, sign })
     }
     3 => {
       let name = deserialize_name(bits, index, names)?;
@@ -594,6 +819,13 @@ pub fn deserialize_statement(bits: &BitVec, index: &mut u128, names: &mut Names)
       let sign = deserialize_sign(bits, index, names)?;
       Some(Statement::Reg { name, ownr, sign })
     }
+    9 => {
+      let name = deserialize_name(bits, index, names)?;
+      let succ = deserialize_fixlen(128, bits, index, names)?.low_u128();
+      let delay = deserialize_fixlen(128, bits, index, names)?.low_u128();
+      let sign = deserialize_sign(bits, index, names)?;
+      Some(Statement::Rot { name, succ, delay, sign })
+    }
     _ => None,
   }
 }
@@ -0,0 +1,174 @@
+// Terminal Colors
+// ===============
+
+// The CLI colors the code it prints, so large states and statements are easier to skim:
+// constructors, functions, numbers, bound variables and keywords each get a color. Errors are
+// printed in red, and parse errors point at the line and column they're at. Colors are picked with
+// `--color`:
+//
+// - `auto`: colors what goes to a terminal, unless `$NO_COLOR` is set (the default)
+// - `always`: colors even what goes to files and pipes
+// - `never`: never colors
+//
+// Code is colored after being laid out, so coloring doesn't change where lines break.
+
+use std::io::IsTerminal;
+
+use crate::hvm::{self, ParseErr};
+
+// Environment variable that turns colors off in `auto` mode, see https://no-color.org
+pub const NO_COLOR_ENV_VAR : &str = "NO_COLOR";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ColorMode {
+  Auto,
+  Always,
+  Never,
+}
+
+impl std::str::FromStr for ColorMode {
+  type Err = String;
+  fn from_str(code: &str) -> Result<Self, Self::Err> {
+    match code {
+      "auto" => Ok(ColorMode::Auto),
+      "always" => Ok(ColorMode::Always),
+      "never" => Ok(ColorMode::Never),
+      _ => Err(format!("Invalid color mode: '{}'. Expected 'auto', 'always' or 'never'.", code)),
+    }
+  }
+}
+
+// What a piece of text is, which picks its color
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Style {
+  Ctr,     // constructor names
+  Fun,     // function names
+  Num,     // numbers
+  Var,     // variables bound by lambdas
+  Keyword, // `fun`, `run`, `dup`...
+  Error,   // error headers and carets
+  Dim,     // elided subterms and gutters
+}
+
+impl Style {
+  fn escape(self) -> &'static str {
+    match self {
+      Style::Ctr     => "\x1b[36m",
+      Style::Fun     => "\x1b[33m",
+      Style::Num     => "\x1b[35m",
+      Style::Var     => "\x1b[32m",
+      Style::Keyword => "\x1b[1;34m",
+      Style::Error   => "\x1b[1;31m",
+      Style::Dim     => "\x1b[2m",
+    }
+  }
+}
+
+const RESET : &str = "\x1b[0m";
+
+const KEYWORDS : &[&str] = &["fun", "ctr", "run", "reg", "rot", "with", "sign", "mana", "pure", "use", "dup", "let", "ask"];
+
+// Colors of an output stream: plain when off
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Colors {
+  pub enabled: bool,
+}
+
+impl Colors {
+  // Colors for a stream, given whether it's a terminal
+  pub fn new(mode: ColorMode, terminal: bool) -> Colors {
+    let enabled = match mode {
+      ColorMode::Always => true,
+      ColorMode::Never => false,
+      ColorMode::Auto => terminal && std::env::var_os(NO_COLOR_ENV_VAR).map_or(true, |var| var.is_empty()),
+    };
+    Colors { enabled }
+  }
+
+  pub fn stdout(mode: ColorMode) -> Colors {
+    Colors::new(mode, std::io::stdout().is_terminal())
+  }
+
+  pub fn stderr(mode: ColorMode) -> Colors {
+    Colors::new(mode, std::io::stderr().is_terminal())
+  }
+
+  pub fn paint(&self, style: Style, text: &str) -> String {
+    if self.enabled && !text.is_empty() {
+      format!("{}{}{}", style.escape(), text, RESET)
+    } else {
+      text.to_string()
+    }
+  }
+
+  // Colors code as shown by `view_term` or `view_statement`. Names after `{` are constructors,
+  // capitalized names after `(` are functions, and names after `@` are bound variables.
+  pub fn code(&self, text: &str) -> String {
+    if !self.enabled {
+      return text.to_string();
+    }
+    let mut output = String::new();
+    let mut last = ' '; // the last character that isn't part of a name or number
+    let mut rest = text;
+    while let Some(chr) = rest.chars().next() {
+      let word_len = rest.find(|chr: char| !hvm::is_name_char(chr)).unwrap_or(rest.len());
+      if chr == '#' {
+        let len = 1 + rest[1 ..].find(|chr: char| !chr.is_ascii_alphanumeric()).unwrap_or(rest.len() - 1);
+        output.push_str(&self.paint(Style::Num, &rest[.. len]));
+        rest = &rest[len ..];
+        last = '#';
+      } else if rest.starts_with("...") {
+        output.push_str(&self.paint(Style::Dim, "..."));
+        rest = &rest[3 ..];
+        last = '.';
+      } else if word_len > 0 {
+        let word = &rest[.. word_len];
+        let capitalized = chr.is_ascii_uppercase();
+        let style = match last {
+          '{' if capitalized => Some(Style::Ctr),
+          '(' if capitalized => Some(Style::Fun),
+          '@' => Some(Style::Var),
+          ' ' | '\n' if KEYWORDS.contains(&word) => Some(Style::Keyword),
+          _ => None,
+        };
+        match style {
+          Some(style) => output.push_str(&self.paint(style, word)),
+          None => output.push_str(word),
+        }
+        rest = &rest[word_len ..];
+        last = '_';
+      } else {
+        output.push(chr);
+        rest = &rest[chr.len_utf8() ..];
+        last = chr;
+      }
+    }
+    output
+  }
+
+  // Shows a parse error of `source` with the line it's at, and a caret under the column:
+  //
+  //   Expected identifier, found `}`.
+  //    --> line 2, column 19
+  //     |
+  //   2 |   (Foo a) = (Bar a}
+  //     |                   ^
+  //
+  // Only the first line of the message is shown, as the rest is context the snippet replaces.
+  pub fn parse_error(&self, source: &str, err: &ParseErr) -> String {
+    let message = err.erro.lines().next().unwrap_or_default();
+    let Some((line, column)) = hvm::parse_error_location(source, err) else { return message.to_string() };
+    let text = source.lines().nth(line - 1).unwrap_or_default();
+    let number = line.to_string();
+    let gutter = " ".repeat(number.len());
+    let bar = self.paint(Style::Dim, "|");
+    format!(
+      "{}\n{}{} line {}, column {}\n{} {}\n{} {} {}\n{} {} {}{}",
+      message,
+      gutter, self.paint(Style::Dim, "-->"), line, column,
+      gutter, bar,
+      self.paint(Style::Dim, &number), bar, text,
+      gutter, bar, " ".repeat(column - 1), self.paint(Style::Error, "^"),
+    )
+  }
+}
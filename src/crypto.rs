@@ -4,8 +4,9 @@ use secp256k1::rand::rngs::OsRng;
 use secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
 use secp256k1::{Secp256k1, Message, SecretKey, PublicKey};
 use tiny_keccak::Hasher;
+use std::collections::{BTreeMap, HashMap};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Signature(pub [u8; 65]);
 pub struct Address(pub [u8; 20]);
 pub struct Hash(pub [u8; 32]);
@@ -64,6 +65,10 @@ impl Address {
     return Address(hash.0[12..32].try_into().unwrap());
   }
 
+  pub fn from_hex(hex: &str) -> Option<Self> {
+    Some(Address(hex::decode(hex).ok()?.try_into().ok()?))
+  }
+
   pub fn show(&self) -> String {
     format!("0x{}", hex::encode(self.0))
   }
@@ -77,7 +82,21 @@ impl Name {
   // A Kindelia name is the first 120 bits of an Ethereum address
   // This corresponds to the bytes 12-27 of the ECDSA public key.
   pub fn from_hash(hash: &Hash) -> Self {
-    return Name(u128::from_be_bytes(vec![hash.0[12..27].to_vec(), vec![0]].concat().try_into().unwrap()) >> 8);
+    return Name(u128::from_be_bytes([hash.0[12..27].to_vec(), vec![0]].concat().try_into().unwrap()) >> 8);
+  }
+
+  // The name of an address: its first 120 bits
+  pub fn from_address(addr: &Address) -> Self {
+    return Name(u128::from_be_bytes([addr.0[0..15].to_vec(), vec![0]].concat().try_into().unwrap()) >> 8);
+  }
+
+  // Reads a name as shown: `#x` and up to 30 hex digits
+  pub fn read(text: &str) -> Option<Self> {
+    let digits = text.strip_prefix("#x")?;
+    if digits.is_empty() || digits.len() > 30 {
+      return None;
+    }
+    Some(Name(u128::from_str_radix(digits, 16).ok()?))
   }
 
   pub fn show(&self) -> String {
@@ -91,7 +110,7 @@ impl Account {
   }
 
   pub fn hash_public_key(pubk: &PublicKey) -> Hash {
-    return keccak256(&pubk.serialize_uncompressed()[1..65].to_vec());
+    return keccak256(&pubk.serialize_uncompressed()[1..65]);
   }
 
   pub fn from_private_key(key: &[u8]) -> Self {
@@ -113,6 +132,55 @@ impl Account {
   }
 }
 
+// Signers recovered from statement signatures, keyed by statement hash and signature. Recovering
+// a signer is expensive, and a statement's signature is checked once when it enters the mempool,
+// and again when it runs on a block. The least recently used entries are evicted first.
+pub struct SignatureCache {
+  capacity: usize,
+  clock: u64,                                   // bumped on every use
+  entries: HashMap<SignatureKey, (Option<u128>, u64)>, // signer name and last use of each entry
+  recent: BTreeMap<u64, SignatureKey>,          // entries by last use
+  pub hits: u64,
+  pub misses: u64,
+}
+
+type SignatureKey = ([u8; 32], [u8; 65]);
+
+impl SignatureCache {
+  pub fn new(capacity: usize) -> Self {
+    SignatureCache { capacity, clock: 0, entries: HashMap::new(), recent: BTreeMap::new(), hits: 0, misses: 0 }
+  }
+
+  // Recovers the name of the signer of `hash`, using the cached result when there is one
+  pub fn signer_name(&mut self, sign: &Signature, hash: &Hash) -> Option<Name> {
+    let key = (hash.0, sign.0);
+    self.clock += 1;
+    if let Some((name, used)) = self.entries.get_mut(&key) {
+      self.recent.remove(used);
+      self.recent.insert(self.clock, key);
+      *used = self.clock;
+      self.hits += 1;
+      return name.map(Name);
+    }
+    self.misses += 1;
+    let name = sign.signer_name(hash).map(|x| x.0);
+    if self.capacity > 0 {
+      if self.entries.len() >= self.capacity {
+        if let Some((_, oldest)) = self.recent.pop_first() {
+          self.entries.remove(&oldest);
+        }
+      }
+      self.entries.insert(key, (name, self.clock));
+      self.recent.insert(self.clock, key);
+    }
+    return name.map(Name);
+  }
+
+  pub fn len(&self) -> usize {
+    return self.entries.len();
+  }
+}
+
 fn main() {
 
   // Creates an account from a private key
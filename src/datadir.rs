@@ -0,0 +1,123 @@
+// Data Directory
+// ==============
+
+// The node keeps its files under a data directory (`--path`, `$KINDELIA_PATH` or `~/.kindelia`):
+//
+//   VERSION                   layout version of the directory, as a decimal number
+//   state/blocks/             the blocks of the chain, one serialized block per file, named after
+//                             its height
+//   state/block_index         hashes of the blocks of the chain, 32 bytes each, by height from 1
+//   state/heaps/              snapshots of the runtime, as buffers of each heap, plain (`.bin`) or
+//                             compressed with zstd (`.bin.zst`). A heap's `base` buffer lists the
+//                             saved heaps its buffers are deltas on top of.
+//   state/local_transactions  statements posted to this node and not mined yet
+//   state/headers             headers of the chain being synced, while far behind the peers
+//
+// When the layout or the format of a file changes, `DATA_VERSION` is bumped, and a migration from
+// the previous version is added to `MIGRATIONS`. The node runs the pending ones when it starts, so
+// an older directory is upgraded in place, instead of being synced again from scratch. `kindelia
+// migrate --dry-run` lists them without running them.
+
+use std::path::{Path, PathBuf};
+
+// Layout version written by this node
+pub const DATA_VERSION : u32 = 5;
+
+// A step that upgrades a data directory from a version to the next
+pub struct Migration {
+  pub from: u32,
+  pub about: &'static str,
+  pub run: fn(&Path) -> std::io::Result<()>,
+}
+
+pub const MIGRATIONS : &[Migration] = &[
+  Migration {
+    from: 0,
+    about: "Drops the runtime snapshots saved before heaps had call limits and frozen states. The node runs the stored blocks again on start, so they're rebuilt.",
+    run: drop_heaps,
+  },
+  Migration {
+    from: 1,
+    about: "Drops the runtime snapshots saved before namespaces had owner rotations. The node runs the stored blocks again on start, so they're rebuilt.",
+    run: drop_heaps,
+  },
+  Migration {
+    from: 2,
+    about: "Marks the directory as holding runtime snapshots compressed with zstd, which older nodes can't read. The uncompressed ones are kept, and read as they are.",
+    run: keep_heaps,
+  },
+  Migration {
+    from: 3,
+    about: "Marks the directory as holding runtime snapshots saved as deltas on top of older ones, which older nodes can't read. The snapshots saved whole are kept, and read as they are.",
+    run: keep_heaps,
+  },
+  Migration {
+    from: 4,
+    about: "Drops the runtime snapshots saved before heaps had authorizations, scheduled actions, hooks, randomness seeds and mana prices, which the ones kept by the earlier migrations lack. The node runs the stored blocks again on start, so they're rebuilt.",
+    run: drop_heaps,
+  },
+];
+
+fn version_path(path: &Path) -> PathBuf {
+  path.join("VERSION")
+}
+
+// Reads the layout version of a data directory. Directories written before it was versioned are
+// version 0, and new ones are of the current version.
+pub fn read_version(path: &Path) -> Result<u32, String> {
+  match std::fs::read_to_string(version_path(path)) {
+    Ok(text) => text.trim().parse().map_err(|_| format!("Invalid data directory version: '{}'.", text.trim())),
+    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+      Ok(if path.join("state").exists() { 0 } else { DATA_VERSION })
+    }
+    Err(err) => Err(format!("Couldn't read the data directory version: {}", err)),
+  }
+}
+
+fn write_version(path: &Path, version: u32) -> std::io::Result<()> {
+  std::fs::create_dir_all(path)?;
+  std::fs::write(version_path(path), format!("{}\n", version))
+}
+
+// Migrations that upgrade a directory of `version` to the current one, in order
+pub fn pending(version: u32) -> Vec<&'static Migration> {
+  MIGRATIONS.iter().filter(|step| step.from >= version && step.from < DATA_VERSION).collect()
+}
+
+// Upgrades a data directory to the current version, returning the migrations run. The version is
+// written after each one, so an interrupted upgrade resumes where it stopped. With `dry_run`, the
+// migrations are only returned. A directory of a newer version is refused, as this node can't read
+// it.
+pub fn migrate(path: &Path, dry_run: bool) -> Result<Vec<&'static Migration>, String> {
+  let version = read_version(path)?;
+  if version > DATA_VERSION {
+    return Err(format!("The data directory {:?} is of version {}, newer than this node's ({}). Upgrade the node.", path, version, DATA_VERSION));
+  }
+  let steps = pending(version);
+  if dry_run {
+    return Ok(steps);
+  }
+  for step in &steps {
+    (step.run)(path).map_err(|err| format!("Couldn't migrate the data directory from version {}: {}", step.from, err))?;
+    write_version(path, step.from + 1).map_err(|err| format!("Couldn't write the data directory version: {}", err))?;
+  }
+  if version == DATA_VERSION && !version_path(path).exists() {
+    write_version(path, DATA_VERSION).map_err(|err| format!("Couldn't write the data directory version: {}", err))?;
+  }
+  Ok(steps)
+}
+
+// Migrations
+// ----------
+
+fn keep_heaps(_path: &Path) -> std::io::Result<()> {
+  Ok(())
+}
+
+fn drop_heaps(path: &Path) -> std::io::Result<()> {
+  let heaps = path.join("state").join("heaps");
+  if heaps.exists() {
+    std::fs::remove_dir_all(&heaps)?;
+  }
+  Ok(())
+}
@@ -58,7 +58,7 @@
 // Notes:
 //
 //   1. The duplication label is an internal value used on the DUP-SUP rule.
-//   2. The operation name only uses 4 of the 72 bits, as there are only 16 ops.
+//   2. The operation name only uses 5 of the 72 bits, as there are only 21 ops.
 //   3. NUM pointers don't point anywhere, they just store the number directly.
 //
 // A node is a tuple of N pointers stored on sequential memory indices.
@@ -237,18 +237,23 @@
 
 #![allow(clippy::identity_op)]
 
-use std::collections::{hash_map, HashMap, HashSet};
+use std::collections::{hash_map, BTreeSet, HashMap, HashSet};
 use std::fmt::Write;
 use std::hash::{BuildHasherDefault, Hash, Hasher};
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+use primitive_types::U256;
 
 use crate::NoHashHasher as NHH;
 
 use crate::bits;
 use crate::crypto;
 use crate::dbg_println;
+#[cfg(feature = "mmap")]
+use crate::mmap;
+use crate::upgrade::{Activations, Feature};
 use crate::util::U128_SIZE;
 use crate::util;
 
@@ -279,6 +284,41 @@ pub enum Term {
   Op2 { oper: u128, val0: Box<Term>, val1: Box<Term> },
 }
 
+// Drops a term using a stack instead of recursion, so that deep terms can't overflow the native
+// stack. Each node has its children moved out before being dropped, so it is released shallowly.
+impl Drop for Term {
+  fn drop(&mut self) {
+    fn take(term: &mut Term, stack: &mut Vec<Term>) {
+      match term {
+        Term::Dup { expr, body, .. } => {
+          stack.push(std::mem::replace(&mut **expr, Term::Num { numb: 0 }));
+          stack.push(std::mem::replace(&mut **body, Term::Num { numb: 0 }));
+        }
+        Term::Lam { body, .. } => {
+          stack.push(std::mem::replace(&mut **body, Term::Num { numb: 0 }));
+        }
+        Term::App { func, argm } => {
+          stack.push(std::mem::replace(&mut **func, Term::Num { numb: 0 }));
+          stack.push(std::mem::replace(&mut **argm, Term::Num { numb: 0 }));
+        }
+        Term::Ctr { args, .. } | Term::Fun { args, .. } => {
+          stack.append(args);
+        }
+        Term::Op2 { val0, val1, .. } => {
+          stack.push(std::mem::replace(&mut **val0, Term::Num { numb: 0 }));
+          stack.push(std::mem::replace(&mut **val1, Term::Num { numb: 0 }));
+        }
+        Term::Var { .. } | Term::Num { .. } => {}
+      }
+    }
+    let mut stack = Vec::new();
+    take(self, &mut stack);
+    while let Some(mut term) = stack.pop() {
+      take(&mut term, &mut stack);
+    }
+  }
+}
+
 // A native HVM 120-bit machine integer operation
 // - Add: addition
 // - Sub: subtraction
@@ -302,6 +342,8 @@ pub enum Oper {
   Mod, And, Or,  Xor,
   Shl, Shr, Ltn, Lte,
   Eql, Gte, Gtn, Neq,
+  Rol, Ror, Clz, Pop,
+  Bsw,
 }
 
 // A u64 HashMap
@@ -339,6 +381,7 @@ pub struct CompRule {
   pub vars: Vec<Var>,          // left-hand side variable locations
   pub eras: Vec<(u128, u128)>, // must-clear locations (argument number and arity)
   pub body: Term,              // right-hand side body of rule
+  pub mana: u128,              // cost of applying it
 }
 
 // Compiled information about a function.
@@ -348,6 +391,31 @@ pub struct CompFunc {
   pub arity: u128,          // number of arguments
   pub redux: Vec<u128>,     // index of strict arguments
   pub rules: Vec<CompRule>, // vector of rules
+  pub table: Option<RuleTable>, // precompiled dispatch, if it has enough rules
+  pub strict: Vec<u128>,    // index of the arguments declared strict, as in `fun (F !x)`
+  pub pure: bool,           // whether calls are memoized, as in `fun (F x) { ... } pure`
+}
+
+impl CompFunc {
+  // Declares arguments strict, so they're reduced before matching, even if no rule matches on them.
+  // This keeps accumulators from piling up unreduced terms, one per call.
+  pub fn with_strict(mut self, strict: &[u128]) -> CompFunc {
+    self.strict = strict.to_vec();
+    self.redux.extend_from_slice(strict);
+    self.redux.sort_unstable();
+    self.redux.dedup();
+    self.table = build_rule_table(&self.rules, &self.redux);
+    return self;
+  }
+}
+
+// The rules of a function that may match a call, by what one of its strict arguments is. Built
+// when the function is compiled, so calls only check these, instead of every rule in turn.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuleTable {
+  pub param: u128,            // the argument the rules are dispatched on
+  pub cases: Map<Vec<usize>>, // for each constructor or number matched there, the rules that may match it, in order
+  pub other: Vec<usize>,      // the rules that may match anything else
 }
 
 // A file, which is just a map of `FuncID -> CompFunc`
@@ -371,6 +439,48 @@ pub struct Ownrs {
   pub ownrs: Map<u128>,
 }
 
+// A map of `Name -> FuncID`
+// Stores the function that authorizes statements acting as 'Name'.
+#[derive(Clone, Debug)]
+pub struct Auths {
+  pub auths: Map<u128>,
+}
+
+// A map of `Tick -> Ptr`
+// Links a tick to the list of IO actions scheduled to run on it.
+#[derive(Clone, Debug)]
+pub struct Schds {
+  pub schds: Map<u128>,
+}
+
+// A map of `FuncID -> Ptr`
+// Links a function id to the list of functions subscribed to its events.
+#[derive(Clone, Debug)]
+pub struct Hooks {
+  pub hooks: Map<u128>,
+}
+
+// A map of `FuncID -> u128`
+// Links a function id to the maximum mana a call to it may spend.
+#[derive(Clone, Debug)]
+pub struct Limits {
+  pub limits: Map<u128>,
+}
+
+// A map of `FuncID -> Tick`
+// Links a function id to the tick its state was frozen at, after which it can't be changed.
+#[derive(Clone, Debug)]
+pub struct Frozen {
+  pub frozen: Map<u128>,
+}
+
+// A map of `Name -> (Successor, Tick)`
+// Links a namespace to the owner it's rotating to, and the tick it takes over at.
+#[derive(Clone, Debug)]
+pub struct Rotations {
+  pub rotations: Map<(u128, u128)>,
+}
+
 // A map of `FuncID -> Ptr`
 // It links a function id to its state on the runtime memory.
 #[derive(Clone, Debug)]
@@ -379,21 +489,33 @@ pub struct Store {
 }
 
 /// A global statement that alters the state of the blockchain
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Statement {
-  Fun { name: u128, args: Vec<u128>, func: Func, init: Term, sign: Option<crypto::Signature> },
+  Fun { name: u128, args: Vec<u128>, func: Func, init: Term, mana: Option<u128>, strict: Vec<u128>, pure: bool, uses: Vec<(u128, U256)>, sign: Option<crypto::Signature> },
   Ctr { name: u128, args: Vec<u128>, sign: Option<crypto::Signature> },
-  Run { expr: Term, sign: Option<crypto::Signature> },
+  Run { expr: Term, price: Option<u128>, sign: Option<crypto::Signature> },
   Reg { name: u128, ownr: u128, sign: Option<crypto::Signature> },
+  Rot { name: u128, succ: u128, delay: u128, sign: Option<crypto::Signature> },
 }
 
 // An HVM pointer. It can point to an HVM node, a variable, or store an unboxed u120.
 pub type Ptr = u128;
 
 // A mergeable vector of u128 values
-#[derive(Debug, Clone)]
+#[derive(Debug)]
+#[cfg_attr(not(feature = "mmap"), derive(Clone))]
 pub struct Nodes {
   pub nodes: Map<u128>,
+  #[cfg(feature = "mmap")]
+  pub mapped: Option<mmap::MappedWords>, // when set, the nodes are on this file instead
+}
+
+// A clone keeps its nodes in memory, since a mapped file has a single owner
+#[cfg(feature = "mmap")]
+impl Clone for Nodes {
+  fn clone(&self) -> Self {
+    Nodes { nodes: self.iter().collect(), mapped: None }
+  }
 }
 
 // HVM's memory state (nodes, functions, metadata, statistics)
@@ -405,11 +527,20 @@ pub struct Heap {
   pub file: Funcs, // function codes
   pub arit: Arits, // function arities
   pub ownr: Ownrs, // namespace owners
+  pub auth: Auths, // name authorizers
+  pub schd: Schds, // scheduled actions
+  pub hook: Hooks, // event subscribers
+  pub lmit: Limits, // mana limits per call
+  pub frzn: Frozen, // frozen states
+  pub rotn: Rotations, // owner rotations
   pub tick: u128,  // tick counter
   pub time: u128,  // block timestamp
   pub meta: u128,  // block metadata
   pub hax0: u128,  // block hash, part 0
   pub hax1: u128,  // block hash, part 1
+  pub rand: u128,  // randomness beacon
+  pub pric: u128,  // base mana price of the next block
+  pub burn: u128,  // total burned mana fees
   pub funs: u128,  // total function count
   pub dups: u128,  // total dups count
   pub rwts: u128,  // total graph rewrites
@@ -417,6 +548,7 @@ pub struct Heap {
   pub size: i128,  // total used memory (in 64-bit words)
   pub mcap: u128,  // memory capacity (in 64-bit words)
   pub next: u128,  // memory index that *may* be empty
+  pub base: Vec<u128>, // uuids of the saved deltas this heap's buffers go on top of, oldest first
 }
 
 #[derive(Debug, Clone)]
@@ -428,6 +560,12 @@ pub struct SerializedHeap {
   pub file: Vec<u128>,
   pub arit: Vec<u128>,
   pub ownr: Vec<u128>,
+  pub auth: Vec<u128>,
+  pub schd: Vec<u128>,
+  pub hook: Vec<u128>,
+  pub lmit: Vec<u128>,
+  pub frzn: Vec<u128>,
+  pub rotn: Vec<u128>,
   pub nums: Vec<u128>,
   pub stat: Vec<u128>,
 }
@@ -445,6 +583,45 @@ pub enum Rollback {
   Nil,
 }
 
+// What a name was deployed as: a function, a constructor, or a registered namespace
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NameKind {
+  Ctr,
+  Fun,
+  Reg,
+}
+
+impl NameKind {
+  pub fn show(self) -> &'static str {
+    match self {
+      NameKind::Ctr => "ctr",
+      NameKind::Fun => "fun",
+      NameKind::Reg => "reg",
+    }
+  }
+}
+
+impl std::str::FromStr for NameKind {
+  type Err = String;
+  fn from_str(code: &str) -> Result<Self, Self::Err> {
+    match code {
+      "ctr" => Ok(NameKind::Ctr),
+      "fun" => Ok(NameKind::Fun),
+      "reg" => Ok(NameKind::Reg),
+      _ => Err(format!("Invalid kind: '{}'. Expected 'ctr', 'fun' or 'reg'.", code)),
+    }
+  }
+}
+
+// A name deployed on the chain, as listed by `Runtime::list_names`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameInfo {
+  pub name: u128,
+  pub kind: NameKind,
+  pub arity: Option<u128>, // of functions and constructors
+  pub hash: Option<U256>,   // of the code of functions (see `hash_func`)
+}
+
 // The current and past states
 pub struct Runtime {
   heap: Vec<Heap>,      // heap objects
@@ -453,6 +630,94 @@ pub struct Runtime {
   nuls: Vec<u64>,       // reuse heap indices
   back: Arc<Rollback>,  // past states
   path: PathBuf,        // where to save runtime state
+  trace: Option<Vec<String>>, // executed IO effects, when tracing is enabled
+  rewrites: Option<RewriteStats>, // rewrites performed by kind, when counting them
+  events: Vec<(u128, Term)>,  // events emitted by the running statement, pending delivery
+  emitted: Vec<(u128, Term)>, // events delivered since `take_emitted`, with their emitters
+  limits: TermLimits,         // size limits of terms read back from the runtime
+  stmt_limits: StatementLimits, // size and complexity limits of statements
+  state_limit: usize,         // largest state a function can save, serialized, in bytes
+  activations: Activations,   // heights the consensus rule changes apply from
+  sigs: crypto::SignatureCache, // signers of recently checked statements
+  upstream: Option<Arc<dyn Upstream>>, // chain this one forked from, if any
+  touched: HashSet<u128>,     // names whose state, code or owner was written since `take_touched`
+  tables: bool,               // whether calls use the functions' rule tables, or try each rule
+  memo: Memo,                 // results of pure calls made in the current block
+  deadline: Deadline,         // when the running block is aborted, if ever
+  compression: Option<i32>,   // zstd level of the heap buffers saved from now on, if compressed
+  fault: Option<RuntimeError>, // arity mismatch met while building a term, reported by `reduce`
+  disk_faults: Option<DiskFaults>, // heap writes failed on purpose, on chaos mode
+}
+
+// Fails a share of the writes of heaps to disk, to check the node recovers from failing disks (see
+// `node::Chaos`). The state in memory is kept, so only the saved state is left behind.
+pub struct DiskFaults {
+  rate: u128, // percentage of heap writes that fail
+  rng: rand::rngs::StdRng,
+}
+
+// Results of calls to pure functions, by a hash of the function and its arguments. It's emptied at
+// the start of each block, and holds up to MEMO_MAX_SIZE nodes, so every node running a block hits
+// it on the same calls, and spends the same mana.
+#[derive(Default)]
+pub struct Memo {
+  results: HashMap<[u8; 32], (Term, u128)>, // results, and their sizes in nodes
+  size: u128, // nodes held
+}
+
+// A wall-clock time after which reduction fails. Only the node's watchdog sets one, to abort blocks
+// that take too long (see `node::BlockWatchdog`), as whether it passes differs between machines.
+#[derive(Default)]
+pub struct Deadline {
+  at: Option<Instant>,
+  steps: u64,   // reduction steps since it was set
+  passed: bool, // once it passes, every reduction fails right away
+}
+
+impl Deadline {
+  fn check(&mut self) -> bool {
+    if let Some(at) = self.at {
+      if !self.passed {
+        self.steps += 1;
+        if self.steps.is_multiple_of(DEADLINE_CHECK_STEPS) {
+          self.passed = Instant::now() >= at;
+        }
+      }
+    }
+    return self.passed;
+  }
+}
+
+// A chain the runtime forked from. Functions the runtime doesn't know yet are looked up there, the
+// first time a statement mentions them, so a local chain can build on a remote one's state.
+pub trait Upstream: Send + Sync {
+  // The code and state of a function, as they were at the fork
+  fn get_function(&self, name: u128) -> Option<UpstreamFunc>;
+}
+
+pub type UpstreamFunc = (Func, Option<Term>);
+
+// Limits on the size of terms read back from the runtime, such as `run` results and events
+#[derive(Debug, Copy, Clone)]
+pub struct TermLimits {
+  pub depth: u128, // maximum nesting depth
+  pub nodes: u128, // maximum number of nodes
+}
+
+// Limits on the size and complexity of statements, checked before they are run
+#[derive(Debug, Copy, Clone)]
+pub struct StatementLimits {
+  pub size: usize,  // maximum serialized size, in bytes
+  pub rules: usize, // maximum number of rules of a function
+  pub arity: usize, // maximum number of fields of a constructor or function
+}
+
+// Why a statement was rejected without being run
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StatementRejection {
+  TooLarge { size: usize, limit: usize },
+  TooManyRules { rules: usize, limit: usize },
+  TooManyFields { arity: usize, limit: usize },
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -461,6 +726,11 @@ pub enum RuntimeError {
   NotEnoughSpace,
   TypeMismatch,
   EffectFailure,
+  TermTooLarge,
+  CyclicTerm,
+  StateTooLarge { size: usize, limit: usize },
+  Timeout,
+  ArityMismatch { name: u128, expected: u128, found: u128 }, // `expected` is U128_NONE if undeclared
 }
 
 //pub fn heaps_invariant(rt: &Runtime) -> (bool, Vec<u8>, Vec<u64>) {
@@ -493,8 +763,9 @@ pub type StatementResult = Result<StatementInfo, StatementErr>;
 pub enum StatementInfo {
   Ctr { name: u128, args: Vec<u128> },
   Fun { name: u128, args: Vec<u128> },
-  Run { done_term: Term, used_mana: u128, size_diff: i128, end_size: u128 },
+  Run { done_term: Term, used_mana: u128, refunded_mana: u128, size_diff: i128, end_size: u128 },
   Reg { name: u128, ownr: u128 },
+  Rot { name: u128, succ: u128, tick: u128 },
 }
 
 #[derive(Debug, Clone)]
@@ -502,6 +773,89 @@ pub struct StatementErr {
   pub err: String,
 }
 
+// What running a statement took, hooks included, as measured by the node that ran it. Only the time
+// differs between nodes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct StatementUsage {
+  pub rwts: u128,     // rewrites
+  pub mana: u128,     // spent, after refunds
+  pub size: i128,     // change in memory, in words
+  pub time: Duration, // wall-clock
+}
+
+// Kinds of rewrites, as in the Mana Table. FUN-MEMO is a pure call answered from the memo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rewrite {
+  AppLam,
+  AppSup,
+  Op2Num,
+  Op2Sup,
+  FunCtr,
+  FunSup,
+  FunNum,
+  FunMemo,
+  DupLam,
+  DupNum,
+  DupCtr,
+  DupSupD,
+  DupSupE,
+  DupEra,
+}
+
+impl Rewrite {
+  pub const ALL : [Rewrite; 14] = [
+    Rewrite::AppLam, Rewrite::AppSup, Rewrite::Op2Num, Rewrite::Op2Sup, Rewrite::FunCtr, Rewrite::FunSup, Rewrite::FunNum,
+    Rewrite::FunMemo, Rewrite::DupLam, Rewrite::DupNum, Rewrite::DupCtr, Rewrite::DupSupD, Rewrite::DupSupE, Rewrite::DupEra,
+  ];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Rewrite::AppLam  => "APP-LAM",
+      Rewrite::AppSup  => "APP-SUP",
+      Rewrite::Op2Num  => "OP2-NUM",
+      Rewrite::Op2Sup  => "OP2-SUP",
+      Rewrite::FunCtr  => "FUN-CTR",
+      Rewrite::FunSup  => "FUN-SUP",
+      Rewrite::FunNum  => "FUN-NUM",
+      Rewrite::FunMemo => "FUN-MEMO",
+      Rewrite::DupLam  => "DUP-LAM",
+      Rewrite::DupNum  => "DUP-NUM",
+      Rewrite::DupCtr  => "DUP-CTR",
+      Rewrite::DupSupD => "DUP-SUP-D",
+      Rewrite::DupSupE => "DUP-SUP-E",
+      Rewrite::DupEra  => "DUP-ERA",
+    }
+  }
+}
+
+// Rewrites performed by kind, for research on what real workloads do. The runtime only counts
+// them between `start_rewrite_stats` and `stop_rewrite_stats`, as it costs a check per rewrite.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RewriteStats {
+  pub counts: [u64; Rewrite::ALL.len()],
+}
+
+impl RewriteStats {
+  pub fn get(&self, rewrite: Rewrite) -> u64 {
+    self.counts[rewrite as usize]
+  }
+
+  pub fn total(&self) -> u64 {
+    self.counts.iter().sum()
+  }
+
+  // As CSV, a line per kind, with its share of the total
+  pub fn to_csv(&self) -> String {
+    let total = std::cmp::max(self.total(), 1) as f64;
+    let mut csv = "rewrite,count,share\n".to_string();
+    for rewrite in Rewrite::ALL {
+      let count = self.get(rewrite);
+      csv.push_str(&format!("{},{},{:.4}\n", rewrite.name(), count, count as f64 / total));
+    }
+    csv
+  }
+}
+
 pub type ParseResult<'a, A> = Result<(&'a str, A), ParseErr>;
 
 #[derive(Debug, Clone)]
@@ -577,6 +931,11 @@ pub const EQL : u128 = 0xC;
 pub const GTE : u128 = 0xD;
 pub const GTN : u128 = 0xE;
 pub const NEQ : u128 = 0xF;
+pub const ROL : u128 = 0x10;
+pub const ROR : u128 = 0x11;
+pub const CLZ : u128 = 0x12;
+pub const POP : u128 = 0x13;
+pub const BSW : u128 = 0x14;
 
 pub const VAR_NONE  : u128 = 0x3FFFF;
 pub const U128_NONE : u128 = 0xFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF;
@@ -591,6 +950,14 @@ pub const I128_NONE : i128 = -0x7FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF;
 //   (FROM           then) : (IO r)
 //   (TICK           then) : (IO r)
 //   (TIME           then) : (IO r)
+//   (AUTH func      then) : (IO r)
+//   (ACTS name proof expr then) : (IO r)
+//   (SCHD tick mana expr then) : (IO r)
+//   (RAND then) : (IO r)
+//   (HOOK name on then) : (IO r)
+//   (EMIT event then) : (IO r)
+//   (HASH expr then) : (IO r)
+//   (OWNS name owner then) : (IO r)
 const IO_DONE : u128 = 0x39960f; // name_to_u128("DONE")
 const IO_TAKE : u128 = 0x78b54f; // name_to_u128("TAKE")
 const IO_SAVE : u128 = 0x74b80f; // name_to_u128("SAVE")
@@ -603,10 +970,99 @@ const IO_TIME : u128 = 0x7935cf; // name_to_u128("TIME")
 const IO_META : u128 = 0x5cf78b; // name_to_u128("META")
 const IO_HAX0 : u128 = 0x48b881; // name_to_u128("HAX0")
 const IO_HAX1 : u128 = 0x48b882; // name_to_u128("HAX1")
+const IO_AUTH : u128 = 0x2df792; // name_to_u128("AUTH")
+const IO_ACTS : u128 = 0x2cd79d; // name_to_u128("ACTS")
+const IO_SCHD : u128 = 0x74d48e; // name_to_u128("SCHD")
+const IO_RAND : u128 = 0x70b60e; // name_to_u128("RAND")
+const IO_HOOK : u128 = 0x499655; // name_to_u128("HOOK")
+const IO_EMIT : u128 = 0x3d74de; // name_to_u128("EMIT")
+const IO_HASH : u128 = 0x48b752; // name_to_u128("HASH")
+const IO_OWNS : u128 = 0x66161d; // name_to_u128("OWNS")
+const IO_FRZE : u128 = 0x41c90f; // name_to_u128("FRZE")
+
+// Constructors used to chain hooks and scheduled actions
+const T2 : u128 = 0x783; // name_to_u128("T2")
+const T4 : u128 = 0x785; // name_to_u128("T4")
+
+// Constructor sent to an authorizer function, asking it to validate a proof
+const AUTHORIZE : u128 = 0xbe78b33dadfa9; // name_to_u128("Authorize")
+const EVENT : u128 = 0xfea9cb8; // name_to_u128("Event")
+
+// Genesis functions building the `CALL` and `DONE` effects
+const CALL : u128 = 0x365c30; // name_to_u128("Call")
+const DONE : u128 = 0x3b3ca9; // name_to_u128("Done")
+
+// Standard library functions computed natively on numbers (see `call_builtin`)
+const MATH_SQRT    : u128 = 0x5e5e2c775db8; // name_to_u128("MathSqrt")
+const MATH_LOG2    : u128 = 0x5e5e2c5b3ac3; // name_to_u128("MathLog2")
+const MATH_POW_MOD : u128 = 0x5e5e2c6b3ed7ce8; // name_to_u128("MathPowMod")
+const MATH_MUL_DIV : u128 = 0x5e5e2c5f9c0eb7a; // name_to_u128("MathMulDiv")
+const PACK_GET     : u128 = 0x1a967bd1a78; // name_to_u128("PackGet")
+const PACK_SET     : u128 = 0x1a967bdda78; // name_to_u128("PackSet")
 
 // Maximum mana that can be spent in a block
 pub const BLOCK_MANA_LIMIT : u128 = 4_000_000;
 
+// Mana usage per block that keeps the base mana price stable
+pub const BLOCK_MANA_TARGET : u128 = BLOCK_MANA_LIMIT / 2;
+
+// Base mana price of the genesis block, and the max price of runs that don't bid one
+pub const INITIAL_MANA_PRICE : u128 = 1000;
+
+// The base mana price changes by at most 1/N per block
+pub const MANA_PRICE_CHANGE_DENOMINATOR : u128 = 8;
+
+// Mana paid when scheduling an action, on top of the mana the action may spend
+pub const SCHEDULE_MANA : u128 = 1_000;
+
+// Mana refunded per memory word freed by a statement
+pub const REFUND_MANA_PER_WORD : u128 = 20;
+
+// A statement is refunded at most 1/MAX_REFUND_QUOTIENT of the mana it used
+pub const MAX_REFUND_QUOTIENT : u128 = 2;
+
+// Maximum mana a subscriber can spend handling an event
+pub const HOOK_MANA_LIMIT : u128 = 100_000;
+
+// Mana paid when subscribing to the events of a function
+pub const HOOK_MANA : u128 = 1_000;
+
+// Maximum subscribers to the events of a function
+pub const MAX_HOOKS : usize = 16;
+
+// Default limits of terms read back from the runtime. Reading back and printing are recursive, so
+// the depth is kept to what they handle on the 2 MB stack of a spawned thread; dropping is not.
+pub const DEFAULT_TERM_LIMITS : TermLimits = TermLimits { depth: 1 << 14, nodes: 1 << 20 };
+
+// Default limits of statements. The size is that of the largest transaction a block body fits.
+pub const DEFAULT_STATEMENT_LIMITS : StatementLimits = StatementLimits { size: 1275, rules: 256, arity: 16 };
+
+// Default size limit of the state a function saves, serialized, in bytes. States saved before it
+// was set or lowered are still read and loaded, and can be saved again once they fit.
+pub const DEFAULT_STATE_LIMIT : usize = 1 << 20;
+
+// Number of statement signers kept in the signature cache
+pub const SIGNATURE_CACHE_SIZE : usize = 65536;
+
+// Saved heaps a heap's buffers may go on top of, before it's saved whole (see `Heap::rebase`)
+pub const MAX_HEAP_DELTAS : usize = 8;
+
+// Default zstd level of the heap buffers saved on snapshots. The state is mostly repeated words,
+// so fast levels already shrink it several times.
+pub const HEAP_COMPRESSION_LEVEL : i32 = 3;
+
+// Maximum nodes held by the memo of pure calls, per block
+pub const MEMO_MAX_SIZE : u128 = 1 << 16;
+
+// Maximum nodes in the arguments of a memoized call. Larger ones aren't hashed into a key.
+pub const MEMO_MAX_KEY : u128 = 256;
+
+// Reduction steps between reads of the clock, when a deadline is set
+pub const DEADLINE_CHECK_STEPS : u64 = 4096;
+
+// Interval, in ticks, between compactions of the heap
+pub const COMPACT_INTERVAL : u128 = 4096;
+
 // Maximum state growth per block, in bits
 pub const BLOCK_BITS_LIMIT : i128 = 2048; // 1024 bits per sec = about 8 GB per year
 
@@ -628,9 +1084,19 @@ pub const BLOCK_BITS_LIMIT : i128 = 2048; // 1024 bits per sec = about 8 GB per
 // | DUP-SUP-D | clones a superposition          | 4     |
 // | DUP-SUP-E | undoes a superposition          | 2     |
 // | DUP-ERA   | clones an erasure               | 2     |
+// | FUN-NUM   | runs a built-in on numbers      | B     |
+// | FUN-MEMO  | reuses a memoized result        | 2 + S |
+// | MEMO-KEY  | hashes the arguments of a call  | K     |
 // |-----------------------------------------------------|
 // | * A is the constructor or function arity            |
 // | * M is the alloc count of the right-hand side       |
+// | * B is 2 for PackGet and PackSet, 4 for MathLog2,   |
+// |   8 for MathSqrt, MathMulDiv, and 4 + 4 * E for     |
+// |   MathPowMod, E being the number of bits of the     |
+// |   exponent                                          |
+// | * S is the node count of the memoized result        |
+// | * K is the node count of the pure call's arguments, |
+// |   paid by every pure call that could be memoized    |
 // |-----------------------------------------------------|
 
 
@@ -654,6 +1120,14 @@ fn FunCtrMana(body: &Term) -> u128 {
   return 2 + count_allocs(body);
 }
 
+fn MemoMana(size: u128) -> u128 {
+  return 2 + size;
+}
+
+fn MemoKeyMana(size: u128) -> u128 {
+  return size;
+}
+
 fn FunSupMana(arity: u128) -> u128 {
   return 2 + arity;
 }
@@ -682,6 +1156,26 @@ fn DupEraMana() -> u128 {
   return 2;
 }
 
+fn SqrtMana() -> u128 {
+  return 8;
+}
+
+fn Log2Mana() -> u128 {
+  return 4;
+}
+
+fn PowModMana(exp: u128) -> u128 {
+  return 4 + 4 * (128 - exp.leading_zeros() as u128);
+}
+
+fn MulDivMana() -> u128 {
+  return 8;
+}
+
+fn PackMana() -> u128 {
+  return 2;
+}
+
 fn count_allocs(body: &Term) -> u128 {
   match body {
     Term::Var { name } => {
@@ -820,9 +1314,92 @@ fun (Hax1) {
   (Hax1) = @cont {HAX1 cont}
 }
 
+// RAND returns the block's randomness beacon, a
+// 120-bit hash chained over the hashes of every block
+// up to this one. It is unpredictable before the block
+// is mined, but its miner sees it first, and can bias
+// it by grinding the block for a value it likes, or
+// withholding the ones it dislikes. Contracts must not
+// let it decide more than that work is worth.
+// It is the same for every statement in a block.
+ctr {RAND cont}
+fun (GetRandom) {
+  (GetRandom) = @cont {RAND cont}
+}
+
+// HOOK subscribes the current subject to the events
+// emitted by a function (#1), or unsubscribes it (#0)
+ctr {HOOK name on cont}
+fun (Subscribe name) {
+  (Subscribe name) = @cont {HOOK name #1 cont}
+}
+fun (Unsubscribe name) {
+  (Unsubscribe name) = @cont {HOOK name #0 cont}
+}
+
+// EMIT emits an event from the current subject. Once
+// the statement succeeds, each subscriber is called
+// with {Event subject event}, in the same block
+ctr {EMIT event cont}
+fun (Emit event) {
+  (Emit event) = @cont {EMIT event cont}
+}
+
+// Received by subscribers of a function's events
+ctr {Event name event}
+
+// HASH returns a 120-bit hash of a term, once
+// normalized. Sealed bids and commitments use it.
+ctr {HASH expr cont}
+fun (Hash expr) {
+  (Hash expr) = @cont {HASH expr cont}
+}
+
+// OWNS gives a short namespace nobody owns to an
+// owner, returning #1, or #0 if it can't. Only the
+// name registry ('Names') can use it.
+ctr {OWNS name owner cont}
+fun (Own name owner) {
+  (Own name owner) = @cont {OWNS name owner cont}
+}
+
+// AUTH designates a function as the authorizer
+// of the current subject's name (#0 removes it)
+ctr {AUTH func cont}
+fun (Auth func) {
+  (Auth func) = @cont {AUTH func cont}
+}
+
+// ACTS runs an IO operation as another name. The
+// name's authorizer is called with an Authorize
+// action, and must return #1 to accept the proof
+ctr {ACTS name proof expr cont}
+fun (Acts name proof expr) {
+  (Acts name proof expr) = @cont {ACTS name proof expr cont}
+}
+ctr {Authorize subject proof}
+
+// SCHD schedules an IO operation to run as the
+// current subject when the block tick reaches 'tick'.
+// It may spend up to 'mana', paid upfront
+ctr {SCHD tick mana expr cont}
+fun (Schedule tick mana expr) {
+  (Schedule tick mana expr) = @cont {SCHD tick mana expr cont}
+}
+
+// FRZE freezes the state of a function, after which
+// TAKE and SAVE fail on it, but LOAD still works.
+// Only the function itself, or the owner of its
+// namespace, can do it, and it can't be undone
+ctr {FRZE name cont}
+fun (Freeze name) {
+  (Freeze name) = @cont {FRZE name cont}
+}
+
 // LOAD works like TAKE, but clones the state
+ctr {LOAD cont}
 fun (Load) {
-  (Load) = @cont {TAKE @x dup x0 x1 = x; {SAVE x0 @~ (cont x1)}}
+  (Load) = @cont {LOAD cont}
 }
 
 // This is here for debugging. Will be removed.
@@ -839,6 +1416,32 @@ reg {
 }
 ";
 
+// Functions every contract can use, deployed right after the genesis statements
+pub const STDLIB : &str = include_str!("stdlib.kdl");
+
+// The name registry, which auctions short namespaces, deployed after the standard library
+pub const NAMES_CODE : &str = include_str!("names.kdl");
+pub const NAMES : u128 = 0x18971a77; // name_to_u128("Names")
+
+// Top-level namespaces of up to this many characters are short
+pub const SHORT_NAME_LEN : usize = 4;
+
+pub fn is_short_name(name: u128) -> bool {
+  name != 0 && name < 1 << (6 * SHORT_NAME_LEN) && get_namespace(name).is_none()
+}
+
+// Runs the genesis statements, the standard library and the name registry on a new runtime
+fn run_genesis(rt: &mut Runtime) {
+  rt.run_statements_from_code(GENESIS, true);
+  for (what, code) in [("standard library", STDLIB), ("name registry", NAMES_CODE)] {
+    for result in rt.run_statements_from_code(code, true) {
+      if let Err(err) = result {
+        panic!("The {} failed at genesis: {}", what, err.err);
+      }
+    }
+  }
+}
+
 // Utils
 // -----
 
@@ -862,18 +1465,48 @@ pub fn get_namespace(name: u128) -> Option<u128> {
   }
 }
 
+// Mana
+// ----
+
+// Computes the base mana price of a block from its parent's price and mana usage. As in EIP-1559,
+// the price rises when the parent used more than BLOCK_MANA_TARGET, falls when it used less, and
+// never changes by more than 1/MANA_PRICE_CHANGE_DENOMINATOR per block.
+pub fn compute_next_mana_price(last_price: u128, last_used: u128) -> u128 {
+  let used = std::cmp::min(last_used, BLOCK_MANA_LIMIT);
+  if used > BLOCK_MANA_TARGET {
+    let delta = last_price * (used - BLOCK_MANA_TARGET) / BLOCK_MANA_TARGET / MANA_PRICE_CHANGE_DENOMINATOR;
+    return last_price + std::cmp::max(delta, 1);
+  } else {
+    let delta = last_price * (BLOCK_MANA_TARGET - used) / BLOCK_MANA_TARGET / MANA_PRICE_CHANGE_DENOMINATOR;
+    return std::cmp::max(last_price - delta, 1);
+  }
+}
+
+// Mana refunded to a statement that used `used_mana` and changed memory usage by `size_diff` words
+pub fn compute_refund(used_mana: u128, size_diff: i128) -> u128 {
+  if size_diff >= 0 {
+    return 0;
+  }
+  let freed = size_diff.unsigned_abs();
+  return std::cmp::min(freed * REFUND_MANA_PER_WORD, used_mana / MAX_REFUND_QUOTIENT);
+}
+
 // Statements
 // ----------
 
 // Removes the signature from a statement
 pub fn remove_sign(statement: &Statement) -> Statement {
   match statement {
-    Statement::Fun { name, args, func, init, sign } => {
+    Statement::Fun { name, args, func, init, mana, strict, pure, uses, sign } => {
       Statement::Fun {
         name: *name,
         args: args.clone(),
         func: func.clone(),
         init: init.clone(),
+        mana: *mana,
+        strict: strict.clone(),
+        pure: *pure,
+        uses: uses.clone(),
         sign: None,
       }
     }
@@ -884,9 +1517,10 @@ pub fn remove_sign(statement: &Statement) -> Statement {
         sign: None,
       }
     }
-    Statement::Run { expr, sign } => {
+    Statement::Run { expr, price, sign } => {
       Statement::Run {
         expr: expr.clone(),
+        price: *price,
         sign: None,
       }
     }
@@ -897,17 +1531,39 @@ pub fn remove_sign(statement: &Statement) -> Statement {
         sign: None,
       }
     }
+    Statement::Rot { name, succ, delay, sign } => {
+      Statement::Rot {
+        name: *name,
+        succ: *succ,
+        delay: *delay,
+        sign: None,
+      }
+    }
+  }
+}
+
+pub fn get_sign(statement: &Statement) -> &Option<crypto::Signature> {
+  match statement {
+    Statement::Fun { sign, .. } => sign,
+    Statement::Ctr { sign, .. } => sign,
+    Statement::Run { sign, .. } => sign,
+    Statement::Reg { sign, .. } => sign,
+    Statement::Rot { sign, .. } => sign,
   }
 }
 
 pub fn set_sign(statement: &Statement, new_sign: crypto::Signature) -> Statement {
   match statement {
-    Statement::Fun { name, args, func, init, sign } => {
+    Statement::Fun { name, args, func, init, mana, strict, pure, uses, sign } => {
       Statement::Fun {
         name: *name,
         args: args.clone(),
         func: func.clone(),
         init: init.clone(),
+        mana: *mana,
+        strict: strict.clone(),
+        pure: *pure,
+        uses: uses.clone(),
         sign: Some(new_sign),
       }
     }
@@ -918,9 +1574,10 @@ pub fn set_sign(statement: &Statement, new_sign: crypto::Signature) -> Statement
         sign: Some(new_sign),
       }
     }
-    Statement::Run { expr, sign } => {
+    Statement::Run { expr, price, sign } => {
       Statement::Run {
         expr: expr.clone(),
+        price: *price,
         sign: Some(new_sign),
       }
     }
@@ -931,6 +1588,14 @@ pub fn set_sign(statement: &Statement, new_sign: crypto::Signature) -> Statement
         sign: Some(new_sign),
       }
     }
+    Statement::Rot { name, succ, delay, sign } => {
+      Statement::Rot {
+        name: *name,
+        succ: *succ,
+        delay: *delay,
+        sign: Some(new_sign),
+      }
+    }
   }
 }
 
@@ -976,6 +1641,42 @@ impl Heap {
   fn read_ownr(&self, fid: u128) -> Option<u128> {
     return self.ownr.read(fid);
   }
+  fn write_auth(&mut self, name: u128, val: u128) {
+    return self.auth.write(name, val);
+  }
+  fn read_auth(&self, name: u128) -> Option<u128> {
+    return self.auth.read(name);
+  }
+  fn write_schd(&mut self, tick: u128, val: Ptr) {
+    return self.schd.write(tick, val);
+  }
+  fn read_schd(&self, tick: u128) -> Option<Ptr> {
+    return self.schd.read(tick);
+  }
+  fn write_hook(&mut self, fid: u128, val: Ptr) {
+    return self.hook.write(fid, val);
+  }
+  fn read_hook(&self, fid: u128) -> Option<Ptr> {
+    return self.hook.read(fid);
+  }
+  fn write_lmit(&mut self, fid: u128, mana: u128) {
+    return self.lmit.write(fid, mana);
+  }
+  fn read_lmit(&self, fid: u128) -> Option<u128> {
+    return self.lmit.read(fid);
+  }
+  fn write_frzn(&mut self, fid: u128, tick: u128) {
+    return self.frzn.write(fid, tick);
+  }
+  fn read_frzn(&self, fid: u128) -> Option<u128> {
+    return self.frzn.read(fid);
+  }
+  fn write_rotn(&mut self, name: u128, rotation: (u128, u128)) {
+    return self.rotn.write(name, rotation);
+  }
+  fn read_rotn(&self, name: u128) -> Option<(u128, u128)> {
+    return self.rotn.read(name);
+  }
   fn set_tick(&mut self, tick: u128) {
     self.tick = tick;
   }
@@ -1006,6 +1707,18 @@ impl Heap {
   fn get_hax1(&self) -> u128 {
     return self.hax1;
   }
+  fn set_rand(&mut self, rand: u128) {
+    self.rand = rand;
+  }
+  fn get_rand(&self) -> u128 {
+    return self.rand;
+  }
+  fn set_pric(&mut self, pric: u128) {
+    self.pric = pric;
+  }
+  fn set_burn(&mut self, burn: u128) {
+    self.burn = burn;
+  }
   fn set_funs(&mut self, funs: u128) {
     self.funs = funs;
   }
@@ -1053,11 +1766,21 @@ impl Heap {
     self.disk.absorb(&mut other.disk, overwrite);
     self.file.absorb(&mut other.file, overwrite);
     self.arit.absorb(&mut other.arit, overwrite);
+    self.ownr.absorb(&mut other.ownr, overwrite);
+    self.auth.absorb(&mut other.auth, overwrite);
+    self.schd.absorb(&mut other.schd, overwrite);
+    self.hook.absorb(&mut other.hook, overwrite);
+    self.lmit.absorb(&mut other.lmit, overwrite);
+    self.frzn.absorb(&mut other.frzn, overwrite);
+    self.rotn.absorb(&mut other.rotn, overwrite);
     self.tick = absorb_u128(self.tick, other.tick, overwrite);
     self.time = absorb_u128(self.time, other.time, overwrite);
     self.meta = absorb_u128(self.meta, other.meta, overwrite);
     self.hax0 = absorb_u128(self.hax0, other.hax0, overwrite);
     self.hax1 = absorb_u128(self.hax1, other.hax1, overwrite);
+    self.rand = absorb_u128(self.rand, other.rand, overwrite);
+    self.pric = absorb_u128(self.pric, other.pric, overwrite);
+    self.burn = absorb_u128(self.burn, other.burn, overwrite);
     self.funs = absorb_u128(self.funs, other.funs, overwrite);
     self.dups = absorb_u128(self.dups, other.dups, overwrite);
     self.rwts = absorb_u128(self.rwts, other.rwts, overwrite);
@@ -1072,11 +1795,21 @@ impl Heap {
     self.disk.clear();
     self.file.clear();
     self.arit.clear();
+    self.ownr.clear();
+    self.auth.clear();
+    self.schd.clear();
+    self.hook.clear();
+    self.lmit.clear();
+    self.frzn.clear();
+    self.rotn.clear();
     self.tick = U128_NONE;
     self.time = U128_NONE;
     self.meta = U128_NONE;
     self.hax0 = U128_NONE;
     self.hax1 = U128_NONE;
+    self.rand = U128_NONE;
+    self.pric = U128_NONE;
+    self.burn = U128_NONE;
     self.funs = U128_NONE;
     self.dups = U128_NONE;
     self.rwts = U128_NONE;
@@ -1084,58 +1817,86 @@ impl Heap {
     self.size = I128_NONE;
     self.mcap = U128_NONE;
     self.next = U128_NONE;
+    self.base.clear();
   }
   pub fn serialize(&self) -> SerializedHeap {
     // Serializes stat and size
     let size = self.size as u128;
-    let stat = vec![self.tick, self.time, self.meta, self.hax0, self.hax1, self.funs, self.dups, self.rwts, self.mana, size, self.mcap, self.next];
-    // Serializes Nodes
+    let stat = vec![self.tick, self.time, self.meta, self.hax0, self.hax1, self.funs, self.dups, self.rwts, self.mana, size, self.mcap, self.next, self.rand, self.pric, self.burn];
+    // Serializes Nodes. Nodes on a mapped file are persisted by the file itself.
     let mut memo_buff : Vec<u128> = vec![];
     for (idx, val) in &self.memo.nodes {
-      memo_buff.push(*idx as u128);
-      memo_buff.push(*val as u128);
+      memo_buff.push(*idx);
+      memo_buff.push(*val);
     }
     // Serializes Store
     let mut disk_buff : Vec<u128> = vec![];
     for (fnid, lnk) in &self.disk.links {
-      disk_buff.push(*fnid as u128);
-      disk_buff.push(*lnk as u128);
+      disk_buff.push(*fnid);
+      disk_buff.push(*lnk);
     }
     // Serializes Funcs
     let mut file_buff : Vec<u128> = vec![];
     for (fnid, func) in &self.file.funcs {
       let mut func_buff = util::u8s_to_u128s(&mut bits::serialized_func(&func.func).to_bytes());
-      file_buff.push(*fnid as u128);
+      file_buff.push(*fnid);
+      file_buff.push(func.pure as u128);
+      file_buff.push(func.strict.len() as u128);
+      file_buff.extend_from_slice(&func.strict);
       file_buff.push(func_buff.len() as u128);
       file_buff.append(&mut func_buff);
     }
     // Serializes Arits
     let mut arit_buff : Vec<u128> = vec![];
     for (fnid, arit) in &self.arit.arits {
-      arit_buff.push(*fnid as u128);
+      arit_buff.push(*fnid);
       arit_buff.push(*arit);
     }
     // Serializes Ownrs
     let mut ownr_buff : Vec<u128> = vec![];
     for (fnid, ownr) in &self.ownr.ownrs {
-      ownr_buff.push(*fnid as u128);
+      ownr_buff.push(*fnid);
       ownr_buff.push(*ownr);
     }
+    // Serializes Auths
+    let mut auth_buff : Vec<u128> = vec![];
+    for (name, auth) in &self.auth.auths {
+      auth_buff.push(*name);
+      auth_buff.push(*auth);
+    }
+    // Serializes Schds
+    let mut schd_buff : Vec<u128> = vec![];
+    for (tick, list) in &self.schd.schds {
+      schd_buff.push(*tick);
+      schd_buff.push(*list);
+    }
+    // Serializes Hooks
+    let mut hook_buff : Vec<u128> = vec![];
+    for (fnid, list) in &self.hook.hooks {
+      hook_buff.push(*fnid);
+      hook_buff.push(*list);
+    }
+    // Serializes Limits
+    let mut lmit_buff : Vec<u128> = vec![];
+    for (fnid, mana) in &self.lmit.limits {
+      lmit_buff.push(*fnid);
+      lmit_buff.push(*mana);
+    }
+    // Serializes Frozen
+    let mut frzn_buff : Vec<u128> = vec![];
+    for (fnid, tick) in &self.frzn.frozen {
+      frzn_buff.push(*fnid);
+      frzn_buff.push(*tick);
+    }
+    // Serializes Rotations
+    let mut rotn_buff : Vec<u128> = vec![];
+    for (name, (succ, tick)) in &self.rotn.rotations {
+      rotn_buff.push(*name);
+      rotn_buff.push(*succ);
+      rotn_buff.push(*tick);
+    }
     // Serializes Nums
-    let nums_buff : Vec<u128> = vec![
-      self.tick,
-      self.time,
-      self.meta,
-      self.hax0,
-      self.hax1,
-      self.funs,
-      self.dups,
-      self.rwts,
-      self.mana,
-      self.size as u128,
-      self.mcap,
-      self.next
-    ];
+    let nums_buff = self.serialize_nums();
     // Returns the serialized heap
     return SerializedHeap {
       uuid: self.uuid,
@@ -1144,10 +1905,35 @@ impl Heap {
       file: file_buff,
       arit: arit_buff,
       ownr: ownr_buff,
+      auth: auth_buff,
+      schd: schd_buff,
+      hook: hook_buff,
+      lmit: lmit_buff,
+      frzn: frzn_buff,
+      rotn: rotn_buff,
       nums: nums_buff,
       stat,
     };
   }
+  fn serialize_nums(&self) -> Vec<u128> {
+    vec![
+      self.tick,
+      self.time,
+      self.meta,
+      self.hax0,
+      self.hax1,
+      self.funs,
+      self.dups,
+      self.rwts,
+      self.mana,
+      self.size as u128,
+      self.mcap,
+      self.next,
+      self.rand,
+      self.pric,
+      self.burn,
+    ]
+  }
   pub fn deserialize(&mut self, serial: &SerializedHeap) {
     // Deserializes stat and size
     self.tick = serial.nums[0];
@@ -1162,6 +1948,9 @@ impl Heap {
     self.size = serial.nums[9] as i128;
     self.mcap = serial.nums[10];
     self.next = serial.nums[11];
+    self.rand = serial.nums[12];
+    self.pric = serial.nums[13];
+    self.burn = serial.nums[14];
 
     // Deserializes Nodes
     let mut i = 0;
@@ -1182,13 +1971,18 @@ impl Heap {
     // Deserializes Funcs
     let mut i = 0;
     while i < serial.file.len() {
-      let fnid = serial.file[i + 0];
-      let size = serial.file[i + 1];
-      let buff = &serial.file[i + 2 .. i + 2 + size as usize];
+      let fnid = serial.file[i];
+      let pure = serial.file[i + 1] == 1;
+      let nstr = serial.file[i + 2] as usize;
+      let strict = &serial.file[i + 3 .. i + 3 + nstr];
+      i = i + 3 + nstr;
+      let size = serial.file[i];
+      let buff = &serial.file[i + 1 .. i + 1 + size as usize];
       let func = &bits::deserialized_func(&bit_vec::BitVec::from_bytes(&util::u128s_to_u8s(&buff))).unwrap();
-      let func = compile_func(func, false).unwrap();
+      let mut func = compile_func(func, false).unwrap().with_strict(strict);
+      func.pure = pure;
       self.write_file(fnid, Arc::new(func));
-      i = i + 2 + size as usize;
+      i = i + 1 + size as usize;
     }
     // Deserializes Arits
     for i in 0 .. serial.arit.len() / 2 {
@@ -1202,59 +1996,225 @@ impl Heap {
       let ownr = serial.ownr[i * 2 + 1];
       self.write_ownr(fnid, ownr);
     }
+    // Deserializes Auths
+    for i in 0 .. serial.auth.len() / 2 {
+      let name = serial.auth[i * 2 + 0];
+      let auth = serial.auth[i * 2 + 1];
+      self.write_auth(name, auth);
+    }
+    // Deserializes Schds
+    for i in 0 .. serial.schd.len() / 2 {
+      let tick = serial.schd[i * 2 + 0];
+      let list = serial.schd[i * 2 + 1];
+      self.write_schd(tick, list);
+    }
+    // Deserializes Hooks
+    for i in 0 .. serial.hook.len() / 2 {
+      let fnid = serial.hook[i * 2 + 0];
+      let list = serial.hook[i * 2 + 1];
+      self.write_hook(fnid, list);
+    }
+    // Deserializes Limits
+    for i in 0 .. serial.lmit.len() / 2 {
+      let fnid = serial.lmit[i * 2 + 0];
+      let mana = serial.lmit[i * 2 + 1];
+      self.write_lmit(fnid, mana);
+    }
+    // Deserializes Frozen
+    for i in 0 .. serial.frzn.len() / 2 {
+      let fnid = serial.frzn[i * 2 + 0];
+      let tick = serial.frzn[i * 2 + 1];
+      self.write_frzn(fnid, tick);
+    }
+    // Deserializes Rotations
+    for i in 0 .. serial.rotn.len() / 3 {
+      let name = serial.rotn[i * 3 + 0];
+      let succ = serial.rotn[i * 3 + 1];
+      let tick = serial.rotn[i * 3 + 2];
+      self.write_rotn(name, (succ, tick));
+    }
   }
   fn buffer_file_path(&self, uuid: u128, buffer_name: &str, path: &PathBuf) -> PathBuf {
     path.join(format!("{:0>32x}.{}.bin", uuid, buffer_name))
   }
-  fn write_buffer(&self, uuid: u128, buffer_name: &str, buffer: &[u128], append: bool, path: &PathBuf) -> std::io::Result<()> {
+  fn compressed_file_path(&self, uuid: u128, buffer_name: &str, path: &PathBuf) -> PathBuf {
+    path.join(format!("{:0>32x}.{}.bin.zst", uuid, buffer_name))
+  }
+  // Writes a buffer, compressed with zstd at `level`, if any. A buffer file keeps the format it was
+  // created with, so appending to one saved before compression was turned on, or off, works. The
+  // appended data is a zstd frame of its own, and consecutive frames decompress as one.
+  fn write_buffer(&self, uuid: u128, buffer_name: &str, buffer: &[u128], append: bool, path: &PathBuf, level: Option<i32>) -> std::io::Result<()> {
     use std::io::Write;
+    let plain = self.buffer_file_path(self.uuid, buffer_name, path);
+    let compressed = self.compressed_file_path(self.uuid, buffer_name, path);
+    let level = if plain.exists() { None } else if compressed.exists() { Some(level.unwrap_or(HEAP_COMPRESSION_LEVEL)) } else { level };
+    let bytes = util::u128s_to_u8s(buffer);
+    let (file, bytes) = match level {
+      Some(level) => (compressed, zstd::bulk::compress(&bytes, level)?),
+      None        => (plain, bytes),
+    };
     std::fs::OpenOptions::new()
       .write(true)
       .append(append)
+      .truncate(!append)
       .create(true)
-      .open(self.buffer_file_path(self.uuid, buffer_name, path))?
-      .write_all(&util::u128s_to_u8s(buffer))?;
+      .open(file)?
+      .write_all(&bytes)?;
     return Ok(());
   }
   fn read_buffer(&self, uuid: u128, buffer_name: &str, path: &PathBuf) -> std::io::Result<Vec<u128>> {
+    let compressed = self.compressed_file_path(uuid, buffer_name, path);
+    if compressed.exists() {
+      return zstd::stream::decode_all(std::fs::File::open(compressed)?).map(|x| util::u8s_to_u128s(&x));
+    }
     std::fs::read(self.buffer_file_path(uuid, buffer_name, path)).map(|x| util::u8s_to_u128s(&x))
   }
   fn delete_buffer(&self, uuid: u128, buffer_name: &str, path: &PathBuf) -> std::io::Result<()> {
+    let compressed = self.compressed_file_path(uuid, buffer_name, path);
+    if compressed.exists() {
+      return std::fs::remove_file(compressed);
+    }
     std::fs::remove_file(self.buffer_file_path(uuid, buffer_name, path))
   }
-  pub fn save_buffers(&self, path: &PathBuf) -> std::io::Result<()> {
-    self.append_buffers(self.uuid, path)
+  pub fn save_buffers(&mut self, path: &PathBuf, level: Option<i32>) -> std::io::Result<()> {
+    self.write_buffers(true, path, level)
+  }
+  // Keeps the saved buffers of a heap this one absorbed, along with the ones it went on top of, as
+  // deltas under this heap's own, instead of saving the merged heap again. Loading it applies them
+  // in order, then this heap's. Once there are more than MAX_HEAP_DELTAS, the merged heap is saved
+  // whole, and they're deleted.
+  fn rebase(&mut self, mut base: Vec<u128>, absorbed: u128, path: &PathBuf, level: Option<i32>) -> std::io::Result<()> {
+    base.push(absorbed);
+    base.append(&mut self.base);
+    if base.len() > MAX_HEAP_DELTAS {
+      self.write_buffers(false, path, level)?;
+      for delta in base {
+        self.delete_delta(delta, path)?;
+      }
+    } else {
+      self.base = base;
+      #[cfg(feature = "mmap")]
+      self.map_nodes(path)?;
+      // Absorbing fills the numbers this heap didn't have, so they're saved again
+      self.write_buffer(self.uuid, "nums", &self.serialize_nums(), false, path, level)?;
+    }
+    self.write_buffer(self.uuid, "base", &self.base, false, path, level)?;
+    return Ok(());
+  }
+  // Moves the nodes to a memory-mapped file, if they aren't on one yet, and flushes it
+  #[cfg(feature = "mmap")]
+  fn map_nodes(&mut self, path: &PathBuf) -> std::io::Result<()> {
+    if self.memo.mapped.is_none() {
+      let mut mapped = mmap::MappedWords::open(&self.buffer_file_path(self.uuid, "nodes", path))?;
+      for (idx, val) in self.memo.nodes.drain() {
+        mapped.write(idx, val);
+      }
+      self.memo.mapped = Some(mapped);
+    }
+    return self.memo.mapped.as_ref().unwrap().sync();
   }
-  fn append_buffers(&self, uuid: u128, path: &PathBuf) -> std::io::Result<()> {
+  // Saves the heap, appending to its buffers, or else replacing them
+  fn write_buffers(&mut self, append: bool, path: &PathBuf, level: Option<i32>) -> std::io::Result<()> {
+    #[cfg(feature = "mmap")]
+    self.map_nodes(path)?;
     let serial = self.serialize();
-    self.write_buffer(serial.uuid, "memo", &serial.memo, true, path)?;
-    self.write_buffer(serial.uuid, "disk", &serial.disk, true, path)?;
-    self.write_buffer(serial.uuid, "file", &serial.file, true, path)?;
-    self.write_buffer(serial.uuid, "arit", &serial.arit, true, path)?;
-    self.write_buffer(serial.uuid, "ownr", &serial.ownr, true, path)?;
-    self.write_buffer(serial.uuid, "nums", &serial.nums, true, path)?;
-    self.write_buffer(serial.uuid, "stat", &serial.stat, false, path)?;
+    #[cfg(not(feature = "mmap"))]
+    self.write_buffer(serial.uuid, "memo", &serial.memo, append, path, level)?;
+    self.write_buffer(serial.uuid, "disk", &serial.disk, append, path, level)?;
+    self.write_buffer(serial.uuid, "file", &serial.file, append, path, level)?;
+    self.write_buffer(serial.uuid, "arit", &serial.arit, append, path, level)?;
+    self.write_buffer(serial.uuid, "ownr", &serial.ownr, append, path, level)?;
+    self.write_buffer(serial.uuid, "auth", &serial.auth, append, path, level)?;
+    self.write_buffer(serial.uuid, "schd", &serial.schd, append, path, level)?;
+    self.write_buffer(serial.uuid, "hook", &serial.hook, append, path, level)?;
+    self.write_buffer(serial.uuid, "lmit", &serial.lmit, append, path, level)?;
+    self.write_buffer(serial.uuid, "frzn", &serial.frzn, append, path, level)?;
+    self.write_buffer(serial.uuid, "rotn", &serial.rotn, append, path, level)?;
+    self.write_buffer(serial.uuid, "nums", &serial.nums, append, path, level)?;
+    self.write_buffer(serial.uuid, "stat", &serial.stat, false, path, level)?;
     return Ok(());
   }
+  // Loads a saved heap: the deltas it goes on top of, then its own buffers
   pub fn load_buffers(&mut self, uuid: u128, path: &PathBuf) -> std::io::Result<()> {
+    let base = match self.read_buffer(uuid, "base", path) {
+      Ok(base) => base,
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => vec![],
+      Err(err) => return Err(err),
+    };
+    for delta in &base {
+      self.load_delta(*delta, path)?;
+    }
+    // The nodes of the deltas were moved to the heap's own file when it absorbed them
+    #[cfg(feature = "mmap")]
+    {
+      self.memo.mapped = Some(mmap::MappedWords::open(&self.buffer_file_path(uuid, "nodes", path))?);
+    }
+    self.load_delta(uuid, path)?;
+    self.base = base;
+    return Ok(());
+  }
+  fn load_delta(&mut self, uuid: u128, path: &PathBuf) -> std::io::Result<()> {
+    #[cfg(feature = "mmap")]
+    let memo = vec![];
+    #[cfg(not(feature = "mmap"))]
     let memo = self.read_buffer(uuid, "memo", path)?;
     let disk = self.read_buffer(uuid, "disk", path)?;
     let file = self.read_buffer(uuid, "file", path)?;
     let arit = self.read_buffer(uuid, "arit", path)?;
     let ownr = self.read_buffer(uuid, "ownr", path)?;
+    let auth = self.read_buffer(uuid, "auth", path)?;
+    let schd = self.read_buffer(uuid, "schd", path)?;
+    let hook = self.read_buffer(uuid, "hook", path)?;
+    let lmit = self.read_buffer(uuid, "lmit", path)?;
+    let frzn = self.read_buffer(uuid, "frzn", path)?;
+    let rotn = self.read_buffer(uuid, "rotn", path)?;
     let nums = self.read_buffer(uuid, "nums", path)?;
     let stat = self.read_buffer(uuid, "stat", path)?;
-    self.deserialize(&SerializedHeap { uuid, memo, disk, file, arit, ownr, nums, stat });
+    self.deserialize(&SerializedHeap { uuid, memo, disk, file, arit, ownr, auth, schd, hook, lmit, frzn, rotn, nums, stat });
     return Ok(());
   }
+  // Deletes the saved heap, along with the deltas it goes on top of
   fn delete_buffers(&mut self, path: &PathBuf) -> std::io::Result<()> {
-    self.delete_buffer(self.uuid, "memo", path)?;
-    self.delete_buffer(self.uuid, "disk", path)?;
-    self.delete_buffer(self.uuid, "file", path)?;
-    self.delete_buffer(self.uuid, "arit", path)?;
-    self.delete_buffer(self.uuid, "ownr", path)?;
-    self.delete_buffer(self.uuid, "nums", path)?;
-    self.delete_buffer(self.uuid, "stat", path)?;
+    self.delete_nodes(path)?;
+    for delta in std::mem::take(&mut self.base) {
+      self.delete_delta(delta, path)?;
+    }
+    return self.delete_delta(self.uuid, path);
+  }
+  // Deletes the memory-mapped nodes of the heap, which are the only ones not saved as a delta
+  fn delete_nodes(&mut self, path: &PathBuf) -> std::io::Result<()> {
+    #[cfg(feature = "mmap")]
+    {
+      let file = match self.memo.mapped.take() {
+        Some(mapped) => mapped.path().to_path_buf(),
+        None         => self.buffer_file_path(self.uuid, "nodes", path),
+      };
+      if file.exists() {
+        std::fs::remove_file(file)?;
+      }
+    }
+    return Ok(());
+  }
+  fn delete_delta(&self, uuid: u128, path: &PathBuf) -> std::io::Result<()> {
+    #[cfg(not(feature = "mmap"))]
+    self.delete_buffer(uuid, "memo", path)?;
+    self.delete_buffer(uuid, "disk", path)?;
+    self.delete_buffer(uuid, "file", path)?;
+    self.delete_buffer(uuid, "arit", path)?;
+    self.delete_buffer(uuid, "ownr", path)?;
+    self.delete_buffer(uuid, "auth", path)?;
+    self.delete_buffer(uuid, "schd", path)?;
+    self.delete_buffer(uuid, "hook", path)?;
+    self.delete_buffer(uuid, "lmit", path)?;
+    self.delete_buffer(uuid, "frzn", path)?;
+    self.delete_buffer(uuid, "rotn", path)?;
+    self.delete_buffer(uuid, "nums", path)?;
+    self.delete_buffer(uuid, "stat", path)?;
+    if let Err(err) = self.delete_buffer(uuid, "base", path) {
+      if err.kind() != std::io::ErrorKind::NotFound {
+        return Err(err);
+      }
+    }
     return Ok(());
   }
 }
@@ -1262,16 +2222,29 @@ impl Heap {
 pub fn init_heap() -> Heap {
   Heap {
     uuid: fastrand::u128(..),
-    memo: Nodes { nodes: init_map() },
+    memo: Nodes {
+      nodes: init_map(),
+      #[cfg(feature = "mmap")]
+      mapped: None,
+    },
     disk: Store { links: init_map() },
     file: Funcs { funcs: init_map() },
     arit: Arits { arits: init_map() },
     ownr: Ownrs { ownrs: init_map() },
+    auth: Auths { auths: init_map() },
+    schd: Schds { schds: init_map() },
+    hook: Hooks { hooks: init_map() },
+    lmit: Limits { limits: init_map() },
+    frzn: Frozen { frozen: init_map() },
+    rotn: Rotations { rotations: init_map() },
     tick: U128_NONE,
     time: U128_NONE,
     meta: U128_NONE,
     hax0: U128_NONE,
     hax1: U128_NONE,
+    rand: U128_NONE,
+    pric: U128_NONE,
+    burn: U128_NONE,
     funs: U128_NONE,
     dups: U128_NONE,
     rwts: U128_NONE,
@@ -1279,23 +2252,44 @@ pub fn init_heap() -> Heap {
     size: I128_NONE,
     mcap: U128_NONE,
     next: U128_NONE,
+    base: vec![],
   }
 }
 
 impl Nodes {
   fn write(&mut self, idx: u128, val: u128) {
+    #[cfg(feature = "mmap")]
+    if let Some(mapped) = &mut self.mapped {
+      return mapped.write(idx, val);
+    }
     self.nodes.insert(idx, val);
   }
   fn read(&self, idx: u128) -> u128 {
+    #[cfg(feature = "mmap")]
+    if let Some(mapped) = &self.mapped {
+      return mapped.read(idx);
+    }
     return self.nodes.get(&idx).map(|x| *x).unwrap_or(U128_NONE);
   }
+  // Iterates the written nodes, as `(index, value)` pairs
+  pub fn iter(&self) -> Box<dyn Iterator<Item = (u128, u128)> + '_> {
+    #[cfg(feature = "mmap")]
+    if let Some(mapped) = &self.mapped {
+      return Box::new(mapped.iter());
+    }
+    return Box::new(self.nodes.iter().map(|(idx, val)| (*idx, *val)));
+  }
   fn clear(&mut self) {
+    #[cfg(feature = "mmap")]
+    {
+      self.mapped = None;
+    }
     self.nodes.clear();
   }
   fn absorb(&mut self, other: &mut Self, overwrite: bool) {
-    for (idx, ownr) in other.nodes.drain() {
-      if overwrite || !self.nodes.contains_key(&idx) {
-        self.nodes.insert(idx, ownr);
+    for (idx, ownr) in other.iter() {
+      if overwrite || self.read(idx) == U128_NONE {
+        self.write(idx, ownr);
       }
     }
     other.clear();
@@ -1315,7 +2309,7 @@ impl Store {
   fn absorb(&mut self, other: &mut Self, overwrite: bool) {
     for (fid, func) in other.links.drain() {
       if overwrite || !self.links.contains_key(&fid) {
-        self.write(fid as u128, func);
+        self.write(fid, func);
       }
     }
   }
@@ -1334,7 +2328,7 @@ impl Funcs {
   fn absorb(&mut self, other: &mut Self, overwrite: bool) {
     for (fid, func) in other.funcs.drain() {
       if overwrite || !self.funcs.contains_key(&fid) {
-        self.write(fid as u128, func.clone());
+        self.write(fid, func.clone());
       }
     }
   }
@@ -1378,35 +2372,241 @@ impl Ownrs {
   }
 }
 
-pub fn init_runtime(path: Option<&PathBuf>) -> Runtime {
-  // Default runtime store path
-  let dflt = dirs::home_dir().unwrap().join(".kindelia").join("state").join("heaps");
-  let path = path.unwrap_or_else(|| &dflt);
-  std::fs::create_dir_all(&path).unwrap(); // TODO remove unwrap?
-  let mut heap = Vec::new();
-  for i in 0 .. MAX_HEAPS {
-    heap.push(init_heap());
+impl Auths {
+  fn write(&mut self, name: u128, val: u128) {
+    self.auths.insert(name, val);
   }
-  let mut rt = Runtime {
+  fn read(&self, name: u128) -> Option<u128> {
+    return self.auths.get(&name).copied();
+  }
+  fn clear(&mut self) {
+    self.auths.clear();
+  }
+  fn absorb(&mut self, other: &mut Self, overwrite: bool) {
+    for (name, auth) in other.auths.drain() {
+      if overwrite || !self.auths.contains_key(&name) {
+        self.auths.insert(name, auth);
+      }
+    }
+  }
+}
+
+impl Schds {
+  fn write(&mut self, tick: u128, val: Ptr) {
+    self.schds.insert(tick, val);
+  }
+  fn read(&self, tick: u128) -> Option<Ptr> {
+    return self.schds.get(&tick).copied();
+  }
+  fn clear(&mut self) {
+    self.schds.clear();
+  }
+  fn absorb(&mut self, other: &mut Self, overwrite: bool) {
+    for (tick, list) in other.schds.drain() {
+      if overwrite || !self.schds.contains_key(&tick) {
+        self.schds.insert(tick, list);
+      }
+    }
+  }
+}
+
+impl Hooks {
+  fn write(&mut self, fid: u128, val: Ptr) {
+    self.hooks.insert(fid, val);
+  }
+  fn read(&self, fid: u128) -> Option<Ptr> {
+    return self.hooks.get(&fid).copied();
+  }
+  fn clear(&mut self) {
+    self.hooks.clear();
+  }
+  fn absorb(&mut self, other: &mut Self, overwrite: bool) {
+    for (fid, list) in other.hooks.drain() {
+      if overwrite || !self.hooks.contains_key(&fid) {
+        self.hooks.insert(fid, list);
+      }
+    }
+  }
+}
+
+impl Limits {
+  fn write(&mut self, fid: u128, mana: u128) {
+    self.limits.insert(fid, mana);
+  }
+  fn read(&self, fid: u128) -> Option<u128> {
+    return self.limits.get(&fid).copied();
+  }
+  fn clear(&mut self) {
+    self.limits.clear();
+  }
+  fn absorb(&mut self, other: &mut Self, overwrite: bool) {
+    for (fid, mana) in other.limits.drain() {
+      if overwrite || !self.limits.contains_key(&fid) {
+        self.limits.insert(fid, mana);
+      }
+    }
+  }
+}
+
+impl Frozen {
+  fn write(&mut self, fid: u128, tick: u128) {
+    self.frozen.insert(fid, tick);
+  }
+  fn read(&self, fid: u128) -> Option<u128> {
+    return self.frozen.get(&fid).copied();
+  }
+  fn clear(&mut self) {
+    self.frozen.clear();
+  }
+  fn absorb(&mut self, other: &mut Self, overwrite: bool) {
+    for (fid, tick) in other.frozen.drain() {
+      if overwrite || !self.frozen.contains_key(&fid) {
+        self.frozen.insert(fid, tick);
+      }
+    }
+  }
+}
+
+impl Rotations {
+  fn write(&mut self, name: u128, rotation: (u128, u128)) {
+    self.rotations.insert(name, rotation);
+  }
+  fn read(&self, name: u128) -> Option<(u128, u128)> {
+    return self.rotations.get(&name).copied();
+  }
+  fn clear(&mut self) {
+    self.rotations.clear();
+  }
+  fn absorb(&mut self, other: &mut Self, overwrite: bool) {
+    for (name, rotation) in other.rotations.drain() {
+      if overwrite || !self.rotations.contains_key(&name) {
+        self.rotations.insert(name, rotation);
+      }
+    }
+  }
+}
+
+pub fn init_runtime(path: Option<&PathBuf>) -> Runtime {
+  // Default runtime store path
+  let dflt = dirs::home_dir().unwrap().join(".kindelia").join("state").join("heaps");
+  let path = path.unwrap_or_else(|| &dflt);
+  std::fs::create_dir_all(&path).unwrap(); // TODO remove unwrap?
+  let mut heap = Vec::new();
+  for i in 0 .. MAX_HEAPS {
+    heap.push(init_heap());
+  }
+  let mut rt = Runtime {
     heap,
     draw: 0,
     curr: 1,
     nuls: (2 .. MAX_HEAPS).collect(),
     back: Arc::new(Rollback::Nil),
     path: path.clone(),
+    trace: None,
+    rewrites: None,
+    events: vec![],
+    limits: DEFAULT_TERM_LIMITS,
+    stmt_limits: DEFAULT_STATEMENT_LIMITS,
+    state_limit: DEFAULT_STATE_LIMIT,
+    activations: Activations::default(),
+    sigs: crypto::SignatureCache::new(SIGNATURE_CACHE_SIZE),
+    upstream: None,
+    touched: HashSet::new(),
+    tables: true,
+    memo: Memo::default(),
+    deadline: Deadline::default(),
+    compression: Some(HEAP_COMPRESSION_LEVEL),
+    fault: None,
+    emitted: vec![],
+    disk_faults: None,
   };
-  rt.run_statements_from_code(GENESIS, true);
+  run_genesis(&mut rt);
   
   rt.snapshot();
   return rt;
 }
 
+// Builds a scratch runtime holding only the genesis state. It is never persisted.
+pub fn init_scratch_runtime() -> Runtime {
+  let mut rt = Runtime {
+    heap: vec![init_heap(), init_heap()],
+    draw: 0,
+    curr: 1,
+    nuls: vec![],
+    back: Arc::new(Rollback::Nil),
+    path: PathBuf::new(),
+    trace: None,
+    rewrites: None,
+    events: vec![],
+    limits: DEFAULT_TERM_LIMITS,
+    stmt_limits: DEFAULT_STATEMENT_LIMITS,
+    state_limit: DEFAULT_STATE_LIMIT,
+    activations: Activations::default(),
+    sigs: crypto::SignatureCache::new(SIGNATURE_CACHE_SIZE),
+    upstream: None,
+    touched: HashSet::new(),
+    tables: true,
+    memo: Memo::default(),
+    deadline: Deadline::default(),
+    compression: Some(HEAP_COMPRESSION_LEVEL),
+    fault: None,
+    emitted: vec![],
+    disk_faults: None,
+  };
+  run_genesis(&mut rt);
+  rt.draw();
+  return rt;
+}
+
+// Checks the state saved at a path, telling the faults its heap has. A state that can't be loaded
+// fails instead.
+pub fn check_saved_state(path: &PathBuf) -> std::io::Result<Vec<HeapFault>> {
+  return Ok(check_heap(&load_runtime(path)?));
+}
+
+// Loads the runtime state persisted on `path`, without running the genesis block. The heap isn't
+// checked, so that a corrupted state can still be inspected.
+pub fn load_runtime(path: &PathBuf) -> std::io::Result<Runtime> {
+  let mut heap = Vec::new();
+  for i in 0 .. MAX_HEAPS {
+    heap.push(init_heap());
+  }
+  let mut rt = Runtime {
+    heap,
+    draw: 0,
+    curr: 1,
+    nuls: (2 .. MAX_HEAPS).collect(),
+    back: Arc::new(Rollback::Nil),
+    path: path.clone(),
+    trace: None,
+    rewrites: None,
+    events: vec![],
+    limits: DEFAULT_TERM_LIMITS,
+    stmt_limits: DEFAULT_STATEMENT_LIMITS,
+    state_limit: DEFAULT_STATE_LIMIT,
+    activations: Activations::default(),
+    sigs: crypto::SignatureCache::new(SIGNATURE_CACHE_SIZE),
+    upstream: None,
+    touched: HashSet::new(),
+    tables: true,
+    memo: Memo::default(),
+    deadline: Deadline::default(),
+    compression: Some(HEAP_COMPRESSION_LEVEL),
+    fault: None,
+    emitted: vec![],
+    disk_faults: None,
+  };
+  rt.restore_state_unchecked()?;
+  return Ok(rt);
+}
+
 impl Runtime {
 
   // API
   // ---
 
   pub fn define_function(&mut self, fid: u128, func: CompFunc) {
+    self.touched.insert(fid);
     self.get_heap_mut(self.draw).write_arit(fid, func.arity);
     self.get_heap_mut(self.draw).write_file(fid, Arc::new(func));
   }
@@ -1459,13 +2659,23 @@ impl Runtime {
   //}
 
   pub fn run_statements(&mut self, statements: &[Statement], silent: bool) -> Vec<StatementResult> {
+    self.run_statements_measured(statements, silent).into_iter().map(|(res, _)| res).collect()
+  }
+
+  // Like `run_statements`, but also tells what each statement took to run
+  pub fn run_statements_measured(&mut self, statements: &[Statement], silent: bool) -> Vec<(StatementResult, StatementUsage)> {
     statements.iter().map(
-      |s| { 
-        let res = self.run_statement(s, silent);
-        if let Ok(..) = res {
-          self.draw();
-        }
-        res
+      |s| {
+        let (rwts, mana, size) = (self.get_rwts(), self.get_mana(), self.get_size());
+        let start = Instant::now();
+        let res = self.run_statement_with_hooks(s, silent);
+        let usage = StatementUsage {
+          rwts: self.get_rwts().saturating_sub(rwts),
+          mana: self.get_mana().saturating_sub(mana),
+          size: self.get_size() - size,
+          time: start.elapsed(),
+        };
+        (res, usage)
       }
     ).collect()
   }
@@ -1519,6 +2729,27 @@ impl Runtime {
     return Ok(done);
   }
 
+  // Like `compute`, but also computes the fields of constructors, so that data, like numbers and
+  // constructors of numbers, ends up in normal form
+  pub fn compute_data(&mut self, lnk: Ptr, mana: u128) -> Result<Ptr, RuntimeError> {
+    let host = alloc_lnk(self, lnk);
+    let done = compute_data_at(self, host, mana)?;
+    clear(self, host, 1);
+    return Ok(done);
+  }
+
+  pub fn compact(&mut self) -> u128 {
+    return compact(self);
+  }
+
+  // Compacts the heap between two blocks, on an operator's demand. The moves are kept at once, so
+  // a failing statement of the next block can't undo them along with its own changes.
+  pub fn compact_now(&mut self) -> u128 {
+    let moved = self.compact();
+    self.draw();
+    return moved;
+  }
+
   pub fn show_term(&self, lnk: Ptr) -> String {
     return show_term(self, lnk, None);
   }
@@ -1543,8 +2774,8 @@ impl Runtime {
     // FIXME: can we satisfy the borrow checker without using unsafe pointers?
     unsafe {
       let a_arr = &mut self.heap as *mut Vec<Heap>;
-      let a_ref = &mut *(&mut (*a_arr)[absorber as usize] as *mut Heap);
-      let b_ref = &mut *(&mut (*a_arr)[absorbed as usize] as *mut Heap);
+      let a_ref = &mut *(&mut (&mut *a_arr)[absorber as usize] as *mut Heap);
+      let b_ref = &mut *(&mut (&mut *a_arr)[absorbed as usize] as *mut Heap);
       a_ref.absorb(b_ref, overwrite);
     }
   }
@@ -1562,9 +2793,187 @@ impl Runtime {
     self.clear_heap(self.draw);
   }
 
+  // Tracing
+  // -------
+
+  // Starts recording the IO effects performed by statements, discarding any previous trace
+  pub fn start_trace(&mut self) {
+    self.trace = Some(vec![]);
+  }
+
+  // Stops recording IO effects, returning what was recorded
+  pub fn stop_trace(&mut self) -> Vec<String> {
+    return self.trace.take().unwrap_or_default();
+  }
+
+  // Starts counting the rewrites performed, by kind, discarding any previous count
+  pub fn start_rewrite_stats(&mut self) {
+    self.rewrites = Some(RewriteStats::default());
+  }
+
+  // Stops counting rewrites, returning the counts
+  pub fn stop_rewrite_stats(&mut self) -> RewriteStats {
+    return self.rewrites.take().unwrap_or_default();
+  }
+
+  fn count_rewrite(&mut self, rewrite: Rewrite) {
+    if let Some(stats) = &mut self.rewrites {
+      stats.counts[rewrite as usize] += 1;
+    }
+  }
+
+  // Sets the size limits of terms read back from the runtime
+  pub fn set_term_limits(&mut self, limits: TermLimits) {
+    self.limits = limits;
+  }
+
+  // Sets the size and complexity limits of statements
+  pub fn set_statement_limits(&mut self, limits: StatementLimits) {
+    self.stmt_limits = limits;
+  }
+
+  pub fn get_statement_limits(&self) -> StatementLimits {
+    return self.stmt_limits;
+  }
+
+  // Sets the size limit of saved states, serialized, in bytes. Saves past it fail.
+  pub fn set_state_limit(&mut self, limit: usize) {
+    self.state_limit = limit;
+  }
+
+  pub fn get_state_limit(&self) -> usize {
+    return self.state_limit;
+  }
+
+  // Sets the heights consensus rule changes apply from (see `upgrade.rs`)
+  pub fn set_activations(&mut self, activations: Activations) {
+    self.activations = activations;
+  }
+
+  pub fn get_activations(&self) -> Activations {
+    return self.activations;
+  }
+
+  // Whether the rules of a feature apply to the block being run, which is at the height after
+  // the current tick
+  pub fn is_active(&self, feature: Feature) -> bool {
+    return self.activations.is_active(feature, self.get_tick() + 1);
+  }
+
+  // The value a block info effect (TICK, TIME, META, HAX0, HAX1) passes on, and the caller its
+  // continuation runs with. Before `EffectResults`, these were the subject and the block value.
+  fn block_info(&self, subject: u128, caller: u128, value: u128) -> (u128, u128) {
+    if self.is_active(Feature::EffectResults) {
+      (value, caller)
+    } else {
+      (subject, value)
+    }
+  }
+
+  // Turns the precompiled rule tables on or off. Either way, calls match the same rules, and cost
+  // the same mana; only how fast the rules are found changes.
+  pub fn set_rule_tables(&mut self, enabled: bool) {
+    self.tables = enabled;
+  }
+
+  // Forgets the results of pure calls. Done at the start of each block.
+  pub fn clear_memo(&mut self) {
+    self.memo = Memo::default();
+  }
+
+  // Makes reduction fail with `RuntimeError::Timeout` from `deadline` on, or never, if None
+  pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+    self.deadline = Deadline { at: deadline, ..Deadline::default() };
+  }
+
+  // Whether the deadline passed while reducing
+  pub fn timed_out(&self) -> bool {
+    self.deadline.passed
+  }
+
+  // Sets the zstd level of the heap buffers saved from now on, or `None` to save them uncompressed
+  pub fn set_compression(&mut self, level: Option<i32>) {
+    self.compression = level;
+  }
+
+  // Fails `rate`% of the heap writes from now on, picked by a generator seeded with `seed`
+  pub fn set_disk_faults(&mut self, rate: u128, seed: u64) {
+    self.disk_faults = if rate > 0 { Some(DiskFaults { rate, rng: rand::SeedableRng::seed_from_u64(seed) }) } else { None };
+  }
+
+  // Fails if this heap write was picked to fail
+  fn check_disk_fault(&mut self) -> std::io::Result<()> {
+    if let Some(faults) = &mut self.disk_faults {
+      if rand::Rng::gen_range(&mut faults.rng, 0 .. 100) < faults.rate {
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, "chaos: injected disk failure"));
+      }
+    }
+    return Ok(());
+  }
+
+  // Sets the chain this runtime forked from, whose functions it fetches as they're mentioned
+  pub fn set_upstream(&mut self, upstream: Option<Arc<dyn Upstream>>) {
+    self.upstream = upstream;
+  }
+
+  pub fn get_upstream(&self) -> Option<Arc<dyn Upstream>> {
+    return self.upstream.clone();
+  }
+
+  // Defines the functions a statement mentions that only the upstream knows, along with the ones
+  // their code and state mention, and so on. Constructors are defined with the arities they're
+  // used with there. Names computed while running aren't seen, so they aren't fetched.
+  fn fetch_upstream(&mut self, statement: &Statement) {
+    let Some(upstream) = self.upstream.clone() else { return };
+    let (mut funs, _) = get_statement_refs(statement);
+    if let Statement::Ctr { name, .. } | Statement::Reg { name, .. } = statement {
+      funs.push(*name);
+    }
+    if let Statement::Fun { uses, .. } = statement {
+      funs.extend(uses.iter().map(|(used, _)| *used));
+    }
+    let mut ctrs = vec![]; // the statement's own constructors must be deployed by it
+    while let Some(name) = funs.pop() {
+      if name == 0 || self.exists(name) {
+        continue;
+      }
+      let Some((func, state)) = upstream.get_function(name) else { continue };
+      let Some(comp) = compile_func(&func, false) else { continue };
+      for rule in &func.rules {
+        get_term_refs(&rule.lhs, &mut funs, &mut ctrs);
+        get_term_refs(&rule.rhs, &mut funs, &mut ctrs);
+      }
+      if let Some(state) = &state {
+        get_term_refs(state, &mut funs, &mut ctrs);
+      }
+      // Before the state is built, as terms with constructors of unknown arity are built as `#0`
+      for (ctr, arity) in ctrs.drain(..) {
+        if !self.exists(ctr) {
+          self.define_constructor(ctr, arity);
+        }
+      }
+      self.define_function(name, comp);
+      if let Some(state) = state {
+        let state = self.create_term(&state, 0, &mut init_map());
+        self.write_disk(name, state);
+      }
+    }
+  }
+
+  // Records a trace entry. The entry is only built when tracing is enabled.
+  fn trace(&mut self, subject: u128, entry: impl FnOnce(&Runtime) -> String) {
+    if self.trace.is_some() {
+      let line = format!("[{}] {} ({} mana)", u128_to_name(subject), entry(self), self.get_mana());
+      if let Some(trace) = &mut self.trace {
+        trace.push(line);
+      }
+    }
+  }
+
   // IO
   // --
 
+  #[allow(clippy::only_used_in_recursion)]
   pub fn run_io(&mut self, subject: u128, caller: u128, host: u128, mana: u128) -> Result<Ptr, RuntimeError> {
     let term = reduce(self, host, mana)?;
     // eprintln!("-- {}", show_term(self, term));
@@ -1573,6 +2982,7 @@ impl Runtime {
         match get_ext(term) {
           IO_DONE => {
             let retr = ask_arg(self, term, 0);
+            self.trace(subject, |rt| format!("DONE {}", show_term(rt, retr, None)));
             clear(self, host, 1);
             clear(self, get_loc(term, 0), 1);
             return Ok(retr);
@@ -1580,8 +2990,12 @@ impl Runtime {
           IO_TAKE => {
             //println!("- IO_TAKE subject is {} {}", u128_to_name(subject), subject);
             let cont = ask_arg(self, term, 0);
+            if self.get_frozen(subject).is_some() {
+              return Err(RuntimeError::EffectFailure);
+            }
             if let Some(state) = self.read_disk(subject) {
               if state != 0 {
+                self.trace(subject, |rt| format!("TAKE {}", show_term(rt, state, None)));
                 self.write_disk(subject, 0);
                 let cont = alloc_app(self, cont, state);
                 let done = self.run_io(subject, subject, cont, mana);
@@ -1594,8 +3008,19 @@ impl Runtime {
           }
           IO_SAVE => {
             //println!("- IO_SAVE subject is {} {}", u128_to_name(subject), subject);
+            if self.get_frozen(subject).is_some() {
+              return Err(RuntimeError::EffectFailure);
+            }
             let expr = ask_arg(self, term, 0);
             let save = self.compute(expr, mana)?;
+            check_state_term(self, save, self.limits)?;
+            if self.is_active(Feature::StateSizeLimit) {
+              let size = state_size(&readback_term(self, save));
+              if size > self.state_limit {
+                return Err(RuntimeError::StateTooLarge { size, limit: self.state_limit });
+              }
+            }
+            self.trace(subject, |rt| format!("SAVE {}", show_term(rt, save, None)));
             self.write_disk(subject, save);
             let cont = ask_arg(self, term, 1);
             let cont = alloc_app(self, cont, Num(0));
@@ -1604,8 +3029,37 @@ impl Runtime {
             clear(self, get_loc(term, 0), 2);
             return done;
           }
+          IO_LOAD => {
+            let cont = ask_arg(self, term, 0);
+            if let Some(state) = self.read_disk(subject) {
+              if state != 0 {
+                self.trace(subject, |rt| format!("LOAD {}", show_term(rt, state, None)));
+                // Duplicates the state, keeping one copy and passing the other on
+                let node = alloc(self, 3);
+                let dupk = self.fresh_dups();
+                link(self, node + 2, state);
+                let cont = alloc_app(self, cont, Dp1(dupk, node));
+                let keep = self.compute(Dp0(dupk, node), mana)?;
+                self.write_disk(subject, keep);
+                let done = self.run_io(subject, subject, cont, mana);
+                clear(self, host, 1);
+                clear(self, get_loc(term, 0), 1);
+                return done;
+              }
+            }
+            return Err(RuntimeError::EffectFailure);
+          }
           IO_CALL => {
-            let fnid = ask_arg(self, term, 0);
+            // The name may come from a variable, so it's computed
+            let fnid = if self.is_active(Feature::EffectResults) {
+              let fnid = self.compute(ask_arg(self, term, 0), mana)?;
+              if get_tag(fnid) != NUM {
+                return Err(RuntimeError::EffectFailure);
+              }
+              fnid
+            } else {
+              ask_arg(self, term, 0)
+            };
             let tupl = ask_arg(self, term, 1);
             let cont = ask_arg(self, term, 2);
             // Builds the argument vector
@@ -1614,10 +3068,14 @@ impl Runtime {
             for i in 0 .. arit {
               args.push(ask_arg(self, tupl, i));
             }
+            self.trace(subject, |rt| format!("CALL {} {}", u128_to_name(get_num(fnid)), show_term(rt, tupl, None)));
             // Calls called function IO, changing the subject
             // TODO: this should not alloc a Fun as it's limited to 72-bit names
             let ioxp = alloc_fun(self, get_num(fnid), &args);
-            let retr = self.run_io(get_num(fnid), subject, ioxp, mana)?;
+            let lim  = self.call_mana(get_num(fnid), mana);
+            let retr = self.run_io(get_num(fnid), subject, ioxp, lim)?;
+            // With a call limit, the returned value is also computed under it
+            let retr = if lim < mana { self.compute(retr, lim)? } else { retr };
             // Calls the continuation with the value returned
             let cont = alloc_app(self, cont, retr);
             let done = self.run_io(subject, caller, cont, mana);
@@ -1629,6 +3087,7 @@ impl Runtime {
           }
           IO_SUBJ => {
             let cont = ask_arg(self, term, 0);
+            self.trace(subject, |rt| "SUBJ".to_string());
             let cont = alloc_app(self, cont, Num(subject));
             let done = self.run_io(subject, caller, cont, mana);
             clear(self, host, 1);
@@ -1637,7 +3096,9 @@ impl Runtime {
           }
           IO_FROM => {
             let cont = ask_arg(self, term, 0);
-            let cont = alloc_app(self, cont, Num(subject));
+            self.trace(subject, |rt| "FROM".to_string());
+            let from = if self.is_active(Feature::EffectResults) { caller } else { subject };
+            let cont = alloc_app(self, cont, Num(from));
             let done = self.run_io(subject, caller, cont, mana);
             clear(self, host, 1);
             clear(self, get_loc(term, 0), 1);
@@ -1645,44 +3106,235 @@ impl Runtime {
           }
           IO_TICK => {
             let cont = ask_arg(self, term, 0);
-            let cont = alloc_app(self, cont, Num(subject));
-            let done = self.run_io(subject, self.get_tick(), cont, mana);
+            self.trace(subject, |rt| "TICK".to_string());
+            let (value, caller) = self.block_info(subject, caller, self.get_tick());
+            let cont = alloc_app(self, cont, Num(value));
+            let done = self.run_io(subject, caller, cont, mana);
             clear(self, host, 1);
             clear(self, get_loc(term, 0), 1);
             return done;
           }
           IO_TIME => {
             let cont = ask_arg(self, term, 0);
-            let cont = alloc_app(self, cont, Num(subject));
-            let done = self.run_io(subject, self.get_time(), cont, mana);
+            self.trace(subject, |rt| "TIME".to_string());
+            let (value, caller) = self.block_info(subject, caller, self.get_time());
+            let cont = alloc_app(self, cont, Num(value));
+            let done = self.run_io(subject, caller, cont, mana);
             clear(self, host, 1);
             clear(self, get_loc(term, 0), 1);
             return done;
           }
           IO_META => {
             let cont = ask_arg(self, term, 0);
-            let cont = alloc_app(self, cont, Num(subject));
-            let done = self.run_io(subject, self.get_meta(), cont, mana);
+            self.trace(subject, |rt| "META".to_string());
+            let (value, caller) = self.block_info(subject, caller, self.get_meta());
+            let cont = alloc_app(self, cont, Num(value));
+            let done = self.run_io(subject, caller, cont, mana);
             clear(self, host, 1);
             clear(self, get_loc(term, 0), 1);
             return done;
           }
           IO_HAX0 => {
             let cont = ask_arg(self, term, 0);
-            let cont = alloc_app(self, cont, Num(subject));
-            let done = self.run_io(subject, self.get_hax0(), cont, mana);
+            self.trace(subject, |rt| "HAX0".to_string());
+            let (value, caller) = self.block_info(subject, caller, self.get_hax0());
+            let cont = alloc_app(self, cont, Num(value));
+            let done = self.run_io(subject, caller, cont, mana);
             clear(self, host, 1);
             clear(self, get_loc(term, 0), 1);
             return done;
           }
           IO_HAX1 => {
             let cont = ask_arg(self, term, 0);
-            let cont = alloc_app(self, cont, Num(subject));
-            let done = self.run_io(subject, self.get_hax1(), cont, mana);
+            self.trace(subject, |rt| "HAX1".to_string());
+            let (value, caller) = self.block_info(subject, caller, self.get_hax1());
+            let cont = alloc_app(self, cont, Num(value));
+            let done = self.run_io(subject, caller, cont, mana);
+            clear(self, host, 1);
+            clear(self, get_loc(term, 0), 1);
+            return done;
+          }
+          IO_RAND => {
+            let cont = ask_arg(self, term, 0);
+            self.trace(subject, |rt| "RAND".to_string());
+            let cont = alloc_app(self, cont, Num(self.get_rand()));
+            let done = self.run_io(subject, caller, cont, mana);
             clear(self, host, 1);
             clear(self, get_loc(term, 0), 1);
             return done;
           }
+          IO_HOOK => {
+            let name = get_num(ask_arg(self, term, 0));
+            let on   = get_num(ask_arg(self, term, 1));
+            let cont = ask_arg(self, term, 2);
+            if subject == 0 || name == 0 {
+              return Err(RuntimeError::EffectFailure);
+            }
+            self.trace(subject, |rt| format!("HOOK {} {}", u128_to_name(name), on));
+            let mut hooks = self.get_hooks(name);
+            hooks.retain(|hook| *hook != subject);
+            if on != 0 {
+              if hooks.len() >= MAX_HOOKS {
+                return Err(RuntimeError::EffectFailure);
+              }
+              if self.get_mana() + HOOK_MANA > mana {
+                return Err(RuntimeError::NotEnoughMana);
+              }
+              self.set_mana(self.get_mana() + HOOK_MANA);
+              hooks.push(subject);
+            }
+            self.set_hooks(name, &hooks);
+            let cont = alloc_app(self, cont, Num(0));
+            let done = self.run_io(subject, caller, cont, mana);
+            clear(self, host, 1);
+            clear(self, get_loc(term, 0), 3);
+            return done;
+          }
+          IO_EMIT => {
+            if subject == 0 {
+              return Err(RuntimeError::EffectFailure);
+            }
+            let event = self.compute(ask_arg(self, term, 0), mana)?;
+            self.trace(subject, |rt| format!("EMIT {}", show_term(rt, event, None)));
+            check_term_size(self, event, self.limits)?;
+            self.events.push((subject, readback_linear_term(self, event)));
+            self.collect(event);
+            let cont = ask_arg(self, term, 1);
+            let cont = alloc_app(self, cont, Num(0));
+            let done = self.run_io(subject, caller, cont, mana);
+            clear(self, host, 1);
+            clear(self, get_loc(term, 0), 2);
+            return done;
+          }
+          IO_HASH => {
+            let expr = self.compute_data(ask_arg(self, term, 0), mana)?;
+            self.trace(subject, |rt| format!("HASH {}", show_term(rt, expr, None)));
+            check_term_size(self, expr, self.limits)?;
+            let hash = hash_term(&readback_linear_term(self, expr));
+            self.collect(expr);
+            let cont = ask_arg(self, term, 1);
+            let cont = alloc_app(self, cont, Num(crypto::Name::from_hash(&hash).0));
+            let done = self.run_io(subject, caller, cont, mana);
+            clear(self, host, 1);
+            clear(self, get_loc(term, 0), 2);
+            return done;
+          }
+          IO_OWNS => {
+            let name  = self.compute(ask_arg(self, term, 0), mana)?;
+            let owner = self.compute(ask_arg(self, term, 1), mana)?;
+            if subject != NAMES || get_tag(name) != NUM || get_tag(owner) != NUM {
+              return Err(RuntimeError::EffectFailure);
+            }
+            let (name, owner) = (get_num(name), get_num(owner));
+            self.trace(subject, |rt| format!("OWNS {} #x{:0>30x}", u128_to_name(name), owner));
+            // Only namespaces nobody owns yet are given
+            let given = is_short_name(name) && owner != 0 && self.get_owner(name) == U128_NONE;
+            if given {
+              self.set_owner(name, owner);
+            }
+            let cont = ask_arg(self, term, 2);
+            let cont = alloc_app(self, cont, Num(given as u128));
+            let done = self.run_io(subject, caller, cont, mana);
+            clear(self, host, 1);
+            clear(self, get_loc(term, 0), 3);
+            return done;
+          }
+          IO_FRZE => {
+            let name = self.compute(ask_arg(self, term, 0), mana)?;
+            if get_tag(name) != NUM {
+              return Err(RuntimeError::EffectFailure);
+            }
+            let name = get_num(name);
+            let owner = get_namespace(name).map(|namespace| self.get_owner(namespace));
+            let allowed = subject == name || (subject != 0 && owner == Some(subject));
+            if !allowed || self.get_func(name).is_none() {
+              return Err(RuntimeError::EffectFailure);
+            }
+            self.trace(subject, |rt| format!("FRZE {}", u128_to_name(name)));
+            if self.get_frozen(name).is_none() {
+              self.set_frozen(name, self.get_tick());
+            }
+            let cont = ask_arg(self, term, 1);
+            let cont = alloc_app(self, cont, Num(0));
+            let done = self.run_io(subject, caller, cont, mana);
+            clear(self, host, 1);
+            clear(self, get_loc(term, 0), 2);
+            return done;
+          }
+          IO_SCHD => {
+            let tick = self.compute(ask_arg(self, term, 0), mana)?;
+            let cost = self.compute(ask_arg(self, term, 1), mana)?;
+            if subject == 0 || get_tag(tick) != NUM || get_num(tick) <= self.get_tick() || get_tag(cost) != NUM {
+              return Err(RuntimeError::EffectFailure);
+            }
+            let (tick, cost) = (get_num(tick), get_num(cost));
+            if self.get_mana() + SCHEDULE_MANA + cost > mana {
+              return Err(RuntimeError::NotEnoughMana);
+            }
+            self.set_mana(self.get_mana() + SCHEDULE_MANA + cost);
+            let expr = self.compute(ask_arg(self, term, 2), mana)?;
+            self.trace(subject, |rt| format!("SCHD {} {} {}", tick, cost, show_term(rt, expr, None)));
+            // Prepends the action to the tick's list
+            let node = alloc(self, 4);
+            link(self, node + 0, Num(subject));
+            link(self, node + 1, Num(cost));
+            link(self, node + 2, expr);
+            link(self, node + 3, self.get_schedule(tick));
+            self.set_schedule(tick, Ctr(T4, node));
+            let cont = ask_arg(self, term, 3);
+            let cont = alloc_app(self, cont, Num(0));
+            let done = self.run_io(subject, caller, cont, mana);
+            clear(self, host, 1);
+            clear(self, get_loc(term, 0), 4);
+            return done;
+          }
+          IO_AUTH => {
+            let func = ask_arg(self, term, 0);
+            let cont = ask_arg(self, term, 1);
+            let func = get_num(func);
+            if subject == 0 || (func != 0 && self.get_func(func).is_none()) {
+              return Err(RuntimeError::EffectFailure);
+            }
+            self.trace(subject, |rt| format!("AUTH {}", u128_to_name(func)));
+            self.set_authorizer(subject, func);
+            let cont = alloc_app(self, cont, Num(0));
+            let done = self.run_io(subject, caller, cont, mana);
+            clear(self, host, 1);
+            clear(self, get_loc(term, 0), 2);
+            return done;
+          }
+          IO_ACTS => {
+            let name  = get_num(ask_arg(self, term, 0));
+            let proof = ask_arg(self, term, 1);
+            let expr  = ask_arg(self, term, 2);
+            let cont  = ask_arg(self, term, 3);
+            let auth  = self.get_authorizer(name).ok_or(RuntimeError::EffectFailure)?;
+            if self.get_arity(auth) != 1 {
+              return Err(RuntimeError::EffectFailure);
+            }
+            self.trace(subject, |rt| format!("ACTS {} {}", u128_to_name(name), show_term(rt, proof, None)));
+            // Asks the authorizer to validate the proof
+            let node = alloc(self, 2);
+            link(self, node + 0, Num(subject));
+            link(self, node + 1, proof);
+            let ioxp = alloc_fun(self, auth, &[Ctr(AUTHORIZE, node)]);
+            let answ = self.run_io(auth, subject, ioxp, self.call_mana(auth, mana))?;
+            let answ = self.compute(answ, mana)?;
+            if get_tag(answ) != NUM || get_num(answ) != 1 {
+              self.collect(answ);
+              self.collect(expr);
+              return Err(RuntimeError::EffectFailure);
+            }
+            // Runs the IO operation as the authorized name
+            let ioxp = alloc_lnk(self, expr);
+            let retr = self.run_io(name, subject, ioxp, mana)?;
+            // Calls the continuation with the value returned
+            let cont = alloc_app(self, cont, retr);
+            let done = self.run_io(subject, caller, cont, mana);
+            clear(self, host, 1);
+            clear(self, get_loc(term, 0), 4);
+            return done;
+          }
           _ => {
             return Err(RuntimeError::EffectFailure);
           }
@@ -1694,14 +3346,134 @@ impl Runtime {
     }
   }
 
+  // Runs the actions scheduled for the current tick, in the order they were scheduled, each one as
+  // the subject that scheduled it, with the mana it was given. A failing action is undone without
+  // affecting the others, but still uses the mana it spent.
+  pub fn run_scheduled(&mut self, silent: bool) {
+    let tick = self.get_tick();
+    let mut list = self.get_schedule(tick);
+    let mut actions = vec![];
+    while get_tag(list) == CTR {
+      let subj = get_num(ask_arg(self, list, 0));
+      let cost = get_num(ask_arg(self, list, 1));
+      let expr = ask_arg(self, list, 2);
+      let next = ask_arg(self, list, 3);
+      clear(self, get_loc(list, 0), 4);
+      actions.push((subj, cost, expr));
+      list = next;
+    }
+    if actions.is_empty() {
+      return;
+    }
+    self.set_schedule(tick, Num(0));
+    self.draw();
+    for (subj, cost, expr) in actions.into_iter().rev() {
+      let mana_lim = std::cmp::min(self.get_mana().saturating_add(cost), self.get_mana_limit());
+      let host = alloc_lnk(self, expr);
+      let done = self.run_io(subj, subj, host, mana_lim).and_then(|done| self.compute(done, mana_lim));
+      match done {
+        Ok(done) => {
+          self.collect(done);
+          self.draw();
+          if !silent {
+            println!("[schd] {}", u128_to_name(subj));
+          }
+          self.run_hooks(silent);
+        }
+        Err(err) => {
+          let used = self.get_mana();
+          self.undo();
+          self.set_mana(used);
+          self.draw();
+          self.events.clear();
+          if !silent {
+            println!("[schd] Error. {}", show_runtime_error(err));
+          }
+        }
+      }
+    }
+  }
+
+  // Delivers the pending events to their subscribers, including the events emitted while doing so.
+  // Each delivery is limited to HOOK_MANA_LIMIT, and a failing one is undone without affecting the
+  // others, but still uses the mana it spent.
+  pub fn run_hooks(&mut self, silent: bool) {
+    let mut next = 0;
+    while next < self.events.len() {
+      let (name, event) = self.events[next].clone();
+      next += 1;
+      self.emitted.push((name, event.clone()));
+      for hook in self.get_hooks(name) {
+        if self.get_func(hook).is_none() || self.get_arity(hook) != 1 {
+          continue;
+        }
+        let mana_lim = self.call_mana(hook, std::cmp::min(self.get_mana() + HOOK_MANA_LIMIT, self.get_mana_limit()));
+        let pending = self.events.len();
+        let argm = Term::Ctr { name: EVENT, args: vec![Term::Num { numb: name }, event.clone()] };
+        let host = self.alloc_term(&Term::Fun { name: hook, args: vec![argm] });
+        let done = self.run_io(hook, name, host, mana_lim).and_then(|done| self.compute(done, mana_lim));
+        match done {
+          Ok(done) => {
+            self.collect(done);
+            self.draw();
+            if !silent {
+              println!("[hook] {} -> {}", u128_to_name(name), u128_to_name(hook));
+            }
+          }
+          Err(err) => {
+            let used = self.get_mana();
+            self.undo();
+            self.set_mana(used);
+            self.draw();
+            self.events.truncate(pending);
+            if !silent {
+              println!("[hook] Error. {}", show_runtime_error(err));
+            }
+          }
+        }
+      }
+    }
+    self.events.clear();
+  }
+
+  // Runs a statement and, if it succeeds, delivers the events it emitted. The mana the deliveries
+  // use is charged to the statement, which burns its fees too.
+  pub fn run_statement_with_hooks(&mut self, statement: &Statement, silent: bool) -> StatementResult {
+    let mut res = self.run_statement(statement, silent);
+    if let Ok(info) = &mut res {
+      self.draw();
+      let mana_ini = self.get_mana();
+      self.run_hooks(silent);
+      let mana_dif = self.get_mana() - mana_ini;
+      if let StatementInfo::Run { used_mana, .. } = info {
+        *used_mana += mana_dif;
+        if self.is_active(Feature::ManaMarket) {
+          self.set_burned(self.get_burned().saturating_add(mana_dif.saturating_mul(self.get_mana_price())));
+          self.draw();
+        }
+      }
+    }
+    return res;
+  }
+
   // Gets the subject of a signature
   pub fn get_subject(&mut self, sign: &Option<crypto::Signature>, hash: crypto::Hash) -> u128 {
     match sign {
       None       => 0,
-      Some(sign) => sign.signer_name(&hash).map(|x| x.0).unwrap_or(1),
+      Some(sign) => self.sigs.signer_name(sign, &hash).map(|x| x.0).unwrap_or(1),
     }
   }
 
+  // Recovers the signer of a statement ahead of time, so that running it later hits the cache
+  pub fn precheck_signature(&mut self, statement: &Statement) -> u128 {
+    let hash = sign_hash(statement);
+    return self.get_subject(get_sign(statement), hash);
+  }
+
+  pub fn get_signature_cache(&self) -> &crypto::SignatureCache {
+    return &self.sigs;
+  }
+
   // Can this subject deploy this name?
   pub fn can_deploy(&mut self, subj: u128, name: u128) -> bool {
     if name == 0 {
@@ -1726,6 +3498,9 @@ impl Runtime {
     if name == 0 {
       // anyone can register the empty namespace (should happen on Genesis Block)
       return true;
+    } else if is_short_name(name) && self.exists(NAMES) {
+      // short namespaces are given by the registry's auctions
+      return false;
     } else {
       // only namespace owner can register a sub-namespace
       return subj == self.get_owner(get_namespace(name).unwrap_or(0));
@@ -1737,48 +3512,77 @@ impl Runtime {
   /// It doesn't alter `curr` heap.
   #[allow(clippy::useless_format)]
   pub fn run_statement(&mut self, statement: &Statement, silent: bool) -> StatementResult {
-    fn error(rt: &mut Runtime, tag: &str, err: String) -> StatementResult {
+    fn error(rt: &mut Runtime, silent: bool, tag: &str, err: String) -> StatementResult {
       rt.undo();
-      println!("[{}] Error. {}", tag, err);
+      rt.events.clear();
+      if !silent {
+        println!("[{}] Error. {}", tag, err);
+      }
       return Err(StatementErr { err });
     }
-    let hash = hash_statement(statement);
+    self.fault = None;
+    if let Err(rejection) = check_statement(statement, self.stmt_limits) {
+      return error(self, silent, "statement", show_statement_rejection(rejection));
+    }
+    self.fetch_upstream(statement);
+    let hash = sign_hash(statement);
     match statement {
-      Statement::Fun { name, args, func, init, sign } => {
+      Statement::Fun { name, args, func, init, mana, strict, pure, uses, sign } => {
         if self.exists(*name) {
-          return error(self, "fun", format!("Can't redefine '{}'.", u128_to_name(*name)));
+          return error(self, silent, "fun", format!("Can't redefine '{}'.", u128_to_name(*name)));
+        }
+        for (used, code) in uses {
+          match self.get_func(*used) {
+            None => {
+              return error(self, silent, "fun", format!("Used function '{}' isn't deployed.", u128_to_name(*used)));
+            }
+            Some(used_func) if U256::from_big_endian(&hash_func(*used, &used_func.func).0) != *code => {
+              return error(self, silent, "fun", format!("Used function '{}' doesn't have the pinned code.", u128_to_name(*used)));
+            }
+            Some(_) => {}
+          }
         }
         let subj = self.get_subject(&sign, hash);
         if !self.can_deploy(subj, *name) {
-          return error(self, "fun", format!("Subject '#x{:0>30x}' not allowed to deploy '{}'.", subj, u128_to_name(*name)));
+          return error(self, silent, "fun", format!("Subject '#x{:0>30x}' not allowed to deploy '{}'.", subj, u128_to_name(*name)));
         }
         if !self.check_func(&func) {
-          return error(self, "fun", format!("Invalid function {}.", u128_to_name(*name)));
+          return error(self, silent, "fun", format!("Invalid function {}.", u128_to_name(*name)));
+        }
+        if let Err(err) = self.check_func_arities(*name, args.len() as u128, func, init) {
+          return error(self, silent, "fun", show_runtime_error(err));
         }
         let func = compile_func(func, true);
         if func.is_none() {
-          return error(self, "fun", format!("Invalid function {}.", u128_to_name(*name)));
+          return error(self, silent, "fun", format!("Invalid function {}.", u128_to_name(*name)));
+        }
+        if let Some(arg) = strict.iter().find(|arg| **arg >= args.len() as u128) {
+          return error(self, silent, "fun", format!("Invalid strict argument {} of {}.", arg, u128_to_name(*name)));
         }
-        let func = func.unwrap();
+        let mut func = func.unwrap().with_strict(strict);
+        func.pure = *pure;
         if !silent {
           println!("[fun] {}", u128_to_name(*name));
         }
         self.set_arity(*name, args.len() as u128);
         self.define_function(*name, func);
+        if let Some(mana) = mana {
+          self.set_call_limit(*name, *mana);
+        }
         let state = self.create_term(init, 0, &mut init_map());
         self.write_disk(*name, state);
         Ok(StatementInfo::Fun { name: *name, args: args.clone() })
       }
       Statement::Ctr { name, args, sign } => {
         if self.exists(*name) {
-          return error(self, "ctr", format!("Can't redefine '{}'.", u128_to_name(*name)));
+          return error(self, silent, "ctr", format!("Can't redefine '{}'.", u128_to_name(*name)));
         }
         let subj = self.get_subject(&sign, hash);
         if !self.can_deploy(subj, *name) {
-          return error(self, "ctr", format!("Subject '#x{:0>30x}' not allowed to deploy '{}'.", subj, u128_to_name(*name)));
+          return error(self, silent, "ctr", format!("Subject '#x{:0>30x}' not allowed to deploy '{}'.", subj, u128_to_name(*name)));
         }
         if args.len() > 16 {
-          return error(self, "ctr", format!("Can't define contructor with arity larger than 16."));
+          return error(self, silent, "ctr", format!("Can't define contructor with arity larger than 16."));
         }
         if !silent {
           println!("[ctr] {}", u128_to_name(*name));
@@ -1786,66 +3590,157 @@ impl Runtime {
         self.set_arity(*name, args.len() as u128);
         Ok(StatementInfo::Ctr { name: *name, args: args.clone() })
       }
-      Statement::Run { expr, sign } => {
+      Statement::Run { expr, price, sign } => {
+        let market = self.is_active(Feature::ManaMarket);
+        let base_price = self.get_mana_price();
+        let max_price = price.unwrap_or(INITIAL_MANA_PRICE);
+        if market && max_price < base_price {
+          return error(self, silent, "run", format!("Max mana price of {} is below the base price of {}.", max_price, base_price));
+        }
         let mana_ini = self.get_mana(); 
         let mana_lim = self.get_mana_limit();
         let size_ini = self.get_size();
         let size_lim = self.get_size_limit(); 
         if !self.check_term(expr) {
-          return error(self, "run", format!("Invalid term."));
+          return error(self, silent, "run", format!("Invalid term."));
+        }
+        if let Err(err) = self.check_term_arities(expr, None) {
+          return error(self, silent, "run", show_runtime_error(err));
         }
         let subj = self.get_subject(&sign, hash);
         let host = self.alloc_term(expr);
         let done = self.run_io(subj, 0, host, mana_lim);
         if let Err(err) = done {
-          return error(self, "run", show_runtime_error(err));
+          return error(self, silent, "run", show_runtime_error(err));
         }
         let done = done.unwrap();
         let done = self.compute(done, mana_lim);
         if let Err(err) = done {
-          return error(self, "run", show_runtime_error(err));
+          return error(self, silent, "run", show_runtime_error(err));
         }
         let done = done.unwrap();
+        if let Err(err) = check_term_size(self, done, self.limits) {
+          return error(self, silent, "run", show_runtime_error(err));
+        }
         let term = readback_linear_term(self, done);
         self.collect(done);
         let size_end = self.get_size();
-        let mana_dif = self.get_mana() - mana_ini;
         let size_dif = size_end - size_ini;
         if size_end > size_lim {
-          return error(self, "run", format!("Not enough space."));
+          return error(self, silent, "run", format!("Not enough space."));
+        }
+        let refund = compute_refund(self.get_mana() - mana_ini, size_dif);
+        self.set_mana(self.get_mana() - refund);
+        let mana_dif = self.get_mana() - mana_ini;
+        if market {
+          self.set_burned(self.get_burned().saturating_add(mana_dif.saturating_mul(base_price)));
         }
         if !silent {
-          println!("[run] {} \x1b[2m[{} mana | {} size]\x1b[0m", view_term(&term), mana_dif, size_dif);
+          println!("[run] {} \x1b[2m[{} mana | {} refunded | {} size]\x1b[0m", view_term(&term), mana_dif, refund, size_dif);
         }
         Ok(StatementInfo::Run {
           done_term: term,
           used_mana: mana_dif,
+          refunded_mana: refund,
           size_diff: size_dif,
           end_size: size_end as u128, // TODO: rename to done_size for consistency?
         })
       }
       Statement::Reg { name, ownr, sign } => {
         if self.exists(*name) {
-          return error(self, "run", format!("Can't redefine '{}'.", u128_to_name(*name)));
+          return error(self, silent, "run", format!("Can't redefine '{}'.", u128_to_name(*name)));
         }
         let subj = self.get_subject(sign, hash);
         if !self.can_register(subj, *name) {
-          return error(self, "run", format!("Subject '#x{:0>30x}' not allowed to register '{}'.", subj, u128_to_name(*name)));
+          return error(self, silent, "run", format!("Subject '#x{:0>30x}' not allowed to register '{}'.", subj, u128_to_name(*name)));
         }
         self.set_owner(*name, *ownr);
         if !silent {
           println!("[reg] #x{:0>30x} {}", ownr, u128_to_name(*name));
         }
-        Ok(StatementInfo::Reg {
-          name: *name,
-          ownr: *ownr,
-        })
+        Ok(StatementInfo::Reg {
+          name: *name,
+          ownr: *ownr,
+        })
+      }
+      Statement::Rot { name, succ, delay, sign } => {
+        let ownr = self.get_owner(*name);
+        if ownr == U128_NONE {
+          return error(self, silent, "rot", format!("Namespace '{}' isn't registered.", u128_to_name(*name)));
+        }
+        let subj = self.get_subject(sign, hash);
+        if subj != ownr {
+          return error(self, silent, "rot", format!("Subject '#x{:0>30x}' doesn't own '{}'.", subj, u128_to_name(*name)));
+        }
+        let Some(tick) = self.get_tick().checked_add(*delay) else {
+          return error(self, silent, "rot", format!("Delay of {} blocks is too long.", delay));
+        };
+        // Settles a rotation that already took over, as the new one replaces it
+        self.set_owner(*name, ownr);
+        self.set_rotation(*name, *succ, tick);
+        if !silent {
+          println!("[rot] #x{:0>30x} {} at tick {}", succ, u128_to_name(*name), tick);
+        }
+        Ok(StatementInfo::Rot {
+          name: *name,
+          succ: *succ,
+          tick,
+        })
+      }
+    }
+  }
+
+  pub fn check_term(&self, term: &Term) -> bool {
+    return self.check_term_depth(term, 0) && is_linear(term);
+  }
+
+  // Checks that every constructor and function in a term is applied to as many arguments as it was
+  // declared with, so statements are rejected before they run. `own` is the name and arity of a
+  // function being deployed, which isn't declared yet, but can call itself. Its rules may also name
+  // constructors and functions declared later; those are checked when the rules are applied.
+  pub fn check_term_arities(&self, term: &Term, own: Option<(u128, u128)>) -> Result<(), RuntimeError> {
+    let mut stack = vec![term];
+    while let Some(term) = stack.pop() {
+      match term {
+        Term::Var { .. } | Term::Num { .. } => {}
+        Term::Dup { expr, body, .. } => {
+          stack.push(expr);
+          stack.push(body);
+        }
+        Term::Lam { body, .. } => {
+          stack.push(body);
+        }
+        Term::App { func, argm } => {
+          stack.push(func);
+          stack.push(argm);
+        }
+        Term::Op2 { val0, val1, .. } => {
+          stack.push(val0);
+          stack.push(val1);
+        }
+        Term::Ctr { name, args } | Term::Fun { name, args } => {
+          let expected = match own {
+            Some((own, arity)) if own == *name => arity,
+            _ => self.get_arity(*name),
+          };
+          let later = expected == U128_NONE && own.is_some();
+          if !later && expected != args.len() as u128 {
+            return Err(RuntimeError::ArityMismatch { name: *name, expected, found: args.len() as u128 });
+          }
+          stack.extend(args.iter());
+        }
       }
     }
+    return Ok(());
   }
 
-  pub fn check_term(&self, term: &Term) -> bool {
-    return self.check_term_depth(term, 0) && is_linear(term); // && self.check_term_arities(term)
+  // Checks the arities of the rules and initial state of a function being deployed
+  pub fn check_func_arities(&self, name: u128, arity: u128, func: &Func, init: &Term) -> Result<(), RuntimeError> {
+    for rule in &func.rules {
+      self.check_term_arities(&rule.lhs, Some((name, arity)))?;
+      self.check_term_arities(&rule.rhs, Some((name, arity)))?;
+    }
+    return self.check_term_arities(init, Some((name, arity)));
   }
 
   pub fn check_func(&self, func: &Func) -> bool {
@@ -1928,10 +3823,20 @@ impl Runtime {
   // Advances the heap time counter, saving past states for rollback.
   pub fn tick(&mut self) {
     self.set_tick(self.get_tick() + 1);
+    if self.get_tick().is_multiple_of(COMPACT_INTERVAL) {
+      self.compact();
+    }
     self.draw();
     self.snapshot();
   }
 
+  // Advances the heap time counter without saving a snapshot. Used by scratch runtimes, which
+  // are thrown away after use and never persisted.
+  pub fn tick_scratch(&mut self) {
+    self.set_tick(self.get_tick() + 1);
+    self.draw();
+  }
+
   pub fn snapshot(&mut self) {
     //println!("tick self.curr={}", self.curr);
     let (included, absorber, deleted, rollback) = rollback_push(self.curr, self.back.clone(), 0);
@@ -1939,15 +3844,29 @@ impl Runtime {
     self.back = rollback;
     // println!(" - back {}", view_rollback(&self.back));
     if included {
-      self.save_state_metadata().expect("Error saving state metadata.");
+      // A failed write leaves the saved state behind the one in memory, which goes on. The node
+      // finds it damaged when it starts again, and replays the blocks instead.
       let path = &self.get_dir_path();
-      self.heap[self.curr as usize].save_buffers(path).expect("Error saving buffers."); // TODO: persistence-WIP
+      let saved = self.check_disk_fault().and_then(|()| self.save_state_metadata());
+      let saved = saved.and_then(|()| self.check_disk_fault());
+      let saved = saved.and_then(|()| self.heap[self.curr as usize].save_buffers(path, self.compression));
+      if let Err(err) = saved {
+        eprintln!("Couldn't save the heap: {}", err);
+      }
       if let Some(deleted) = deleted {
         if let Some(absorber) = absorber {
           self.absorb_heap(absorber, deleted, false);
-          self.heap[absorber as usize].append_buffers(self.heap[deleted as usize].uuid, path).expect("Couldn't append buffers."); // TODO: persistence-WIP
+          let uuid = self.heap[deleted as usize].uuid;
+          let base = std::mem::take(&mut self.heap[deleted as usize].base);
+          let rebased = self.check_disk_fault();
+          let rebased = rebased.and_then(|()| self.heap[absorber as usize].rebase(base, uuid, path, self.compression));
+          let rebased = rebased.and_then(|()| self.heap[deleted as usize].delete_nodes(path));
+          if let Err(err) = rebased {
+            eprintln!("Couldn't save the absorbed heap: {}", err);
+          }
+        } else if let Err(err) = self.heap[deleted as usize].delete_buffers(path) {
+          eprintln!("Couldn't delete the heap: {}", err);
         }
-        self.heap[deleted as usize].delete_buffers(path).expect("Couldn't delete buffers.");
         self.clear_heap(deleted);
         self.curr = deleted;
       } else if let Some(empty) = self.nuls.pop() {
@@ -1986,6 +3905,105 @@ impl Runtime {
     // println!("- rolled back to {}", self.get_tick());
   }
 
+  // Drops every change since the newest snapshot, including those of the statement being run, and
+  // returns the tick reached. Used to abort a block, which may take earlier unsaved ticks with it.
+  pub fn discard(&mut self) -> u128 {
+    self.undo();
+    self.clear_heap(self.curr);
+    self.events.clear();
+    return self.get_tick();
+  }
+
+  // Runs `run` on the state, then puts the state back as it was, returning what `run` returned.
+  // Used to try a block on top of the tip. Only the changes since the newest snapshot are copied,
+  // to be restored.
+  pub fn simulate<T>(&mut self, run: impl FnOnce(&mut Runtime) -> T) -> T {
+    let curr = self.heap[self.curr as usize].clone();
+    let touched = self.touched.clone();
+    let result = run(self);
+    self.undo();
+    self.heap[self.curr as usize] = curr;
+    self.touched = touched;
+    self.events.clear();
+    self.clear_memo();
+    return result;
+  }
+
+  // Ticks of the retained snapshots, newest first
+  pub fn get_snapshot_ticks(&self) -> Vec<u128> {
+    let mut ticks = vec![];
+    let mut back = &self.back;
+    while let Rollback::Cons { head, tail, .. } = &**back {
+      let tick = self.get_heap(*head).get_tick();
+      ticks.push(if tick == U128_NONE { 0 } else { tick });
+      back = tail;
+    }
+    return ticks;
+  }
+
+  // Builds a scratch runtime holding the state of the newest retained snapshot at or before
+  // `tick`. Returns None if all retained snapshots are newer than that. The scratch runtime
+  // doesn't share heaps with this one, so it can be advanced freely with `tick_scratch`.
+  pub fn fork_at(&self, tick: u128) -> Option<Runtime> {
+    let mut heaps = vec![];
+    let mut back = &self.back;
+    while let Rollback::Cons { head, tail, .. } = &**back {
+      let heap = self.get_heap(*head);
+      let heap_tick = if heap.get_tick() == U128_NONE { 0 } else { heap.get_tick() };
+      if heap_tick <= tick || !heaps.is_empty() {
+        heaps.push(heap.clone());
+      }
+      back = tail;
+    }
+    if heaps.is_empty() {
+      return None;
+    }
+    let mut rt = Runtime {
+      heap: vec![init_heap(), init_heap()],
+      draw: 0,
+      curr: 1,
+      nuls: vec![],
+      back: Arc::new(Rollback::Nil),
+      path: self.path.clone(),
+      trace: None,
+      rewrites: None,
+      events: vec![],
+      limits: self.limits,
+      stmt_limits: self.stmt_limits,
+      state_limit: self.state_limit,
+      activations: self.activations,
+      sigs: crypto::SignatureCache::new(SIGNATURE_CACHE_SIZE),
+      upstream: self.upstream.clone(),
+      touched: HashSet::new(),
+      tables: self.tables,
+      memo: Memo::default(),
+      deadline: Deadline::default(),
+      compression: self.compression,
+      fault: None,
+      emitted: vec![],
+      disk_faults: None,
+    };
+    for heap in heaps.into_iter().rev() {
+      let head = rt.heap.len() as u64;
+      rt.heap.push(heap);
+      rt.back = Arc::new(Rollback::Cons { keep: 0, life: 0, head, tail: rt.back.clone() });
+    }
+    return Some(rt);
+  }
+
+  // Builds a scratch runtime holding the current state: the retained snapshots, as `fork_at`, and
+  // the changes made since the newest of them.
+  pub fn fork_tip(&self) -> Runtime {
+    let mut rt = self.fork_at(U128_NONE).unwrap_or_else(init_scratch_runtime);
+    rt.heap[rt.curr as usize] = self.heap[self.curr as usize].clone();
+    rt.set_rule_tables(self.tables);
+    rt.set_statement_limits(self.stmt_limits);
+    rt.set_state_limit(self.state_limit);
+    rt.set_activations(self.activations);
+    rt.set_upstream(self.get_upstream());
+    return rt;
+  }
+
   // Persistence
   // -----------
 
@@ -2019,8 +4037,19 @@ impl Runtime {
     return Ok(());
   }
 
-  // Restores the saved state. This loads the persisted Rollback list and its heaps.
+  // Restores the saved state, checking the integrity of the restored heap
   pub fn restore_state(&mut self) -> std::io::Result<()> {
+    self.restore_state_unchecked()?;
+    let faults = check_heap(self);
+    if let Some(fault) = faults.first() {
+      let erro = format!("Corrupted heap ({} faults). {}", faults.len(), show_heap_fault(fault));
+      return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, erro));
+    }
+    return Ok(());
+  }
+
+  // Restores the saved state. This loads the persisted Rollback list and its heaps.
+  pub fn restore_state_unchecked(&mut self) -> std::io::Result<()> {
     for i in 0 .. MAX_HEAPS {
       self.heap[i as usize].clear();
     }
@@ -2092,7 +4121,7 @@ impl Runtime {
           if val != none {
             return val;
           }
-          back = &*tail;
+          back = tail;
         }
         Rollback::Nil => {
           return zero;
@@ -2120,7 +4149,7 @@ impl Runtime {
           if let Some(func) = got {
             return Some(func);
           }
-          back = &*tail;
+          back = tail;
         }
         Rollback::Nil => {
           return None;
@@ -2135,7 +4164,7 @@ impl Runtime {
     let mut back = &self.back;
     while let Rollback::Cons { keep: _, life, head, tail } = &**back {
       reduce(acc, self.get_heap(*head));
-      back = &*tail;
+      back = tail;
     }
   }
 
@@ -2148,16 +4177,31 @@ impl Runtime {
   }
 
   pub fn write_disk(&mut self, fid: u128, val: Ptr) {
+    self.touched.insert(fid);
     return self.get_heap_mut(self.draw).write_disk(fid, val);
   }
 
+  // Names whose state or code may have changed since the last call. Includes the ones written by
+  // statements that were undone.
+  pub fn take_touched(&mut self) -> HashSet<u128> {
+    return std::mem::take(&mut self.touched);
+  }
+
+  // Events delivered since the last call, in order, with the names that emitted them. Events of
+  // statements and hooks that failed aren't delivered, so they aren't included.
+  pub fn take_emitted(&mut self) -> Vec<(u128, Term)> {
+    return std::mem::take(&mut self.emitted);
+  }
+
   pub fn read_disk(&self, fid: u128) -> Option<Ptr> {
     return self.get_with(Some(0), None, |heap| heap.read_disk(fid));
   }
 
+  // Reads back the state of a name. None if it never had one (unlike `read_disk`, which defaults
+  // to the null pointer).
   pub fn read_disk_as_term(&mut self, fid: u128) -> Option<Term> {
-    let host = self.read_disk(fid)?;
-    let term = readback_linear_term(self, host);
+    let host = self.get_with(None, None, |heap| heap.read_disk(fid))?;
+    let term = readback_term(self, host);
     Some(term)
   }
 
@@ -2178,6 +4222,11 @@ impl Runtime {
   }
 
   pub fn get_owner(&self, name: u128) -> u128 {
+    if let Some((succ, tick)) = self.get_rotation(name) {
+      if self.get_tick() >= tick {
+        return succ;
+      }
+    }
     if let Some(owner) = self.get_with(None, None, |heap| heap.read_ownr(name)) {
       return owner;
     } else {
@@ -2186,9 +4235,119 @@ impl Runtime {
   }
 
   pub fn set_owner(&mut self, name: u128, owner: u128) {
+    self.touched.insert(name);
     self.get_heap_mut(self.draw).write_ownr(name, owner);
   }
 
+  // Names deployed on the chain, of one kind or of all, in name order. A name may be listed twice,
+  // e.g. as a function and as the namespace of the ones under it.
+  pub fn list_names(&self, kind: Option<NameKind>) -> Vec<NameInfo> {
+    let mut names = BTreeSet::new();
+    self.reduce_with(&mut names, |acc, heap| {
+      acc.extend(heap.arit.arits.keys());
+      acc.extend(heap.ownr.ownrs.keys());
+    });
+    let mut infos = vec![];
+    for name in names {
+      let arity = Some(self.get_arity(name)).filter(|arity| *arity != U128_NONE);
+      match (self.read_file(name), arity) {
+        (Some(func), arity) => infos.push(NameInfo { name, kind: NameKind::Fun, arity, hash: Some(U256::from_big_endian(&hash_func(name, &func.func).0)) }),
+        (None, Some(arity)) => infos.push(NameInfo { name, kind: NameKind::Ctr, arity: Some(arity), hash: None }),
+        (None, None) => {}
+      }
+      if self.get_owner(name) != U128_NONE {
+        infos.push(NameInfo { name, kind: NameKind::Reg, arity: None, hash: None });
+      }
+    }
+    infos.retain(|info| kind.map_or(true, |kind| info.kind == kind));
+    return infos;
+  }
+
+  // Gets the owner a namespace is rotating to, and the tick it takes over at, if any. Once that
+  // tick is reached, it's the owner `get_owner` returns.
+  pub fn get_rotation(&self, name: u128) -> Option<(u128, u128)> {
+    return self.get_with(None, None, |heap| heap.read_rotn(name));
+  }
+
+  pub fn set_rotation(&mut self, name: u128, succ: u128, tick: u128) {
+    self.touched.insert(name);
+    self.get_heap_mut(self.draw).write_rotn(name, (succ, tick));
+  }
+
+  // Gets the function that authorizes statements acting as `name`, if any
+  pub fn get_authorizer(&self, name: u128) -> Option<u128> {
+    return self.get_with(None, None, |heap| heap.read_auth(name)).filter(|auth| *auth != 0);
+  }
+
+  pub fn set_authorizer(&mut self, name: u128, auth: u128) {
+    self.get_heap_mut(self.draw).write_auth(name, auth);
+  }
+
+  // Gets the list of actions scheduled for `tick`, as a chain of `{T4 subject mana action next}`
+  pub fn get_schedule(&self, tick: u128) -> Ptr {
+    return self.get_with(Num(0), 0, |heap| heap.read_schd(tick).unwrap_or(0));
+  }
+
+  pub fn set_schedule(&mut self, tick: u128, list: Ptr) {
+    self.get_heap_mut(self.draw).write_schd(tick, list);
+  }
+
+  // Gets the functions subscribed to the events of `fid`, in subscription order
+  pub fn get_hooks(&self, fid: u128) -> Vec<u128> {
+    let mut hooks = vec![];
+    let mut list = self.get_with(Num(0), 0, |heap| heap.read_hook(fid).unwrap_or(0));
+    while get_tag(list) == CTR {
+      hooks.push(get_num(ask_arg(self, list, 0)));
+      list = ask_arg(self, list, 1);
+    }
+    hooks.reverse();
+    return hooks;
+  }
+
+  // Replaces the subscribers of `fid`, stored as a chain of `{T2 subscriber next}`
+  pub fn set_hooks(&mut self, fid: u128, hooks: &[u128]) {
+    let mut list = self.get_with(Num(0), 0, |heap| heap.read_hook(fid).unwrap_or(0));
+    while get_tag(list) == CTR {
+      let next = ask_arg(self, list, 1);
+      clear(self, get_loc(list, 0), 2);
+      list = next;
+    }
+    let mut list = Num(0);
+    for hook in hooks {
+      let node = alloc(self, 2);
+      link(self, node + 0, Num(*hook));
+      link(self, node + 1, list);
+      list = Ctr(T2, node);
+    }
+    self.get_heap_mut(self.draw).write_hook(fid, list);
+  }
+
+  // Gets the maximum mana a call to `fid` may spend, as declared when it was deployed
+  pub fn get_call_limit(&self, fid: u128) -> Option<u128> {
+    return self.get_with(None, None, |heap| heap.read_lmit(fid));
+  }
+
+  pub fn set_call_limit(&mut self, fid: u128, mana: u128) {
+    self.get_heap_mut(self.draw).write_lmit(fid, mana);
+  }
+
+  // Gets the tick the state of `fid` was frozen at, if it was
+  pub fn get_frozen(&self, fid: u128) -> Option<u128> {
+    return self.get_with(None, None, |heap| heap.read_frzn(fid));
+  }
+
+  pub fn set_frozen(&mut self, fid: u128, tick: u128) {
+    self.get_heap_mut(self.draw).write_frzn(fid, tick);
+  }
+
+  // Caps the mana available to a call to `fid`, given the caller's limit
+  fn call_mana(&self, fid: u128, mana: u128) -> u128 {
+    match self.get_call_limit(fid) {
+      Some(limit) => std::cmp::min(mana, self.get_mana() + limit),
+      None        => mana,
+    }
+  }
+
   pub fn exists(&self, fid: u128) -> bool {
     if let Some(arity) = self.get_with(None, None, |heap| heap.read_arit(fid)) {
       return true;
@@ -2259,6 +4418,39 @@ impl Runtime {
     return self.get_with(0, U128_NONE, |heap| heap.hax1);
   }
 
+  pub fn set_rand(&mut self, rand: u128) {
+    self.get_heap_mut(self.draw).set_rand(rand);
+  }
+
+  pub fn get_rand(&self) -> u128 {
+    return self.get_with(0, U128_NONE, |heap| heap.rand);
+  }
+
+  pub fn set_mana_price(&mut self, price: u128) {
+    self.get_heap_mut(self.draw).set_pric(price);
+  }
+
+  // The base mana price of the block being run
+  pub fn get_mana_price(&self) -> u128 {
+    return self.get_with(INITIAL_MANA_PRICE, U128_NONE, |heap| heap.pric);
+  }
+
+  pub fn set_burned(&mut self, burn: u128) {
+    self.get_heap_mut(self.draw).set_burn(burn);
+  }
+
+  // The mana fees burned so far: each run burns the mana it used times the base price
+  pub fn get_burned(&self) -> u128 {
+    return self.get_with(0, U128_NONE, |heap| heap.burn);
+  }
+
+  // Sets the base mana price of the next block from the mana the runs of this one used
+  pub fn update_mana_price(&mut self, used: u128) {
+    if self.is_active(Feature::ManaMarket) {
+      self.set_mana_price(compute_next_mana_price(self.get_mana_price(), used));
+    }
+  }
+
   pub fn set_size(&mut self, size: i128) {
     self.get_heap_mut(self.draw).size = size;
   }
@@ -2615,6 +4807,112 @@ fn count_uses(term: &Term, name: u128) -> u128 {
   }
 }
 
+// Collects the functions a statement defines or calls, and the constructors it builds, with their
+// arities
+pub fn get_statement_refs(statement: &Statement) -> (Vec<u128>, Vec<(u128, u128)>) {
+  let mut funs = vec![];
+  let mut ctrs = vec![];
+  match statement {
+    Statement::Fun { name, func, init, .. } => {
+      funs.push(*name);
+      for rule in &func.rules {
+        get_term_refs(&rule.lhs, &mut funs, &mut ctrs);
+        get_term_refs(&rule.rhs, &mut funs, &mut ctrs);
+      }
+      get_term_refs(init, &mut funs, &mut ctrs);
+    }
+    Statement::Run { expr, .. } => {
+      get_term_refs(expr, &mut funs, &mut ctrs);
+    }
+    Statement::Ctr { .. } | Statement::Reg { .. } | Statement::Rot { .. } => {}
+  }
+  return (funs, ctrs);
+}
+
+// Collects the functions a term calls, directly or with `Call`, and the constructors it builds,
+// with their arities
+fn get_term_refs(term: &Term, funs: &mut Vec<u128>, ctrs: &mut Vec<(u128, u128)>) {
+  let mut stack = vec![term];
+  while let Some(term) = stack.pop() {
+    match term {
+      Term::Var { .. } | Term::Num { .. } => {}
+      Term::Dup { expr, body, .. } => {
+        stack.push(expr);
+        stack.push(body);
+      }
+      Term::Lam { body, .. } => {
+        stack.push(body);
+      }
+      Term::App { func, argm } => {
+        stack.push(func);
+        stack.push(argm);
+      }
+      Term::Ctr { name, args } => {
+        if let (IO_CALL, Some(Term::Num { numb })) = (*name, args.first()) {
+          funs.push(*numb);
+        }
+        ctrs.push((*name, args.len() as u128));
+        stack.extend(args);
+      }
+      Term::Fun { name, args } => {
+        if let (CALL, Some(Term::Num { numb })) = (*name, args.first()) {
+          funs.push(*numb);
+        }
+        funs.push(*name);
+        stack.extend(args);
+      }
+      Term::Op2 { val0, val1, .. } => {
+        stack.push(val0);
+        stack.push(val1);
+      }
+    }
+  }
+}
+
+// Whether a name is of a genesis function that only builds effects, as `Call` and `Done`, which
+// touches no state
+pub fn is_effect_builder(name: u128) -> bool {
+  return name == CALL || name == DONE;
+}
+
+// Whether a statement calls a function by a name only known when it runs, as in `(Call name ...)`
+// with a computed name, so the functions it touches can't be told by `get_statement_refs`
+pub fn has_dynamic_calls(statement: &Statement) -> bool {
+  let terms: Vec<&Term> = match statement {
+    Statement::Fun { func, init, .. } => func.rules.iter().map(|rule| &rule.rhs).chain(std::iter::once(init)).collect(),
+    Statement::Run { expr, .. } => vec![expr],
+    Statement::Ctr { .. } | Statement::Reg { .. } | Statement::Rot { .. } => vec![],
+  };
+  let mut stack = terms;
+  while let Some(term) = stack.pop() {
+    match term {
+      Term::Var { .. } | Term::Num { .. } => {}
+      Term::Dup { expr, body, .. } => {
+        stack.push(expr);
+        stack.push(body);
+      }
+      Term::Lam { body, .. } => {
+        stack.push(body);
+      }
+      Term::App { func, argm } => {
+        stack.push(func);
+        stack.push(argm);
+      }
+      Term::Ctr { name, args } | Term::Fun { name, args } => {
+        if (*name == IO_CALL || *name == CALL) && !matches!(args.first(), Some(Term::Num { .. })) {
+          return true;
+        }
+        stack.extend(args);
+      }
+      Term::Op2 { val0, val1, .. } => {
+        stack.push(val0);
+        stack.push(val1);
+      }
+    }
+  }
+  return false;
+}
+
 // Checks if:
 // - Every non-erased variable is used exactly once
 // - Every erased variable is never used
@@ -2691,85 +4989,95 @@ pub fn create_term(rt: &mut Runtime, term: &Term, loc: u128, vars_data: &mut Map
       }
     }
   }
-  match term {
-    Term::Var { name } => {
-      //println!("~~ var {} {}", u128_to_name(*name), vars_data.len());
-      let got = vars_data.get(name).map(|x| *x);
-      match got {
-        Some(got) => {
-          vars_data.remove(name);
-          return got;
-        }
-        None => {
-          vars_data.insert(*name, loc);
-          return Num(0);
+  // Terms are built with a stack instead of recursion, so that deep terms can't overflow the native
+  // stack. Each item holds a term and the location its pointer is linked to, or `None` for the root.
+  let mut root = Num(0);
+  let mut stack: Vec<(&Term, Option<u128>)> = vec![(term, None)];
+  while let Some((term, host)) = stack.pop() {
+    let lnk = match term {
+      Term::Var { name } => {
+        //println!("~~ var {} {}", u128_to_name(*name), vars_data.len());
+        let got = vars_data.get(name).map(|x| *x);
+        match got {
+          Some(got) => {
+            vars_data.remove(name);
+            got
+          }
+          None => {
+            vars_data.insert(*name, host.unwrap_or(loc));
+            Num(0)
+          }
         }
       }
-    }
-    Term::Dup { nam0, nam1, expr, body } => {
-      let node = alloc(rt, 3);
-      let dupk = rt.fresh_dups();
-      bind(rt, node + 0, *nam0, Dp0(dupk, node), vars_data);
-      bind(rt, node + 1, *nam1, Dp1(dupk, node), vars_data);
-      let expr = create_term(rt, expr, node + 2, vars_data);
-      link(rt, node + 2, expr);
-      let body = create_term(rt, body, loc, vars_data);
-      body
-    }
-    Term::Lam { name, body } => {
-      let node = alloc(rt, 2);
-      bind(rt, node + 0, *name, Var(node), vars_data);
-      let body = create_term(rt, body, node + 1, vars_data);
-      link(rt, node + 1, body);
-      Lam(node)
-    }
-    Term::App { func, argm } => {
-      let node = alloc(rt, 2);
-      let func = create_term(rt, func, node + 0, vars_data);
-      link(rt, node + 0, func);
-      let argm = create_term(rt, argm, node + 1, vars_data);
-      link(rt, node + 1, argm);
-      App(node)
-    }
-    Term::Fun { name, args } => {
-      if args.len() != rt.get_arity(*name) as usize {
-        Num(0)
-      } else {
-        let size = args.len() as u128;
-        let node = alloc(rt, size);
-        for (i, arg) in args.iter().enumerate() {
-          let arg_lnk = create_term(rt, arg, node + i as u128, vars_data);
-          link(rt, node + i as u128, arg_lnk);
+      Term::Dup { nam0, nam1, expr, body } => {
+        let node = alloc(rt, 3);
+        let dupk = rt.fresh_dups();
+        bind(rt, node + 0, *nam0, Dp0(dupk, node), vars_data);
+        bind(rt, node + 1, *nam1, Dp1(dupk, node), vars_data);
+        // The body takes the place of the dup
+        stack.push((body, host));
+        stack.push((expr, Some(node + 2)));
+        continue;
+      }
+      Term::Lam { name, body } => {
+        let node = alloc(rt, 2);
+        bind(rt, node + 0, *name, Var(node), vars_data);
+        stack.push((body, Some(node + 1)));
+        Lam(node)
+      }
+      Term::App { func, argm } => {
+        let node = alloc(rt, 2);
+        stack.push((argm, Some(node + 1)));
+        stack.push((func, Some(node + 0)));
+        App(node)
+      }
+      Term::Fun { name, args } => {
+        let arity = rt.get_arity(*name);
+        if args.len() as u128 != arity {
+          // Built as a number, so the heap stays well-formed, and reported as a fault
+          rt.fault.get_or_insert(RuntimeError::ArityMismatch { name: *name, expected: arity, found: args.len() as u128 });
+          Num(0)
+        } else {
+          let size = args.len() as u128;
+          let node = alloc(rt, size);
+          for (i, arg) in args.iter().enumerate().rev() {
+            stack.push((arg, Some(node + i as u128)));
+          }
+          Fun(*name, node)
         }
-        Fun(*name, node)
       }
-    }
-    Term::Ctr { name, args } => {
-      if args.len() != rt.get_arity(*name) as usize {
-        Num(0)
-      } else {
-        let size = args.len() as u128;
-        let node = alloc(rt, size);
-        for (i, arg) in args.iter().enumerate() {
-          let arg_lnk = create_term(rt, arg, node + i as u128, vars_data);
-          link(rt, node + i as u128, arg_lnk);
+      Term::Ctr { name, args } => {
+        let arity = rt.get_arity(*name);
+        if args.len() as u128 != arity {
+          // Built as a number, so the heap stays well-formed, and reported as a fault
+          rt.fault.get_or_insert(RuntimeError::ArityMismatch { name: *name, expected: arity, found: args.len() as u128 });
+          Num(0)
+        } else {
+          let size = args.len() as u128;
+          let node = alloc(rt, size);
+          for (i, arg) in args.iter().enumerate().rev() {
+            stack.push((arg, Some(node + i as u128)));
+          }
+          Ctr(*name, node)
         }
-        Ctr(*name, node)
       }
-    }
-    Term::Num { numb } => {
-      // TODO: assert numb size
-      Num(*numb as u128)
-    }
-    Term::Op2 { oper, val0, val1 } => {
-      let node = alloc(rt, 2);
-      let val0 = create_term(rt, val0, node + 0, vars_data);
-      link(rt, node + 0, val0);
-      let val1 = create_term(rt, val1, node + 1, vars_data);
-      link(rt, node + 1, val1);
-      Op2(*oper, node)
+      Term::Num { numb } => {
+        // TODO: assert numb size
+        Num(*numb)
+      }
+      Term::Op2 { oper, val0, val1 } => {
+        let node = alloc(rt, 2);
+        stack.push((val1, Some(node + 1)));
+        stack.push((val0, Some(node + 0)));
+        Op2(*oper, node)
+      }
+    };
+    match host {
+      Some(host) => { link(rt, host, lnk); }
+      None       => { root = lnk; }
     }
   }
+  return root;
 }
 
 /// Given a Func (a vector of rules, lhs/rhs pairs), builds the CompFunc object
@@ -2868,7 +5176,7 @@ pub fn compile_func(func: &Func, debug: bool) -> Option<CompFunc> {
           // If it is a number...
           Term::Num { numb: arg_numb } => {
             strict[i as usize] = true;
-            cond.push(Num(*arg_numb as u128)); // adds its matching condition
+            cond.push(Num(*arg_numb)); // adds its matching condition
           }
           // If it is a variable...
           Term::Var { name: arg_name } => {
@@ -2903,7 +5211,8 @@ pub fn compile_func(func: &Func, debug: bool) -> Option<CompFunc> {
     let body = rule.rhs.clone();
 
     // Adds the rule to the result vector
-    comp_rules.push(CompRule { cond, vars, eras, body });
+    let mana = FunCtrMana(&body);
+    comp_rules.push(CompRule { cond, vars, eras, body, mana });
   }
 
   // Builds the redux object, with the index of strict arguments
@@ -2914,14 +5223,67 @@ pub fn compile_func(func: &Func, debug: bool) -> Option<CompFunc> {
     }
   }
 
+  let table = build_rule_table(&comp_rules, &redux);
   return Some(CompFunc {
     func: func.clone(),
     arity,
     redux,
     rules: comp_rules,
+    table,
+    strict: vec![],
+    pure: false,
   });
 }
 
+// Functions with fewer rules are matched by trying each one
+pub const RULE_TABLE_MIN_RULES : usize = 4;
+
+// Precompiles the dispatch of a function's rules, on the strict argument that tells the most of
+// them apart. Each rule may match the constructor or number it expects there, or anything, if it
+// expects a variable. None if the function has too few rules, or no argument tells them apart.
+pub fn build_rule_table(rules: &[CompRule], redux: &[u128]) -> Option<RuleTable> {
+  if rules.len() < RULE_TABLE_MIN_RULES {
+    return None;
+  }
+  let key_of = |cond: Ptr| match get_tag(cond) {
+    CTR | NUM => Some(cond),
+    _ => None,
+  };
+  let mut best: Option<(usize, u128)> = None;
+  for param in redux {
+    let keys: HashSet<Ptr> = rules.iter().filter_map(|rule| key_of(rule.cond[*param as usize])).collect();
+    if keys.len() > 1 && best.map_or(true, |(count, _)| keys.len() > count) {
+      best = Some((keys.len(), *param));
+    }
+  }
+  let (_, param) = best?;
+  let mut cases: Map<Vec<usize>> = init_map();
+  let mut other = vec![];
+  for (index, rule) in rules.iter().enumerate() {
+    if let Some(key) = key_of(rule.cond[param as usize]) {
+      cases.entry(key).or_insert_with(|| other.clone()).push(index);
+    } else {
+      for indices in cases.values_mut() {
+        indices.push(index);
+      }
+      other.push(index);
+    }
+  }
+  Some(RuleTable { param, cases, other })
+}
+
+impl RuleTable {
+  // The rules that may match a call whose dispatched argument is `arg`
+  pub fn candidates(&self, arg: Ptr) -> &[usize] {
+    let key = match get_tag(arg) {
+      CTR => Ctr(get_ext(arg), 0),
+      NUM => Num(get_val(arg)),
+      _ => return &self.other,
+    };
+    self.cases.get(&key).unwrap_or(&self.other)
+  }
+}
+
 pub fn create_app(rt: &mut Runtime, func: Ptr, argm: Ptr) -> Ptr {
   let node = alloc(rt, 2);
   link(rt, node + 0, func);
@@ -2964,11 +5326,18 @@ pub fn subst(rt: &mut Runtime, lnk: Ptr, val: Ptr) {
   }
 }
 
+// Flag of the items on the stack of `reduce` that are the hosts of pure calls' results, memoized
+// once reduced
+const MEMO_ITEM : u128 = 0x2_0000_0000_0000;
+
 pub fn reduce(rt: &mut Runtime, root: u128, mana: u128) -> Result<Ptr, RuntimeError> {
   let mut vars_data: Map<u128> = init_map();
 
   let mut stack: Vec<u128> = Vec::new();
 
+  // Keys of the pure calls whose results are being reduced, marked on the stack by MEMO_ITEM
+  let mut memos: Vec<[u8; 32]> = Vec::new();
+
   let mut init = 1;
   let mut host = root;
 
@@ -2982,6 +5351,10 @@ pub fn reduce(rt: &mut Runtime, root: u128, mana: u128) -> Result<Ptr, RuntimeEr
       return Err(RuntimeError::NotEnoughMana);
     }
 
+    if rt.deadline.check() {
+      return Err(RuntimeError::Timeout);
+    }
+
     // if true {
     //   println!("----------------------");
     //   println!("{}", show_term(rt, ask_lnk(rt, root), Some(term)));
@@ -3039,6 +5412,7 @@ pub fn reduce(rt: &mut Runtime, root: u128, mana: u128) -> Result<Ptr, RuntimeEr
           // body
           if get_tag(arg0) == LAM {
             //println!("app-lam");
+            rt.count_rewrite(Rewrite::AppLam);
             rt.set_mana(rt.get_mana() + AppLamMana());
             rt.set_rwts(rt.get_rwts() + 1);
             subst(rt, ask_arg(rt, arg0, 0), ask_arg(rt, term, 1));
@@ -3053,6 +5427,7 @@ pub fn reduce(rt: &mut Runtime, root: u128, mana: u128) -> Result<Ptr, RuntimeEr
           // {(a x0) (b x1)}
           } else if get_tag(arg0) == SUP {
             //println!("app-sup");
+            rt.count_rewrite(Rewrite::AppSup);
             rt.set_mana(rt.get_mana() + AppSupMana());
             rt.set_rwts(rt.get_rwts() + 1);
             let app0 = get_loc(term, 0);
@@ -3082,6 +5457,7 @@ pub fn reduce(rt: &mut Runtime, root: u128, mana: u128) -> Result<Ptr, RuntimeEr
           // x <- {x0 x1}
           if get_tag(arg0) == LAM {
             //println!("dup-lam");
+            rt.count_rewrite(Rewrite::DupLam);
             rt.set_mana(rt.get_mana() + DupLamMana());
             rt.set_rwts(rt.get_rwts() + 1);
             let let0 = get_loc(term, 0);
@@ -3110,6 +5486,7 @@ pub fn reduce(rt: &mut Runtime, root: u128, mana: u128) -> Result<Ptr, RuntimeEr
           } else if get_tag(arg0) == SUP {
             if get_ext(term) == get_ext(arg0) {
               //println!("dup-sup-e");
+              rt.count_rewrite(Rewrite::DupSupE);
               rt.set_mana(rt.get_mana() + DupSupMana());
               rt.set_rwts(rt.get_rwts() + 1);
               subst(rt, ask_arg(rt, term, 0), ask_arg(rt, arg0, 0));
@@ -3127,6 +5504,7 @@ pub fn reduce(rt: &mut Runtime, root: u128, mana: u128) -> Result<Ptr, RuntimeEr
             // dup xB yB = b
             } else {
               //println!("dup-sup-d");
+              rt.count_rewrite(Rewrite::DupSupD);
               rt.set_mana(rt.get_mana() + DupDupMana());
               rt.set_rwts(rt.get_rwts() + 1);
               let par0 = alloc(rt, 2);
@@ -3153,6 +5531,7 @@ pub fn reduce(rt: &mut Runtime, root: u128, mana: u128) -> Result<Ptr, RuntimeEr
           // ~
           } else if get_tag(arg0) == NUM {
             //println!("dup-num");
+            rt.count_rewrite(Rewrite::DupNum);
             rt.set_mana(rt.get_mana() + DupNumMana());
             rt.set_rwts(rt.get_rwts() + 1);
             subst(rt, ask_arg(rt, term, 0), arg0);
@@ -3170,6 +5549,7 @@ pub fn reduce(rt: &mut Runtime, root: u128, mana: u128) -> Result<Ptr, RuntimeEr
           // y <- (K a1 b1 c1 ...)
           } else if get_tag(arg0) == CTR {
             //println!("dup-ctr");
+            rt.count_rewrite(Rewrite::DupCtr);
             let func = get_ext(arg0);
             let arit = rt.get_arity(func);
             rt.set_mana(rt.get_mana() + DupCtrMana(arit));
@@ -3205,6 +5585,7 @@ pub fn reduce(rt: &mut Runtime, root: u128, mana: u128) -> Result<Ptr, RuntimeEr
           // y <- *
           } else if get_tag(arg0) == ERA {
             //println!("dup-era");
+            rt.count_rewrite(Rewrite::DupEra);
             rt.set_mana(rt.get_mana() + DupEraMana());
             rt.set_rwts(rt.get_rwts() + 1);
             subst(rt, ask_arg(rt, term, 0), Era());
@@ -3225,6 +5606,7 @@ pub fn reduce(rt: &mut Runtime, root: u128, mana: u128) -> Result<Ptr, RuntimeEr
           // add(a, b)
           if get_tag(arg0) == NUM && get_tag(arg1) == NUM {
             //eprintln!("op2-num");
+            rt.count_rewrite(Rewrite::Op2Num);
             rt.set_mana(rt.get_mana() + Op2NumMana());
             let op  = get_ext(term);
             let a_u = get_num(arg0);
@@ -3247,6 +5629,11 @@ pub fn reduce(rt: &mut Runtime, root: u128, mana: u128) -> Result<Ptr, RuntimeEr
               GTE => u128::from(a_u >= b_u),
               GTN => u128::from(a_u >  b_u),
               NEQ => u128::from(a_u != b_u),
+              ROL => rotate_left_u120(a_u, b_u),
+              ROR => rotate_left_u120(a_u, 120 - b_u % 120),
+              CLZ => a_u.leading_zeros() as u128 - 8,
+              POP => a_u.count_ones() as u128,
+              BSW => a_u.swap_bytes() >> 8,
               _ => panic!("Invalid operation!"),
             };
             let done = Num(res);
@@ -3258,6 +5645,7 @@ pub fn reduce(rt: &mut Runtime, root: u128, mana: u128) -> Result<Ptr, RuntimeEr
           // {(+ a0 b0) (+ a1 b1)}
           } else if get_tag(arg0) == SUP {
             //println!("op2-sup-0");
+            rt.count_rewrite(Rewrite::Op2Sup);
             rt.set_mana(rt.get_mana() + Op2SupMana());
             rt.set_rwts(rt.get_rwts() + 1);
             let op20 = get_loc(term, 0);
@@ -3279,6 +5667,7 @@ pub fn reduce(rt: &mut Runtime, root: u128, mana: u128) -> Result<Ptr, RuntimeEr
           // {(+ a0 b0) (+ a1 b1)}
           } else if get_tag(arg1) == SUP {
             //println!("op2-sup-1");
+            rt.count_rewrite(Rewrite::Op2Sup);
             rt.set_mana(rt.get_mana() + Op2SupMana());
             rt.set_rwts(rt.get_rwts() + 1);
             let op20 = get_loc(term, 0);
@@ -3300,7 +5689,7 @@ pub fn reduce(rt: &mut Runtime, root: u128, mana: u128) -> Result<Ptr, RuntimeEr
         }
         FUN => {
 
-          fn call_function(rt: &mut Runtime, func: Arc<CompFunc>, host: u128, term: Ptr, mana: u128, vars_data: &mut Map<u128>) -> bool {
+          fn call_function(rt: &mut Runtime, func: Arc<CompFunc>, host: u128, term: Ptr, mana: u128, vars_data: &mut Map<u128>, memos: &mut Vec<[u8; 32]>) -> bool {
             // For each argument, if it is a redex and a SUP, apply the cal_par rule
             for idx in &func.redux {
               // (F {a0 a1} b c ...)
@@ -3311,6 +5700,7 @@ pub fn reduce(rt: &mut Runtime, root: u128, mana: u128) -> Result<Ptr, RuntimeEr
               // {(F a0 b0 c0 ...) (F a1 b1 c1 ...)}
               if get_tag(ask_arg(rt, term, *idx)) == SUP {
                 //println!("fun-sup");
+                rt.count_rewrite(Rewrite::FunSup);
                 let funx = get_ext(term);
                 let arit = rt.get_arity(funx);
                 rt.set_mana(rt.get_mana() + FunSupMana(arit));
@@ -3338,23 +5728,29 @@ pub fn reduce(rt: &mut Runtime, root: u128, mana: u128) -> Result<Ptr, RuntimeEr
                 return true;
               }
             }
-            // For each rule condition vector
-            for rule in &func.rules {
-              // Check if the rule matches
+            // (MathSqrt #n)
+            // ------------- FUN-NUM
+            // #isqrt(n)
+            if let Some((done, mana)) = call_builtin(rt, term) {
+              rt.set_mana(rt.get_mana() + mana);
+              rt.set_rwts(rt.get_rwts() + 1);
+              rt.count_rewrite(Rewrite::FunNum);
+              link(rt, host, Num(done));
+              clear(rt, get_loc(term, 0), func.arity);
+              return true;
+            }
+            // Tests each rule condition (ex: `get_tag(args[0]) == SUCC`)
+            fn matches(rt: &Runtime, func: &CompFunc, rule: &CompRule, term: Ptr) -> bool {
               let mut matched = true;
-              //println!("- matching rule");
-              // Tests each rule condition (ex: `get_tag(args[0]) == SUCC`)
               for i in 0 .. rule.cond.len() as u128 {
                 let cond = rule.cond[i as usize];
                 match get_tag(cond) {
                   NUM => {
-                    //println!("Didn't match because of NUM. i={} {} {}", i, get_val(ask_arg(rt, term, i)), get_val(cond));
                     let same_tag = get_tag(ask_arg(rt, term, i)) == NUM;
                     let same_val = get_val(ask_arg(rt, term, i)) == get_val(cond);
                     matched = matched && same_tag && same_val;
                   }
                   CTR => {
-                    //println!("Didn't match because of CTR. i={} {} {}", i, get_tag(ask_arg(rt, term, i)), get_val(cond));
                     let same_tag = get_tag(ask_arg(rt, term, i)) == CTR;
                     let same_ext = get_ext(ask_arg(rt, term, i)) == get_ext(cond);
                     matched = matched && same_tag && same_ext;
@@ -3368,59 +5764,94 @@ pub fn reduce(rt: &mut Runtime, root: u128, mana: u128) -> Result<Ptr, RuntimeEr
                   _ => {}
                 }
               }
+              return matched;
+            }
+            // A pure call on data is looked up in the memo. If it isn't there, its key is kept until
+            // the caller reduces its result, which is memoized if it's data by then.
+            let key = if func.pure { memo_key(rt, term, func.arity) } else { None };
+            if let Some((done, size)) = key.and_then(|key| rt.memo.results.get(&key).cloned()) {
+              rt.set_mana(rt.get_mana() + MemoMana(size));
+              rt.set_rwts(rt.get_rwts() + 1);
+              rt.count_rewrite(Rewrite::FunMemo);
+              let done = create_term(rt, &done, host, vars_data);
+              link(rt, host, done);
+              for i in 0 .. func.arity {
+                collect(rt, ask_arg(rt, term, i));
+              }
+              clear(rt, get_loc(term, 0), func.arity);
+              return true;
+            }
+            // Finds the first rule that matches, among the ones its rule table leaves, if any
+            let found = match func.table.as_ref().filter(|_| rt.tables) {
+              Some(table) => {
+                let candidates = table.candidates(ask_arg(rt, term, table.param));
+                candidates.iter().map(|index| &func.rules[*index]).find(|rule| matches(rt, &func, rule, term))
+              }
+              None => func.rules.iter().find(|rule| matches(rt, &func, rule, term)),
+            };
+            if let Some(rule) = found {
               // (user-defined)
               // -------------- FUN-CTR
               // (user-defined)
-              // If all conditions are satisfied, the rule matched, so we must apply it
-              if matched {
-                //println!("fun-ctr");
-                //println!("- matched");
-                // Increments the gas count
-                rt.set_mana(rt.get_mana() + FunCtrMana(&rule.body));
-                rt.set_rwts(rt.get_rwts() + 1);
-                // Gathers matched variables
-                //let mut vars = vec![None; 16]; // FIXME: pre-alloc statically
-                for (i, rule_var) in rule.vars.iter().enumerate() {
-                  let mut var = term;
-                  var = ask_arg(rt, var, rule_var.param);
-                  if let Some(field) = rule_var.field {
-                    var = ask_arg(rt, var, field);
-                  }
-                  //eprintln!("~~ set {} {}", u128_to_name(rule_var.name), show_lnk(var));
-                  if !rule_var.erase {
-                    vars_data.insert(rule_var.name, var);
-                  } else {
-                    // Collects unused argument
-                    collect(rt, var);
-                  }
+              // The rule matched, so we must apply it
+              //println!("fun-ctr");
+              rt.count_rewrite(Rewrite::FunCtr);
+              //println!("- matched");
+              // Increments the gas count
+              rt.set_mana(rt.get_mana() + rule.mana);
+              rt.set_rwts(rt.get_rwts() + 1);
+              // Gathers matched variables
+              //let mut vars = vec![None; 16]; // FIXME: pre-alloc statically
+              for (i, rule_var) in rule.vars.iter().enumerate() {
+                let mut var = term;
+                var = ask_arg(rt, var, rule_var.param);
+                if let Some(field) = rule_var.field {
+                  var = ask_arg(rt, var, field);
                 }
-                // Builds the right-hand side term (ex: `(Succ (Add a b))`)
-                //println!("-- vars: {:?}", vars);
-                let done = create_term(rt, &rule.body, host, vars_data);
-                // Links the host location to it
-                link(rt, host, done);
-                // Clears the matched ctrs (the `(Succ ...)` and the `(Add ...)` ctrs)
-                for (eras_index, eras_arity) in &rule.eras {
-                  clear(rt, get_loc(ask_arg(rt, term, *eras_index), 0), *eras_arity);
+                //eprintln!("~~ set {} {}", u128_to_name(rule_var.name), show_lnk(var));
+                if !rule_var.erase {
+                  vars_data.insert(rule_var.name, var);
+                } else {
+                  // Collects unused argument
+                  collect(rt, var);
                 }
-                clear(rt, get_loc(term, 0), func.arity);
-                // // Collects unused variables (none in this example)
-                // for i in 0 .. rule.vars.len() {
-                //   if rule.vars[i].erase {
-                //     if let Some(var) = vars_data.get(&(i as u64)) {
-                //       collect(rt, *var, mana)?;
-                //     }
-                //   }
-                // }
-                return true;
               }
+              // Builds the right-hand side term (ex: `(Succ (Add a b))`)
+              //println!("-- vars: {:?}", vars);
+              let done = create_term(rt, &rule.body, host, vars_data);
+              // Links the host location to it
+              link(rt, host, done);
+              // Clears the matched ctrs (the `(Succ ...)` and the `(Add ...)` ctrs)
+              for (eras_index, eras_arity) in &rule.eras {
+                clear(rt, get_loc(ask_arg(rt, term, *eras_index), 0), *eras_arity);
+              }
+              clear(rt, get_loc(term, 0), func.arity);
+              if let Some(key) = key.filter(|_| rt.fault.is_none()) {
+                memos.push(key);
+              }
+              // // Collects unused variables (none in this example)
+              // for i in 0 .. rule.vars.len() {
+              //   if rule.vars[i].erase {
+              //     if let Some(var) = vars_data.get(&(i as u64)) {
+              //       collect(rt, *var, mana)?;
+              //     }
+              //   }
+              // }
+              return true;
             }
             return false;
           }
 
           let fun = get_ext(term);
           if let Some(func) = rt.get_func(fun) {
-            if call_function(rt, func, host, term, mana, &mut vars_data) {
+            let pending = memos.len();
+            if call_function(rt, func, host, term, mana, &mut vars_data, &mut memos) {
+              if let Some(err) = rt.fault.take() {
+                return Err(err);
+              }
+              if memos.len() > pending {
+                stack.push(host | MEMO_ITEM);
+              }
               init = 1;
               continue;
             } else {
@@ -3433,19 +5864,188 @@ pub fn reduce(rt: &mut Runtime, root: u128, mana: u128) -> Result<Ptr, RuntimeEr
       }
     }
 
-    if let Some(item) = stack.pop() {
-      init = item >> 48;
-      host = item & 0x0_FFFF_FFFF_FFFF;
-      continue;
+    // Pops the next host, memoizing the results of the pure calls reduced on the way
+    let mut next = None;
+    while let Some(item) = stack.pop() {
+      if item & MEMO_ITEM != 0 {
+        memoize(rt, memos.pop().unwrap(), item & 0x0_FFFF_FFFF_FFFF);
+        if rt.get_mana() > mana {
+          return Err(RuntimeError::NotEnoughMana);
+        }
+      } else {
+        next = Some(item);
+        break;
+      }
+    }
+
+    if let Some(item) = next {
+      init = item >> 48;
+      host = item & 0x0_FFFF_FFFF_FFFF;
+      continue;
+    }
+
+    break;
+  }
+
+  // FIXME: remove this when Runtime is split (see above)
+  //rt.get_heap_mut(self.curr).file = file;
+
+  return Ok(ask_lnk(rt, root));
+}
+
+// Like `compute_at`, but computes the fields of every constructor, as `Runtime::compute_data` does
+pub fn compute_data_at(rt: &mut Runtime, host: u128, mana: u128) -> Result<Ptr, RuntimeError> {
+  let mut stack = vec![host];
+  while let Some(loc) = stack.pop() {
+    let term = reduce(rt, loc, mana)?;
+    if get_tag(term) == CTR {
+      for i in 0 .. rt.get_arity(get_ext(term)) {
+        stack.push(get_loc(term, i));
+      }
+    }
+  }
+  return Ok(ask_lnk(rt, host));
+}
+
+// The number of nodes of a term made only of numbers and constructors, if it is one, of at most
+// `limit` nodes. Only these are memoized, as arguments and results of pure calls.
+fn data_size(rt: &Runtime, term: Ptr, limit: u128) -> Option<u128> {
+  let mut size = 0;
+  let mut stack = vec![term];
+  while let Some(term) = stack.pop() {
+    size += 1;
+    if size > limit {
+      return None;
+    }
+    match get_tag(term) {
+      NUM => {}
+      CTR => {
+        for i in 0 .. rt.get_arity(get_ext(term)) {
+          stack.push(ask_arg(rt, term, i));
+        }
+      }
+      _ => return None,
+    }
+  }
+  return Some(size);
+}
+
+// Rotates the bits of a number, as a 120-bit word
+fn rotate_left_u120(a: u128, b: u128) -> u128 {
+  let b = b % 120;
+  if b == 0 {
+    return a;
+  }
+  return ((a << b) | (a >> (120 - b))) & NUM_MASK;
+}
+
+// Computes a call to a built-in function, if it is one and its arguments are numbers. Answers the
+// result and the mana it costs.
+fn call_builtin(rt: &Runtime, term: Ptr) -> Option<(u128, u128)> {
+  let num = |i| {
+    let arg = ask_arg(rt, term, i);
+    if get_tag(arg) == NUM { Some(get_num(arg)) } else { None }
+  };
+  match get_ext(term) {
+    MATH_SQRT => {
+      let n = num(0)?;
+      Some((n.isqrt(), SqrtMana()))
+    }
+    MATH_LOG2 => {
+      let n = num(0)?;
+      Some((n.checked_ilog2().unwrap_or(0) as u128, Log2Mana()))
+    }
+    MATH_POW_MOD => {
+      let (base, exp, modulus) = (num(0)?, num(1)?, num(2)?);
+      Some((pow_mod(base, exp, modulus), PowModMana(exp)))
+    }
+    MATH_MUL_DIV => {
+      let (a, b, c) = (num(0)?, num(1)?, num(2)?);
+      Some((mul_div(a, b, c), MulDivMana()))
+    }
+    PACK_GET => {
+      let (num, offset, width) = (num(0)?, num(1)?, num(2)?);
+      Some((pack_get(num, offset, width), PackMana()))
+    }
+    PACK_SET => {
+      let (num, offset, width, value) = (num(0)?, num(1)?, num(2)?, num(3)?);
+      Some((pack_set(num, offset, width, value), PackMana()))
+    }
+    _ => None,
+  }
+}
+
+// Products of numbers fit 240 bits, so they're taken as U256
+pub fn pow_mod(base: u128, exp: u128, modulus: u128) -> u128 {
+  if modulus == 0 {
+    return 0;
+  }
+  let modulus = U256::from(modulus);
+  let mut base = U256::from(base) % modulus;
+  let mut done = U256::one() % modulus;
+  let mut exp = exp;
+  while exp > 0 {
+    if exp & 1 == 1 {
+      done = done * base % modulus;
     }
+    base = base * base % modulus;
+    exp >>= 1;
+  }
+  return done.low_u128();
+}
 
-    break;
+pub fn mul_div(a: u128, b: u128, c: u128) -> u128 {
+  if c == 0 {
+    return 0;
   }
+  return (U256::from(a) * U256::from(b) / U256::from(c)).low_u128() & NUM_MASK;
+}
 
-  // FIXME: remove this when Runtime is split (see above)
-  //rt.get_heap_mut(self.curr).file = file;
+// Bits from `offset` to `offset + width`, as a mask. Those past the 120th are left out.
+fn pack_mask(offset: u128, width: u128) -> u128 {
+  if offset >= 120 {
+    return 0;
+  }
+  let ones = if width >= 120 { NUM_MASK } else { (1 << width) - 1 };
+  return (ones << offset) & NUM_MASK;
+}
 
-  return Ok(ask_lnk(rt, root));
+pub fn pack_get(num: u128, offset: u128, width: u128) -> u128 {
+  if offset >= 120 {
+    return 0;
+  }
+  return (num & pack_mask(offset, width)) >> offset;
+}
+
+pub fn pack_set(num: u128, offset: u128, width: u128, value: u128) -> u128 {
+  let mask = pack_mask(offset, width);
+  if mask == 0 {
+    return num;
+  }
+  return (num & !mask) | ((value << offset) & mask);
+}
+
+// The key of a pure call in the memo, if its arguments are data of up to MEMO_MAX_KEY nodes.
+// Hashing them costs mana, proportional to their size.
+fn memo_key(rt: &mut Runtime, term: Ptr, arity: u128) -> Option<[u8; 32]> {
+  let mut left = MEMO_MAX_KEY;
+  for i in 0 .. arity {
+    left -= data_size(rt, ask_arg(rt, term, i), left)?;
+  }
+  rt.set_mana(rt.get_mana() + MemoKeyMana(MEMO_MAX_KEY - left));
+  let args = (0 .. arity).map(|i| readback_linear_term(rt, ask_arg(rt, term, i))).collect();
+  return Some(hash_term(&Term::Fun { name: get_ext(term), args }).0);
+}
+
+// Keeps the result of a pure call in the memo, once reduced at `host`, if it's data and fits. Its
+// fields aren't computed here, since the caller may never need them.
+fn memoize(rt: &mut Runtime, key: [u8; 32], host: u128) {
+  let done = ask_lnk(rt, host);
+  if let Some(size) = data_size(rt, done, MEMO_MAX_SIZE - rt.memo.size) {
+    rt.set_mana(rt.get_mana() + MemoMana(size));
+    rt.memo.size += size;
+    rt.memo.results.insert(key, (readback_linear_term(rt, done), size));
+  }
 }
 
 /// Evaluates redexes iteratively. This is used to save space before storing a term, since,
@@ -3529,6 +6129,267 @@ pub fn compute_at(rt: &mut Runtime, host: u128, mana: u128) -> Result<Ptr, Runti
   Ok(output.pop().unwrap().unwrap())
 }
 
+// Integrity
+// ---------
+
+// A place, outside of the memory, holding a pointer into it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeapRoot {
+  Disk(u128), // the state of a function
+  Schd(u128), // the actions scheduled for a tick
+  Hook(u128), // the subscribers of a function
+}
+
+// Gathers the roots of the memory, from every heap on the rollback list
+pub fn heap_roots(rt: &Runtime) -> Vec<(HeapRoot, Ptr)> {
+  let mut keys: (HashSet<u128>, HashSet<u128>, HashSet<u128>) = Default::default();
+  rt.reduce_with(&mut keys, |(disks, schds, hooks), heap| {
+    disks.extend(heap.disk.links.keys());
+    schds.extend(heap.schd.schds.keys());
+    hooks.extend(heap.hook.hooks.keys());
+  });
+  let (disks, schds, hooks) = keys;
+  let mut roots = vec![];
+  for fid in disks {
+    if let Some(state) = rt.read_disk(fid).filter(|state| *state != 0) {
+      roots.push((HeapRoot::Disk(fid), state));
+    }
+  }
+  for tick in schds {
+    roots.push((HeapRoot::Schd(tick), rt.get_schedule(tick)));
+  }
+  for fid in hooks {
+    roots.push((HeapRoot::Hook(fid), rt.get_with(Num(0), 0, |heap| heap.read_hook(fid).unwrap_or(0))));
+  }
+  return roots;
+}
+
+pub fn set_heap_root(rt: &mut Runtime, root: HeapRoot, lnk: Ptr) {
+  match root {
+    HeapRoot::Disk(fid) => rt.write_disk(fid, lnk),
+    HeapRoot::Schd(tick) => rt.set_schedule(tick, lnk),
+    HeapRoot::Hook(fid) => rt.get_heap_mut(rt.draw).write_hook(fid, lnk),
+  }
+}
+
+// A problem found on the runtime heap by `check_heap`. The `host` is the location holding the
+// faulty pointer, or `None` when the pointer is a root (a function state, schedule or hook list).
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeapFault {
+  BadPointer { host: Option<u128>, lnk: Ptr }, // points to a free location, or has an invalid tag
+  BadArity { host: Option<u128>, name: u128 }, // a constructor or function that isn't declared
+  BadBinder { host: Option<u128>, lnk: Ptr },  // a variable whose binder doesn't point back to it
+  Shared { host: Option<u128>, node: u128 },   // a node that is reachable from two places
+  Leaked { loc: u128 },                        // an allocated word that no root reaches
+  BadSize { size: i128, used: u128 },          // the size counter doesn't match the used words
+}
+
+// Walks the runtime heap from its roots, validating every node on the way. Since memory isn't
+// managed by a free list, but by scanning for empty words, the allocator's consistency amounts to
+// every used word being reachable, and to the size counter matching the amount of used words.
+pub fn check_heap(rt: &Runtime) -> Vec<HeapFault> {
+  let mut faults = vec![];
+
+  // Gathers the used words of every heap on the rollback list
+  let mut words: HashSet<u128> = HashSet::new();
+  rt.reduce_with(&mut words, |words, heap| words.extend(heap.memo.iter().map(|(idx, _)| idx)));
+  let mut stack: Vec<(Option<u128>, Ptr)> = heap_roots(rt).into_iter().map(|(_, lnk)| (None, lnk)).collect();
+
+  // Visits every node reachable from the roots, marking its words as live
+  let mut live: HashSet<u128> = HashSet::new();
+  while let Some((host, term)) = stack.pop() {
+    // Finds the node's words, and where its children start
+    let (node, size, kids) = match get_tag(term) {
+      DP0 | DP1 => {
+        let node = get_loc(term, 0);
+        if let Some(host) = host {
+          if rt.read(node + (get_tag(term) & 1)) != Arg(host) {
+            faults.push(HeapFault::BadBinder { host: Some(host), lnk: term });
+          }
+        }
+        // Both sides of a dup share its node, so it is only visited by the first one
+        if live.contains(&(node + 2)) {
+          continue;
+        }
+        (node, 3, 2)
+      }
+      VAR => {
+        if let Some(host) = host {
+          if rt.read(get_loc(term, 0)) != Arg(host) {
+            faults.push(HeapFault::BadBinder { host: Some(host), lnk: term });
+          }
+        }
+        continue;
+      }
+      LAM => {
+        (get_loc(term, 0), 2, 1)
+      }
+      APP | SUP | OP2 => {
+        (get_loc(term, 0), 2, 0)
+      }
+      CTR | FUN => {
+        let arity = rt.get_arity(get_ext(term));
+        if arity == U128_NONE {
+          faults.push(HeapFault::BadArity { host, name: get_ext(term) });
+          continue;
+        }
+        (get_loc(term, 0), arity, 0)
+      }
+      NUM | ERA => {
+        continue;
+      }
+      _ => {
+        faults.push(HeapFault::BadPointer { host, lnk: term });
+        continue;
+      }
+    };
+    if size == 0 {
+      continue;
+    }
+    if live.contains(&node) {
+      faults.push(HeapFault::Shared { host, node });
+      continue;
+    }
+    if (0 .. size).any(|i| rt.read(node + i) == 0) {
+      faults.push(HeapFault::BadPointer { host, lnk: term });
+      continue;
+    }
+    for i in 0 .. size {
+      live.insert(node + i);
+    }
+    // Binder slots must hold the location of their variable, or be erased
+    for i in 0 .. kids {
+      let slot = rt.read(node + i);
+      if get_tag(slot) != ARG && get_tag(slot) != ERA {
+        faults.push(HeapFault::BadBinder { host: Some(node + i), lnk: slot });
+      }
+    }
+    for i in kids .. size {
+      stack.push((Some(node + i), rt.read(node + i)));
+    }
+  }
+
+  // Every used word must be live, and counted by the size
+  let mut used: u128 = 0;
+  let mut leaked: Vec<u128> = vec![];
+  for loc in words {
+    if rt.read(loc) != 0 {
+      used += 1;
+      if !live.contains(&loc) {
+        leaked.push(loc);
+      }
+    }
+  }
+  leaked.sort_unstable();
+  faults.extend(leaked.into_iter().map(|loc| HeapFault::Leaked { loc }));
+  if rt.get_size() != used as i128 {
+    faults.push(HeapFault::BadSize { size: rt.get_size(), used });
+  }
+
+  return faults;
+}
+
+pub fn show_heap_fault(fault: &HeapFault) -> String {
+  fn show_host(host: &Option<u128>) -> String {
+    host.map(|host| format!("{:x}", host)).unwrap_or_else(|| "root".to_string())
+  }
+  match fault {
+    HeapFault::BadPointer { host, lnk } => format!("Bad pointer {} at {}.", show_lnk(*lnk), show_host(host)),
+    HeapFault::BadArity { host, name } => format!("Undeclared '{}' at {}.", u128_to_name(*name), show_host(host)),
+    HeapFault::BadBinder { host, lnk } => format!("Unbound variable {} at {}.", show_lnk(*lnk), show_host(host)),
+    HeapFault::Shared { host, node } => format!("Node {:x} shared at {}.", node, show_host(host)),
+    HeapFault::Leaked { loc } => format!("Leaked word at {:x}.", loc),
+    HeapFault::BadSize { size, used } => format!("Size is {}, but {} words are used.", size, used),
+  }
+}
+
+// Compaction
+// ----------
+
+// Relocates the live nodes to the start of the memory, in address order, leaving the free space as
+// a single block after them, where the allocator resumes. Nodes are moved with regular writes to
+// the drawing heap, so a compaction is persisted and rolled back like any other change, and nodes
+// that are already in place aren't rewritten. Returns how many words were moved.
+pub fn compact(rt: &mut Runtime) -> u128 {
+  // Finds the live nodes, and their sizes
+  let roots = heap_roots(rt);
+  let mut nodes: Map<u128> = init_map();
+  let mut stack: Vec<Ptr> = roots.iter().map(|(_, lnk)| *lnk).collect();
+  while let Some(term) = stack.pop() {
+    let (node, size, kids) = match get_tag(term) {
+      DP0 | DP1       => (get_loc(term, 0), 3, 2),
+      LAM             => (get_loc(term, 0), 2, 1),
+      APP | SUP | OP2 => (get_loc(term, 0), 2, 0),
+      CTR | FUN       => (get_loc(term, 0), rt.get_arity(get_ext(term)), 0),
+      _               => continue,
+    };
+    if size == 0 || size == U128_NONE || nodes.contains_key(&node) {
+      continue;
+    }
+    nodes.insert(node, size);
+    for i in kids .. size {
+      stack.push(rt.read(node + i));
+    }
+  }
+
+  // Assigns each live word its new location
+  let mut nodes: Vec<(u128, u128)> = nodes.into_iter().collect();
+  nodes.sort_unstable();
+  let mut dest: Map<u128> = init_map();
+  let mut next: u128 = 0;
+  for (node, size) in &nodes {
+    for i in 0 .. *size {
+      dest.insert(node + i, next + i);
+    }
+    next += size;
+  }
+
+  // Redirects a pointer to the new location of its target
+  fn relocate(rt: &Runtime, dest: &Map<u128>, lnk: Ptr) -> Ptr {
+    let movable = match get_tag(lnk) {
+      DP0 | DP1 | VAR | ARG | LAM | APP | SUP | OP2 => true,
+      CTR | FUN => rt.get_arity(get_ext(lnk)) > 0,
+      _ => false,
+    };
+    match dest.get(&get_val(lnk)) {
+      Some(loc) if movable => lnk - get_val(lnk) + loc,
+      _ => lnk,
+    }
+  }
+
+  // Reads every word before writing any, since old and new locations may overlap
+  let mut words: Vec<(u128, Ptr)> = vec![];
+  let mut moved = 0;
+  for (node, size) in &nodes {
+    for loc in *node .. node + size {
+      words.push((dest[&loc], relocate(rt, &dest, rt.read(loc))));
+      if dest[&loc] != loc {
+        moved += 1;
+      }
+    }
+  }
+  for (node, size) in &nodes {
+    for loc in *node .. node + size {
+      if loc >= next {
+        rt.write(loc, 0);
+      }
+    }
+  }
+  for (loc, lnk) in words {
+    if rt.read(loc) != lnk {
+      rt.write(loc, lnk);
+    }
+  }
+  for (root, lnk) in roots {
+    let new_lnk = relocate(rt, &dest, lnk);
+    if new_lnk != lnk {
+      set_heap_root(rt, root, new_lnk);
+    }
+  }
+  rt.set_next(next);
+  return moved;
+}
+
 // Debug
 // -----
 
@@ -3719,6 +6580,11 @@ pub fn show_term(rt: &Runtime, term: Ptr, focus: Option<u128>) -> String {
                 GTE => ">=",
                 GTN => ">",
                 NEQ => "!=",
+                ROL => "rotl",
+                ROR => "rotr",
+                CLZ => "clz",
+                POP => "popcount",
+                BSW => "bswap",
                 _        => "?",
               };
               output.push(format!("({} ", symb));
@@ -3786,10 +6652,158 @@ fn show_runtime_error(err: RuntimeError) -> String {
     RuntimeError::NotEnoughMana => "Not enough mana.",
     RuntimeError::NotEnoughSpace => "Not enough space.",
     RuntimeError::TypeMismatch => "Runtime type mismatch.",
-    RuntimeError::EffectFailure => "Runtime effect failure."
+    RuntimeError::EffectFailure => "Runtime effect failure.",
+    RuntimeError::TermTooLarge => "Term too large.",
+    RuntimeError::CyclicTerm => "Term refers to itself.",
+    RuntimeError::StateTooLarge { size, limit } => {
+      return format!("State takes {} bytes, over the limit of {}.", size, limit);
+    }
+    RuntimeError::Timeout => "Execution timed out.",
+    RuntimeError::ArityMismatch { name, expected, found } => {
+      return if expected == U128_NONE {
+        format!("'{}' isn't declared.", u128_to_name(name))
+      } else {
+        format!("'{}' takes {} arguments, but is applied to {}.", u128_to_name(name), expected, found)
+      };
+    }
   }).to_string()
 }
 
+// Checks that a statement is within the given limits. Block validation and the mempool both use
+// this, so that nodes agree on which statements are accepted.
+pub fn check_statement(statement: &Statement, limits: StatementLimits) -> Result<(), StatementRejection> {
+  let arity = match statement {
+    Statement::Fun { args, .. } => args.len(),
+    Statement::Ctr { args, .. } => args.len(),
+    _ => 0,
+  };
+  if arity > limits.arity {
+    return Err(StatementRejection::TooManyFields { arity, limit: limits.arity });
+  }
+  if let Statement::Fun { func, .. } = statement {
+    if func.rules.len() > limits.rules {
+      return Err(StatementRejection::TooManyRules { rules: func.rules.len(), limit: limits.rules });
+    }
+  }
+  let size = util::bitvec_to_bytes(&bits::serialized_statement(statement)).len();
+  if size > limits.size {
+    return Err(StatementRejection::TooLarge { size, limit: limits.size });
+  }
+  return Ok(());
+}
+
+pub fn show_statement_rejection(rejection: StatementRejection) -> String {
+  match rejection {
+    StatementRejection::TooLarge { size, limit } => {
+      format!("Statement too large: {} bytes, limit is {}.", size, limit)
+    }
+    StatementRejection::TooManyRules { rules, limit } => {
+      format!("Too many rules: {}, limit is {}.", rules, limit)
+    }
+    StatementRejection::TooManyFields { arity, limit } => {
+      format!("Too many fields: {}, limit is {}.", arity, limit)
+    }
+  }
+}
+
+// Checks that a term is within the given limits before reading it back. The term is traversed
+// with a stack, in the same order the readback does, so this never overflows the native stack.
+pub fn check_term_size(rt: &Runtime, term: Ptr, limits: TermLimits) -> Result<(), RuntimeError> {
+  let mut dups: HashSet<u128> = HashSet::new();
+  let mut nodes: u128 = 0;
+  let mut stack = vec![(term, 1)];
+  while let Some((term, depth)) = stack.pop() {
+    nodes += 1;
+    if depth > limits.depth || nodes > limits.nodes {
+      return Err(RuntimeError::TermTooLarge);
+    }
+    match get_tag(term) {
+      LAM => {
+        stack.push((ask_arg(rt, term, 1), depth + 1));
+      }
+      APP | SUP | OP2 => {
+        stack.push((ask_arg(rt, term, 1), depth + 1));
+        stack.push((ask_arg(rt, term, 0), depth + 1));
+      }
+      DP0 | DP1 => {
+        if dups.insert(get_loc(term, 0)) {
+          stack.push((ask_arg(rt, term, 2), depth + 1));
+        }
+      }
+      CTR | FUN => {
+        let arity = rt.get_arity(get_ext(term));
+        for i in 0 .. arity {
+          stack.push((ask_arg(rt, term, i), depth + 1));
+        }
+      }
+      _ => {}
+    }
+  }
+  return Ok(());
+}
+
+// Size of a state, serialized, in bytes, which saved states are limited on
+pub fn state_size(state: &Term) -> usize {
+  util::bitvec_to_bytes(&bits::serialized_term(state)).len()
+}
+
+// Checks that a term can be saved as a state: that it has at most `limits.nodes` nodes, and that no
+// dup's expression reaches that same dup, as reading it back would never end. Dups reached again
+// after their expression was checked are only shared, and aren't walked twice. The depth isn't
+// limited, since states are read back with stacks, and long lists are common states.
+pub fn check_state_term(rt: &Runtime, term: Ptr, limits: TermLimits) -> Result<(), RuntimeError> {
+  enum StackItem {
+    Term(Ptr),
+    Exit(u128), // the expression of this dup was checked
+  }
+  let mut open: HashSet<u128> = HashSet::new(); // dups whose expression is being checked
+  let mut done: HashSet<u128> = HashSet::new();
+  let mut nodes: u128 = 0;
+  let mut stack = vec![StackItem::Term(term)];
+  while let Some(item) = stack.pop() {
+    let term = match item {
+      StackItem::Term(term) => term,
+      StackItem::Exit(dup) => {
+        open.remove(&dup);
+        done.insert(dup);
+        continue;
+      }
+    };
+    nodes += 1;
+    if nodes > limits.nodes {
+      return Err(RuntimeError::TermTooLarge);
+    }
+    match get_tag(term) {
+      LAM => {
+        stack.push(StackItem::Term(ask_arg(rt, term, 1)));
+      }
+      APP | SUP | OP2 => {
+        stack.push(StackItem::Term(ask_arg(rt, term, 1)));
+        stack.push(StackItem::Term(ask_arg(rt, term, 0)));
+      }
+      DP0 | DP1 => {
+        let dup = get_loc(term, 0);
+        if open.contains(&dup) {
+          return Err(RuntimeError::CyclicTerm);
+        }
+        if !done.contains(&dup) {
+          open.insert(dup);
+          stack.push(StackItem::Exit(dup));
+          stack.push(StackItem::Term(ask_arg(rt, term, 2)));
+        }
+      }
+      CTR | FUN => {
+        let arity = rt.get_arity(get_ext(term));
+        for i in 0 .. arity {
+          stack.push(StackItem::Term(ask_arg(rt, term, i)));
+        }
+      }
+      _ => {}
+    }
+  }
+  return Ok(());
+}
+
 // FIXME: This is NOT the readback function. I didn't notice it before. This is just the debug
 // stringification function, converted to return a term instead. There is a crucial difference: the
 // proper readback function does NOT return dups, i.e., it resolves pending dups, returning a
@@ -3859,19 +6873,17 @@ pub fn readback_linear_term(rt: &Runtime, term: Ptr) -> Term {
     if lets.is_empty() {
       cont
     } else {
-      let mut output = Term::Var { name: 0 };
-      for (i, (_key, pos)) in lets.iter().enumerate() {
+      let mut output = cont;
+      for (_key, pos) in lets.iter() {
         // todo: reverse
         let what = String::from("?h");
         let name = names.get(&pos).unwrap_or(&what);
-        let nam0 = if ask_lnk(rt, pos + 0) == Era() { String::from("*") } else { format!("a{}", name) };
-        let nam1 = if ask_lnk(rt, pos + 1) == Era() { String::from("*") } else { format!("b{}", name) };
+        // erased sides are written `~`
+        let era = if rt.is_active(Feature::EffectResults) { VAR_NONE } else { name_to_u128("*") };
+        let nam0 = if ask_lnk(rt, pos + 0) == Era() { era } else { name_to_u128(&format!("a{}", name)) };
+        let nam1 = if ask_lnk(rt, pos + 1) == Era() { era } else { name_to_u128(&format!("b{}", name)) };
         let expr = expr(rt, ask_lnk(rt, pos + 2), &names);
-        if i == 0 {
-          output = Term::Dup { nam0: name_to_u128(&nam0), nam1: name_to_u128(&nam1), expr: Box::new(expr), body: Box::new(cont.clone()) };
-        } else {
-          output = Term::Dup { nam0: name_to_u128(&nam0), nam1: name_to_u128(&nam1), expr: Box::new(expr), body: Box::new(output) };
-        }
+        output = Term::Dup { nam0, nam1, expr: Box::new(expr), body: Box::new(output) };
       }
       output
     }
@@ -3933,46 +6945,148 @@ pub fn readback_linear_term(rt: &Runtime, term: Ptr) -> Term {
               output.push(Term::Var { name: name_to_u128(&name) });
             }
             VAR => {
-              let name = format!("x{}", names.get(&get_loc(term, 0)).unwrap_or(&String::from("?x")));
-              output.push(Term::Var { name: name_to_u128(&name) });
+              let name = format!("x{}", names.get(&get_loc(term, 0)).unwrap_or(&String::from("?x")));
+              output.push(Term::Var { name: name_to_u128(&name) });
+            }
+            LAM => {
+              stack.push(StackItem::Resolver(term));
+              stack.push(StackItem::Term(ask_arg(rt, term, 1)));
+            }
+            APP => {
+              stack.push(StackItem::Resolver(term));
+              stack.push(StackItem::Term(ask_arg(rt, term, 1)));
+              stack.push(StackItem::Term(ask_arg(rt, term, 0)));
+            }
+            SUP => {}
+            OP2 => {
+              stack.push(StackItem::Resolver(term));
+              stack.push(StackItem::Term(ask_arg(rt, term, 1)));
+              stack.push(StackItem::Term(ask_arg(rt, term, 0)));
+            }
+            NUM => {
+              let numb = get_num(term);
+              output.push(Term::Num { numb });
+            }
+            CTR => {
+              let func = get_ext(term);
+              let arit = rt.get_arity(func);
+              stack.push(StackItem::Resolver(term));
+              for i in 0..arit {
+                stack.push(StackItem::Term(ask_arg(rt, term, i)));
+              }
+            }
+            FUN => {
+              let func = get_ext(term);
+              let arit = rt.get_arity(func);
+              stack.push(StackItem::Resolver(term));
+              for i in 0..arit {
+                stack.push(StackItem::Term(ask_arg(rt, term, i)));
+              }
+            }
+            ERA => {}
+            _ => {}
+          }
+        }
+      }
+    }
+    output.pop().unwrap()
+  }
+
+  let mut names: HashMap<u128, String> = HashMap::new();
+  dups(rt, term, &mut names)
+}
+
+// Reads a term back from the heap as the graph it is, rather than as a tree. A first pass walks the
+// nodes reachable from the term, numbering its lambdas and dups in the order they're found; a
+// second reads each node back once. Dups whose sides are both used share their expression, which is
+// read back once, as a `dup` binding, however many copies of it the heap stands for. Dups with an
+// erased side share nothing, so their expression is read back in place of the side that's used.
+//
+// Each binding is placed outside of the ones whose expressions use it, so the result loads back as
+// it reads, and it depends on the shape of the graph only, not on where its nodes are nor on the
+// order of a hash map. States are read back with this for the API and the CLI, while the terms the
+// runtime hashes are still read back by `readback_linear_term`, which nodes must agree on. Saved
+// states were checked by `check_state_term`, so no dup's expression leads back to itself.
+pub fn readback_term(rt: &Runtime, term: Ptr) -> Term {
+  enum StackItem {
+    Term(Ptr),
+    Resolver(Ptr),
+  }
+
+  // Reads the nodes under `term` back, stopping at the sides of shared dups, which are pushed to
+  // `shared` in the order they're used
+  fn read(rt: &Runtime, term: Ptr, names: &HashMap<u128, u128>, shared: &mut Vec<u128>) -> Term {
+    let name = |prefix: &str, loc: u128| {
+      names.get(&loc).map(|count| name_to_u128(&format!("{}{}", prefix, count))).unwrap_or(VAR_NONE)
+    };
+    let mut stack = vec![StackItem::Term(term)];
+    let mut output = Vec::new();
+    while let Some(item) = stack.pop() {
+      match item {
+        StackItem::Resolver(term) => {
+          match get_tag(term) {
+            LAM => {
+              let body = Box::new(output.pop().unwrap());
+              output.push(Term::Lam { name: name("x", get_loc(term, 0)), body });
+            }
+            APP => {
+              let argm = Box::new(output.pop().unwrap());
+              let func = Box::new(output.pop().unwrap());
+              output.push(Term::App { func, argm });
+            }
+            OP2 => {
+              let val1 = Box::new(output.pop().unwrap());
+              let val0 = Box::new(output.pop().unwrap());
+              output.push(Term::Op2 { oper: get_ext(term), val0, val1 });
+            }
+            _ => {
+              let name = get_ext(term);
+              let args = output.split_off(output.len() - rt.get_arity(name) as usize);
+              if get_tag(term) == CTR {
+                output.push(Term::Ctr { name, args });
+              } else {
+                output.push(Term::Fun { name, args });
+              }
+            }
+          }
+        }
+        StackItem::Term(term) => {
+          match get_tag(term) {
+            DP0 | DP1 => {
+              let dup = get_loc(term, 0);
+              let (side, other) = if get_tag(term) == DP0 { ("a", 1) } else { ("b", 0) };
+              if ask_lnk(rt, dup + other) == Era() {
+                stack.push(StackItem::Term(ask_lnk(rt, dup + 2)));
+              } else {
+                shared.push(dup);
+                output.push(Term::Var { name: name(side, dup) });
+              }
+            }
+            VAR => {
+              output.push(Term::Var { name: name("x", get_loc(term, 0)) });
             }
             LAM => {
               stack.push(StackItem::Resolver(term));
               stack.push(StackItem::Term(ask_arg(rt, term, 1)));
             }
-            APP => {
-              stack.push(StackItem::Resolver(term));
-              stack.push(StackItem::Term(ask_arg(rt, term, 1)));
-              stack.push(StackItem::Term(ask_arg(rt, term, 0)));
-            }
-            SUP => {}
-            OP2 => {
+            APP | OP2 => {
               stack.push(StackItem::Resolver(term));
               stack.push(StackItem::Term(ask_arg(rt, term, 1)));
               stack.push(StackItem::Term(ask_arg(rt, term, 0)));
             }
             NUM => {
-              let numb = get_num(term);
-              output.push(Term::Num { numb });
+              output.push(Term::Num { numb: get_num(term) });
             }
-            CTR => {
-              let func = get_ext(term);
-              let arit = rt.get_arity(func);
+            CTR | FUN => {
               stack.push(StackItem::Resolver(term));
-              for i in 0..arit {
+              for i in (0 .. rt.get_arity(get_ext(term))).rev() {
                 stack.push(StackItem::Term(ask_arg(rt, term, i)));
               }
             }
-            FUN => {
-              let func = get_ext(term);
-              let arit = rt.get_arity(func);
-              stack.push(StackItem::Resolver(term));
-              for i in 0..arit {
-                stack.push(StackItem::Term(ask_arg(rt, term, i)));
-              }
+            // Superpositions and erasures have no syntax, so they're shown as erased variables
+            _ => {
+              output.push(Term::Var { name: VAR_NONE });
             }
-            ERA => {}
-            _ => {}
           }
         }
       }
@@ -3980,8 +7094,60 @@ pub fn readback_linear_term(rt: &Runtime, term: Ptr) -> Term {
     output.pop().unwrap()
   }
 
-  let mut names: HashMap<u128, String> = HashMap::new();
-  dups(rt, term, &mut names)
+  // Numbers the lambdas and dups, in the order they're found
+  let mut names: HashMap<u128, u128> = HashMap::new();
+  let mut stack = vec![term];
+  while let Some(term) = stack.pop() {
+    match get_tag(term) {
+      LAM => {
+        let count = names.len() as u128;
+        names.entry(get_loc(term, 0)).or_insert(count);
+        stack.push(ask_arg(rt, term, 1));
+      }
+      APP | SUP | OP2 => {
+        stack.push(ask_arg(rt, term, 1));
+        stack.push(ask_arg(rt, term, 0));
+      }
+      DP0 | DP1 => {
+        let count = names.len() as u128;
+        if let hash_map::Entry::Vacant(entry) = names.entry(get_loc(term, 0)) {
+          entry.insert(count);
+          stack.push(ask_arg(rt, term, 2));
+        }
+      }
+      CTR | FUN => {
+        for i in (0 .. rt.get_arity(get_ext(term))).rev() {
+          stack.push(ask_arg(rt, term, i));
+        }
+      }
+      _ => {}
+    }
+  }
+
+  // Reads the shared expressions back once each, listing every binding after the ones it uses
+  let mut shared = vec![];
+  let body = read(rt, term, &names, &mut shared);
+  let mut exprs: HashMap<u128, Term> = HashMap::new();
+  let mut order = vec![];
+  let mut stack: Vec<(u128, bool)> = shared.into_iter().rev().map(|dup| (dup, false)).collect();
+  while let Some((dup, visited)) = stack.pop() {
+    if visited {
+      order.push(dup);
+    } else if let hash_map::Entry::Vacant(entry) = exprs.entry(dup) {
+      let mut uses = vec![];
+      entry.insert(read(rt, ask_lnk(rt, dup + 2), &names, &mut uses));
+      stack.push((dup, true));
+      stack.extend(uses.into_iter().rev().map(|dup| (dup, false)));
+    }
+  }
+  let mut output = body;
+  for dup in order.into_iter().rev() {
+    let nam0 = name_to_u128(&format!("a{}", names[&dup]));
+    let nam1 = name_to_u128(&format!("b{}", names[&dup]));
+    let expr = Box::new(exprs.remove(&dup).unwrap());
+    output = Term::Dup { nam0, nam1, expr, body: Box::new(output) };
+  }
+  output
 }
 
 // Parsing
@@ -4037,26 +7203,26 @@ fn hash(name: &str) -> u128 {
   hasher.finish() as u128
 }
 
-fn is_name_char(chr: char) -> bool {
+pub fn is_name_char(chr: char) -> bool {
   return chr == '_' || chr == '.'
       || chr >= 'a' && chr <= 'z'
       || chr >= 'A' && chr <= 'Z'
       || chr >= '0' && chr <= '9';
 }
 
-pub fn read_char(code: &str, chr: char) -> ParseResult<()> {
+pub fn read_char(code: &str, chr: char) -> ParseResult<'_, ()> {
   let code = skip(code);
   if head(code) == chr {
     Ok((tail(code), ()))
   } else {
     Err(ParseErr {
-      erro: format!("Expected '{}', found '{}'. Context:\n\x1b[2m{}\x1b[0m", chr, head(code), code.chars().take(64).collect::<String>()),
+      erro: format!("Expected '{}', found '{}'.\nContext: {}", chr, head(code), code.chars().take(64).collect::<String>()),
       code: code.to_string(),
     })
   }
 }
 
-pub fn read_numb(code: &str) -> ParseResult<u128> {
+pub fn read_numb(code: &str) -> ParseResult<'_, u128> {
   let mut code = skip(code);
   if head(code) == 'x' {
     code = tail(code);
@@ -4087,7 +7253,7 @@ pub fn read_numb(code: &str) -> ParseResult<u128> {
   }
 }
 
-pub fn read_name(code: &str) -> ParseResult<u128> {
+pub fn read_name(code: &str) -> ParseResult<'_, u128> {
   let code = skip(code);
   let mut name = String::new();
   if head(code) == '~' {
@@ -4130,7 +7296,7 @@ pub fn read_name(code: &str) -> ParseResult<u128> {
   }
 }
 
-pub fn read_hex(code: &str) -> ParseResult<Vec<u8>> {
+pub fn read_hex(code: &str) -> ParseResult<'_, Vec<u8>> {
   let mut data : Vec<u8> = Vec::new();
   let mut code = skip(code);
   while nth(code,0).is_ascii_hexdigit() && nth(code,1).is_ascii_hexdigit() {
@@ -4191,7 +7357,7 @@ pub fn u128_to_name(num: u128) -> String {
   name.chars().rev().collect()
 }
 
-pub fn read_until<A>(code: &str, stop: char, read: fn(&str) -> ParseResult<A>) -> ParseResult<Vec<A>> {
+pub fn read_until<A>(code: &str, stop: char, read: fn(&str) -> ParseResult<A>) -> ParseResult<'_, Vec<A>> {
   let mut elems = Vec::new();
   let mut code = code;
   while code.len() > 0 && head(skip(code)) != stop {
@@ -4203,7 +7369,7 @@ pub fn read_until<A>(code: &str, stop: char, read: fn(&str) -> ParseResult<A>) -
   return Ok((code, elems));
 }
 
-pub fn read_term(code: &str) -> ParseResult<Term> {
+pub fn read_term(code: &str) -> ParseResult<'_, Term> {
   let code = skip(code);
   match head(code) {
     '@' => {
@@ -4217,7 +7383,11 @@ pub fn read_term(code: &str) -> ParseResult<Term> {
       let (code, oper) = read_oper(code);
       if let Some(oper) = oper {
         let (code, val0) = read_term(code)?;
-        let (code, val1) = read_term(code)?;
+        let (code, val1) = if is_unary_oper(oper) && head(skip(code)) == ')' {
+          (code, Term::Num { numb: 0 })
+        } else {
+          read_term(code)?
+        };
         let (code, unit) = read_char(code, ')')?;
         return Ok((code, Term::Op2 { oper: oper, val0: Box::new(val0), val1: Box::new(val1) }));
       } else if head(code) == '!' {
@@ -4258,6 +7428,9 @@ pub fn read_term(code: &str) -> ParseResult<Term> {
     },
     '#' => {
       let code = tail(code);
+      if head(code) == '[' {
+        return read_packed(tail(code));
+      }
       let (code, numb) = read_numb(code)?;
       return Ok((code, Term::Num { numb }));
     },
@@ -4323,6 +7496,28 @@ pub fn read_term(code: &str) -> ParseResult<Term> {
   }
 }
 
+// Reads the fields of `#[val0:width0 val1:width1 ...]`, after the `[`, as calls to PackSet, the
+// first field taking the least significant bits
+pub fn read_packed(code: &str) -> ParseResult<'_, Term> {
+  let mut code = code;
+  let mut term = Term::Num { numb: 0 };
+  let mut offset: u128 = 0;
+  while !skip(code).is_empty() && head(skip(code)) != ']' {
+    let (new_code, value) = read_term(code)?;
+    let (new_code, ()) = read_char(new_code, ':')?;
+    let (new_code, width) = read_numb(new_code)?;
+    code = new_code;
+    if offset.saturating_add(width) > 120 {
+      return Err(ParseErr { code: code.to_string(), erro: "Packed fields take more than 120 bits".to_string() });
+    }
+    let args = vec![term, Term::Num { numb: offset }, Term::Num { numb: width }, value];
+    term = Term::Fun { name: PACK_SET, args };
+    offset += width;
+  }
+  let (code, ()) = read_char(code, ']')?;
+  return Ok((code, term));
+}
+
 pub fn read_oper(in_code: &str) -> (&str, Option<u128>) {
   let code = skip(in_code);
   match head(code) {
@@ -4353,23 +7548,33 @@ pub fn read_oper(in_code: &str) -> (&str, Option<u128>) {
       '=' => (tail(tail(code)), Some(NEQ)),
       _   => (code, None),
     },
-    _ => (code, None),
+    _ => {
+      // Named operations, which must be followed by a space, so they aren't taken for variables
+      for (word, oper) in [("rotl", ROL), ("rotr", ROR), ("clz", CLZ), ("popcount", POP), ("bswap", BSW)] {
+        if let Some(rest) = code.strip_prefix(word) {
+          if rest.starts_with(char::is_whitespace) {
+            return (rest, Some(oper));
+          }
+        }
+      }
+      (code, None)
+    }
   }
 }
 
-pub fn read_rule(code: &str) -> ParseResult<Rule> {
+pub fn read_rule(code: &str) -> ParseResult<'_, Rule> {
   let (code, lhs) = read_term(code)?;
   let (code, ())  = read_char(code, '=')?;
   let (code, rhs) = read_term(code)?;
   return Ok((code, Rule{lhs, rhs}));
 }
 
-pub fn read_rules(code: &str) -> ParseResult<Vec<Rule>> {
+pub fn read_rules(code: &str) -> ParseResult<'_, Vec<Rule>> {
   let (code, rules) = read_until(code, '\0', read_rule)?;
   return Ok((code, rules));
 }
 
-pub fn read_func(code: &str) -> ParseResult<CompFunc> {
+pub fn read_func(code: &str) -> ParseResult<'_, CompFunc> {
   let (code, rules) = read_until(code, '\0', read_rule)?;
   let func = Func { rules };
   if let Some(func) = compile_func(&func, false) {
@@ -4382,7 +7587,7 @@ pub fn read_func(code: &str) -> ParseResult<CompFunc> {
   }
 }
 
-pub fn read_sign(code: &str) -> ParseResult<Option<crypto::Signature>> {
+pub fn read_sign(code: &str) -> ParseResult<'_, Option<crypto::Signature>> {
   let code = skip(code);
   if let ('s','i','g','n') = (nth(code,0), nth(code,1), nth(code,2), nth(code,3)) {
     let code = drop(code,4);
@@ -4401,14 +7606,48 @@ pub fn read_sign(code: &str) -> ParseResult<Option<crypto::Signature>> {
   return Ok((code, None));
 }
 
-pub fn read_statement(code: &str) -> ParseResult<Statement> {
+// Reads the `use 'Name' { code_hash }` declarations of a `fun` statement, which pin the code of the
+// functions it uses
+fn read_uses(code: &str) -> ParseResult<'_, Vec<(u128, U256)>> {
+  let mut uses = vec![];
+  let mut code = skip(code);
+  while let ('u','s','e') = (nth(code,0), nth(code,1), nth(code,2)) {
+    let (rest, unit) = read_char(drop(code,3), '\'')?;
+    let (rest, name) = read_name(rest)?;
+    let (rest, unit) = read_char(rest, '\'')?;
+    let (rest, unit) = read_char(rest, '{')?;
+    let (rest, hash) = read_hex(rest)?;
+    let (rest, unit) = read_char(rest, '}')?;
+    if hash.len() != 32 {
+      return Err(ParseErr {
+        code: rest.to_string(),
+        erro: "Wrong code hash size".to_string()
+      });
+    }
+    uses.push((name, U256::from_big_endian(&hash)));
+    code = skip(rest);
+  }
+  return Ok((code, uses));
+}
+
+// Reads an argument of a `fun` statement, which is strict if written `!name`
+fn read_fun_arg(code: &str) -> ParseResult<'_, (bool, u128)> {
+  let code = skip(code);
+  let (code, strict) = if nth(code, 0) == '!' { (drop(code, 1), true) } else { (code, false) };
+  let (code, name) = read_name(code)?;
+  return Ok((code, (strict, name)));
+}
+
+pub fn read_statement(code: &str) -> ParseResult<'_, Statement> {
   let code = skip(code);
   match (nth(code,0), nth(code,1), nth(code,2)) {
     ('f','u','n') => {
       let code = drop(code,3);
       let (code, unit) = read_char(code, '(')?;
       let (code, name) = read_name(code)?;
-      let (code, args) = read_until(code, ')', read_name)?;
+      let (code, args) = read_until(code, ')', read_fun_arg)?;
+      let strict = args.iter().enumerate().filter(|(_, (strict, _))| *strict).map(|(i, _)| i as u128).collect();
+      let args = args.into_iter().map(|(_, name)| name).collect();
       let (code, unit) = read_char(code, '{')?;
       let (code, ruls) = read_until(code, '}', read_rule)?;
       let code = skip(code);
@@ -4421,9 +7660,27 @@ pub fn read_statement(code: &str) -> ParseResult<Statement> {
       } else {
         (code, Term::Num { numb: 0 })
       };
+      let code = skip(code);
+      let (code, mana) = if let ('m','a','n','a') = (nth(code,0), nth(code,1), nth(code,2), nth(code,3)) {
+        let code = drop(code,4);
+        let (code, unit) = read_char(code, '{')?;
+        let (code, unit) = read_char(code, '#')?;
+        let (code, mana) = read_numb(code)?;
+        let (code, unit) = read_char(code, '}')?;
+        (code, Some(mana))
+      } else {
+        (code, None)
+      };
+      let code = skip(code);
+      let (code, pure) = if let ('p','u','r','e') = (nth(code,0), nth(code,1), nth(code,2), nth(code,3)) {
+        (drop(code,4), true)
+      } else {
+        (code, false)
+      };
+      let (code, uses) = read_uses(code)?;
       let (code, sign) = read_sign(code)?;
       let func = Func { rules: ruls };
-      return Ok((code, Statement::Fun { name, args, func, init, sign }));
+      return Ok((code, Statement::Fun { name, args, func, init, mana, strict, pure, uses, sign }));
     }
     ('c','t','r') => {
       let code = drop(code,3);
@@ -4438,8 +7695,19 @@ pub fn read_statement(code: &str) -> ParseResult<Statement> {
       let (code, unit) = read_char(code, '{')?;
       let (code, expr) = read_term(code)?;
       let (code, unit) = read_char(code, '}')?;
+      let code = skip(code);
+      let (code, price) = if let ('p','r','i','c','e') = (nth(code,0), nth(code,1), nth(code,2), nth(code,3), nth(code,4)) {
+        let code = drop(code,5);
+        let (code, unit) = read_char(code, '{')?;
+        let (code, unit) = read_char(code, '#')?;
+        let (code, price) = read_numb(code)?;
+        let (code, unit) = read_char(code, '}')?;
+        (code, Some(price))
+      } else {
+        (code, None)
+      };
       let (code, sign) = read_sign(code)?;
-      return Ok((code, Statement::Run { expr, sign  }));
+      return Ok((code, Statement::Run { expr, price, sign }));
     }
     // reg Foo.Bar { #x123456 } sign { signature }
     ('r','e','g') => {
@@ -4452,16 +7720,41 @@ pub fn read_statement(code: &str) -> ParseResult<Statement> {
       let (code, sign) = read_sign(code)?;
       return Ok((code, Statement::Reg { name, ownr, sign }));
     }
+    // rot Foo.Bar { #x123456 #100 } sign { signature }
+    ('r','o','t') => {
+      let code = skip(drop(code, 3));
+      let (code, name) = if nth(code,0) == '{' { (code, 0) } else { read_name(code)? };
+      let (code, unit) = read_char(code, '{')?;
+      let (code, unit) = read_char(code, '#')?;
+      let (code, succ) = read_numb(code)?;
+      let (code, unit) = read_char(code, '#')?;
+      let (code, delay) = read_numb(code)?;
+      let (code, unit) = read_char(code, '}')?;
+      let (code, sign) = read_sign(code)?;
+      return Ok((code, Statement::Rot { name, succ, delay, sign }));
+    }
     _ => {
       return Err(ParseErr { code: code.to_string(),  erro: "Expected statement.".to_string() });
     }
   }
 }
 
-pub fn read_statements(code: &str) -> ParseResult<Vec<Statement>> {
+pub fn read_statements(code: &str) -> ParseResult<'_, Vec<Statement>> {
   read_until(code, '\0', read_statement)
 }
 
+// Line and column, from 1, where parsing `source` failed. Errors hold the code left to parse, so
+// they're located by its length.
+pub fn parse_error_location(source: &str, err: &ParseErr) -> Option<(usize, usize)> {
+  if !source.ends_with(&err.code) {
+    return None;
+  }
+  let parsed = &source[.. source.len() - err.code.len()];
+  let line = parsed.matches('\n').count() + 1;
+  let column = parsed.rsplit('\n').next().unwrap_or_default().chars().count() + 1;
+  Some((line, column))
+}
+
 // View
 // ----
 
@@ -4474,22 +7767,39 @@ pub fn view_name(name: u128) -> String {
 }
 
 pub fn view_term(term: &Term) -> String {
+  return view_term_limited(term, None, None).unwrap();
+}
+
+// Shows a term on one line, writing subterms `depth` or more levels down as `...`. Gives up,
+// returning None, once the text gets longer than `budget` characters.
+pub fn view_term_limited(term: &Term, depth: Option<usize>, budget: Option<usize>) -> Option<String> {
   enum StackItem<'a> {
-    Term(&'a Term),
+    Term(&'a Term, usize),
     Str(String),
   }
 
-  let mut stack = vec![StackItem::Term(term)];
+  let mut stack = vec![StackItem::Term(term, 0)];
   let mut output = Vec::new();
+  let mut length = 0;
 
   while !stack.is_empty() {
+    if budget.map_or(false, |budget| length > budget) {
+      return None;
+    }
     let item = stack.pop().unwrap();
 
     match item {
       StackItem::Str(str) => {
+        length += str.len();
         output.push(str);
       }
-      StackItem::Term(term) => {  
+      StackItem::Term(_, level) if depth.map_or(false, |depth| level >= depth) => {
+        length += 3;
+        output.push("...".to_string());
+      }
+      StackItem::Term(term, level) => {
+        let level = level + 1;
+        let start = output.len();
         match term {
           Term::Var { name } => {
             output.push(view_name(*name));
@@ -4500,20 +7810,20 @@ pub fn view_term(term: &Term) -> String {
             output.push(" ".to_string());
             output.push(view_name(*nam1));
             output.push(" = ".to_string());
-            stack.push(StackItem::Term(&*body));
+            stack.push(StackItem::Term(body, level));
             stack.push(StackItem::Str("; ".to_string()));
-            stack.push(StackItem::Term(&*expr));
+            stack.push(StackItem::Term(expr, level));
           }
           Term::Lam { name, body } => {
             output.push(format!("@{} ", view_name(*name)));
-            stack.push(StackItem::Term(&*body));
+            stack.push(StackItem::Term(body, level));
           }
           Term::App { func, argm } => {
             output.push("(".to_string());
             stack.push(StackItem::Str(")".to_string()));
-            stack.push(StackItem::Term(&*argm));
+            stack.push(StackItem::Term(argm, level));
             stack.push(StackItem::Str(" ".to_string()));
-            stack.push(StackItem::Term(&*func));
+            stack.push(StackItem::Term(func, level));
           }
           Term::Ctr { name, args } => {
             let name = view_name(*name);
@@ -4527,7 +7837,7 @@ pub fn view_term(term: &Term) -> String {
               output.push(name);
               stack.push(StackItem::Str("}".to_string()));
               for arg in args.iter().rev() {
-                stack.push(StackItem::Term(arg));
+                stack.push(StackItem::Term(arg, level));
                 stack.push(StackItem::Str(" ".to_string()));
               }
             }
@@ -4538,7 +7848,7 @@ pub fn view_term(term: &Term) -> String {
             output.push(name);
             stack.push(StackItem::Str(")".to_string()));
             for arg in args.iter().rev() {
-              stack.push(StackItem::Term(arg));
+              stack.push(StackItem::Term(arg, level));
               stack.push(StackItem::Str(" ".to_string()));
             }
           }
@@ -4551,19 +7861,131 @@ pub fn view_term(term: &Term) -> String {
             //}
           }
           Term::Op2 { oper, val0, val1 } => {
-            let oper = view_oper(oper);
-            output.push(format!("({} ", oper));
+            output.push(format!("({} ", view_oper(oper)));
             stack.push(StackItem::Str(")".to_string()));
-            stack.push(StackItem::Term(val1));
-            stack.push(StackItem::Str(" ".to_string()));
-            stack.push(StackItem::Term(val0));
+            if !is_implicit_operand(*oper, val1) {
+              stack.push(StackItem::Term(val1, level));
+              stack.push(StackItem::Str(" ".to_string()));
+            }
+            stack.push(StackItem::Term(val0, level));
           }
         }
+        length += output[start ..].iter().map(|str| str.len()).sum::<usize>();
       }
     }
   }
+  if budget.map_or(false, |budget| length > budget) {
+    return None;
+  }
   let res = output.join("");
-  res
+  Some(res)
+}
+
+// How terms and statements are laid out over several lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+  pub width: usize,  // columns lines are kept within, when they can be
+  pub indent: usize, // columns each level is indented by
+}
+
+impl Default for Layout {
+  fn default() -> Self {
+    Layout { width: 80, indent: 2 }
+  }
+}
+
+// Shows a term over several lines. Constructors and calls that don't fit in the layout's width
+// have their arguments indented on lines of their own. Subterms `depth` or more levels down are
+// shown as `...`, so that large states can be skimmed.
+pub fn view_term_pretty(term: &Term, layout: &Layout, depth: Option<usize>) -> String {
+  view_term_pretty_at(term, layout, depth, 0, 0)
+}
+
+// Like `view_term_pretty`, for a term starting at `column` of a line indented by `indent`
+fn view_term_pretty_at<'a>(term: &'a Term, layout: &Layout, depth: Option<usize>, column: usize, indent: usize) -> String {
+  enum StackItem<'a> {
+    Term(&'a Term, usize, usize), // term, level, indentation
+    Line(usize),                  // line break, then indentation
+    Str(String),
+  }
+
+  let width = layout.width;
+  let mut stack = vec![StackItem::Term(term, 0, indent)];
+  let mut output = String::new();
+  let mut column = column;
+
+  while let Some(item) = stack.pop() {
+    match item {
+      StackItem::Str(str) => {
+        column += str.len();
+        output.push_str(&str);
+      }
+      StackItem::Line(indent) => {
+        output.push('\n');
+        output.push_str(&" ".repeat(indent));
+        column = indent;
+      }
+      StackItem::Term(term, level, indent) => {
+        let depth_left = depth.map(|depth| depth.saturating_sub(level));
+        if let Some(flat) = view_term_limited(term, depth_left, Some(width.saturating_sub(column))) {
+          column += flat.len();
+          output.push_str(&flat);
+          continue;
+        }
+        // Too wide: its parts are laid out in the same way, one level down
+        let level = level + 1;
+        let mut open = |head: String, args: Vec<&'a Term>, tail: &str| {
+          stack.push(StackItem::Str(tail.to_string()));
+          stack.push(StackItem::Line(indent));
+          for arg in args.into_iter().rev() {
+            stack.push(StackItem::Term(arg, level, indent + layout.indent));
+            stack.push(StackItem::Line(indent + layout.indent));
+          }
+          stack.push(StackItem::Str(head));
+        };
+        match term {
+          Term::Ctr { name, args } => {
+            open(format!("{{{}", view_name(*name)), args.iter().collect(), "}");
+          }
+          Term::Fun { name, args } => {
+            open(format!("({}", view_name(*name)), args.iter().collect(), ")");
+          }
+          Term::App { func, argm } => {
+            open("(".to_string(), vec![func, argm], ")");
+          }
+          Term::Op2 { oper, val0, val1 } => {
+            let vals: Vec<&Term> = if is_implicit_operand(*oper, val1) { vec![val0] } else { vec![val0, val1] };
+            open(format!("({}", view_oper(oper)), vals, ")");
+          }
+          Term::Lam { name, body } => {
+            stack.push(StackItem::Term(body, level, indent));
+            stack.push(StackItem::Str(format!("@{} ", view_name(*name))));
+          }
+          Term::Dup { nam0, nam1, expr, body } => {
+            stack.push(StackItem::Term(body, level, indent));
+            stack.push(StackItem::Line(indent));
+            stack.push(StackItem::Str(";".to_string()));
+            stack.push(StackItem::Term(expr, level, indent + layout.indent));
+            stack.push(StackItem::Str(format!("dup {} {} = ", view_name(*nam0), view_name(*nam1))));
+          }
+          Term::Var { .. } | Term::Num { .. } => {
+            stack.push(StackItem::Str(view_term(term)));
+          }
+        }
+      }
+    }
+  }
+  output
+}
+
+// Unary operations take a second operand, which they ignore. It's #0 when written out of code, and
+// left out of views then.
+pub fn is_unary_oper(oper: u128) -> bool {
+  matches!(oper, CLZ | POP | BSW)
+}
+
+fn is_implicit_operand(oper: u128, val1: &Term) -> bool {
+  is_unary_oper(oper) && *val1 == Term::Num { numb: 0 }
 }
 
 pub fn view_oper(oper: &u128) -> String {
@@ -4584,11 +8006,22 @@ pub fn view_oper(oper: &u128) -> String {
     GTE => ">=",
     GTN => ">",
     NEQ => "!=",
+    ROL => "rotl",
+    ROR => "rotr",
+    CLZ => "clz",
+    POP => "popcount",
+    BSW => "bswap",
     _   => "??",
   }.to_string()
 }
 
+// Shows a statement as code, with its terms laid out by `view_term_pretty`
 pub fn view_statement(statement: &Statement) -> String {
+  view_statement_pretty(statement, &Layout::default())
+}
+
+pub fn view_statement_pretty(statement: &Statement, layout: &Layout) -> String {
+  let tab = " ".repeat(layout.indent);
   fn view_sign(sign: &Option<crypto::Signature>) -> String {
     fn format_sign(sign: &crypto::Signature) -> String {
       let hex = sign.to_hex();
@@ -4606,15 +8039,27 @@ pub fn view_statement(statement: &Statement) -> String {
     }
   }
   match statement {
-    Statement::Fun { name, args, func, init, sign } => {
+    Statement::Fun { name, args, func, init, mana, strict, pure, uses, sign } => {
       let name = u128_to_name(*name);
-      let func = func.rules.iter().map(|x| format!("\n  {} = {}", view_term(&x.lhs), view_term(&x.rhs)));
+      let func = func.rules.iter().map(|x| {
+        let lhs = format!("{}{} = ", tab, view_term(&x.lhs));
+        format!("\n{}{}", lhs, view_term_pretty_at(&x.rhs, layout, None, lhs.len(), layout.indent))
+      });
       let func = func.collect::<Vec<String>>().join("");
-      let args = args.iter().map(|x| u128_to_name(*x)).collect::<Vec<String>>().join(" ");
-      let init = view_term(init);
-      let init = format!(" with {{\n  {}\n}}", init);
+      let args = args.iter().enumerate().map(|(i, x)| {
+        format!("{}{}", if strict.contains(&(i as u128)) { "!" } else { "" }, u128_to_name(*x))
+      }).collect::<Vec<String>>().join(" ");
+      let init = view_term_pretty_at(init, layout, None, layout.indent, layout.indent);
+      let init = format!(" with {{\n{}{}\n}}", tab, init);
+      let mana = mana.map(|mana| format!(" mana {{ #{} }}", mana)).unwrap_or_default();
+      let pure = if *pure { " pure" } else { "" };
+      let uses = uses.iter().map(|(used, code)| {
+        let mut hash = [0u8; 32];
+        code.to_big_endian(&mut hash);
+        format!(" use '{}' {{ {} }}", u128_to_name(*used), hex::encode(hash))
+      }).collect::<Vec<String>>().join("");
       let sign = view_sign(sign);
-      return format!("fun ({} {}) {{{}\n}}{}{}", name, args, func, init, sign);
+      return format!("fun ({} {}) {{{}\n}}{}{}{}{}{}", name, args, func, init, mana, pure, uses, sign);
     }
     Statement::Ctr { name, args, sign } => {
       // correct:
@@ -4623,10 +8068,11 @@ pub fn view_statement(statement: &Statement) -> String {
       let sign = view_sign(sign);
       return format!("ctr {{{}{}}}{}", name, args, sign);
     }
-    Statement::Run { expr, sign } => {
-      let expr = view_term(expr);
+    Statement::Run { expr, price, sign } => {
+      let expr = view_term_pretty_at(expr, layout, None, layout.indent, layout.indent);
+      let price = price.map(|price| format!(" price {{ #{} }}", price)).unwrap_or_default();
       let sign = view_sign(sign);
-      return format!("run {{\n  {}\n}}{}", expr, sign);
+      return format!("run {{\n{}{}\n}}{}{}", tab, expr, price, sign);
     }
     Statement::Reg { name, ownr, sign } => {
       let name = u128_to_name(*name);
@@ -4634,6 +8080,12 @@ pub fn view_statement(statement: &Statement) -> String {
       let sign = view_sign(sign);
       return format!("reg {} {{ {} }}{}", name, ownr, sign);
     }
+    Statement::Rot { name, succ, delay, sign } => {
+      let name = u128_to_name(*name);
+      let succ = format!("#x{:0>30x}", succ);
+      let sign = view_sign(sign);
+      return format!("rot {} {{ {} #{} }}{}", name, succ, delay, sign);
+    }
   }
 }
 
@@ -4646,6 +8098,124 @@ pub fn view_statements(statements: &[Statement]) -> String {
   return result;
 }
 
+// Diffing
+// -------
+
+// A difference between two terms. The path goes from the root to where they differ, as the index
+// of the child taken at each step (see `term_children`).
+#[derive(Debug, Clone, PartialEq)]
+pub enum TermChange {
+  Insert { path: Vec<usize>, term: Term },            // a field only the new term has
+  Delete { path: Vec<usize>, term: Term },            // a field only the old term has
+  Replace { path: Vec<usize>, old: Term, new: Term }, // a subterm that differs
+}
+
+// Children of a term, in order
+fn term_children(term: &Term) -> Vec<&Term> {
+  match term {
+    Term::Var { .. } | Term::Num { .. } => vec![],
+    Term::Dup { expr, body, .. } => vec![expr, body],
+    Term::Lam { body, .. } => vec![body],
+    Term::App { func, argm } => vec![func, argm],
+    Term::Op2 { val0, val1, .. } => vec![val0, val1],
+    Term::Ctr { args, .. } | Term::Fun { args, .. } => args.iter().collect(),
+  }
+}
+
+// Do two terms differ only in their children?
+fn same_head(old: &Term, new: &Term) -> bool {
+  match (old, new) {
+    (Term::Var { name: old }, Term::Var { name: new }) => old == new,
+    (Term::Num { numb: old }, Term::Num { numb: new }) => old == new,
+    (Term::Dup { nam0, nam1, .. }, Term::Dup { nam0: new0, nam1: new1, .. }) => nam0 == new0 && nam1 == new1,
+    (Term::Lam { name, .. }, Term::Lam { name: new, .. }) => name == new,
+    (Term::App { .. }, Term::App { .. }) => true,
+    (Term::Op2 { oper, .. }, Term::Op2 { oper: new, .. }) => oper == new,
+    (Term::Ctr { name, .. }, Term::Ctr { name: new, .. }) => name == new,
+    (Term::Fun { name, .. }, Term::Fun { name: new, .. }) => name == new,
+    _ => false,
+  }
+}
+
+// Finds where two terms differ, outermost and leftmost first. Terms with the same head are
+// compared child by child, so constructors of the same name only differ in the fields that do.
+// When they have a different number of fields, the ones past the shorter are deleted or inserted.
+// Anything else is replaced whole. Equal terms have no changes.
+pub fn diff_terms(old: &Term, new: &Term) -> Vec<TermChange> {
+  enum StackItem<'a> {
+    Compare(&'a Term, &'a Term, Vec<usize>),
+    Change(TermChange),
+  }
+
+  let mut stack = vec![StackItem::Compare(old, new, vec![])];
+  let mut changes = vec![];
+
+  while let Some(item) = stack.pop() {
+    let (old, new, path) = match item {
+      StackItem::Compare(old, new, path) => (old, new, path),
+      StackItem::Change(change) => {
+        changes.push(change);
+        continue;
+      }
+    };
+    if !same_head(old, new) {
+      changes.push(TermChange::Replace { path, old: old.clone(), new: new.clone() });
+      continue;
+    }
+    let old_children = term_children(old);
+    let new_children = term_children(new);
+    let shared = old_children.len().min(new_children.len());
+    let child_path = |i: usize| {
+      let mut child_path = path.clone();
+      child_path.push(i);
+      child_path
+    };
+    for (i, term) in new_children.iter().enumerate().skip(shared).rev() {
+      stack.push(StackItem::Change(TermChange::Insert { path: child_path(i), term: (*term).clone() }));
+    }
+    for (i, term) in old_children.iter().enumerate().skip(shared).rev() {
+      stack.push(StackItem::Change(TermChange::Delete { path: child_path(i), term: (*term).clone() }));
+    }
+    for i in (0 .. shared).rev() {
+      stack.push(StackItem::Compare(old_children[i], new_children[i], child_path(i)));
+    }
+  }
+
+  changes
+}
+
+// Shows a path into a term, naming the terms it goes through, e.g. `Pair[1].Leaf[0]`
+pub fn view_term_path(root: &Term, path: &[usize]) -> String {
+  if path.is_empty() {
+    return "root".to_string();
+  }
+  let mut steps = vec![];
+  let mut term = Some(root);
+  for index in path {
+    let head = match term {
+      Some(Term::Ctr { name, .. }) | Some(Term::Fun { name, .. }) => u128_to_name(*name),
+      Some(Term::Lam { name, .. }) => format!("@{}", u128_to_name(*name)),
+      Some(Term::Op2 { oper, .. }) => view_oper(oper),
+      Some(Term::App { .. }) => "app".to_string(),
+      Some(Term::Dup { .. }) => "dup".to_string(),
+      Some(Term::Var { .. }) | Some(Term::Num { .. }) | None => "?".to_string(),
+    };
+    steps.push(format!("{}[{}]", head, index));
+    term = term.and_then(|term| term_children(term).get(*index).copied());
+  }
+  steps.join(".")
+}
+
+// Shows a change on one line: `~ path: old -> new`, `+ path: new` or `- path: old`. `root` is the
+// old term, whose heads name the path.
+pub fn view_term_change(root: &Term, change: &TermChange) -> String {
+  match change {
+    TermChange::Insert { path, term } => format!("+ {}: {}", view_term_path(root, path), view_term(term)),
+    TermChange::Delete { path, term } => format!("- {}: {}", view_term_path(root, path), view_term(term)),
+    TermChange::Replace { path, old, new } => format!("~ {}: {} -> {}", view_term_path(root, path), view_term(old), view_term(new)),
+  }
+}
+
 // Hashing
 // -------
 
@@ -4657,6 +8227,223 @@ pub fn hash_statement(statement: &Statement) -> crypto::Hash {
   crypto::keccak256(&util::bitvec_to_bytes(&bits::serialized_statement(&remove_sign(&statement))))
 }
 
+// Signing
+// -------
+
+// Statements aren't signed by their hash alone, but by a payload naming what is signed and on which
+// network, so a signature can't be replayed on another network, nor passed off as one of something
+// other than a statement:
+//
+//   domain     18 bytes, "KINDELIA_STATEMENT"
+//   version     1 byte, of this layout
+//   network     8 bytes, big-endian
+//   statement  32 bytes, its hash without the signature
+//
+// What's signed is the keccak256 of the payload.
+pub const SIGN_DOMAIN : &[u8] = b"KINDELIA_STATEMENT";
+pub const SIGN_VERSION : u8 = 1;
+
+// Network whose statements this node runs. A fork of the chain changes it, so that signatures of
+// either network are invalid on the other.
+pub const NETWORK_ID : u64 = 1;
+
+pub struct SignPayload {
+  pub network: u64,
+  pub hash: crypto::Hash, // of the statement, as `hash_statement`
+}
+
+impl SignPayload {
+  pub fn new(statement: &Statement, network: u64) -> Self {
+    SignPayload { network, hash: hash_statement(statement) }
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = SIGN_DOMAIN.to_vec();
+    bytes.push(SIGN_VERSION);
+    bytes.extend_from_slice(&self.network.to_be_bytes());
+    bytes.extend_from_slice(&self.hash.0);
+    bytes
+  }
+
+  // The hash that gets signed
+  pub fn digest(&self) -> crypto::Hash {
+    crypto::keccak256(&self.to_bytes())
+  }
+}
+
+// Hash that the signature of a statement signs, on this network
+pub fn sign_hash(statement: &Statement) -> crypto::Hash {
+  SignPayload::new(statement, NETWORK_ID).digest()
+}
+
+// Templates
+// ---------
+
+// Most arguments a call can pass, as they go in a tuple
+pub const MAX_CALL_ARGS : usize = 12;
+
+// Builds the statement that calls a function and returns what it returns, bidding a max mana
+// price if given:
+//
+//   run { ask x = (Call 'Name' [args...]); (Done x) }
+pub fn call_statement(name: u128, args: Vec<Term>, price: Option<u128>) -> Result<Statement, String> {
+  if args.len() > MAX_CALL_ARGS {
+    return Err(format!("Calls take at most {} arguments.", MAX_CALL_ARGS));
+  }
+  let x = name_to_u128("x");
+  let tuple = Term::Ctr { name: name_to_u128(&format!("T{}", args.len())), args };
+  let call = Term::Fun { name: CALL, args: vec![Term::Num { numb: name }, tuple] };
+  let done = Term::Fun { name: DONE, args: vec![Term::Var { name: x }] };
+  let expr = Term::App { func: Box::new(call), argm: Box::new(Term::Lam { name: x, body: Box::new(done) }) };
+  Ok(Statement::Run { expr, price, sign: None })
+}
+
+// Hashes the code of a function, taking its own name, where its rules match or call it, as `#0`.
+// So copies of a function deployed under other names hash the same.
+pub fn hash_func(name: u128, func: &Func) -> crypto::Hash {
+  let mut func = func.clone();
+  let mut stack: Vec<&mut Term> = vec![];
+  for rule in &mut func.rules {
+    stack.push(&mut rule.lhs);
+    stack.push(&mut rule.rhs);
+  }
+  while let Some(term) = stack.pop() {
+    match term {
+      Term::Fun { name: fnid, args } => {
+        if *fnid == name {
+          *fnid = 0;
+        }
+        stack.extend(args.iter_mut());
+      }
+      Term::Ctr { args, .. } => {
+        stack.extend(args.iter_mut());
+      }
+      Term::Dup { expr, body, .. } => {
+        stack.push(expr);
+        stack.push(body);
+      }
+      Term::Lam { body, .. } => {
+        stack.push(body);
+      }
+      Term::App { func, argm } => {
+        stack.push(func);
+        stack.push(argm);
+      }
+      Term::Op2 { val0, val1, .. } => {
+        stack.push(val0);
+        stack.push(val1);
+      }
+      Term::Var { .. } | Term::Num { .. } => {}
+    }
+  }
+  crypto::keccak256(&util::bitvec_to_bytes(&bits::serialized_func(&func)))
+}
+
+// Hashes the state of a runtime: its tick and randomness beacon, plus the arity, owner, code and
+// state of every name, in name order. Terms are hashed by shape, not by location, so runtimes that
+// computed the same blocks agree on it however their heaps are laid out.
+pub fn hash_runtime_state(rt: &Runtime) -> crypto::Hash {
+  let mut names: BTreeSet<u128> = BTreeSet::new();
+  rt.reduce_with(&mut names, |names, heap| {
+    names.extend(heap.disk.links.keys());
+    names.extend(heap.file.funcs.keys());
+    names.extend(heap.arit.arits.keys());
+    names.extend(heap.ownr.ownrs.keys());
+    names.extend(heap.rotn.rotations.keys());
+  });
+  let mut bytes: Vec<u8> = vec![];
+  bytes.extend_from_slice(&rt.get_tick().to_le_bytes());
+  bytes.extend_from_slice(&rt.get_rand().to_le_bytes());
+  // Only once the mana market moved them, so the hash of states before it is unchanged
+  if rt.get_mana_price() != INITIAL_MANA_PRICE || rt.get_burned() != 0 {
+    bytes.extend_from_slice(&rt.get_mana_price().to_le_bytes());
+    bytes.extend_from_slice(&rt.get_burned().to_le_bytes());
+  }
+  for name in names {
+    bytes.extend_from_slice(&name.to_le_bytes());
+    bytes.extend_from_slice(&rt.get_arity(name).to_le_bytes());
+    bytes.extend_from_slice(&rt.get_owner(name).to_le_bytes());
+    if let Some(func) = rt.get_func(name) {
+      bytes.extend_from_slice(&crypto::keccak256(&util::bitvec_to_bytes(&bits::serialized_func(&func.func))).0);
+      // Only when declared, so the hash of functions without strict arguments is unchanged
+      for arg in &func.strict {
+        bytes.extend_from_slice(&arg.to_le_bytes());
+      }
+      if func.pure {
+        bytes.extend_from_slice(b"pure");
+      }
+    }
+    if let Some(tick) = rt.get_frozen(name) {
+      bytes.extend_from_slice(b"frozen");
+      bytes.extend_from_slice(&tick.to_le_bytes());
+    }
+    if let Some((succ, tick)) = rt.get_rotation(name) {
+      bytes.extend_from_slice(b"rotation");
+      bytes.extend_from_slice(&succ.to_le_bytes());
+      bytes.extend_from_slice(&tick.to_le_bytes());
+    }
+    if let Some(state) = rt.get_with(None, None, |heap| heap.read_disk(name)) {
+      hash_heap_term(rt, state, &mut bytes);
+    }
+  }
+  return crypto::keccak256(&bytes);
+}
+
+// Writes the shape of a term of the memory to `bytes`. Nodes are numbered in the order they are
+// first reached, instead of by location. Back-references from binders to their variables are
+// skipped, since the variables themselves are written.
+fn hash_heap_term(rt: &Runtime, term: Ptr, bytes: &mut Vec<u8>) {
+  let mut ids: HashMap<u128, u128> = HashMap::new();
+  let mut expanded: HashSet<u128> = HashSet::new();
+  let mut stack = vec![term];
+  while let Some(term) = stack.pop() {
+    let tag = get_tag(term);
+    bytes.push(tag as u8);
+    if tag == NUM {
+      bytes.extend_from_slice(&get_num(term).to_le_bytes());
+      continue;
+    }
+    if tag == ERA {
+      continue;
+    }
+    // Nullary constructors take no memory, so their location means nothing
+    if (tag == CTR || tag == FUN) && rt.get_arity(get_ext(term)) == 0 {
+      bytes.extend_from_slice(&get_ext(term).to_le_bytes());
+      continue;
+    }
+    let loc = get_loc(term, 0);
+    let next = ids.len() as u128;
+    let id = *ids.entry(loc).or_insert(next);
+    bytes.extend_from_slice(&get_ext(term).to_le_bytes());
+    bytes.extend_from_slice(&id.to_le_bytes());
+    if !expanded.insert(loc) {
+      continue;
+    }
+    match tag {
+      LAM => {
+        stack.push(ask_arg(rt, term, 1));
+      }
+      APP | SUP | OP2 => {
+        stack.push(ask_arg(rt, term, 1));
+        stack.push(ask_arg(rt, term, 0));
+      }
+      DP0 | DP1 => {
+        stack.push(ask_arg(rt, term, 2));
+      }
+      CTR | FUN => {
+        let arity = rt.get_arity(get_ext(term));
+        for i in (0 .. arity).rev() {
+          stack.push(ask_arg(rt, term, i));
+        }
+      }
+      _ => {
+        // Variables only point at their binder
+        expanded.remove(&loc);
+      }
+    }
+  }
+}
+
 // Tests
 // -----
 
@@ -4674,8 +8461,8 @@ pub fn print_io_consts() {
   }
 }
 
-// Serializes, deserializes and evaluates statements
-pub fn test_statements(statements: &[Statement]) {
+// Serializes, deserializes and evaluates statements, counting the rewrites performed if asked to
+pub fn test_statements(statements: &[Statement], count_rewrites: bool) -> Option<RewriteStats> {
   let str_0 = view_statements(statements);
   let str_1 = view_statements(&crate::bits::deserialized_statements(&crate::bits::serialized_statements(&statements)).unwrap());
 
@@ -4684,8 +8471,12 @@ pub fn test_statements(statements: &[Statement]) {
   println!();
 
   let mut rt = init_runtime(None);
+  if count_rewrites {
+    rt.start_rewrite_stats();
+  }
   let init = Instant::now();
   rt.run_statements(&statements, false);
+  let rewrites = count_rewrites.then(|| rt.stop_rewrite_stats());
   println!();
 
   println!("Stats");
@@ -4696,16 +8487,20 @@ pub fn test_statements(statements: &[Statement]) {
   println!("[mana] {}", rt.get_mana());
   println!("[rwts] {}", rt.get_rwts());
   println!("[time] {} ms", init.elapsed().as_millis());
+  rewrites
 }
 
-pub fn test_statements_from_code(code: &str) {
+pub fn test_statements_from_code(code: &str, count_rewrites: bool) -> Option<RewriteStats> {
   let statments = read_statements(code);
   match statments {
-    Ok((.., statements)) => test_statements(&statements),
-    Err(ParseErr { code, erro }) => println!("{}", erro),
+    Ok((.., statements)) => test_statements(&statements, count_rewrites),
+    Err(ParseErr { code, erro }) => {
+      println!("{}", erro);
+      None
+    }
   }
 }
 
 pub fn test_statements_from_file(file: &str) {
-  test_statements_from_code(&std::fs::read_to_string(file).expect("file not found"));
+  test_statements_from_code(&std::fs::read_to_string(file).expect("file not found"), false);
 }
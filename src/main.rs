@@ -12,24 +12,48 @@ mod test;
 use rstest_reuse;
 
 mod api;
+mod backup;
+mod bench;
 mod bits;
+mod color;
 mod crypto;
+mod datadir;
 mod hvm;
+#[cfg(feature = "mdns")]
+mod mdns;
+#[cfg(feature = "mmap")]
+mod mmap;
+mod net;
 mod node;
+mod policy;
+mod profile;
+mod query;
+mod runtime;
+mod onion;
+mod socks;
+mod upgrade;
 mod util;
+mod wallet;
+mod webhook;
 mod NoHashHasher;
 
-use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use rand::{Rng, SeedableRng};
 
-pub use clap::{Parser, Subcommand};
+pub use clap::{CommandFactory, Parser, Subcommand};
 
 use crate::api::http::http_api_loop;
+use crate::backup::BackupSchedule;
+use crate::policy::{LocalPolicy, StatementPolicy};
 use crate::bits::*;
+use crate::color::{ColorMode, Colors, Style};
 use crate::hvm::*;
 use crate::node::*;
 use crate::util::*;
+use crate::webhook::Webhook;
 
 // Testnet nodes
 // TODO: move to config file
@@ -55,7 +79,20 @@ fn main() -> Result<(), String> {
     //println!("{:?}", encode_length(i as usize));
     //println!("{} == {}", i, decode_length(encode_length(i as usize)));
   //}
-  return run_cli();
+  let arguments = Cli::parse();
+  let output = arguments.output;
+  let colors = Colors::stderr(arguments.color);
+  match run_cli(arguments) {
+    Err(err) if output == OutputFormat::Json => {
+      eprintln!("{}", serde_json::json!({ "error": err }));
+      std::process::exit(1);
+    }
+    Err(err) => {
+      eprintln!("{} {}", colors.paint(Style::Error, "error:"), err);
+      std::process::exit(1);
+    }
+    result => result,
+  }
   //start_node(dirs::home_dir().unwrap().join(".kindelia"), false);
   //hvm::test_statements_from_file("./example/block_1.kdl");
   //return Ok(());
@@ -70,10 +107,55 @@ pub struct Cli {
   /// Path where Kindelia files are stored
   #[clap(long)]
   path: Option<String>,
+  /// Profile whose config, keys and data are used (see `profile.rs`). Defaults to `$KINDELIA_PROFILE`.
+  #[clap(long, global = true)]
+  profile: Option<String>,
+  /// URL of the node API, for commands that query a node [default: the profile's, or http://127.0.0.1:8000].
+  /// Can be repeated, to fall over to the next node when one can't be reached.
+  #[clap(long, global = true, alias = "node")]
+  api: Vec<String>,
+  /// Prints results as `text` or as `json`, for scripts. In JSON, errors are printed on stderr as `{"error": ...}`.
+  #[clap(long, global = true, default_value = "text")]
+  output: OutputFormat,
+  /// Columns that printed terms and statements are laid out in
+  #[clap(long, global = true, default_value_t = Layout::default().width)]
+  width: usize,
+  /// Colors terms and errors: `auto` (when printing to a terminal), `always` or `never`
+  #[clap(long, global = true, default_value = "auto")]
+  color: ColorMode,
   #[clap(subcommand)]
   pub command: CliCmd,
 }
 
+// How commands print their results
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+  Text,
+  Json,
+}
+
+impl std::str::FromStr for OutputFormat {
+  type Err = String;
+  fn from_str(code: &str) -> Result<Self, Self::Err> {
+    match code {
+      "text" => Ok(OutputFormat::Text),
+      "json" => Ok(OutputFormat::Json),
+      _ => Err(format!("Invalid output format: '{}'. Expected 'text' or 'json'.", code)),
+    }
+  }
+}
+
+impl OutputFormat {
+  // Prints a result: the text for people, or the JSON for scripts
+  fn emit(self, text: impl std::fmt::Display, json: serde_json::Value) {
+    match self {
+      OutputFormat::Text => println!("{}", text),
+      OutputFormat::Json => println!("{}", json),
+    }
+  }
+}
+
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 pub enum CliCmd {
   /// Starts a Kindelia node
@@ -84,15 +166,132 @@ pub enum CliCmd {
     /// Mine blocks
     #[clap(long)]
     mine: bool,
+    /// Randomly delays and drops messages, and fails disk writes (for resilience testing)
+    #[clap(long, hide = true)]
+    chaos: bool,
+    /// Caps the bandwidth each peer may use, in bytes per second each way
+    #[clap(long)]
+    peer_bandwidth: Option<u128>,
+    /// Listens on this address, e.g. `0.0.0.0:42000` or `[::]:42000`. Can be repeated.
+    #[clap(long)]
+    listen: Vec<SocketAddr>,
+    /// Shares this address of the node with peers, besides the listen ones. Can be repeated.
+    #[clap(long)]
+    advertise: Vec<String>,
+    /// Prefers peers of this address family (`ipv4` or `ipv6`)
+    #[clap(long)]
+    prefer: Option<AddressFamily>,
+    /// Sends messages through this SOCKS5 proxy, which must relay UDP (Tor doesn't)
+    #[clap(long)]
+    proxy: Option<SocketAddr>,
+    /// Reaches onion peers over TCP through Tor's SOCKS5 proxy at this address, e.g. `127.0.0.1:9050`
+    #[clap(long)]
+    tor: Option<SocketAddr>,
+    /// Onion address of this node, e.g. `<56 characters>.onion:42000`. Needs `--tor`. Tor must forward it to 127.0.0.1 on the same port.
+    #[clap(long)]
+    onion: Option<String>,
+    /// Doesn't look for nodes on the local network with mDNS (it's off on testnet anyway)
+    #[clap(long)]
+    no_mdns: bool,
+    /// Peers only with this address, dropping messages from any other. Can be repeated.
+    #[clap(long)]
+    connect_only: Vec<String>,
+    /// Names the account paid by the blocks this node mines, once blocks pay fees
+    #[clap(long)]
+    payout: Option<String>,
+    /// Tags the blocks this node mines with up to 7 bytes, e.g. a version or a miner's name, as text or 0x-prefixed hex
+    #[clap(long)]
+    extra_data: Option<String>,
+    /// Percentage of the time spent mining, from 1 to 100. Can be changed at `POST /mine/intensity/<n>`.
+    #[clap(long, default_value = "100")]
+    mining_intensity: u8,
+    /// Blocks a pending statement may wait to be mined before it's dropped
+    #[clap(long, default_value_t = POOL_TTL)]
+    pool_ttl: u128,
+    /// Runs the miner only on these cores, e.g. `2,3`
+    #[clap(long, use_value_delimiter = true)]
+    miner_cores: Vec<usize>,
+    /// Nice level of the miner thread; higher values yield the CPU to the rest of the node
+    #[clap(long)]
+    miner_nice: Option<i32>,
+    /// Answers queries on states older than the retained snapshots (e.g. `?at=<height>`), replaying
+    /// the blocks from genesis
+    #[clap(long)]
+    archive: bool,
+    /// Replays recent blocks on a shadow runtime every 10 minutes, alerting if its state differs from the live one
+    #[clap(long)]
+    verify_replay: bool,
+    /// Runs the computed blocks on a second runtime too, configured as a candidate implementation,
+    /// alerting when its state differs from the live one: `same`, or `linear` (without rule tables)
+    #[clap(long)]
+    shadow: Option<Candidate>,
+    /// Aborts blocks that run for longer than this many milliseconds, halting the node until it's
+    /// restarted with a larger budget. Other nodes don't see it, so it never splits the chain.
+    #[clap(long)]
+    block_timeout: Option<u64>,
+    /// Forks the state of the node with this API URL: functions it has are fetched the first time
+    /// a statement mentions them. The local chain doesn't peer with anyone, so it can diverge.
+    #[clap(long, requires = "fork-height", conflicts_with = "testnet")]
+    fork: Option<String>,
+    /// Height of the block of the forked node whose state is used
+    #[clap(long, requires = "fork")]
+    fork_height: Option<u64>,
+    /// Seeds the node's random choices (mining nonces, peers picked, chaos), to reproduce a run
+    #[clap(long)]
+    seed: Option<u64>,
+    /// Posts chain changes to the webhooks listed in this JSON file (see `webhook.rs`)
+    #[clap(long)]
+    webhooks: Option<PathBuf>,
+    /// Rule picking the tip: `most-work`, as on mainnet, or `heaviest-subtree` (GHOST), only with
+    /// `--testnet` or `--connect-only`, where every node must run the same one
+    #[clap(long, default_value = "most-work")]
+    fork_choice: ForkChoiceRule,
+    /// Pauses mining and taking blocks while the data directory has less than this many MiB free. 0 turns it off.
+    #[clap(long, default_value_t = MIN_FREE_SPACE)]
+    min_free_space: u64,
+    /// Compresses the runtime snapshots with zstd at this level, from 1 to 22: higher ones are
+    /// smaller and slower. 0 saves them uncompressed. Snapshots saved before are read either way.
+    #[clap(long, default_value_t = hvm::HEAP_COMPRESSION_LEVEL)]
+    compression_level: i32,
+    /// Largest state a function can save, serialized, in bytes. Only other than the default with
+    /// `--testnet` or `--connect-only`, where every node must use the same one
+    #[clap(long, default_value_t = hvm::DEFAULT_STATE_LIMIT)]
+    max_state_size: usize,
+    /// Activates a consensus rule change at another height, as `<feature>=<height>` (see
+    /// `GET /upgrades`). Only with `--testnet` or `--connect-only`. Can be repeated.
+    #[clap(long)]
+    activate: Vec<String>,
+    /// Signals in the blocks this node mines that it's ready for a consensus rule change. Takes
+    /// 2 bytes of the extra data. Can be repeated.
+    #[clap(long)]
+    signal: Vec<String>,
   },
   /// Runs a Kindelia (.kdl) file
   Run {
     /// Input file
     file: String,
+    /// Counts the rewrites performed by kind (APP-LAM, FUN-CTR, ...), writing them to this CSV file
+    #[clap(long)]
+    rewrites: Option<PathBuf>,
+  },
+  /// Checks the integrity of the persisted runtime state
+  Fsck,
+  /// Lists the named profiles
+  Profiles,
+  /// Upgrades the data directory to the layout of this version, which `start` also does
+  Migrate {
+    /// Only lists the migrations that would run
+    #[clap(long)]
+    dry_run: bool,
+  },
+  /// Manages the data of the node
+  Node {
+    #[clap(subcommand)]
+    command: NodeCmd,
   },
   /// Prints the address and subject of a secret key
   Subject {
-    /// File containing the 256-bit secret key, as a hex string
+    /// File containing the 256-bit secret key, as a hex string, or the name of a key of the profile
     skey: String,
   },
   /// Prints all statements in a Kindelia (.kdl) file
@@ -112,10 +311,16 @@ pub enum CliCmd {
   },
   /// Signs a serialized statement
   Sign {
-    /// File containing the 256-bit secret key, as a hex string
+    /// File containing the 256-bit secret key, as a hex string, or the name of a key of the profile
     skey: String,
     /// The statement to be signed, in hex
     hex: String,
+    /// Prints what would be signed, and by whom, without signing it
+    #[clap(long)]
+    preview: bool,
+    /// Signs it for the network with this id, instead of this node's
+    #[clap(long, default_value_t = hvm::NETWORK_ID)]
+    network: u64,
   },
   /// Posts a serialized statement to the network
   Post {
@@ -123,6 +328,220 @@ pub enum CliCmd {
     hex: String,
     /// IP of the node to submit it to
     addr: Option<String>,
+    /// Submits it to the node API instead, which checks it, falling over to the next `--api` node
+    /// when one can't be reached
+    #[clap(long, conflicts_with = "addr")]
+    via_api: bool,
+  },
+  /// Calls a function on the chain: builds the `run` statement that calls it with some arguments,
+  /// checks it on the node, and posts it
+  Call {
+    /// Name of the function
+    name: String,
+    /// Arguments, one term for each of the function's, e.g. `{Inc}`
+    args: Vec<String>,
+    /// Signs it with this secret key file, or key of the profile, so the function sees its subject
+    #[clap(long)]
+    sign: Option<String>,
+    /// Only prints the statement and what running it takes, without posting it
+    #[clap(long)]
+    dry_run: bool,
+    /// Block height after which the statement is dropped, if not mined
+    #[clap(long)]
+    expires: Option<u64>,
+    /// Max mana price it bids; the node's base price of the next block if left out
+    #[clap(long)]
+    price: Option<u128>,
+  },
+  /// Keeps track of accounts whose secret keys are elsewhere, preparing statements to be signed offline
+  Wallet {
+    #[clap(subcommand)]
+    command: WalletCmd,
+  },
+  /// Prints how far a node is from its peers' chain, and how long it should take to catch up
+  Sync {
+    /// Prints the progress again every second, until the node catches up
+    #[clap(long)]
+    follow: bool,
+  },
+  /// Inspects the blocks of a node
+  Block {
+    #[clap(subcommand)]
+    command: BlockCmd,
+  },
+  /// Inspects the state of functions on a node
+  State {
+    #[clap(subcommand)]
+    command: StateCmd,
+  },
+  /// Converts names, terms and statements between encodings, for scripts
+  Util {
+    #[clap(subcommand)]
+    command: UtilCmd,
+  },
+  /// Measures what a chain can take, on synthetic workloads
+  Bench {
+    #[clap(subcommand)]
+    command: BenchCmd,
+  },
+  /// Prints a completion script for a shell (`bash`, `zsh`, `fish`, `powershell` or `elvish`)
+  Completions {
+    shell: clap_complete::Shell,
+  },
+}
+
+#[derive(Subcommand)]
+pub enum NodeCmd {
+  /// Replaces the data of the node with a backup (see `backup.rs`). Stop the node first.
+  RestoreBackup {
+    /// Backup file, as written by the node
+    file: PathBuf,
+  },
+  /// Has a running node compact its heap now, instead of waiting for the next periodic compaction
+  Compact,
+}
+
+#[derive(Subcommand)]
+pub enum WalletCmd {
+  /// Watches an account, given as a subject (`#x...`), an address (`0x...`) or a public key, in hex
+  Watch {
+    account: String,
+    /// Name to refer to it by [default: its subject]
+    #[clap(long)]
+    label: Option<String>,
+  },
+  /// Stops watching an account, given its label or subject
+  Unwatch {
+    account: String,
+  },
+  /// Prints the statements the watched accounts signed that wait on the node's pool or were included in the chain
+  Status {
+    /// Only this account, given its label or subject
+    account: Option<String>,
+    /// Included statements printed per account, the latest ones
+    #[clap(long, default_value = "10")]
+    recent: usize,
+  },
+  /// Serializes the statements of a file unsigned, with the digests to sign, to be signed offline with `sign`
+  Prepare {
+    file: PathBuf,
+    /// Prepares them for the network with this id, instead of this node's
+    #[clap(long, default_value_t = hvm::NETWORK_ID)]
+    network: u64,
+  },
+}
+
+#[derive(Subcommand)]
+pub enum BlockCmd {
+  /// Prints a block of the longest chain, with its statements and their results
+  Show {
+    /// Hash (`0x...`) or height of the block
+    block: String,
+  },
+}
+
+#[derive(Subcommand)]
+pub enum StateCmd {
+  /// Prints the state of a function
+  Get {
+    /// Name of the function
+    name: String,
+    /// Shows subterms this many levels down as `...`
+    #[clap(long)]
+    depth: Option<usize>,
+    /// Reads the state right after the block at this height, instead of at the tip
+    #[clap(long)]
+    at: Option<u64>,
+    /// Prints the state as the API's JSON
+    #[clap(long)]
+    json: bool,
+  },
+  /// Prints the size of the state of a function, and the size limit of saved states
+  Usage {
+    /// Name of the function
+    name: String,
+  },
+  /// Prints every function, with its code hash and state, as JSON lines, for backups and analytics
+  Export {
+    /// Functions fetched per request
+    #[clap(long)]
+    page: Option<usize>,
+  },
+  /// Prints the state of a function, then a diff of it every time a new block changes it
+  Watch {
+    /// Name of the function
+    name: String,
+    /// Shows subterms this many levels down as `...`
+    #[clap(long)]
+    depth: Option<usize>,
+  },
+}
+
+#[derive(Subcommand)]
+pub enum BenchCmd {
+  /// Runs blocks of token transfers and counter calls, reporting throughput, mana and state growth
+  Chain {
+    /// Statements generated for each block; the ones that don't fit in its body are left out
+    #[clap(long, default_value = "16")]
+    statements_per_block: usize,
+    /// Number of blocks
+    #[clap(long, default_value = "100")]
+    blocks: usize,
+    /// Percentage of token transfers, the rest being counter calls
+    #[clap(long, default_value = "50")]
+    bank: u8,
+    /// Seeds the workload, to compare runs
+    #[clap(long, default_value = "0")]
+    seed: u64,
+  },
+}
+
+#[derive(Subcommand)]
+pub enum UtilCmd {
+  /// Prints the number a name stands for, in decimal and hex
+  NameToNum {
+    name: String,
+  },
+  /// Prints the name a number stands for
+  NumToName {
+    /// The number, in decimal or in hex with `#x` or `0x`
+    num: String,
+  },
+  /// Serializes a term, printing it in hex
+  SerializeTerm {
+    /// The term, e.g. `{Pair #1 #2}`
+    term: String,
+  },
+  /// Deserializes a term
+  DeserializeTerm {
+    /// The serialized term, in hex
+    hex: String,
+  },
+  /// Prints the hash of a statement, and the digest its signature signs: that of its sign payload
+  HashStatement {
+    /// The serialized statement, in hex
+    hex: String,
+    /// Prints the digest signed for the network with this id, instead of this node's
+    #[clap(long, default_value_t = hvm::NETWORK_ID)]
+    network: u64,
+  },
+  /// Checks the signature of a statement, printing its signer
+  Verify {
+    /// The serialized statement, in hex
+    hex: String,
+    /// Fails unless the statement was signed by this subject
+    #[clap(long)]
+    subject: Option<String>,
+    /// Checks it as signed for the network with this id, instead of this node's
+    #[clap(long, default_value_t = hvm::NETWORK_ID)]
+    network: u64,
+  },
+  /// Prints where two terms differ, each read from a file
+  DiffTerm {
+    /// The file with the old term
+    old: PathBuf,
+    /// The file with the new term
+    new: PathBuf,
   },
 }
 
@@ -149,9 +568,21 @@ fn get_kindelia_path(dir_cli: Option<String>) -> Result<PathBuf, String> {
   }
 }
 
-fn run_cli() -> Result<(), String> {
-  let arguments = Cli::parse();
-  let kindelia_path = get_kindelia_path(arguments.path)?;
+fn run_cli(arguments: Cli) -> Result<(), String> {
+  let output = arguments.output;
+  let layout = Layout { width: arguments.width, ..Layout::default() };
+  let colors = Colors::stdout(arguments.color);
+  // Errors are returned to `main`, which prints them on stderr, as JSON with `--output json`
+  let err_colors = if output == OutputFormat::Json { Colors { enabled: false } } else { Colors::stderr(arguments.color) };
+  let base_path = get_kindelia_path(arguments.path)?;
+  let profile_name = arguments.profile.or_else(|| std::env::var(profile::PROFILE_ENV_VAR).ok());
+  let profile = profile::Profile::load(&base_path, profile_name)?;
+  let kindelia_path = profile.path.clone();
+  let api_urls = match (arguments.api, &profile.config.api) {
+    (urls, _) if !urls.is_empty() => urls,
+    (_, urls) if !urls.is_empty() => urls.clone(),
+    _ => vec![api::client::DEFAULT_API_URL.to_string()],
+  };
   
   fn get_statement(hex: &str) -> Option<Statement> {
     return deserialized_statement(&bytes_to_bitvec(&hex::decode(hex).expect("hex string")));
@@ -159,116 +590,812 @@ fn run_cli() -> Result<(), String> {
 
   match arguments.command {
     // Starts the node process
-    CliCmd::Start { testnet, mine } => {
+    CliCmd::Start { testnet, mine, chaos, peer_bandwidth, listen, advertise, prefer, proxy, tor, onion, no_mdns, connect_only, payout, extra_data, mining_intensity, miner_cores, miner_nice, pool_ttl, archive, verify_replay, shadow, block_timeout, fork, fork_height, seed, webhooks, fork_choice, min_free_space, compression_level, max_state_size, activate, signal } => {
       eprintln!("Starting Kindelia node. Store path: {:?}", kindelia_path);
-      start_node(kindelia_path, testnet, mine);
+      let testnet = testnet || profile.config.testnet;
+      for step in datadir::migrate(&kindelia_path, false)? {
+        eprintln!("Migrated the data directory from version {}: {}", step.from, step.about);
+      }
+      let fork = match (fork, fork_height) {
+        (Some(url), Some(height)) => {
+          let (fork, block) = api::client::Fork::new(&url, height)?;
+          eprintln!("Forking {} at height {} (block {}).", url, height, block.hash);
+          Some(Arc::new(fork) as Arc<dyn hvm::Upstream>)
+        }
+        _ => None,
+      };
+      let chaos = if chaos { Some(Chaos::new()) } else { None };
+      let advertise = advertise.iter().map(|addr| read_address(addr)).collect();
+      // Local discovery is meant for devnets
+      let mdns = !testnet && !no_mdns && fork.is_none();
+      let connect_only = connect_only.iter().map(|addr| read_address(addr)).collect();
+      let onion = onion.map(|addr| read_address(&addr));
+      if onion.map(|addr| addr.family() != AddressFamily::Onion).unwrap_or(false) {
+        return Err("The --onion address must be an onion address.".to_string());
+      }
+      if onion.is_some() && tor.is_none() {
+        return Err("The --onion address needs --tor, to reach other onion peers.".to_string());
+      }
+      let net = NetConfig { listen, advertise, prefer, peer_bandwidth, proxy, tor, onion, mdns, connect_only };
+      let payout = payout.map(|name| read_payout(&name)).transpose()?;
+      let mut extra_data = extra_data.map(|text| read_extra_data(&text)).transpose()?.unwrap_or_default();
+      if !signal.is_empty() {
+        let features = signal.iter().map(|name| upgrade::read_feature(name)).collect::<Result<Vec<_>, _>>()?;
+        extra_data = upgrade::signal_extra_data(&features, &extra_data)?;
+      }
+      if !(1 ..= 100).contains(&mining_intensity) {
+        return Err(format!("Invalid mining intensity: {}. Must be from 1 to 100.", mining_intensity));
+      }
+      let miner = MinerConfig {
+        mining: Mining { active: mine, intensity: mining_intensity },
+        tuning: ThreadTuning { cores: miner_cores, nice: miner_nice },
+        payout,
+        extra_data,
+      };
+      let webhooks = webhooks.map(|path| webhook::read_webhooks(&path)).transpose()?.unwrap_or_default();
+      let block_timeout = block_timeout.map(std::time::Duration::from_millis);
+      if fork_choice != ForkChoiceRule::MostWork && !testnet && net.connect_only.is_empty() {
+        return Err("Only the most-work fork choice rule follows mainnet. Use others with --testnet or --connect-only.".to_string());
+      }
+      if max_state_size != hvm::DEFAULT_STATE_LIMIT && !testnet && net.connect_only.is_empty() {
+        return Err("Only the default state size limit follows mainnet. Use others with --testnet or --connect-only.".to_string());
+      }
+      let mut activations = upgrade::Activations::default();
+      for text in &activate {
+        let (feature, height) = upgrade::read_activation(text)?;
+        activations.set(feature, height);
+      }
+      if !activations.is_mainnet() && !testnet && net.connect_only.is_empty() {
+        return Err("Only the mainnet activation heights follow mainnet. Use others with --testnet or --connect-only.".to_string());
+      }
+      let disk = DiskMonitor::new(min_free_space.saturating_mul(1 << 20));
+      let compression = match compression_level {
+        0 => None,
+        level @ 1 ..= 22 => Some(level),
+        level => return Err(format!("Invalid compression level: {}. Must be from 0 to 22.", level)),
+      };
+      let backups = profile.config.backup.map(|config| config.check().map(|_| BackupSchedule::new(config, &kindelia_path))).transpose()?;
+      let mut policies: Vec<Box<dyn StatementPolicy>> = vec![];
+      if let Some(config) = &profile.config.policy {
+        policies.push(Box::new(LocalPolicy::new(config)?));
+      }
+      start_node(kindelia_path, testnet, miner, chaos, net, pool_ttl, archive, verify_replay, shadow, block_timeout, fork, seed, webhooks, fork_choice, disk, backups, policies, compression, max_state_size, activations);
     }
 
     // Runs a single block, for testing
-    CliCmd::Run { file } => {
+    CliCmd::Run { file, rewrites } if output == OutputFormat::Json => {
+      let code = std::fs::read_to_string(file).map_err(|err| err.to_string())?;
+      let statements = hvm::read_statements(&code).map_err(|err| err_colors.parse_error(&code, &err))?.1;
+      let mut rt = hvm::init_runtime(None);
+      if rewrites.is_some() {
+        rt.start_rewrite_stats();
+      }
+      let results = rt.run_statements(&statements, true);
+      if let Some(path) = rewrites {
+        write_rewrites(&path, &rt.stop_rewrite_stats())?;
+      }
+      println!("{}", serde_json::json!({ "results": results, "size": rt.get_size(), "mana": rt.get_mana(), "rwts": rt.get_rwts() }));
+    }
+    CliCmd::Run { file, rewrites } => {
       let file = std::fs::read_to_string(file);
       match file {
         Err(err) => {
           return Err(format!("{}", err));
         }
         Ok(code) => {
+          let statements = hvm::read_statements(&code).map_err(|err| err_colors.parse_error(&code, &err))?.1;
           // TODO: flag to disable size limit / debug
-          hvm::test_statements_from_code(&code);
+          let stats = hvm::test_statements(&statements, rewrites.is_some());
+          if let (Some(path), Some(stats)) = (rewrites, stats) {
+            write_rewrites(&path, &stats)?;
+          }
         }
       }
     }
 
+    // Checks the persisted runtime state
+    CliCmd::Fsck => {
+      let path = kindelia_path.join("state").join("heaps");
+      let faults = hvm::check_saved_state(&path).map_err(|err| format!("Couldn't load state from {:?}: {}", path, err))?;
+      let faults: Vec<String> = faults.iter().map(hvm::show_heap_fault).collect();
+      let text = if faults.is_empty() { "No faults found.".to_string() } else { faults.join("\n") };
+      output.emit(text, serde_json::json!({ "faults": faults }));
+      if !faults.is_empty() {
+        return Err(format!("Found {} faults.", faults.len()));
+      }
+    }
+
+    // Lists the profiles
+    CliCmd::Profiles => {
+      let names = profile::Profile::list(&base_path);
+      let is_current = |name: &String| profile.name.as_ref() == Some(name);
+      let text: Vec<String> = names.iter().map(|name| format!("{}{}", name, if is_current(name) { " (current)" } else { "" })).collect();
+      let json = names.iter().map(|name| serde_json::json!({ "name": name, "current": is_current(name) })).collect();
+      output.emit(text.join("\n"), serde_json::Value::Array(json));
+    }
+
+    // Upgrades the data directory
+    CliCmd::Migrate { dry_run } => {
+      let version = datadir::read_version(&kindelia_path)?;
+      let steps = datadir::migrate(&kindelia_path, dry_run)?;
+      let verb = if dry_run { "Would migrate" } else { "Migrated" };
+      let text: Vec<String> = steps.iter().map(|step| format!("{} from version {} to {}: {}", verb, step.from, step.from + 1, step.about)).collect();
+      let text = if steps.is_empty() { format!("The data directory is up to date (version {}).", version) } else { text.join("\n") };
+      let json = steps.iter().map(|step| serde_json::json!({ "from": step.from, "to": step.from + 1, "about": step.about })).collect::<Vec<_>>();
+      output.emit(text, serde_json::json!({ "version": version, "dry_run": dry_run, "steps": json }));
+    }
+
+    // Restores the data directory from a backup
+    CliCmd::Node { command: NodeCmd::RestoreBackup { file } } => {
+      let blocks = backup::restore_backup(&file, &kindelia_path)?;
+      output.emit(
+        format!("Restored {} blocks from {:?} into {:?}. Start the node to carry on from them.", blocks, file, kindelia_path),
+        serde_json::json!({ "file": file, "path": kindelia_path, "blocks": blocks }),
+      );
+    }
+
     // Prints all statements in a file
     CliCmd::Print { file } => {
       if let Ok(code) = std::fs::read_to_string(file) {
-        let statements = hvm::read_statements(&code).map_err(|err| err.erro)?.1;
+        let statements = hvm::read_statements(&code).map_err(|err| err_colors.parse_error(&code, &err))?.1;
+        let mut text = String::new();
+        let mut json = vec![];
         for statement in statements {
-          println!("// {}", hex::encode(serialized_statement(&statement).to_bytes()));
-          println!("{}", view_statement(&statement));
-          println!("");
+          let hex = hex::encode(serialized_statement(&statement).to_bytes());
+          let comment = colors.paint(Style::Dim, &format!("// {}", hex));
+          text.push_str(&format!("{}\n{}\n\n", comment, colors.code(&view_statement_pretty(&statement, &layout))));
+          json.push(serde_json::json!({ "hex": hex, "statement": statement }));
         }
+        output.emit(text.trim_end_matches('\n'), serde_json::Value::Array(json));
       } else {
-        println!("Couldn't load file.");
+        return Err("Couldn't load file.".to_string());
       }
     }
 
     // Serializes all statements in a file
     CliCmd::Serialize { file } => {
       if let Ok(code) = std::fs::read_to_string(file) {
-        let statements = hvm::read_statements(&code).map_err(|err| err.erro)?.1;
-        for statement in statements {
-          println!("{}", hex::encode(serialized_statement(&statement).to_bytes()));
-        }
+        let statements = hvm::read_statements(&code).map_err(|err| err_colors.parse_error(&code, &err))?.1;
+        let hexes: Vec<String> = statements.iter().map(|statement| hex::encode(serialized_statement(statement).to_bytes())).collect();
+        output.emit(hexes.join("\n"), serde_json::json!(hexes));
       } else {
-        println!("Couldn't load file.");
+        return Err("Couldn't load file.".to_string());
       }
     }
 
     // Deserializes a statement
     CliCmd::Deserialize { hex } => {
-      if let Some(statement) = get_statement(&hex) {
-        println!("{}", view_statement(&statement));
-      }
+      let statement = get_statement(&hex).ok_or("Hex provided isn't a serialized statement.")?;
+      output.emit(colors.code(&view_statement_pretty(&statement, &layout)), serde_json::json!(statement));
     }
 
     // Signs a statement
-    CliCmd::Sign { hex, skey: skey_file } => {
-      if let Ok(skey) = std::fs::read_to_string(skey_file) {
+    CliCmd::Sign { hex, skey: skey_file, preview, network } => {
+      if let Ok(skey) = std::fs::read_to_string(profile.key_path(&skey_file)) {
         if let Some(statement) = get_statement(&hex) {
           let skey = hex::decode(&skey[0..64]).expect("hex string");
           let user = crypto::Account::from_private_key(&skey);
-          let hash = hvm::hash_statement(&statement);
-          let sign = user.sign(&hash);
+          let payload = hvm::SignPayload::new(&statement, network);
+          if preview {
+            let (text, json) = show_sign_payload(&statement, &payload, &user);
+            output.emit(text, json);
+            return Ok(());
+          }
+          let sign = user.sign(&payload.digest());
           let stat = set_sign(&statement, sign);
-          println!("{}", hex::encode(serialized_statement(&stat).to_bytes()));
+          let hex = hex::encode(serialized_statement(&stat).to_bytes());
+          output.emit(&hex, serde_json::json!({ "hex": hex }));
           return Ok(());
         } else {
-          println!("Hex provided isn't a serialized statement.");
+          return Err("Hex provided isn't a serialized statement.".to_string());
         }
       } else {
-        println!("Couldn't load term and secret key files.");
+        return Err("Couldn't load term and secret key files.".to_string());
       }
     }
 
     // Posts a run statement
-    CliCmd::Post { hex, addr: node_addr } => {
+    CliCmd::Post { hex, addr: _, via_api: true } => {
+      let statement = get_statement(&hex).ok_or("Hex provided isn't a serialized statement.")?;
+      let client = api::client::ApiClient::with_nodes(&api_urls);
+      for result in client.send_code(view_statement(&statement), None)? {
+        result.map_err(|err| format!("{} rejected the statement: {}", client.url(), err))?;
+      }
+      let hash = format!("0x{}", hex::encode(hvm::hash_statement(&statement).0));
+      output.emit(
+        format!("Published statement to {}:\n\n{}", client.url(), view_statement(&statement)),
+        serde_json::json!({ "hash": hash, "node": client.url() }),
+      );
+    }
+    CliCmd::Post { hex, addr: node_addr, via_api: false } => {
       if let Some(statement) = get_statement(&hex) {
         let tx = Transaction::new(bitvec_to_bytes(&serialized_statement(&statement)));
         let ms = Message::PleaseMineThisTransaction { trans: tx };
         let ports = [UDP_PORT + 100, UDP_PORT + 101, UDP_PORT + 102, UDP_PORT + 103];
         if let Some((mut socket, port)) = udp_init(&ports) {
-          let addrs = if let Some(node_addr) = node_addr {
-            vec![read_address(&node_addr)]
+          let addrs = if let Some(node_addr) = &node_addr {
+            vec![read_address(node_addr)]
           } else {
             ENTRY_PEERS.iter().map(|x| read_address(x)).collect()
           };
           udp_send(&mut socket, addrs, &ms);
-          println!("Published statement:\n\n{}", view_statement(&statement));
+          let hash = format!("0x{}", hex::encode(hvm::hash_statement(&statement).0));
+          output.emit(format!("Published statement:\n\n{}", view_statement(&statement)), serde_json::json!({ "hash": hash, "node": node_addr }));
           return Ok(());
         } else {
           panic!("Couldn't open UDP socket on ports: {:?}.", ports);
         }
       } else {
-        println!("Hex provided isn't a serialized statement.");
+        return Err("Hex provided isn't a serialized statement.".to_string());
+      }
+    }
+
+    // Calls a function
+    CliCmd::Call { name, args, sign, dry_run, expires, price } => {
+      let client = api::client::ApiClient::with_nodes(&api_urls);
+      let fid = match hvm::read_name(&name) {
+        Ok(("", fid)) if fid != 0 => fid,
+        _ => return Err(format!("Invalid function name: '{}'.", name)),
+      };
+      // The function's arguments are all its interface says, so they're checked against it
+      let func = client.get_function(&name, None)?.ok_or_else(|| format!("Function '{}' isn't deployed.", name))?.func;
+      let arity = match func.rules.first().map(|rule| &rule.lhs) {
+        Some(Term::Fun { args, .. }) => args.len(),
+        _ => return Err(format!("Function '{}' has no rules.", name)),
+      };
+      if args.len() != arity {
+        let plural = if arity == 1 { "" } else { "s" };
+        return Err(format!("Function '{}' takes {} argument{}, but {} were given.", name, arity, plural, args.len()));
+      }
+      let mut terms = vec![];
+      for arg in &args {
+        let (rest, term) = hvm::read_term(arg).map_err(|err| format!("Invalid argument `{}`: {}", arg, err.erro))?;
+        if !rest.trim().is_empty() {
+          return Err(format!("Unexpected input after the argument: `{}`.", rest.trim()));
+        }
+        terms.push(term);
+      }
+      let price = match price {
+        Some(price) => price,
+        None => client.get_mana_price()?.ok_or_else(|| format!("{} didn't tell its mana price.", client.url()))?.base_price,
+      };
+      let mut statement = hvm::call_statement(fid, terms, Some(price))?;
+      if let Some(key) = sign {
+        let account = profile.load_account(&key)?;
+        statement = set_sign(&statement, account.sign(&hvm::sign_hash(&statement)));
+      }
+      // Runs it on the node first, which also estimates the mana it takes
+      let code = view_statement(&statement);
+      let (done, mana) = match client.test_code(code.clone())?.pop() {
+        Some(Ok(StatementInfo::Run { done_term, used_mana, .. })) => (done_term, used_mana),
+        Some(Ok(info)) => return Err(format!("Unexpected result: {:?}", info)),
+        Some(Err(err)) => return Err(format!("The call fails: {}", err.err)),
+        None => return Err(format!("{} didn't run the call.", client.url())),
+      };
+      let hex = hex::encode(serialized_statement(&statement).to_bytes());
+      let hash = format!("0x{}", hex::encode(hvm::hash_statement(&statement).0));
+      let mut json = serde_json::json!({ "hash": hash, "hex": hex, "mana": mana.to_string(), "result": view_term(&done) });
+      let text = format!("(Call '{}' [{}])\nReturns {} and takes {} mana.", name, args.join(" "), view_term(&done), mana);
+      if dry_run {
+        output.emit(text, json);
+        return Ok(());
+      }
+      for result in client.send_code(code, expires)? {
+        result.map_err(|err| format!("{} rejected the statement: {}", client.url(), err))?;
+      }
+      json["node"] = serde_json::json!(client.url());
+      output.emit(format!("{}\nPosted {} to {}.", text, hash, client.url()), json);
+    }
+
+    // Watches an account
+    CliCmd::Wallet { command: WalletCmd::Watch { account, label } } => {
+      let subject = wallet::read_account(&account)?;
+      let label = label.unwrap_or_else(|| crypto::Name(subject).show());
+      let mut wallet = wallet::Wallet::load(&kindelia_path)?;
+      let watched = wallet.watch(label, subject)?.clone();
+      wallet.save(&kindelia_path)?;
+      output.emit(
+        format!("Watching {} as '{}'.", crypto::Name(subject).show(), watched.label),
+        serde_json::json!(watched),
+      );
+    }
+
+    // Stops watching an account
+    CliCmd::Wallet { command: WalletCmd::Unwatch { account } } => {
+      let mut wallet = wallet::Wallet::load(&kindelia_path)?;
+      let watched = wallet.unwatch(&account)?;
+      wallet.save(&kindelia_path)?;
+      output.emit(
+        format!("Stopped watching '{}' ({}).", watched.label, crypto::Name(watched.subject).show()),
+        serde_json::json!(watched),
+      );
+    }
+
+    // Prints what a node knows of the watched accounts
+    CliCmd::Wallet { command: WalletCmd::Status { account, recent } } => {
+      let wallet = wallet::Wallet::load(&kindelia_path)?;
+      let watched = match &account {
+        Some(account) => vec![wallet.find(account).ok_or_else(|| format!("'{}' isn't watched.", account))?.clone()],
+        None => wallet.watched.clone(),
+      };
+      if watched.is_empty() {
+        return Err("No account is watched. Add one with `kindelia wallet watch`.".to_string());
+      }
+      let client = api::client::ApiClient::with_nodes(&api_urls);
+      let mut text = vec![];
+      let mut json = vec![];
+      for watched in &watched {
+        let status = wallet::AccountStatus::fetch(&client, watched.subject)?;
+        let subject = crypto::Name(watched.subject).show();
+        text.push(format!("{} ({})", watched.label, subject));
+        text.push(format!("  pending: {}", status.pending.len()));
+        for entry in &status.pending {
+          let expires = entry.status["Pending"]["expires"].as_u64().map(|height| format!(", expires after height {}", height)).unwrap_or_default();
+          text.push(format!("    {} {}{}", entry.hash, wallet::describe(&entry.statement), expires));
+        }
+        text.push(format!("  included: {}", status.included.len()));
+        for entry in status.included.iter().skip(status.included.len().saturating_sub(recent)) {
+          text.push(format!("    {} {} at height {}", entry.hash, wallet::describe(&entry.statement), entry.height));
+        }
+        let pending: Vec<_> = status.pending.iter().map(|entry| serde_json::json!({
+          "hash": entry.hash, "transaction": entry.transaction, "kind": wallet::describe(&entry.statement), "status": entry.status, "local": entry.local,
+        })).collect();
+        let included: Vec<_> = status.included.iter().map(|entry| serde_json::json!({
+          "hash": entry.hash, "kind": wallet::describe(&entry.statement), "height": entry.height, "block": entry.block, "position": entry.position,
+        })).collect();
+        json.push(serde_json::json!({ "label": watched.label, "subject": subject, "pending": pending, "included": included }));
+      }
+      output.emit(text.join("\n"), serde_json::Value::Array(json));
+    }
+
+    // Prepares statements to be signed offline
+    CliCmd::Wallet { command: WalletCmd::Prepare { file, network } } => {
+      let code = std::fs::read_to_string(&file).map_err(|err| format!("Couldn't load {:?}: {}", file, err))?;
+      let statements = hvm::read_statements(&code).map_err(|err| err_colors.parse_error(&code, &err))?.1;
+      let mut text = String::new();
+      let mut json = vec![];
+      for statement in statements.iter().map(remove_sign) {
+        let hex = hex::encode(serialized_statement(&statement).to_bytes());
+        let hash = format!("0x{}", hex::encode(hvm::hash_statement(&statement).0));
+        let digest = format!("0x{}", hex::encode(hvm::SignPayload::new(&statement, network).digest().0));
+        let kind = show_statement_kind(&statement);
+        text.push_str(&format!("// {}\n// hash: {}\n// digest: {}\n{}\n\n", kind, hash, digest, hex));
+        json.push(serde_json::json!({ "kind": kind, "hex": hex, "hash": hash, "digest": digest, "network": network }));
+      }
+      text.push_str("Sign each one offline with `kindelia sign <key> <hex>`, then post it with `kindelia post <hex>`.");
+      output.emit(text, serde_json::Value::Array(json));
+    }
+
+    // Prints the sync progress of a node
+    CliCmd::Sync { follow } => {
+      let client = api::client::ApiClient::with_nodes(&api_urls);
+      loop {
+        let sync = client.get_sync()?.ok_or("The node doesn't report its sync progress.")?;
+        let caught_up = !sync.syncing && sync.eta_secs == Some(0);
+        output.emit(show_sync(&sync), serde_json::to_value(&sync).map_err(|err| err.to_string())?);
+        if !follow || caught_up {
+          break;
+        }
+        std::thread::sleep(std::time::Duration::from_secs(1));
+      }
+    }
+
+    // Has a running node compact its heap
+    CliCmd::Node { command: NodeCmd::Compact } => {
+      let client = api::client::ApiClient::with_nodes(&api_urls);
+      let compaction = client.compact()?.ok_or("The node can't compact its heap.")?;
+      output.emit(
+        format!("Compacted the heap at tick {}: moved {} words.", compaction.tick, compaction.moved),
+        serde_json::to_value(&compaction).map_err(|err| err.to_string())?,
+      );
+    }
+
+    // Prints a block fetched from a node
+    CliCmd::Block { command: BlockCmd::Show { block } } => {
+      let client = api::client::ApiClient::with_nodes(&api_urls);
+      fn get_block<T: serde::de::DeserializeOwned>(client: &api::client::ApiClient, block: &str) -> Result<T, String> {
+        let view = match block.strip_prefix("0x") {
+          Some(_) => client.get_block(&api::Hash::try_from(block.to_string())?)?,
+          None => client.get_block_at(block.parse().map_err(|_| format!("Invalid block hash or height: `{}`.", block))?)?,
+        };
+        view.ok_or_else(|| format!("Block {} not found.", block))
+      }
+      match output {
+        OutputFormat::Text => print!("{}", show_block(&get_block(&client, &block)?)),
+        OutputFormat::Json => println!("{}", get_block::<serde_json::Value>(&client, &block)?),
+      }
+    }
+
+    // Prints the state of a function fetched from a node
+    CliCmd::State { command: StateCmd::Get { name, depth, at, json } } => {
+      let client = api::client::ApiClient::with_nodes(&api_urls);
+      let not_found = || format!("Function {} has no state.", name);
+      if json || output == OutputFormat::Json {
+        let state: serde_json::Value = client.get_state(&name, at)?.ok_or_else(not_found)?;
+        println!("{}", serde_json::to_string_pretty(&state).map_err(|err| err.to_string())?);
+      } else {
+        let state: Term = client.get_state(&name, at)?.ok_or_else(not_found)?;
+        println!("{}", colors.code(&view_term_pretty(&state, &layout, depth)));
+      }
+    }
+
+    // Prints how much of the state size limit a function uses
+    CliCmd::State { command: StateCmd::Usage { name } } => {
+      let client = api::client::ApiClient::with_nodes(&api_urls);
+      let usage = client.get_state_usage(&name)?.ok_or_else(|| format!("Function {} has no state.", name))?;
+      let percent = usage.size as f64 * 100.0 / usage.limit.max(1) as f64;
+      let mut text = format!("{} bytes, of a limit of {} ({:.1}%)", usage.size, usage.limit, percent);
+      if usage.size > usage.limit {
+        text.push_str("\nThe state is over the limit, so only smaller states can be saved.");
+      }
+      output.emit(text, serde_json::to_value(&usage).map_err(|err| err.to_string())?);
+    }
+
+    // Dumps the state of a node, a page at a time
+    CliCmd::State { command: StateCmd::Export { page } } => {
+      export_state(&api::client::ApiClient::with_nodes(&api_urls), page)?;
+    }
+
+    // Follows the state of a function on a node
+    CliCmd::State { command: StateCmd::Watch { name, depth } } => {
+      let client = api::client::ApiClient::with_nodes(&api_urls);
+      match output {
+        OutputFormat::Text => watch_state(&client, &name, &layout, &colors, depth)?,
+        OutputFormat::Json => watch_state_json(&client, &name)?,
       }
     }
 
+    // Converts between encodings
+    CliCmd::Util { command } => {
+      run_util(command, output)?;
+    }
+
+    // Simulates block production, on a runtime of its own
+    CliCmd::Bench { command: BenchCmd::Chain { statements_per_block, blocks, bank, seed } } => {
+      let bench = bench::ChainBench { statements_per_block, blocks, bank, seed };
+      let path = std::env::temp_dir().join(format!("kindelia-bench.{}", std::process::id()));
+      eprintln!("Running {} blocks of {} statements ({}% transfers)...", blocks, statements_per_block, bank);
+      let report = bench::bench_chain(&bench, &path);
+      std::fs::remove_dir_all(&path).ok();
+      let report = report?;
+      output.emit(&report, report.to_json());
+    }
+
+    // Prints a completion script
+    CliCmd::Completions { shell } => {
+      clap_complete::generate(shell, &mut Cli::command(), "kindelia", &mut std::io::stdout());
+    }
+
     // Prints the subject
     CliCmd::Subject { skey } => {
-      if let Ok(skey) = std::fs::read_to_string(skey) {
+      if let Ok(skey) = std::fs::read_to_string(profile.key_path(&skey)) {
         let skey = hex::decode(&skey[0..64]).expect("hex string");
         let acc  = crypto::Account::from_private_key(&skey);
-        println!("Ethereum Address: {}", acc.address.show());
-        println!("Kindelia Subject: {}", acc.name.show());
+        output.emit(
+          format!("Ethereum Address: {}\nKindelia Subject: {}", acc.address.show(), acc.name.show()),
+          serde_json::json!({ "address": acc.address.show(), "subject": acc.name.show() }),
+        );
       } else {
-        println!("Couldn't load term and secret key files.");
+        return Err("Couldn't load term and secret key files.".to_string());
       }
     }
   };
   Ok(())
 }
 
-fn start_node(kindelia_path: PathBuf, testnet: bool, mine: bool) {
+// Prints the functions of a node as JSON lines, following the pages of `/state/export`. Blocks
+// may arrive between pages, so the ticks read are reported when they differ.
+fn export_state(client: &api::client::ApiClient, page: Option<usize>) -> Result<(), String> {
+  let mut after: Option<String> = None;
+  let mut ticks: Option<(u128, u128)> = None;
+  let mut count = 0;
+  loop {
+    let page = client.export_state(after.as_deref(), page)?.ok_or("The node can't export its state.")?;
+    ticks = Some(ticks.map_or((page.tick, page.tick), |(first, _)| (first, page.tick)));
+    for entry in &page.entries {
+      println!("{}", serde_json::to_string(entry).map_err(|err| err.to_string())?);
+    }
+    count += page.entries.len();
+    match page.next {
+      Some(next) => after = Some(next),
+      None => break,
+    }
+  }
+  if let Some((first, last)) = ticks {
+    if first != last {
+      eprintln!("Exported {} functions from ticks {} to {}; the ones changed meanwhile are as of different ticks.", count, first, last);
+    }
+  }
+  Ok(())
+}
+
+// Prints the state of a function, then waits for new tips and prints where each changed it (see
+// `diff_terms`), until the node goes away. Subscribes before the first read, so no change is
+// missed in between.
+fn watch_state(client: &api::client::ApiClient, name: &str, layout: &Layout, colors: &Colors, depth: Option<usize>) -> Result<(), String> {
+  let mut events = client.subscribe()?;
+  let mut last: Option<Term> = client.get_state(name, None)?;
+  match &last {
+    Some(state) => println!("{}", colors.code(&view_term_pretty(state, layout, depth))),
+    None => println!("Function {} has no state yet.", name),
+  }
+  loop {
+    let event = events.next()?;
+    if event["event"] != "Tip" {
+      continue;
+    }
+    let state: Option<Term> = client.get_state(name, None)?;
+    let changes = match (&last, &state) {
+      (Some(last), Some(state)) => diff_terms(last, state).iter().map(|change| colors.code(&view_term_change(last, change))).collect(),
+      (None, Some(state)) => vec![colors.code(&view_term_pretty(state, layout, depth))],
+      (Some(_), None) => vec![format!("Function {} has no state anymore.", name)],
+      (None, None) => vec![],
+    };
+    if !changes.is_empty() {
+      println!("\n--- height {}, block {}", event["height"], event["hash"].as_str().unwrap_or("?"));
+      println!("{}", changes.join("\n"));
+    }
+    last = state;
+  }
+}
+
+// Like `watch_state`, but prints the states as JSON lines: `{"height": null, "hash": null, "state": ...}`
+// first, then one with the block of each change. A missing state is `null`.
+fn watch_state_json(client: &api::client::ApiClient, name: &str) -> Result<(), String> {
+  let mut events = client.subscribe()?;
+  let mut last: Option<serde_json::Value> = client.get_state(name, None)?;
+  println!("{}", serde_json::json!({ "height": null, "hash": null, "state": last }));
+  loop {
+    let event = events.next()?;
+    if event["event"] != "Tip" {
+      continue;
+    }
+    let state: Option<serde_json::Value> = client.get_state(name, None)?;
+    if state == last {
+      continue;
+    }
+    println!("{}", serde_json::json!({ "height": event["height"], "hash": event["hash"], "state": state }));
+    last = state;
+  }
+}
+
+fn write_rewrites(path: &Path, stats: &hvm::RewriteStats) -> Result<(), String> {
+  std::fs::write(path, stats.to_csv()).map_err(|err| format!("Couldn't write the rewrite counts to {:?}: {}", path, err))
+}
+
+// Shows what signing a statement signs: each field of the payload, the statement and the signer
+// A statement's kind and name, e.g. `fun Foo`
+fn show_statement_kind(statement: &Statement) -> String {
+  match statement {
+    Statement::Fun { name, .. } => format!("fun {}", u128_to_name(*name)),
+    Statement::Ctr { name, .. } => format!("ctr {}", u128_to_name(*name)),
+    Statement::Run { .. } => "run".to_string(),
+    Statement::Reg { name, .. } => format!("reg {}", u128_to_name(*name)),
+    Statement::Rot { name, .. } => format!("rot {}", u128_to_name(*name)),
+  }
+}
+
+// The hash of a statement, and the digest of its sign payload on a network
+fn show_statement_hashes(statement: &Statement, network: u64) -> (String, serde_json::Value) {
+  let hash = format!("0x{}", hex::encode(hvm::hash_statement(statement).0));
+  let digest = format!("0x{}", hex::encode(hvm::SignPayload::new(statement, network).digest().0));
+  let text = format!("{:<12}{}\n{:<12}{} (signed on network {})", "hash:", hash, "digest:", digest, network);
+  (text, serde_json::json!({ "hash": hash, "digest": digest, "network": network }))
+}
+
+fn show_sign_payload(statement: &Statement, payload: &hvm::SignPayload, signer: &crypto::Account) -> (String, serde_json::Value) {
+  let kind = show_statement_kind(statement);
+  let domain = String::from_utf8_lossy(hvm::SIGN_DOMAIN).to_string();
+  let hash = format!("0x{}", hex::encode(payload.hash.0));
+  let digest = format!("0x{}", hex::encode(payload.digest().0));
+  let network = match payload.network {
+    hvm::NETWORK_ID => format!("{} (this node's)", payload.network),
+    _ => format!("{} (NOT this node's, which is {})", payload.network, hvm::NETWORK_ID),
+  };
+  let mut text = String::new();
+  let mut line = |key: &str, val: String| text.push_str(&format!("{:<12}{}\n", format!("{}:", key), val));
+  line("domain", domain.clone());
+  line("version", hvm::SIGN_VERSION.to_string());
+  line("network", network);
+  line("statement", format!("{} ({})", hash, kind));
+  line("digest", digest.clone());
+  line("signer", format!("{} ({})", signer.name.show(), signer.address.show()));
+  text.push_str(&format!("\n{}", view_statement(statement)));
+  let json = serde_json::json!({
+    "domain": domain,
+    "version": hvm::SIGN_VERSION,
+    "network": payload.network,
+    "statement": { "hash": hash, "kind": kind },
+    "digest": digest,
+    "signer": { "subject": signer.name.show(), "address": signer.address.show() },
+  });
+  (text, json)
+}
+
+// Shows the hex of a block's extra data, with its text when it's printable
+fn show_extra_data(hex: &str) -> String {
+  match hex::decode(hex).ok().and_then(|bytes| String::from_utf8(bytes).ok()) {
+    Some(text) if !text.is_empty() && text.chars().all(|chr| chr.is_ascii_graphic() || chr == ' ') => format!("0x{} ({:?})", hex, text),
+    _ => format!("0x{}", hex),
+  }
+}
+
+// Formats a block for the terminal: its header, then each statement with its result
+fn show_block(view: &api::client::BlockView) -> String {
+  let statements: Vec<Option<Statement>> = view.block.body.iter().map(|hex| {
+    hex::decode(hex).ok().and_then(|bytes| deserialized_statement(&bytes_to_bitvec(&bytes)))
+  }).collect();
+  let mut text = String::new();
+  let mut line = |key: &str, val: String| text.push_str(&format!("{:<12}{}\n", format!("{}:", key), val));
+  line("block", view.hash.to_string());
+  line("height", view.height.to_string());
+  line("prev", view.block.prev.to_string());
+  line("time", view.block.time.to_string());
+  line("meta", view.block.meta.to_string());
+  line("payout", view.payout.clone().unwrap_or_else(|| "-".to_string()));
+  line("extra", view.extra_data.as_deref().map(show_extra_data).unwrap_or_else(|| "-".to_string()));
+  line("state", view.state_hash.as_ref().map(|hash| hash.to_string()).unwrap_or_else(|| "not computed".to_string()));
+  line("statements", statements.len().to_string());
+  if let Some(results) = &view.results {
+    line("mana", get_results_mana(results).to_string());
+  }
+  for (i, statement) in statements.iter().enumerate() {
+    text.push_str(&format!("\n// statement {}", i));
+    match statement {
+      Some(statement) => {
+        text.push_str(&format!(" (0x{})\n", hex::encode(hvm::hash_statement(statement).0)));
+        text.push_str(&format!("{}\n", view_statement(statement)));
+      }
+      None => {
+        text.push_str(" can't be deserialized\n");
+      }
+    }
+    match view.results.as_ref().and_then(|results| results.get(i)) {
+      Some(Ok(StatementInfo::Ctr { name, .. })) => text.push_str(&format!("// => [ctr] {}\n", u128_to_name(*name))),
+      Some(Ok(StatementInfo::Fun { name, .. })) => text.push_str(&format!("// => [fun] {}\n", u128_to_name(*name))),
+      Some(Ok(StatementInfo::Reg { name, ownr })) => text.push_str(&format!("// => [reg] #x{:0>30x} {}\n", ownr, u128_to_name(*name))),
+      Some(Ok(StatementInfo::Rot { name, succ, tick })) => text.push_str(&format!("// => [rot] #x{:0>30x} {} at tick {}\n", succ, u128_to_name(*name), tick)),
+      Some(Ok(StatementInfo::Run { done_term, used_mana, refunded_mana, size_diff, .. })) => {
+        text.push_str(&format!("// => [run] {} [{} mana | {} refunded | {} size]\n", view_term(done_term), used_mana, refunded_mana, size_diff));
+      }
+      Some(Err(err)) => text.push_str(&format!("// => [error] {}\n", err.err)),
+      None => {}
+    }
+  }
+  text
+}
+
+fn show_sync(sync: &api::SyncSummary) -> String {
+  if !sync.syncing && sync.eta_secs == Some(0) {
+    return format!("Caught up, at height {}.", sync.executed);
+  }
+  let peers = sync.peers_best.map(|height| format!(", peers at {}", height)).unwrap_or_default();
+  let speed = match (sync.blocks_per_sec, sync.eta_secs) {
+    (Some(rate), Some(eta)) => format!("{:.1} blocks/s, about {}s left.", rate, eta),
+    _ => "No blocks run in the last minute.".to_string(),
+  };
+  format!("Syncing: headers at {}, bodies at {}, run up to {}{}. {}", sync.headers, sync.bodies, sync.executed, peers, speed)
+}
+
+fn run_util(command: UtilCmd, output: OutputFormat) -> Result<(), String> {
+  fn read_statement(hex: &str) -> Result<Statement, String> {
+    let bytes = hex::decode(hex.strip_prefix("0x").unwrap_or(hex)).map_err(|_| format!("Invalid hex: `{}`.", hex))?;
+    deserialized_statement(&bytes_to_bitvec(&bytes)).ok_or_else(|| "Hex provided isn't a serialized statement.".to_string())
+  }
+  fn read_term_file(file: &Path) -> Result<Term, String> {
+    let code = std::fs::read_to_string(file).map_err(|err| format!("Couldn't load {:?}: {}", file, err))?;
+    let (rest, term) = hvm::read_term(&code).map_err(|err| format!("Invalid term in {:?}: {}", file, err.erro))?;
+    if !rest.trim().is_empty() {
+      return Err(format!("Unexpected input after the term in {:?}: `{}`.", file, rest.trim()));
+    }
+    Ok(term)
+  }
+  match command {
+    UtilCmd::NameToNum { name } => {
+      if name.is_empty() || name.len() > 20 || !name.chars().all(hvm::is_name_char) {
+        return Err(format!("Invalid name: `{}`.", name));
+      }
+      let num = hvm::name_to_u128(&name);
+      let hex = format!("#x{:0>30x}", num);
+      output.emit(format!("{}\n{}", num, hex), serde_json::json!({ "num": num.to_string(), "hex": hex }));
+    }
+    UtilCmd::NumToName { num } => {
+      let num = read_util_num(&num)?;
+      if num >> 120 != 0 {
+        return Err(format!("Names have 120 bits, but {} is bigger.", num));
+      }
+      let name = hvm::u128_to_name(num);
+      output.emit(&name, serde_json::json!({ "name": name }));
+    }
+    UtilCmd::SerializeTerm { term } => {
+      let (rest, term) = hvm::read_term(&term).map_err(|err| err.erro)?;
+      if !rest.trim().is_empty() {
+        return Err(format!("Unexpected input after the term: `{}`.", rest.trim()));
+      }
+      let hex = hex::encode(bitvec_to_bytes(&serialized_term(&term)));
+      output.emit(&hex, serde_json::json!({ "hex": hex }));
+    }
+    UtilCmd::DeserializeTerm { hex } => {
+      let bytes = hex::decode(hex.strip_prefix("0x").unwrap_or(&hex)).map_err(|_| format!("Invalid hex: `{}`.", hex))?;
+      let term = deserialized_term(&bytes_to_bitvec(&bytes)).ok_or("Hex provided isn't a serialized term.")?;
+      output.emit(view_term(&term), serde_json::json!(term));
+    }
+    UtilCmd::HashStatement { hex, network } => {
+      let statement = read_statement(&hex)?;
+      let (text, json) = show_statement_hashes(&statement, network);
+      output.emit(text, json);
+    }
+    UtilCmd::Verify { hex, subject, network } => {
+      let statement = read_statement(&hex)?;
+      let sign = hvm::get_sign(&statement).as_ref().ok_or("Statement isn't signed.")?;
+      let hash = hvm::SignPayload::new(&statement, network).digest();
+      let name = sign.signer_name(&hash).ok_or("Invalid signature.")?;
+      let addr = sign.signer_address(&hash).ok_or("Invalid signature.")?;
+      if let Some(subject) = subject {
+        if read_util_num(&subject)? != name.0 {
+          return Err(format!("Statement wasn't signed by {}.", subject));
+        }
+      }
+      output.emit(
+        format!("Ethereum Address: {}\nKindelia Subject: {}", addr.show(), name.show()),
+        serde_json::json!({ "address": addr.show(), "subject": name.show() }),
+      );
+    }
+    UtilCmd::DiffTerm { old, new } => {
+      let old = read_term_file(&old)?;
+      let new = read_term_file(&new)?;
+      let changes = diff_terms(&old, &new);
+      let text = if changes.is_empty() {
+        "The terms are equal.".to_string()
+      } else {
+        changes.iter().map(|change| view_term_change(&old, change)).collect::<Vec<_>>().join("\n")
+      };
+      let json: Vec<_> = changes.iter().map(|change| match change {
+        TermChange::Insert { path, term } => serde_json::json!({ "change": "insert", "path": path, "new": term }),
+        TermChange::Delete { path, term } => serde_json::json!({ "change": "delete", "path": path, "old": term }),
+        TermChange::Replace { path, old, new } => serde_json::json!({ "change": "replace", "path": path, "old": old, "new": new }),
+      }).collect();
+      output.emit(text, serde_json::json!(json));
+    }
+  }
+  Ok(())
+}
+
+// Reads a number in decimal, or in hex with `#x` or `0x`
+fn read_util_num(text: &str) -> Result<u128, String> {
+  let parsed = match text.strip_prefix("#x").or_else(|| text.strip_prefix("0x")) {
+    Some(hex) => u128::from_str_radix(hex, 16),
+    None => text.parse::<u128>(),
+  };
+  parsed.map_err(|_| format!("Invalid number: `{}`.", text))
+}
+
+// Reads a payout name, which must fit in the 120 bits of an account name
+fn read_payout(name: &str) -> Result<u128, String> {
+  match hvm::read_name(name) {
+    Ok(("", num)) if name.len() <= 20 && !name.starts_with('~') => Ok(num),
+    _ => Err(format!("Invalid payout name: `{}`.", name)),
+  }
+}
+
+// Reads the extra data of mined blocks, as text or as `0x`-prefixed hex. It can't start with
+// `SIGNAL_TAG`, as it'd read as upgrade signals; those are set with `--signal`.
+fn read_extra_data(text: &str) -> Result<Vec<u8>, String> {
+  let bytes = match text.strip_prefix("0x") {
+    Some(hex) => hex::decode(hex).map_err(|_| format!("Invalid extra data hex: `{}`.", text))?,
+    None => text.as_bytes().to_vec(),
+  };
+  if bytes.len() > MAX_EXTRA_DATA {
+    return Err(format!("Extra data takes {} bytes, over the limit of {}.", bytes.len(), MAX_EXTRA_DATA));
+  }
+  if bytes.first() == Some(&upgrade::SIGNAL_TAG) {
+    return Err(format!("Extra data can't start with 0x{:02x}, which marks upgrade signals. Signal features with --signal.", upgrade::SIGNAL_TAG));
+  }
+  Ok(bytes)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn start_node(kindelia_path: PathBuf, testnet: bool, miner: MinerConfig, chaos: Option<Chaos>, net: NetConfig, pool_ttl: u128, archive: bool, verify_replay: bool, shadow: Option<Candidate>, block_timeout: Option<std::time::Duration>, fork: Option<Arc<dyn hvm::Upstream>>, seed: Option<u64>, webhooks: Vec<Webhook>, fork_choice: ForkChoiceRule, disk: DiskMonitor, backups: Option<BackupSchedule>, policies: Vec<Box<dyn StatementPolicy>>, compression: Option<i32>, state_limit: usize, activations: upgrade::Activations) {
   // TODO: move out to config file
   let testnet_peers: Vec<Address> = ENTRY_PEERS.into_iter().map(node::read_address).collect();
   let init_peers = if testnet { Some(testnet_peers) } else { None };
@@ -277,7 +1404,39 @@ fn start_node(kindelia_path: PathBuf, testnet: bool, mine: bool) {
   //let file = file.map(|file| std::fs::read_to_string(file).expect("Block file not found."));
 
   // Node state object
-  let (node_query_sender, node) = Node::new(kindelia_path.clone(), &init_peers);
+  let (node_query_sender, mut node) = Node::new(kindelia_path.clone(), &init_peers, chaos, net);
+  node.payout = miner.payout;
+  node.extra_data = miner.extra_data;
+  node.mining = miner.mining;
+  node.expiry.ttl = pool_ttl;
+  node.history.archive = archive;
+  node.replay.enabled = verify_replay;
+  node.shadow.candidate = shadow;
+  node.watchdog.budget = block_timeout;
+  node.fork_choice = fork_choice.build();
+  node.disk = disk;
+  node.backups = backups;
+  node.policies = policies;
+  // A forked chain is only valid here, so it's kept from every peer
+  if fork.is_some() {
+    node.peers.allow_only(&[]);
+  }
+  node.runtime.set_upstream(fork);
+  node.runtime.set_compression(compression);
+  node.runtime.set_state_limit(state_limit);
+  node.runtime.set_activations(activations);
+  if let Some(seed) = seed {
+    node.set_seed(seed);
+  }
+  eprintln!("Random seed: {}", node.seed);
+  // The miner gets its own generator, so its nonces don't depend on the node's timing
+  let miner_rng = NodeRng::seed_from_u64(node.rng.gen());
+  let events = node.events.clone();
+  let state = node.runtime.reader();
+  if !webhooks.is_empty() {
+    eprintln!("Posting to {} webhooks.", webhooks.len());
+    webhook::start(webhooks, node_query_sender.clone(), events.clone());
+  }
 
   // Node to Miner communication object
   let miner_comm_0 = MinerCommunication::new();
@@ -291,21 +1450,22 @@ fn start_node(kindelia_path: PathBuf, testnet: bool, mine: bool) {
 
   // Spawns the node thread
   let node_thread = thread::spawn(move || {
-    node.main(kindelia_path.clone(), miner_comm_0, mine);
+    node.main(kindelia_path.clone(), miner_comm_0);
   });
   threads.push(node_thread);
 
-  // Spawns the miner thread
-  if mine {
-    let miner_thread = thread::spawn(move || {
-      miner_loop(miner_comm_1);
-    });
-    threads.push(miner_thread);
-  }
+  // Spawns the miner thread, which idles while mining is paused
+  let miner_thread = thread::spawn(move || {
+    if let Err(err) = tune_thread(&miner.tuning) {
+      eprintln!("Couldn't set the miner's cores or nice level: {}", err);
+    }
+    miner_loop(miner_comm_1, miner_rng);
+  });
+  threads.push(miner_thread);
 
   // Spawns the API thread
   let api_thread = thread::spawn(move || {
-    http_api_loop(node_query_sender);
+    http_api_loop(node_query_sender, events, state);
   });
   threads.push(api_thread);
 
@@ -0,0 +1,196 @@
+// mDNS discovery
+// ==============
+
+// Finds peers on the local network with multicast DNS (RFC 6762), so that nodes of a devnet find
+// each other without configuration. Each node answers queries for the `_kindelia._udp.local`
+// service with a PTR record naming its instance, and a SRV record with its UDP port. A peer's
+// address is the source of its answer, with the port of its SRV record.
+//
+// Only the records used here are parsed; anything else on the mDNS port is ignored.
+
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+pub const MDNS_ADDR : Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const MDNS_PORT : u16 = 5353;
+
+// Name of the service nodes announce
+pub const SERVICE : &str = "_kindelia._udp.local";
+
+const TYPE_PTR : u16 = 12;
+const TYPE_SRV : u16 = 33;
+const CLASS_IN : u16 = 1;
+const FLAG_RESPONSE : u16 = 0x8400; // an authoritative answer
+const TTL : u32 = 120;
+
+pub struct Mdns {
+  socket: UdpSocket,
+  instance: String, // name of this node's instance of the service
+  port: u16,        // UDP port of this node
+}
+
+// The parts of an mDNS packet this uses
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Packet {
+  pub response: bool,
+  pub questions: Vec<(String, u16)>, // name and type of each question
+  pub services: Vec<(String, u16)>,  // instance and port of each SRV record
+}
+
+impl Mdns {
+  // Joins the mDNS group. The port is shared with other responders, such as avahi.
+  pub fn new(port: u16) -> std::io::Result<Mdns> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from(([0, 0, 0, 0], MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    let instance = format!("kindelia-{:016x}", fastrand::u64(..));
+    return Ok(Mdns { socket: socket.into(), instance, port });
+  }
+
+  // Asks the local network for nodes
+  pub fn query(&self) {
+    self.socket.send_to(&build_query(), SocketAddrV4::new(MDNS_ADDR, MDNS_PORT)).ok();
+  }
+
+  // Answers pending queries, and returns the nodes found since the last poll
+  pub fn poll(&mut self) -> Vec<SocketAddr> {
+    let mut buffer = [0; 9000];
+    let mut found = Vec::new();
+    let mut asked = false;
+    while let Ok((len, from)) = self.socket.recv_from(&mut buffer) {
+      let Some(packet) = parse_packet(&buffer[0 .. len]) else { continue };
+      if packet.response {
+        for (instance, port) in packet.services {
+          if instance != self.instance {
+            found.push(SocketAddr::new(from.ip(), port));
+          }
+        }
+      } else {
+        asked |= packet.questions.iter().any(|(name, kind)| name == SERVICE && *kind == TYPE_PTR);
+      }
+    }
+    if asked {
+      let response = build_response(&self.instance, self.port);
+      self.socket.send_to(&response, SocketAddrV4::new(MDNS_ADDR, MDNS_PORT)).ok();
+    }
+    return found;
+  }
+}
+
+// Building
+// --------
+
+pub fn build_query() -> Vec<u8> {
+  let mut packet = header(0, 1, 0);
+  write_name(&mut packet, SERVICE);
+  packet.extend_from_slice(&TYPE_PTR.to_be_bytes());
+  packet.extend_from_slice(&CLASS_IN.to_be_bytes());
+  return packet;
+}
+
+// Answers with a PTR record to the instance, and a SRV record with its port
+pub fn build_response(instance: &str, port: u16) -> Vec<u8> {
+  let full_name = format!("{}.{}", instance, SERVICE);
+  let mut packet = header(FLAG_RESPONSE, 0, 2);
+  let mut ptr = Vec::new();
+  write_name(&mut ptr, &full_name);
+  write_record(&mut packet, SERVICE, TYPE_PTR, &ptr);
+  let mut srv = Vec::new();
+  srv.extend_from_slice(&[0, 0, 0, 0]); // priority, weight
+  srv.extend_from_slice(&port.to_be_bytes());
+  write_name(&mut srv, &format!("{}.local", instance));
+  write_record(&mut packet, &full_name, TYPE_SRV, &srv);
+  return packet;
+}
+
+fn header(flags: u16, questions: u16, answers: u16) -> Vec<u8> {
+  let mut packet = Vec::new();
+  for field in [0, flags, questions, answers, 0, 0] {
+    packet.extend_from_slice(&field.to_be_bytes());
+  }
+  return packet;
+}
+
+fn write_name(bytes: &mut Vec<u8>, name: &str) {
+  for label in name.split('.') {
+    bytes.push(label.len() as u8);
+    bytes.extend_from_slice(label.as_bytes());
+  }
+  bytes.push(0);
+}
+
+fn write_record(bytes: &mut Vec<u8>, name: &str, kind: u16, data: &[u8]) {
+  write_name(bytes, name);
+  bytes.extend_from_slice(&kind.to_be_bytes());
+  bytes.extend_from_slice(&CLASS_IN.to_be_bytes());
+  bytes.extend_from_slice(&TTL.to_be_bytes());
+  bytes.extend_from_slice(&(data.len() as u16).to_be_bytes());
+  bytes.extend_from_slice(data);
+}
+
+// Parsing
+// -------
+
+pub fn parse_packet(data: &[u8]) -> Option<Packet> {
+  let flags = read_u16(data, 2)?;
+  let questions = read_u16(data, 4)?;
+  let records = read_u16(data, 6)? as usize + read_u16(data, 8)? as usize + read_u16(data, 10)? as usize;
+  let mut packet = Packet { response: flags & 0x8000 != 0, ..Packet::default() };
+  let mut index = 12;
+  for _ in 0 .. questions {
+    let name = read_name(data, &mut index)?;
+    packet.questions.push((name, read_u16(data, index)?));
+    index += 4;
+  }
+  for _ in 0 .. records {
+    let name = read_name(data, &mut index)?;
+    let kind = read_u16(data, index)?;
+    let size = read_u16(data, index + 8)? as usize;
+    index += 10;
+    if kind == TYPE_SRV && size >= 6 {
+      if let Some(instance) = name.strip_suffix(&format!(".{}", SERVICE)) {
+        packet.services.push((instance.to_string(), read_u16(data, index + 4)?));
+      }
+    }
+    index += size;
+  }
+  return Some(packet);
+}
+
+fn read_u16(data: &[u8], index: usize) -> Option<u16> {
+  Some(u16::from_be_bytes([*data.get(index)?, *data.get(index + 1)?]))
+}
+
+// Reads a name, following compression pointers. Pointers may only go backwards, so this ends.
+fn read_name(data: &[u8], index: &mut usize) -> Option<String> {
+  let mut labels: Vec<String> = Vec::new();
+  let mut at = *index;
+  let mut jumped = false;
+  loop {
+    let len = *data.get(at)? as usize;
+    if len & 0xC0 == 0xC0 {
+      let target = (read_u16(data, at)? & 0x3FFF) as usize;
+      if target >= at {
+        return None;
+      }
+      if !jumped {
+        *index = at + 2;
+        jumped = true;
+      }
+      at = target;
+    } else if len == 0 {
+      if !jumped {
+        *index = at + 1;
+      }
+      return Some(labels.join("."));
+    } else {
+      labels.push(String::from_utf8_lossy(data.get(at + 1 .. at + 1 + len)?).to_string());
+      at += 1 + len;
+    }
+  }
+}
@@ -0,0 +1,159 @@
+// Memory-mapped words
+// ===================
+
+// A growable array of u128 words, backed by a memory-mapped file. It lets the runtime keep heaps
+// larger than the RAM, since the OS pages words in and out of the file as needed. Persisting the
+// words is a `msync`, rather than a rewrite of the whole file.
+//
+// Words are stored negated, so that zeroed regions of the file, including holes of sparse files,
+// read as `U128_NONE`, meaning "never written". As such, `U128_NONE` itself can't be stored.
+//
+// The written indices are tracked on a two-level occupancy bitmap, kept in memory: one bit per
+// word, and one bit per 64 bits of those. It costs 1/128 of the file's size, and lets iteration
+// skip the unwritten regions, so it takes time proportional to the written words, not to the
+// file's capacity. The bitmap is rebuilt by scanning the file when it's opened.
+
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+// Size of a u128, in bytes
+const WORD_SIZE : u64 = 16;
+
+// Minimum capacity of a mapped file, in words
+const MIN_WORDS : u64 = 1 << 16;
+
+pub struct MappedWords {
+  path: PathBuf,    // the backing file
+  file: File,       // the open backing file
+  data: *mut u128,  // start of the mapping
+  size: u64,        // capacity of the mapping, in words
+  used: Vec<u64>,   // one bit per word, set if it was written
+  runs: Vec<u64>,   // one bit per `used` entry, set if it is non-zero
+}
+
+// The mapping is exclusively owned, like a `Vec` would be
+unsafe impl Send for MappedWords {}
+unsafe impl Sync for MappedWords {}
+
+impl MappedWords {
+  // Maps the file on `path`, creating it if it doesn't exist
+  pub fn open(path: &Path) -> std::io::Result<MappedWords> {
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+    let size = file.metadata()?.len() / WORD_SIZE;
+    let mut words = MappedWords { path: path.to_path_buf(), file, data: std::ptr::null_mut(), size: 0, used: vec![], runs: vec![] };
+    words.remap(std::cmp::max(size, MIN_WORDS))?;
+    for idx in 0 .. size {
+      if words.read(idx as u128) != !0 {
+        words.mark(idx);
+      }
+    }
+    return Ok(words);
+  }
+
+  pub fn path(&self) -> &Path {
+    return &self.path;
+  }
+
+  pub fn read(&self, idx: u128) -> u128 {
+    if idx >= self.size as u128 {
+      return !0;
+    }
+    return !unsafe { std::ptr::read_volatile(self.data.add(idx as usize)) };
+  }
+
+  pub fn write(&mut self, idx: u128, val: u128) {
+    if idx >= self.size as u128 {
+      let size = std::cmp::max(self.size * 2, (idx as u64 + 1).next_power_of_two());
+      self.remap(size).expect("Couldn't grow mapped file.");
+    }
+    unsafe { std::ptr::write_volatile(self.data.add(idx as usize), !val) };
+    self.mark(idx as u64);
+  }
+
+  // Iterates the written words, as `(index, value)` pairs, in index order
+  pub fn iter(&self) -> impl Iterator<Item = (u128, u128)> + '_ {
+    let runs = self.runs.iter().enumerate().flat_map(|(at, run)| set_bits(*run).map(move |bit| at as u64 * 64 + bit));
+    let idxs = runs.flat_map(|at| set_bits(self.used[at as usize]).map(move |bit| at * 64 + bit));
+    idxs.map(|idx| (idx as u128, self.read(idx as u128)))
+  }
+
+  // Forgets every word, shrinking the file back to its minimum size
+  pub fn clear(&mut self) {
+    self.unmap();
+    self.used.clear();
+    self.runs.clear();
+    self.file.set_len(0).and_then(|_| self.remap(MIN_WORDS)).expect("Couldn't clear mapped file.");
+  }
+
+  fn mark(&mut self, idx: u64) {
+    let at = (idx / 64) as usize;
+    self.used[at] |= 1 << (idx % 64);
+    self.runs[at / 64] |= 1 << (at % 64);
+  }
+
+  // Flushes the written words to the file
+  pub fn sync(&self) -> std::io::Result<()> {
+    let done = unsafe { libc::msync(self.data as *mut libc::c_void, (self.size * WORD_SIZE) as usize, libc::MS_SYNC) };
+    if done != 0 {
+      return Err(std::io::Error::last_os_error());
+    }
+    return Ok(());
+  }
+
+  fn remap(&mut self, size: u64) -> std::io::Result<()> {
+    self.unmap();
+    if self.file.metadata()?.len() < size * WORD_SIZE {
+      self.file.set_len(size * WORD_SIZE)?;
+    }
+    let data = unsafe {
+      libc::mmap(
+        std::ptr::null_mut(),
+        (size * WORD_SIZE) as usize,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_SHARED,
+        std::os::unix::io::AsRawFd::as_raw_fd(&self.file),
+        0,
+      )
+    };
+    if data == libc::MAP_FAILED {
+      return Err(std::io::Error::last_os_error());
+    }
+    self.data = data as *mut u128;
+    self.size = size;
+    self.used.resize(size.div_ceil(64) as usize, 0);
+    self.runs.resize(size.div_ceil(64 * 64) as usize, 0);
+    return Ok(());
+  }
+
+  fn unmap(&mut self) {
+    if !self.data.is_null() {
+      unsafe { libc::munmap(self.data as *mut libc::c_void, (self.size * WORD_SIZE) as usize) };
+      self.data = std::ptr::null_mut();
+      self.size = 0;
+    }
+  }
+}
+
+// Iterates the positions of the set bits of a word, lowest first
+fn set_bits(mut word: u64) -> impl Iterator<Item = u64> {
+  std::iter::from_fn(move || {
+    if word == 0 {
+      return None;
+    }
+    let bit = word.trailing_zeros() as u64;
+    word &= word - 1;
+    return Some(bit);
+  })
+}
+
+impl Drop for MappedWords {
+  fn drop(&mut self) {
+    self.unmap();
+  }
+}
+
+impl std::fmt::Debug for MappedWords {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "MappedWords({:?}, {} words)", self.path, self.size)
+  }
+}
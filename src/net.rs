@@ -0,0 +1,362 @@
+// Networking
+// ==========
+
+// The node's UDP sockets are served by tokio tasks, on a thread of their own. Received datagrams
+// are decoded there and handed to the node through a bounded inbox; datagrams to send go through a
+// bounded outbox. When either is full, datagrams are dropped and counted, as the network itself
+// would under load, so a flood of messages can't make the node queue up memory.
+//
+// Tor can't carry UDP, so onion peers are reached over TCP, through Tor's SOCKS5 proxy, on the same
+// thread. Each connection carries messages both ways, as frames prefixed by their length. The one
+// that opens it first sends a hello frame with its own onion address, so that its messages are
+// attributed to it, and so it can be answered on that connection. The address on the hello isn't
+// authenticated, as Tor doesn't tell who connected; a peer may claim another's, as it could spoof
+// a UDP source address. Peers without an onion service send an empty hello, and are given a
+// random address on port 0, which is only reachable while they stay connected.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bit_vec::BitVec;
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+
+use crate::bits::{deserialized_address, deserialized_message, serialized_address};
+use crate::node::{decode_datagram, Address, Message};
+use crate::onion::onion_host;
+use crate::socks;
+use crate::util::bitvec_to_bytes;
+
+// Received messages waiting to be handled by the node
+pub const INBOX_CAPACITY : usize = 4096;
+
+// Datagrams waiting to be sent
+pub const OUTBOX_CAPACITY : usize = 4096;
+
+// Messages waiting to be written to each onion connection
+pub const CONNECTION_CAPACITY : usize = 256;
+
+// Largest frame read from an onion connection, as large as a datagram can be
+pub const MAX_FRAME : usize = 65536;
+
+// How to reach onion peers
+#[derive(Debug, Clone, Copy)]
+pub struct OnionConfig {
+  pub tor: SocketAddr,            // Tor's SOCKS5 proxy
+  pub own: Option<Address>,       // this node's onion address, if it runs an onion service
+  pub listen: Option<SocketAddr>, // where Tor forwards the connections to the onion service
+}
+
+// A message received from a peer, with its size in bytes
+pub struct Incoming {
+  pub from: Address,
+  pub message: Message,
+  pub size: usize,
+}
+
+// A datagram to send through one of the sockets, or a message to an onion peer
+struct Outgoing {
+  to: Destination,
+  data: Arc<Vec<u8>>,
+}
+
+enum Destination {
+  Udp(usize, SocketAddr),
+  Onion(Address),
+}
+
+// Writers of the open onion connections, by peer
+type Connections = Arc<Mutex<HashMap<Address, mpsc::Sender<Arc<Vec<u8>>>>>>;
+
+#[derive(Default)]
+struct Counters {
+  received: AtomicU64,
+  dropped_in: AtomicU64,
+  sent: AtomicU64,
+  dropped_out: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct NetStats {
+  pub received: u64,    // messages put on the inbox
+  pub dropped_in: u64,  // messages dropped because the inbox was full
+  pub sent: u64,        // datagrams sent
+  pub dropped_out: u64, // datagrams dropped because the outbox was full
+  pub queued_in: usize, // messages on the inbox, waiting to be handled
+}
+
+pub struct Network {
+  pub locals: Vec<SocketAddr>, // addresses of the sockets, in order
+  pub onion: Option<OnionConfig>,
+  inbox: mpsc::Receiver<Incoming>,
+  outbox: mpsc::Sender<Outgoing>,
+  counters: Arc<Counters>,
+  taken: u64, // messages taken from the inbox
+}
+
+impl Network {
+  // Serves the sockets until the network is dropped. Datagrams from the SOCKS5 `relay` are
+  // unwrapped. With `onion`, onion peers are reached through Tor, and the onion service's
+  // connections are accepted.
+  pub fn start(
+    sockets: Vec<std::net::UdpSocket>,
+    relay: Option<SocketAddr>,
+    onion: Option<OnionConfig>,
+    inbox_capacity: usize,
+    outbox_capacity: usize,
+  ) -> std::io::Result<Network> {
+    let locals = sockets.iter().map(|socket| socket.local_addr()).collect::<Result<Vec<_>, _>>()?;
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_io().enable_time().build()?;
+    let (sockets, listener) = {
+      let _context = runtime.enter();
+      let sockets = sockets.into_iter().map(|socket| {
+        socket.set_nonblocking(true)?;
+        UdpSocket::from_std(socket).map(Arc::new)
+      });
+      let listener = match onion.and_then(|onion| onion.listen) {
+        Some(listen) => {
+          let listener = std::net::TcpListener::bind(listen)?;
+          listener.set_nonblocking(true)?;
+          Some(TcpListener::from_std(listener)?)
+        }
+        None => None,
+      };
+      (sockets.collect::<Result<Vec<_>, _>>()?, listener)
+    };
+    let (inbox_sender, inbox) = mpsc::channel(inbox_capacity);
+    let (outbox, outbox_receiver) = mpsc::channel(outbox_capacity);
+    let counters = Arc::new(Counters::default());
+    let serve_counters = counters.clone();
+    std::thread::Builder::new().name("network".to_string()).spawn(move || {
+      runtime.block_on(serve(sockets, relay, onion, listener, inbox_sender, outbox_receiver, serve_counters));
+    })?;
+    Ok(Network { locals, onion, inbox, outbox, counters, taken: 0 })
+  }
+
+  // Takes the next received message, if any
+  pub fn recv(&mut self) -> Option<Incoming> {
+    let incoming = self.inbox.try_recv().ok()?;
+    self.taken += 1;
+    Some(incoming)
+  }
+
+  // Sends a datagram through a socket, unless too many are waiting already
+  pub fn send(&self, socket: usize, to: SocketAddr, data: Arc<Vec<u8>>) {
+    self.push(Outgoing { to: Destination::Udp(socket, to), data });
+  }
+
+  // Sends a message to an onion peer, connecting to it if needed. It's dropped if Tor isn't
+  // configured.
+  pub fn send_onion(&self, to: Address, data: Arc<Vec<u8>>) {
+    if self.has_onion() {
+      self.push(Outgoing { to: Destination::Onion(to), data });
+    }
+  }
+
+  pub fn has_onion(&self) -> bool {
+    self.onion.is_some()
+  }
+
+  fn push(&self, outgoing: Outgoing) {
+    if self.outbox.try_send(outgoing).is_err() {
+      self.counters.dropped_out.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+
+  pub fn stats(&self) -> NetStats {
+    let received = self.counters.received.load(Ordering::Relaxed);
+    NetStats {
+      received,
+      dropped_in: self.counters.dropped_in.load(Ordering::Relaxed),
+      sent: self.counters.sent.load(Ordering::Relaxed),
+      dropped_out: self.counters.dropped_out.load(Ordering::Relaxed),
+      queued_in: received.saturating_sub(self.taken) as usize,
+    }
+  }
+}
+
+// Receives on every socket, and sends until the node drops its outbox. Returning drops the runtime,
+// which stops the receiving tasks.
+async fn serve(
+  sockets: Vec<Arc<UdpSocket>>,
+  relay: Option<SocketAddr>,
+  onion: Option<OnionConfig>,
+  listener: Option<TcpListener>,
+  inbox: mpsc::Sender<Incoming>,
+  mut outbox: mpsc::Receiver<Outgoing>,
+  counters: Arc<Counters>,
+) {
+  for socket in &sockets {
+    tokio::spawn(receive(socket.clone(), relay, inbox.clone(), counters.clone()));
+  }
+  let connections = Connections::default();
+  if let Some(listener) = listener {
+    tokio::spawn(accept(listener, connections.clone(), inbox.clone(), counters.clone()));
+  }
+  while let Some(outgoing) = outbox.recv().await {
+    match (outgoing.to, onion) {
+      (Destination::Udp(socket, to), _) => {
+        if sockets[socket].send_to(&outgoing.data, to).await.is_ok() {
+          counters.sent.fetch_add(1, Ordering::Relaxed);
+        }
+      }
+      (Destination::Onion(to), Some(onion)) => {
+        let mut open = connections.lock().unwrap();
+        let writer = open.entry(to).or_insert_with(|| {
+          let (writer, writes) = mpsc::channel(CONNECTION_CAPACITY);
+          tokio::spawn(dial(onion, to, writes, connections.clone(), inbox.clone(), counters.clone()));
+          writer
+        });
+        if writer.try_send(outgoing.data).is_err() {
+          counters.dropped_out.fetch_add(1, Ordering::Relaxed);
+        }
+      }
+      (Destination::Onion(_), None) => {}
+    }
+  }
+}
+
+async fn receive(socket: Arc<UdpSocket>, relay: Option<SocketAddr>, inbox: mpsc::Sender<Incoming>, counters: Arc<Counters>) {
+  let mut buffer = vec![0; 65536];
+  loop {
+    let Ok((size, sender_addr)) = socket.recv_from(&mut buffer).await else {
+      continue;
+    };
+    // Makes room for the message before decoding it, so a flood costs as little as possible
+    let permit = match inbox.try_reserve() {
+      Ok(permit) => permit,
+      Err(mpsc::error::TrySendError::Full(())) => {
+        counters.dropped_in.fetch_add(1, Ordering::Relaxed);
+        continue;
+      }
+      Err(mpsc::error::TrySendError::Closed(())) => return,
+    };
+    if let Some((from, message)) = decode_datagram(relay, sender_addr, &buffer[0 .. size]) {
+      counters.received.fetch_add(1, Ordering::Relaxed);
+      permit.send(Incoming { from, message, size });
+    }
+  }
+}
+
+// Onion peers
+// -----------
+
+// Connects to an onion peer through Tor, and serves the connection until it closes
+async fn dial(onion: OnionConfig, to: Address, writes: mpsc::Receiver<Arc<Vec<u8>>>, connections: Connections, inbox: mpsc::Sender<Incoming>, counters: Arc<Counters>) {
+  // Owns the writes, so that they're closed once it ends, whether it connected or not
+  let serving = async move {
+    let Address::Onion { key, port } = to else {
+      return;
+    };
+    // Peers on port 0 have no onion service, so they're only reached on their own connection
+    if port == 0 {
+      return;
+    }
+    if let Ok(mut stream) = socks::connect(onion.tor, &onion_host(&key), port).await {
+      let hello = onion.own.map(|own| bitvec_to_bytes(&serialized_address(&own))).unwrap_or_default();
+      if write_frame(&mut stream, &hello).await.is_ok() {
+        serve_connection(stream, to, Some(writes), inbox, counters).await;
+      }
+    }
+  };
+  serving.await;
+  forget_closed(&connections);
+}
+
+// Accepts the connections Tor forwards to this node's onion service
+async fn accept(listener: TcpListener, connections: Connections, inbox: mpsc::Sender<Incoming>, counters: Arc<Counters>) {
+  loop {
+    let Ok((mut stream, _)) = listener.accept().await else {
+      continue;
+    };
+    let connections = connections.clone();
+    let inbox = inbox.clone();
+    let counters = counters.clone();
+    tokio::spawn(async move {
+      let Ok(hello) = read_frame(&mut stream).await else {
+        return;
+      };
+      let from = if hello.is_empty() {
+        Address::Onion { key: rand::random(), port: 0 }
+      } else {
+        match deserialized_address(&BitVec::from_bytes(&hello)) {
+          Some(from @ Address::Onion { .. }) => from,
+          _ => return,
+        }
+      };
+      // Answers on this connection, unless there's one to this peer already
+      let writes = {
+        let mut open = connections.lock().unwrap();
+        if open.get(&from).map(|writer| !writer.is_closed()).unwrap_or(false) {
+          None
+        } else {
+          let (writer, writes) = mpsc::channel(CONNECTION_CAPACITY);
+          open.insert(from, writer);
+          Some(writes)
+        }
+      };
+      serve_connection(stream, from, writes, inbox, counters).await;
+      forget_closed(&connections);
+    });
+  }
+}
+
+// Reads the messages of a peer, and writes the ones sent to it, until either side fails
+async fn serve_connection(stream: TcpStream, peer: Address, writes: Option<mpsc::Receiver<Arc<Vec<u8>>>>, inbox: mpsc::Sender<Incoming>, counters: Arc<Counters>) {
+  let (mut reader, mut writer) = stream.into_split();
+  let reading = async {
+    while let Ok(frame) = read_frame(&mut reader).await {
+      let permit = match inbox.try_reserve() {
+        Ok(permit) => permit,
+        Err(mpsc::error::TrySendError::Full(())) => {
+          counters.dropped_in.fetch_add(1, Ordering::Relaxed);
+          continue;
+        }
+        Err(mpsc::error::TrySendError::Closed(())) => return,
+      };
+      if let Some(message) = deserialized_message(&BitVec::from_bytes(&frame)) {
+        counters.received.fetch_add(1, Ordering::Relaxed);
+        permit.send(Incoming { from: peer, message, size: frame.len() });
+      }
+    }
+  };
+  let writing = async {
+    let Some(mut writes) = writes else {
+      return std::future::pending().await;
+    };
+    while let Some(data) = writes.recv().await {
+      if write_frame(&mut writer, &data).await.is_err() {
+        return;
+      }
+      counters.sent.fetch_add(1, Ordering::Relaxed);
+    }
+  };
+  tokio::select! {
+    _ = reading => {}
+    _ = writing => {}
+  }
+}
+
+// Forgets the connections whose tasks have ended
+fn forget_closed(connections: &Connections) {
+  connections.lock().unwrap().retain(|_, writer| !writer.is_closed());
+}
+
+async fn write_frame(stream: &mut (impl AsyncWriteExt + Unpin), data: &[u8]) -> std::io::Result<()> {
+  stream.write_all(&(data.len() as u32).to_be_bytes()).await?;
+  stream.write_all(data).await
+}
+
+async fn read_frame(stream: &mut (impl AsyncReadExt + Unpin)) -> std::io::Result<Vec<u8>> {
+  let size = stream.read_u32().await? as usize;
+  if size > MAX_FRAME {
+    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "frame too large"));
+  }
+  let mut frame = vec![0; size];
+  stream.read_exact(&mut frame).await?;
+  return Ok(frame);
+}
@@ -3,26 +3,39 @@ use json::object;
 use primitive_types::U256;
 use priority_queue::PriorityQueue;
 use rand::seq::IteratorRandom;
+use rand::{Rng, SeedableRng};
 use sha3::Digest;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::net::*;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
 use std::sync::mpsc;
 use std::sync::mpsc::{SyncSender, Receiver};
 
-use tokio::sync::oneshot;
+use tokio::sync::{broadcast, oneshot};
 
 use std::hash::{BuildHasherDefault};
 use crate::{NoHashHasher as NHH, print_with_timestamp};
 
 use crate::api;
-use crate::api::{NodeRequest, BlockInfo, FuncInfo, BlockRepr};
+use crate::backup::BackupSchedule;
+use crate::api::{NodeRequest, NodeEvent, BlockInfo, ForkInfo, ForkSummary, FuncInfo, BlockRepr, MinerInfo, NameEntry, PostRejection, PrecheckFailureInfo, DiskSummary, PrecheckSummary, Reexecution, ReplaySummary, StatementEntry, SyncSummary, WatchdogSummary};
+use crate::crypto;
 use crate::util::*;
 use crate::bits::*;
 use crate::hvm::{self, *};
+use crate::policy::StatementPolicy;
+use crate::query::{BlockBloom, CmpOp, Filter, StatementIndex, StatementMeta};
+use crate::runtime::RuntimeHandle;
+use crate::net::{NetStats, Network, OnionConfig, INBOX_CAPACITY, OUTBOX_CAPACITY};
+use crate::onion;
+use crate::socks::Socks5Relay;
+use crate::upgrade;
+#[cfg(feature = "mdns")]
+use crate::mdns::Mdns;
 
 // Types
 // -----
@@ -39,6 +52,9 @@ use crate::hvm::{self, *};
 pub type Map<A> = HashMap<u64, A, BuildHasherDefault<NHH::NoHashHasher<u64>>>;
 pub type Set    = im::HashSet<u64, BuildHasherDefault<NHH::NoHashHasher<u64>>>;
 
+// Random generator of the node. Seeded, so that a run can be reproduced.
+pub type NodeRng = rand::rngs::StdRng;
+
 #[derive(Debug, Clone)]
 pub struct Transaction {
   pub data: Vec<u8>,
@@ -84,8 +100,10 @@ pub enum InclusionState {
 // fast removal of mined transactions. An immutable map should suffice.
 pub struct Node {
   pub path       : PathBuf,                          // path where files are saved
-  pub socket     : UdpSocket,                        // UDP socket
-  pub port       : u16,                              // UDP port
+  pub net        : Network,                          // UDP sockets, one per listen address
+  pub port       : u16,                              // UDP port of the first socket
+  pub advertise  : Vec<Address>,                     // addresses of this node, shared with peers
+  pub proxy      : Option<Socks5Relay>,              // SOCKS5 proxy relaying outbound messages
   pub tip        : U256,                             // current tip
   pub block      : U256Map<Block>,                   // block_hash -> block
   pub pending    : U256Map<Block>,                   // block_hash -> downloaded block, waiting for ancestors
@@ -95,11 +113,45 @@ pub struct Node {
   pub work       : U256Map<U256>,                    // block_hash -> accumulated work
   pub target     : U256Map<U256>,                    // block_hash -> this block's target
   pub height     : U256Map<u128>,                    // block_hash -> cached height
+  pub chain      : Vec<U256>,                        // height -> hash of the block of the longest chain at it, from genesis
   pub results    : U256Map<Vec<StatementResult>>,    // block_hash -> results of the statements in this block
+  pub usage      : U256Map<Vec<StatementUsage>>,     // block_hash -> what the statements in this block took to run
+  pub bloom      : U256Map<BlockBloom>,              // block_hash -> blooms of the names this block touched and the events it emitted
+  pub state_hash : U256Map<U256>,                    // block_hash -> hash of the state right after this block
   pub pool       : PriorityQueue<Transaction, u64>,  // transactions to be mined
   pub peers      : PeersStore,                       // peers store and state control
-  pub runtime    : Runtime,                          // Kindelia's runtime
+  pub runtime    : RuntimeHandle,                    // Kindelia's runtime, on its own thread
   pub receiver   : Receiver<NodeRequest>,                // Receives an API request
+  pub chaos      : Option<Chaos>,                    // fault injection settings (testing only)
+  pub delayed    : Vec<(u128, Address, Message)>,    // messages held back by chaos mode
+  pub traffic    : TrafficStore,                     // bytes exchanged per peer and message kind
+  pub payout     : Option<u128>,                     // name paid by the blocks this node mines
+  pub extra_data : Vec<u8>,                          // extra data of the blocks this node mines
+  pub mining     : Mining,                           // whether, and how hard, the miner works
+  pub template   : MinerTemplate,                    // the block the miner was last asked for
+  pub forks      : ForkStats,                        // competing blocks and reorgs seen
+  pub fork_choice: Box<dyn ForkChoice>,              // picks the tip among the valid blocks
+  pub usage_stats: UsageStats,                       // what the statements of computed blocks took to run
+  pub index      : StatementIndex,                   // statements of the longest chain, for queries
+  pub expiry     : PoolExpiry,                       // when pool transactions are evicted
+  pub local      : LocalPool,                        // pool transactions submitted through the API
+  pub events     : broadcast::Sender<Arc<NodeEvent>>, // events sent to API subscribers
+  pub history    : HistoryQueries,                   // queries on past states, answered off this thread
+  pub replay     : ReplayVerifier,                   // checks the live state against replays
+  pub shadow     : ShadowExecution,                  // runs the blocks on a candidate runtime too
+  pub precheck   : BlockPrecheck,                    // runs the candidate blocks before mining them
+  pub preexec    : PoolPreexecution,                 // estimates of the pool's statements, run ahead of time
+  pub sync       : HeaderSync,                       // headers of the chain being synced
+  pub progress   : SyncProgress,                     // peers' tips and sync speed, for progress reports
+  pub watchdog   : BlockWatchdog,                    // aborts blocks that take too long to run
+  pub disk       : DiskMonitor,                      // free space of the data directory
+  pub backups    : Option<BackupSchedule>,           // when the data directory is backed up
+  pub policies   : Vec<Box<dyn StatementPolicy>>,    // statements this node won't relay nor mine
+  pub clock      : Arc<dyn Clock>,                   // where the node reads the time from
+  pub seed       : u64,                              // seed of `rng`, to reproduce a run
+  pub rng        : NodeRng,                          // source of every random choice of the node
+  #[cfg(feature = "mdns")]
+  pub mdns       : Option<Mdns>,                     // discovers nodes on the local network
 }
 
 // Peers
@@ -108,18 +160,37 @@ pub struct Node {
 pub struct PeersStore {
   seen: HashMap<Address, Peer>,
   active: HashMap<Address, Peer>,
+  prefer: Option<AddressFamily>, // family picked first when choosing random peers
+  allow: Option<HashSet<Address>>, // if set, the only addresses peered with
 }
 
 impl PeersStore {
-  pub fn new() -> PeersStore {
+  pub fn new(prefer: Option<AddressFamily>) -> PeersStore {
     PeersStore {
       seen: HashMap::new(),
       active: HashMap::new(),
+      prefer,
+      allow: None,
     }
   }
 
+  // Restricts the peers to these addresses, forgetting any others
+  pub fn allow_only(&mut self, addrs: &[Address]) {
+    let allow: HashSet<Address> = addrs.iter().copied().collect();
+    self.seen.retain(|addr, _| allow.contains(addr));
+    self.active.retain(|addr, _| allow.contains(addr));
+    self.allow = Some(allow);
+  }
+
+  pub fn is_allowed(&self, addr: &Address) -> bool {
+    self.allow.as_ref().map(|allow| allow.contains(addr)).unwrap_or(true)
+  }
+
   pub fn see_peer(&mut self, peer: Peer) {
     let addr = peer.address;
+    if !self.is_allowed(&addr) {
+      return;
+    }
     // print_with_timestamp!("- see peer {}", addr);
     match self.seen.get(&addr) {
       None => { // New peer, not seen before
@@ -145,12 +216,12 @@ impl PeersStore {
     }
   }
 
-  fn timeout(&mut self) {
+  fn timeout(&mut self, now: u128) {
     let mut forget = Vec::new();
     for (id,peer) in &self.active {
       // print_with_timestamp!("- Peer {}: {}", id, peer.address);
-      // print_with_timestamp!("... {} < {} {}", peer.seen_at, get_time() - PEER_TIMEOUT, peer.seen_at < get_time() - PEER_TIMEOUT);
-      if peer.seen_at < get_time() - PEER_TIMEOUT {
+      // print_with_timestamp!("... {} < {} {}", peer.seen_at, now - PEER_TIMEOUT, peer.seen_at < now - PEER_TIMEOUT);
+      if peer.seen_at < now - PEER_TIMEOUT {
         // print_with_timestamp!("... forgetting {}", id);
         forget.push(peer.address);
       }
@@ -164,14 +235,32 @@ impl PeersStore {
     self.active.remove(addr);
   }
 
+  pub fn get_all_seen(&self) -> Vec<Peer> {
+    self.seen.values().cloned().collect()
+  }
+
+  pub fn is_active(&self, addr: &Address) -> bool {
+    self.active.contains_key(addr)
+  }
+
   pub fn get_all_active(&self) -> Vec<Peer> {
     self.active.values().cloned().collect()
   }
 
-  pub fn get_random_active(&self, amount: u128) -> Vec<Peer> {
+  pub fn get_random_active(&self, amount: u128, rng: &mut impl Rng) -> Vec<Peer> {
     let amount = amount as usize;
-    let mut rng = rand::thread_rng();
-    let peers = self.active.values().cloned().choose_multiple(&mut rng, amount);
+    let mut peers = Vec::new();
+    // Sorted, so that the same seed picks the same peers
+    let mut active: Vec<Peer> = self.active.values().cloned().collect();
+    active.sort_by_key(|peer| peer.address);
+    // Picks peers of the preferred family first, then fills up with the others
+    if let Some(prefer) = self.prefer {
+      let (preferred, others): (Vec<Peer>, Vec<Peer>) = active.into_iter().partition(|peer| peer.address.family() == prefer);
+      peers.extend(preferred.into_iter().choose_multiple(rng, amount));
+      peers.extend(others.into_iter().choose_multiple(rng, amount - peers.len()));
+    } else {
+      peers = active.into_iter().choose_multiple(rng, amount);
+    }
     // print_with_timestamp!("- get random peers {:?}", peers.iter().map(|p| p.address).collect::<Vec<_>>());
     peers
   }
@@ -183,6 +272,9 @@ pub enum MinerMessage {
     prev: U256,
     body: Body,
     targ: U256, 
+    time: u128,     // timestamp of the block
+    extra: Vec<u8>, // extra data of the block's meta
+    intensity: u8,  // percentage of the time spent mining
   },
   Answer {
     block: Block
@@ -190,6 +282,862 @@ pub enum MinerMessage {
   Stop
 }
 
+// Mempool expiry
+// ==============
+
+// Where a transaction stands in the pool
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PoolStatus {
+  Pending { expires: u128 }, // evicted once the chain passes this height
+  Expired { at: u128 },      // evicted when the chain reached this height
+}
+
+// Evicts transactions that waited too long in the pool: after `ttl` blocks, or earlier, at the
+// height their sender asked for. The latest evictions are remembered, so senders can find why
+// their transaction is gone.
+pub struct PoolExpiry {
+  pub ttl: u128,
+  expires: U256Map<u128>,     // transaction hash -> height it expires after
+  evicted: U256Map<u128>,     // transaction hash -> height it was evicted at
+  evicted_order: VecDeque<U256>, // evicted transactions, oldest first
+}
+
+impl PoolExpiry {
+  pub fn new(ttl: u128) -> PoolExpiry {
+    PoolExpiry { ttl, expires: u256map_new(), evicted: u256map_new(), evicted_order: VecDeque::new() }
+  }
+
+  // Notes a transaction added to the pool at `height`. Re-adding it doesn't extend its life.
+  pub fn add(&mut self, hash: U256, height: u128, expires: Option<u128>) {
+    let expires = std::cmp::min(height + self.ttl, expires.unwrap_or(u128::MAX));
+    self.expires.entry(hash).or_insert(expires);
+    self.evicted.remove(&hash);
+  }
+
+  // Forgets a transaction that left the pool by being mined
+  pub fn remove(&mut self, hash: &U256) {
+    self.expires.remove(hash);
+  }
+
+  // Takes the transactions that expired once the chain reached `height`
+  pub fn expire(&mut self, height: u128) -> Vec<U256> {
+    let expired: Vec<U256> = self.expires.iter().filter(|(_, expires)| **expires < height).map(|(hash, _)| *hash).collect();
+    for hash in &expired {
+      self.expires.remove(hash);
+      self.evicted.insert(*hash, height);
+      self.evicted_order.push_back(*hash);
+    }
+    while self.evicted_order.len() > EVICTED_LIMIT {
+      if let Some(hash) = self.evicted_order.pop_front() {
+        self.evicted.remove(&hash);
+      }
+    }
+    return expired;
+  }
+
+  pub fn status(&self, hash: &U256) -> Option<PoolStatus> {
+    if let Some(expires) = self.expires.get(hash) {
+      return Some(PoolStatus::Pending { expires: *expires });
+    }
+    return self.evicted.get(hash).map(|at| PoolStatus::Expired { at: *at });
+  }
+}
+
+// Local transactions
+// ==================
+
+// A transaction submitted through this node's API
+#[derive(Debug, Clone)]
+pub struct LocalTransaction {
+  pub trans: Transaction,
+  pub broadcasts: u64,      // times it was sent to peers
+  pub last_broadcast: u128, // time of the last broadcast, or 0
+}
+
+// Transactions submitted through this node's API. Unlike relayed ones, these are rebroadcast until
+// they're mined or expire, and are kept on disk across restarts.
+#[derive(Default)]
+pub struct LocalPool {
+  txs: U256Map<LocalTransaction>,
+}
+
+impl LocalPool {
+  // Returns true if the transaction is new
+  pub fn add(&mut self, trans: Transaction) -> bool {
+    if self.txs.contains_key(&trans.hash) {
+      return false;
+    }
+    self.txs.insert(trans.hash, LocalTransaction { trans, broadcasts: 0, last_broadcast: 0 });
+    return true;
+  }
+
+  // Returns true if the transaction was there
+  pub fn remove(&mut self, hash: &U256) -> bool {
+    self.txs.remove(hash).is_some()
+  }
+
+  pub fn get(&self, hash: &U256) -> Option<&LocalTransaction> {
+    self.txs.get(hash)
+  }
+
+  pub fn len(&self) -> usize {
+    self.txs.len()
+  }
+
+  // Takes the transactions due for a rebroadcast at `now`, counting it
+  pub fn due(&mut self, now: u128) -> Vec<Transaction> {
+    let mut due = vec![];
+    for local in self.txs.values_mut() {
+      if local.broadcasts == 0 || local.last_broadcast + REBROADCAST_DELAY <= now {
+        local.broadcasts += 1;
+        local.last_broadcast = now;
+        due.push(local.trans.clone());
+      }
+    }
+    return due;
+  }
+
+  // One hex transaction per line
+  pub fn to_text(&self) -> String {
+    self.txs.values().map(|local| hex::encode(&local.trans.data) + "\n").collect()
+  }
+
+  pub fn from_text(text: &str) -> Vec<Transaction> {
+    text.lines().filter_map(|line| hex::decode(line.trim()).ok()).filter(|data| !data.is_empty()).map(Transaction::new).collect()
+  }
+}
+
+// Forks
+// =====
+
+// Competing blocks, i.e., valid blocks with the same parent, and reorganizations of the chain.
+// Frequent ones point to slow block propagation, or to selfish mining.
+#[derive(Debug, Clone, Default)]
+pub struct ForkStats {
+  pub competing: BTreeMap<u128, Vec<U256>>, // height -> valid blocks at it, if more than one
+  pub reorgs: u64,                          // times the tip moved to another branch
+  pub deepest_reorg: u128,                  // most blocks dropped from the chain by a reorg
+}
+
+impl ForkStats {
+  // Notes a valid block at `height`, among the children of its parent
+  pub fn see_block(&mut self, height: u128, siblings: &[U256], heights: &U256Map<u128>) {
+    let valid: Vec<U256> = siblings.iter().filter(|sibling| heights.get(sibling) == Some(&height)).copied().collect();
+    if valid.len() > 1 {
+      self.competing.insert(height, valid);
+    }
+  }
+
+  // Notes a tip change that dropped `depth` blocks from the chain
+  pub fn see_tip_change(&mut self, depth: u128) {
+    if depth > 0 {
+      self.reorgs += 1;
+      self.deepest_reorg = std::cmp::max(self.deepest_reorg, depth);
+    }
+  }
+}
+
+// Fork choice
+// ===========
+
+// Which of the valid blocks the node follows as its tip. Mainnet picks the one with the most
+// accumulated work, and so must every node following it. Other rules only make sense on test
+// networks, to see how they'd behave, with every node running the same one.
+pub trait ForkChoice: Send {
+  // Name shown on the node's metrics
+  fn name(&self) -> &'static str;
+  // The tip after `added`, a valid block already on the tree, joins it
+  fn choose_tip(&self, tree: &BlockTree, tip: U256, added: U256) -> U256;
+}
+
+// The valid blocks seen by a node, from the genesis block, which has no work. A block's work
+// includes its ancestors', so only blocks with more work than their parent are valid.
+pub struct BlockTree<'a> {
+  pub children: &'a U256Map<Vec<U256>>, // block_hash -> hashes of this block's children
+  pub work: &'a U256Map<U256>,          // block_hash -> accumulated work
+}
+
+impl<'a> BlockTree<'a> {
+  // Valid children of a valid block, in the order they were seen
+  pub fn valid_children(&self, hash: U256) -> impl Iterator<Item = U256> + '_ {
+    let parent_work = self.work[&hash];
+    let children = self.children.get(&hash).map(|children| children.as_slice()).unwrap_or(&[]);
+    children.iter().copied().filter(move |child| self.work[child] > parent_work)
+  }
+
+  // Work of each valid block plus its valid descendants' own work
+  pub fn subtree_work(&self) -> U256Map<U256> {
+    // Blocks are listed parents first, with their own work, so the weights are summed in reverse
+    let mut order = vec![(ZERO_HASH(), u256(0))];
+    let mut next = 0;
+    while next < order.len() {
+      let (hash, _) = order[next];
+      let parent_work = self.work[&hash];
+      order.extend(self.valid_children(hash).map(|child| (child, self.work[&child] - parent_work)));
+      next += 1;
+    }
+    let mut weight = u256map_new();
+    for &(hash, own) in order.iter().rev() {
+      let children = self.valid_children(hash).fold(u256(0), |sum, child| sum + weight[&child]);
+      weight.insert(hash, own + children);
+    }
+    weight
+  }
+}
+
+// Follows the chain with the most accumulated work. On a tie, the block seen first stays.
+pub struct MostWork;
+
+impl ForkChoice for MostWork {
+  fn name(&self) -> &'static str {
+    "most-work"
+  }
+
+  fn choose_tip(&self, tree: &BlockTree, tip: U256, added: U256) -> U256 {
+    if tree.work[&added] > tree.work[&tip] { added } else { tip }
+  }
+}
+
+// GHOST: from the genesis block, follows the child whose subtree has the most work, counting
+// stale blocks, until a leaf. On a tie, the child seen first is followed. It walks the whole tree
+// on each block, so it's meant for test networks.
+pub struct HeaviestSubtree;
+
+impl ForkChoice for HeaviestSubtree {
+  fn name(&self) -> &'static str {
+    "heaviest-subtree"
+  }
+
+  fn choose_tip(&self, tree: &BlockTree, _tip: U256, _added: U256) -> U256 {
+    let weight = tree.subtree_work();
+    let mut hash = ZERO_HASH();
+    while let Some(heaviest) = tree.valid_children(hash).reduce(|best, child| if weight[&child] > weight[&best] { child } else { best }) {
+      hash = heaviest;
+    }
+    hash
+  }
+}
+
+// A fork choice rule, as picked with `kindelia start --fork-choice`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ForkChoiceRule {
+  MostWork,
+  HeaviestSubtree,
+}
+
+impl ForkChoiceRule {
+  pub fn build(self) -> Box<dyn ForkChoice> {
+    match self {
+      ForkChoiceRule::MostWork => Box::new(MostWork),
+      ForkChoiceRule::HeaviestSubtree => Box::new(HeaviestSubtree),
+    }
+  }
+}
+
+impl std::str::FromStr for ForkChoiceRule {
+  type Err = String;
+  fn from_str(code: &str) -> Result<Self, Self::Err> {
+    match code {
+      "most-work" => Ok(ForkChoiceRule::MostWork),
+      "heaviest-subtree" => Ok(ForkChoiceRule::HeaviestSubtree),
+      _ => Err(format!("Invalid fork choice rule: '{}'. Expected 'most-work' or 'heaviest-subtree'.", code)),
+    }
+  }
+}
+
+// Header-first sync
+// =================
+
+// A node far behind its peers syncs in two steps. It first downloads the headers of their chain,
+// many per message, walking back from the tip it was told of until they reach a block it has, and
+// checks that each one hits its target and advances time. It then asks the bodies of the blocks
+// after its tip, a window at a time, from many peers at once, and runs them in order as they join
+// the chain. Gossip of a tip only brings its ancestors a few blocks per message, so it's kept to
+// nodes that are close to their peers.
+//
+// A block's hash covers its whole body, so the hash of a header is taken on trust until its body
+// comes. A peer that lies about one costs a few requests: when a body can't be had, the headers
+// are dropped, and sync starts again from the next tip seen. The headers of the chain being
+// synced are saved as `state/headers`, so a node restarted mid-sync carries on from its last block.
+
+// Headers in a `NoticeTheseHeaders` message; each one takes 96 bytes
+pub const HEADERS_PER_MESSAGE : usize = 64;
+
+// Blocks a node must be behind the tip it's told of, going by their times, to sync header-first
+pub const SYNC_DISTANCE : u128 = 16;
+
+// Blocks after the tip whose bodies are asked at once
+pub const SYNC_WINDOW : usize = 64;
+
+// Bodies asked per request. A `GiveMeThatBlock` answer brings the block and the ancestors that
+// fit, which is about 5 full blocks.
+pub const BODIES_PER_REQUEST : usize = 4;
+
+// Time to wait for an answer before asking again, in ms
+pub const SYNC_REQUEST_TIMEOUT : u128 = 2_000;
+
+// Times a body is asked before the headers are dropped
+pub const SYNC_MAX_ATTEMPTS : u32 = 8;
+
+// Most headers kept, so a peer can't fill the memory with them
+pub const MAX_SYNC_HEADERS : usize = 100_000;
+
+// A block without its body
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockHeader {
+  pub hash: U256,
+  pub prev: U256,
+  pub time: u128,
+  pub meta: u128,
+}
+
+impl BlockHeader {
+  pub fn of(block: &Block) -> BlockHeader {
+    BlockHeader { hash: block.hash, prev: block.prev, time: block.time, meta: block.meta }
+  }
+}
+
+// A body asked to peers
+#[derive(Debug, Clone, Copy)]
+pub struct BodyRequest {
+  pub sent_at: u128,
+  pub attempts: u32,
+}
+
+// Headers of blocks not included yet. A header is linked once its parent is an included block, or
+// another linked header, and it was checked.
+#[derive(Debug, Default)]
+pub struct HeaderSync {
+  pub headers  : U256Map<BlockHeader>,   // block_hash -> header
+  pub waiting  : U256Map<Vec<U256>>,     // block_hash -> headers not linked, waiting for this one
+  pub height   : U256Map<u128>,          // block_hash -> height of a linked header
+  pub target   : U256Map<U256>,          // block_hash -> target of a linked header
+  pub work     : U256Map<U256>,          // block_hash -> accumulated work of a linked header
+  pub best     : Option<U256>,           // linked header with the most work
+  pub requests : U256Map<BodyRequest>,   // block_hash -> body asked
+  pub sources  : Vec<Address>,           // peers that sent headers, which bodies are asked from
+  pub asked_at : u128,                   // when headers were last asked
+  pub changed  : bool,                   // whether the chain being synced wasn't saved yet
+}
+
+impl HeaderSync {
+  pub fn is_syncing(&self) -> bool {
+    self.best.is_some()
+  }
+
+  // Notes a peer that has the chain being synced
+  pub fn add_source(&mut self, addr: Address) {
+    if !self.sources.contains(&addr) {
+      self.sources.push(addr);
+    }
+  }
+
+  // Drops every header, e.g. once synced, or when a body can't be had
+  pub fn reset(&mut self) {
+    *self = HeaderSync { changed: self.is_syncing(), ..HeaderSync::default() };
+  }
+}
+
+// Samples of the executed height kept to estimate how fast a node syncs, one per second
+pub const SYNC_RATE_SAMPLES : usize = 60;
+
+// What a node knows of the chain it syncs to, for `GET /sync`
+#[derive(Debug, Default)]
+pub struct SyncProgress {
+  pub peer_tips: HashMap<Address, U256>, // latest tip gossiped by each peer
+  pub samples: VecDeque<(u128, u128)>,   // (time, executed height), oldest first
+}
+
+impl SyncProgress {
+  pub fn sample(&mut self, time: u128, height: u128) {
+    self.samples.push_back((time, height));
+    if self.samples.len() > SYNC_RATE_SAMPLES {
+      self.samples.pop_front();
+    }
+  }
+
+  // Blocks executed per second over the samples kept, if any were
+  pub fn rate(&self) -> Option<f64> {
+    let (first, last) = (self.samples.front()?, self.samples.back()?);
+    if last.1 <= first.1 || last.0 <= first.0 {
+      return None;
+    }
+    Some((last.1 - first.1) as f64 * 1000.0 / (last.0 - first.0) as f64)
+  }
+}
+
+// Statement usage
+// ===============
+
+// Statements slower than this are logged when their block is computed
+pub const SLOW_STATEMENT_TIME : std::time::Duration = std::time::Duration::from_millis(250);
+
+// Slowest statements kept by `UsageStats`
+pub const SLOWEST_STATEMENTS : usize = 16;
+
+// What the statements of the blocks this node computed took to run, so operators can find slow or
+// abusive contracts. Blocks computed again after a reorg are counted again.
+#[derive(Debug, Clone, Default)]
+pub struct UsageStats {
+  pub blocks: u64,
+  pub statements: u64,
+  pub rwts: u128,
+  pub mana: u128,
+  pub time: std::time::Duration,
+  pub slowest: Vec<SlowStatement>, // slowest first
+}
+
+#[derive(Debug, Clone)]
+pub struct SlowStatement {
+  pub height: u64,
+  pub position: usize,
+  pub funs: Vec<u128>, // functions it defines or calls
+  pub usage: StatementUsage,
+}
+
+impl UsageStats {
+  // Notes the usage of the statements of the block at `height`
+  pub fn see_block(&mut self, height: u64, statements: &[Statement], usage: &[StatementUsage]) {
+    self.blocks += 1;
+    for (position, (statement, usage)) in statements.iter().zip(usage).enumerate() {
+      self.statements += 1;
+      self.rwts += usage.rwts;
+      self.mana += usage.mana;
+      self.time += usage.time;
+      let slower = self.slowest.len() < SLOWEST_STATEMENTS || self.slowest.last().map_or(true, |last| usage.time > last.usage.time);
+      if slower {
+        let funs = StatementMeta::new(statement).funs;
+        let at = self.slowest.partition_point(|slow| slow.usage.time >= usage.time);
+        self.slowest.insert(at, SlowStatement { height, position, funs, usage: *usage });
+        self.slowest.truncate(SLOWEST_STATEMENTS);
+      }
+    }
+  }
+}
+
+// Past states
+// ===========
+
+// Queries on the state as of a past block (`?at=<height>`, re-execution, rewrite counts) start from
+// the newest retained snapshot at or before the block, and replay the blocks after it. That may take
+// long, so it's done on threads of their own, at most MAX_HISTORY_QUERIES at a time. Heights older
+// than every retained snapshot would be replayed from genesis, so only archive nodes answer them.
+
+// A past state to be rebuilt: a scratch runtime at a retained snapshot, and the blocks after it
+pub struct PastState {
+  runtime: Runtime,
+  blocks: Vec<Block>,
+}
+
+impl PastState {
+  // Replays the blocks, reaching the state right after the last of them
+  pub fn rebuild(mut self) -> Runtime {
+    for block in &self.blocks {
+      execute_block(&mut self.runtime, block, true);
+      self.runtime.tick_scratch();
+    }
+    return self.runtime;
+  }
+}
+
+#[derive(Default)]
+pub struct HistoryQueries {
+  pub archive: bool,       // replays from genesis, for heights older than every retained snapshot
+  running: Arc<AtomicUsize>, // queries being answered
+}
+
+// Counts a query as running until it's dropped
+struct RunningQuery(Arc<AtomicUsize>);
+
+impl Drop for RunningQuery {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+// Replay verification
+// ===================
+
+// A replay of the longest chain from a snapshot, on a shadow runtime, compared with the live one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayCheck {
+  pub from: u128,     // tick of the snapshot the replay started from
+  pub to: u128,       // tick both runtimes reached
+  pub live: U256,     // state hash of the live runtime
+  pub replayed: U256, // state hash of the shadow runtime
+}
+
+impl ReplayCheck {
+  pub fn diverged(&self) -> bool {
+    self.live != self.replayed
+  }
+}
+
+// A safety net for the rollback machinery, enabled by `--verify-replay`. Every now and then, the
+// blocks since a retained snapshot are replayed on a shadow runtime, in another thread, and the
+// state it reaches is compared with the live runtime's.
+#[derive(Default)]
+pub struct ReplayVerifier {
+  pub enabled: bool,
+  pub checks: u64,
+  pub divergences: u64,
+  pub last: Option<ReplayCheck>,
+  running: Option<mpsc::Receiver<ReplayCheck>>, // the check being replayed, if any
+}
+
+impl ReplayVerifier {
+  // Notes a finished check. Returns true if it diverged.
+  pub fn record(&mut self, check: ReplayCheck) -> bool {
+    let diverged = check.diverged();
+    self.checks += 1;
+    if diverged {
+      self.divergences += 1;
+    }
+    self.last = Some(check);
+    return diverged;
+  }
+}
+
+// Shadow execution
+// ================
+
+// A way to try a change to the runtime on a live network before trusting it with consensus,
+// enabled by `--shadow <candidate>`. The blocks the live runtime computes are run again on a shadow
+// runtime, configured as the candidate, in another thread, and the state hashes both reach after
+// each block are compared. Divergences are logged and sent as events, and don't affect the node.
+// The shadow starts from a snapshot of the live runtime, and starts over from one after reorgs and
+// divergences.
+
+// A runtime configuration compared with the live one
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Candidate {
+  Same,   // the live configuration, checking that execution is deterministic
+  Linear, // calls try each rule in order, instead of looking them up in the rule tables
+}
+
+impl Candidate {
+  pub fn name(self) -> &'static str {
+    match self {
+      Candidate::Same => "same",
+      Candidate::Linear => "linear",
+    }
+  }
+
+  // Turns a copy of the live runtime into the candidate
+  pub fn apply(self, rt: &mut Runtime) {
+    match self {
+      Candidate::Same => {}
+      Candidate::Linear => rt.set_rule_tables(false),
+    }
+  }
+}
+
+impl std::str::FromStr for Candidate {
+  type Err = String;
+  fn from_str(code: &str) -> Result<Self, Self::Err> {
+    match code {
+      "same" => Ok(Candidate::Same),
+      "linear" => Ok(Candidate::Linear),
+      _ => Err(format!("Invalid shadow candidate: '{}'. Expected 'same' or 'linear'.", code)),
+    }
+  }
+}
+
+// The states both runtimes reached after a block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShadowCheck {
+  pub height: u128,
+  pub block: U256,
+  pub live: U256,   // state hash of the live runtime
+  pub shadow: U256, // state hash of the shadow runtime
+}
+
+impl ShadowCheck {
+  pub fn diverged(&self) -> bool {
+    self.live != self.shadow
+  }
+}
+
+#[derive(Default)]
+pub struct ShadowExecution {
+  pub candidate: Option<Candidate>,
+  pub blocks: u64,                    // blocks compared
+  pub divergences: u64,
+  pub height: Option<u128>,           // of the latest block compared
+  pub diverged: Option<ShadowCheck>,  // the latest divergence
+  idle: Option<(Runtime, U256)>,      // the shadow between batches, and the last block it ran
+  running: Option<mpsc::Receiver<(Runtime, Vec<ShadowCheck>)>>, // the batch being run, if any
+}
+
+impl ShadowExecution {
+  // Notes a compared block. Returns true if it diverged.
+  pub fn record(&mut self, check: ShadowCheck) -> bool {
+    let diverged = check.diverged();
+    self.blocks += 1;
+    self.height = Some(check.height);
+    if diverged {
+      self.divergences += 1;
+      self.diverged = Some(check);
+    }
+    return diverged;
+  }
+}
+
+// Execution watchdog
+// ==================
+
+// A block whose execution was aborted for taking too long
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockTimeout {
+  pub hash: U256,
+  pub height: u128,
+  pub elapsed: std::time::Duration, // until it was aborted
+}
+
+// Protects the node from blocks that are valid, but take too long to run, when `--block-timeout`
+// sets a budget. It isn't a consensus rule, as how long a block takes depends on the machine: the
+// block isn't rejected, and stays on the chain if it's the longest. Instead, the node halts at its
+// parent, computing and mining no more blocks until it's restarted with a larger budget, so it
+// never follows a chain its peers wouldn't.
+#[derive(Default)]
+pub struct BlockWatchdog {
+  pub budget: Option<std::time::Duration>,
+  pub halted: Option<BlockTimeout>, // the block the node halted on, if any
+}
+
+// Disk space
+// ==========
+
+// The free space of the data directory is checked every few seconds. Below a threshold, the node
+// stops mining and taking blocks from peers, as it couldn't save them, and a write failing halfway
+// would leave the stored chain or snapshots broken. It warns on the log and the metrics, and
+// carries on once space is freed.
+
+// Delay between checks of the free space, in ms
+pub const DISK_CHECK_DELAY : u128 = 10_000;
+
+// Default free space below which the node stops, in MiB
+pub const MIN_FREE_SPACE : u64 = 512;
+
+#[derive(Debug, Clone, Default)]
+pub struct DiskMonitor {
+  pub min_free: u64,     // threshold, in bytes; 0 turns the safeguards off
+  pub free: Option<u64>, // free space at the last check, if it could be read
+  pub low: bool,         // whether it was below the threshold
+}
+
+impl DiskMonitor {
+  pub fn new(min_free: u64) -> Self {
+    DiskMonitor { min_free, free: None, low: false }
+  }
+
+  // Notes the free space read, returning whether it crossed the threshold
+  pub fn see(&mut self, free: Option<u64>) -> bool {
+    self.free = free;
+    let low = matches!(free, Some(free) if free < self.min_free);
+    let crossed = low != self.low;
+    self.low = low;
+    crossed
+  }
+}
+
+// Space available to unprivileged users on the file system of a path, in bytes
+#[cfg(unix)]
+pub fn free_space(path: &std::path::Path) -> std::io::Result<u64> {
+  use std::os::unix::ffi::OsStrExt;
+  let path = std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+  let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+  if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+    return Err(std::io::Error::last_os_error());
+  }
+  // The field types differ across platforms
+  #[allow(clippy::unnecessary_cast)]
+  return Ok(stat.f_bavail as u64 * stat.f_frsize as u64);
+}
+
+#[cfg(not(unix))]
+pub fn free_space(_path: &std::path::Path) -> std::io::Result<u64> {
+  return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "free space is only read on Unix"));
+}
+
+// Block pre-check
+// ===============
+
+// The miner's candidate body is run on top of the tip before it's mined, and the transactions
+// that fail there, like statements past the block's mana or that can't be decoded, are left out,
+// so no work is spent on a block holding what this node's runtime refuses. A candidate is only
+// checked again once the tip or the pool change.
+
+// Latest exclusions remembered, for the metrics
+pub const PRECHECK_FAILURES_KEPT : usize = 16;
+
+// A transaction left out of a candidate block
+#[derive(Debug, Clone)]
+pub struct PrecheckFailure {
+  pub transaction: U256,
+  pub height: u128, // of the candidate
+  pub err: String,
+}
+
+#[derive(Default)]
+pub struct BlockPrecheck {
+  pub checks: u64,                         // candidates run
+  pub excluded: u64,                       // transactions left out of them
+  pub failures: VecDeque<PrecheckFailure>, // the latest ones left out, newest last
+  checked: Option<(U256, U256, Body)>,     // tip and hash of the last candidate, and its checked body
+}
+
+impl BlockPrecheck {
+  fn exclude(&mut self, failure: PrecheckFailure) {
+    self.excluded += 1;
+    if self.failures.len() == PRECHECK_FAILURES_KEPT {
+      self.failures.pop_front();
+    }
+    self.failures.push_back(failure);
+  }
+}
+
+// Pool pre-execution
+// ==================
+
+// While mining, the pool's statements are run ahead of time on copies of the tip's state, to
+// estimate the mana each spends and whether it fails. Candidate blocks then leave out the ones
+// that would fail, and the ones past the mana left in the block, so it fits more that run.
+// Statements are grouped by the names their code may touch: ones sharing no name can't see each
+// other's effects, so each group runs on its own copy, in parallel. Calls by computed names may
+// touch anything, so a pool holding one runs as a single group. Estimates only pick what goes in
+// a candidate: it's still pre-checked as a whole, and blocks are still run in order.
+
+// Threads the groups of a pre-execution are spread over
+pub const PREEXECUTE_THREADS : usize = 4;
+
+// Delay between pre-executions of a changed pool, in ms
+pub const PREEXECUTE_DELAY : u128 = 2_000;
+
+// The names a statement may touch, as far as its code tells
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatementDeps {
+  pub names: Vec<u128>, // functions defined or called, names registered, their namespaces and the signer
+  pub dynamic: bool,    // whether it calls functions by computed names, so it may touch any
+}
+
+impl StatementDeps {
+  pub fn new(statement: &Statement) -> Self {
+    let meta = StatementMeta::with_signer(statement);
+    let mut names: Vec<u128> = meta.funs.into_iter().filter(|name| !is_effect_builder(*name)).collect();
+    names.extend(meta.name);
+    names.extend(meta.signer);
+    let namespaces: Vec<u128> = names.iter().filter_map(|name| get_namespace(*name)).collect();
+    names.extend(namespaces);
+    names.sort_unstable();
+    names.dedup();
+    StatementDeps { names, dynamic: has_dynamic_calls(statement) }
+  }
+}
+
+// Splits statements into groups sharing no name, by index, keeping their order in each group
+pub fn group_statements(deps: &[StatementDeps]) -> Vec<Vec<usize>> {
+  if deps.iter().any(|dep| dep.dynamic) {
+    return vec![(0 .. deps.len()).collect()];
+  }
+  fn find(parent: &mut [usize], mut i: usize) -> usize {
+    while parent[i] != i {
+      parent[i] = parent[parent[i]];
+      i = parent[i];
+    }
+    i
+  }
+  let mut parent: Vec<usize> = (0 .. deps.len()).collect();
+  let mut first: HashMap<u128, usize> = HashMap::new(); // first statement touching each name
+  for (i, dep) in deps.iter().enumerate() {
+    for name in &dep.names {
+      let j = *first.entry(*name).or_insert(i);
+      let (a, b) = (find(&mut parent, i), find(&mut parent, j));
+      parent[std::cmp::max(a, b)] = std::cmp::min(a, b);
+    }
+  }
+  let mut groups: Vec<Vec<usize>> = vec![];
+  let mut group_of: HashMap<usize, usize> = HashMap::new();
+  for i in 0 .. deps.len() {
+    let root = find(&mut parent, i);
+    let group = *group_of.entry(root).or_insert_with(|| {
+      groups.push(vec![]);
+      groups.len() - 1
+    });
+    groups[group].push(i);
+  }
+  return groups;
+}
+
+// What a statement is expected to do in the next block
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PoolEstimate {
+  pub mana: u128, // spent, after refunds
+  pub ok: bool,   // whether it ran without failing
+}
+
+// A finished pre-execution: the tip it ran on, the mana left to the block after it, the groups
+// run, and the estimates by transaction
+type PreexecutionRun = (U256, u128, usize, Vec<(U256, PoolEstimate)>);
+
+#[derive(Default)]
+pub struct PoolPreexecution {
+  pub runs: u64,
+  pub groups: u64,                        // of the latest run
+  tip: U256,                              // block the estimates were made on
+  budget: u128,                           // mana the block after it can spend
+  estimates: HashMap<U256, PoolEstimate>, // by transaction
+  running: Option<mpsc::Receiver<PreexecutionRun>>, // the run going on, if any
+}
+
+// Runs groups of statements on copies of a runtime, in parallel, in the environment of a block.
+// Answers the estimate of each statement, by index.
+pub fn preexecute(runtime: Runtime, statements: &[Statement], groups: &[Vec<usize>], block: &Block) -> Vec<Option<PoolEstimate>> {
+  let threads = std::cmp::min(PREEXECUTE_THREADS, groups.len());
+  let mut runtimes: Vec<Runtime> = (1 .. threads).map(|_| runtime.fork_tip()).collect();
+  runtimes.push(runtime);
+  let mut estimates = vec![None; statements.len()];
+  thread::scope(|scope| {
+    let workers: Vec<_> = runtimes.iter_mut().enumerate().map(|(worker, runtime)| {
+      scope.spawn(move || {
+        let mut found = vec![];
+        for group in groups.iter().skip(worker).step_by(threads) {
+          let group_statements: Vec<Statement> = group.iter().map(|i| statements[*i].clone()).collect();
+          let results = runtime.simulate(|runtime| {
+            set_block_env(runtime, block);
+            runtime.run_statements_measured(&group_statements, true)
+          });
+          for (i, (result, usage)) in group.iter().zip(results) {
+            found.push((*i, PoolEstimate { mana: usage.mana, ok: result.is_ok() }));
+          }
+        }
+        found
+      })
+    }).collect();
+    for worker in workers {
+      for (i, estimate) in worker.join().expect("A pre-execution thread crashed.") {
+        estimates[i] = Some(estimate);
+      }
+    }
+  });
+  return estimates;
+}
+
+// Miner settings, changeable at runtime
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Mining {
+  pub active: bool,  // if false, the miner is paused
+  pub intensity: u8, // percentage of the time spent mining, from 1 to 100
+}
+
+// What the miner was last asked to mine, to tell when it's worth asking for another block
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MinerTemplate {
+  pub prev: U256,  // tip it builds on
+  pub size: usize, // bytes of the body, before leaving out the statements that fail
+  pub time: u128,  // when it was asked for
+}
+
 #[derive(Debug, Clone)]
 pub struct MinerCommunication {
   message: Arc<Mutex<MinerMessage>>
@@ -208,10 +1156,16 @@ pub enum Message {
   },
   PleaseMineThisTransaction {
     trans: Transaction
-  }
+  },
+  GiveMeTheseHeaders {
+    bhash: Hash
+  },
+  NoticeTheseHeaders {
+    headers: Vec<BlockHeader>,
+  },
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Address {
   IPv4 {
     val0: u8,
@@ -219,7 +1173,66 @@ pub enum Address {
     val2: u8,
     val3: u8,
     port: u16,
-  }
+  },
+  IPv6 {
+    segs: [u16; 8],
+    port: u16,
+  },
+  Onion {
+    key: [u8; 32], // public key of a Tor onion service
+    port: u16,     // port 0 marks peers without an onion service, reachable only while connected
+  },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AddressFamily {
+  IPv4,
+  IPv6,
+  Onion,
+}
+
+// Network settings of a node
+#[derive(Debug, Clone, Default)]
+pub struct NetConfig {
+  pub listen         : Vec<SocketAddr>,       // addresses to listen on; if empty, the first free default port
+  pub advertise      : Vec<Address>,          // addresses shared with peers, besides the listen ones
+  pub prefer         : Option<AddressFamily>, // family to prefer when picking peers
+  pub peer_bandwidth : Option<u128>,          // bandwidth cap per peer, in bytes per second
+  pub proxy          : Option<SocketAddr>,    // SOCKS5 proxy to send messages through
+  pub tor            : Option<SocketAddr>,    // Tor's SOCKS5 proxy, to reach onion peers over TCP
+  pub onion          : Option<Address>,       // onion address of this node; Tor forwards it to 127.0.0.1 on its port
+  pub mdns           : bool,                  // discovers nodes on the local network (needs the `mdns` feature)
+  pub connect_only   : Vec<Address>,          // if not empty, the only peers; messages from others are dropped
+}
+
+// Fault injection settings, enabled by the hidden `--chaos` flag. Used by operators and CI to
+// check that the node recovers from lossy networks and failing disks. Disk writes fail both for
+// blocks and for the runtime's heaps.
+#[derive(Debug, Copy, Clone)]
+pub struct Chaos {
+  pub max_delay : u128, // maximum delay added to an outgoing message, in ms
+  pub drop_rate : u128, // percentage of outgoing messages that are dropped
+  pub disk_fail : u128, // percentage of disk writes that fail
+}
+
+// Bytes and messages exchanged with a peer, or of a kind of message
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Traffic {
+  pub sent_bytes : u128,
+  pub sent_count : u128,
+  pub recv_bytes : u128,
+  pub recv_count : u128,
+  pub dropped    : u128, // messages dropped for going over the bandwidth cap
+}
+
+// The node's traffic, per peer and per kind of message. When capped, a peer can't use more than
+// `cap` bytes per second each way; messages over it are dropped. Peers are forgotten along with
+// inactive peers, and at most `MAX_TRAFFIC_PEERS` are tracked, so spoofed senders can't grow it.
+pub struct TrafficStore {
+  pub cap : Option<u128>,
+  peers   : HashMap<Address, Traffic>,
+  kinds   : HashMap<&'static str, Traffic>,
+  window  : HashMap<Address, (u128, u128, u128)>, // peer -> current second, bytes sent and received on it
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -240,6 +1253,17 @@ pub const HASH_SIZE : usize = 32;
 // Size of a block's body, in bytes
 pub const MAX_BODY_SIZE : usize = 1280;
 
+// A body may end with the name of its miner's payout account: this tag and the name, in 16 bytes.
+// Nodes that don't know of it ignore the bytes past the transactions.
+pub const PAYOUT_TAG : u8 = 0xFA;
+pub const PAYOUT_SIZE : usize = 17;
+
+// The high 8 bytes of a block's meta may carry extra data set by its miner, to signal versions or
+// identify itself: this tag and up to 7 bytes, zero-padded. The low 8 bytes are the mining nonce.
+// Untagged metas start with a zero byte, so they're never read as extra data.
+pub const EXTRA_DATA_TAG : u8 = 0xED;
+pub const MAX_EXTRA_DATA : usize = 7;
+
 // Max size of a big UDP packet, in bytes
 pub const MAX_UDP_SIZE_SLOW : usize = 8000;
 
@@ -264,6 +1288,15 @@ pub const GOSSIP_FACTOR : u128 = 16;
 // How many times the mining thread attempts before unblocking?
 pub const MINE_ATTEMPTS : u128 = 1024;
 
+// Statements pay no fees, so a block is worth as much as the statements it holds. Between the
+// periodic requests, the miner is asked for a new block as soon as the tip moves, and when the
+// block grew by this many bytes of new statements, at most once per TEMPLATE_REFRESH_DELAY ms.
+pub const TEMPLATE_MIN_GAIN : usize = 128;
+pub const TEMPLATE_REFRESH_DELAY : u128 = 250;
+
+// Time the miner waits between checks for requests, while idle
+pub const MINER_IDLE_DELAY : std::time::Duration = std::time::Duration::from_millis(10);
+
 // Desired average time between mined blocks, in milliseconds
 pub const TIME_PER_BLOCK : u128 = 3000;
 
@@ -276,12 +1309,42 @@ pub const BLOCKS_PER_PERIOD : u128 = 20;
 // Readjusts difficulty every N seconds
 pub const TIME_PER_PERIOD : u128 = TIME_PER_BLOCK * BLOCKS_PER_PERIOD;
 
+// Bytes per block on the block index
+pub const BLOCK_INDEX_ENTRY : u64 = 32;
+
+// Blocks the hash rate of the network is estimated over
+pub const HASH_RATE_WINDOW : u128 = BLOCKS_PER_PERIOD * 3;
+
 // Initial difficulty, in expected hashes per block
 pub const INITIAL_DIFFICULTY : u128 = 256;
 
 // How many milliseconds without notice until we forget a peer?
 pub const PEER_TIMEOUT : u128 = 10 * 1000;
 
+// Peers whose traffic is tracked at once
+pub const MAX_TRAFFIC_PEERS : usize = 4096;
+
+// Blocks a transaction may wait in the pool before it's evicted (about an hour)
+pub const POOL_TTL : u128 = 1200;
+
+// Evicted transactions remembered, to answer what happened to them
+pub const EVICTED_LIMIT : usize = 4096;
+
+// Time between broadcasts of a local transaction, until it's mined
+pub const REBROADCAST_DELAY : u128 = 30 * 1000;
+
+// Number of peers a local transaction is broadcast to each time
+pub const REBROADCAST_PEERS : u128 = 8;
+
+// Events kept for subscribers that fall behind
+pub const EVENT_BUFFER : usize = 256;
+
+// Queries on past states answered at the same time, each on a thread of its own
+pub const MAX_HISTORY_QUERIES : usize = 2;
+
+// Time between replay verifications, when enabled
+pub const REPLAY_CHECK_DELAY : u128 = 10 * 60 * 1000;
+
 // How many peers we need to keep minimum?
 pub const PEER_COUNT_MINIMUM : u128 = 256;
 
@@ -322,6 +1385,11 @@ pub const HANDLE_REQUEST_DELAY : u128 = 20;
 //   on that tips ancestry. That information may be cached, avoiding that loop.
 pub const HANDLE_MESSAGE_LIMIT : u128 = 5;
 
+// Default chaos mode settings: delay up to 2s, drop 10% of messages, fail 5% of disk writes
+pub const CHAOS_MAX_DELAY : u128 = 2000;
+pub const CHAOS_DROP_RATE : u128 = 10;
+pub const CHAOS_DISK_FAIL : u128 = 5;
+
 
 // UDP
 // ===
@@ -334,65 +1402,94 @@ pub fn ipv4(val0: u8, val1: u8, val2: u8, val3: u8, port: u16) -> Address {
 /// Starts listening to UDP messages on one port of a set of ports
 pub fn udp_init(ports: &[u16]) -> Option<(UdpSocket,u16)> {
   for port in ports {
-    if let Ok(socket) = UdpSocket::bind(&format!("0.0.0.0:{}",port)) {
-      socket.set_nonblocking(true).ok();
+    if let Ok(socket) = udp_bind(SocketAddr::from(([0, 0, 0, 0], *port))) {
       return Some((socket, *port));
     }
   }
   return None;
 }
 
+/// Starts listening to UDP messages on an address. On dual-stack hosts, a socket bound to `[::]`
+/// also receives IPv4 messages.
+pub fn udp_bind(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+  let socket = UdpSocket::bind(addr)?;
+  socket.set_nonblocking(true)?;
+  return Ok(socket);
+}
+
 /// Sends an UDP message to many addresses
 pub fn udp_send(socket: &mut UdpSocket, addresses: Vec<Address>, message: &Message) {
-  let bits = bitvec_to_bytes(&serialized_message(message));
+  udp_send_bytes(socket, addresses, &bitvec_to_bytes(&serialized_message(message)));
+}
+
+/// Sends an already serialized UDP message to many addresses. An IPv6 socket reaches IPv4
+/// addresses through IPv4-mapped addresses, which works on dual-stack hosts.
+pub fn udp_send_bytes(socket: &mut UdpSocket, addresses: Vec<Address>, bits: &[u8]) {
+  let is_ipv6 = socket.local_addr().map(|addr| addr.is_ipv6()).unwrap_or(false);
   for address in addresses {
-    match address {
-      Address::IPv4 { val0, val1, val2, val3, port } => {
-        let addr = SocketAddrV4::new(Ipv4Addr::new(val0, val1, val2, val3), port);
-        socket.send_to(bits.as_slice(), addr).ok();
-      }
+    if let Some(target) = udp_target(is_ipv6, &address) {
+      socket.send_to(bits, target).ok();
     }
   }
 }
 
+/// Where a socket sends to reach an address: IPv6 sockets reach IPv4 ones through IPv4-mapped
+/// addresses. Onion addresses aren't reachable over UDP.
+pub fn udp_target(is_ipv6: bool, address: &Address) -> Option<SocketAddr> {
+  match (is_ipv6, address.to_socket_addr()?) {
+    (true, SocketAddr::V4(v4addr)) => Some(SocketAddr::new(IpAddr::V6(v4addr.ip().to_ipv6_mapped()), v4addr.port())),
+    (_, addr) => Some(addr),
+  }
+}
+
 // Receives an UDP messages
-// Non-blocking, returns a vector of received messages on buffer
-pub fn udp_recv(socket: &mut UdpSocket) -> Vec<(Address, Message)> {
+// Non-blocking, returns a vector of received messages on buffer, with their sizes in bytes
+pub fn udp_recv(socket: &mut UdpSocket) -> Vec<(Address, Message, usize)> {
+  udp_recv_with(socket, None)
+}
+
+// Receives an UDP messages, unwrapping the ones relayed by a SOCKS5 proxy
+pub fn udp_recv_with(socket: &mut UdpSocket, proxy: Option<&Socks5Relay>) -> Vec<(Address, Message, usize)> {
   let mut buffer = [0; 65536];
   let mut messages = Vec::new();
+  let relay = proxy.map(|proxy| proxy.relay);
   while let Ok((msg_len, sender_addr)) = socket.recv_from(&mut buffer) {
-    let bits = BitVec::from_bytes(&buffer[0 .. msg_len]);
-    if let Some(msge) = deserialized_message(&bits) {
-      let addr = match sender_addr.ip() {
-        std::net::IpAddr::V4(v4addr) => {
-          let [val0, val1, val2, val3] = v4addr.octets();
-          Address::IPv4 { val0, val1, val2, val3, port: sender_addr.port() }
-        }
-        _ => {
-          panic!("TODO: IPv6")
-        }
-      };
-      messages.push((addr, msge));
+    if let Some((addr, msge)) = decode_datagram(relay, sender_addr, &buffer[0 .. msg_len]) {
+      messages.push((addr, msge, msg_len));
     }
   }
   return messages;
 }
 
+// Decodes a received datagram, unwrapping it if it came from the SOCKS5 `relay`
+pub fn decode_datagram(relay: Option<SocketAddr>, sender_addr: SocketAddr, data: &[u8]) -> Option<(Address, Message)> {
+  let (sender_addr, data) = match relay {
+    Some(relay) if sender_addr == relay => Socks5Relay::unwrap(data)?,
+    _ => (sender_addr, data),
+  };
+  let msge = deserialized_message(&BitVec::from_bytes(data))?;
+  return Some((Address::from(sender_addr), msge));
+}
+
 // Stringification
 // ===============
 
-// Converts a string to an address
+// Converts a string to an address. IPv6 addresses with a port are written as `[::1]:42000`, and
+// onion addresses as `<56 characters>.onion:42000`.
 pub fn read_address(code: &str) -> Address {
-  let strs = code.split(':').collect::<Vec<&str>>();
-  let vals = strs[0].split('.').map(|o| o.parse::<u8>().unwrap()).collect::<Vec<u8>>();
-  let port = strs.get(1).map(|s| s.parse::<u16>().unwrap()).unwrap_or(UDP_PORT);
-  Address::IPv4 {
-    val0: vals[0],
-    val1: vals[1],
-    val2: vals[2],
-    val3: vals[3],
-    port: port,
+  if let Ok(addr) = code.parse::<SocketAddr>() {
+    return Address::from(addr);
+  }
+  let (host, port) = match code.rsplit_once(':') {
+    Some((host, port)) if host.ends_with(".onion") => (host, port.parse().expect("Invalid port.")),
+    _ => (code, UDP_PORT),
+  };
+  if host.ends_with(".onion") {
+    let key = onion::read_onion_host(host).expect("Invalid onion address.");
+    return Address::Onion { key, port };
   }
+  let ip = code.parse::<IpAddr>().expect("Invalid address.");
+  return Address::from(SocketAddr::new(ip, UDP_PORT));
 }
 
 // Shows an address's hostname
@@ -401,6 +1498,12 @@ pub fn show_address_hostname(address: &Address) -> String {
     Address::IPv4{ val0, val1, val2, val3, port } => {
       return format!("{}.{}.{}.{}", val0, val1, val2, val3);
     }
+    Address::IPv6{ segs, .. } => {
+      return Ipv6Addr::from(*segs).to_string();
+    }
+    Address::Onion { key, .. } => {
+      return onion::onion_host(key);
+    }
   }
 }
 
@@ -434,11 +1537,28 @@ pub fn compute_next_target(last_target: U256, scale: U256) -> U256 {
   return difficulty_to_target(next_difficulty);
 }
 
+// Computes the target of a period, after the last one took `period_time` to complete.
+pub fn compute_period_target(last_target: U256, period_time: u128) -> U256 {
+  let next_scaler = 2u128.pow(32) * TIME_PER_PERIOD / std::cmp::max(period_time, 1);
+  return compute_next_target(last_target, u256(next_scaler));
+}
+
 // Computes the next target, scaling by a floating point factor.
 pub fn compute_next_target_f64(last_target: U256, scale: f64) -> U256 {
   return compute_next_target(last_target, u256(scale as u128));
 }
 
+// Sums the mana spent by the statements of a block
+pub fn get_results_mana(results: &[StatementResult]) -> u128 {
+  let mut mana = 0;
+  for result in results {
+    if let Ok(StatementInfo::Run { used_mana, .. }) = result {
+      mana += used_mana;
+    }
+  }
+  return mana;
+}
+
 // Estimates how many hashes were necessary to get this one.
 pub fn get_hash_work(hash: U256) -> U256 {
   if hash == u256(0) {
@@ -484,9 +1604,41 @@ pub fn bytes_to_body(bytes: &[u8]) -> Body {
 // Converts a string (with a list of statements) to a body.
 pub fn code_to_body(code: &str) -> Body {
   let (_rest, acts) = crate::hvm::read_statements(code).unwrap(); // TODO: handle error
-  let bits = serialized_statements(&acts);
-  let body = bytes_to_body(&bitvec_to_bytes(&bits));
-  return body;
+  return statements_to_body(&acts);
+}
+
+// Builds a body with as many of the given statements as fit. Each statement is a transaction on
+// its own, so they are serialized in parallel.
+pub fn statements_to_body(statements: &[Statement]) -> Body {
+  let transactions: Vec<Transaction> =
+    serialized_each(serialize_statement, statements).iter().map(|bits| Transaction::new(bitvec_to_bytes(bits))).collect();
+  return transactions_to_body(transactions.iter(), None);
+}
+
+// Concatenates transactions into a body, stopping at the first one that doesn't fit. Ends it
+// with the payout name, if any.
+pub fn transactions_to_body<'a>(transactions: impl Iterator<Item = &'a Transaction>, payout: Option<u128>) -> Body {
+  let max_size = MAX_BODY_SIZE - if payout.is_some() { PAYOUT_SIZE } else { 0 };
+  let mut body_vec = Vec::with_capacity(MAX_BODY_SIZE);
+  body_vec.push(0);
+  let mut tx_count = 0;
+  for transaction in transactions {
+    let tx_len = transaction.data.len();
+    if tx_len == 0 { continue; }
+    let len_info = transaction.encode_length(); // number we will store as the length
+    if body_vec.len() + 2 + tx_len > max_size { break; }
+    if tx_count + 1 > 255 { break; }
+    body_vec.push(len_info.0);
+    body_vec.push(len_info.1);
+    body_vec.extend_from_slice(&transaction.data);
+    tx_count += 1;
+  }
+  body_vec[0] = tx_count as u8;
+  if let Some(payout) = payout {
+    body_vec.push(PAYOUT_TAG);
+    body_vec.extend_from_slice(&payout.to_be_bytes());
+  }
+  return Body { data: body_vec };
 }
 
 // Encodes a transaction length as a pair of 2 bytes
@@ -504,9 +1656,9 @@ fn decode_length(pair: (u8,u8)) -> usize {
 pub fn extract_transactions(body: &Body) -> Vec<Transaction> {
   let mut transactions = Vec::new();
   let mut index = 1;
-  let tx_count = body.data[0];
+  let tx_count = body.data.first().copied().unwrap_or(0); // bodies from the wire can be empty
   for i in 0 .. tx_count {
-    if index >= body.data.len() { break; }
+    if index + 1 >= body.data.len() { break; }
     let tx_len = decode_length((body.data[index], body.data[index + 1]));
     index += 2;
     if index + tx_len > body.data.len() { break; }
@@ -516,6 +1668,38 @@ pub fn extract_transactions(body: &Body) -> Vec<Transaction> {
   return transactions;
 }
 
+// Reads the payout name a body ends with, if any
+pub fn extract_payout(body: &Body) -> Option<u128> {
+  let mut index = 1;
+  for _ in 0 .. *body.data.first()? {
+    let tx_len = decode_length((*body.data.get(index)?, *body.data.get(index + 1)?));
+    index += 2 + tx_len;
+  }
+  let rest = body.data.get(index ..)?;
+  if rest.len() != PAYOUT_SIZE || rest[0] != PAYOUT_TAG {
+    return None;
+  }
+  return Some(u128::from_be_bytes(rest[1 ..].try_into().ok()?));
+}
+
+// Builds the meta of a block carrying extra data, which must fit in `MAX_EXTRA_DATA` bytes
+pub fn block_meta(extra: &[u8], nonce: u64) -> u128 {
+  let mut high = [0; 8];
+  high[0] = EXTRA_DATA_TAG;
+  high[1 .. 1 + extra.len()].copy_from_slice(extra);
+  return (u64::from_be_bytes(high) as u128) << 64 | nonce as u128;
+}
+
+// Reads the extra data of a block's meta, if its miner set any, without the zero padding
+pub fn extract_extra_data(meta: u128) -> Option<Vec<u8>> {
+  let high = ((meta >> 64) as u64).to_be_bytes();
+  if high[0] != EXTRA_DATA_TAG {
+    return None;
+  }
+  let len = high.iter().rposition(|byte| *byte != 0).unwrap_or(0);
+  return Some(high[1 ..= len].to_vec());
+}
+
 // Initial target of 256 hashes per block
 pub fn INITIAL_TARGET() -> U256 {
   return difficulty_to_target(u256(INITIAL_DIFFICULTY));
@@ -547,52 +1731,370 @@ pub fn GENESIS_BLOCK() -> Block {
 
 
 
-impl Transaction {
-  pub fn new(mut data: Vec<u8>) -> Self {
-    // Transaction length is always a non-zero multiple of 5
-    while data.len() == 0 || data.len() % 5 != 0 {
-      data.push(0);
+impl Transaction {
+  pub fn new(mut data: Vec<u8>) -> Self {
+    // Transaction length is always a non-zero multiple of 5
+    while data.len() == 0 || !data.len().is_multiple_of(5) {
+      data.push(0);
+    }
+    let hash = hash_bytes(&data);
+    return Transaction { data, hash };
+  }
+
+  pub fn encode_length(&self) -> (u8, u8) {
+    return encode_length(self.data.len());
+  }
+
+  pub fn to_statement(&self) -> Option<Statement> {
+    return deserialized_statement(&BitVec::from_bytes(&self.data));
+  }
+
+  // The max mana price the transaction bids, if it is a run
+  pub fn max_price(&self) -> Option<u128> {
+    match self.to_statement()? {
+      Statement::Run { price, .. } => Some(price.unwrap_or(INITIAL_MANA_PRICE)),
+      _ => None,
+    }
+  }
+
+  // Its priority on the pool: runs that bid a higher max mana price come first. Other statements
+  // spend no mana, and go along the runs that bid the initial price. Ties are broken by hash.
+  pub fn priority(&self) -> u64 {
+    let price = std::cmp::min(self.max_price().unwrap_or(INITIAL_MANA_PRICE), u32::MAX as u128) as u64;
+    return price << 32 | self.hash.low_u64() & 0xFFFF_FFFF;
+  }
+}
+
+impl PartialEq for Transaction {
+  fn eq(&self, other: &Self) -> bool {
+    self.hash == other.hash
+  }
+}
+
+impl Eq for Transaction {}
+
+impl std::hash::Hash for Transaction {
+  fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+    self.hash.hash(state);
+  }
+}
+
+// Addresses
+// ---------
+
+impl Address {
+  pub fn family(&self) -> AddressFamily {
+    match self {
+      Address::IPv4 { .. } => AddressFamily::IPv4,
+      Address::IPv6 { .. } => AddressFamily::IPv6,
+      Address::Onion { .. } => AddressFamily::Onion,
+    }
+  }
+
+  pub fn port(&self) -> u16 {
+    match self {
+      Address::IPv4 { port, .. } => *port,
+      Address::IPv6 { port, .. } => *port,
+      Address::Onion { port, .. } => *port,
+    }
+  }
+
+  // The socket address of an IP address. Onion addresses have none.
+  pub fn to_socket_addr(&self) -> Option<SocketAddr> {
+    match self {
+      Address::IPv4 { val0, val1, val2, val3, port } => {
+        Some(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(*val0, *val1, *val2, *val3), *port)))
+      }
+      Address::IPv6 { segs, port } => {
+        let [s0, s1, s2, s3, s4, s5, s6, s7] = *segs;
+        Some(SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::new(s0, s1, s2, s3, s4, s5, s6, s7), *port, 0, 0)))
+      }
+      Address::Onion { .. } => None,
+    }
+  }
+
+  pub fn is_loopback(&self) -> bool {
+    self.to_socket_addr().map(|addr| addr.ip().is_loopback()).unwrap_or(false)
+  }
+
+  // Can this address be shared with peers? Onion peers without an onion service can't be reached
+  // by anyone else.
+  pub fn is_shareable(&self) -> bool {
+    self.family() != AddressFamily::Onion || self.port() != 0
+  }
+}
+
+// IPv4-mapped IPv6 addresses, as received by dual-stack sockets, are converted back to IPv4
+impl From<SocketAddr> for Address {
+  fn from(addr: SocketAddr) -> Self {
+    let ip = match addr.ip() {
+      IpAddr::V6(v6addr) => v6addr.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(IpAddr::V6(v6addr)),
+      ip => ip,
+    };
+    match ip {
+      IpAddr::V4(v4addr) => {
+        let [val0, val1, val2, val3] = v4addr.octets();
+        Address::IPv4 { val0, val1, val2, val3, port: addr.port() }
+      }
+      IpAddr::V6(v6addr) => {
+        Address::IPv6 { segs: v6addr.segments(), port: addr.port() }
+      }
+    }
+  }
+}
+
+impl std::str::FromStr for AddressFamily {
+  type Err = String;
+  fn from_str(code: &str) -> Result<Self, Self::Err> {
+    match code {
+      "ipv4" => Ok(AddressFamily::IPv4),
+      "ipv6" => Ok(AddressFamily::IPv6),
+      "onion" => Ok(AddressFamily::Onion),
+      _ => Err(format!("Invalid address family: '{}'. Expected 'ipv4', 'ipv6' or 'onion'.", code)),
+    }
+  }
+}
+
+// Messages
+// --------
+
+impl Message {
+  // The kind of a message, as shown on traffic stats
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Message::NoticeTheseBlocks { .. } => "NoticeTheseBlocks",
+      Message::GiveMeThatBlock { .. } => "GiveMeThatBlock",
+      Message::GiveMeTheseHeaders { .. } => "GiveMeTheseHeaders",
+      Message::NoticeTheseHeaders { .. } => "NoticeTheseHeaders",
+      Message::PleaseMineThisTransaction { .. } => "PleaseMineThisTransaction",
+    }
+  }
+}
+
+// Traffic
+// -------
+
+impl TrafficStore {
+  pub fn new(cap: Option<u128>) -> Self {
+    TrafficStore { cap, peers: HashMap::new(), kinds: HashMap::new(), window: HashMap::new() }
+  }
+
+  // Accounts for a message sent to a peer. Returns false if it must be dropped instead.
+  pub fn send(&mut self, addr: Address, kind: &'static str, bytes: u128, now: u128) -> bool {
+    self.account(addr, kind, bytes, now, true)
+  }
+
+  // Accounts for a message received from a peer. Returns false if it must be dropped instead.
+  pub fn recv(&mut self, addr: Address, kind: &'static str, bytes: u128, now: u128) -> bool {
+    self.account(addr, kind, bytes, now, false)
+  }
+
+  fn account(&mut self, addr: Address, kind: &'static str, bytes: u128, now: u128, sent: bool) -> bool {
+    let second = now / 1000;
+    if !self.window.contains_key(&addr) && self.window.len() >= MAX_TRAFFIC_PEERS {
+      self.make_room(now);
+    }
+    let window = self.window.entry(addr).or_insert((second, 0, 0));
+    if window.0 != second {
+      *window = (second, 0, 0);
+    }
+    let used = if sent { &mut window.1 } else { &mut window.2 };
+    let allowed = self.cap.map(|cap| *used + bytes <= cap).unwrap_or(true);
+    if allowed {
+      *used += bytes;
+    }
+    for traffic in [self.peers.entry(addr).or_default(), self.kinds.entry(kind).or_default()] {
+      if !allowed {
+        traffic.dropped += 1;
+      } else if sent {
+        traffic.sent_bytes += bytes;
+        traffic.sent_count += 1;
+      } else {
+        traffic.recv_bytes += bytes;
+        traffic.recv_count += 1;
+      }
+    }
+    return allowed;
+  }
+
+  // Forgets the peers that exchanged nothing for `PEER_TIMEOUT`. Their traffic still counts on
+  // the totals, which are kept per kind.
+  pub fn evict(&mut self, now: u128) {
+    let since = now.saturating_sub(PEER_TIMEOUT) / 1000;
+    self.window.retain(|_, (second, _, _)| *second >= since);
+    let window = &self.window;
+    self.peers.retain(|addr, _| window.contains_key(addr));
+  }
+
+  // Forgets the inactive peers, or else the least recently active one
+  fn make_room(&mut self, now: u128) {
+    self.evict(now);
+    if self.window.len() >= MAX_TRAFFIC_PEERS {
+      if let Some(oldest) = self.window.iter().min_by_key(|(_, (second, _, _))| *second).map(|(addr, _)| *addr) {
+        self.window.remove(&oldest);
+        self.peers.remove(&oldest);
+      }
+    }
+  }
+
+  pub fn get_peer(&self, addr: &Address) -> Traffic {
+    self.peers.get(addr).copied().unwrap_or_default()
+  }
+
+  pub fn get_peer_count(&self) -> usize {
+    self.peers.len()
+  }
+
+  pub fn get_kinds(&self) -> Vec<(&'static str, Traffic)> {
+    let mut kinds: Vec<_> = self.kinds.iter().map(|(kind, traffic)| (*kind, *traffic)).collect();
+    kinds.sort_by_key(|(kind, _)| *kind);
+    kinds
+  }
+
+  // The traffic of all peers together
+  pub fn get_total(&self) -> Traffic {
+    let mut total = Traffic::default();
+    for traffic in self.kinds.values() {
+      total.sent_bytes += traffic.sent_bytes;
+      total.sent_count += traffic.sent_count;
+      total.recv_bytes += traffic.recv_bytes;
+      total.recv_count += traffic.recv_count;
+      total.dropped += traffic.dropped;
     }
-    let hash = hash_bytes(&data);
-    return Transaction { data, hash };
+    total
   }
+}
 
-  pub fn encode_length(&self) -> (u8, u8) {
-    return encode_length(self.data.len());
+// Chaos
+// -----
+
+impl Chaos {
+  pub fn new() -> Self {
+    Chaos {
+      max_delay: CHAOS_MAX_DELAY,
+      drop_rate: CHAOS_DROP_RATE,
+      disk_fail: CHAOS_DISK_FAIL,
+    }
   }
 
-  pub fn to_statement(&self) -> Option<Statement> {
-    return deserialized_statement(&BitVec::from_bytes(&self.data));
+  // Returns true with a `percent`% chance
+  pub fn roll(&self, percent: u128, rng: &mut impl Rng) -> bool {
+    return rng.gen_range(0 .. 100) < percent;
+  }
+
+  // Picks a random delay for an outgoing message, in ms
+  pub fn delay(&self, rng: &mut impl Rng) -> u128 {
+    return rng.gen_range(0 ..= self.max_delay);
   }
 }
 
-impl PartialEq for Transaction {
-  fn eq(&self, other: &Self) -> bool {
-    self.hash == other.hash
+// Execution
+// ---------
+
+// Runs a block's statements on a runtime, setting the block's time, metadata and hash first.
+// The caller is responsible for ticking the runtime afterwards.
+pub fn execute_block(runtime: &mut Runtime, block: &Block, silent: bool) -> Vec<StatementResult> {
+  execute_block_measured(runtime, block, silent).into_iter().map(|(result, _)| result).collect()
+}
+
+// Like `execute_block`, but also tells what each statement took to run
+pub fn execute_block_measured(runtime: &mut Runtime, block: &Block, silent: bool) -> Vec<(StatementResult, StatementUsage)> {
+  let transactions = extract_transactions(&block.body);
+  let mut statements = Vec::new();
+  for transaction in transactions {
+    if let Some(statement) = transaction.to_statement() {
+      //print_with_timestamp!("- {}", view_statement(&statement));
+      statements.push(statement);
+    }
   }
+  runtime.clear_memo();
+  set_block_env(runtime, block);
+  runtime.run_scheduled(silent);
+  let results = runtime.run_statements_measured(&statements, silent);
+  let used = results.iter().map(|(result, _)| result).filter_map(|result| match result {
+    Ok(StatementInfo::Run { used_mana, .. }) => Some(used_mana),
+    _ => None,
+  }).sum();
+  runtime.update_mana_price(used);
+  return results;
 }
 
-impl Eq for Transaction {}
+// Exposes a block's time, metadata and hash to the statements that run on a runtime
+pub fn set_block_env(runtime: &mut Runtime, block: &Block) {
+  runtime.set_time(block.time >> 8);
+  runtime.set_meta(block.meta >> 8);
+  runtime.set_hax0((block.hash >>   0).low_u128() >> 8);
+  runtime.set_hax1((block.hash >> 120).low_u128() >> 8);
+  runtime.set_rand(next_random(runtime.get_rand(), block.hash));
+}
 
-impl std::hash::Hash for Transaction {
-  fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-    self.hash.hash(state);
+// Hashes a runtime's state, as a U256 like block hashes
+pub fn get_state_hash(runtime: &Runtime) -> U256 {
+  return U256::from_big_endian(&hash_runtime_state(runtime).0);
+}
+
+// Reads the block index: the hashes of the longest chain the node saved, by height from 1. The
+// hash of the block at a height is at `(height - 1) * BLOCK_INDEX_ENTRY`, and its file is named
+// after the height, so any block is found without going over the others.
+pub fn read_block_index(path: &std::path::Path) -> std::io::Result<Vec<U256>> {
+  let data = std::fs::read(path)?;
+  Ok(data.chunks_exact(BLOCK_INDEX_ENTRY as usize).map(U256::from_big_endian).collect())
+}
+
+// Replays blocks on a shadow runtime, comparing the state it reaches with the live state hash
+pub fn replay_blocks(mut shadow: Runtime, blocks: &[Block], live: U256) -> ReplayCheck {
+  let from = shadow.get_tick();
+  for block in blocks {
+    execute_block(&mut shadow, block, true);
+    shadow.tick_scratch();
+  }
+  return ReplayCheck { from, to: shadow.get_tick(), live, replayed: get_state_hash(&shadow) };
+}
+
+// Runs blocks on a shadow runtime, comparing its state after each with the live state hash. Stops
+// at the first divergence, as the blocks after it would diverge too.
+pub fn shadow_blocks(shadow: &mut Runtime, blocks: &[(Block, U256)]) -> Vec<ShadowCheck> {
+  let mut checks = vec![];
+  for (block, live) in blocks {
+    execute_block(shadow, block, true);
+    shadow.tick_scratch();
+    let check = ShadowCheck { height: shadow.get_tick(), block: block.hash, live: *live, shadow: get_state_hash(shadow) };
+    let diverged = check.diverged();
+    checks.push(check);
+    if diverged {
+      break;
+    }
   }
+  return checks;
+}
+
+// Chains a block hash into the randomness beacon. Must run exactly once per block.
+pub fn next_random(last_rand: u128, block_hash: U256) -> u128 {
+  let mut bytes : Vec<u8> = Vec::new();
+  bytes.extend_from_slice(&u128_to_bytes(last_rand));
+  bytes.extend_from_slice(&u256_to_bytes(block_hash));
+  return hash_bytes(&bytes).low_u128() >> 8;
 }
 
 // Mining
 // ------
 
 // Given a target, attempts to mine a block by changing its nonce up to `max_attempts` times
-pub fn try_mine(prev: U256, body: Body, targ: U256, max_attempts: u128) -> Option<Block> {
-  let rand = rand::random::<u128>();
-  let time = get_time();
-  let mut block = new_block(prev, time, rand, body);
+pub fn try_mine(prev: U256, body: Body, targ: U256, time: u128, extra: &[u8], max_attempts: u128, rng: &mut impl Rng) -> Option<Block> {
+  let nonce = rng.gen::<u64>();
+  let mut meta = if extra.is_empty() {
+    ((rng.gen::<u64>() >> 8) as u128) << 64 | nonce as u128
+  } else {
+    block_meta(extra, nonce)
+  };
+  let mut block = new_block(prev, time, meta, body);
   for _i in 0 .. max_attempts {
     if block.hash >= targ {
       return Some(block);
     } else {
-      block.meta = block.meta.wrapping_add(1);
+      // Only the nonce changes, so the extra data stays
+      meta = meta >> 64 << 64 | (meta as u64).wrapping_add(1) as u128;
+      block = new_block(prev, time, meta, block.body);
     }
   }
   return None;
@@ -618,17 +2120,94 @@ impl MinerCommunication {
 }
 
 // Main miner loop: if asked, attempts to mine a block
-pub fn miner_loop(mut miner_communication: MinerCommunication) {
+pub fn miner_loop(mut miner_communication: MinerCommunication, mut rng: NodeRng) {
   loop {
-    if let MinerMessage::Request { prev, body, targ } = miner_communication.read() {
+    if let MinerMessage::Request { prev, body, targ, time, extra, intensity } = miner_communication.read() {
       //print_with_timestamp!("[miner] mining with target: {}", hex::encode(u256_to_bytes(targ)));
-      let mined = try_mine(prev, body, targ, MINE_ATTEMPTS);
+      let start = std::time::Instant::now();
+      let mined = try_mine(prev, body, targ, time, &extra, MINE_ATTEMPTS, &mut rng);
       if let Some(block) = mined {
         //print_with_timestamp!("[miner] mined a block!");
         miner_communication.write(MinerMessage::Answer { block });
       }
+      // Rests long enough to mine only `intensity`% of the time
+      let intensity = intensity.clamp(1, 100) as u32;
+      std::thread::sleep(start.elapsed() * (100 - intensity) / intensity);
+    } else {
+      std::thread::sleep(MINER_IDLE_DELAY);
+    }
+  }
+}
+
+// How the node mines, set at startup
+#[derive(Debug, Clone)]
+pub struct MinerConfig {
+  pub mining: Mining,
+  pub tuning: ThreadTuning,  // scheduling of the miner thread
+  pub payout: Option<u128>,  // name paid by the blocks mined
+  pub extra_data: Vec<u8>,   // extra data of the blocks mined, at most `MAX_EXTRA_DATA` bytes
+}
+
+// Scheduling of a thread: the cores it may run on (any, if empty), and its nice level
+#[derive(Debug, Clone, Default)]
+pub struct ThreadTuning {
+  pub cores: Vec<usize>,
+  pub nice: Option<i32>,
+}
+
+// Applies a scheduling to the calling thread. Lets the miner run in the background without
+// slowing down the node thread's block validation.
+#[cfg(target_os = "linux")]
+pub fn tune_thread(tuning: &ThreadTuning) -> std::io::Result<()> {
+  if !tuning.cores.is_empty() {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &core in &tuning.cores {
+      if core >= libc::CPU_SETSIZE as usize {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("no core {}", core)));
+      }
+      unsafe { libc::CPU_SET(core, &mut set) };
+    }
+    if unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) } != 0 {
+      return Err(std::io::Error::last_os_error());
+    }
+  }
+  if let Some(nice) = tuning.nice {
+    // On Linux, nice levels are per thread
+    let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::id_t;
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, tid, nice) } != 0 {
+      return Err(std::io::Error::last_os_error());
     }
   }
+  return Ok(());
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn tune_thread(tuning: &ThreadTuning) -> std::io::Result<()> {
+  if tuning.cores.is_empty() && tuning.nice.is_none() {
+    return Ok(());
+  }
+  return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "thread tuning is only supported on Linux"));
+}
+
+// Checks the state a previous run saved, before the runtime starts over on top of it. The node
+// rebuilds its state by replaying the blocks, but a damaged state is moved aside first, so nothing
+// is read from it, and it's kept for inspection. Tells whether it was.
+pub fn discard_corrupted_state(path: &PathBuf) -> bool {
+  if !path.join("_keeps_").exists() {
+    return false;
+  }
+  let problem = match check_saved_state(path) {
+    Ok(faults) if faults.is_empty() => return false,
+    Ok(faults) => format!("{} faults. {}", faults.len(), show_heap_fault(&faults[0])),
+    Err(err) => err.to_string(),
+  };
+  let aside = path.with_extension("corrupted");
+  eprintln!("The saved state is damaged ({}). Moving it to {:?}, and replaying the blocks instead.", problem, aside);
+  std::fs::remove_dir_all(&aside).ok();
+  if let Err(err) = std::fs::rename(path, &aside) {
+    panic!("Couldn't move the damaged state aside: {}", err);
+  }
+  return true;
 }
 
 // Node
@@ -638,14 +2217,68 @@ impl Node {
   pub fn new(
     kindelia_path: PathBuf,
     init_peers: &Option<Vec<Address>>,
+    chaos: Option<Chaos>,
+    net: NetConfig,
   ) -> (SyncSender<NodeRequest>, Self) {
     let try_ports = [UDP_PORT, UDP_PORT + 1, UDP_PORT + 2, UDP_PORT + 3];
-    let (socket, port) = udp_init(&try_ports).expect("Couldn't open UDP socket.");
+    let mut sockets = vec![];
+    let mut advertise = vec![];
+    if net.listen.is_empty() {
+      let (socket, _) = udp_init(&try_ports).expect("Couldn't open UDP socket.");
+      sockets.push(socket);
+    }
+    for addr in &net.listen {
+      match udp_bind(*addr) {
+        Ok(socket) => {
+          // Wildcard addresses aren't reachable as such, so they aren't advertised
+          let bound = socket.local_addr().unwrap_or(*addr);
+          if !bound.ip().is_unspecified() {
+            advertise.push(Address::from(bound));
+          }
+          sockets.push(socket);
+        }
+        Err(err) => {
+          eprintln!("Couldn't listen on {}: {}", addr, err);
+        }
+      }
+    }
+    if sockets.is_empty() {
+      panic!("Couldn't open UDP socket.");
+    }
+    advertise.extend(net.advertise.iter().copied());
+    advertise.extend(net.onion);
+    let local = sockets[0].local_addr().expect("Couldn't read UDP socket address.");
+    let port = local.port();
+    // Never falls back to sending directly, since the operator asked to hide the node's address
+    let proxy = net.proxy.map(|proxy| {
+      Socks5Relay::associate(proxy, local).unwrap_or_else(|err| panic!("Couldn't use proxy {}: {}", proxy, err))
+    });
+    let relay = proxy.as_ref().map(|proxy| proxy.relay);
+    let onion = net.tor.map(|tor| OnionConfig {
+      tor,
+      own: net.onion,
+      listen: net.onion.map(|own| SocketAddr::from(([127, 0, 0, 1], own.port()))),
+    });
+    let network = Network::start(sockets, relay, onion, INBOX_CAPACITY, OUTBOX_CAPACITY).expect("Couldn't start networking.");
+    // Discovery announces the node's address, so it's never done through a proxy
+    #[cfg(feature = "mdns")]
+    let mdns = if net.mdns && net.proxy.is_none() && net.connect_only.is_empty() {
+      Mdns::new(port).map_err(|err| eprintln!("Couldn't start mDNS discovery: {}", err)).ok()
+    } else {
+      None
+    };
+    let heaps = kindelia_path.join("state").join("heaps");
+    discard_corrupted_state(&heaps);
+    let runtime = init_runtime(Some(&heaps));
+    let genesis_state = get_state_hash(&runtime);
     let (query_sender, query_receiver) = mpsc::sync_channel(1);
+    let seed = rand::random::<u64>();
     let mut node = Node {
       path       : kindelia_path,
-      socket     : socket,
+      net        : network,
       port       : port,
+      advertise  : advertise,
+      proxy      : proxy,
       block      : u256map_from([(ZERO_HASH(), GENESIS_BLOCK())]),
       pending    : u256map_new(),
       ancestor   : u256map_new(),
@@ -653,16 +2286,62 @@ impl Node {
       children   : u256map_from([(ZERO_HASH(), vec![])]),
       work       : u256map_from([(ZERO_HASH(), u256(0))]),
       height     : u256map_from([(ZERO_HASH(), 0)]),
+      chain      : vec![ZERO_HASH()],
       target     : u256map_from([(ZERO_HASH(), INITIAL_TARGET())]),
       results    : u256map_from([(ZERO_HASH(), vec![])]),
+      usage      : u256map_from([(ZERO_HASH(), vec![])]),
+      bloom      : u256map_new(),
+      state_hash : u256map_from([(ZERO_HASH(), genesis_state)]),
       tip        : ZERO_HASH(),
       pool       : PriorityQueue::new(),
-      peers      : PeersStore::new(),
-      runtime    : init_runtime(None),
+      peers      : PeersStore::new(net.prefer),
+      runtime    : RuntimeHandle::spawn(runtime),
       receiver   : query_receiver,
+      chaos      : chaos,
+      delayed    : vec![],
+      traffic    : TrafficStore::new(net.peer_bandwidth),
+      payout     : None,
+      extra_data : vec![],
+      mining     : Mining { active: false, intensity: 100 },
+      template   : MinerTemplate::default(),
+      forks      : ForkStats::default(),
+      fork_choice: Box::new(MostWork),
+      usage_stats: UsageStats::default(),
+      index      : StatementIndex::default(),
+      expiry     : PoolExpiry::new(POOL_TTL),
+      local      : LocalPool::default(),
+      events     : broadcast::channel(EVENT_BUFFER).0,
+      history    : HistoryQueries::default(),
+      replay     : ReplayVerifier::default(),
+      shadow     : ShadowExecution::default(),
+      precheck   : BlockPrecheck::default(),
+      preexec    : PoolPreexecution::default(),
+      sync       : HeaderSync::default(),
+      progress   : SyncProgress::default(),
+      watchdog   : BlockWatchdog::default(),
+      disk       : DiskMonitor::new(MIN_FREE_SPACE << 20),
+      backups    : None,
+      policies   : vec![],
+      clock      : Arc::new(SystemClock),
+      seed,
+      rng        : NodeRng::seed_from_u64(seed),
+      #[cfg(feature = "mdns")]
+      mdns       : mdns,
     };
 
-    let now = get_time();
+    if let Some(chaos) = chaos {
+      node.runtime.set_disk_faults(chaos.disk_fail, seed);
+    }
+
+    let now = node.clock.now();
+
+    // On a private network, the allowed peers are the initial ones, and the only ones
+    if !net.connect_only.is_empty() {
+      node.peers.allow_only(&net.connect_only);
+      for address in &net.connect_only {
+        node.peers.see_peer(Peer { address: *address, seen_at: now });
+      }
+    }
 
     if let Some(init_peers) = init_peers {
       init_peers.iter().for_each(|address| {
@@ -681,6 +2360,33 @@ impl Node {
     (query_sender, node)
   }
 
+  // Reseeds the node's random choices, e.g. to reproduce a run
+  pub fn set_seed(&mut self, seed: u64) {
+    self.seed = seed;
+    self.rng = NodeRng::seed_from_u64(seed);
+    if let Some(chaos) = self.chaos {
+      self.runtime.set_disk_faults(chaos.disk_fail, seed);
+    }
+  }
+
+  // Target of a block at `height` after `phash`, mined at `time`, where the parent's target is
+  // `last_target`. It changes on the first block of each period, after how long the last period
+  // took to complete. Ancestors are found among the included blocks and the synced headers.
+  fn get_next_target(&self, phash: U256, height: u128, time: u128, last_target: U256) -> U256 {
+    if height > BLOCKS_PER_PERIOD && height % BLOCKS_PER_PERIOD == 1 {
+      // Finds the checkpoint hash (hash of the first block of the last period)
+      let mut checkpoint_hash = phash;
+      for _ in 0 .. BLOCKS_PER_PERIOD - 1 {
+        checkpoint_hash = self.get_header(&checkpoint_hash).expect("ancestor").prev;
+      }
+      // Computes how much time the last period took to complete
+      let period_time = time - self.get_header(&checkpoint_hash).expect("ancestor").time;
+      // Computes the target of this period
+      return compute_period_target(last_target, period_time);
+    }
+    return last_target;
+  }
+
   // Registers a block on the node's database. This performs several actions:
   // - If this block is too far into the future, ignore it.
   // - If this block's parent isn't available:
@@ -702,19 +2408,19 @@ impl Node {
       let btime = block.time; // the block timestamp
       //print_with_timestamp!("- add block time={}", btime);
       // If block is too far into the future, ignore it
-      if btime >= get_time() + DELAY_TOLERANCE {
+      if btime >= self.clock.now() + DELAY_TOLERANCE {
         //print_with_timestamp!("# new block: too late");
         continue;
       }
       let bhash = block.hash; // hash of the block
       // If we already registered this block, ignore it
-      if self.block.get(&bhash).is_some() {
+      if self.block.contains_key(&bhash) {
         //print_with_timestamp!("# new block: already in");
         continue;
       }
       let phash = block.prev; // hash of the previous block
       // If previous block is available, add the block to the chain
-      if self.block.get(&phash).is_some() {
+      if self.block.contains_key(&phash) {
         //print_with_timestamp!("- previous available");
         let work = get_hash_work(bhash); // block work score
         self.block.insert(bhash, block.clone()); // inserts the block
@@ -723,6 +2429,7 @@ impl Node {
         self.target.insert(bhash, u256(0)); // inits the target attr
         self.children.insert(bhash, vec![]); // inits the children attrs
         self.ancestor.remove(&bhash); // remove it from the ancestor jump table
+        self.children.entry(phash).or_insert_with(Vec::new).push(bhash); // registers it as a child of its parent
         // Checks if this block PoW hits the target
         let has_enough_work = bhash >= self.target[&phash];
         // Checks if this block's timestamp is larger than its parent's timestamp
@@ -733,34 +2440,24 @@ impl Node {
           //print_with_timestamp!("# new_block: enough work & advances_time");
           self.work.insert(bhash, self.work[&phash] + work); // sets this block accumulated work
           self.height.insert(bhash, self.height[&phash] + 1); // sets this block accumulated height
-          // If this block starts a new period, computes the new target
-          if self.height[&bhash] > 0 && self.height[&bhash] > BLOCKS_PER_PERIOD && self.height[&bhash] % BLOCKS_PER_PERIOD == 1 {
-            // Finds the checkpoint hash (hash of the first block of the last period)
-            let mut checkpoint_hash = phash;
-            for _ in 0 .. BLOCKS_PER_PERIOD - 1 {
-              checkpoint_hash = self.block[&checkpoint_hash].prev;
-            }
-            // Computes how much time the last period took to complete
-            let period_time = btime - self.block[&checkpoint_hash].time;
-            // Computes the target of this period
-            let last_target = self.target[&phash];
-            let next_scaler = 2u128.pow(32) * TIME_PER_PERIOD / period_time;
-            let next_target = compute_next_target(last_target, u256(next_scaler));
-            // Sets the new target
-            self.target.insert(bhash, next_target);
-          // Otherwise, keep the old target
-          } else {
-            self.target.insert(bhash, self.target[&phash]);
-          }
+          let target = self.get_next_target(phash, self.height[&bhash], btime, self.target[&phash]);
+          self.target.insert(bhash, target);
           // Removes this block's transactions from mempool
+          let mut mined_local = false;
           for tx in extract_transactions(&block.body) {
             self.pool.remove(&tx);
+            self.expiry.remove(&tx.hash);
+            mined_local |= self.local.remove(&tx.hash);
+          }
+          if mined_local {
+            self.save_local_transactions();
           }
           // Updates the tip work and block hash
           let old_tip = self.tip;
-          let new_tip = bhash;
-          if self.work[&new_tip] > self.work[&old_tip] {
-            self.tip = bhash;
+          let tree = BlockTree { children: &self.children, work: &self.work };
+          let new_tip = self.fork_choice.choose_tip(&tree, old_tip, bhash);
+          if new_tip != old_tip {
+            self.tip = new_tip;
             //print_with_timestamp!("- hash: {:x}", bhash);
             //print_with_timestamp!("- work: {}", self.work[&new_tip]);
             if true {
@@ -771,6 +2468,7 @@ impl Node {
               //               |         '-> highest common block shared by both timelines
               //               '-----> highest runtime snapshot before block D
               let mut must_compute = Vec::new();
+              let mut disconnected = Vec::new(); // blocks dropped from the old timeline, newest first
               let mut old_bhash = old_tip;
               let mut new_bhash = new_tip;
               // 1. Finds the highest block with same height on both timelines
@@ -780,43 +2478,71 @@ impl Node {
                 new_bhash = self.block[&new_bhash].prev;
               }
               while self.height[&old_bhash] > self.height[&new_bhash] {
+                disconnected.push(old_bhash);
                 old_bhash = self.block[&old_bhash].prev;
               }
               // 2. Finds highest block with same value on both timelines
               //    On the example above, we'd have `D`
               while old_bhash != new_bhash {
                 must_compute.push(new_bhash);
+                disconnected.push(old_bhash);
                 old_bhash = self.block[&old_bhash].prev;
                 new_bhash = self.block[&new_bhash].prev;
               }
-              // 3. Saves overwritten blocks to disk
+              self.forks.see_tip_change(self.height[&old_tip] - self.height[&old_bhash]);
+              let connected: Vec<U256> = must_compute.iter().rev().copied().collect();
+              // 3. Saves overwritten blocks to disk, and indexes them
               for bhash in must_compute.iter().rev() {
                 let file_path = self.get_blocks_path().join(format!("{:0>32x}.kindelia_block.bin", self.height[bhash]));
                 let file_buff = bitvec_to_bytes(&serialized_block(&self.block[bhash]));
-                std::fs::write(file_path, file_buff).expect("Couldn't save block to disk.");
+                if let Err(err) = self.write_file(file_path, file_buff) {
+                  eprintln!("Couldn't save block to disk: {}", err);
+                }
               }
-              // 4. Reverts the runtime to a state older than that block
-              //    On the example above, we'd find `runtime.tick = 1`
-              let mut tick = self.height[&old_bhash];
-              //print_with_timestamp!("- tick: old={} new={}", self.runtime.get_tick(), tick);
-              self.runtime.rollback(tick);
-              // 5. Finds the last block included on the reverted runtime state
-              //    On the example above, we'd find `new_bhash = B`
-              while tick > self.runtime.get_tick() {
-                must_compute.push(new_bhash);
-                new_bhash = self.block[&new_bhash].prev;
-                tick -= 1;
+              self.chain.truncate(self.height[&old_bhash] as usize + 1);
+              self.chain.extend(connected.iter().copied());
+              if let Err(err) = self.save_block_index(self.height[&old_bhash]) {
+                eprintln!("Couldn't save the block index: {}", err);
               }
-              // 6. Computes every block after that on the new timeline
-              //    On the example above, we'd compute `C, D, P, Q, R, S, T`
-              for block in must_compute.iter().rev() {
-                self.compute_block(&self.block[block].clone());
+              // The runtime of a halted node stays at the parent of the block it halted on
+              if self.watchdog.halted.is_none() {
+                // 4. Reverts the runtime to a state older than that block
+                //    On the example above, we'd find `runtime.tick = 1`
+                let mut tick = self.height[&old_bhash];
+                //print_with_timestamp!("- tick: old={} new={}", self.runtime.get_tick(), tick);
+                let reached = self.runtime.rollback(tick);
+                self.index.truncate(tick as u64 + 1);
+                // 5. Finds the last block included on the reverted runtime state
+                //    On the example above, we'd find `new_bhash = B`
+                while tick > reached {
+                  must_compute.push(new_bhash);
+                  new_bhash = self.block[&new_bhash].prev;
+                  tick -= 1;
+                }
+                // 6. Computes every block after that on the new timeline
+                //    On the example above, we'd compute `C, D, P, Q, R, S, T`
+                for block in must_compute.iter().rev() {
+                  self.compute_block(&self.block[block].clone());
+                }
+              }
+              // 7. Returns the dropped statements to the pool, and tells about the reorg
+              if !disconnected.is_empty() {
+                self.notice_reorg(old_tip, new_tip, &disconnected, &connected);
+              }
+              if self.watchdog.halted.is_none() {
+                let event = NodeEvent::Tip {
+                  hash: new_tip.into(),
+                  height: self.height[&new_tip] as u64,
+                  state_hash: self.state_hash[&new_tip].into(),
+                };
+                self.events.send(Arc::new(event)).ok();
               }
             }
           }
         }
-        // Registers this block as a child of its parent
-        self.children.insert(phash, vec![bhash]);
+        if has_enough_work && advances_time {
+          self.forks.see_block(self.height[&bhash], &self.children[&phash], &self.height);
+        }
         // If there were blocks waiting for this one, include them on the next loop
         // This will cause the block to be moved from self.pending to self.block
         if let Some(wait_list) = self.wait_list.get(&bhash) {
@@ -827,31 +2553,231 @@ impl Node {
         }
       // Otherwise, if the previous block isn't available,
       // include this block on .pending, and on its parent's wait_list
-      } else if self.pending.get(&bhash).is_none() {
-        self.pending.insert(bhash, block.clone());
+      } else if let std::collections::hash_map::Entry::Vacant(e) = self.pending.entry(bhash) {
+        e.insert(block.clone());
         self.wait_list.entry(phash).or_insert_with(|| Vec::new()).push(bhash);
       }
     }
   }
 
+  // Why the local policies refuse to relay and mine a statement, if any does (see `policy.rs`)
+  pub fn check_policies(&self, statement: &Statement) -> Result<(), String> {
+    self.policies.iter().try_for_each(|policy| policy.check(statement))
+  }
+
+  // Puts the statements of the dropped blocks that the new timeline lacks back on the pool, and
+  // sends a reorg event to subscribers, so they can undo what they saw of the dropped blocks
+  fn notice_reorg(&mut self, old_tip: U256, new_tip: U256, disconnected: &[U256], connected: &[U256]) {
+    let kept: HashSet<U256> =
+      connected.iter().flat_map(|bhash| extract_transactions(&self.block[bhash].body)).map(|tx| tx.hash).collect();
+    let height = self.height[&new_tip];
+    let mut returned = vec![];
+    for bhash in disconnected {
+      for tx in extract_transactions(&self.block[bhash].body) {
+        if kept.contains(&tx.hash) || self.pool.get(&tx).is_some() {
+          continue;
+        }
+        if let Some(statement) = tx.to_statement().filter(|statement| self.check_policies(statement).is_ok()) {
+          self.expiry.add(tx.hash, height, None);
+          self.pool.push(tx.clone(), tx.priority());
+          returned.push(statement);
+        }
+      }
+    }
+    let fork_height = self.height[&old_tip] - disconnected.len() as u128;
+    eprintln!(
+      "Reorg at height {}: dropped {} blocks, added {}, returned {} statements to the pool.",
+      fork_height, disconnected.len(), connected.len(), returned.len()
+    );
+    let event = NodeEvent::Reorg {
+      old_tip: old_tip.into(),
+      new_tip: new_tip.into(),
+      fork_height: fork_height as u64,
+      disconnected: disconnected.iter().map(|bhash| (*bhash).into()).collect(),
+      connected: connected.iter().map(|bhash| (*bhash).into()).collect(),
+      returned,
+    };
+    // Fails only if nobody is listening
+    self.events.send(Arc::new(event)).ok();
+  }
+
   pub fn compute_block(&mut self, block: &Block) {
     //print_with_timestamp!("Computing block...");
     //print_with_timestamp!("==================");
-    let transactions = extract_transactions(&block.body);
-    let mut statements = Vec::new();
-    for transaction in transactions {
-      if let Some(statement) = transaction.to_statement() {
-        //print_with_timestamp!("- {}", view_statement(&statement));
-        statements.push(statement);
-      }
-    }
-    self.runtime.set_time(block.time >> 8);
-    self.runtime.set_meta(block.meta >> 8);
-    self.runtime.set_hax0((block.hash >>   0).low_u128() >> 8);
-    self.runtime.set_hax1((block.hash >> 120).low_u128() >> 8);
-    let result = self.runtime.run_statements(&statements, false);
+    if self.watchdog.halted.is_some() {
+      return;
+    }
+    let start = std::time::Instant::now();
+    let run = match self.watchdog.budget {
+      Some(budget) => self.runtime.run_block_within(block, budget),
+      None => Ok(self.runtime.run_block(block)),
+    };
+    let (result, usage, state_hash, emitted) = match run {
+      Ok(run) => run,
+      Err(reached) => {
+        self.halt_on_timeout(block, reached, start.elapsed());
+        return;
+      }
+    };
+    let height = self.height[&block.hash] as u64;
+    let statements: Vec<Statement> = extract_transactions(&block.body).iter().filter_map(Transaction::to_statement).collect();
+    self.bloom.insert(block.hash, BlockBloom::new(&statements, &emitted));
+    self.notice_terms(block, height, &result, emitted);
     self.results.insert(block.hash, result);
-    self.runtime.tick();
+    self.state_hash.insert(block.hash, state_hash);
+    for (position, (statement, usage)) in statements.iter().zip(&usage).enumerate() {
+      if usage.time >= SLOW_STATEMENT_TIME {
+        let funs = StatementMeta::new(statement).funs.into_iter().map(u128_to_name).collect::<Vec<_>>().join(", ");
+        eprintln!(
+          "Slow statement at height {}, position {}: {} ms, {} rewrites, {} mana, {} words ({}).",
+          height, position, usage.time.as_millis(), usage.rwts, usage.mana, usage.size, funs
+        );
+      }
+    }
+    self.usage_stats.see_block(height, &statements, &usage);
+    self.usage.insert(block.hash, usage);
+    self.index.index_block(height, &statements);
+  }
+
+  // Tells subscribers about the terms a block produced, if it produced any and anyone listens
+  fn notice_terms(&self, block: &Block, height: u64, results: &[StatementResult], emitted: Vec<(u128, Term)>) {
+    if self.events.receiver_count() == 0 {
+      return;
+    }
+    let results: Vec<_> = results.iter().enumerate().filter_map(|(position, result)| match result {
+      Ok(StatementInfo::Run { done_term, .. }) => Some((position, done_term.clone())),
+      _ => None,
+    }).collect();
+    if results.is_empty() && emitted.is_empty() {
+      return;
+    }
+    let emitted = emitted.into_iter().map(|(name, event)| (u128_to_name(name), event)).collect();
+    let event = NodeEvent::Terms { hash: block.hash.into(), height, results, emitted };
+    self.events.send(Arc::new(event)).ok();
+  }
+
+  // Halts the node on a block its runtime aborted, bringing the state back to its parent: the
+  // blocks dropped along with it, since the newest snapshot, are run again, with no budget, as
+  // they took less than it before.
+  fn halt_on_timeout(&mut self, block: &Block, reached: u128, elapsed: std::time::Duration) {
+    let height = self.height[&block.hash];
+    let budget = self.watchdog.budget.unwrap_or_default();
+    eprintln!(
+      "ALERT: block {:x} at height {} ran for over {} ms, the execution budget, and was aborted. No more blocks will be computed or mined until the node is restarted with a larger `--block-timeout`.",
+      block.hash, height, budget.as_millis()
+    );
+    let mut dropped = vec![];
+    let mut bhash = block.prev;
+    while self.height[&bhash] > reached {
+      dropped.push(bhash);
+      bhash = self.block[&bhash].prev;
+    }
+    for bhash in dropped.iter().rev() {
+      self.runtime.run_block(&self.block[bhash].clone());
+    }
+    self.watchdog.halted = Some(BlockTimeout { hash: block.hash, height, elapsed });
+    let event = NodeEvent::BlockTimeout {
+      hash: block.hash.into(),
+      height: height as u64,
+      elapsed_ms: elapsed.as_millis() as u64,
+      budget_ms: budget.as_millis() as u64,
+    };
+    self.events.send(Arc::new(event)).ok();
+  }
+
+  // The block the runtime's state is at: the tip, unless the node halted on a block that took too
+  // long to run, in which case it's that block's parent
+  pub fn get_computed_tip(&self) -> U256 {
+    match &self.watchdog.halted {
+      Some(halted) => self.block[&halted.hash].prev,
+      None => self.tip,
+    }
+  }
+
+  // The state right after the block at `height` was computed, to be rebuilt from the newest
+  // retained snapshot at or before it. Fails for heights older than every retained snapshot, unless
+  // the node is an archive node.
+  pub fn get_past_state(&self, height: u128) -> Result<PastState, String> {
+    let tip = self.height[&self.get_computed_tip()];
+    if height > tip {
+      return Err(format!("Height {} is past the tip, at {}.", height, tip));
+    }
+    let oldest = self.runtime.get_snapshot_ticks().into_iter().min().unwrap_or(0);
+    if height < oldest && !self.history.archive {
+      return Err(format!("The state at height {} is older than the oldest one kept, at {}. Ask an archive node.", height, oldest));
+    }
+    let runtime = self.runtime.fork(height);
+    let blocks = self.chain[runtime.get_tick() as usize + 1 ..= height as usize].iter().map(|bhash| self.block[bhash].clone()).collect();
+    return Ok(PastState { runtime, blocks });
+  }
+
+  // Answers a query on a past state from a thread of its own, refusing it while too many run
+  pub fn answer_history_query<T: Send + 'static>(
+    &self,
+    past: Result<PastState, String>,
+    answer: oneshot::Sender<Result<T, String>>,
+    query: impl FnOnce(Runtime) -> T + Send + 'static,
+  ) {
+    let past = past.and_then(|past| {
+      if self.history.running.fetch_add(1, Ordering::Relaxed) >= MAX_HISTORY_QUERIES {
+        self.history.running.fetch_sub(1, Ordering::Relaxed);
+        return Err("Too many queries on past states are running. Try again later.".to_string());
+      }
+      Ok((past, RunningQuery(self.history.running.clone())))
+    });
+    match past {
+      Ok((past, running)) => {
+        // The query stops counting as running before it's answered, so the next one, sent once
+        // this one is answered, is never refused for it
+        thread::spawn(move || {
+          let result = query(past.rebuild());
+          drop(running);
+          answer.send(Ok(result)).ok();
+        });
+      }
+      Err(err) => {
+        answer.send(Err(err)).ok();
+      }
+    }
+  }
+
+  // Finds a statement on the longest chain by its hash, on the index, and executes it again on top
+  // of the state it originally ran on, recording a trace of the IO effects it performed. Answers
+  // None if it isn't on the longest chain.
+  pub fn reexecute_statement(&self, hash: &crypto::Hash, answer: oneshot::Sender<Result<Option<Reexecution>, String>>) {
+    let Some((height, index)) = self.index.locate(hash).filter(|(height, _)| *height > 0) else {
+      answer.send(Ok(None)).ok();
+      return;
+    };
+    let bhash = self.chain[height as usize];
+    let block = self.block[&bhash].clone();
+    let mut statements: Vec<Statement> = extract_transactions(&block.body).iter().filter_map(Transaction::to_statement).collect();
+    self.answer_history_query(self.get_past_state(height as u128 - 1), answer, move |mut runtime| {
+      set_block_env(&mut runtime, &block);
+      runtime.run_scheduled(true);
+      runtime.run_statements(&statements[0 .. index], true);
+      runtime.start_trace();
+      let result = runtime.run_statement_with_hooks(&statements[index], true);
+      let trace = runtime.stop_trace();
+      let statement = statements.swap_remove(index);
+      Some(Reexecution { block: bhash.into(), height, statement, result, trace })
+    });
+  }
+
+  // Runs a block of the longest chain again, on top of the state it ran on, counting the rewrites
+  // it performs by kind. Answers None if the block isn't on the longest chain.
+  pub fn count_block_rewrites(&self, hash: &U256, answer: oneshot::Sender<Result<Option<RewriteStats>, String>>) {
+    let height = self.height.get(hash).copied().unwrap_or(0);
+    if height == 0 || self.get_hash_at(height) != Some(*hash) {
+      answer.send(Ok(None)).ok();
+      return;
+    }
+    let block = self.block[hash].clone();
+    self.answer_history_query(self.get_past_state(height - 1), answer, move |mut runtime| {
+      runtime.start_rewrite_stats();
+      execute_block(&mut runtime, &block, true);
+      Some(runtime.stop_rewrite_stats())
+    });
   }
 
   // Get the current target
@@ -859,32 +2785,36 @@ impl Node {
     self.target[&self.tip]
   }
 
+  // Hashes of the last `num` blocks of the longest chain (all if `None`), oldest first
   pub fn get_longest_chain(&self, num: Option<usize>) -> Vec<U256> {
-    let mut longest = Vec::new();
-    let mut bhash = self.tip;
-    let mut count = 0;
-    while self.block.contains_key(&bhash) && bhash != ZERO_HASH() {
-      let block = self.block.get(&bhash).unwrap();
-      longest.push(bhash);
-      bhash = block.prev;
-      count += 1;
-      if let Some(num) = num {
-        if count >= num {
-          break;
-        }
-      }
+    let start = num.map_or(1, |num| std::cmp::max(self.chain.len().saturating_sub(num), 1));
+    return self.chain[start ..].to_vec();
+  }
+
+  // Hash of the block of the longest chain at a height
+  pub fn get_hash_at(&self, height: u128) -> Option<U256> {
+    if height == 0 {
+      return None;
     }
-    longest.reverse();
-    return longest;
+    self.chain.get(height as usize).copied()
   }
 
+  // Handles the messages received so far. Bounded by the inbox's capacity, so a flood arriving
+  // meanwhile doesn't keep the node here.
   pub fn receive_message(&mut self) {
-    let mut count = 0;
-    for (addr, msg) in udp_recv(&mut self.socket) {
-      //if count < HANDLE_MESSAGE_LIMIT {
+    let now = self.clock.now();
+    for _ in 0 .. INBOX_CAPACITY {
+      let Some(incoming) = self.net.recv() else {
+        break;
+      };
+      let (addr, msg) = (incoming.from, incoming.message);
+      if !self.peers.is_allowed(&addr) {
+        continue;
+      }
+      if !self.traffic.recv(addr, msg.kind(), incoming.size as u128, now) {
+        continue;
+      }
       self.handle_message(addr, &msg);
-      count = count + 1;
-      //}
     }
   }
 
@@ -900,24 +2830,327 @@ impl Node {
     let height = self.height.get(hash).expect("Missing block height.");
     let height: u64 = (*height).try_into().expect("Block height is too big.");
     let results = self.results.get(hash).map(|r| r.clone());
+    let usage = self.usage.get(hash).cloned();
     let transactions = extract_transactions(&block.body);
     let content = transactions.iter().filter_map(Transaction::to_statement).collect();
     let info = BlockInfo {
       block: block.into(),
-      hash: *hash,
+      hash: (*hash).into(),
       height,
       content,
       results,
+      usage,
+      payout: extract_payout(&block.body).map(u128_to_name),
+      extra_data: extract_extra_data(block.meta).map(hex::encode),
+      state_hash: self.state_hash.get(hash).map(|state_hash| (*state_hash).into()),
+      bloom: self.bloom.get(hash).cloned(),
     };
     Some(info)
   }
 
-  pub fn get_func_info(&self, fid: u128) -> Option<FuncInfo> {
-    let comp_func = self.runtime.read_file(fid)?;
-    let func = comp_func.func;
-    Some(FuncInfo { func })
+  // Lists the blooms of the computed blocks of the longest chain in a range of heights, only the
+  // ones that may touch a name and emit an event, if given
+  pub fn get_blooms(&self, range: (u64, u64), name: Option<u128>, event: Option<u128>) -> Vec<api::BloomEntry> {
+    let (from, to) = range;
+    let mut entries = vec![];
+    for height in from ..= to {
+      let Some(hash) = self.get_hash_at(height as u128) else { continue };
+      let Some(bloom) = self.bloom.get(&hash) else { continue };
+      if name.map_or(true, |name| bloom.names.contains(name)) && event.map_or(true, |event| bloom.events.contains(event)) {
+        entries.push(api::BloomEntry { height, hash: hash.into(), bloom: bloom.clone() });
+      }
+    }
+    return entries;
+  }
+
+  // Counts valid blocks left out of the longest chain, and the forks that caused them
+  pub fn get_fork_summary(&self) -> ForkSummary {
+    let valid = self.height.values().filter(|height| **height > 0).count() as u64;
+    let stale = valid - self.height[&self.tip] as u64;
+    ForkSummary {
+      stale_blocks: stale,
+      stale_rate: if valid > 0 { stale as f64 / valid as f64 } else { 0.0 },
+      competing_heights: self.forks.competing.len() as u64,
+      reorgs: self.forks.reorgs,
+      deepest_reorg: self.forks.deepest_reorg as u64,
+      fork_choice: self.fork_choice.name(),
+    }
+  }
+
+  pub fn get_shadow_summary(&self) -> Option<api::ShadowSummary> {
+    let candidate = self.shadow.candidate?;
+    Some(api::ShadowSummary {
+      candidate: candidate.name().to_string(),
+      blocks: self.shadow.blocks,
+      divergences: self.shadow.divergences,
+      height: self.shadow.height.map(|height| height as u64),
+      last_divergence: self.shadow.diverged.as_ref().map(|check| check.height as u64),
+    })
+  }
+
+  pub fn get_replay_summary(&self) -> Option<ReplaySummary> {
+    if !self.replay.enabled {
+      return None;
+    }
+    Some(ReplaySummary {
+      checks: self.replay.checks,
+      divergences: self.replay.divergences,
+      last_from: self.replay.last.as_ref().map(|check| check.from as u64),
+      last_to: self.replay.last.as_ref().map(|check| check.to as u64),
+    })
+  }
+
+  pub fn get_precheck_summary(&self) -> PrecheckSummary {
+    PrecheckSummary {
+      checks: self.precheck.checks,
+      excluded: self.precheck.excluded,
+      failures: self.precheck.failures.iter().map(|failure| PrecheckFailureInfo {
+        transaction: failure.transaction.into(),
+        height: failure.height as u64,
+        err: failure.err.clone(),
+      }).collect(),
+      preexecutions: self.preexec.runs,
+      groups: self.preexec.groups,
+    }
+  }
+
+  // How far the node is from its peers' chain, and how long it should take to get there
+  pub fn get_sync_summary(&self) -> SyncSummary {
+    let tip = self.height[&self.tip];
+    let headers = self.sync.best.map_or(tip, |best| self.sync.height[&best]);
+    // Bodies downloaded past the tip wait for their ancestors, so the newest one counts
+    let bodies = match self.get_synced_chain().iter().rposition(|bhash| self.pending.contains_key(bhash)) {
+      Some(index) => tip + index as u128 + 1,
+      None => tip,
+    };
+    let executed = self.height[&self.get_computed_tip()];
+    let peers_best = self.progress.peer_tips.values()
+      .filter_map(|bhash| self.height.get(bhash).or_else(|| self.sync.height.get(bhash)))
+      .max()
+      .copied();
+    let goal = std::cmp::max(headers, peers_best.unwrap_or(0));
+    let blocks_per_sec = self.progress.rate();
+    let eta_secs = if executed >= goal { Some(0) } else { blocks_per_sec.map(|rate| ((goal - executed) as f64 / rate).ceil() as u64) };
+    let answered = |bhash: &&U256| self.block.contains_key(*bhash) || self.pending.contains_key(*bhash);
+    SyncSummary {
+      syncing: self.sync.is_syncing(),
+      headers: headers as u64,
+      bodies: bodies as u64,
+      executed: executed as u64,
+      peers_best: peers_best.map(|height| height as u64),
+      blocks_per_sec,
+      eta_secs,
+      downloaded: self.pending.len() as u64,
+      requested: self.sync.requests.keys().filter(|bhash| !answered(bhash)).count() as u64,
+    }
+  }
+
+  // Notes the executed height, to estimate the sync speed, and forgets the tips of gone peers
+  fn sample_sync_progress(&mut self) {
+    let executed = self.height[&self.get_computed_tip()];
+    self.progress.sample(self.clock.now(), executed);
+    let peers = &self.peers;
+    self.progress.peer_tips.retain(|addr, _| peers.is_active(addr));
+  }
+
+  pub fn get_disk_summary(&self) -> DiskSummary {
+    DiskSummary { free: self.disk.free, min_free: self.disk.min_free, low: self.disk.low }
+  }
+
+  // Reads the free space of the data directory, warning when it goes below the threshold or back
+  pub fn check_disk(&mut self) {
+    let free = match free_space(&self.path) {
+      Ok(free) => Some(free),
+      Err(err) => {
+        if self.disk.free.is_some() {
+          eprintln!("Couldn't read the free space of {:?}: {}", self.path, err);
+        }
+        None
+      }
+    };
+    if self.disk.see(free) {
+      let free = free.unwrap_or(0) >> 20;
+      if self.disk.low {
+        eprintln!("Warning: only {} MiB free on {:?}. Mining and taking blocks are paused until there's {} MiB.", free, self.path, self.disk.min_free >> 20);
+      } else {
+        eprintln!("{} MiB free on {:?}. Mining and taking blocks again.", free, self.path);
+      }
+    }
+  }
+
+  // Backs up the data directory when the executed chain reaches the next multiple of the interval.
+  // Not while syncing, when that would happen every few seconds, nor while the disk is low.
+  pub fn check_backup(&mut self) {
+    if self.sync.is_syncing() || self.disk.low {
+      return;
+    }
+    let height = self.height[&self.get_computed_tip()];
+    let Some(backups) = &mut self.backups else { return };
+    if !backups.due(height) {
+      return;
+    }
+    match backups.run(&self.path, height) {
+      Ok(file) => eprintln!("Backed up the chain at height {} to {:?}.", height, file),
+      Err(err) => eprintln!("Couldn't back up the chain at height {}: {}", height, err),
+    }
+  }
+
+  pub fn get_watchdog_summary(&self) -> Option<WatchdogSummary> {
+    let budget = self.watchdog.budget?;
+    let halted = self.watchdog.halted.as_ref();
+    Some(WatchdogSummary {
+      budget_ms: budget.as_millis() as u64,
+      halted_block: halted.map(|timeout| timeout.hash.into()),
+      halted_height: halted.map(|timeout| timeout.height as u64),
+      halted_elapsed_ms: halted.map(|timeout| timeout.elapsed.as_millis() as u64),
+    })
+  }
+
+  // Lists the latest `count` heights with competing blocks, and the one the chain kept
+  pub fn get_forks(&self, count: usize) -> Vec<ForkInfo> {
+    self.forks.competing.iter().rev().take(count).map(|(height, blocks)| {
+      let kept = self.get_hash_at(*height).filter(|hash| blocks.contains(hash));
+      ForkInfo {
+        height: *height as u64,
+        blocks: blocks.iter().map(|hash| (*hash).into()).collect(),
+        kept: kept.map(|hash| hash.into()),
+      }
+    }).collect()
+  }
+
+  // Counts the blocks of the longest chain paying each name
+  pub fn get_miners(&self) -> Vec<MinerInfo> {
+    let mut miners: HashMap<u128, MinerInfo> = HashMap::new();
+    for hash in &self.chain[1 ..] {
+      let Some(payout) = extract_payout(&self.block[hash].body) else { continue };
+      let height = self.height[hash] as u64;
+      let miner = miners.entry(payout).or_insert_with(|| MinerInfo { name: u128_to_name(payout), blocks: 0, last_height: 0 });
+      miner.blocks += 1;
+      miner.last_height = std::cmp::max(miner.last_height, height);
+    }
+    let mut miners: Vec<MinerInfo> = miners.into_values().collect();
+    miners.sort_by(|a, b| b.blocks.cmp(&a.blocks).then(a.name.cmp(&b.name)));
+    return miners;
+  }
+
+  // Lists the consensus rule changes, with the signals of the latest blocks of the chain for each
+  pub fn get_upgrades(&self) -> Vec<api::UpgradeInfo> {
+    let activations = self.runtime.get_status().activations;
+    let next = self.height[&self.tip] + 1;
+    let window = &self.chain[1 ..][self.chain.len().saturating_sub(1 + upgrade::SIGNAL_WINDOW) ..];
+    let extras: Vec<Vec<u8>> = window.iter().filter_map(|hash| extract_extra_data(self.block[hash].meta)).collect();
+    upgrade::UPGRADES.iter().map(|upgrade| api::UpgradeInfo {
+      feature: upgrade.name.to_string(),
+      about: upgrade.about.to_string(),
+      bit: upgrade.bit,
+      height: activations.get(upgrade.feature) as u64,
+      active: activations.is_active(upgrade.feature, next),
+      signals: extras.iter().filter(|extra| upgrade::signals(extra, upgrade.feature)).count() as u64,
+      window: window.len() as u64,
+    }).collect()
+  }
+
+  // Estimates the hash rate of the network from the difficulties and times of the last blocks of
+  // the chain, and predicts the difficulty of the next period, as if its remaining blocks came at
+  // the same pace
+  pub fn get_mining_stats(&self) -> api::MiningStats {
+    let tip = self.tip;
+    let height = self.height[&tip];
+    let difficulty = target_to_difficulty(self.target[&tip]);
+    // Goes back over the window, without passing the first block, as genesis has no real time.
+    // Blocks are mined at the target of their parents.
+    let mut start = tip;
+    let mut work = u256(0);
+    let mut window = 0;
+    while window < HASH_RATE_WINDOW && self.height[&start] > 1 {
+      start = self.block[&start].prev;
+      work += target_to_difficulty(self.target[&start]);
+      window += 1;
+    }
+    let elapsed = self.block[&tip].time - self.block[&start].time;
+    let (block_time, hash_rate) = if window > 0 && elapsed > 0 {
+      (std::cmp::max(elapsed / window, 1), work * u256(1000) / u256(elapsed))
+    } else {
+      (TIME_PER_BLOCK, difficulty * u256(1000) / u256(TIME_PER_BLOCK))
+    };
+    // The target changes on the next block starting a period, after the time since the first block
+    // of the period before it (see `get_next_target`)
+    let mut next = height / BLOCKS_PER_PERIOD * BLOCKS_PER_PERIOD + 1;
+    if next <= height {
+      next += BLOCKS_PER_PERIOD;
+    }
+    if next <= BLOCKS_PER_PERIOD {
+      next += BLOCKS_PER_PERIOD;
+    }
+    let first = next - BLOCKS_PER_PERIOD;
+    let mut known = 0;
+    if height >= first {
+      let mut checkpoint = tip;
+      for _ in first .. height {
+        checkpoint = self.block[&checkpoint].prev;
+      }
+      known = self.block[&tip].time - self.block[&checkpoint].time;
+    }
+    let period_time = known + (next - std::cmp::max(height, first)) * block_time;
+    let next_target = compute_period_target(self.target[&tip], period_time);
+    api::MiningStats {
+      height: height as u64,
+      difficulty: difficulty.low_u128(),
+      window: window as u64,
+      block_time,
+      hash_rate: hash_rate.low_u128(),
+      retarget: api::Retarget {
+        height: next as u64,
+        blocks_left: (next - height) as u64,
+        period_time,
+        difficulty: target_to_difficulty(next_target).low_u128(),
+      },
+    }
+  }
+
+  // Lists the statements waiting on the pool, by hash, only the ones a subject signed if given
+  pub fn get_pool(&self, signer: Option<u128>) -> Vec<api::PoolEntry> {
+    let mut txs: Vec<&Transaction> = self.pool.iter().map(|(tx, _)| tx).collect();
+    txs.sort_by_key(|tx| tx.hash);
+    let mut entries = vec![];
+    for tx in txs {
+      let Some(statement) = tx.to_statement() else { continue };
+      let Some(status) = self.expiry.status(&tx.hash) else { continue };
+      let subject = StatementMeta::with_signer(&statement).signer;
+      if signer.is_some() && signer != subject {
+        continue;
+      }
+      entries.push(api::PoolEntry {
+        hash: U256::from_big_endian(&hash_statement(&statement).0).into(),
+        transaction: tx.hash.into(),
+        statement,
+        signer: subject.map(|subject| crypto::Name(subject).show()),
+        status,
+        local: self.local.get(&tx.hash).is_some(),
+        estimate: self.get_estimate(&tx.hash),
+      });
+    }
+    return entries;
+  }
+
+  // Finds the statements of the longest chain matching a filter, oldest first
+  pub fn get_statements(&self, filter: Option<&Filter>, limit: usize) -> Vec<StatementEntry> {
+    let locs = match filter {
+      Some(filter) => self.index.query(filter, limit),
+      None => self.index.query(&Filter::Height(CmpOp::Ge, 0), limit),
+    };
+    let mut entries = vec![];
+    for (height, position) in locs {
+      let bhash = self.chain[height as usize];
+      let transactions = extract_transactions(&self.block[&bhash].body);
+      let Some(statement) = transactions.iter().filter_map(Transaction::to_statement).nth(position) else { continue };
+      let hash = U256::from_big_endian(&hash_statement(&statement).0).into();
+      entries.push(StatementEntry { height, block: bhash.into(), position, hash, statement });
+    }
+    return entries;
   }
 
+
   pub fn handle_request(&mut self, request: NodeRequest) {
     // TODO: handle unwraps
     match request {
@@ -926,6 +3159,59 @@ impl Node {
         let stats = api::Stats { tick };
         answer.send(stats).unwrap();
       }
+      NodeRequest::GetStateHash { tx: answer } => {
+        let tip = self.get_computed_tip();
+        let info = api::StateHash {
+          height: self.height[&tip] as u64,
+          block: tip.into(),
+          hash: self.state_hash[&tip].into(),
+        };
+        answer.send(info).unwrap();
+      }
+      NodeRequest::GetPeers { tx: answer } => {
+        let mut peers: Vec<api::PeerInfo> = self.peers.get_all_seen().iter().map(|peer| api::PeerInfo {
+          address: peer.address.to_string(),
+          seen_at: peer.seen_at,
+          active: self.peers.is_active(&peer.address),
+          traffic: self.traffic.get_peer(&peer.address),
+        }).collect();
+        peers.sort_by(|a, b| a.address.cmp(&b.address));
+        answer.send(peers).unwrap();
+      }
+      NodeRequest::GetMetrics { tx: answer } => {
+        let metrics = api::Metrics {
+          tick: self.runtime.get_tick(),
+          active_peers: self.peers.get_all_active().len(),
+          peer_bandwidth: self.traffic.cap,
+          traffic: self.traffic.get_total(),
+          traffic_by_kind: self.traffic.get_kinds().into_iter().map(|(kind, traffic)| (kind.to_string(), traffic)).collect(),
+          forks: self.get_fork_summary(),
+          replay: self.get_replay_summary(),
+          shadow: self.get_shadow_summary(),
+          watchdog: self.get_watchdog_summary(),
+          disk: self.get_disk_summary(),
+          precheck: self.get_precheck_summary(),
+          sync: self.get_sync_summary(),
+          net: self.net.stats(),
+          usage: self.usage_stats.clone(),
+        };
+        answer.send(metrics).unwrap();
+      }
+      NodeRequest::GetSync { tx: answer } => {
+        answer.send(self.get_sync_summary()).unwrap();
+      }
+      NodeRequest::GetManaPrice { tx: answer } => {
+        let status = self.runtime.get_status();
+        let used = get_results_mana(&self.results[&self.get_computed_tip()]);
+        let info = api::ManaPrice {
+          base_price: status.mana_price,
+          used,
+          target: BLOCK_MANA_TARGET,
+          limit: BLOCK_MANA_LIMIT,
+          burned: status.burned,
+        };
+        answer.send(info).unwrap();
+      }
       NodeRequest::GetBlocks { range, tx: answer } => {
         let (start, end) = range;
         debug_assert!(start <= end);
@@ -938,33 +3224,96 @@ impl Node {
           ).collect();
         answer.send(infos).unwrap();
       },
+      NodeRequest::GetPoolStatus { hash, tx: answer } => {
+        let info = self.expiry.status(&hash).map(|status| {
+          let local = self.local.get(&hash);
+          api::PoolInfo {
+            status,
+            local: local.is_some(),
+            broadcasts: local.map(|local| local.broadcasts).unwrap_or(0),
+            last_broadcast: local.map(|local| local.last_broadcast).filter(|time| *time > 0),
+          }
+        });
+        answer.send(info).unwrap();
+      },
+      NodeRequest::GetPool { signer, tx: answer } => {
+        answer.send(self.get_pool(signer)).unwrap();
+      },
+      NodeRequest::GetForks { count, tx: answer } => {
+        answer.send(self.get_forks(count)).unwrap();
+      },
+      NodeRequest::GetMining { tx: answer } => {
+        answer.send(self.mining).unwrap();
+      },
+      NodeRequest::SetMining { active, intensity, tx: answer } => {
+        if let Some(active) = active {
+          self.mining.active = active;
+        }
+        if let Some(intensity) = intensity {
+          self.mining.intensity = intensity.clamp(1, 100);
+        }
+        answer.send(self.mining).unwrap();
+      },
+      NodeRequest::GetMiners { tx: answer } => {
+        answer.send(self.get_miners()).unwrap();
+      }
+      NodeRequest::GetUpgrades { tx: answer } => {
+        answer.send(self.get_upgrades()).unwrap();
+      },
+      NodeRequest::GetMiningStats { tx: answer } => {
+        answer.send(self.get_mining_stats()).unwrap();
+      },
       NodeRequest::GetBlock { hash, tx: answer } => {
-        // TODO: actual indexing
         let info = self.get_block_info(&hash);
         answer.send(info).unwrap();
       },
-      NodeRequest::GetFunctions { tx } => {
-        let mut funcs: HashSet<u128> = HashSet::new();
-        self.runtime.reduce_with(&mut funcs, |acc, heap| {
-          for func in heap.disk.links.keys() {
-            acc.insert(*func);
-          }
-        });
-        tx.send(funcs).unwrap();
+      NodeRequest::GetBlockAt { height, tx: answer } => {
+        let info = self.get_hash_at(height as u128).and_then(|hash| self.get_block_info(&hash));
+        answer.send(info).unwrap();
+      },
+      NodeRequest::GetFunction { name, at, tx: answer } =>  {
+        let info = move |func: Option<CompFunc>| func.map(|func| FuncInfo::new(name, func.func));
+        match at {
+          Some(height) => self.answer_history_query(self.get_past_state(height as u128), answer, move |runtime| info(runtime.read_file(name))),
+          None => answer.send(Ok(info(self.runtime.read_func(name)))).unwrap(),
+        }
+      },
+      NodeRequest::GetState { name, at, tx: answer } => {
+        match at {
+          Some(height) => self.answer_history_query(self.get_past_state(height as u128), answer, move |mut runtime| runtime.read_disk_as_term(name)),
+          None => answer.send(Ok(self.runtime.read_state(name))).unwrap(),
+        }
+      },
+      NodeRequest::GetNames { kind, tx: answer } => {
+        let names = self.runtime.list_names(kind).into_iter().map(NameEntry::from).collect();
+        answer.send(names).unwrap();
+      },
+      NodeRequest::GetBlooms { range, name, event, tx: answer } => {
+        answer.send(self.get_blooms(range, name, event)).unwrap();
+      },
+      NodeRequest::GetStatements { filter, limit, tx: answer } => {
+        answer.send(self.get_statements(filter.as_ref(), limit)).unwrap();
+      },
+      NodeRequest::Reexecute { hash, tx: answer } => {
+        self.reexecute_statement(&hash, answer);
       },
-      NodeRequest::GetFunction { name, tx: answer } =>  {
-        let info = self.get_func_info(name);
-        answer.send(info).unwrap();
+      NodeRequest::CountRewrites { hash, tx: answer } => {
+        self.count_block_rewrites(&hash, answer);
       },
-      NodeRequest::GetState { name, tx: answer } => {
-        let state = self.runtime.read_disk_as_term(name);
-        answer.send(state).unwrap();
+      NodeRequest::Compact { tx: answer } => {
+        let moved = self.runtime.compact();
+        let tick = self.runtime.get_tick();
+        eprintln!("Compacted the heap at tick {}: moved {} words.", tick, moved);
+        answer.send(api::Compaction { tick, moved }).unwrap();
       },
       NodeRequest::TestCode { code, tx: answer } => {
-        let result = self.runtime.test_statements_from_code(&code);
+        let result = match hvm::read_statements(&code) {
+          Ok((_, statements)) => self.runtime.test_statements(statements),
+          Err(err) => vec![Err(StatementErr { err: err.erro })],
+        };
         answer.send(result).unwrap();
       },
-      NodeRequest::PostCode { code, tx: answer } => {
+      NodeRequest::PostCode { code, expires, tx: answer } => {
         let statements = 
           hvm::read_statements(&code)
             .map_err(|err| err.erro)
@@ -975,15 +3324,23 @@ impl Node {
             Err(err)
           }
           Ok(statements) => {
-            statements
+            let limits = self.runtime.get_status().statement_limits;
+            let results = statements
               .iter()
-              .map(|s| Transaction::new(bitvec_to_bytes(&serialized_statement(s))))
-              .into_iter()
-              .for_each(|t| {
-                let hash = t.hash.low_u64();
-                self.pool.push(t, hash);
-              });
-            Ok(())
+              .map(|s| {
+                check_statement(s, limits).map_err(PostRejection::Invalid)?;
+                self.check_policies(s).map_err(PostRejection::Policy)?;
+                self.runtime.precheck_signature(s.clone());
+                let t = Transaction::new(bitvec_to_bytes(&serialized_statement(s)));
+                let priority = t.priority();
+                self.expiry.add(t.hash, self.height[&self.tip], expires);
+                self.local.add(t.clone());
+                self.pool.push(t, priority);
+                Ok(())
+              })
+              .collect();
+            self.save_local_transactions();
+            Ok(results)
           }
         };
       
@@ -992,7 +3349,7 @@ impl Node {
       NodeRequest::Run { hex, tx: answer } => {
         if let Ok(bytes) = hex::decode(hex) {
           if let Some(statement) = deserialized_statement(&bytes_to_bitvec(&bytes)) {
-            let result = self.runtime.test_statements(&[statement])[0].clone();
+            let result = self.runtime.test_statements(vec![statement])[0].clone();
             answer.send(result).unwrap();
             return;
           }
@@ -1006,10 +3363,21 @@ impl Node {
   // FIXME: instead of sharing random peers, share recently active peers
   pub fn send_blocks_to(&mut self, addrs: Vec<Address>, gossip: bool, blocks: Vec<Block>, share_peers: u128) {
     //print_with_timestamp!("- sending block: {:?}", block);
-    let peers = self.peers.get_random_active(share_peers);
-    let msg = Message::NoticeTheseBlocks { gossip, blocks, peers };
-    // print_with_timestamp!("- sending block: {:?}", msg);
-    udp_send(&mut self.socket, addrs, &msg);
+    let now = self.clock.now();
+    let mut peers: Vec<Peer> = self.advertise.iter().map(|address| Peer { address: *address, seen_at: now }).collect();
+    peers.extend(self.peers.get_random_active(share_peers, &mut self.rng));
+    peers.retain(|peer| peer.address.is_shareable());
+    // Nodes reached over UDP may not read onion addresses, so they're only shared over Tor
+    let (onion, addrs): (Vec<Address>, Vec<Address>) = addrs.into_iter().partition(|addr| addr.family() == AddressFamily::Onion);
+    if !onion.is_empty() {
+      self.send(onion, &Message::NoticeTheseBlocks { gossip, blocks: blocks.clone(), peers: peers.clone() });
+    }
+    if !addrs.is_empty() {
+      peers.retain(|peer| peer.address.family() != AddressFamily::Onion);
+      let msg = Message::NoticeTheseBlocks { gossip, blocks, peers };
+      // print_with_timestamp!("- sending block: {:?}", msg);
+      self.send(addrs, &msg);
+    }
   }
 
   // Returns the block inclusion state
@@ -1053,50 +3421,318 @@ impl Node {
   // Requests the most recent missing ancestor
   pub fn request_missing_ancestor(&mut self, addr: Address, bhash: &U256) {
     if let Some(missing_ancestor) = self.find_missing_ancestor(bhash) {
-      udp_send(&mut self.socket, vec![addr], &Message::GiveMeThatBlock { bhash: missing_ancestor })
+      self.send(vec![addr], &Message::GiveMeThatBlock { bhash: missing_ancestor })
+    }
+  }
+
+  // A block and the ancestors that fit on a message, newest first
+  pub fn get_block_chunk(&self, bhash: U256) -> Vec<Block> {
+    let mut bhash = bhash;
+    let mut chunk = vec![];
+    let mut tsize = 0; // total size of the corresponding "NoticeTheseBlocks" message
+    while let Some(block) = self.block.get(&bhash) {
+      if bhash == ZERO_HASH() { break; }
+      let bsize = serialized_block_size(block) as usize;
+      if tsize + bsize > MAX_UDP_SIZE_SLOW { break }
+      chunk.push(block.clone());
+      tsize += bsize;
+      bhash = block.prev;
+    }
+    chunk
+  }
+
+  // Header-first sync
+  // -----------------
+
+  // Header of an included block, or of a synced one
+  pub fn get_header(&self, bhash: &U256) -> Option<BlockHeader> {
+    self.block.get(bhash).map(BlockHeader::of).or_else(|| self.sync.headers.get(bhash).copied())
+  }
+
+  // Headers of an included block and its ancestors, newest first, as many as fit on a message
+  pub fn get_headers(&self, bhash: U256) -> Vec<BlockHeader> {
+    let mut bhash = bhash;
+    let mut headers = vec![];
+    while let Some(block) = self.block.get(&bhash) {
+      if bhash == ZERO_HASH() || headers.len() == HEADERS_PER_MESSAGE { break; }
+      headers.push(BlockHeader::of(block));
+      bhash = block.prev;
+    }
+    headers
+  }
+
+  // Height, target and accumulated work of a valid included block, or of a linked header
+  fn get_link(&self, bhash: &U256) -> Option<(u128, U256, U256)> {
+    if self.block.contains_key(bhash) {
+      let valid = *bhash == ZERO_HASH() || self.height[bhash] > 0;
+      return valid.then(|| (self.height[bhash], self.target[bhash], self.work[bhash]));
+    }
+    let height = *self.sync.height.get(bhash)?;
+    Some((height, self.sync.target[bhash], self.sync.work[bhash]))
+  }
+
+  // Whether a block told of is far enough ahead of the tip, going by its time, to sync header-first
+  pub fn is_far_behind(&self, time: u128) -> bool {
+    time > self.block[&self.tip].time + SYNC_DISTANCE * TIME_PER_BLOCK
+  }
+
+  // Takes the headers a peer sent, newest first. Returns the block whose headers should be asked
+  // next, when the oldest ones don't reach a known block yet.
+  pub fn add_headers(&mut self, headers: &[BlockHeader]) -> Option<U256> {
+    let oldest = *headers.last()?;
+    if headers.windows(2).any(|pair| pair[0].prev != pair[1].hash) {
+      return None;
+    }
+    for header in headers.iter().rev() {
+      if self.block.contains_key(&header.hash) || self.sync.headers.contains_key(&header.hash) {
+        continue;
+      }
+      if self.sync.headers.len() >= MAX_SYNC_HEADERS {
+        break;
+      }
+      self.sync.headers.insert(header.hash, *header);
+      self.sync.waiting.entry(header.prev).or_insert_with(Vec::new).push(header.hash);
+    }
+    for header in headers.iter().rev() {
+      if self.get_link(&header.prev).is_some() {
+        self.link_headers(header.prev);
+      }
+    }
+    let known = self.block.contains_key(&oldest.prev) || self.sync.headers.contains_key(&oldest.prev);
+    if known { None } else { Some(oldest.prev) }
+  }
+
+  // Links the headers waiting for a linked block, and the ones waiting for those. A header that
+  // misses its target, or doesn't advance time, is left out, with the ones after it. Hashes are
+  // claimed, so the work they add up to is capped, rather than trusted not to overflow.
+  fn link_headers(&mut self, phash: U256) {
+    let now = self.clock.now();
+    let mut parents = vec![phash];
+    while let Some(phash) = parents.pop() {
+      let Some(waiting) = self.sync.waiting.remove(&phash) else { continue };
+      let (height, target, work) = self.get_link(&phash).expect("linked");
+      let time = self.get_header(&phash).expect("linked").time;
+      for bhash in waiting {
+        let header = self.sync.headers[&bhash];
+        if bhash < target || bhash == U256::MAX || header.time <= time || header.time >= now + DELAY_TOLERANCE {
+          continue;
+        }
+        let work = work.saturating_add(get_hash_work(bhash));
+        self.sync.height.insert(bhash, height + 1);
+        self.sync.target.insert(bhash, self.get_next_target(phash, height + 1, header.time, target));
+        self.sync.work.insert(bhash, work);
+        if self.sync.best.map_or(true, |best| work > self.sync.work[&best]) {
+          self.sync.best = Some(bhash);
+        }
+        self.sync.changed = true;
+        parents.push(bhash);
+      }
+    }
+  }
+
+  // The linked headers from the tip to the best one, oldest first
+  pub fn get_synced_chain(&self) -> Vec<U256> {
+    let mut chain = vec![];
+    let mut bhash = match self.sync.best { Some(best) => best, None => return chain };
+    while !self.block.contains_key(&bhash) {
+      chain.push(bhash);
+      bhash = self.sync.headers[&bhash].prev;
+    }
+    chain.reverse();
+    chain
+  }
+
+  // Bodies to ask next: in the window after the tip, the newest of each run of blocks that aren't
+  // downloaded yet, unless it was asked recently. The headers are dropped once they're synced, or
+  // when a body was asked too many times.
+  pub fn get_body_requests(&mut self) -> Vec<U256> {
+    let Some(best) = self.sync.best else { return vec![] };
+    if self.block.contains_key(&best) || self.sync.work[&best] <= self.work[&self.tip] {
+      self.sync.reset();
+      return vec![];
+    }
+    let mut chain = self.get_synced_chain();
+    chain.truncate(SYNC_WINDOW);
+    let now = self.clock.now();
+    let block = &self.block;
+    self.sync.requests.retain(|bhash, _| !block.contains_key(bhash));
+    let mut asked = vec![];
+    for run in chain.chunks(BODIES_PER_REQUEST) {
+      if run.iter().all(|bhash| self.pending.contains_key(bhash)) {
+        continue;
+      }
+      let newest = run[run.len() - 1];
+      let request = self.sync.requests.entry(newest).or_insert(BodyRequest { sent_at: 0, attempts: 0 });
+      if request.attempts > 0 && now < request.sent_at + SYNC_REQUEST_TIMEOUT {
+        continue;
+      }
+      if request.attempts >= SYNC_MAX_ATTEMPTS {
+        eprintln!("Couldn't get block {:x} from peers. Dropping the headers synced.", newest);
+        self.sync.reset();
+        return vec![];
+      }
+      request.sent_at = now;
+      request.attempts += 1;
+      asked.push(newest);
+    }
+    asked
+  }
+
+  // Asks the headers of a block and its ancestors, unless some were asked recently
+  fn ask_headers(&mut self, addr: Address, bhash: U256, force: bool) {
+    let now = self.clock.now();
+    if force || now >= self.sync.asked_at + SYNC_REQUEST_TIMEOUT {
+      self.sync.asked_at = now;
+      self.send(vec![addr], &Message::GiveMeTheseHeaders { bhash });
+    }
+  }
+
+  // Asks the bodies of the next blocks to the peers that sent their headers
+  fn request_bodies(&mut self) {
+    if self.disk.low {
+      return;
+    }
+    for bhash in self.get_body_requests() {
+      let source = self.sync.sources.iter().choose(&mut self.rng).copied();
+      let addrs = match source {
+        Some(addr) => vec![addr],
+        None => self.peers.get_random_active(1, &mut self.rng).iter().map(|peer| peer.address).collect(),
+      };
+      self.send(addrs, &Message::GiveMeThatBlock { bhash });
+    }
+  }
+
+  fn get_headers_path(&self) -> PathBuf {
+    self.path.join("state").join("headers")
+  }
+
+  // Saves the chain being synced, so a restart carries on with it
+  pub fn save_headers(&mut self) {
+    if !self.sync.changed {
+      return;
+    }
+    self.sync.changed = false;
+    let path = self.get_headers_path();
+    if !self.sync.is_syncing() {
+      std::fs::remove_file(path).ok();
+      return;
+    }
+    let headers: Vec<BlockHeader> = self.get_synced_chain().iter().map(|bhash| self.sync.headers[bhash]).collect();
+    let mut bits = BitVec::new();
+    serialize_list(serialize_header, &headers, &mut bits, &mut HashMap::new());
+    if let Some(dir) = path.parent() {
+      std::fs::create_dir_all(dir).ok();
+    }
+    if let Err(err) = self.write_file(path, bitvec_to_bytes(&bits)) {
+      eprintln!("Couldn't save headers to disk: {}", err);
+    }
+  }
+
+  // Takes back the chain that was being synced before a restart
+  pub fn load_headers(&mut self) {
+    let Ok(buffer) = std::fs::read(self.get_headers_path()) else { return };
+    let Some(mut headers) = deserialize_list(deserialize_header, &bytes_to_bitvec(&buffer), &mut 0, &mut HashMap::new()) else { return };
+    headers.reverse();
+    self.add_headers(&headers);
+    if let Some(best) = self.sync.best {
+      eprintln!("Syncing up to height {}, from the headers on disk.", self.sync.height[&best]);
+    }
+  }
+
+  // Is this one of the addresses this node listens on?
+  pub fn is_own_address(&self, addr: &Address) -> bool {
+    if self.advertise.contains(addr) {
+      return true;
+    }
+    let ports = self.net.locals.iter().map(|local| local.port());
+    return addr.is_loopback() && ports.into_iter().any(|port| port == addr.port());
+  }
+
+  // Picks the socket to reach an address: one of its family, or else a dual-stack IPv6 one
+  fn socket_for(&self, addr: &Address) -> Option<usize> {
+    let family = |local: &SocketAddr| Address::from(*local).family();
+    let same = self.net.locals.iter().position(|local| family(local) == addr.family());
+    return same.or_else(|| self.net.locals.iter().position(|local| family(local) == AddressFamily::IPv6));
+  }
+
+  // Sends serialized bytes, each address through the socket that reaches it. With a proxy, all
+  // messages go through the first socket to the proxy's relay. Messages to onion addresses go
+  // through Tor, or are dropped if it isn't configured.
+  fn send_bytes(&mut self, addrs: Vec<Address>, bits: &[u8]) {
+    let data = Arc::new(bits.to_vec());
+    for addr in addrs {
+      if addr.family() == AddressFamily::Onion {
+        self.net.send_onion(addr, data.clone());
+      } else if let Some(proxy) = &self.proxy {
+        if let Some(to) = addr.to_socket_addr() {
+          self.net.send(0, proxy.relay, Arc::new(proxy.wrap(to, bits)));
+        }
+      } else if let Some(index) = self.socket_for(&addr) {
+        if let Some(to) = udp_target(self.net.locals[index].is_ipv6(), &addr) {
+          self.net.send(index, to, data.clone());
+        }
+      }
     }
   }
 
   pub fn handle_message(&mut self, addr: Address, msg: &Message) {
-    if addr != (Address::IPv4 { val0: 127, val1: 0, val2: 0, val3: 1, port: self.port }) {
+    if !self.is_own_address(&addr) {
       // print_with_timestamp!("- received message from {:?}: {:?}", addr, msg);
-      self.peers.see_peer(Peer { address: addr, seen_at: get_time() });
+      self.peers.see_peer(Peer { address: addr, seen_at: self.clock.now() });
       match msg {
         // Someone asked a block
         Message::GiveMeThatBlock { bhash } => {
           // Sends the requested block, plus some of its ancestors
-          let mut bhash = bhash;
-          let mut chunk = vec![];
-          let mut tsize = 0; // total size of the corresponding "NoticeTheseBlocks" message
-          loop {
-            if !self.block.contains_key(&bhash) { break; }
-            if *bhash == ZERO_HASH() { break; }
-            let block = &self.block[bhash];
-            let bsize = serialized_block_size(block) as usize;
-            if tsize + bsize > MAX_UDP_SIZE_SLOW { break }
-            chunk.push(block.clone());
-            tsize += bsize;
-            bhash = &block.prev;
-          }
+          let chunk = self.get_block_chunk(*bhash);
           self.send_blocks_to(vec![addr], false, chunk, 0);
         }
         // Someone sent us some blocks
         Message::NoticeTheseBlocks { gossip, blocks, peers } => {
           // TODO: validate if blocks are sorted by age?
 
-          // Notice received peers
+          // Notice received peers, except onion ones when Tor isn't configured
           for peer in peers {
-            self.peers.see_peer(*peer);
+            let reachable = peer.address.family() != AddressFamily::Onion || self.net.has_onion();
+            if reachable && !self.is_own_address(&peer.address) {
+              self.peers.see_peer(*peer);
+            }
           }
 
-          // Adds the block to the database
+          // Adds the block to the database, unless there's no space to save it
+          if self.disk.low {
+            return;
+          }
           for block in blocks {
             self.add_block(block);
           }
 
-          // Requests missing ancestors
+          // Requests missing ancestors: their headers, if the block is far ahead of the tip, or
+          // else the blocks themselves, unless a sync is already bringing them
           if *gossip && blocks.len() > 0 {
-            self.request_missing_ancestor(addr, &blocks[0].hash);
+            let block = &blocks[0];
+            self.progress.peer_tips.insert(addr, block.hash);
+            if self.is_far_behind(block.time) && self.inclusion_state(&block.hash) == InclusionState::PENDING {
+              self.ask_headers(addr, block.hash, false);
+            } else if !self.sync.is_syncing() {
+              self.request_missing_ancestor(addr, &block.hash);
+            }
+          }
+        }
+        // Someone asked the headers of a block
+        Message::GiveMeTheseHeaders { bhash } => {
+          let headers = self.get_headers(*bhash);
+          if !headers.is_empty() {
+            self.send(vec![addr], &Message::NoticeTheseHeaders { headers });
+          }
+        }
+        // Someone sent us some headers; goes on asking older ones, until they reach a known block
+        Message::NoticeTheseHeaders { headers } => {
+          let older = self.add_headers(headers);
+          if !headers.is_empty() {
+            self.sync.add_source(addr);
+          }
+          if let Some(bhash) = older {
+            self.ask_headers(addr, bhash, true);
           }
         }
         // Someone sent us a transaction to mine
@@ -1104,8 +3740,18 @@ impl Node {
           //print_with_timestamp!("- Transaction added to pool:");
           //print_with_timestamp!("-- {:?}", trans.data);
           //print_with_timestamp!("-- {}", if let Some(st) = trans.to_statement() { view_statement(&st) } else { String::new() });
-          if self.pool.get(&trans).is_none() {
-            self.pool.push(trans.clone(), trans.hash.low_u64());
+          // Transactions over the statement limits would be rejected by every block anyway
+          let limits = self.runtime.get_status().statement_limits;
+          let statement = trans.to_statement();
+          let over_limits = statement.as_ref().map(|s| check_statement(s, limits).is_err()).unwrap_or(false);
+          // Nor does this node relay or mine what its policy refuses
+          let refused = statement.as_ref().map(|s| self.check_policies(s).is_err()).unwrap_or(false);
+          if !over_limits && !refused && self.pool.get(&trans).is_none() {
+            if let Some(statement) = statement {
+              self.runtime.precheck_signature(statement);
+            }
+            self.expiry.add(trans.hash, self.height[&self.tip], None);
+            self.pool.push(trans.clone(), trans.priority());
             self.gossip(5, msg);
           }
         }
@@ -1114,14 +3760,321 @@ impl Node {
   }
 
   pub fn gossip(&mut self, peer_count: u128, message: &Message) {
-    let addrs = self.peers.get_random_active(peer_count).iter().map(|x| x.address).collect();
-    udp_send(&mut self.socket, addrs, message);
+    let addrs = self.peers.get_random_active(peer_count, &mut self.rng).iter().map(|x| x.address).collect();
+    self.send(addrs, message);
+  }
+
+  // Sends a message to many addresses. Messages to peers over the bandwidth cap are dropped. On
+  // chaos mode, some messages are dropped, and the others are held back for a random delay before
+  // being sent.
+  pub fn send(&mut self, addrs: Vec<Address>, message: &Message) {
+    let bits = bitvec_to_bytes(&serialized_message(message));
+    let now = self.clock.now();
+    let kind = message.kind();
+    let addrs: Vec<Address> = addrs.into_iter().filter(|addr| self.traffic.send(*addr, kind, bits.len() as u128, now)).collect();
+    if let Some(chaos) = self.chaos {
+      for addr in addrs {
+        if !chaos.roll(chaos.drop_rate, &mut self.rng) {
+          self.delayed.push((now + chaos.delay(&mut self.rng), addr, message.clone()));
+        }
+      }
+    } else {
+      self.send_bytes(addrs, &bits);
+    }
+  }
+
+  // Sends the delayed messages whose time has come
+  fn send_delayed(&mut self) {
+    let now = self.clock.now();
+    let (ready, waiting) = std::mem::take(&mut self.delayed).into_iter().partition(|(time, _, _)| *time <= now);
+    self.delayed = waiting;
+    for (_, addr, message) in ready {
+      self.send_bytes(vec![addr], &bitvec_to_bytes(&serialized_message(&message)));
+    }
+  }
+
+  // Answers mDNS queries, and adds the nodes found on the local network as peers
+  #[cfg(feature = "mdns")]
+  fn discover_local_peers(&mut self) {
+    if let Some(mdns) = &mut self.mdns {
+      let now = self.clock.now();
+      for addr in mdns.poll() {
+        let address = Address::from(addr);
+        if !self.is_own_address(&address) {
+          self.peers.see_peer(Peer { address, seen_at: now });
+        }
+      }
+    }
+  }
+
+  #[cfg(feature = "mdns")]
+  fn query_local_peers(&mut self) {
+    if let Some(mdns) = &self.mdns {
+      mdns.query();
+    }
+  }
+
+  // Evicts the pool transactions that expired
+  fn evict_expired(&mut self) {
+    let mut expired_local = false;
+    for hash in self.expiry.expire(self.height[&self.tip]) {
+      self.pool.remove(&Transaction { data: vec![], hash });
+      expired_local |= self.local.remove(&hash);
+    }
+    if expired_local {
+      self.save_local_transactions();
+    }
+  }
+
+  // Starts replaying the longest chain from a random retained snapshot, in another thread. The
+  // result is compared with the live state at the tip, as it is now.
+  fn start_replay_check(&mut self) {
+    if self.replay.running.is_some() {
+      return;
+    }
+    let to = self.height[&self.tip];
+    if self.runtime.get_tick() != to {
+      return;
+    }
+    let from = self.runtime.get_snapshot_ticks().into_iter().filter(|tick| *tick < to).choose(&mut self.rng);
+    let Some(shadow) = from.map(|from| self.runtime.fork(from)) else { return };
+    let blocks: Vec<Block> = self.chain[shadow.get_tick() as usize + 1 ..= to as usize].iter().map(|bhash| self.block[bhash].clone()).collect();
+    let live = self.state_hash[&self.tip];
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+      sender.send(replay_blocks(shadow, &blocks, live)).ok();
+    });
+    self.replay.running = Some(receiver);
+  }
+
+  // Records the replay check that finished, if any, alerting on divergence
+  fn finish_replay_check(&mut self) {
+    let Some(running) = &self.replay.running else { return };
+    let check = match running.try_recv() {
+      Ok(check) => check,
+      Err(mpsc::TryRecvError::Empty) => return,
+      Err(mpsc::TryRecvError::Disconnected) => {
+        eprintln!("Replay verification failed: the replay thread crashed.");
+        self.replay.running = None;
+        return;
+      }
+    };
+    self.replay.running = None;
+    if self.replay.record(check.clone()) {
+      eprintln!(
+        "ALERT: replaying ticks {} to {} gave state {:x}, but the live state is {:x}.",
+        check.from, check.to, check.replayed, check.live
+      );
+      let event = NodeEvent::ReplayDivergence {
+        from: check.from as u64,
+        to: check.to as u64,
+        live: check.live.into(),
+        replayed: check.replayed.into(),
+      };
+      self.events.send(Arc::new(event)).ok();
+    }
+  }
+
+  // Pre-executes the pool's statements on top of the tip, in other threads, unless they already
+  // were. See `PoolPreexecution`.
+  fn start_preexecution(&mut self) {
+    if self.preexec.running.is_some() || self.get_computed_tip() != self.tip {
+      return;
+    }
+    let transactions: Vec<(U256, Statement)> = self.pool.iter().filter_map(|(transaction, _)| {
+      Some((transaction.hash, transaction.to_statement()?))
+    }).collect();
+    let fresh = self.preexec.tip == self.tip;
+    if transactions.is_empty() || fresh && transactions.iter().all(|(hash, _)| self.preexec.estimates.contains_key(hash)) {
+      return;
+    }
+    let tip = self.tip;
+    let runtime = self.runtime.fork_tip();
+    let block = new_block(tip, self.clock.now(), 0, Body { data: vec![0] });
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+      let budget = runtime.get_mana_limit().saturating_sub(runtime.get_mana());
+      let (hashes, statements): (Vec<U256>, Vec<Statement>) = transactions.into_iter().unzip();
+      let deps: Vec<StatementDeps> = statements.iter().map(StatementDeps::new).collect();
+      let groups = group_statements(&deps);
+      let estimates = preexecute(runtime, &statements, &groups, &block);
+      let estimates = hashes.into_iter().zip(estimates).filter_map(|(hash, estimate)| Some((hash, estimate?))).collect();
+      sender.send((tip, budget, groups.len(), estimates)).ok();
+    });
+    self.preexec.running = Some(receiver);
+  }
+
+  // Keeps the estimates of the pre-execution that finished, if any, and if the tip is the same
+  fn finish_preexecution(&mut self) {
+    let Some(running) = &self.preexec.running else { return };
+    let (tip, budget, groups, estimates) = match running.try_recv() {
+      Ok(run) => run,
+      Err(mpsc::TryRecvError::Empty) => return,
+      Err(mpsc::TryRecvError::Disconnected) => {
+        eprintln!("Pool pre-execution failed: its thread crashed.");
+        self.preexec.running = None;
+        return;
+      }
+    };
+    self.preexec.running = None;
+    if tip != self.tip {
+      return;
+    }
+    self.preexec.runs += 1;
+    self.preexec.groups = groups as u64;
+    self.preexec.tip = tip;
+    self.preexec.budget = budget;
+    self.preexec.estimates = estimates.into_iter().collect();
+  }
+
+  // The estimate of a pool transaction on top of the tip, if it was pre-executed there
+  pub fn get_estimate(&self, transaction: &U256) -> Option<PoolEstimate> {
+    if self.preexec.tip != self.tip {
+      return None;
+    }
+    return self.preexec.estimates.get(transaction).copied();
+  }
+
+  // Runs the blocks computed since the shadow's last on it, in another thread. After a reorg or a
+  // divergence, the shadow starts over from the newest snapshot of the live runtime.
+  fn start_shadow_batch(&mut self) {
+    let Some(candidate) = self.shadow.candidate else { return };
+    if self.shadow.running.is_some() {
+      return;
+    }
+    let to = std::cmp::min(self.runtime.get_tick(), self.height[&self.tip]);
+    let mut shadow = match self.shadow.idle.take() {
+      Some((shadow, last)) if self.chain.get(shadow.get_tick() as usize) == Some(&last) => shadow,
+      _ => {
+        let mut shadow = self.runtime.fork(to);
+        candidate.apply(&mut shadow);
+        shadow
+      }
+    };
+    let from = shadow.get_tick();
+    let blocks: Vec<(Block, U256)> = self.chain.iter().take(to as usize + 1).skip(from as usize + 1).map_while(|hash| {
+      Some((self.block[hash].clone(), *self.state_hash.get(hash)?))
+    }).collect();
+    if blocks.is_empty() {
+      // Waits for the live runtime to compute more blocks
+      self.shadow.idle = Some((shadow, self.chain[from as usize]));
+      return;
+    }
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+      let checks = shadow_blocks(&mut shadow, &blocks);
+      sender.send((shadow, checks)).ok();
+    });
+    self.shadow.running = Some(receiver);
+  }
+
+  // Records the shadow batch that finished, if any, alerting on divergence
+  fn finish_shadow_batch(&mut self) {
+    let Some(running) = &self.shadow.running else { return };
+    let (shadow, checks) = match running.try_recv() {
+      Ok(batch) => batch,
+      Err(mpsc::TryRecvError::Empty) => return,
+      Err(mpsc::TryRecvError::Disconnected) => {
+        eprintln!("Shadow execution failed: the shadow thread crashed. Starting over.");
+        self.shadow.running = None;
+        return;
+      }
+    };
+    self.shadow.running = None;
+    let candidate = self.shadow.candidate.map(Candidate::name).unwrap_or_default();
+    let last = checks.last().map(|check| check.block);
+    for check in checks {
+      if self.shadow.record(check.clone()) {
+        eprintln!(
+          "ALERT: the `{}` shadow runtime reached state {:x} after block {} ({:x}), but the live one reached {:x}.",
+          candidate, check.shadow, check.height, check.block, check.live
+        );
+        let event = NodeEvent::ShadowDivergence {
+          candidate: candidate.to_string(),
+          height: check.height as u64,
+          block: check.block.into(),
+          live: check.live.into(),
+          shadow: check.shadow.into(),
+        };
+        self.events.send(Arc::new(event)).ok();
+        return;
+      }
+    }
+    self.shadow.idle = last.map(|last| (shadow, last));
+  }
+
+  // Sends the local transactions that are due to some peers
+  fn rebroadcast_local(&mut self) {
+    let due = self.local.due(self.clock.now());
+    if due.is_empty() {
+      return;
+    }
+    let addrs: Vec<Address> = self.peers.get_random_active(REBROADCAST_PEERS, &mut self.rng).iter().map(|peer| peer.address).collect();
+    for trans in due {
+      self.send(addrs.clone(), &Message::PleaseMineThisTransaction { trans });
+    }
+  }
+
+  pub fn get_local_transactions_path(&self) -> PathBuf {
+    self.path.join("state").join("local_transactions")
+  }
+
+  fn save_local_transactions(&mut self) {
+    let path = self.get_local_transactions_path();
+    if let Some(dir) = path.parent() {
+      std::fs::create_dir_all(dir).ok();
+    }
+    let data = self.local.to_text().into_bytes();
+    if let Err(err) = self.write_file(path, data) {
+      eprintln!("Couldn't save local transactions to disk: {}", err);
+    }
+  }
+
+  // Puts back on the pool the local transactions of a previous run
+  fn load_local_transactions(&mut self) {
+    let Ok(text) = std::fs::read_to_string(self.get_local_transactions_path()) else { return };
+    let height = self.height[&self.tip];
+    for trans in LocalPool::from_text(&text) {
+      if let Some(Err(err)) = trans.to_statement().map(|statement| self.check_policies(&statement)) {
+        eprintln!("Dropped a local transaction: {}", err);
+        continue;
+      }
+      self.expiry.add(trans.hash, height, None);
+      self.pool.push(trans.clone(), trans.priority());
+      self.local.add(trans);
+    }
+    eprintln!("Loaded {} local transactions from disk.", self.local.len());
+  }
+
+  // Writes a file to disk. On chaos mode, some writes fail.
+  fn write_file(&mut self, path: PathBuf, data: Vec<u8>) -> std::io::Result<()> {
+    if let Some(chaos) = self.chaos {
+      if chaos.roll(chaos.disk_fail, &mut self.rng) {
+        return Err(std::io::Error::new(std::io::ErrorKind::Other, "chaos: injected disk failure"));
+      }
+    }
+    std::fs::write(path, data)
   }
 
   pub fn get_blocks_path(&self) -> PathBuf {
     self.path.join("state").join("blocks")
   }
 
+  pub fn get_block_index_path(&self) -> PathBuf {
+    self.path.join("state").join("block_index")
+  }
+
+  // Writes the hashes of the longest chain after a height to the block index, dropping the ones
+  // past the tip
+  fn save_block_index(&mut self, after: u128) -> std::io::Result<()> {
+    use std::io::{Seek, SeekFrom, Write};
+    let mut file = std::fs::OpenOptions::new().write(true).create(true).truncate(false).open(self.get_block_index_path())?;
+    file.set_len(after as u64 * BLOCK_INDEX_ENTRY)?;
+    file.seek(SeekFrom::End(0))?;
+    let data: Vec<u8> = self.chain[after as usize + 1 ..].iter().flat_map(|hash| u256_to_bytes(*hash)).collect();
+    file.write_all(&data)
+  }
+
   fn broadcast_tip_block(&mut self) {
     let addrs  = self.peers.get_all_active().iter().map(|x| x.address).collect();
     let blocks = vec![self.block[&self.tip].clone()];
@@ -1129,12 +4082,15 @@ impl Node {
   }
 
   fn gossip_tip_block(&mut self, peer_count: u128) {
-    let addrs  = self.peers.get_random_active(peer_count).iter().map(|x| x.address).collect();
+    let addrs  = self.peers.get_random_active(peer_count, &mut self.rng).iter().map(|x| x.address).collect();
     let blocks = vec![self.block[&self.tip].clone()];
     self.send_blocks_to(addrs, true, blocks, 3);
   }
 
-  fn load_blocks(&mut self) {
+  // Loads the stored blocks, rebuilding the block index along the way, and warns when the index
+  // didn't agree with them, as some of their files were lost or damaged
+  pub fn load_blocks(&mut self) {
+    let indexed = read_block_index(&self.get_block_index_path());
     let blocks_dir = self.get_blocks_path();
     std::fs::create_dir_all(&blocks_dir).ok();
     let mut file_paths : Vec<PathBuf> = vec![];
@@ -1148,6 +4104,18 @@ impl Node {
       let block = deserialized_block(&bytes_to_bitvec(&buffer)).unwrap();
       self.add_block(&block);
     }
+    match indexed {
+      Ok(indexed) if indexed[..] != self.chain[1 ..] => {
+        let agree = indexed.iter().zip(&self.chain[1 ..]).take_while(|(a, b)| a == b).count();
+        eprintln!(
+          "Warning: the block index listed {} blocks, and {} were loaded, agreeing up to height {}. Rebuilt it from the loaded blocks.",
+          indexed.len(), self.chain.len() - 1, agree
+        );
+      }
+      Ok(_) => {}
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+      Err(err) => eprintln!("Warning: couldn't read the block index: {}. Rebuilt it from the loaded blocks.", err),
+    }
   }
 
   fn ask_mine(&self, miner_communication: &mut MinerCommunication, body: Body) {
@@ -1159,9 +4127,37 @@ impl Node {
       prev: self.tip,
       body,
       targ: self.get_tip_target(),
+      time: self.clock.now(),
+      extra: self.extra_data.clone(),
+      intensity: self.mining.intensity,
     });
   }
 
+  // Asks the miner for a block, unless mining is paused, the node halted, or the disk is full
+  pub fn ask_mine_or_stop(&mut self, miner_communication: &mut MinerCommunication) {
+    if self.mining.active && self.watchdog.halted.is_none() && !self.disk.low {
+      let size = self.build_body().data.len();
+      self.template = MinerTemplate { prev: self.tip, size, time: self.clock.now() };
+      let body = self.build_checked_body();
+      self.ask_mine(miner_communication, body);
+    } else if let MinerMessage::Request { .. } = miner_communication.read() {
+      miner_communication.write(MinerMessage::Stop);
+    }
+  }
+
+  // Asks the miner for a new block ahead of the periodic request, if the one it works on went
+  // stale: the tip moved, or enough new statements arrived to make a fuller block
+  pub fn refresh_template(&mut self, miner_communication: &mut MinerCommunication) {
+    if !matches!(miner_communication.read(), MinerMessage::Request { .. }) {
+      return;
+    }
+    let stale = self.template.prev != self.tip;
+    let due = self.clock.now() >= self.template.time + TEMPLATE_REFRESH_DELAY;
+    if stale || (due && self.build_body().data.len() >= self.template.size + TEMPLATE_MIN_GAIN) {
+      self.ask_mine_or_stop(miner_communication);
+    }
+  }
+
   fn add_mined_block(&mut self, miner_communication: &MinerCommunication) {
     if let MinerMessage::Answer { block } = miner_communication.read() {
       self.add_block(&block);
@@ -1171,22 +4167,66 @@ impl Node {
 
   // Builds the body to be mined.
   // To convert back to a vector of transactions, use `extract_transactions()`.
+  // Takes the runs that bid a higher max mana price first, and leaves out the ones that bid under
+  // the base price, the transactions estimated to fail, and the ones that would spend more mana
+  // than the block has left
   pub fn build_body(&self) -> Body {
-    let mut body_vec = vec![0]; 
-    let mut tx_count = 0;
-    for (transaction, score) in self.pool.iter() {
-      let tx_len = transaction.data.len();
-      if tx_len == 0 { continue; }
-      let len_info = transaction.encode_length(); // number we will store as the length
-      if body_vec.len() + 2 + tx_len > MAX_BODY_SIZE { break; }
-      if tx_count + 1 > 255 { break; }
-      body_vec.push(len_info.0);
-      body_vec.push(len_info.1);
-      body_vec.extend_from_slice(&transaction.data);
-      tx_count += 1;
-    }
-    body_vec[0] = tx_count as u8;
-    return Body { data: body_vec };
+    let status = self.runtime.get_status();
+    let market = status.activations.is_active(upgrade::Feature::ManaMarket, self.height[&self.tip] + 1);
+    let mut budget = self.preexec.budget;
+    let mut pool: Vec<(&Transaction, &u64)> = self.pool.iter().collect();
+    pool.sort_by_key(|(_, priority)| std::cmp::Reverse(**priority));
+    let transactions = pool.into_iter().map(|(transaction, _)| transaction).filter(|transaction| {
+      if market && transaction.max_price().is_some_and(|price| price < status.mana_price) {
+        return false;
+      }
+      match self.get_estimate(&transaction.hash) {
+        None => true,
+        Some(estimate) if !estimate.ok || estimate.mana > budget => false,
+        Some(estimate) => {
+          budget -= estimate.mana;
+          true
+        }
+      }
+    });
+    return transactions_to_body(transactions, self.payout);
+  }
+
+  // Builds the body to be mined, leaving out the transactions that fail on top of the tip
+  pub fn build_checked_body(&mut self) -> Body {
+    let body = self.build_body();
+    let hash = hash_bytes(&body.data);
+    if let Some((tip, checked_hash, checked)) = &self.precheck.checked {
+      if *tip == self.tip && *checked_hash == hash {
+        return checked.clone();
+      }
+    }
+    // The runtime is behind the tip while blocks are being computed, so this waits for them
+    if self.get_computed_tip() != self.tip {
+      return body;
+    }
+    let height = self.height[&self.tip] + 1;
+    let block = new_block(self.tip, self.clock.now(), 0, body.clone());
+    let mut results = self.runtime.precheck_block(&block).into_iter();
+    let mut kept = vec![];
+    for transaction in extract_transactions(&body) {
+      // Results are only given for the transactions that decode to statements
+      let err = match transaction.to_statement() {
+        None => Some("Not a statement.".to_string()),
+        Some(_) => results.next().and_then(|result| result.err()).map(|err| err.err),
+      };
+      match err {
+        None => kept.push(transaction),
+        Some(err) => {
+          eprintln!("Left transaction {:x} out of the block to mine: {}", transaction.hash, err);
+          self.precheck.exclude(PrecheckFailure { transaction: transaction.hash, height, err });
+        }
+      }
+    }
+    self.precheck.checks += 1;
+    let checked = transactions_to_body(kept.iter(), self.payout);
+    self.precheck.checked = Some((self.tip, hash, checked.clone()));
+    return checked;
   }
 
   fn log_heartbeat(&self) {
@@ -1202,22 +4242,24 @@ impl Node {
     let mut missing_count: u64 = 0;
     let mut pending_count: u64 = 0;
     for (bhash, _) in self.wait_list.iter() {
-      if self.pending.get(bhash).is_some() {
+      if self.pending.contains_key(bhash) {
         pending_count += 1;
       }
       missing_count += 1;
     }
 
-    let mana_cur = self.runtime.get_mana() as i64;
-    let mana_lim = self.runtime.get_mana_limit() as i64;
-    let size_cur = self.runtime.get_size() as i64;
-    let size_lim = self.runtime.get_size_limit() as i64;
+    let status = self.runtime.get_status();
+    let mana_cur = status.mana as i64;
+    let mana_lim = status.mana_limit as i64;
+    let size_cur = status.size as i64;
+    let size_lim = status.size_limit as i64;
     let mana_avail = mana_lim - mana_cur;
     let size_avail = size_lim - size_cur;
     debug_assert!(size_avail >= 0);
     debug_assert!(mana_avail >= 0);
 
     let peers_num = self.peers.get_all_active().len();
+    let sync = self.get_sync_summary();
 
     let log = object!{
       event: "heartbeat",
@@ -1227,12 +4269,26 @@ impl Node {
         // target: u256_to_hex(tip_target),
         difficulty: difficulty.low_u64(),
         hashrate: hash_rate.low_u64(),
+        state_hash: format!("0x{:0>64x}", self.state_hash[&self.get_computed_tip()]),
       },
       blocks: {
         missing: missing_count,
         pending: pending_count,
         included: included_count,
       },
+      precheck: {
+        checks: self.precheck.checks,
+        excluded: self.precheck.excluded,
+      },
+      disk: {
+        free: self.disk.free,
+        low: self.disk.low,
+      },
+      sync: {
+        syncing: sync.syncing,
+        headers: sync.headers,
+        requested: sync.requested,
+      },
       runtime: {
         mana: {
           current: mana_cur.to_string(),
@@ -1250,9 +4306,12 @@ impl Node {
     println!("{}", log);
   }
 
-  pub fn main(mut self, kindelia_path: PathBuf, mut miner_communication: MinerCommunication, mine: bool) -> ! {
+  pub fn main(mut self, kindelia_path: PathBuf, mut miner_communication: MinerCommunication) -> ! {
 
     eprintln!("Port: {}", self.port);
+    for local in &self.net.locals {
+      eprintln!("Listening on: {}", local);
+    }
     eprintln!("Initial peers: ");
     for peer in self.peers.get_all_active() {
       eprintln!("- {}", peer.address);
@@ -1262,6 +4321,8 @@ impl Node {
     if self.port == UDP_PORT {
       self.load_blocks();
     }
+    self.load_headers();
+    self.load_local_transactions();
 
    // A task that is executed continuously on the main loop
     struct Task {
@@ -1286,10 +4347,44 @@ impl Node {
         delay: HANDLE_REQUEST_DELAY,
         action: |node, mc| { node.receive_request(); },
       },
-      // Forgets inactive peers
+      // Forgets inactive peers, and their traffic
+      Task {
+        delay: 5_000,
+        action: |node, mc| {
+          let now = node.clock.now();
+          node.peers.timeout(now);
+          node.traffic.evict(now);
+        },
+      },
+      // Rebroadcasts the transactions submitted to this node
+      Task {
+        delay: 1_000,
+        action: |node, mc| { node.rebroadcast_local(); },
+      },
+      // Asks the bodies of the blocks being synced
+      Task {
+        delay: 100,
+        action: |node, mc| { node.request_bodies(); },
+      },
+      // Checks the free space of the data directory
+      Task {
+        delay: DISK_CHECK_DELAY,
+        action: |node, mc| { node.check_disk(); },
+      },
+      // Samples the sync speed
+      Task {
+        delay: 1_000,
+        action: |node, mc| { node.sample_sync_progress(); },
+      },
+      // Saves the headers of the chain being synced
       Task {
         delay: 5_000,
-        action: |node, mc| { node.peers.timeout(); },
+        action: |node, mc| { node.save_headers(); },
+      },
+      // Evicts expired transactions from the pool
+      Task {
+        delay: 1_000,
+        action: |node, mc| { node.evict_expired(); },
       },
       // Prints stats
       Task {
@@ -1298,22 +4393,98 @@ impl Node {
       },
     ];
 
-    if mine {
-      let miner_tasks = vec![
-        // Asks the miner thread to mine a block
-        Task {
-          delay: 1000,
-          action: |node, mc| { node.ask_mine(mc, node.build_body()); },
-        },
-        // If the miner mined a block, adds it
-        Task {
-          delay: 5,
-          action: |node, mc| { node.add_mined_block(mc); },
-        },
-      ];
-      tasks.extend(miner_tasks);
+    if self.chaos.is_some() {
+      // The seed is shown, so a run can be reproduced with `--seed`
+      eprintln!("Chaos mode enabled: {:?}, seed {}", self.chaos, self.seed);
+      // Sends messages held back by chaos mode
+      tasks.push(Task {
+        delay: 10,
+        action: |node, mc| { node.send_delayed(); },
+      });
+    }
+
+    if !self.policies.is_empty() {
+      eprintln!("Local policies refuse to relay and mine some statements.");
+    }
+
+    if let Some(backups) = &self.backups {
+      eprintln!("Backing up every {} blocks to {:?}.", backups.config.every, backups.dir);
+      // Backs up the data directory
+      tasks.push(Task {
+        delay: 1_000,
+        action: |node, mc| { node.check_backup(); },
+      });
+    }
+
+    if self.replay.enabled {
+      eprintln!("Verifying replays every {} seconds.", REPLAY_CHECK_DELAY / 1000);
+      // Replays recent blocks on a shadow runtime
+      tasks.push(Task {
+        delay: REPLAY_CHECK_DELAY,
+        action: |node, mc| { node.start_replay_check(); },
+      });
+      // Compares the finished replays with the live state
+      tasks.push(Task {
+        delay: 1_000,
+        action: |node, mc| { node.finish_replay_check(); },
+      });
+    }
+
+    // Pre-executes the pool while mining
+    tasks.push(Task {
+      delay: PREEXECUTE_DELAY,
+      action: |node, mc| {
+        node.finish_preexecution();
+        if node.mining.active {
+          node.start_preexecution();
+        }
+      },
+    });
+
+    if let Some(candidate) = self.shadow.candidate {
+      eprintln!("Running the blocks on a `{}` shadow runtime too.", candidate.name());
+      // Runs the computed blocks on the shadow runtime, comparing the states
+      tasks.push(Task {
+        delay: 1_000,
+        action: |node, mc| { node.finish_shadow_batch(); node.start_shadow_batch(); },
+      });
     }
 
+    #[cfg(feature = "mdns")]
+    if self.mdns.is_some() {
+      eprintln!("Discovering local nodes with mDNS.");
+      // Answers mDNS queries and adds the local nodes found
+      tasks.push(Task {
+        delay: 100,
+        action: |node, mc| { node.discover_local_peers(); },
+      });
+      // Asks the local network for nodes
+      tasks.push(Task {
+        delay: 5_000,
+        action: |node, mc| { node.query_local_peers(); },
+      });
+    }
+
+    // The miner thread is always up, so mining can be resumed at runtime
+    let miner_tasks = vec![
+      // Asks the miner thread to mine a block
+      Task {
+        delay: 1000,
+        action: |node, mc| { node.ask_mine_or_stop(mc); },
+      },
+      // If the miner mined a block, adds it
+      Task {
+        delay: 5,
+        action: |node, mc| { node.add_mined_block(mc); },
+      },
+      // Asks for a new block early, when the tip moved or the pool can fill a fuller one
+      Task {
+        delay: 20,
+        action: |node, mc| { node.refresh_template(mc); },
+      },
+    ];
+    tasks.extend(miner_tasks);
+
     // Last time a task was executed
     let mut last_tick_time: Vec<u128> = vec![0; tasks.len()];
 
@@ -1342,6 +4513,12 @@ impl std::fmt::Display for Address {
       Address::IPv4 { val0, val1, val2, val3, port } => {
         f.write_fmt(format_args!("{}.{}.{}.{}:{}", val0, val1, val2, val3, port))
       },
+      Address::IPv6 { segs, port } => {
+        f.write_fmt(format_args!("[{}]:{}", Ipv6Addr::from(*segs), port))
+      },
+      Address::Onion { key, port } => {
+        f.write_fmt(format_args!("{}:{}", onion::onion_host(key), port))
+      },
     }
   }
 }
@@ -0,0 +1,77 @@
+// Onion addresses
+// ===============
+
+// Tor onion services (version 3) are named by their ed25519 public key. The hostname is the
+// base32 of the key, a 2-byte checksum and the version, followed by `.onion`. Nodes keep only the
+// key, and rebuild the hostname to reach the service through Tor.
+
+use sha3::Digest;
+
+const VERSION : u8 = 3;
+const CHECKSUM_PREFIX : &[u8] = b".onion checksum";
+const ALPHABET : &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+// Length of a hostname without its `.onion` suffix: 35 bytes, in base32
+const HOST_LEN : usize = 56;
+
+// The hostname of the onion service with this key, e.g. `2gzyxa5i....onion`
+pub fn onion_host(key: &[u8; 32]) -> String {
+  let mut bytes = key.to_vec();
+  bytes.extend_from_slice(&checksum(key));
+  bytes.push(VERSION);
+  return format!("{}.onion", base32(&bytes));
+}
+
+// Reads the key of an onion service from its hostname. Hostnames of other versions, or with a
+// wrong checksum, aren't onion addresses.
+pub fn read_onion_host(host: &str) -> Option<[u8; 32]> {
+  let host = host.strip_suffix(".onion")?.to_ascii_lowercase();
+  if host.len() != HOST_LEN {
+    return None;
+  }
+  let bytes = unbase32(&host)?;
+  let key: [u8; 32] = bytes[0 .. 32].try_into().ok()?;
+  if bytes[34] != VERSION || bytes[32 .. 34] != checksum(&key) {
+    return None;
+  }
+  return Some(key);
+}
+
+fn checksum(key: &[u8; 32]) -> [u8; 2] {
+  let mut hasher = sha3::Sha3_256::new();
+  hasher.update(CHECKSUM_PREFIX);
+  hasher.update(key);
+  hasher.update([VERSION]);
+  let hash = hasher.finalize();
+  return [hash[0], hash[1]];
+}
+
+// Base32 (RFC 4648) of bytes whose bit length is a multiple of 5, without padding
+fn base32(bytes: &[u8]) -> String {
+  let mut text = String::with_capacity(bytes.len() * 8 / 5);
+  let (mut acc, mut bits) = (0u32, 0);
+  for byte in bytes {
+    acc = (acc << 8) | *byte as u32;
+    bits += 8;
+    while bits >= 5 {
+      bits -= 5;
+      text.push(ALPHABET[((acc >> bits) & 31) as usize] as char);
+    }
+  }
+  return text;
+}
+
+fn unbase32(text: &str) -> Option<Vec<u8>> {
+  let mut bytes = Vec::with_capacity(text.len() * 5 / 8);
+  let (mut acc, mut bits) = (0u32, 0);
+  for chr in text.bytes() {
+    let val = ALPHABET.iter().position(|x| *x == chr)? as u32;
+    acc = (acc << 5) | val;
+    bits += 5;
+    if bits >= 8 {
+      bits -= 8;
+      bytes.push((acc >> bits) as u8);
+    }
+  }
+  return Some(bytes);
+}
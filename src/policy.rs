@@ -0,0 +1,119 @@
+// Local Policy
+// ============
+
+// Operators can keep their node from relaying and mining statements they'd rather not carry, e.g.
+// ones deploying huge states, or using some names. This is local to the node: blocks holding such
+// statements are as valid as any other, and are accepted and run as usual. The policy is set in
+// the profile config (see `profile.rs`):
+//
+//   { "policy": { "deny_names": ["Casino"], "deny_signers": ["#x..."], "max_state_size": 4096 } }
+//
+// - `deny_names`: names statements can't define, register or call, along with the names under them
+// - `deny_signers`: subjects whose signed statements are refused
+// - `max_state_size`: most nodes the initial state of a deployed function can have
+//
+// Refused statements aren't put on the pool, so they're neither relayed nor mined, and posting
+// them to `/code/send` fails. Other policies implement `StatementPolicy`, and are added to the
+// node's `policies` along with this one.
+
+use std::collections::HashSet;
+
+use serde::Deserialize;
+
+use crate::crypto;
+use crate::hvm::{self, Statement, Term};
+use crate::query::StatementMeta;
+
+// A local rule of which statements the node relays and mines
+pub trait StatementPolicy: Send {
+  // Why the node refuses a statement, if it does
+  fn check(&self, statement: &Statement) -> Result<(), String>;
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyConfig {
+  #[serde(default)]
+  pub deny_names: Vec<String>,
+  #[serde(default)]
+  pub deny_signers: Vec<String>,
+  pub max_state_size: Option<usize>,
+}
+
+// The policy set in the profile config
+#[derive(Debug, Default)]
+pub struct LocalPolicy {
+  pub deny_names: HashSet<u128>,
+  pub deny_signers: HashSet<u128>,
+  pub max_state_size: Option<usize>,
+}
+
+impl LocalPolicy {
+  pub fn new(config: &PolicyConfig) -> Result<LocalPolicy, String> {
+    let mut policy = LocalPolicy { max_state_size: config.max_state_size, ..LocalPolicy::default() };
+    for name in &config.deny_names {
+      match hvm::read_name(name) {
+        Ok(("", num)) if !name.is_empty() && name.len() <= 20 => policy.deny_names.insert(num),
+        _ => return Err(format!("Invalid policy config: '{}' isn't a name.", name)),
+      };
+    }
+    for signer in &config.deny_signers {
+      let subject = crypto::Name::read(signer).ok_or_else(|| format!("Invalid policy config: '{}' isn't a subject (#x...).", signer))?;
+      policy.deny_signers.insert(subject.0);
+    }
+    Ok(policy)
+  }
+
+  // Is this name denied, or under a denied one?
+  fn denies(&self, name: u128) -> bool {
+    let mut name = Some(name);
+    while let Some(current) = name {
+      if self.deny_names.contains(&current) {
+        return true;
+      }
+      name = hvm::get_namespace(current);
+    }
+    false
+  }
+}
+
+impl StatementPolicy for LocalPolicy {
+  fn check(&self, statement: &Statement) -> Result<(), String> {
+    let meta = StatementMeta::new(statement);
+    if let Some(name) = meta.name.into_iter().chain(meta.funs).find(|name| self.denies(*name)) {
+      return Err(format!("The name '{}' is denied by this node.", hvm::u128_to_name(name)));
+    }
+    if let (Some(limit), Statement::Fun { init, .. }) = (self.max_state_size, statement) {
+      let size = term_size(init);
+      if size > limit {
+        return Err(format!("The initial state has {} nodes, but this node takes at most {}.", size, limit));
+      }
+    }
+    // Recovering the signer takes a while, so it's only done when some are denied
+    if !self.deny_signers.is_empty() {
+      let signer = hvm::get_sign(statement).as_ref().and_then(|sign| sign.signer_name(&hvm::sign_hash(statement)));
+      if let Some(signer) = signer.filter(|signer| self.deny_signers.contains(&signer.0)) {
+        return Err(format!("Statements signed by {} are denied by this node.", signer.show()));
+      }
+    }
+    Ok(())
+  }
+}
+
+// Nodes of a term
+fn term_size(term: &Term) -> usize {
+  let mut size = 0;
+  let mut stack = vec![term];
+  while let Some(term) = stack.pop() {
+    size += 1;
+    match term {
+      Term::Var { .. } | Term::Num { .. } => {}
+      Term::Dup { expr, body, .. } => stack.extend([&**expr, &**body]),
+      Term::Lam { body, .. } => stack.push(body),
+      Term::App { func, argm } => stack.extend([&**func, &**argm]),
+      Term::Op2 { val0, val1, .. } => stack.extend([&**val0, &**val1]),
+      Term::Ctr { args, .. } | Term::Fun { args, .. } => stack.extend(args.iter()),
+    }
+  }
+  size
+}
@@ -0,0 +1,123 @@
+// Profiles
+// ========
+
+// A profile keeps the files of a network apart from the others', so that, e.g., mainnet and
+// testnet tools can be used side by side. It's picked with `--profile <name>` or
+// `$KINDELIA_PROFILE`. Each one is a directory under the Kindelia path:
+//
+//   ~/.kindelia/                       the default profile
+//   ~/.kindelia/profiles/<name>/       a named profile
+//     config.json                      defaults for the CLI, all optional
+//     keys/<key>                       secret keys, which commands taking a key file find by name
+//     wallet.json                      accounts watched by `kindelia wallet` (see `wallet.rs`)
+//     VERSION, state/                  the node's data (see `datadir.rs`)
+//     backups/                         backups of the node's data, by default (see `backup.rs`)
+//
+// The config holds defaults that flags override:
+//
+//   { "api": "http://127.0.0.1:8001", "testnet": true }
+//
+// `api` can also list several nodes, which commands fall over to in order when one can't be
+// reached (see `ApiClient`). `backup` has the node back up its data as the chain grows (see
+// `backup.rs`), and `policy` keeps it from relaying and mining some statements (see `policy.rs`).
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::backup::BackupConfig;
+use crate::crypto;
+use crate::policy::PolicyConfig;
+
+// Environment variable with the profile, when `--profile` isn't given
+pub const PROFILE_ENV_VAR : &str = "KINDELIA_PROFILE";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileConfig {
+  #[serde(default, deserialize_with = "one_or_many")]
+  pub api: Vec<String>,             // URLs of the node APIs
+  #[serde(default)]
+  pub testnet: bool,                // as `start --testnet`
+  pub backup: Option<BackupConfig>, // when the node backs up its data
+  pub policy: Option<PolicyConfig>, // statements the node won't relay nor mine
+}
+
+#[derive(Debug)]
+pub struct Profile {
+  pub name: Option<String>, // `None` for the default profile
+  pub path: PathBuf,        // where its files are
+  pub config: ProfileConfig,
+}
+
+// Reads a string or a list of strings
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+  #[derive(Deserialize)]
+  #[serde(untagged)]
+  enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+  }
+  Ok(match OneOrMany::deserialize(deserializer)? {
+    OneOrMany::One(url) => vec![url],
+    OneOrMany::Many(urls) => urls,
+  })
+}
+
+fn is_profile_name(name: &str) -> bool {
+  !name.is_empty() && name.chars().all(|chr| chr.is_ascii_alphanumeric() || chr == '-' || chr == '_')
+}
+
+impl Profile {
+  // Opens a profile under the Kindelia path. Its directory is created when the node first writes
+  // to it, so a profile without files is just empty.
+  pub fn load(base: &Path, name: Option<String>) -> Result<Profile, String> {
+    let path = match &name {
+      None => base.to_path_buf(),
+      Some(name) if is_profile_name(name) => base.join("profiles").join(name),
+      Some(name) => return Err(format!("Invalid profile name: '{}'. Use letters, digits, '-' and '_'.", name)),
+    };
+    let config_path = path.join("config.json");
+    let config = match std::fs::read_to_string(&config_path) {
+      Ok(text) => serde_json::from_str(&text).map_err(|err| format!("Invalid profile config {:?}: {}", config_path, err))?,
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => ProfileConfig::default(),
+      Err(err) => return Err(format!("Couldn't read profile config {:?}: {}", config_path, err)),
+    };
+    Ok(Profile { name, path, config })
+  }
+
+  // Resolves a secret key file given to a command: an existing path is used as is, and anything
+  // else names a key of the profile
+  pub fn key_path(&self, key: &str) -> PathBuf {
+    let path = PathBuf::from(key);
+    if path.exists() {
+      path
+    } else {
+      self.path.join("keys").join(key)
+    }
+  }
+
+  // Reads the secret key file given to a command, as `key_path` finds it
+  pub fn load_account(&self, key: &str) -> Result<crypto::Account, String> {
+    let path = self.key_path(key);
+    let text = std::fs::read_to_string(&path).map_err(|err| format!("Couldn't read the secret key {:?}: {}", path, err))?;
+    let skey = hex::decode(text.trim()).ok().filter(|skey| skey.len() == 32);
+    let skey = skey.ok_or_else(|| format!("Invalid secret key {:?}: expected 64 hex digits.", path))?;
+    secp256k1::SecretKey::from_slice(&skey)
+      .map(crypto::Account::from_secret_key)
+      .map_err(|_| format!("Invalid secret key {:?}.", path))
+  }
+
+  // Named profiles under the Kindelia path, in name order
+  pub fn list(base: &Path) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(base.join("profiles")) else { return vec![] };
+    let mut names: Vec<String> = entries
+      .filter_map(|entry| entry.ok())
+      .filter(|entry| entry.path().is_dir())
+      .filter_map(|entry| entry.file_name().into_string().ok())
+      .filter(|name| is_profile_name(name))
+      .collect();
+    names.sort();
+    names
+  }
+}
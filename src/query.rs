@@ -0,0 +1,525 @@
+// Statement Queries
+// =================
+
+// A small filter language over the statements of the longest chain, used by `GET /statements`:
+//
+//   kind == 'run' && (fun == 'Bank' || fun == 'Token') && height >= 100
+//
+// Fields:
+// - `kind`: `fun`, `ctr`, `run`, `reg` or `rot`
+// - `name`: the name a statement defines or registers
+// - `fun`: a function a statement defines or calls
+// - `signer`: the subject that signed a statement, as `#x...`
+// - `height`: height of the block holding the statement
+//
+// `and` and `or` can be written instead of `&&` and `||`, which must be escaped in URLs. The
+// filter is narrowed down with the node's index: `==` on `kind`, `name`, `fun` and `signer` and comparisons
+// on `height` are lookups, and what's left is checked on the statements found.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::crypto;
+use crate::hvm::{self, Statement, Term};
+
+// Statements returned by a query when no limit is given, and at most
+pub const DEFAULT_QUERY_LIMIT : usize = 100;
+pub const MAX_QUERY_LIMIT : usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StatementKind {
+  Fun,
+  Ctr,
+  Run,
+  Reg,
+  Rot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+  Eq,
+  Ne,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+  Kind(CmpOp, StatementKind),
+  Name(CmpOp, u128),
+  Fun(CmpOp, u128),
+  Signer(CmpOp, u128),
+  Height(CmpOp, u64),
+  And(Box<Filter>, Box<Filter>),
+  Or(Box<Filter>, Box<Filter>),
+}
+
+// Where a statement is on the longest chain: its block's height, and its position in the block
+pub type StatementLoc = (u64, usize);
+
+// What filters look at, per statement
+#[derive(Debug, Clone)]
+pub struct StatementMeta {
+  pub kind: StatementKind,
+  pub name: Option<u128>,   // name defined or registered
+  pub funs: Vec<u128>,      // functions defined or called
+  pub signer: Option<u128>, // subject that signed it, if signed and recovered
+}
+
+impl StatementMeta {
+  pub fn new(statement: &Statement) -> Self {
+    let (mut funs, _) = hvm::get_statement_refs(statement);
+    funs.sort_unstable();
+    funs.dedup();
+    let (kind, name) = match statement {
+      Statement::Fun { name, .. } => (StatementKind::Fun, Some(*name)),
+      Statement::Ctr { name, .. } => (StatementKind::Ctr, Some(*name)),
+      Statement::Run { .. } => (StatementKind::Run, None),
+      Statement::Reg { name, .. } => (StatementKind::Reg, Some(*name)),
+      Statement::Rot { name, .. } => (StatementKind::Rot, Some(*name)),
+    };
+    StatementMeta { kind, name, funs, signer: None }
+  }
+
+  // Also recovers the signer, which takes a while, so it's only done for indexed statements
+  pub fn with_signer(statement: &Statement) -> Self {
+    let signer = hvm::get_sign(statement).as_ref().and_then(|sign| sign.signer_name(&hvm::sign_hash(statement)));
+    StatementMeta { signer: signer.map(|signer| signer.0), ..StatementMeta::new(statement) }
+  }
+
+  fn keys(&self) -> impl Iterator<Item = IndexKey> + '_ {
+    let kind = std::iter::once(IndexKey::Kind(self.kind));
+    let name = self.name.map(IndexKey::Name);
+    let signer = self.signer.map(IndexKey::Signer);
+    kind.chain(name).chain(signer).chain(self.funs.iter().map(|fun| IndexKey::Fun(*fun)))
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum IndexKey {
+  Kind(StatementKind),
+  Name(u128),
+  Fun(u128),
+  Signer(u128),
+  Hash([u8; 32]),
+}
+
+// Statements of the longest chain, by the fields filters look up, and by hash
+#[derive(Default)]
+pub struct StatementIndex {
+  metas: BTreeMap<StatementLoc, StatementMeta>,
+  hashes: BTreeMap<StatementLoc, [u8; 32]>,
+  keys: HashMap<IndexKey, BTreeSet<StatementLoc>>,
+}
+
+impl StatementIndex {
+  // Indexes the statements of the block at `height`, replacing the ones indexed at it and above
+  pub fn index_block(&mut self, height: u64, statements: &[Statement]) {
+    self.truncate(height);
+    for (position, statement) in statements.iter().enumerate() {
+      let loc = (height, position);
+      let meta = StatementMeta::with_signer(statement);
+      let hash = hvm::hash_statement(statement).0;
+      for key in meta.keys().chain(std::iter::once(IndexKey::Hash(hash))) {
+        self.keys.entry(key).or_default().insert(loc);
+      }
+      self.metas.insert(loc, meta);
+      self.hashes.insert(loc, hash);
+    }
+  }
+
+  // Forgets the statements at `height` and above
+  pub fn truncate(&mut self, height: u64) {
+    let hashes = self.hashes.split_off(&(height, 0));
+    for (loc, meta) in self.metas.split_off(&(height, 0)) {
+      for key in meta.keys().chain(hashes.get(&loc).map(|hash| IndexKey::Hash(*hash))) {
+        if let Some(locs) = self.keys.get_mut(&key) {
+          locs.remove(&loc);
+          if locs.is_empty() {
+            self.keys.remove(&key);
+          }
+        }
+      }
+    }
+  }
+
+  // Where the newest statement with a hash is
+  pub fn locate(&self, hash: &crypto::Hash) -> Option<StatementLoc> {
+    self.keys.get(&IndexKey::Hash(hash.0))?.last().copied()
+  }
+
+  pub fn len(&self) -> usize {
+    self.metas.len()
+  }
+
+  // Statements matching a filter, oldest first, up to `limit`
+  pub fn query(&self, filter: &Filter, limit: usize) -> Vec<StatementLoc> {
+    let matches = |loc: &StatementLoc| filter.matches(*loc, &self.metas[loc]);
+    match self.lookup(filter) {
+      Some(locs) => locs.into_iter().filter(matches).take(limit).collect(),
+      None => self.metas.keys().copied().filter(matches).take(limit).collect(),
+    }
+  }
+
+  // The statements a filter may match, as found on the index. None if it can't narrow them down.
+  fn lookup(&self, filter: &Filter) -> Option<BTreeSet<StatementLoc>> {
+    let get = |key| Some(self.keys.get(&key).cloned().unwrap_or_default());
+    let heights = |range: (std::ops::Bound<StatementLoc>, std::ops::Bound<StatementLoc>)| {
+      Some(self.metas.range(range).map(|(loc, _)| *loc).collect())
+    };
+    use std::ops::Bound::{Excluded, Included, Unbounded};
+    match filter {
+      Filter::Kind(CmpOp::Eq, kind) => get(IndexKey::Kind(*kind)),
+      Filter::Name(CmpOp::Eq, name) => get(IndexKey::Name(*name)),
+      Filter::Fun(CmpOp::Eq, fun) => get(IndexKey::Fun(*fun)),
+      Filter::Signer(CmpOp::Eq, signer) => get(IndexKey::Signer(*signer)),
+      Filter::Height(CmpOp::Eq, height) => heights((Included((*height, 0)), Included((*height, usize::MAX)))),
+      Filter::Height(CmpOp::Lt, height) => heights((Unbounded, Excluded((*height, 0)))),
+      Filter::Height(CmpOp::Le, height) => heights((Unbounded, Included((*height, usize::MAX)))),
+      Filter::Height(CmpOp::Gt, height) => heights((Excluded((*height, usize::MAX)), Unbounded)),
+      Filter::Height(CmpOp::Ge, height) => heights((Included((*height, 0)), Unbounded)),
+      Filter::And(a, b) => match (self.lookup(a), self.lookup(b)) {
+        (Some(a), Some(b)) => Some(a.intersection(&b).copied().collect()),
+        (found, None) | (None, found) => found,
+      },
+      Filter::Or(a, b) => {
+        let (mut a, b) = (self.lookup(a)?, self.lookup(b)?);
+        a.extend(b);
+        Some(a)
+      }
+      _ => None,
+    }
+  }
+}
+
+impl Filter {
+  pub fn matches(&self, loc: StatementLoc, meta: &StatementMeta) -> bool {
+    match self {
+      Filter::Kind(op, kind) => op.test(meta.kind == *kind),
+      Filter::Name(op, name) => op.test(meta.name == Some(*name)),
+      Filter::Fun(op, fun) => op.test(meta.funs.contains(fun)),
+      Filter::Signer(op, signer) => op.test(meta.signer == Some(*signer)),
+      Filter::Height(op, height) => op.compare(loc.0, *height),
+      Filter::And(a, b) => a.matches(loc, meta) && b.matches(loc, meta),
+      Filter::Or(a, b) => a.matches(loc, meta) || b.matches(loc, meta),
+    }
+  }
+}
+
+impl CmpOp {
+  // For fields that are only compared for equality
+  fn test(self, equal: bool) -> bool {
+    if self == CmpOp::Ne { !equal } else { equal }
+  }
+
+  fn compare(self, a: u64, b: u64) -> bool {
+    match self {
+      CmpOp::Eq => a == b,
+      CmpOp::Ne => a != b,
+      CmpOp::Lt => a < b,
+      CmpOp::Le => a <= b,
+      CmpOp::Gt => a > b,
+      CmpOp::Ge => a >= b,
+    }
+  }
+}
+
+// Parsing
+// -------
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+  Ident(String),
+  Str(String),
+  Num(u64),
+  Cmp(CmpOp),
+  And,
+  Or,
+  Open,
+  Close,
+}
+
+fn tokenize(code: &str) -> Result<Vec<Token>, String> {
+  let chars: Vec<char> = code.chars().collect();
+  let mut tokens = vec![];
+  let mut i = 0;
+  while i < chars.len() {
+    let rest: String = chars[i ..].iter().take(2).collect();
+    let (token, len) = match chars[i] {
+      c if c.is_whitespace() => {
+        i += 1;
+        continue;
+      }
+      '(' => (Token::Open, 1),
+      ')' => (Token::Close, 1),
+      _ if rest == "&&" => (Token::And, 2),
+      _ if rest == "||" => (Token::Or, 2),
+      _ if rest == "==" => (Token::Cmp(CmpOp::Eq), 2),
+      _ if rest == "!=" => (Token::Cmp(CmpOp::Ne), 2),
+      _ if rest == "<=" => (Token::Cmp(CmpOp::Le), 2),
+      _ if rest == ">=" => (Token::Cmp(CmpOp::Ge), 2),
+      '<' => (Token::Cmp(CmpOp::Lt), 1),
+      '>' => (Token::Cmp(CmpOp::Gt), 1),
+      quote @ ('\'' | '"') => {
+        let len = chars[i + 1 ..].iter().position(|c| *c == quote).ok_or("Unclosed string.")?;
+        (Token::Str(chars[i + 1 .. i + 1 + len].iter().collect()), len + 2)
+      }
+      c if c.is_ascii_digit() => {
+        let len = chars[i ..].iter().take_while(|c| c.is_ascii_digit()).count();
+        let text: String = chars[i .. i + len].iter().collect();
+        (Token::Num(text.parse().map_err(|_| format!("Number too big: {}.", text))?), len)
+      }
+      c if c.is_ascii_alphabetic() || c == '_' => {
+        let len = chars[i ..].iter().take_while(|c| c.is_ascii_alphanumeric() || **c == '_').count();
+        let word: String = chars[i .. i + len].iter().collect();
+        let token = match word.as_str() {
+          "and" => Token::And,
+          "or" => Token::Or,
+          _ => Token::Ident(word),
+        };
+        (token, len)
+      }
+      c => return Err(format!("Unexpected `{}` in filter.", c)),
+    };
+    tokens.push(token);
+    i += len;
+  }
+  Ok(tokens)
+}
+
+struct Parser {
+  tokens: Vec<Token>,
+  pos: usize,
+}
+
+impl Parser {
+  fn next(&mut self) -> Option<Token> {
+    let token = self.tokens.get(self.pos).cloned();
+    self.pos += 1;
+    token
+  }
+
+  fn peek(&self) -> Option<&Token> {
+    self.tokens.get(self.pos)
+  }
+
+  // `||` binds looser than `&&`
+  fn parse_or(&mut self) -> Result<Filter, String> {
+    let mut filter = self.parse_and()?;
+    while self.peek() == Some(&Token::Or) {
+      self.pos += 1;
+      filter = Filter::Or(Box::new(filter), Box::new(self.parse_and()?));
+    }
+    Ok(filter)
+  }
+
+  fn parse_and(&mut self) -> Result<Filter, String> {
+    let mut filter = self.parse_atom()?;
+    while self.peek() == Some(&Token::And) {
+      self.pos += 1;
+      filter = Filter::And(Box::new(filter), Box::new(self.parse_atom()?));
+    }
+    Ok(filter)
+  }
+
+  fn parse_atom(&mut self) -> Result<Filter, String> {
+    match self.next() {
+      Some(Token::Open) => {
+        let filter = self.parse_or()?;
+        match self.next() {
+          Some(Token::Close) => Ok(filter),
+          _ => Err("Expected `)`.".to_string()),
+        }
+      }
+      Some(Token::Ident(field)) => {
+        let Some(Token::Cmp(op)) = self.next() else {
+          return Err(format!("Expected a comparison after `{}`.", field));
+        };
+        let value = self.next();
+        match (field.as_str(), value) {
+          ("height", Some(Token::Num(height))) => Ok(Filter::Height(op, height)),
+          ("height", _) => Err("`height` is compared with a number.".to_string()),
+          (_, _) if op != CmpOp::Eq && op != CmpOp::Ne => Err(format!("`{}` can only be compared with `==` or `!=`.", field)),
+          ("kind", Some(Token::Str(kind))) => Ok(Filter::Kind(op, read_kind(&kind)?)),
+          ("name", Some(Token::Str(name))) => Ok(Filter::Name(op, read_name(&name)?)),
+          ("fun", Some(Token::Str(name))) => Ok(Filter::Fun(op, read_name(&name)?)),
+          ("signer", Some(Token::Str(subject))) => Ok(Filter::Signer(op, read_subject(&subject)?)),
+          ("kind" | "name" | "fun" | "signer", _) => Err(format!("`{}` is compared with a quoted string.", field)),
+          _ => Err(format!("Unknown field: `{}`.", field)),
+        }
+      }
+      Some(token) => Err(format!("Unexpected {:?} in filter.", token)),
+      None => Err("Unexpected end of filter.".to_string()),
+    }
+  }
+}
+
+fn read_kind(kind: &str) -> Result<StatementKind, String> {
+  match kind {
+    "fun" => Ok(StatementKind::Fun),
+    "ctr" => Ok(StatementKind::Ctr),
+    "run" => Ok(StatementKind::Run),
+    "reg" => Ok(StatementKind::Reg),
+    "rot" => Ok(StatementKind::Rot),
+    _ => Err(format!("Unknown statement kind: `{}`.", kind)),
+  }
+}
+
+fn read_name(name: &str) -> Result<u128, String> {
+  match hvm::read_name(name) {
+    Ok(("", num)) if name.len() <= 20 => Ok(num),
+    _ => Err(format!("Invalid name: `{}`.", name)),
+  }
+}
+
+fn read_subject(subject: &str) -> Result<u128, String> {
+  crypto::Name::read(subject).map(|name| name.0).ok_or_else(|| format!("Invalid subject: `{}`. Expected `#x` and hex digits.", subject))
+}
+
+pub fn parse_filter(code: &str) -> Result<Filter, String> {
+  let mut parser = Parser { tokens: tokenize(code)?, pos: 0 };
+  let filter = parser.parse_or()?;
+  match parser.peek() {
+    None => Ok(filter),
+    Some(token) => Err(format!("Unexpected {:?} in filter.", token)),
+  }
+}
+
+// Block Blooms
+// ------------
+
+// Each computed block has bloom filters of the names its statements define, register or call, along
+// with the functions that emitted events, and of the constructors of the events emitted, served by
+// `GET /blocks/blooms`. Light clients and indexers check them to skip the blocks that can't concern
+// them: a filter never misses a name of its block, but may rarely hold one that isn't.
+
+// Bits of a filter, and bits set per name
+pub const BLOOM_BITS : usize = 512;
+pub const BLOOM_HASHES : usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bloom(pub [u8; BLOOM_BITS / 8]);
+
+impl Default for Bloom {
+  fn default() -> Self {
+    Bloom([0; BLOOM_BITS / 8])
+  }
+}
+
+impl Bloom {
+  // Bits of a name: the first pairs of bytes of its hash, as big-endian numbers, mod BLOOM_BITS
+  fn bits(name: u128) -> [usize; BLOOM_HASHES] {
+    let hash = crypto::keccak256(&name.to_be_bytes());
+    std::array::from_fn(|i| u16::from_be_bytes([hash.0[i * 2], hash.0[i * 2 + 1]]) as usize % BLOOM_BITS)
+  }
+
+  pub fn insert(&mut self, name: u128) {
+    for bit in Bloom::bits(name) {
+      self.0[bit / 8] |= 1 << (bit % 8);
+    }
+  }
+
+  // Might the name have been inserted?
+  pub fn contains(&self, name: u128) -> bool {
+    Bloom::bits(name).iter().all(|bit| self.0[bit / 8] & (1 << (bit % 8)) != 0)
+  }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockBloom {
+  pub names: Bloom,  // names defined, registered or called, and functions that emitted events
+  pub events: Bloom, // constructors of the events emitted
+}
+
+impl BlockBloom {
+  pub fn new(statements: &[Statement], emitted: &[(u128, Term)]) -> Self {
+    let mut bloom = BlockBloom::default();
+    for statement in statements {
+      let meta = StatementMeta::new(statement);
+      meta.name.into_iter().chain(meta.funs).for_each(|name| bloom.names.insert(name));
+    }
+    for (emitter, event) in emitted {
+      bloom.names.insert(*emitter);
+      if let Term::Ctr { name, .. } = event {
+        bloom.events.insert(*name);
+      }
+    }
+    bloom
+  }
+}
+
+// Term Patterns
+// -------------
+
+// Patterns that subscribers of `/events` register, to be sent the results of `run` statements and
+// the emitted events they match, instead of decoding every statement themselves:
+//
+//   {Transfer from to amount}
+//
+// A pattern is made of constructors, numbers and variables. A variable matches any term, and is
+// bound to it; one used twice must match equal terms, and `~` matches without binding. A pattern
+// matches anywhere inside a term, e.g. the one above matches in `{Ok {Transfer #1 #2 #3}}`.
+
+// What the variables of a pattern matched, by name
+pub type Bindings = BTreeMap<String, Term>;
+
+pub fn parse_pattern(code: &str) -> Result<Term, String> {
+  let (rest, term) = hvm::read_term(code).map_err(|err| format!("Invalid pattern: {}", err.erro))?;
+  if !rest.trim().is_empty() {
+    return Err(format!("Unexpected `{}` after pattern.", rest.trim()));
+  }
+  check_pattern(&term)?;
+  Ok(term)
+}
+
+fn check_pattern(term: &Term) -> Result<(), String> {
+  match term {
+    Term::Var { .. } | Term::Num { .. } => Ok(()),
+    Term::Ctr { args, .. } => args.iter().try_for_each(check_pattern),
+    _ => Err("Patterns are made of constructors, numbers and variables.".to_string()),
+  }
+}
+
+fn match_pattern(pattern: &Term, term: &Term, binds: &mut Bindings) -> bool {
+  match (pattern, term) {
+    (Term::Var { name }, _) if *name == hvm::VAR_NONE => true,
+    (Term::Var { name }, _) => {
+      let name = hvm::u128_to_name(*name);
+      match binds.get(&name) {
+        Some(bound) => bound == term,
+        None => {
+          binds.insert(name, term.clone());
+          true
+        }
+      }
+    }
+    (Term::Num { numb: x }, Term::Num { numb: y }) => x == y,
+    (Term::Ctr { name: x, args: xs }, Term::Ctr { name: y, args: ys }) => {
+      x == y && xs.len() == ys.len() && xs.iter().zip(ys).all(|(x, y)| match_pattern(x, y, binds))
+    }
+    _ => false,
+  }
+}
+
+// Finds where a pattern matches inside a term, outermost and leftmost first, with what its
+// variables were bound to
+pub fn find_matches(pattern: &Term, term: &Term) -> Vec<(Term, Bindings)> {
+  let mut found = vec![];
+  let mut stack = vec![term];
+  while let Some(term) = stack.pop() {
+    let mut binds = Bindings::new();
+    if match_pattern(pattern, term, &mut binds) {
+      found.push((term.clone(), binds));
+    }
+    match term {
+      Term::Var { .. } | Term::Num { .. } => {}
+      Term::Dup { expr, body, .. } => stack.extend([body, expr].map(|term| &**term)),
+      Term::Lam { body, .. } => stack.push(body),
+      Term::App { func, argm } => stack.extend([argm, func].map(|term| &**term)),
+      Term::Op2 { val0, val1, .. } => stack.extend([val1, val0].map(|term| &**term)),
+      Term::Ctr { args, .. } | Term::Fun { args, .. } => stack.extend(args.iter().rev()),
+    }
+  }
+  found
+}
@@ -0,0 +1,431 @@
+// Runtime Thread
+// ==============
+
+// The node's runtime lives on a thread of its own, which owns its state. The rest of the node
+// reaches it through a `RuntimeHandle`, which sends it the commands below and, for most of them,
+// waits for the answer. Commands run one at a time, in the order they were sent, so the runtime
+// needs no locks, and everything that touches the live state is listed here.
+//
+// Reads of the state at the tip don't wait for blocks being run: after each block, the thread
+// publishes a `StateView` of the committed state, which readers take from a `StateReader`.
+
+use std::collections::HashSet;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use primitive_types::U256;
+
+use crate::hvm::{self, CompFunc, NameInfo, NameKind, Runtime, Statement, StatementLimits, StatementResult, StatementUsage, Term, Upstream};
+use crate::upgrade::Activations;
+use crate::node::{execute_block, execute_block_measured, get_state_hash, Block};
+
+// Commands waiting for the runtime thread. Senders block once it's full.
+pub const RUNTIME_QUEUE : usize = 64;
+
+type Answer<T> = Sender<T>;
+
+pub enum RuntimeCommand {
+  // Runs a block on top of the current state and advances the tick. Answers the block's results,
+  // what its statements took to run, the hash of the state right after it, and the events emitted
+  // while running it, with their emitters. If it runs for
+  // longer than `budget`, it's aborted instead, the state going back to the newest snapshot, and
+  // the tick reached is answered.
+  RunBlock { block: Block, budget: Option<Duration>, tx: Answer<Result<BlockRun, u128>> },
+  // Reverts the state to the newest snapshot at or before a tick. Answers the tick reached.
+  Rollback { tick: u128, tx: Answer<u128> },
+  // Copies the state at the newest snapshot at or before a tick (see `Runtime::fork_at`), or
+  // else the genesis state, to be advanced elsewhere
+  Fork { tick: u128, tx: Answer<Runtime> },
+  // Copies the current state (see `Runtime::fork_tip`), to be run on elsewhere
+  ForkTip { tx: Answer<Runtime> },
+  GetSnapshotTicks { tx: Answer<Vec<u128>> },
+  GetStatus { tx: Answer<RuntimeStatus> },
+  GetStateHash { tx: Answer<U256> },
+  GetState { name: u128, tx: Answer<Option<Term>> },
+  GetFunc { name: u128, tx: Answer<Option<CompFunc>> },
+  ListNames { kind: Option<NameKind>, tx: Answer<Vec<NameInfo>> },
+  // Runs statements and undoes them
+  TestStatements { statements: Vec<Statement>, tx: Answer<Vec<StatementResult>> },
+  // Runs a block on top of the current state, then puts the state back as it was. Answers the
+  // block's results.
+  PrecheckBlock { block: Block, tx: Answer<Vec<StatementResult>> },
+  // Recovers the signer of a statement bound to the pool, so the block including it finds it cached
+  PrecheckSignature { statement: Statement },
+  SetUpstream { upstream: Option<Arc<dyn Upstream>> },
+  // Sets the zstd level of the heap buffers saved from now on (see `Runtime::set_compression`)
+  SetCompression { level: Option<i32> },
+  // Sets the size limit of saved states (see `Runtime::set_state_limit`)
+  SetStateLimit { limit: usize },
+  // Sets the heights consensus rule changes apply from (see `Runtime::set_activations`)
+  SetActivations { activations: Activations },
+  // Fails a share of the heap writes, on chaos mode (see `Runtime::set_disk_faults`)
+  SetDiskFaults { rate: u128, seed: u64 },
+  // Compacts the heap now (see `Runtime::compact_now`). Answers how many words were moved.
+  Compact { tx: Answer<u128> },
+}
+
+pub type BlockRun = (Vec<StatementResult>, Vec<StatementUsage>, U256, Vec<(u128, Term)>);
+
+#[derive(Debug, Clone, Copy)]
+pub struct RuntimeStatus {
+  pub tick: u128,
+  pub mana: u128,
+  pub mana_limit: u128,
+  pub size: i128,
+  pub size_limit: i128,
+  pub statement_limits: StatementLimits,
+  pub state_limit: usize,
+  pub activations: Activations,
+  pub mana_price: u128, // base mana price of the next block
+  pub burned: u128,     // mana fees burned so far
+}
+
+// The state as of a tick, read back. Cloning it is cheap, as the maps share their contents.
+#[derive(Clone, Default)]
+pub struct StateView {
+  pub tick: u128,
+  pub state_limit: usize,                   // size limit of saved states, serialized, in bytes
+  pub mana_price: u128,                     // base mana price of the next block
+  states: im::HashMap<u128, Arc<Term>>,
+  funcs: im::HashMap<u128, Arc<CompFunc>>,
+  owners: im::HashMap<u128, u128>,
+  hashes: im::HashMap<u128, U256>,          // code hash of each function (see `hvm::hash_func`)
+  codes: im::HashMap<U256, im::OrdSet<u128>>, // functions with each code hash
+}
+
+impl StateView {
+  pub fn get_state(&self, name: u128) -> Option<Term> {
+    self.states.get(&name).map(|state| (**state).clone())
+  }
+
+  // Size of a state, as saved states are limited on
+  pub fn get_state_size(&self, name: u128) -> Option<usize> {
+    self.states.get(&name).map(|state| hvm::state_size(state))
+  }
+
+  pub fn get_func(&self, name: u128) -> Option<CompFunc> {
+    self.funcs.get(&name).map(|func| (**func).clone())
+  }
+
+  // Names that have a state
+  pub fn get_functions(&self) -> HashSet<u128> {
+    self.states.keys().copied().collect()
+  }
+
+  // Owner of a registered namespace
+  pub fn get_owner(&self, name: u128) -> Option<u128> {
+    self.owners.get(&name).copied()
+  }
+
+  // Registered namespaces
+  pub fn get_namespaces(&self) -> HashSet<u128> {
+    self.owners.keys().copied().collect()
+  }
+
+  // Hash of the code of a function
+  pub fn get_code_hash(&self, name: u128) -> Option<U256> {
+    self.hashes.get(&name).copied()
+  }
+
+  // Up to `limit` functions, in name order, that come after the name `after`
+  pub fn get_function_page(&self, after: Option<u128>, limit: usize) -> Vec<u128> {
+    let mut names: Vec<u128> = self.funcs.keys().copied().filter(|name| after.map_or(true, |after| *name > after)).collect();
+    names.sort_unstable();
+    names.truncate(limit);
+    names
+  }
+
+  // Functions deployed with the code of a hash, in name order
+  pub fn get_code_names(&self, hash: &U256) -> Vec<u128> {
+    self.codes.get(hash).map(|names| names.iter().copied().collect()).unwrap_or_default()
+  }
+
+  // Reads `names` back again from the runtime
+  fn update(&mut self, runtime: &mut Runtime, names: impl IntoIterator<Item = u128>) {
+    self.tick = runtime.get_tick();
+    self.state_limit = runtime.get_state_limit();
+    self.mana_price = runtime.get_mana_price();
+    for name in names {
+      match runtime.read_disk_as_term(name) {
+        Some(state) => self.states.insert(name, Arc::new(state)),
+        None => self.states.remove(&name),
+      };
+      if let Some(hash) = self.hashes.remove(&name) {
+        if let Some(names) = self.codes.get_mut(&hash) {
+          names.remove(&name);
+          if names.is_empty() {
+            self.codes.remove(&hash);
+          }
+        }
+      }
+      match runtime.read_file(name) {
+        Some(func) => {
+          let hash = U256::from_big_endian(&hvm::hash_func(name, &func.func).0);
+          self.hashes.insert(name, hash);
+          self.codes.entry(hash).or_default().insert(name);
+          self.funcs.insert(name, Arc::new(func))
+        }
+        None => self.funcs.remove(&name),
+      };
+      match runtime.get_owner(name) {
+        hvm::U128_NONE => self.owners.remove(&name),
+        owner => self.owners.insert(name, owner),
+      };
+    }
+  }
+
+  // Reads the whole state back
+  fn rebuild(runtime: &mut Runtime) -> StateView {
+    let mut names = HashSet::new();
+    runtime.reduce_with(&mut names, |acc, heap| {
+      acc.extend(heap.disk.links.keys());
+      acc.extend(heap.file.funcs.keys());
+      acc.extend(heap.ownr.ownrs.keys());
+    });
+    runtime.take_touched();
+    let mut view = StateView::default();
+    view.update(runtime, names);
+    view
+  }
+}
+
+// Where the runtime thread publishes the latest state view
+#[derive(Clone)]
+pub struct StateReader {
+  view: Arc<RwLock<Arc<StateView>>>,
+}
+
+impl StateReader {
+  // The lock is only held to swap or copy the `Arc`
+  pub fn view(&self) -> Arc<StateView> {
+    self.view.read().unwrap().clone()
+  }
+
+  fn publish(&self, view: StateView) {
+    *self.view.write().unwrap() = Arc::new(view);
+  }
+}
+
+#[derive(Clone)]
+pub struct RuntimeHandle {
+  sender: SyncSender<RuntimeCommand>,
+  reader: StateReader,
+}
+
+impl RuntimeHandle {
+  // Moves a runtime to a new thread, which serves it until every handle is dropped
+  pub fn spawn(mut runtime: Runtime) -> RuntimeHandle {
+    let (sender, receiver) = mpsc::sync_channel(RUNTIME_QUEUE);
+    let view = StateView::rebuild(&mut runtime);
+    let reader = StateReader { view: Arc::new(RwLock::new(Arc::new(view.clone()))) };
+    let thread_reader = reader.clone();
+    thread::Builder::new()
+      .name("runtime".to_string())
+      .spawn(move || runtime_loop(runtime, view, thread_reader, receiver))
+      .expect("Couldn't start the runtime thread.");
+    RuntimeHandle { sender, reader }
+  }
+
+  pub fn reader(&self) -> StateReader {
+    self.reader.clone()
+  }
+
+  fn send(&self, command: RuntimeCommand) {
+    self.sender.send(command).expect("The runtime thread crashed.");
+  }
+
+  fn ask<T>(&self, command: impl FnOnce(Answer<T>) -> RuntimeCommand) -> T {
+    let (tx, rx) = mpsc::channel();
+    self.send(command(tx));
+    rx.recv().expect("The runtime thread crashed.")
+  }
+
+  pub fn run_block(&self, block: &Block) -> BlockRun {
+    let run = self.ask(|tx| RuntimeCommand::RunBlock { block: block.clone(), budget: None, tx });
+    run.expect("A block without a time budget was aborted.")
+  }
+
+  // Runs a block, unless it takes longer than `budget`. Answers the tick reached otherwise.
+  pub fn run_block_within(&self, block: &Block, budget: Duration) -> Result<BlockRun, u128> {
+    self.ask(|tx| RuntimeCommand::RunBlock { block: block.clone(), budget: Some(budget), tx })
+  }
+
+  pub fn rollback(&self, tick: u128) -> u128 {
+    self.ask(|tx| RuntimeCommand::Rollback { tick, tx })
+  }
+
+  pub fn fork(&self, tick: u128) -> Runtime {
+    self.ask(|tx| RuntimeCommand::Fork { tick, tx })
+  }
+
+  pub fn fork_tip(&self) -> Runtime {
+    self.ask(|tx| RuntimeCommand::ForkTip { tx })
+  }
+
+  pub fn get_snapshot_ticks(&self) -> Vec<u128> {
+    self.ask(|tx| RuntimeCommand::GetSnapshotTicks { tx })
+  }
+
+  pub fn get_status(&self) -> RuntimeStatus {
+    self.ask(|tx| RuntimeCommand::GetStatus { tx })
+  }
+
+  pub fn get_tick(&self) -> u128 {
+    self.get_status().tick
+  }
+
+  pub fn get_state_hash(&self) -> U256 {
+    self.ask(|tx| RuntimeCommand::GetStateHash { tx })
+  }
+
+  pub fn read_state(&self, name: u128) -> Option<Term> {
+    self.ask(|tx| RuntimeCommand::GetState { name, tx })
+  }
+
+  pub fn read_func(&self, name: u128) -> Option<CompFunc> {
+    self.ask(|tx| RuntimeCommand::GetFunc { name, tx })
+  }
+
+  pub fn list_names(&self, kind: Option<NameKind>) -> Vec<NameInfo> {
+    self.ask(|tx| RuntimeCommand::ListNames { kind, tx })
+  }
+
+  pub fn test_statements(&self, statements: Vec<Statement>) -> Vec<StatementResult> {
+    self.ask(|tx| RuntimeCommand::TestStatements { statements, tx })
+  }
+
+  pub fn precheck_block(&self, block: &Block) -> Vec<StatementResult> {
+    self.ask(|tx| RuntimeCommand::PrecheckBlock { block: block.clone(), tx })
+  }
+
+  // Doesn't wait for the runtime
+  pub fn precheck_signature(&self, statement: Statement) {
+    self.send(RuntimeCommand::PrecheckSignature { statement });
+  }
+
+  pub fn set_upstream(&self, upstream: Option<Arc<dyn Upstream>>) {
+    self.send(RuntimeCommand::SetUpstream { upstream });
+  }
+
+  pub fn set_compression(&self, level: Option<i32>) {
+    self.send(RuntimeCommand::SetCompression { level });
+  }
+
+  pub fn set_state_limit(&self, limit: usize) {
+    self.send(RuntimeCommand::SetStateLimit { limit });
+  }
+
+  pub fn set_activations(&self, activations: Activations) {
+    self.send(RuntimeCommand::SetActivations { activations });
+  }
+
+  pub fn set_disk_faults(&self, rate: u128, seed: u64) {
+    self.send(RuntimeCommand::SetDiskFaults { rate, seed });
+  }
+
+  pub fn compact(&self) -> u128 {
+    self.ask(|tx| RuntimeCommand::Compact { tx })
+  }
+}
+
+// Answers are sent without checking: a requester that went away doesn't need them
+fn runtime_loop(mut runtime: Runtime, mut view: StateView, reader: StateReader, receiver: Receiver<RuntimeCommand>) {
+  while let Ok(command) = receiver.recv() {
+    match command {
+      RuntimeCommand::RunBlock { block, budget, tx } => {
+        runtime.set_deadline(budget.map(|budget| Instant::now() + budget));
+        runtime.take_emitted();
+        let (results, usage): (Vec<_>, Vec<_>) = execute_block_measured(&mut runtime, &block, false).into_iter().unzip();
+        let timed_out = runtime.timed_out();
+        runtime.set_deadline(None);
+        if timed_out {
+          tx.send(Err(runtime.discard())).ok();
+          continue;
+        }
+        runtime.tick();
+        // Published before answering, so readers told about the block find its state
+        let touched = runtime.take_touched();
+        view.update(&mut runtime, touched);
+        reader.publish(view.clone());
+        tx.send(Ok((results, usage, get_state_hash(&runtime), runtime.take_emitted()))).ok();
+      }
+      RuntimeCommand::Rollback { tick, tx } => {
+        runtime.rollback(tick);
+        view = StateView::rebuild(&mut runtime);
+        reader.publish(view.clone());
+        tx.send(runtime.get_tick()).ok();
+      }
+      RuntimeCommand::ForkTip { tx } => {
+        tx.send(runtime.fork_tip()).ok();
+      }
+      RuntimeCommand::Fork { tick, tx } => {
+        let mut fork = runtime.fork_at(tick).unwrap_or_else(hvm::init_scratch_runtime);
+        fork.set_upstream(runtime.get_upstream());
+        fork.set_state_limit(runtime.get_state_limit());
+        fork.set_activations(runtime.get_activations());
+        tx.send(fork).ok();
+      }
+      RuntimeCommand::GetSnapshotTicks { tx } => {
+        tx.send(runtime.get_snapshot_ticks()).ok();
+      }
+      RuntimeCommand::GetStatus { tx } => {
+        let status = RuntimeStatus {
+          tick: runtime.get_tick(),
+          mana: runtime.get_mana(),
+          mana_limit: runtime.get_mana_limit(),
+          size: runtime.get_size(),
+          size_limit: runtime.get_size_limit(),
+          statement_limits: runtime.get_statement_limits(),
+          state_limit: runtime.get_state_limit(),
+          activations: runtime.get_activations(),
+          mana_price: runtime.get_mana_price(),
+          burned: runtime.get_burned(),
+        };
+        tx.send(status).ok();
+      }
+      RuntimeCommand::GetStateHash { tx } => {
+        tx.send(get_state_hash(&runtime)).ok();
+      }
+      RuntimeCommand::GetState { name, tx } => {
+        tx.send(runtime.read_disk_as_term(name)).ok();
+      }
+      RuntimeCommand::GetFunc { name, tx } => {
+        tx.send(runtime.read_file(name)).ok();
+      }
+      RuntimeCommand::ListNames { kind, tx } => {
+        tx.send(runtime.list_names(kind)).ok();
+      }
+      RuntimeCommand::TestStatements { statements, tx } => {
+        tx.send(runtime.test_statements(&statements)).ok();
+      }
+      RuntimeCommand::PrecheckBlock { block, tx } => {
+        tx.send(runtime.simulate(|runtime| execute_block(runtime, &block, true))).ok();
+      }
+      RuntimeCommand::PrecheckSignature { statement } => {
+        runtime.precheck_signature(&statement);
+      }
+      RuntimeCommand::SetUpstream { upstream } => {
+        runtime.set_upstream(upstream);
+      }
+      RuntimeCommand::SetCompression { level } => {
+        runtime.set_compression(level);
+      }
+      RuntimeCommand::SetStateLimit { limit } => {
+        runtime.set_state_limit(limit);
+        view.state_limit = limit;
+        reader.publish(view.clone());
+      }
+      RuntimeCommand::SetActivations { activations } => {
+        runtime.set_activations(activations);
+      }
+      RuntimeCommand::SetDiskFaults { rate, seed } => {
+        runtime.set_disk_faults(rate, seed);
+      }
+      RuntimeCommand::Compact { tx } => {
+        tx.send(runtime.compact_now()).ok();
+      }
+    }
+  }
+}
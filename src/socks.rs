@@ -0,0 +1,160 @@
+// SOCKS5 proxies
+// ==============
+
+// Sends and receives UDP datagrams through a SOCKS5 proxy (RFC 1928), with the UDP ASSOCIATE
+// command. The proxy relays datagrams while the TCP control connection stays open. Each datagram
+// exchanged with the relay starts with a header holding the remote address.
+//
+// Tor doesn't relay UDP, so it can't carry this node's UDP traffic. The relay is meant for proxies
+// that do relay UDP, such as Dante or 3proxy. Onion peers are reached over TCP instead, with the
+// CONNECT command, which Tor supports.
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+const VERSION : u8 = 5;
+const NO_AUTH : u8 = 0;
+const CONNECT : u8 = 1;
+const UDP_ASSOCIATE : u8 = 3;
+const ATYP_IPV4 : u8 = 1;
+const ATYP_DOMAIN : u8 = 3;
+const ATYP_IPV6 : u8 = 4;
+
+// Time to wait for the proxy to answer the handshake
+const HANDSHAKE_TIMEOUT : Duration = Duration::from_secs(10);
+
+// Time to wait for the proxy to connect, as building a circuit to an onion service is slow
+const CONNECT_TIMEOUT : Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct Socks5Relay {
+  _control: TcpStream,   // closing it ends the association
+  pub relay: SocketAddr, // where datagrams are sent to, and come from
+}
+
+impl Socks5Relay {
+  // Asks the proxy to relay the datagrams sent from `local`, the address of a UDP socket
+  pub fn associate(proxy: SocketAddr, local: SocketAddr) -> std::io::Result<Socks5Relay> {
+    let mut control = TcpStream::connect_timeout(&proxy, HANDSHAKE_TIMEOUT)?;
+    control.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    control.write_all(&[VERSION, 1, NO_AUTH])?;
+    let mut answer = [0; 2];
+    control.read_exact(&mut answer)?;
+    if answer != [VERSION, NO_AUTH] {
+      return Err(error("proxy requires an unsupported authentication method"));
+    }
+    let mut request = vec![VERSION, UDP_ASSOCIATE, 0];
+    write_address(&mut request, local);
+    control.write_all(&request)?;
+    let mut answer = [0; 3];
+    control.read_exact(&mut answer)?;
+    if answer[1] != 0 {
+      return Err(error(&format!("proxy refused to relay UDP (reply {})", answer[1])));
+    }
+    let mut relay = read_address(&mut control)?;
+    // A relay on an unspecified address is on the proxy's own host
+    if relay.ip().is_unspecified() {
+      relay.set_ip(proxy.ip());
+    }
+    control.set_read_timeout(None)?;
+    return Ok(Socks5Relay { _control: control, relay });
+  }
+
+  // Prefixes a datagram to `to` with the relay header
+  pub fn wrap(&self, to: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let mut datagram = Vec::with_capacity(data.len() + 22);
+    datagram.extend_from_slice(&[0, 0, 0]); // reserved, fragment number
+    write_address(&mut datagram, to);
+    datagram.extend_from_slice(data);
+    return datagram;
+  }
+
+  // Splits a datagram from the relay into its sender and contents. Fragments are dropped.
+  pub fn unwrap(datagram: &[u8]) -> Option<(SocketAddr, &[u8])> {
+    if datagram.len() < 4 || datagram[2] != 0 {
+      return None;
+    }
+    let mut rest = &datagram[3 ..];
+    let from = read_address(&mut rest).ok()?;
+    return Some((from, rest));
+  }
+}
+
+// Opens a TCP connection to `host:port` through a SOCKS5 proxy. The proxy resolves the host, so
+// it may be an onion address when the proxy is Tor.
+pub async fn connect(proxy: SocketAddr, host: &str, port: u16) -> std::io::Result<tokio::net::TcpStream> {
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+  if host.len() > u8::MAX as usize {
+    return Err(error("host name too long"));
+  }
+  let handshake = async {
+    let mut stream = tokio::net::TcpStream::connect(proxy).await?;
+    stream.write_all(&[VERSION, 1, NO_AUTH]).await?;
+    let mut answer = [0; 2];
+    stream.read_exact(&mut answer).await?;
+    if answer != [VERSION, NO_AUTH] {
+      return Err(error("proxy requires an unsupported authentication method"));
+    }
+    let mut request = vec![VERSION, CONNECT, 0, ATYP_DOMAIN, host.len() as u8];
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+    let mut answer = [0; 4];
+    stream.read_exact(&mut answer).await?;
+    if answer[1] != 0 {
+      return Err(error(&format!("proxy couldn't connect to {}:{} (reply {})", host, port, answer[1])));
+    }
+    // Skips the address the proxy connected from, which isn't needed
+    let bound = match answer[3] {
+      ATYP_IPV4 => 4,
+      ATYP_IPV6 => 16,
+      ATYP_DOMAIN => stream.read_u8().await? as usize,
+      _ => return Err(error("unsupported address type")),
+    };
+    stream.read_exact(&mut vec![0; bound + 2]).await?;
+    return Ok(stream);
+  };
+  return tokio::time::timeout(CONNECT_TIMEOUT, handshake).await.unwrap_or_else(|_| Err(error("proxy timed out")));
+}
+
+fn write_address(bytes: &mut Vec<u8>, addr: SocketAddr) {
+  match addr.ip() {
+    IpAddr::V4(ip) => {
+      bytes.push(ATYP_IPV4);
+      bytes.extend_from_slice(&ip.octets());
+    }
+    IpAddr::V6(ip) => {
+      bytes.push(ATYP_IPV6);
+      bytes.extend_from_slice(&ip.octets());
+    }
+  }
+  bytes.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+fn read_address(source: &mut impl Read) -> std::io::Result<SocketAddr> {
+  let mut atyp = [0; 1];
+  source.read_exact(&mut atyp)?;
+  let ip = match atyp[0] {
+    ATYP_IPV4 => {
+      let mut octets = [0; 4];
+      source.read_exact(&mut octets)?;
+      IpAddr::V4(Ipv4Addr::from(octets))
+    }
+    ATYP_IPV6 => {
+      let mut octets = [0; 16];
+      source.read_exact(&mut octets)?;
+      IpAddr::V6(Ipv6Addr::from(octets))
+    }
+    _ => {
+      return Err(error("unsupported address type"));
+    }
+  };
+  let mut port = [0; 2];
+  source.read_exact(&mut port)?;
+  return Ok(SocketAddr::new(ip, u16::from_be_bytes(port)));
+}
+
+fn error(msg: &str) -> std::io::Error {
+  std::io::Error::new(std::io::ErrorKind::Other, format!("SOCKS5: {}", msg))
+}
@@ -0,0 +1,73 @@
+use std::path::Path;
+
+use crate::backup::{list_backups, restore_backup, write_backup, BackupConfig, BackupSchedule};
+use crate::datadir::DATA_VERSION;
+use crate::test::util::temp_dir;
+
+fn write_data(path: &Path, blocks: &[&str], heap: &str) {
+  let state = path.join("state");
+  std::fs::create_dir_all(state.join("blocks")).unwrap();
+  std::fs::create_dir_all(state.join("heaps")).unwrap();
+  for block in blocks {
+    std::fs::write(state.join("blocks").join(block), block.repeat(100)).unwrap();
+  }
+  std::fs::write(state.join("heaps").join("_uuids_"), heap).unwrap();
+  std::fs::write(path.join("VERSION"), format!("{}\n", DATA_VERSION)).unwrap();
+}
+
+#[test]
+fn backups_are_taken_every_n_blocks_and_pruned() {
+  let dir = temp_dir();
+  let config = BackupConfig { every: 10, keep: 2, compress: false, level: 3, dir: None };
+  let mut schedule = BackupSchedule::new(config, &dir.path);
+  assert_eq!(schedule.dir, dir.path.join("backups"));
+  // the height the node starts at isn't backed up again
+  assert!(!schedule.due(25));
+  assert!(!schedule.due(29));
+  assert!(schedule.due(30));
+
+  write_data(&dir.path, &["a"], "1");
+  let mut taken = vec![];
+  for height in [30, 40, 50] {
+    taken.push(schedule.run(&dir.path, height).unwrap());
+    std::thread::sleep(std::time::Duration::from_millis(20));
+  }
+  assert_eq!(schedule.last, Some(50));
+  assert!(!schedule.due(59));
+  assert!(schedule.due(61));
+  assert_eq!(taken[2].file_name().unwrap(), "backup-000000000050.tar");
+  // only the newest are kept, and no temporary file is left
+  assert_eq!(list_backups(&schedule.dir).unwrap(), vec![taken[2].clone(), taken[1].clone()]);
+  assert_eq!(std::fs::read_dir(&schedule.dir).unwrap().count(), 2);
+}
+
+#[test]
+fn backups_are_restored_over_the_data() {
+  for level in [None, Some(3)] {
+    let dir = temp_dir();
+    let data = dir.path.join("data");
+    write_data(&data, &["a", "b"], "before");
+    let file = write_backup(&data, &dir.path.join("backups"), 2, level).unwrap();
+    assert_eq!(file.to_str().unwrap().ends_with(".zst"), level.is_some());
+
+    // the data goes on, and breaks
+    write_data(&data, &["c"], "after");
+    std::fs::write(data.join("VERSION"), "0\n").unwrap();
+    assert_eq!(restore_backup(&file, &data).unwrap(), 2);
+    let mut blocks: Vec<String> = std::fs::read_dir(data.join("state").join("blocks")).unwrap()
+      .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+      .collect();
+    blocks.sort();
+    assert_eq!(blocks, vec!["a", "b"]);
+    assert_eq!(std::fs::read_to_string(data.join("state").join("blocks").join("b")).unwrap(), "b".repeat(100));
+    assert_eq!(std::fs::read_to_string(data.join("state").join("heaps").join("_uuids_")).unwrap(), "before");
+    assert_eq!(std::fs::read_to_string(data.join("VERSION")).unwrap().trim(), DATA_VERSION.to_string());
+    assert!(!data.join("state.old").exists() && !data.join(".restore.tmp").exists());
+
+    // a bad backup leaves the data as it was
+    let bad = dir.path.join("bad.tar");
+    std::fs::write(&bad, "not a backup").unwrap();
+    assert!(restore_backup(&bad, &data).is_err());
+    assert_eq!(std::fs::read_to_string(data.join("state").join("heaps").join("_uuids_")).unwrap(), "before");
+  }
+}
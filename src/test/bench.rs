@@ -0,0 +1,36 @@
+use rstest::rstest;
+
+use crate::{
+  bench::{bench_chain, ChainBench},
+  test::util::{temp_dir, TempDir},
+};
+
+#[rstest]
+fn counter_blocks_take_every_statement(temp_dir: TempDir) {
+  let bench = ChainBench { statements_per_block: 4, blocks: 3, bank: 0, seed: 0 };
+  let report = bench_chain(&bench, &temp_dir.path).unwrap();
+  assert_eq!((report.requested, report.included), (12, 12));
+  assert!(report.errors.is_empty());
+  assert_eq!(report.mana.len(), 3);
+  assert!(report.mana.iter().all(|mana| *mana > 0));
+  assert_eq!(report.size_growth(), 0);
+}
+
+#[rstest]
+fn blocks_are_cut_at_the_body_size(temp_dir: TempDir) {
+  // signed transfers are over 100 bytes each, so a body can't take 50 of them
+  let bench = ChainBench { statements_per_block: 50, blocks: 2, bank: 100, seed: 0 };
+  let report = bench_chain(&bench, &temp_dir.path).unwrap();
+  assert_eq!(report.requested, 100);
+  assert!(report.included < report.requested);
+  assert!(report.body_bytes <= 2 * crate::node::MAX_BODY_SIZE);
+  assert!(report.size_growth() > 0);
+}
+
+#[test]
+fn workloads_are_seeded() {
+  let bench = ChainBench { statements_per_block: 8, blocks: 4, bank: 50, seed: 7 };
+  let a = bench_chain(&bench, &temp_dir().path).unwrap();
+  let b = bench_chain(&bench, &temp_dir().path).unwrap();
+  assert_eq!((a.included, &a.errors, &a.mana, a.size_end), (b.included, &b.errors, &b.mana, b.size_end));
+}
@@ -1,18 +1,23 @@
 use std::collections::HashMap;
 
 use crate::{
+  api::{http::{decode_bytes, decode_payload, PayloadKind}, Decoded},
   bits::{
     deserialize_fixlen, deserialize_list, deserialize_varlen, deserialized_message,
-    deserialized_statements, serialize_fixlen, serialize_list, serialize_varlen,
-    serialized_message, serialized_statements,
+    deserialized_statements, serialize_block, serialize_bytes, serialize_fixlen, serialize_list, serialize_list_of,
+    serialize_statement, serialize_varlen, serialized_each, serialized_each_on, serialized_message,
+    serialized_block, serialized_statement, serialized_statements, serialized_term, deserialized_term,
   },
-  hvm::{view_statements, Term},
-  node::Message,
-  test::strategies::{message, statement, u256 as u256_strategy},
+  hvm::{name_to_u128, view_statement, view_statements, Term, BSW, NEQ},
+  node::{
+    extract_payout, extract_transactions, new_block, statements_to_body, transactions_to_body, Body, Message,
+    Transaction, MAX_BODY_SIZE,
+  },
+  test::{strategies::{block, message, statement, term, u256 as u256_strategy}, util::assert_terms_eq},
   util::u256,
 };
 use bit_vec::BitVec;
-use proptest::{collection::vec, proptest};
+use proptest::{collection::vec, prelude::ProptestConfig, proptest, strategy::{Strategy, ValueTree}, test_runner::TestRunner};
 
 proptest! {
   #[test]
@@ -30,6 +35,76 @@ proptest! {
     let message2 = deserialized_message(&bits).unwrap();
     assert_eq!(format!("{:?}", message), format!("{:?}", message2));
   }
+
+  #[test]
+  fn body_payout(datas in vec(vec(proptest::num::u8::ANY, 1..200), 0..20), payout in 0 .. (1u128 << 120)) {
+    let transactions: Vec<_> = datas.into_iter().map(Transaction::new).collect();
+    let plain = transactions_to_body(transactions.iter(), None);
+    let paying = transactions_to_body(transactions.iter(), Some(payout));
+    assert!(paying.data.len() <= MAX_BODY_SIZE);
+    assert_eq!(extract_payout(&plain), None);
+    assert_eq!(extract_payout(&paying), Some(payout));
+    // the payout takes room from the transactions, but doesn't change them
+    let txs = extract_transactions(&paying);
+    assert!(txs.len() <= extract_transactions(&plain).len());
+    assert_eq!(txs, transactions[.. txs.len()]);
+  }
+}
+
+proptest! {
+  // statements are slow to generate, so fewer cases are tried
+  #![proptest_config(ProptestConfig::with_cases(32))]
+
+  #[test]
+  fn serialize_in_parallel(statements in vec(statement(), 0..20)) {
+    // repeated, so that there are enough to be split among threads
+    let statements: Vec<_> = statements.iter().cycle().take(statements.len() * 4).cloned().collect();
+    let sequential: Vec<_> = statements.iter().map(serialized_statement).collect();
+    for threads in [1, 3, 8] {
+      assert_eq!(serialized_each_on(threads, serialize_statement, &statements), sequential);
+    }
+  }
+
+  #[test]
+  fn statements_to_body_roundtrip(statements in vec(statement(), 0..20)) {
+    let body = statements_to_body(&statements);
+    let read: Vec<_> = extract_transactions(&body).iter().map(|t| view_statement(&t.to_statement().unwrap())).collect();
+    assert!(read.len() <= statements.len());
+    assert_eq!(read, statements[.. read.len()].iter().map(view_statement).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn serialize_bytes_as_fixlen(bytes in vec(proptest::num::u8::ANY, 0..64)) {
+    let mut bits = BitVec::new();
+    serialize_bytes(bytes.len() as u128, &bytes, &mut bits, &mut HashMap::new());
+    let mut expected = BitVec::new();
+    for byte in &bytes {
+      serialize_fixlen(8, &u256(*byte as u128), &mut expected, &mut HashMap::new());
+    }
+    assert_eq!(bits, expected);
+  }
+
+  #[test]
+  fn term_json_roundtrip(term in term()) {
+    let json = serde_json::to_string(&term).unwrap();
+    let read: Term = serde_json::from_str(&json).unwrap();
+    assert_terms_eq(&read, &term);
+  }
+
+  #[test]
+  fn decode_payloads(statement in statement(), block in block()) {
+    let bytes = crate::util::bitvec_to_bytes(&serialized_statement(&statement));
+    for text in [hex::encode(&bytes), format!("0x{}", hex::encode(&bytes)), base64::encode(&bytes)] {
+      let decoded = decode_payload(&decode_bytes(&text).unwrap(), PayloadKind::Statement).unwrap();
+      let Decoded::Statement { text, .. } = decoded else { panic!("expected a statement") };
+      assert_eq!(text, view_statement(&statement));
+    }
+    let bytes = crate::util::bitvec_to_bytes(&serialized_block(&block));
+    let decoded = decode_payload(&bytes, PayloadKind::Block).unwrap();
+    let Decoded::Block { hash, .. } = decoded else { panic!("expected a block") };
+    let hash: crate::util::U256 = hash.into();
+    assert_eq!(hash, block.hash);
+  }
 }
 
 #[test]
@@ -86,3 +161,77 @@ pub fn test_serializer_2() {
   .unwrap();
   assert_eq!(vals, gots);
 }
+
+#[test]
+fn operations_keep_their_bits() {
+  // `!=` is written as it was before the operations after it: the Op2 tag, then its 4-bit code
+  let num = |numb| Term::Num { numb };
+  let neq = Term::Op2 { oper: NEQ, val0: Box::new(num(1)), val1: Box::new(num(2)) };
+  let mut expected = BitVec::new();
+  serialize_fixlen(3, &u256(7), &mut expected, &mut HashMap::new());
+  serialize_fixlen(4, &u256(15), &mut expected, &mut HashMap::new());
+  expected.append(&mut serialized_term(&num(1)));
+  expected.append(&mut serialized_term(&num(2)));
+  assert_eq!(serialized_term(&neq), expected);
+  // and every operation reads back
+  for oper in 0 ..= BSW {
+    let term = Term::Op2 { oper, val0: Box::new(num(1)), val1: Box::new(Term::Var { name: name_to_u128("x") }) };
+    assert_eq!(deserialized_term(&serialized_term(&term)), Some(term));
+  }
+}
+
+// Benchmarks
+// ==========
+
+fn sample<T>(strategy: impl Strategy<Value = T>, count: usize) -> Vec<T> {
+  let mut runner = TestRunner::deterministic();
+  (0 .. count).map(|_| strategy.new_tree(&mut runner).unwrap().current()).collect()
+}
+
+fn elapsed_ms(iters: u32, mut run: impl FnMut()) -> f64 {
+  let init_time = std::time::Instant::now();
+  for _ in 0 .. iters {
+    run();
+  }
+  init_time.elapsed().as_secs_f64() * 1000.0 / iters as f64
+}
+
+#[test]
+#[ignore = "benchmark"]
+fn bench_serialize_blocks() {
+  // full blocks, as gossiped
+  let blocks: Vec<_> = sample(block(), 64).into_iter().map(|b| new_block(b.prev, b.time, b.meta, Body { data: vec![0xAB; 1280] })).collect();
+  let per_bit = elapsed_ms(20, || {
+    let mut bits = BitVec::new();
+    for block in &blocks {
+      for byte in &block.body.data {
+        serialize_fixlen(8, &u256(*byte as u128), &mut bits, &mut HashMap::new());
+      }
+    }
+  });
+  let sequential = elapsed_ms(20, || {
+    let mut bits = BitVec::new();
+    serialize_list(serialize_block, &blocks, &mut bits, &mut HashMap::new());
+  });
+  let parallel = elapsed_ms(20, || {
+    let mut bits = BitVec::new();
+    serialize_list_of(serialized_each(serialize_block, &blocks), &mut bits);
+  });
+  println!(
+    "block serialization benchmark: {} ms (bodies bit by bit) vs {} ms (sequential) vs {} ms (parallel) for {} blocks",
+    per_bit, sequential, parallel, blocks.len()
+  );
+}
+
+#[test]
+#[ignore = "benchmark"]
+fn bench_serialize_statements() {
+  let statements = sample(statement(), 256);
+  let sequential = elapsed_ms(20, || {
+    serialized_each_on(1, serialize_statement, &statements);
+  });
+  let parallel = elapsed_ms(20, || {
+    serialized_each(serialize_statement, &statements);
+  });
+  println!("statement serialization benchmark: {} ms (sequential) vs {} ms (parallel) for {} statements", sequential, parallel, statements.len());
+}
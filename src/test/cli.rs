@@ -0,0 +1,56 @@
+use clap::{CommandFactory, Parser};
+
+use crate::{color::ColorMode, hvm, read_extra_data, show_statement_hashes, upgrade, Cli, OutputFormat};
+
+#[test]
+fn output_format_is_global() {
+  let cli = Cli::try_parse_from(["kindelia", "util", "name-to-num", "Foo", "--output", "json"]).unwrap();
+  assert_eq!(cli.output, OutputFormat::Json);
+  let cli = Cli::try_parse_from(["kindelia", "util", "name-to-num", "Foo"]).unwrap();
+  assert_eq!(cli.output, OutputFormat::Text);
+  assert!(Cli::try_parse_from(["kindelia", "--output", "yaml", "profiles"]).is_err());
+}
+
+#[test]
+fn color_mode_is_global() {
+  let cli = Cli::try_parse_from(["kindelia", "state", "get", "Foo", "--color", "never"]).unwrap();
+  assert_eq!(cli.color, ColorMode::Never);
+  let cli = Cli::try_parse_from(["kindelia", "profiles"]).unwrap();
+  assert_eq!(cli.color, ColorMode::Auto);
+  assert!(Cli::try_parse_from(["kindelia", "--color", "sometimes", "profiles"]).is_err());
+}
+
+#[test]
+fn completions_cover_subcommands() {
+  let mut script = vec![];
+  clap_complete::generate(clap_complete::Shell::Bash, &mut Cli::command(), "kindelia", &mut script);
+  let script = String::from_utf8(script).unwrap();
+  for command in ["start", "state", "completions", "--output"] {
+    assert!(script.contains(command), "missing {}", command);
+  }
+}
+
+#[test]
+fn statement_hashes_show_the_signed_digest() {
+  let (_, statement) = hvm::read_statement("run { (Done #7) }").unwrap();
+  for network in [hvm::NETWORK_ID, hvm::NETWORK_ID + 1] {
+    let (text, json) = show_statement_hashes(&statement, network);
+    let hash = format!("0x{}", hex::encode(hvm::hash_statement(&statement).0));
+    let digest = format!("0x{}", hex::encode(hvm::SignPayload::new(&statement, network).digest().0));
+    assert_eq!(json, serde_json::json!({ "hash": hash, "digest": digest, "network": network }));
+    assert!(text.contains(&hash) && text.contains(&digest));
+  }
+  // the digest is what signatures sign, so it differs from the hash, and across networks
+  let (_, this) = show_statement_hashes(&statement, hvm::NETWORK_ID);
+  let (_, other) = show_statement_hashes(&statement, hvm::NETWORK_ID + 1);
+  assert_ne!(this["digest"], this["hash"]);
+  assert_ne!(this["digest"], other["digest"]);
+}
+
+#[test]
+fn extra_data_cant_pass_for_signals() {
+  assert_eq!(read_extra_data("pool").unwrap(), b"pool");
+  assert_eq!(read_extra_data("0x02ff").unwrap(), vec![0x02, 0xff]);
+  assert!(read_extra_data(&format!("0x{:02x}ff", upgrade::SIGNAL_TAG)).is_err());
+  assert!(read_extra_data("\u{1}pool").is_err());
+}
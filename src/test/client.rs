@@ -0,0 +1,43 @@
+use std::sync::mpsc;
+
+use warp::Filter as _;
+
+use crate::api::client::ApiClient;
+use crate::api::Stats;
+
+// Serves `/tick` on a local port, as a node at this tick would. The tick is a `u64`, as
+// `serde_json` values don't hold `u128`s.
+fn node(tick: u64) -> String {
+  let route = warp::path!("tick").map(move || warp::reply::json(&serde_json::json!({ "status": "ok", "data": { "tick": tick } })));
+  let (addr_tx, addr_rx) = mpsc::channel();
+  std::thread::spawn(move || {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    runtime.block_on(async move {
+      let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+      addr_tx.send(addr).unwrap();
+      server.await;
+    });
+  });
+  format!("http://{}", addr_rx.recv().unwrap())
+}
+
+// A URL nothing listens on
+fn dead_node() -> String {
+  let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+  format!("http://{}", listener.local_addr().unwrap())
+}
+
+#[test]
+fn client_falls_over_to_reachable_nodes() {
+  let dead = dead_node();
+  let live = node(10);
+  let client = ApiClient::with_nodes(&[dead.clone(), live.clone()]);
+  let stats: Stats = client.get("/tick").unwrap().unwrap();
+  assert_eq!(stats.tick, 10);
+  // the node that answered is kept for the next requests
+  assert_eq!(client.url(), live);
+  assert!(client.get::<Stats>("/tick").is_ok());
+
+  let client = ApiClient::with_nodes(&[dead.clone(), dead_node()]);
+  assert!(client.get::<Stats>("/tick").is_err());
+}
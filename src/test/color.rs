@@ -0,0 +1,32 @@
+use crate::{
+  color::{ColorMode, Colors, Style},
+  hvm::read_statements,
+};
+
+#[test]
+fn code_is_colored_by_kind() {
+  let colors = Colors::new(ColorMode::Always, false);
+  let code = "fun (Get x) {\n  (Get {Box @y y}) = (+ #2 ...)\n}";
+  let shown = colors.code(code);
+  for (style, text) in [(Style::Keyword, "fun"), (Style::Fun, "Get"), (Style::Ctr, "Box"), (Style::Var, "y"), (Style::Num, "#2"), (Style::Dim, "...")] {
+    assert!(shown.contains(&colors.paint(style, text)), "{} isn't {:?}", text, style);
+  }
+  // The variable after `@` is colored, but not where it's used
+  assert!(shown.contains("\x1b[0m y}"));
+  assert_eq!(Colors::new(ColorMode::Never, true).code(code), code);
+  assert_eq!(Colors::new(ColorMode::Auto, false).code(code), code);
+}
+
+#[test]
+fn parse_errors_point_at_their_location() {
+  let code = "ctr {Pair a b}\n\nfun (Foo a) {\n  (Foo a) = (Bar a}\n}";
+  let err = read_statements(code).unwrap_err();
+  let shown = Colors::new(ColorMode::Never, false).parse_error(code, &err);
+  assert_eq!(shown, [
+    "Expected identifier, found `}`.",
+    " --> line 4, column 19",
+    "  |",
+    "4 |   (Foo a) = (Bar a}",
+    "  |                   ^",
+  ].join("\n"));
+}
@@ -0,0 +1,41 @@
+use crate::datadir::{migrate, read_version, DATA_VERSION};
+use crate::test::util::temp_dir;
+
+#[test]
+fn new_directories_are_current() {
+  let dir = temp_dir();
+  assert_eq!(read_version(&dir.path).unwrap(), DATA_VERSION);
+  assert!(migrate(&dir.path, false).unwrap().is_empty());
+  assert_eq!(std::fs::read_to_string(dir.path.join("VERSION")).unwrap().trim(), DATA_VERSION.to_string());
+}
+
+#[test]
+fn unversioned_directories_are_migrated() {
+  let dir = temp_dir();
+  let heaps = dir.path.join("state").join("heaps");
+  let blocks = dir.path.join("state").join("blocks");
+  std::fs::create_dir_all(&heaps).unwrap();
+  std::fs::create_dir_all(&blocks).unwrap();
+  std::fs::write(heaps.join("_uuids_"), [0u8; 16]).unwrap();
+  assert_eq!(read_version(&dir.path).unwrap(), 0);
+
+  // a dry run only lists the migrations
+  let steps = migrate(&dir.path, true).unwrap();
+  assert_eq!(steps.iter().map(|step| step.from).collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+  assert!(heaps.join("_uuids_").exists());
+  assert_eq!(read_version(&dir.path).unwrap(), 0);
+
+  assert_eq!(migrate(&dir.path, false).unwrap().len(), 5);
+  assert!(!heaps.exists());
+  assert!(blocks.exists());
+  assert_eq!(read_version(&dir.path).unwrap(), DATA_VERSION);
+  assert!(migrate(&dir.path, false).unwrap().is_empty());
+}
+
+#[test]
+fn newer_directories_are_refused() {
+  let dir = temp_dir();
+  std::fs::create_dir_all(&dir.path).unwrap();
+  std::fs::write(dir.path.join("VERSION"), format!("{}\n", DATA_VERSION + 1)).unwrap();
+  assert!(migrate(&dir.path, false).is_err());
+}
@@ -0,0 +1,116 @@
+use std::sync::mpsc;
+
+use primitive_types::U256;
+use serde_json::json;
+
+use crate::{
+  api::{graphql, BlockInfo, NodeRequest, StatementEntry},
+  crypto::Account,
+  hvm::{hash_statement, init_runtime, read_statements, set_sign, sign_hash, view_statement},
+  node::{code_to_body, extract_transactions, new_block, Block, Transaction, ZERO_HASH},
+  query::{CmpOp, Filter, StatementIndex},
+  runtime::RuntimeHandle,
+  test::util::temp_dir,
+};
+
+// The empty namespace is registered at genesis to the account of secret key 0x1. Namespaces of up
+// to 4 characters are auctioned, so the one registered here is longer.
+fn signed_code() -> String {
+  let mut key = [0; 32];
+  key[31] = 1;
+  let account = Account::from_private_key(&key);
+  let code = format!("ctr {{Beat}} fun (Clock action) {{ (Clock {{Beat}}) = #1 }} with {{ #0 }} reg Fooze {{ #x{:0>30x} }} fun (Fooze.Bar) {{ (Fooze.Bar) = #2 }} with {{ #5 }}", account.name.0);
+  let (_, statements) = read_statements(&code).unwrap();
+  let signed = statements.iter().map(|statement| set_sign(statement, account.sign(&sign_hash(statement))));
+  signed.map(|statement| view_statement(&statement)).collect::<Vec<_>>().join("\n")
+}
+
+// Answers the block requests of the schema as a node holding only `block` would
+fn serve(block: Block, info: impl Fn() -> BlockInfo + Send + 'static) -> mpsc::SyncSender<NodeRequest> {
+  let (node_query_tx, requests) = mpsc::sync_channel(16);
+  std::thread::spawn(move || {
+    for request in requests {
+      match request {
+        NodeRequest::GetBlock { hash, tx } => tx.send(Some(info()).filter(|_| hash == block.hash)).unwrap(),
+        NodeRequest::GetBlocks { tx, .. } => tx.send(vec![info()]).unwrap(),
+        NodeRequest::GetStatements { filter, limit, tx } => {
+          let statements = extract_transactions(&block.body).iter().filter_map(Transaction::to_statement).collect::<Vec<_>>();
+          let mut index = StatementIndex::default();
+          index.index_block(1, &statements);
+          let locs = index.query(&filter.unwrap_or(Filter::Height(CmpOp::Ge, 0)), limit);
+          let entries = locs.into_iter().map(|(height, position)| StatementEntry {
+            height,
+            block: block.hash.into(),
+            position,
+            hash: U256::from_big_endian(&hash_statement(&statements[position]).0).into(),
+            statement: statements[position].clone(),
+          });
+          tx.send(entries.collect()).unwrap();
+        }
+        _ => panic!("unexpected request"),
+      }
+    }
+  });
+  node_query_tx
+}
+
+fn execute(schema: &graphql::ApiSchema, query: &str) -> serde_json::Value {
+  let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+  let response = runtime.block_on(schema.execute(query));
+  assert!(response.errors.is_empty(), "{:?}", response.errors);
+  serde_json::to_value(response.data).unwrap()
+}
+
+#[test]
+fn resolves_nested_fields() {
+  let dir = temp_dir();
+  let runtime = RuntimeHandle::spawn(init_runtime(Some(&dir.path)));
+  let block = new_block(ZERO_HASH(), 1, 0, code_to_body(&signed_code()));
+  let (results, usage, state_hash, _) = runtime.run_block(&block);
+  assert!(results.iter().all(|result| result.is_ok()));
+  let info_block = block.clone();
+  let info = move || BlockInfo {
+    block: (&info_block).into(),
+    hash: info_block.hash.into(),
+    height: 1,
+    content: extract_transactions(&info_block.body).iter().filter_map(Transaction::to_statement).collect(),
+    results: Some(results.clone()),
+    usage: Some(usage.clone()),
+    payout: None,
+    extra_data: None,
+    state_hash: Some(state_hash.into()),
+    bloom: None,
+  };
+  let schema = graphql::schema(serve(block.clone(), info), runtime.reader());
+
+  let data = execute(&schema, "{ tip { height parent { height } statements { kind name } } }");
+  let statements = json!([
+    { "kind": "ctr", "name": "Beat" },
+    { "kind": "fun", "name": "Clock" },
+    { "kind": "reg", "name": "Fooze" },
+    { "kind": "fun", "name": "Fooze.Bar" },
+  ]);
+  assert_eq!(data, json!({ "tip": { "height": 1, "parent": null, "statements": statements } }));
+
+  let data = execute(&schema, "{ statements(filter: \"fun == 'Clock'\") { position functions receipt { ok } block { height } } }");
+  let statement = json!({ "position": 1, "functions": ["Clock"], "receipt": { "ok": true }, "block": { "height": 1 } });
+  assert_eq!(data["statements"][0], statement);
+
+  let data = execute(&schema, "{ name(name: \"Fooze\") { functions { name rules state } } function(name: \"Clock\") { state } }");
+  let functions = json!([{ "name": "Fooze.Bar", "rules": ["(Fooze.Bar) = #2"], "state": "#5" }]);
+  assert_eq!(data["name"], json!({ "functions": functions }));
+  assert_eq!(data["function"], json!({ "state": "#0" }));
+}
+
+#[test]
+fn rejects_bad_arguments() {
+  let dir = temp_dir();
+  let runtime = RuntimeHandle::spawn(init_runtime(Some(&dir.path)));
+  let (node_query_tx, _requests) = mpsc::sync_channel(16);
+  let schema = graphql::schema(node_query_tx, runtime.reader());
+  let tokio = tokio::runtime::Builder::new_current_thread().build().unwrap();
+  for query in ["{ function(name: \"NameWayTooLongForKindelia\") { state } }", "{ statements(filter: \"kind == \") { hash } }", "{ block(hash: \"0x12\") { height } }"] {
+    let response = tokio.block_on(schema.execute(query));
+    assert_eq!(response.errors.len(), 1, "{}", query);
+  }
+}
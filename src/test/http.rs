@@ -0,0 +1,191 @@
+use std::collections::HashSet;
+use std::sync::mpsc;
+
+use bit_vec::BitVec;
+use serde_json::json;
+use tokio::sync::broadcast;
+
+use crate::{
+  api::{http::api_routes, openapi, v1, VERSION_HEADER},
+  bits::{deserialized_statement, serialized_statement},
+  crypto::Account,
+  hvm::{call_statement, hash_statement, init_runtime, name_to_u128, read_term, sign_hash, state_size, view_statement, Statement, INITIAL_MANA_PRICE},
+  node::{code_to_body, new_block, ZERO_HASH},
+  runtime::RuntimeHandle,
+  test::util::temp_dir,
+};
+
+#[test]
+fn endpoints_are_served_under_their_versions() {
+  let dir = temp_dir();
+  let runtime = RuntimeHandle::spawn(init_runtime(Some(&dir.path)));
+  let (node_query_tx, _requests) = mpsc::sync_channel(16);
+  let (events, _) = broadcast::channel(16);
+  let routes = api_routes(node_query_tx, events, runtime.reader());
+  let tokio = tokio::runtime::Builder::new_current_thread().build().unwrap();
+  let get = |path: &str| tokio.block_on(warp::test::request().path(path).reply(&routes));
+
+  let versions = json!({ "status": "ok", "data": { "latest": 1, "supported": [1] } });
+  for path in ["/versions", "/v1/versions"] {
+    let reply = get(path);
+    assert_eq!(serde_json::from_slice::<serde_json::Value>(reply.body()).unwrap(), versions);
+  }
+  let latest = get("/functions/Nothing/state");
+  let versioned = get("/v1/functions/Nothing/state");
+  assert_eq!(latest.body(), versioned.body());
+  assert_eq!(latest.headers().get(VERSION_HEADER), None);
+  assert_eq!(versioned.headers().get(VERSION_HEADER).unwrap(), "1");
+  assert!(!get("/v2/versions").status().is_success());
+  assert_eq!(get("/v1").body().as_ref(), b"UP");
+  let document: serde_json::Value = serde_json::from_slice(get("/v1/openapi.json").body()).unwrap();
+  assert_eq!(document, openapi::document());
+  assert!(get("/docs").headers()["content-type"].to_str().unwrap().starts_with("text/html"));
+
+  let endpoints: HashSet<_> = v1::ENDPOINTS.iter().map(|endpoint| (endpoint.method, endpoint.path)).collect();
+  assert_eq!(endpoints.len(), v1::ENDPOINTS.len());
+}
+
+#[test]
+fn openapi_document_follows_the_endpoints() {
+  let document = openapi::document();
+  let block_at = &document["paths"]["/blocks/height/{height}"]["get"];
+  assert_eq!(block_at["parameters"][0], json!({ "name": "height", "in": "path", "required": true, "schema": { "type": "integer" } }));
+  let data = &block_at["responses"]["200"]["content"]["application/json"]["schema"]["properties"]["data"];
+  assert_eq!(data, &json!({ "allOf": [{ "$ref": "#/components/schemas/BlockInfo" }], "nullable": true }));
+  assert!(document["components"]["schemas"]["BlockInfo"].is_object());
+  assert_eq!(document["paths"]["/mine/start"]["post"]["summary"], "Starts mining");
+
+  // Every JSON response is described by a schema
+  for endpoint in v1::ENDPOINTS.iter().filter(|endpoint| !endpoint.response.ends_with(", as text") && endpoint.path != "/events") {
+    assert!(openapi::read_schema(endpoint.response).is_some(), "{} {}", endpoint.method, endpoint.path);
+  }
+  let (schema, refs) = openapi::read_schema("{tick: number, next: string | null, entries: [ExportedFunc]}").unwrap();
+  assert_eq!(schema["properties"]["next"], json!({ "type": "string", "nullable": true }));
+  assert_eq!(schema["properties"]["entries"]["items"], json!({ "$ref": "#/components/schemas/ExportedFunc" }));
+  assert_eq!(refs, vec!["ExportedFunc".to_string()]);
+  assert_eq!(openapi::read_schema("{<name>: number}").unwrap().0, json!({ "type": "object", "additionalProperties": { "type": "number" } }));
+  assert!(openapi::read_schema("[Stats").is_none());
+}
+
+#[test]
+fn calls_are_composed_against_the_tip() {
+  let dir = temp_dir();
+  let runtime = RuntimeHandle::spawn(init_runtime(Some(&dir.path)));
+  let code = "ctr {Add n} fun (Plus action) { (Plus {Add n}) = (Done (+ n #1)) } fun (Pair a b) { (Pair a b) = (Done #0) }";
+  runtime.run_block(&new_block(ZERO_HASH(), 1, 0, code_to_body(code)));
+  let (node_query_tx, _requests) = mpsc::sync_channel(16);
+  let (events, _) = broadcast::channel(16);
+  let routes = api_routes(node_query_tx, events, runtime.reader());
+  let tokio = tokio::runtime::Builder::new_current_thread().build().unwrap();
+  let compose = |body: serde_json::Value| {
+    let reply = tokio.block_on(warp::test::request().method("POST").path("/v1/compose").json(&body).reply(&routes));
+    (reply.status(), serde_json::from_slice::<serde_json::Value>(reply.body()).unwrap())
+  };
+
+  let account = Account::from_private_key(&[1; 32]);
+  let signer = hex::encode(account.public_key.serialize());
+  let (_, action) = read_term("{Add #41}").unwrap();
+  let statement = call_statement(name_to_u128("Plus"), vec![action.clone()], Some(runtime.get_status().mana_price)).unwrap();
+  let expected = json!({
+    "hex": hex::encode(serialized_statement(&statement).to_bytes()),
+    "hash": format!("0x{}", hex::encode(hash_statement(&statement).0)),
+    "digest": format!("0x{}", hex::encode(sign_hash(&statement).0)),
+    "network": 1,
+    "signer": account.name.show(),
+    "address": account.address.show(),
+    "text": view_statement(&statement),
+  });
+  // The action may be code or JSON
+  let (status, reply) = compose(json!({ "function": "Plus", "action": "{Add #41}", "signer": signer }));
+  assert!(status.is_success());
+  assert_eq!(reply["data"], expected);
+  let (_, reply) = compose(json!({ "function": "Plus", "action": serde_json::to_value(&action).unwrap(), "signer": format!("0x{}", signer) }));
+  assert_eq!(reply["data"], expected);
+
+  for body in [
+    json!({ "function": "Minus", "action": "{Add #41}", "signer": signer }),
+    json!({ "function": "Pair", "action": "{Add #41}", "signer": signer }),
+    json!({ "function": "Plus", "action": "{Add #41} #2", "signer": signer }),
+    json!({ "function": "Plus", "action": "{Add #41}", "signer": "0x1234" }),
+  ] {
+    let (status, reply) = compose(body);
+    assert_eq!(status.as_u16(), 400);
+    assert_eq!(reply["status"], "error");
+  }
+}
+
+#[test]
+fn composed_calls_bid_the_base_price() {
+  let dir = temp_dir();
+  let mut rt = init_runtime(Some(&dir.path));
+  rt.set_mana_price(8 * INITIAL_MANA_PRICE);
+  let runtime = RuntimeHandle::spawn(rt);
+  runtime.run_block(&new_block(ZERO_HASH(), 1, 0, code_to_body("ctr {Add n} fun (Plus action) { (Plus {Add n}) = (Done (+ n #1)) }")));
+  let base = runtime.get_status().mana_price;
+  assert!(base > INITIAL_MANA_PRICE);
+  let (node_query_tx, _requests) = mpsc::sync_channel(16);
+  let (events, _) = broadcast::channel(16);
+  let routes = api_routes(node_query_tx, events, runtime.reader());
+  let tokio = tokio::runtime::Builder::new_current_thread().build().unwrap();
+  let compose = |body: serde_json::Value| {
+    let reply = tokio.block_on(warp::test::request().method("POST").path("/v1/compose").json(&body).reply(&routes));
+    let reply = serde_json::from_slice::<serde_json::Value>(reply.body()).unwrap();
+    let bytes = hex::decode(reply["data"]["hex"].as_str().unwrap()).unwrap();
+    deserialized_statement(&BitVec::from_bytes(&bytes)).unwrap()
+  };
+
+  let signer = hex::encode(Account::from_private_key(&[1; 32]).public_key.serialize());
+  // Without a price, the call bids the base price, so it runs
+  let statement = compose(json!({ "function": "Plus", "action": "{Add #41}", "signer": signer }));
+  assert!(matches!(statement, Statement::Run { price: Some(price), .. } if price == base));
+  assert!(runtime.test_statements(vec![statement]).pop().unwrap().is_ok());
+  let statement = compose(json!({ "function": "Plus", "action": "{Add #41}", "signer": signer, "price": base as u64 * 2 }));
+  assert!(matches!(statement, Statement::Run { price: Some(price), .. } if price == base * 2));
+}
+
+#[test]
+fn state_usage_is_served() {
+  let dir = temp_dir();
+  let runtime = RuntimeHandle::spawn(init_runtime(Some(&dir.path)));
+  runtime.run_block(&new_block(ZERO_HASH(), 1, 0, code_to_body("fun (Keep action) { (Keep ~) = (Done #0) } with { #42 }")));
+  runtime.set_state_limit(4096);
+  let (node_query_tx, _requests) = mpsc::sync_channel(16);
+  let (events, _) = broadcast::channel(16);
+  let routes = api_routes(node_query_tx, events, runtime.reader());
+  let tokio = tokio::runtime::Builder::new_current_thread().build().unwrap();
+  let get = |path: &str| {
+    let reply = tokio.block_on(warp::test::request().path(path).reply(&routes));
+    serde_json::from_slice::<serde_json::Value>(reply.body()).unwrap()
+  };
+
+  let size = state_size(&runtime.read_state(name_to_u128("Keep")).unwrap());
+  assert_eq!(get("/v1/functions/Keep/usage")["data"], json!({ "size": size, "limit": 4096 }));
+  assert_eq!(get("/functions/Nothing/usage")["data"], json!(null));
+}
+
+async fn ask(client: &mut warp::test::WsClient, message: serde_json::Value) -> serde_json::Value {
+  client.send_text(message.to_string()).await;
+  serde_json::from_str(client.recv().await.unwrap().to_str().unwrap()).unwrap()
+}
+
+#[test]
+fn dropped_pattern_ids_are_taken_again() {
+  let dir = temp_dir();
+  let runtime = RuntimeHandle::spawn(init_runtime(Some(&dir.path)));
+  let (node_query_tx, _requests) = mpsc::sync_channel(16);
+  let (events, _) = broadcast::channel(16);
+  let routes = api_routes(node_query_tx, events, runtime.reader());
+  let tokio = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+  tokio.block_on(async {
+    let mut client = warp::test::ws().path("/v1/events").handshake(routes).await.unwrap();
+    for id in 0 .. 32 {
+      assert_eq!(ask(&mut client, json!({ "subscribe": "{Transfer from to #5}" })).await["id"], id);
+    }
+    assert_eq!(ask(&mut client, json!({ "subscribe": "{Transfer from to #5}" })).await["event"], "Error");
+    // A pattern dropped makes room for one more, under its id, as many times as it's done
+    for _ in 0 .. 100 {
+      assert_eq!(ask(&mut client, json!({ "unsubscribe": 5 })).await["event"], "Unsubscribed");
+      assert_eq!(ask(&mut client, json!({ "subscribe": "{Transfer from to #5}" })).await["id"], 5);
+    }
+  });
+}
@@ -1,12 +1,15 @@
 use crate::{
   bits::{deserialized_func, serialized_func},
+  crypto::{self, Account, SignatureCache},
   hvm::{
-    init_map, init_runtime, name_to_u128, read_statements, u128_to_name, view_statements, Rollback,
+    alloc, call_statement, check_heap, check_state_term, diff_terms, check_statement, compile_func, compute_next_mana_price, compute_refund, hash_func, hash_runtime_state, hash_statement, set_sign, sign_hash, state_size, get_loc, link, init_map, init_runtime, load_runtime, name_to_u128, read_statements, readback_linear_term, readback_term, u128_to_name,
+    read_term, view_statement, view_statement_pretty, view_statements, view_term, view_term_change, view_term_limited, view_term_pretty,
+    Ctr, Dp0, Dp1, Era, HeapFault, Layout, Num, RuntimeError, NameInfo, TermChange, NameKind, Rewrite, RewriteStats, Rollback, Runtime, StatementInfo, StatementLimits, StatementRejection, Term, TermLimits, Upstream, UpstreamFunc, BLOCK_MANA_LIMIT, BLOCK_MANA_TARGET, DEFAULT_TERM_LIMITS, HOOK_MANA_LIMIT, INITIAL_MANA_PRICE, MAX_HOOKS, MAX_REFUND_QUOTIENT, NETWORK_ID, REFUND_MANA_PER_WORD, SignPayload,
   },
   test::{
     strategies::{func, heap, name, statement},
     util::{
-      advance, rollback, rollback_path, rollback_simple, temp_dir, test_heap_checksum,
+      advance, assert_terms_eq, rollback, rollback_path, rollback_simple, temp_dir, test_heap_checksum,
       view_rollback_ticks, RuntimeStateTest, TempDir,
     },
   },
@@ -86,13 +89,378 @@ pub fn stack_overflow(fn_names: &[&str], pre_code: &str, code: &str, temp_dir: T
 }
 
 #[rstest]
-#[ignore = "fix not done"]
-// TODO: fix drop stack overflow
 pub fn stack_overflow2(temp_dir: TempDir) {
-  // caused by drop of term
+  // deep terms are read back, printed and dropped without overflowing the stack
   let mut rt = init_runtime(Some(&temp_dir.path));
   rt.run_statements_from_code(PRE_COUNTER, false);
-  rt.run_statements_from_code(COUNTER_STACKOVERFLOW, false);
+  let results = rt.run_statements_from_code(COUNTER_STACKOVERFLOW, false);
+  assert!(results[0].is_ok());
+  // so are terms as deep as the limit allows, and deeper ones fail instead
+  let depth = DEFAULT_TERM_LIMITS.depth;
+  let results = rt.run_statements_from_code(&format!("run {{ (Done (ToSucc #{})) }}", depth - 1), false);
+  assert!(results[0].is_ok());
+  let results = rt.run_statements_from_code(&format!("run {{ (Done (ToSucc #{})) }}", depth), true);
+  assert!(results[0].is_err());
+}
+
+#[apply(hvm_cases)]
+fn heap_integrity(fn_names: &[&str], pre_code: &str, code: &str, temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  rt.run_statements_from_code(pre_code, true);
+  advance(&mut rt, 500, Some(code));
+  assert_eq!(check_heap(&rt), vec![]);
+  rt.restore_state().expect("Could not restore state");
+  assert_eq!(check_heap(&rt), vec![]);
+}
+
+#[apply(hvm_cases)]
+fn failing_heap_writes_keep_the_state_in_memory(fn_names: &[&str], pre_code: &str, code: &str, temp_dir: TempDir) {
+  let clean_dir = crate::test::util::temp_dir();
+  let mut failing = init_runtime(Some(&temp_dir.path));
+  let mut clean = init_runtime(Some(&clean_dir.path));
+  failing.set_disk_faults(30, 0);
+  for rt in [&mut failing, &mut clean] {
+    rt.run_statements_from_code(pre_code, true);
+    advance(rt, 300, Some(code));
+  }
+  assert_eq!(RuntimeStateTest::new(fn_names, &mut failing), RuntimeStateTest::new(fn_names, &mut clean));
+  assert_eq!(check_heap(&failing), vec![]);
+  // once every write fails, the saved state falls behind
+  failing.set_disk_faults(100, 0);
+  advance(&mut failing, 400, Some(code));
+  let saved = load_runtime(&temp_dir.path).map(|rt| rt.get_tick());
+  assert!(!matches!(saved, Ok(tick) if tick >= 300));
+}
+
+#[apply(hvm_cases)]
+fn compressed_heaps_restore_along_plain_ones(fn_names: &[&str], pre_code: &str, code: &str, temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  rt.set_compression(None);
+  rt.run_statements_from_code(pre_code, true);
+  advance(&mut rt, 300, Some(code));
+  rt.set_compression(Some(19));
+  advance(&mut rt, 400, Some(code));
+  let files: Vec<String> = std::fs::read_dir(&temp_dir.path).unwrap().map(|entry| entry.unwrap().file_name().into_string().unwrap()).collect();
+  assert!(files.iter().any(|file| file.ends_with(".bin")));
+  assert!(files.iter().any(|file| file.ends_with(".bin.zst")));
+  // what the last snapshot saved, as the runtime has it before and after restoring it
+  let tick = rt.get_snapshot_ticks()[0];
+  rt.rollback(tick);
+  let before = RuntimeStateTest::new(fn_names, &mut rt);
+  rt.restore_state().expect("Could not restore state");
+  assert_eq!(RuntimeStateTest::new(fn_names, &mut rt), before);
+}
+
+#[apply(hvm_cases)]
+fn heaps_saved_as_deltas_restore_as_they_were(fn_names: &[&str], pre_code: &str, code: &str, temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  rt.run_statements_from_code(pre_code, true);
+  let mut deltas = 0;
+  // a heap absorbs another every 16 ticks, and is saved whole after MAX_HEAP_DELTAS of them
+  for step in 1 ..= 12 {
+    advance(&mut rt, step * 50, Some(code));
+    let bases = std::fs::read_dir(&temp_dir.path).unwrap().filter(|entry| entry.as_ref().unwrap().file_name().to_str().unwrap().contains(".base.")).count();
+    deltas = deltas.max(bases);
+    // the last snapshot saved, as the runtime has it, and as it's loaded from disk
+    let mut saved = rt.fork_at(rt.get_snapshot_ticks()[0]).unwrap();
+    let mut loaded = load_runtime(&temp_dir.path).expect("Could not load state");
+    assert_eq!(RuntimeStateTest::new(fn_names, &mut loaded), RuntimeStateTest::new(fn_names, &mut saved));
+    assert_eq!(check_heap(&loaded), vec![]);
+  }
+  assert!(deltas > 0);
+}
+
+#[rstest]
+fn heap_integrity_faults(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  rt.run_statements_from_code(PRE_COUNTER, true);
+  rt.run_statements_from_code("run { ask (Call 'Store' [{StoreAdd}]); (Done #0) }", true);
+  assert_eq!(check_heap(&rt), vec![]);
+  // the size counter drifts
+  rt.set_size(rt.get_size() + 1);
+  let faults = check_heap(&rt);
+  assert!(faults.iter().any(|fault| matches!(fault, HeapFault::BadSize { .. })));
+  rt.set_size(rt.get_size() - 1);
+  // a node of the counter's state is freed while still referenced
+  let state = rt.read_disk(name_to_u128("Store")).unwrap();
+  let node = get_loc(state, 0);
+  rt.write(node, 0);
+  let faults = check_heap(&rt);
+  assert!(faults.contains(&HeapFault::BadPointer { host: None, lnk: state }));
+}
+
+#[apply(hvm_cases)]
+fn heap_compaction(fn_names: &[&str], pre_code: &str, code: &str, temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  rt.run_statements_from_code(pre_code, true);
+  advance(&mut rt, 200, Some(code));
+  let states = |rt: &mut Runtime| {
+    fn_names.iter().map(|name| rt.read_disk_as_term(name_to_u128(name)).map(|term| view_term(&term))).collect::<Vec<_>>()
+  };
+  let before = states(&mut rt);
+  let hash = hash_runtime_state(&rt);
+  let tick = rt.get_tick();
+  rt.compact();
+  assert_eq!(check_heap(&rt), vec![]);
+  assert_eq!(states(&mut rt), before);
+  // moving nodes around doesn't change the state hash
+  assert_eq!(hash_runtime_state(&rt).0, hash.0);
+  // the live nodes are contiguous, so compacting again moves nothing
+  assert_eq!(rt.get_next() as i128, rt.get_size());
+  assert_eq!(rt.compact(), 0);
+  // the compacted heap keeps working, and rolls back like any other change
+  advance(&mut rt, tick + 10, Some(code));
+  assert_eq!(check_heap(&rt), vec![]);
+  rt.rollback(tick);
+  assert_eq!(check_heap(&rt), vec![]);
+}
+
+#[rstest]
+fn compaction_on_demand_outlives_failing_statements(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  rt.run_statements_from_code(PRE_COUNTER, true);
+  advance(&mut rt, 200, Some(COUNTER));
+  assert!(rt.compact_now() > 0);
+  // a failing statement undoes its own changes, not the compaction
+  assert!(rt.run_statements_from_code("run { (Done (Nope)) }", true)[0].is_err());
+  assert_eq!(check_heap(&rt), vec![]);
+  assert_eq!(rt.compact(), 0);
+}
+
+#[rstest]
+fn deep_term_roundtrip(temp_dir: TempDir) {
+  // create_term, readback and drop must not recurse on the term's depth
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  rt.run_statements_from_code(PRE_COUNTER, true);
+  let mut term = Term::Ctr { name: name_to_u128("Zero"), args: vec![] };
+  for _ in 0 .. 100_000 {
+    term = Term::Ctr { name: name_to_u128("Succ"), args: vec![term] };
+  }
+  let host = rt.alloc_term(&term);
+  let back = readback_linear_term(&rt, rt.read(host));
+  assert_terms_eq(&back, &term);
+}
+
+#[rstest]
+#[case(TermLimits { depth: 10_000, nodes: 10_000 }, true)]
+#[case(TermLimits { depth: 10_000, nodes: 100 }, false)]
+#[case(TermLimits { depth: 100, nodes: 10_000 }, false)]
+fn term_limits(#[case] limits: TermLimits, #[case] succeeds: bool, temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  rt.run_statements_from_code(PRE_COUNTER, true);
+  rt.set_term_limits(limits);
+  let results = rt.run_statements_from_code(COUNTER_STACKOVERFLOW, true);
+  assert_eq!(results[0].is_ok(), succeeds);
+}
+
+#[rstest]
+fn saved_states_are_finite(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  rt.run_statements_from_code("ctr {Pair a b}", true);
+  let pair = name_to_u128("Pair");
+  // dup a ~ = {Pair a #1}; a
+  let dup = alloc(&mut rt, 3);
+  let ctr = alloc(&mut rt, 2);
+  link(&mut rt, ctr, Dp0(1, dup));
+  link(&mut rt, ctr + 1, Num(1));
+  link(&mut rt, dup + 1, Era());
+  link(&mut rt, dup + 2, Ctr(pair, ctr));
+  assert!(matches!(check_state_term(&rt, Dp0(1, dup), DEFAULT_TERM_LIMITS), Err(RuntimeError::CyclicTerm)));
+  // dup a b = #1; {Pair a b} only shares its expression
+  link(&mut rt, dup + 2, Num(1));
+  link(&mut rt, ctr + 1, Dp1(1, dup));
+  assert!(check_state_term(&rt, Ctr(pair, ctr), DEFAULT_TERM_LIMITS).is_ok());
+  assert!(matches!(check_state_term(&rt, Ctr(pair, ctr), TermLimits { depth: 1, nodes: 2 }), Err(RuntimeError::TermTooLarge)));
+
+  // saving a state over the limits fails, keeping the previous one
+  rt.run_statements_from_code(PRE_COUNTER, true);
+  rt.set_term_limits(TermLimits { depth: DEFAULT_TERM_LIMITS.depth, nodes: 8 });
+  let results = rt.run_statements_from_code(&"run { ask (Call 'Store' [{StoreAdd}]); (Done #0) }".repeat(10), true);
+  assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 7);
+  let state = rt.read_disk_as_term(name_to_u128("Store")).unwrap();
+  assert_eq!(view_term(&state).matches("Succ").count(), 7);
+}
+
+#[rstest]
+fn state_size_limit(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  rt.run_statements_from_code(PRE_COUNTER, true);
+  let store = name_to_u128("Store");
+  let size = |rt: &mut Runtime| state_size(&rt.read_disk_as_term(store).unwrap());
+  let add = "run { ask (Call 'Store' [{StoreAdd}]); (Done #0) }";
+  let initial = size(&mut rt);
+  rt.set_state_limit(initial + 40);
+  let mut saved = 0;
+  let failure = loop {
+    rt.tick();
+    match rt.run_statements_from_code(add, true).pop().unwrap() {
+      Ok(_) => saved += 1,
+      Err(err) => break err.err,
+    }
+  };
+  assert!(saved > 0 && failure.starts_with("State takes"), "{}", failure);
+  assert!(size(&mut rt) <= rt.get_state_limit());
+  // lowered under a state, the state is still there, and can shrink back under the limit
+  let before = size(&mut rt);
+  rt.set_state_limit(before - 1);
+  rt.tick();
+  assert!(rt.run_statements_from_code(add, true)[0].as_ref().unwrap_err().err.starts_with("State takes"));
+  assert_eq!(size(&mut rt), before);
+  rt.tick();
+  assert!(rt.run_statements_from_code("run { ask (Call 'Store' [{StoreSub}]); (Done #0) }", true)[0].is_ok());
+  assert!(size(&mut rt) < before);
+}
+
+#[rstest]
+#[case(StatementLimits { size: 1275, rules: 256, arity: 16 }, None)]
+#[case(StatementLimits { size: 8, rules: 256, arity: 16 }, Some("TooLarge"))]
+#[case(StatementLimits { size: 1275, rules: 2, arity: 16 }, Some("TooManyRules"))]
+#[case(StatementLimits { size: 1275, rules: 256, arity: 2 }, Some("TooManyFields"))]
+fn statement_limits(#[case] limits: StatementLimits, #[case] rejection: Option<&str>, temp_dir: TempDir) {
+  let code = "
+    ctr {Triple a b c}
+    ctr {Red}
+    ctr {Green}
+    ctr {Blue}
+    fun (Hue c) {
+      (Hue {Red}) = #0
+      (Hue {Green}) = #120
+      (Hue {Blue}) = #240
+    }
+  ";
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  rt.set_statement_limits(limits);
+  let (_, statements) = read_statements(code).unwrap();
+  let rejections: Vec<_> = statements.iter().filter_map(|s| check_statement(s, limits).err()).collect();
+  let results = rt.run_statements(&statements, true);
+  assert_eq!(results.iter().all(|r| r.is_ok()), rejection.is_none());
+  if let Some(rejection) = rejection {
+    assert!(!rejections.is_empty());
+    assert!(rejections.iter().all(|r| format!("{:?}", r).starts_with(rejection)));
+  }
+}
+
+#[test]
+fn statement_rejection_reasons() {
+  let limits = StatementLimits { size: 4, rules: 1, arity: 1 };
+  let (_, statements) = read_statements("ctr {Pair a b}").unwrap();
+  assert_eq!(check_statement(&statements[0], limits), Err(StatementRejection::TooManyFields { arity: 2, limit: 1 }));
+  let (_, statements) = read_statements("ctr {Unit}").unwrap();
+  assert!(matches!(check_statement(&statements[0], limits), Err(StatementRejection::TooLarge { limit: 4, .. })));
+}
+
+#[test]
+fn term_pretty_printing() {
+  let (_, term) = read_term("{Node {Node {Leaf #1} {Leaf #2}} {Node {Leaf #3} (Get @x x)}}").unwrap();
+  let flat = view_term(&term);
+  let width = |width| Layout { width, ..Layout::default() };
+  assert_eq!(view_term_pretty(&term, &width(flat.len()), None), flat);
+  assert_eq!(view_term_pretty(&term, &width(40), None), "{Node\n  {Node {Leaf #1} {Leaf #2}}\n  {Node {Leaf #3} (Get @x x)}\n}");
+  assert_eq!(view_term_pretty(&term, &Layout { width: 30, indent: 4 }, None), "{Node\n    {Node {Leaf #1} {Leaf #2}}\n    {Node\n        {Leaf #3}\n        (Get @x x)\n    }\n}");
+  assert_eq!(view_term_pretty(&term, &width(80), Some(2)), "{Node {Node ... ...} {Node ... ...}}");
+  assert_eq!(view_term_limited(&term, None, Some(flat.len() - 1)), None);
+}
+
+#[test]
+fn statement_pretty_printing() {
+  let code = "fun (Tree x) {\n  (Tree {Big}) = {Node {Node {Leaf #1} {Leaf #2}} {Node {Leaf #3} {Leaf x}}}\n} with {\n  {Node {Leaf #10} {Leaf #20}}\n}";
+  let statement = read_statements(code).unwrap().1.remove(0);
+  assert_eq!(view_statement(&statement), code);
+  let shown = view_statement_pretty(&statement, &Layout { width: 40, indent: 2 });
+  assert_eq!(shown, [
+    "fun (Tree x) {",
+    "  (Tree {Big}) = {Node",
+    "    {Node {Leaf #1} {Leaf #2}}",
+    "    {Node {Leaf #3} {Leaf x}}",
+    "  }",
+    "} with {",
+    "  {Node {Leaf #10} {Leaf #20}}",
+    "}",
+  ].join("\n"));
+  // Laid out statements read back as they were
+  let read = read_statements(&shown).unwrap().1;
+  assert_eq!(view_statement(&read[0]), code);
+}
+
+#[test]
+fn term_diffs_point_at_changes() {
+  let read = |code: &str| read_term(code).unwrap().1;
+  let old = read("{Node {Node {Leaf #1} {Leaf #2}} {Pair #3 #4 #5} (Get @x x)}");
+  let new = read("{Node {Node {Leaf #1} {Leaf #7}} {Pair #3} (Get @y y)}");
+  assert_eq!(diff_terms(&old, &old), vec![]);
+  let changes = diff_terms(&old, &new);
+  assert_eq!(changes[0], TermChange::Replace { path: vec![0, 1, 0], old: read("#2"), new: read("#7") });
+  let shown: Vec<_> = changes.iter().map(|change| view_term_change(&old, change)).collect();
+  assert_eq!(shown, vec![
+    "~ Node[0].Node[1].Leaf[0]: #2 -> #7",
+    "- Node[1].Pair[1]: #4",
+    "- Node[1].Pair[2]: #5",
+    "~ Node[2].Get[0]: @x x -> @y y",
+  ]);
+  let changes = diff_terms(&new, &old);
+  assert_eq!(changes[1], TermChange::Insert { path: vec![1, 1], term: read("#4") });
+}
+
+#[rstest]
+fn signature_cache(temp_dir: TempDir) {
+  let account = Account::from_private_key(&[1; 32]);
+  let (_, statements) = read_statements("ctr {Pair a b}").unwrap();
+  let statement = set_sign(&statements[0], account.sign(&sign_hash(&statements[0])));
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let misses = rt.get_signature_cache().misses;
+  // checked once on admission, then found on the cache when run
+  assert_eq!(rt.precheck_signature(&statement), account.name.0);
+  assert!(rt.run_statement(&statement, true).is_ok());
+  assert_eq!(rt.get_signature_cache().misses, misses + 1);
+  assert_eq!(rt.get_signature_cache().hits, 1);
+}
+
+#[rstest]
+fn signatures_are_bound_to_the_network(temp_dir: TempDir) {
+  let account = Account::from_private_key(&[1; 32]);
+  let (_, statements) = read_statements("run { ask x = (Subj); (Done x) }").unwrap();
+  let signed = |network| set_sign(&statements[0], account.sign(&SignPayload::new(&statements[0], network).digest()));
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  assert_eq!(rt.precheck_signature(&signed(NETWORK_ID)), account.name.0);
+  // signed for another network, or over the bare hash, it's someone else's
+  assert_ne!(rt.precheck_signature(&signed(NETWORK_ID + 1)), account.name.0);
+  let bare = set_sign(&statements[0], account.sign(&hash_statement(&statements[0])));
+  assert_ne!(rt.precheck_signature(&bare), account.name.0);
+}
+
+#[rstest]
+fn call_statements_call_with_a_tuple(temp_dir: TempDir) {
+  let (_, terms) = read_statements("ctr {Add n} fun (Plus action) { (Plus {Add n}) = (Done (+ n #1)) }").unwrap();
+  let (_, arg) = read_term("{Add #41}").unwrap();
+  let call = call_statement(name_to_u128("Plus"), vec![arg], None).unwrap();
+  assert_eq!(view_statement(&call), view_statements(&read_statements("run { ask x = (Call 'Plus' [{Add #41}]); (Done x) }").unwrap().1).trim());
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  rt.run_statements(&terms, true);
+  let Ok(StatementInfo::Run { done_term, .. }) = rt.run_statement(&call, true) else { panic!("the call failed") };
+  assert_eq!(view_term(&done_term), "#42");
+  assert!(call_statement(name_to_u128("Plus"), (0 .. 13).map(|numb| Term::Num { numb }).collect(), None).is_err());
+}
+
+#[test]
+fn signature_cache_eviction() {
+  let account = Account::from_private_key(&[1; 32]);
+  let hashes: Vec<_> = (0 .. 3u8).map(|i| crypto::keccak256(&[i])).collect();
+  let signs: Vec<_> = hashes.iter().map(|hash| account.sign(hash)).collect();
+  let mut cache = SignatureCache::new(2);
+  for (hash, sign) in hashes.iter().zip(&signs) {
+    assert_eq!(cache.signer_name(sign, hash).map(|x| x.0), Some(account.name.0));
+  }
+  assert_eq!(cache.len(), 2);
+  assert_eq!(cache.misses, 3);
+  // the first signature was the least recently used, so it was evicted
+  cache.signer_name(&signs[2], &hashes[2]);
+  cache.signer_name(&signs[0], &hashes[0]);
+  assert_eq!((cache.hits, cache.misses), (1, 4));
+  // a bad signature is cached as such
+  let bad = crypto::Signature([0xff; 65]);
+  assert!(cache.signer_name(&bad, &hashes[1]).is_none());
+  assert!(cache.signer_name(&bad, &hashes[1]).is_none());
+  assert_eq!((cache.hits, cache.misses), (2, 5));
 }
 
 #[apply(hvm_cases)]
@@ -134,6 +502,716 @@ pub fn persistence1(
   assert_eq!(s3, s5);
 }
 
+#[apply(hvm_cases)]
+pub fn fork_at_snapshot(fn_names: &[&str], pre_code: &str, code: &str, temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  rt.run_statements_from_code(pre_code, true);
+  advance(&mut rt, 1000, Some(code));
+
+  let last = {
+    if let Rollback::Cons { head, .. } = *rt.get_back() {
+      rt.get_heap(head).tick
+    } else {
+      0
+    }
+  };
+
+  // a forked runtime must see the same state as a rollback to the same tick
+  let mut forked = rt.fork_at(last).expect("No snapshot to fork from");
+  let s1 = RuntimeStateTest::new(&fn_names, &mut forked);
+  rt.rollback(last);
+  let s2 = RuntimeStateTest::new(&fn_names, &mut rt);
+
+  assert_eq!(forked.get_tick(), rt.get_tick());
+  assert_eq!(s1, s2);
+  assert_eq!(hash_runtime_state(&forked).0, hash_runtime_state(&rt).0);
+}
+
+#[rstest]
+fn trace_records_effects(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  rt.run_statements_from_code(PRE_COUNTER, true);
+  rt.start_trace();
+  rt.run_statements_from_code(SIMPLE_COUNT, true);
+  let trace = rt.stop_trace();
+  assert!(trace.iter().any(|line| line.starts_with("[Count] TAKE")));
+  assert!(trace.iter().any(|line| line.starts_with("[Count] SAVE")));
+  assert!(trace.iter().any(|line| line.contains("CALL Count")));
+  // tracing stops after `stop_trace`
+  rt.run_statements_from_code(SIMPLE_COUNT, true);
+  assert!(rt.stop_trace().is_empty());
+}
+
+#[rstest]
+fn block_info_effects_keep_the_caller(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let code = "
+    ctr {Info_Get}
+    ctr {Outer_Get}
+    fun (Info action) {
+      (Info {Info_Get}) =
+        ask tick = (Tick);
+        ask time = (Time);
+        ask meta = (Meta);
+        ask hax0 = (Hax0);
+        ask hax1 = (Hax1);
+        ask from = (From);
+        (Done {T6 tick time meta hax0 hax1 from})
+    }
+    fun (Outer action) {
+      (Outer {Outer_Get}) =
+        ask info = (Call (+ 'Info' #0) [{Info_Get}]);
+        (Done info)
+    }
+  ";
+  assert!(rt.run_statements_from_code(code, true).iter().all(|r| r.is_ok()));
+  rt.set_time(11);
+  rt.set_meta(12);
+  rt.set_hax0(13);
+  rt.set_hax1(14);
+  // each effect passes its value on, and the caller stays the one of the function, even after them
+  let info = rt.run_statements_from_code("run { ask x = (Call 'Outer' [{Outer_Get}]); (Done x) }", true).pop().unwrap();
+  match info {
+    Ok(StatementInfo::Run { done_term, .. }) => {
+      let expected = format!("{{T6 #{} #11 #12 #13 #14 #{}}}", rt.get_tick(), name_to_u128("Outer"));
+      assert_eq!(view_term(&done_term), expected);
+    }
+    other => panic!("Unexpected result: {:?}", other),
+  }
+}
+
+#[rstest]
+fn erased_dups_read_back_as_parseable_terms(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let done = rt.run_statements_from_code("run { (Done @x dup a ~ = x; a) }", true).pop().unwrap();
+  match done {
+    Ok(StatementInfo::Run { done_term, .. }) => {
+      let shown = view_term(&done_term);
+      assert!(shown.contains('~'));
+      assert!(read_term(&shown).is_ok());
+    }
+    other => panic!("Unexpected result: {:?}", other),
+  }
+}
+
+// Serves the functions of another runtime, as the node a local chain forked from would
+struct RuntimeUpstream(std::collections::HashMap<u128, UpstreamFunc>);
+
+impl Upstream for RuntimeUpstream {
+  fn get_function(&self, name: u128) -> Option<UpstreamFunc> {
+    self.0.get(&name).cloned()
+  }
+}
+
+#[rstest]
+fn fork_fetches_mentioned_functions(temp_dir: TempDir) {
+  let mut remote = init_runtime(Some(&temp_dir.path.join("remote")));
+  remote.run_statements_from_code(PRE_COUNTER, true);
+  remote.run_statements_from_code("run { ask (Call 'Store' [{StoreAdd}]); (Done #0) }", true);
+  let names = ["ToSucc", "Add", "Sub", "Store"].map(name_to_u128);
+  let funcs = names.iter().map(|name| (*name, (remote.read_file(*name).unwrap().func, remote.read_disk_as_term(*name)))).collect();
+
+  let mut rt = init_runtime(Some(&temp_dir.path.join("local")));
+  rt.set_upstream(Some(std::sync::Arc::new(RuntimeUpstream(funcs))));
+  // 'Store' is fetched with its state, and 'Add', which it calls, along with it
+  let results = rt.run_statements_from_code("run { ask (Call 'Store' [{StoreAdd}]); ask n = (Call 'Store' [{StoreGet}]); (Done n) }", true);
+  match &results[0] {
+    Ok(StatementInfo::Run { done_term, .. }) => assert_eq!(view_term(done_term), "{Succ {Succ {Zero}}}"),
+    other => panic!("Unexpected result: {:?}", other),
+  }
+  // names taken on the fork can't be deployed again
+  assert!(rt.run_statements_from_code("ctr {Sub}", true)[0].is_err());
+  assert!(rt.run_statements_from_code("ctr {ToSucc2}", true)[0].is_ok());
+}
+
+#[rstest]
+fn contract_controlled_name(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let results = rt.run_statements_from_code(PRE_WALLET, true);
+  assert!(results.iter().all(|r| r.is_ok()));
+  // the authorizer accepts the proof, so the action runs as 'Wallet'
+  let accepted = rt.run_statements_from_code(&wallet_acts(42), true);
+  match &accepted[0] {
+    Ok(StatementInfo::Run { done_term, .. }) => assert_eq!(*done_term, Term::Num { numb: 7 }),
+    other => panic!("Unexpected result: {:?}", other),
+  }
+  // the authorizer rejects the proof
+  let rejected = rt.run_statements_from_code(&wallet_acts(41), true);
+  assert!(rejected[0].is_err());
+}
+
+#[rstest]
+fn scheduled_action_runs_at_tick(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let results = rt.run_statements_from_code(PRE_TIMER, true);
+  assert!(results.iter().all(|r| r.is_ok()));
+  // the action must be scheduled for a future tick
+  let past = rt.run_statements_from_code(&timer_arm(0, 1000), true);
+  assert!(past[0].is_err());
+  let target = rt.get_tick() + 3;
+  let armed = rt.run_statements_from_code(&timer_arm(target, 1000), true);
+  assert!(armed[0].is_ok());
+  while rt.get_tick() < target {
+    assert_eq!(rt.read_disk_as_term(name_to_u128("Timer")), Some(Term::Num { numb: 0 }));
+    rt.tick();
+    rt.run_scheduled(true);
+  }
+  assert_eq!(rt.read_disk_as_term(name_to_u128("Timer")), Some(Term::Num { numb: 1 }));
+  // the action runs only once
+  rt.tick();
+  rt.run_scheduled(true);
+  assert_eq!(rt.read_disk_as_term(name_to_u128("Timer")), Some(Term::Num { numb: 1 }));
+}
+
+#[rstest]
+fn scheduled_actions_are_paid_upfront(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let results = rt.run_statements_from_code(PRE_TIMER, true);
+  assert!(results.iter().all(|r| r.is_ok()));
+  // each one takes its own block, for the space it uses
+  let arm = |rt: &mut Runtime, tick: u128, mana: u128| {
+    rt.tick();
+    match rt.run_statements_from_code(&timer_arm(tick, mana), true).pop().unwrap() {
+      Ok(StatementInfo::Run { used_mana, .. }) => used_mana,
+      other => panic!("Unexpected result: {:?}", other),
+    }
+  };
+  let advance = |rt: &mut Runtime, tick: u128| {
+    while rt.get_tick() < tick {
+      rt.tick();
+      rt.run_scheduled(true);
+    }
+  };
+  // the mana given to the action is paid when scheduling it
+  let target = rt.get_tick() + 10;
+  assert_eq!(arm(&mut rt, target, 50_000) - arm(&mut rt, target, 10_000), 40_000);
+  // the action can't spend more than it was given, and failing still spends it
+  let starved = rt.get_tick() + 3;
+  arm(&mut rt, starved, 1);
+  advance(&mut rt, starved - 1);
+  rt.tick();
+  let mana = rt.get_mana();
+  rt.run_scheduled(true);
+  assert!(rt.get_mana() > mana);
+  assert_eq!(rt.read_disk_as_term(name_to_u128("Timer")), Some(Term::Num { numb: 0 }));
+  // the ones given enough mana run
+  advance(&mut rt, target);
+  assert_eq!(rt.read_disk_as_term(name_to_u128("Timer")), Some(Term::Num { numb: 2 }));
+}
+
+#[rstest]
+fn random_beacon_effect(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let code = "run { ask r = (GetRandom); (Done r) }";
+  let seed = crate::node::next_random(0, crate::util::u256(1234));
+  assert_ne!(seed, crate::node::next_random(seed, crate::util::u256(1234)));
+  rt.set_rand(seed);
+  match &rt.run_statements_from_code(code, true)[0] {
+    Ok(StatementInfo::Run { done_term, .. }) => assert_eq!(*done_term, Term::Num { numb: seed }),
+    other => panic!("Unexpected result: {:?}", other),
+  }
+}
+
+#[rstest]
+fn hooks_receive_events(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let results = rt.run_statements_from_code(PRE_HOOKS, true);
+  assert!(results.iter().all(|r| r.is_ok()));
+  let logger = name_to_u128("Logger");
+  assert_eq!(rt.get_hooks(name_to_u128("Bell")), vec![logger]);
+  rt.run_statements_from_code("run { ask (Call 'Bell' [{Bell_Ring #5}]); (Done #0) }", true);
+  assert_eq!(rt.read_disk_as_term(logger), Some(Term::Num { numb: 5 }));
+  // events from failed statements are not delivered
+  rt.run_statements_from_code("run { ask (Call 'Bell' [{Bell_Ring #7}]); ask (Emit #0); (Done #0) }", true);
+  assert_eq!(rt.read_disk_as_term(logger), Some(Term::Num { numb: 5 }));
+  rt.run_statements_from_code("run { ask (Call 'Logger' [{Logger_Stop}]); (Done #0) }", true);
+  assert!(rt.get_hooks(name_to_u128("Bell")).is_empty());
+  rt.run_statements_from_code("run { ask (Call 'Bell' [{Bell_Ring #3}]); (Done #0) }", true);
+  assert_eq!(rt.read_disk_as_term(logger), Some(Term::Num { numb: 5 }));
+}
+
+#[rstest]
+fn hooks_are_charged_to_the_emitter(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let code = "
+    ctr {Crash_Start}
+    fun (Crash action) {
+      (Crash {Crash_Start}) =
+        ask (Subscribe 'Bell');
+        (Done #0)
+      (Crash {Event name x}) = (Crash {Event name x})
+    }
+    run {
+      ask (Call 'Crash' [{Crash_Start}]);
+      (Done #0)
+    }
+  ";
+  assert!(rt.run_statements_from_code(PRE_HOOKS, true).iter().all(|r| r.is_ok()));
+  let ring = |rt: &mut Runtime| {
+    let mana = rt.get_mana();
+    let burned = rt.get_burned();
+    match rt.run_statements_from_code("run { ask (Call 'Bell' [{Bell_Ring #5}]); (Done #0) }", true).pop().unwrap() {
+      Ok(StatementInfo::Run { used_mana, .. }) => {
+        assert_eq!(rt.get_mana() - mana, used_mana);
+        assert_eq!(rt.get_burned() - burned, used_mana * rt.get_mana_price());
+        used_mana
+      }
+      other => panic!("Unexpected result: {:?}", other),
+    }
+  };
+  let logged = ring(&mut rt);
+  // a delivery that fails is undone, but still spends its mana, which the emitter pays
+  assert!(rt.run_statements_from_code(code, true).iter().all(|r| r.is_ok()));
+  let crashed = ring(&mut rt);
+  assert!(crashed >= logged + HOOK_MANA_LIMIT);
+  assert_eq!(rt.read_disk_as_term(name_to_u128("Logger")), Some(Term::Num { numb: 10 }));
+}
+
+#[rstest]
+fn hooks_per_emitter_are_capped(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  assert!(rt.run_statements_from_code(PRE_HOOKS, true).iter().all(|r| r.is_ok()));
+  // Logger is already subscribed, so all but the last of these fit. Each takes its own block, for
+  // the space it uses.
+  let subscribe = |rt: &mut Runtime, i: usize| {
+    let code = format!("
+      fun (Listener{} action) {{
+        (Listener{} {{Event ~ ~}}) = (Done #0)
+        (Listener{} {{Logger_Start}}) =
+          ask (Subscribe 'Bell');
+          (Done #0)
+      }}
+      run {{
+        ask (Call 'Listener{}' [{{Logger_Start}}]);
+        (Done #0)
+      }}
+    ", i, i, i, i);
+    rt.tick();
+    rt.run_statements_from_code(&code, true).pop().unwrap()
+  };
+  for i in 1 .. MAX_HOOKS {
+    assert!(subscribe(&mut rt, i).is_ok());
+  }
+  assert_eq!(rt.get_hooks(name_to_u128("Bell")).len(), MAX_HOOKS);
+  assert!(subscribe(&mut rt, MAX_HOOKS).is_err());
+  assert_eq!(rt.get_hooks(name_to_u128("Bell")).len(), MAX_HOOKS);
+}
+
+#[rstest]
+fn frozen_state_is_read_only(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let results = rt.run_statements_from_code(PRE_FREEZE, true);
+  assert!(results.iter().all(|r| r.is_ok()));
+  let counter = name_to_u128("Counter");
+  let call = |rt: &mut Runtime, action: &str| {
+    let code = format!("run {{ ask x = (Call 'Counter' [{{{}}}]); (Done x) }}", action);
+    rt.run_statements_from_code(&code, true).pop().unwrap()
+  };
+  assert!(call(&mut rt, "Counter_Inc").is_ok());
+  // only the function itself, or its namespace owner, can freeze it
+  assert!(rt.run_statements_from_code("run { ask (Freeze 'Counter'); (Done #0) }", true)[0].is_err());
+  assert!(call(&mut rt, "Counter_Lock").is_ok());
+  assert_eq!(rt.get_frozen(counter), Some(rt.get_tick()));
+  assert!(call(&mut rt, "Counter_Inc").is_err());
+  for _ in 0 .. 2 {
+    match call(&mut rt, "Counter_Get") {
+      Ok(StatementInfo::Run { done_term, .. }) => assert_eq!(done_term, Term::Num { numb: 1 }),
+      other => panic!("Unexpected result: {:?}", other),
+    }
+  }
+  assert_eq!(rt.read_disk_as_term(counter), Some(Term::Num { numb: 1 }));
+}
+
+#[rstest]
+fn refund_for_freed_state(temp_dir: TempDir) {
+  assert_eq!(compute_refund(1000, 10), 0);
+  assert_eq!(compute_refund(1000, -10), 10 * REFUND_MANA_PER_WORD);
+  assert_eq!(compute_refund(1000, -1000), 1000 / MAX_REFUND_QUOTIENT);
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let code = "
+    fun (Hoard action) {
+      (Hoard ~) = ask ~ = (Take); ask (Save #0); (Done #0)
+    } with { {T4 {T4 #1 #2 #3 #4} {T4 #1 #2 #3 #4} {T4 #1 #2 #3 #4} {T4 #1 #2 #3 #4}} }
+    run { ask (Call 'Hoard' [#0]); (Done #0) }
+  ";
+  let results = rt.run_statements_from_code(code, true);
+  match &results[1] {
+    Ok(StatementInfo::Run { refunded_mana, size_diff, .. }) => {
+      assert!(*size_diff < 0);
+      assert!(*refunded_mana > 0);
+    }
+    other => panic!("Unexpected result: {:?}", other),
+  }
+}
+
+#[rstest]
+#[case(None, true)]
+#[case(Some(1_000_000), true)]
+#[case(Some(100), false)]
+fn call_limit_caps_mana(#[case] limit: Option<u128>, #[case] succeeds: bool, temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let limit = limit.map(|mana| format!("mana {{ #{} }}", mana)).unwrap_or_default();
+  let code = format!(
+    "
+    fun (Spin n) {{
+      (Spin #0) = #0
+      (Spin n) = (Spin (- n #1))
+    }}
+    fun (Lib action) {{
+      (Lib ~) = (Done (Spin #100))
+    }} {}
+    run {{ ask x = (Call 'Lib' [#0]); (Done x) }}
+  ",
+    limit
+  );
+  let results = rt.run_statements_from_code(&code, true);
+  assert!(results[1].is_ok());
+  assert_eq!(results[2].is_ok(), succeeds);
+}
+
+#[rstest]
+fn runs_bid_the_base_mana_price(temp_dir: TempDir) {
+  assert!(compute_next_mana_price(1000, BLOCK_MANA_LIMIT) > 1000);
+  assert_eq!(compute_next_mana_price(1000, BLOCK_MANA_TARGET), 1000);
+  assert!(compute_next_mana_price(1000, 0) < 1000);
+  let priced = read_statements("run { (Done (+ #1 #2)) } price { #2000 }").unwrap().1;
+  assert_eq!(read_statements(&view_statement(&priced[0])).unwrap().1, priced);
+
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let price = 2 * INITIAL_MANA_PRICE;
+  rt.set_mana_price(price);
+  rt.tick();
+  let burned = rt.get_burned();
+  // runs that bid under the base price fail, and the ones that bid none bid the initial price
+  assert!(rt.run_statements_from_code("run { (Done (+ #1 #2)) } price { #1999 }", true)[0].is_err());
+  assert!(rt.run_statements_from_code("run { (Done (+ #1 #2)) }", true)[0].is_err());
+  assert_eq!(rt.get_burned(), burned);
+  // the others burn the mana they use times the base price
+  match &rt.run_statements(&priced, true)[0] {
+    Ok(StatementInfo::Run { used_mana, .. }) => assert_eq!(rt.get_burned(), burned + used_mana * price),
+    other => panic!("Unexpected result: {:?}", other),
+  }
+  rt.update_mana_price(0);
+  assert_eq!(rt.get_mana_price(), compute_next_mana_price(price, 0));
+}
+
+#[test]
+fn rule_tables_keep_rule_order() {
+  let (_, statements) = read_statements("
+    fun (Pick a b) {
+      (Pick {Ta} ~) = #0
+      (Pick {Tb} ~) = #1
+      (Pick ~ {Tb}) = #2
+      (Pick {Tc} ~) = #3
+      (Pick #7 ~) = #4
+    }
+  ").unwrap();
+  let func = match &statements[0] {
+    crate::hvm::Statement::Fun { func, .. } => compile_func(func, false).unwrap(),
+    _ => unreachable!(),
+  };
+  let table = func.table.as_ref().unwrap();
+  assert_eq!(table.param, 0);
+  let ctr = |name: &str| crate::hvm::Ctr(name_to_u128(name), 0);
+  assert_eq!(table.candidates(ctr("Ta")), &[0, 2]);
+  assert_eq!(table.candidates(ctr("Tc")), &[2, 3]);
+  assert_eq!(table.candidates(crate::hvm::Num(7)), &[2, 4]);
+  assert_eq!(table.candidates(ctr("Td")), &[2]);
+}
+
+#[rstest]
+fn rule_tables_cost_the_same(temp_dir: TempDir) {
+  let code = "
+    ctr {Ta} ctr {Tb} ctr {Tc} ctr {Td}
+    fun (Cycle n) {
+      (Cycle {Ta}) = {Tb}
+      (Cycle {Tb}) = {Tc}
+      (Cycle {Tc}) = {Td}
+      (Cycle {Td}) = {Ta}
+    }
+    fun (Spin n x) {
+      (Spin #0 x) = x
+      (Spin n x) = (Spin (- n #1) (Cycle (Cycle (Cycle x))))
+    }
+    run { (Done (Spin #50 {Ta})) }
+  ";
+  let mut runs = vec![];
+  for tables in [true, false] {
+    let mut rt = init_runtime(Some(&temp_dir.path.join(tables.to_string())));
+    rt.set_rule_tables(tables);
+    runs.push(rt.run_statements_from_code(code, true).pop().unwrap());
+  }
+  match &runs[0] {
+    Ok(StatementInfo::Run { done_term, used_mana, .. }) => {
+      assert_eq!(view_term(done_term), "{Tc}");
+      assert!(*used_mana > 0);
+    }
+    other => panic!("Unexpected result: {:?}", other),
+  }
+  assert_eq!(format!("{:?}", runs[0]), format!("{:?}", runs[1]));
+}
+
+#[test]
+fn strict_args_roundtrip() {
+  let code = "fun (Sum n !acc) {\n  (Sum #0 acc) = acc\n  (Sum n acc) = (Sum (- n #1) (+ acc n))\n}";
+  let (_, statements) = read_statements(code).unwrap();
+  match &statements[0] {
+    crate::hvm::Statement::Fun { strict, .. } => assert_eq!(strict, &vec![1]),
+    _ => unreachable!(),
+  };
+  assert_eq!(view_statement(&statements[0]), format!("{} with {{\n  #0\n}}", code));
+  let bits = crate::bits::serialized_statement(&statements[0]);
+  assert_eq!(crate::bits::deserialized_statement(&bits), Some(statements[0].clone()));
+}
+
+#[rstest]
+fn strict_args_keep_accumulators_reduced(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  // Both save the sum through a function that doesn't match on it, so only `!acc` reduces it
+  let code = "
+    ctr {Total acc}
+    fun (LazySave acc) {
+      (LazySave acc) = ask (Save {Total acc}); (Done #0)
+    }
+    fun (StrictSave !acc) {
+      (StrictSave acc) = ask (Save {Total acc}); (Done #0)
+    }
+    fun (LazyGo n st) {
+      (LazyGo n {Total acc}) = (LazySave (+ acc n))
+    }
+    fun (StrictGo n st) {
+      (StrictGo n {Total acc}) = (StrictSave (+ acc n))
+    }
+    fun (Lazy n) {
+      (Lazy n) = ask st = (Take); (LazyGo n st)
+    } with { {Total #0} }
+    fun (Strict n) {
+      (Strict n) = ask st = (Take); (StrictGo n st)
+    } with { {Total #0} }
+  ";
+  assert!(rt.run_statements_from_code(code, true).iter().all(|result| result.is_ok()));
+  let mut sizes = vec![];
+  for name in ["Lazy", "Strict"] {
+    let mut size = 0;
+    for n in 1 ..= 20 {
+      let call = format!("run {{ ask (Call '{}' [#{}]); (Done #0) }}", name, n);
+      rt.tick();
+      match rt.run_statements_from_code(&call, true).pop().unwrap() {
+        Ok(StatementInfo::Run { size_diff, .. }) => size += size_diff,
+        other => panic!("Unexpected result: {:?}", other),
+      }
+    }
+    sizes.push(size);
+  }
+  // The lazy sum keeps a node per call, the strict one just a number
+  assert!(sizes[0] >= 40);
+  assert_eq!(sizes[1], 0);
+  let total = |name: &str| rt.show_term(rt.read_disk(name_to_u128(name)).unwrap());
+  assert_eq!(total("Strict"), "{Total #210}");
+}
+
+#[test]
+fn pure_funs_roundtrip() {
+  let code = "fun (Id x) {\n  (Id x) = x\n} with {\n  #0\n} mana { #100 } pure";
+  let (_, statements) = read_statements(code).unwrap();
+  match &statements[0] {
+    crate::hvm::Statement::Fun { pure, mana, .. } => assert_eq!((*pure, *mana), (true, Some(100))),
+    _ => unreachable!(),
+  };
+  assert_eq!(view_statement(&statements[0]), code);
+  let bits = crate::bits::serialized_statement(&statements[0]);
+  assert_eq!(crate::bits::deserialized_statement(&bits), Some(statements[0].clone()));
+}
+
+#[rstest]
+fn used_code_is_pinned(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let lib = "fun (Lib x) { (Lib x) = (+ x #1) }";
+  let (_, statements) = read_statements(lib).unwrap();
+  let code = match &statements[0] {
+    crate::hvm::Statement::Fun { name, func, .. } => crate::hvm::hash_func(*name, func),
+    _ => unreachable!(),
+  };
+  let dep = |name: &str, used: &str, code: &[u8]| {
+    format!("fun ({} x) {{\n  ({} x) = (Lib x)\n}} with {{\n  #0\n}} use '{}' {{ {} }}", name, name, used, hex::encode(code))
+  };
+  // Not deployed yet, so it can't be the pinned code
+  let early = dep("Early", "Lib", &code.0);
+  assert!(rt.run_statements_from_code(&early, true).pop().unwrap().is_err());
+  assert!(rt.run_statements_from_code(lib, true).pop().unwrap().is_ok());
+  assert!(rt.run_statements_from_code(&dep("Wrong", "Lib", &[0; 32]), true).pop().unwrap().is_err());
+  let pinned = dep("Pinned", "Lib", &code.0);
+  assert!(rt.run_statements_from_code(&pinned, true).pop().unwrap().is_ok());
+  // The pins are part of the statement
+  let (_, statements) = read_statements(&pinned).unwrap();
+  assert_eq!(view_statement(&statements[0]), pinned);
+  let bits = crate::bits::serialized_statement(&statements[0]);
+  assert_eq!(crate::bits::deserialized_statement(&bits), Some(statements[0].clone()));
+}
+
+#[rstest]
+fn names_are_listed_by_kind(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  // Namespaces are registered under the empty one, which belongs to the account of secret key 0x1
+  let mut key = [0; 32];
+  key[31] = 1;
+  let account = Account::from_private_key(&key);
+  let code = format!("ctr {{Pair a b}} fun (Swap p) {{ (Swap {{Pair a b}}) = {{Pair b a}} }} reg Swapz {{ #x{:0>30x} }} fun (Swapz.Get) {{ (Swapz.Get) = #2 }}", account.name.0);
+  let (_, statements) = read_statements(&code).unwrap();
+  let statements: Vec<_> = statements.iter().map(|statement| set_sign(statement, account.sign(&sign_hash(statement)))).collect();
+  assert!(rt.run_statements(&statements, true).iter().all(|r| r.is_ok()));
+  let find = |names: &[NameInfo], name: &str| {
+    names.iter().filter(|info| info.name == name_to_u128(name)).map(|info| (info.kind, info.arity)).collect::<Vec<_>>()
+  };
+  let names = rt.list_names(None);
+  assert_eq!(find(&names, "Pair"), vec![(NameKind::Ctr, Some(2))]);
+  assert_eq!(find(&names, "Swap"), vec![(NameKind::Fun, Some(1))]);
+  assert_eq!(find(&names, "Swapz"), vec![(NameKind::Reg, None)]);
+  assert_eq!(find(&names, "Swapz.Get"), vec![(NameKind::Fun, Some(0))]);
+  let swap = names.iter().find(|info| info.name == name_to_u128("Swap")).unwrap();
+  let func = rt.read_file(name_to_u128("Swap")).unwrap();
+  assert_eq!(swap.hash, Some(primitive_types::U256::from_big_endian(&hash_func(swap.name, &func.func).0)));
+  let funs = rt.list_names(Some(NameKind::Fun));
+  assert!(funs.iter().all(|info| info.kind == NameKind::Fun));
+  assert!(find(&funs, "Pair").is_empty());
+  assert_eq!(find(&funs, "Swap"), vec![(NameKind::Fun, Some(1))]);
+  assert_eq!("fun".parse::<NameKind>(), Ok(NameKind::Fun));
+  assert!("func".parse::<NameKind>().is_err());
+}
+
+#[rstest]
+fn arities_are_enforced(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let run = |rt: &mut Runtime, code: &str| rt.run_statements_from_code(code, true).pop().unwrap().map_err(|err| err.err);
+  assert!(run(&mut rt, "ctr {Twin a b}").is_ok());
+  assert_eq!(run(&mut rt, "run { (Done {Twin #1}) }").unwrap_err(), "'Twin' takes 2 arguments, but is applied to 1.");
+  assert_eq!(run(&mut rt, "run { (Done {Nope #1}) }").unwrap_err(), "'Nope' isn't declared.");
+  assert_eq!(run(&mut rt, "fun (Bad x) { (Bad x) = {Twin x} }").unwrap_err(), "'Twin' takes 2 arguments, but is applied to 1.");
+  // Rules can name constructors declared later, which are checked when the rules are applied
+  assert!(run(&mut rt, "fun (Early x) { (Early x) = {Later x} }").is_ok());
+  assert!(run(&mut rt, "ctr {Later a b}").is_ok());
+  assert_eq!(run(&mut rt, "run { (Done (Early #1)) }").unwrap_err(), "'Later' takes 2 arguments, but is applied to 1.");
+  assert!(check_heap(&rt).is_empty());
+  assert!(run(&mut rt, "run { (Done {Twin #1 #2}) }").is_ok());
+}
+
+#[rstest]
+fn rewrites_are_counted_by_kind(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let code = "
+    ctr {Pair a b}
+    fun (Swap p) {
+      (Swap {Pair a b}) = {Pair b a}
+    }
+    fun (Sum p) {
+      (Sum {Pair a b}) = (+ a b)
+    }
+  ";
+  assert!(rt.run_statements_from_code(code, true).iter().all(|result| result.is_ok()));
+  // nothing is counted until asked to
+  assert_eq!(rt.stop_rewrite_stats(), RewriteStats::default());
+  rt.start_rewrite_stats();
+  let code = "run { dup a b = {Pair #1 #2}; (Done (+ (Sum (Swap a)) (+ (Sum b) (+ (@x (+ x #1) #2) (MathSqrt #16))))) }";
+  assert!(rt.run_statements_from_code(code, true).iter().all(|result| result.is_ok()));
+  let stats = rt.stop_rewrite_stats();
+  let counts = [(Rewrite::AppLam, 1), (Rewrite::Op2Num, 6), (Rewrite::FunCtr, 4), (Rewrite::FunNum, 1), (Rewrite::DupNum, 2), (Rewrite::DupCtr, 1)];
+  assert_eq!(stats.total(), 15);
+  for (rewrite, count) in counts {
+    assert_eq!(stats.get(rewrite), count, "{}", rewrite.name());
+  }
+  let csv = stats.to_csv();
+  assert_eq!(csv.lines().count(), Rewrite::ALL.len() + 1);
+  assert!(csv.starts_with("rewrite,count,share\nAPP-LAM,1,"));
+  assert!(csv.contains("\nDUP-CTR,1,"));
+}
+
+#[rstest]
+fn pure_calls_are_memoized(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let code = "
+    fun (Sum n) {
+      (Sum #0) = #0
+      (Sum n) = dup a b = n; (+ a (Sum (- b #1)))
+    }
+    fun (PureSum n) {
+      (PureSum #0) = #0
+      (PureSum n) = dup a b = n; (+ a (PureSum (- b #1)))
+    } pure
+  ";
+  assert!(rt.run_statements_from_code(code, true).iter().all(|result| result.is_ok()));
+  let run = |rt: &mut Runtime, name: &str| {
+    let code = format!("run {{ (Done (+ ({} #200) ({} #200))) }}", name, name);
+    match rt.run_statements_from_code(&code, true).pop().unwrap() {
+      Ok(StatementInfo::Run { done_term, used_mana, .. }) => (view_term(&done_term), used_mana),
+      other => panic!("Unexpected result: {:?}", other),
+    }
+  };
+  let (lazy, lazy_mana) = run(&mut rt, "Sum");
+  let (pure, pure_mana) = run(&mut rt, "PureSum");
+  assert_eq!(lazy, "#40200");
+  assert_eq!(pure, lazy);
+  assert!(pure_mana < lazy_mana * 3 / 4);
+  // Later calls in the same block find the results, until the memo is cleared for the next one
+  let (_, warm_mana) = run(&mut rt, "PureSum");
+  assert!(warm_mana < pure_mana / 10);
+  rt.clear_memo();
+  assert_eq!(run(&mut rt, "PureSum").1, pure_mana);
+}
+
+#[rstest]
+fn pure_calls_stay_lazy(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let code = "
+    fun (Nats n) {
+      (Nats n) = dup a b = n; {Cons a (Nats (+ b #1))}
+    } pure
+    fun (Head xs) {
+      (Head {Cons x ~}) = x
+    }
+  ";
+  assert!(rt.run_statements_from_code(code, true).iter().all(|result| result.is_ok()));
+  let run = |rt: &mut Runtime, code: &str| {
+    match rt.run_statements_from_code(code, true).pop().unwrap() {
+      Ok(StatementInfo::Run { done_term, used_mana, .. }) => (view_term(&done_term), used_mana),
+      other => panic!("Unexpected result: {:?}", other),
+    }
+  };
+  // the infinite list is only reduced as far as its head, which isn't data, so it isn't memoized
+  let (head, mana) = run(&mut rt, "run { (Done (Head (Nats #7))) }");
+  assert_eq!(head, "#7");
+  assert_eq!(run(&mut rt, "run { (Done (Head (Nats #7))) }").1, mana);
+}
+
+#[rstest]
+fn pure_calls_on_large_arguments_arent_memoized(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let code = "
+    fun (Tally xs) {
+      (Tally {Nil}) = #0
+      (Tally {Cons ~ xs}) = (+ #1 (Tally xs))
+    }
+    fun (Len xs) {
+      (Len xs) = (Tally xs)
+    } pure
+  ";
+  assert!(rt.run_statements_from_code(code, true).iter().all(|result| result.is_ok()));
+  // a list of pairs, of 4 nodes per element
+  let run = |rt: &mut Runtime, size: u128| {
+    let list = (0 .. size).fold("{Nil}".to_string(), |list, x| format!("{{Cons {{Cons #{} #{}}} {}}}", x, x, list));
+    let code = format!("run {{ (Done (Len {})) }}", list);
+    match rt.run_statements_from_code(&code, true).pop().unwrap() {
+      Ok(StatementInfo::Run { used_mana, .. }) => used_mana,
+      other => panic!("Unexpected result: {:?}", other),
+    }
+  };
+  // a list of 30 elements fits a key, so the second call finds the result
+  let cold = run(&mut rt, 30);
+  assert!(run(&mut rt, 30) < cold);
+  // one of 70 doesn't, so it's computed again, at the same cost
+  let cold = run(&mut rt, 70);
+  assert_eq!(run(&mut rt, 70), cold);
+}
+
 #[rstest]
 fn one_hundred_snapshots(temp_dir: TempDir) {
   // run this with rollback in each 4th snapshot
@@ -333,6 +1411,111 @@ pub const BANK: &'static str = "
   }
 ";
 
+pub const PRE_WALLET: &'static str = "
+  ctr {Setup}
+
+  fun (Wallet action) {
+    (Wallet {Setup}) =
+      ask (Auth 'Wallet');
+      (Done #0)
+    (Wallet {Authorize ~ proof}) =
+      (Done (== proof #42))
+  } with { #7 }
+
+  run {
+    ask (Call 'Wallet' [{Setup}]);
+    (Done #0)
+  }
+";
+
+pub fn wallet_acts(proof: u128) -> String {
+  format!(
+    "
+    run {{
+      ask x = (Acts 'Wallet' #{} ((Load) @x (Done x)));
+      (Done x)
+    }}
+  ",
+    proof
+  )
+}
+
+pub const PRE_TIMER: &'static str = "
+  ctr {Timer_Arm tick mana}
+  ctr {Timer_Fire}
+
+  fun (Timer action) {
+    (Timer {Timer_Arm tick mana}) =
+      ask (Schedule tick mana ((Call 'Timer' [{Timer_Fire}]) @~ (Done #0)));
+      (Done #0)
+    (Timer {Timer_Fire}) =
+      ask x = (Take);
+      ask (Save (+ x #1));
+      (Done #0)
+  } with { #0 }
+";
+
+pub const PRE_FREEZE: &'static str = "
+  ctr {Counter_Inc}
+  ctr {Counter_Get}
+  ctr {Counter_Lock}
+
+  fun (Counter action) {
+    (Counter {Counter_Inc}) =
+      ask x = (Take);
+      ask (Save (+ x #1));
+      (Done #0)
+    (Counter {Counter_Get}) =
+      ask x = (Load);
+      (Done x)
+    (Counter {Counter_Lock}) =
+      ask (Freeze 'Counter');
+      (Done #0)
+  } with { #0 }
+";
+
+pub fn timer_arm(tick: u128, mana: u128) -> String {
+  format!(
+    "
+    run {{
+      ask (Call 'Timer' [{{Timer_Arm #{} #{}}}]);
+      (Done #0)
+    }}
+  ",
+    tick, mana
+  )
+}
+
+pub const PRE_HOOKS: &'static str = "
+  ctr {Bell_Ring x}
+  ctr {Logger_Start}
+  ctr {Logger_Stop}
+
+  fun (Bell action) {
+    (Bell {Bell_Ring x}) =
+      ask (Emit x);
+      (Done #0)
+  }
+
+  fun (Logger action) {
+    (Logger {Logger_Start}) =
+      ask (Subscribe 'Bell');
+      (Done #0)
+    (Logger {Logger_Stop}) =
+      ask (Unsubscribe 'Bell');
+      (Done #0)
+    (Logger {Event ~ x}) =
+      ask y = (Take);
+      ask (Save (+ x y));
+      (Done #0)
+  } with { #0 }
+
+  run {
+    ask (Call 'Logger' [{Logger_Start}]);
+    (Done #0)
+  }
+";
+
 pub fn keyword_fail_1(keyword: &str) -> String {
   format!(
     "
@@ -373,3 +1556,62 @@ pub fn keyword_fail_3(keyword: &str) -> String {
     keyword, keyword, keyword
   )
 }
+
+#[rstest]
+#[case("(rotl #1 #121)", "#2")]
+#[case("(rotl #664613997892457936451903530140172288 #1)", "#1")]
+#[case("(rotr #1 #1)", "#664613997892457936451903530140172288")]
+#[case("(rotr #6 #120)", "#6")]
+#[case("(clz #1)", "#119")]
+#[case("(clz #0)", "#120")]
+#[case("(popcount #255)", "#8")]
+#[case("(bswap #1)", "#5192296858534827628530496329220096")]
+#[case("(bswap (bswap #123456789))", "#123456789")]
+fn bitwise_operations(#[case] expr: &str, #[case] expected: &str, temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  match &rt.run_statements_from_code(&format!("run {{ (Done {}) }}", expr), true)[0] {
+    Ok(StatementInfo::Run { done_term, .. }) => assert_eq!(view_term(done_term), expected),
+    other => panic!("{} failed: {:?}", expr, other),
+  }
+}
+
+#[test]
+fn unary_operations_roundtrip() {
+  for code in ["(clz (popcount x))", "(bswap x #1)", "(rotl x #3)"] {
+    let (_, term) = read_term(code).unwrap();
+    assert_eq!(view_term(&term), code);
+    assert_eq!(crate::bits::deserialized_term(&crate::bits::serialized_term(&term)), Some(term));
+  }
+  // Named operations are followed by a space, so variables can start with their names
+  let (_, term) = read_term("(clzx y)").unwrap();
+  assert!(matches!(term, Term::App { .. }));
+}
+
+#[rstest]
+fn shared_states_read_back_as_bindings(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  rt.run_statements_from_code("
+    ctr {Pair a b}
+    fun (Shared action) {
+      (Shared {Nil}) =
+        ask (Save dup f g = @x {Pair x #1}; dup h i = f; dup j k = {Pair h g}; dup l m = {Pair j k}; dup n o = {Pair l m}; {Pair n {Pair o i}});
+        (Done #0)
+    } with { #0 }
+    fun (Erased action) {
+      (Erased {Nil}) = ask (Save dup f ~ = @x {Pair x #2}; {Pair f #0}); (Done #0)
+    } with { #0 }
+  ", true);
+  advance(&mut rt, 10, None);
+  rt.run_statements_from_code("run { ask (Call 'Shared' [{Nil}]); (Done #0) } run { ask (Call 'Erased' [{Nil}]); (Done #0) }", true);
+  // each binding comes before the ones using it, so the state loads back as it reads
+  let shared = rt.read_disk_as_term(name_to_u128("Shared")).unwrap();
+  assert_eq!(
+    view_term(&shared),
+    "dup a4 b4 = @x5 {Pair x5 #1}; dup a3 b3 = a4; dup a2 b2 = {Pair a3 b4}; dup a1 b1 = {Pair a2 b2}; dup a0 b0 = {Pair a1 b1}; {Pair a0 {Pair b0 b3}}",
+  );
+  let host = rt.alloc_term(&shared);
+  assert_terms_eq(&readback_term(&rt, rt.read(host)), &shared);
+  // a dup with an erased side shares nothing, so its expression is read in place
+  let erased = rt.read_disk_as_term(name_to_u128("Erased")).unwrap();
+  assert_eq!(view_term(&erased), "{Pair @x1 {Pair x1 #2} #0}");
+}
@@ -0,0 +1,57 @@
+use crate::mdns::{build_query, build_response, parse_packet, SERVICE};
+
+#[test]
+fn mdns_query() {
+  let packet = parse_packet(&build_query()).unwrap();
+  assert!(!packet.response);
+  assert_eq!(packet.questions, vec![(SERVICE.to_string(), 12)]);
+  assert!(packet.services.is_empty());
+}
+
+#[test]
+fn mdns_response() {
+  let packet = parse_packet(&build_response("kindelia-00ff", 42001)).unwrap();
+  assert!(packet.response);
+  assert!(packet.questions.is_empty());
+  assert_eq!(packet.services, vec![("kindelia-00ff".to_string(), 42001)]);
+}
+
+#[test]
+fn mdns_compressed_names() {
+  // a response whose SRV record name points back to the PTR record's data
+  let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 2, 0, 0, 0, 0];
+  let service = 12; // offset of the service name
+  for label in ["_kindelia", "_udp", "local"] {
+    packet.push(label.len() as u8);
+    packet.extend_from_slice(label.as_bytes());
+  }
+  packet.push(0);
+  packet.extend_from_slice(&[0, 12, 0, 1, 0, 0, 0, 120, 0, 8]);
+  let instance = packet.len();
+  packet.extend_from_slice(&[5, b'n', b'o', b'd', b'e', b'1', 0xC0, service as u8]);
+  packet.extend_from_slice(&[0xC0, instance as u8, 0, 33, 0, 1, 0, 0, 0, 120, 0, 8]);
+  packet.extend_from_slice(&[0, 0, 0, 0, 0xa4, 0x10, 0xC0, service as u8]);
+  let parsed = parse_packet(&packet).unwrap();
+  assert_eq!(parsed.services, vec![("node1".to_string(), 42000)]);
+
+  // pointers that loop, or forward, are rejected
+  let mut looping = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+  looping.extend_from_slice(&[0xC0, 12, 0, 12, 0, 1]);
+  assert!(parse_packet(&looping).is_none());
+  // truncated packets too
+  assert!(parse_packet(&packet[.. packet.len() - 3]).is_none());
+}
+
+#[test]
+#[ignore = "needs multicast"]
+fn mdns_discovery() {
+  let mut a = crate::mdns::Mdns::new(42100).unwrap();
+  let mut b = crate::mdns::Mdns::new(42101).unwrap();
+  a.query();
+  std::thread::sleep(std::time::Duration::from_millis(100));
+  assert!(a.poll().is_empty());
+  b.poll(); // answers
+  std::thread::sleep(std::time::Duration::from_millis(100));
+  let found = a.poll();
+  assert!(found.iter().any(|addr| addr.port() == 42101), "{:?}", found);
+}
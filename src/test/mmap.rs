@@ -0,0 +1,48 @@
+use crate::{
+  hvm::U128_NONE,
+  mmap::MappedWords,
+  test::util::{temp_dir, TempDir},
+};
+use proptest::collection::vec;
+use proptest::proptest;
+use rstest::rstest;
+
+#[rstest]
+fn mapped_words_persist(temp_dir: TempDir) {
+  std::fs::create_dir_all(&temp_dir.path).unwrap();
+  let path = temp_dir.path.join("words.bin");
+  let mut words = MappedWords::open(&path).unwrap();
+  assert_eq!(words.read(7), U128_NONE);
+  words.write(7, 0);
+  words.write(1 << 20, 42); // grows the file
+  words.sync().unwrap();
+  drop(words);
+  let mut words = MappedWords::open(&path).unwrap();
+  assert_eq!(words.read(7), 0);
+  assert_eq!(words.read(1 << 20), 42);
+  assert_eq!(words.iter().collect::<Vec<_>>(), vec![(7, 0), (1 << 20, 42)]);
+  words.clear();
+  assert_eq!(words.iter().count(), 0);
+}
+
+proptest! {
+  #[test]
+  fn mapped_words_match_map(writes in vec((0 .. 1u128 << 18, 0 .. U128_NONE), 0..64)) {
+    let dir = temp_dir();
+    std::fs::create_dir_all(&dir.path).unwrap();
+    let path = dir.path.join("words.bin");
+    let mut words = MappedWords::open(&path).unwrap();
+    let mut map = std::collections::BTreeMap::new();
+    for (idx, val) in writes {
+      words.write(idx, val);
+      map.insert(idx, val);
+    }
+    let map = map.into_iter().collect::<Vec<_>>();
+    assert_eq!(words.iter().collect::<Vec<_>>(), map);
+    // The occupancy is rebuilt from the file
+    words.sync().unwrap();
+    drop(words);
+    let words = MappedWords::open(&path).unwrap();
+    assert_eq!(words.iter().collect::<Vec<_>>(), map);
+  }
+}
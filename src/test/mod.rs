@@ -3,6 +3,33 @@ mod strategies;
 mod util;
 
 // test modules
+mod backup;
+mod bench;
 mod bits;
+mod cli;
+mod client;
+mod color;
+mod datadir;
 mod hasher;
+mod http;
 mod hvm;
+mod names;
+mod net;
+mod node;
+mod onion;
+mod policy;
+mod profile;
+mod query;
+mod runtime;
+mod socks;
+mod stdlib;
+mod token;
+mod upgrade;
+mod wallet;
+mod webhook;
+#[cfg(feature = "graphql")]
+mod graphql;
+#[cfg(feature = "mdns")]
+mod mdns;
+#[cfg(feature = "mmap")]
+mod mmap;
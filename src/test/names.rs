@@ -0,0 +1,183 @@
+use crate::{
+  crypto::{Account, Name},
+  hvm::{hash_term, init_runtime, name_to_u128, read_statements, set_sign, sign_hash, Runtime, StatementInfo, Term},
+  test::util::{temp_dir, TempDir},
+};
+use rstest::rstest;
+
+const TOKEN : &str = include_str!("../../example/token.kdl");
+
+// Blocks each phase of an auction lasts
+const PHASE : u128 = 28800;
+
+fn foundation() -> Account {
+  let mut key = [0; 32];
+  key[31] = 1;
+  Account::from_private_key(&key)
+}
+
+fn alice() -> Account {
+  Account::from_private_key(&[2; 32])
+}
+
+fn bob() -> Account {
+  Account::from_private_key(&[3; 32])
+}
+
+fn name(account: &Account) -> String {
+  format!("#x{:0>30x}", account.name.0)
+}
+
+// Runs a statement, signed if an account is given, in a block of its own
+fn run(rt: &mut Runtime, account: Option<&Account>, code: &str) -> Result<u128, String> {
+  let (_, statements) = read_statements(code).unwrap();
+  let mut statement = statements[0].clone();
+  if let Some(account) = account {
+    statement = set_sign(&statement, account.sign(&sign_hash(&statement)));
+  }
+  let result = rt.run_statement(&statement, true);
+  rt.tick();
+  match result {
+    Ok(StatementInfo::Run { done_term: Term::Num { numb }, .. }) => Ok(numb),
+    Ok(_) => Ok(0),
+    Err(err) => Err(err.err),
+  }
+}
+
+fn call(rt: &mut Runtime, account: Option<&Account>, func: &str, action: &str) -> u128 {
+  let code = format!("run {{ ask x = (Call '{}' [{}]); (Done x) }}", func, action);
+  run(rt, account, &code).unwrap()
+}
+
+fn balance(rt: &mut Runtime, account: &Account) -> u128 {
+  call(rt, None, "Token", &format!("{{TokenBalance {}}}", name(account)))
+}
+
+// What bidders seal, as the registry hashes it
+fn seal(short: &str, amount: u128, salt: u128, bidder: &Account) -> u128 {
+  let args = [name_to_u128(short), amount, salt, bidder.name.0].map(|numb| Term::Num { numb }).to_vec();
+  Name::from_hash(&hash_term(&Term::Ctr { name: name_to_u128("T4"), args })).0
+}
+
+fn bid(rt: &mut Runtime, bidder: &Account, short: &str, amount: u128, salt: u128) -> u128 {
+  let seal = seal(short, amount, salt, bidder);
+  call(rt, Some(bidder), "Names", &format!("{{NamesBid '{}' #{}}}", short, seal))
+}
+
+// Pays `amount` and reveals a bid of `amount`, in one statement
+fn reveal(rt: &mut Runtime, bidder: &Account, short: &str, amount: u128, salt: u128) -> u128 {
+  let code = format!(
+    "run {{ ask (Call 'Token' [{{TokenSend 'Names' #{}}}]); ask ok = (Call 'Names' [{{NamesReveal '{}' #{} #{}}}]); (Done ok) }}",
+    amount, short, amount, salt
+  );
+  run(rt, Some(bidder), &code).unwrap()
+}
+
+fn advance_to(rt: &mut Runtime, tick: u128) {
+  rt.set_tick(tick - 1);
+  rt.tick();
+}
+
+// A runtime with a payment token set, and 500 of it for alice and bob
+fn setup(temp_dir: &TempDir) -> Runtime {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  advance_to(&mut rt, 100); // leaves room for the state to grow
+  for result in rt.run_statements_from_code(TOKEN, true) {
+    result.unwrap();
+  }
+  assert_eq!(call(&mut rt, Some(&alice()), "Names", "{NamesToken 'Token'}"), 0);
+  assert_eq!(call(&mut rt, Some(&foundation()), "Names", "{NamesToken 'Token'}"), 1);
+  assert_eq!(call(&mut rt, Some(&foundation()), "Names", "{NamesToken 'Other'}"), 0);
+  call(&mut rt, Some(&alice()), "Token", "{TokenMint #1000}");
+  call(&mut rt, Some(&alice()), "Token", &format!("{{TokenSend {} #500}}", name(&bob())));
+  rt
+}
+
+#[rstest]
+fn short_names_are_auctioned(temp_dir: TempDir) {
+  let mut rt = setup(&temp_dir);
+  let abc = name_to_u128("Abc");
+
+  // short namespaces aren't registered, only won
+  assert!(run(&mut rt, Some(&foundation()), &format!("reg Abc {{ {} }}", name(&alice()))).is_err());
+  assert!(run(&mut rt, Some(&foundation()), &format!("reg Abcde {{ {} }}", name(&alice()))).is_ok());
+
+  let start = rt.get_tick(); // the auction starts with the first bid
+  assert_eq!(bid(&mut rt, &alice(), "Abc", 100, 7), 1);
+  assert_eq!(bid(&mut rt, &bob(), "Abc", 200, 9), 1);
+
+  // reveals wait for the bidding to end, and the deposit is sent back
+  assert_eq!(reveal(&mut rt, &alice(), "Abc", 100, 7), 0);
+  assert_eq!(balance(&mut rt, &alice()), 500);
+
+  advance_to(&mut rt, start + PHASE);
+  assert_eq!(bid(&mut rt, &alice(), "Abc", 300, 7), 0);
+  assert_eq!(reveal(&mut rt, &alice(), "Abc", 100, 7), 1);
+  assert_eq!(balance(&mut rt, &alice()), 400);
+  assert_eq!(reveal(&mut rt, &bob(), "Abc", 200, 8), 0);
+  assert_eq!(reveal(&mut rt, &bob(), "Abc", 200, 9), 1);
+  assert_eq!(balance(&mut rt, &alice()), 500);
+  assert_eq!(balance(&mut rt, &bob()), 300);
+
+  assert_eq!(call(&mut rt, None, "Names", "{NamesClaim 'Abc'}"), 0);
+  advance_to(&mut rt, start + 2 * PHASE);
+  assert_eq!(reveal(&mut rt, &alice(), "Abc", 300, 7), 0);
+  assert_eq!(call(&mut rt, None, "Names", "{NamesClaim 'Abc'}"), 1);
+  assert_eq!(rt.get_owner(abc), bob().name.0);
+  assert_eq!(balance(&mut rt, &foundation()), 200);
+  assert_eq!(balance(&mut rt, &bob()), 300);
+  assert_eq!(balance(&mut rt, &alice()), 500);
+
+  // the winner deploys on it
+  assert!(run(&mut rt, Some(&alice()), "fun (Abc.Foo) { (Abc.Foo) = #1 }").is_err());
+  assert!(run(&mut rt, Some(&bob()), "fun (Abc.Foo) { (Abc.Foo) = #1 }").is_ok());
+}
+
+#[rstest]
+fn owners_rotate_after_a_delay(temp_dir: TempDir) {
+  let mut rt = setup(&temp_dir);
+  let space = name_to_u128("Abcde");
+  run(&mut rt, Some(&foundation()), &format!("reg Abcde {{ {} }}", name(&alice()))).unwrap();
+
+  // only the owner rotates, and keeps the namespace until the delay passes
+  assert!(run(&mut rt, Some(&bob()), &format!("rot Abcde {{ {} #3 }}", name(&bob()))).is_err());
+  run(&mut rt, Some(&alice()), &format!("rot Abcde {{ {} #3 }}", name(&bob()))).unwrap();
+  let (succ, tick) = rt.get_rotation(space).unwrap();
+  assert_eq!((succ, tick), (bob().name.0, rt.get_tick() + 2));
+  assert_eq!(rt.get_owner(space), alice().name.0);
+  assert!(run(&mut rt, Some(&alice()), "fun (Abcde.Foo) { (Abcde.Foo) = #1 }").is_ok());
+
+  advance_to(&mut rt, tick);
+  assert_eq!(rt.get_owner(space), bob().name.0);
+  assert!(run(&mut rt, Some(&alice()), "fun (Abcde.Bar) { (Abcde.Bar) = #1 }").is_err());
+  assert!(run(&mut rt, Some(&bob()), "fun (Abcde.Bar) { (Abcde.Bar) = #1 }").is_ok());
+
+  // a pending rotation is replaced by the next one, which cancels it
+  run(&mut rt, Some(&bob()), &format!("rot Abcde {{ {} #10 }}", name(&alice()))).unwrap();
+  run(&mut rt, Some(&bob()), &format!("rot Abcde {{ {} #0 }}", name(&bob()))).unwrap();
+  let tick = rt.get_tick();
+  advance_to(&mut rt, tick + 10);
+  assert_eq!(rt.get_owner(space), bob().name.0);
+  assert!(run(&mut rt, Some(&alice()), &format!("rot Abcde {{ {} #0 }}", name(&alice()))).is_err());
+}
+
+#[rstest]
+fn taken_names_send_bids_back(temp_dir: TempDir) {
+  let mut rt = setup(&temp_dir);
+  assert_eq!(bid(&mut rt, &alice(), "Names", 100, 1), 0);
+
+  // alice wins 'Abc', then bob bids for it again, but it's already owned
+  for (bidder, won) in [(alice(), 1), (bob(), 0)] {
+    let start = rt.get_tick();
+    assert_eq!(bid(&mut rt, &bidder, "Abc", 100, 1), 1);
+    advance_to(&mut rt, start + PHASE);
+    assert_eq!(reveal(&mut rt, &bidder, "Abc", 100, 1), 1);
+    assert_eq!(balance(&mut rt, &bidder), 400);
+    advance_to(&mut rt, start + 2 * PHASE);
+    assert_eq!(call(&mut rt, None, "Names", "{NamesClaim 'Abc'}"), won);
+  }
+  assert_eq!(rt.get_owner(name_to_u128("Abc")), alice().name.0);
+  assert_eq!(balance(&mut rt, &alice()), 400);
+  assert_eq!(balance(&mut rt, &bob()), 500);
+  assert_eq!(balance(&mut rt, &foundation()), 100);
+}
@@ -0,0 +1,212 @@
+use crate::{
+  bits::serialized_message,
+  net::{NetStats, Network, OnionConfig, INBOX_CAPACITY, OUTBOX_CAPACITY},
+  node::{udp_bind, udp_recv, Address, Message},
+  onion::onion_host,
+  util::bitvec_to_bytes,
+};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+fn local_udp() -> UdpSocket {
+  udp_bind("127.0.0.1:0".parse().unwrap()).unwrap()
+}
+
+fn start(capacity: usize) -> Network {
+  Network::start(vec![local_udp()], None, None, capacity, OUTBOX_CAPACITY).unwrap()
+}
+
+// Sends `count` messages as fast as the socket takes them
+fn flood(to: SocketAddr, count: usize) {
+  flood_paced(to, count, usize::MAX);
+}
+
+// Sends `count` messages, pausing after each `burst`, so fewer are lost before reaching the network
+fn flood_paced(to: SocketAddr, count: usize, burst: usize) {
+  let socket = local_udp();
+  let data = bitvec_to_bytes(&serialized_message(&Message::GiveMeThatBlock { bhash: 7.into() }));
+  for sent in 1 ..= count {
+    while socket.send_to(&data, to).is_err() {
+      std::thread::yield_now();
+    }
+    if sent % burst == 0 {
+      std::thread::sleep(Duration::from_micros(200));
+    }
+  }
+}
+
+// Waits until the network's stats satisfy `done`
+fn wait_for(net: &Network, done: impl Fn(&NetStats) -> bool) -> NetStats {
+  let start = Instant::now();
+  loop {
+    let stats = net.stats();
+    if done(&stats) {
+      return stats;
+    }
+    assert!(start.elapsed() < Duration::from_secs(10), "network stuck at {:?}", stats);
+    std::thread::sleep(Duration::from_millis(1));
+  }
+}
+
+// Waits until the network stops receiving, as the socket's buffer may still hold a flood
+fn settle(net: &Network) -> NetStats {
+  let mut last = net.stats();
+  loop {
+    std::thread::sleep(Duration::from_millis(50));
+    let stats = net.stats();
+    if (stats.received, stats.dropped_in) == (last.received, last.dropped_in) {
+      return stats;
+    }
+    last = stats;
+  }
+}
+
+// Resident memory of this process, in bytes
+fn resident_memory() -> u64 {
+  let statm = std::fs::read_to_string("/proc/self/statm").unwrap();
+  let pages: u64 = statm.split_whitespace().nth(1).unwrap().parse().unwrap();
+  pages * 4096
+}
+
+#[test]
+fn inbox_is_bounded() {
+  let mut net = start(16);
+  flood(net.locals[0], 2000);
+  // a node that doesn't keep up only holds as many messages as fit on the inbox
+  let stats = wait_for(&net, |stats| stats.dropped_in > 0);
+  assert_eq!((stats.received, stats.queued_in), (16, 16));
+  let mut taken = 0;
+  while let Some(incoming) = net.recv() {
+    assert!(matches!(incoming.message, Message::GiveMeThatBlock { .. }));
+    taken += 1;
+  }
+  assert_eq!(taken, 16);
+
+  // once drained, messages get through again
+  flood(net.locals[0], 1);
+  wait_for(&net, |stats| stats.queued_in > 0);
+  assert!(net.recv().is_some());
+}
+
+#[test]
+fn sends_through_outbox() {
+  let net = start(16);
+  let mut peer = local_udp();
+  let data = bitvec_to_bytes(&serialized_message(&Message::GiveMeThatBlock { bhash: 7.into() }));
+  net.send(0, peer.local_addr().unwrap(), Arc::new(data));
+  wait_for(&net, |stats| stats.sent == 1);
+  let start = Instant::now();
+  let received = loop {
+    let received = udp_recv(&mut peer);
+    if !received.is_empty() || start.elapsed() > Duration::from_secs(5) {
+      break received;
+    }
+    std::thread::sleep(Duration::from_millis(1));
+  };
+  assert_eq!(received.len(), 1);
+  assert_eq!(received[0].0, Address::from(net.locals[0]));
+}
+
+// A SOCKS5 proxy standing for Tor: it connects `host` to `service`, and refuses other hosts
+fn fake_tor(host: String, service: SocketAddr) -> SocketAddr {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  std::thread::spawn(move || {
+    for stream in listener.incoming() {
+      let mut client = stream.unwrap();
+      let mut greeting = [0; 3];
+      client.read_exact(&mut greeting).unwrap();
+      client.write_all(&[5, 0]).unwrap();
+      let mut request = [0; 5];
+      client.read_exact(&mut request).unwrap();
+      assert_eq!(&request[.. 4], &[5, 1, 0, 3]);
+      let mut target = vec![0; request[4] as usize + 2];
+      client.read_exact(&mut target).unwrap();
+      if target[.. target.len() - 2] != *host.as_bytes() {
+        client.write_all(&[5, 4, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+        continue;
+      }
+      client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+      let server = TcpStream::connect(service).unwrap();
+      let (mut client_in, mut server_out) = (client.try_clone().unwrap(), server.try_clone().unwrap());
+      std::thread::spawn(move || std::io::copy(&mut client_in, &mut server_out));
+      let (mut server_in, mut client_out) = (server, client);
+      std::thread::spawn(move || std::io::copy(&mut server_in, &mut client_out));
+    }
+  });
+  return addr;
+}
+
+fn free_port() -> u16 {
+  TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+// Waits for the next block asked on the network, returning who asked it
+fn next_asked(net: &mut Network) -> (Address, u64) {
+  let start = Instant::now();
+  loop {
+    if let Some(incoming) = net.recv() {
+      let Message::GiveMeThatBlock { bhash } = incoming.message else { panic!("unexpected message") };
+      return (incoming.from, bhash.low_u64());
+    }
+    assert!(start.elapsed() < Duration::from_secs(10), "no message arrived");
+    std::thread::sleep(Duration::from_millis(1));
+  }
+}
+
+#[test]
+fn onion_peers_over_tor() {
+  let service = Address::Onion { key: [1; 32], port: free_port() };
+  let listen = SocketAddr::from(([127, 0, 0, 1], service.port()));
+  let Address::Onion { key, .. } = service else { unreachable!() };
+  let tor = fake_tor(onion_host(&key), listen);
+  let onion = |own| Some(OnionConfig { tor, own, listen: None });
+  let mut server = Network::start(vec![local_udp()], None, Some(OnionConfig { tor, own: Some(service), listen: Some(listen) }), 16, OUTBOX_CAPACITY).unwrap();
+  let ask = |bhash: u64| Arc::new(bitvec_to_bytes(&serialized_message(&Message::GiveMeThatBlock { bhash: bhash.into() })));
+
+  // a peer with an onion service is known by its address, and answered on its connection
+  let client_addr = Address::Onion { key: [2; 32], port: 42000 };
+  let mut client = Network::start(vec![local_udp()], None, onion(Some(client_addr)), 16, OUTBOX_CAPACITY).unwrap();
+  client.send_onion(service, ask(7));
+  assert_eq!(next_asked(&mut server), (client_addr, 7));
+  server.send_onion(client_addr, ask(8));
+  assert_eq!(next_asked(&mut client), (service, 8));
+
+  // a peer without one is given an unshareable address, but answered all the same
+  let mut anonymous = Network::start(vec![local_udp()], None, onion(None), 16, OUTBOX_CAPACITY).unwrap();
+  anonymous.send_onion(service, ask(9));
+  let (from, asked) = next_asked(&mut server);
+  assert_eq!(asked, 9);
+  assert!(!from.is_shareable());
+  server.send_onion(from, ask(10));
+  assert_eq!(next_asked(&mut anonymous), (service, 10));
+
+  // messages to onion addresses Tor can't reach are dropped
+  client.send_onion(Address::Onion { key: [3; 32], port: 42000 }, ask(11));
+  std::thread::sleep(Duration::from_millis(100));
+  assert!(server.recv().is_none());
+}
+
+// Measures the whole process, so it's run alone: `cargo test memory_stable_under_flood -- --ignored`
+#[test]
+#[ignore]
+fn memory_stable_under_flood() {
+  let net = start(INBOX_CAPACITY);
+  let rounds = 10;
+  let per_round = 50_000;
+  let mut memory = vec![];
+  for _ in 0 .. rounds {
+    flood_paced(net.locals[0], per_round, 64);
+    let stats = settle(&net);
+    assert!(stats.queued_in <= INBOX_CAPACITY);
+    memory.push(resident_memory());
+  }
+  // a queue holding every message would grow by megabytes each round
+  let growth = memory[rounds - 1].saturating_sub(memory[0]);
+  assert!(growth < 8 << 20, "memory grew by {} bytes: {:?}", growth, memory);
+  let stats = net.stats();
+  assert_eq!(stats.received, INBOX_CAPACITY as u64);
+  assert!(stats.dropped_in > 0);
+}
@@ -0,0 +1,1068 @@
+use crate::{
+  api::{NodeEvent, NodeRequest},
+  crypto::Account,
+  bits::{deserialized_address, serialized_address, serialized_statement},
+  hvm::{check_saved_state, hash_statement, init_runtime, name_to_u128, read_statements, set_sign, sign_hash, view_statement, view_term, StatementUsage},
+  node::{
+    block_meta, code_to_body, discard_corrupted_state, extract_extra_data, extract_transactions, get_state_hash, group_statements, miner_loop, new_block, preexecute, read_address, read_block_index, replay_blocks, shadow_blocks, try_mine, tune_thread, udp_bind, udp_recv, udp_send, Address,
+    AddressFamily, BlockHeader, Candidate, Chaos, BlockTree, Body, DiskMonitor, ForkChoice, ForkChoiceRule, ForkStats, HeaviestSubtree, LocalPool, Message, MinerCommunication, MinerMessage, MostWork, NetConfig, Node, NodeRng, Peer,
+    PeersStore, PoolExpiry, PoolStatus, ReplayVerifier, ShadowExecution, StatementDeps, ThreadTuning, Traffic, TrafficStore, Transaction, UsageStats, EVICTED_LIMIT, MAX_TRAFFIC_PEERS, PEER_TIMEOUT, SLOWEST_STATEMENTS,
+    target_to_difficulty, compute_period_target, BLOCKS_PER_PERIOD, BODIES_PER_REQUEST, HEADERS_PER_MESSAGE, MAX_BODY_SIZE, SYNC_MAX_ATTEMPTS, SYNC_REQUEST_TIMEOUT, SYNC_WINDOW, DELAY_TOLERANCE, INITIAL_DIFFICULTY, INITIAL_TARGET, MAX_EXTRA_DATA, MAX_HISTORY_QUERIES, REBROADCAST_DELAY, TEMPLATE_REFRESH_DELAY, TIME_PER_BLOCK, TIME_PER_PERIOD, ZERO_HASH,
+  },
+  policy::{LocalPolicy, PolicyConfig},
+  test::{hvm::PRE_HOOKS, strategies::address, util::{advance, temp_dir, test_rng}},
+  util::{bitvec_to_bytes, u256, u256map_from, u256map_new, Clock, ManualClock, U256, U256Map},
+};
+use proptest::proptest;
+use rstest::rstest;
+use rand::SeedableRng;
+use std::net::SocketAddr;
+
+#[test]
+fn traffic_per_peer_and_kind() {
+  let (a, b) = (read_address("10.0.0.1:42000"), read_address("10.0.0.2:42000"));
+  let mut traffic = TrafficStore::new(None);
+  assert!(traffic.send(a, "GiveMeThatBlock", 40, 0));
+  assert!(traffic.send(b, "GiveMeThatBlock", 40, 0));
+  assert!(traffic.recv(a, "NoticeTheseBlocks", 1400, 0));
+  assert_eq!(traffic.get_peer(&a), Traffic { sent_bytes: 40, sent_count: 1, recv_bytes: 1400, recv_count: 1, dropped: 0 });
+  assert_eq!(traffic.get_peer(&b), Traffic { sent_bytes: 40, sent_count: 1, ..Traffic::default() });
+  let kinds = traffic.get_kinds();
+  assert_eq!(kinds.iter().map(|(kind, _)| *kind).collect::<Vec<_>>(), vec!["GiveMeThatBlock", "NoticeTheseBlocks"]);
+  assert_eq!(kinds[0].1.sent_bytes, 80);
+  assert_eq!(traffic.get_total(), Traffic { sent_bytes: 80, sent_count: 2, recv_bytes: 1400, recv_count: 1, dropped: 0 });
+}
+
+#[test]
+fn traffic_bandwidth_cap() {
+  let (a, b) = (read_address("10.0.0.1:42000"), read_address("10.0.0.2:42000"));
+  let mut traffic = TrafficStore::new(Some(1000));
+  assert!(traffic.send(a, "NoticeTheseBlocks", 600, 5_000));
+  // over the cap of this second
+  assert!(!traffic.send(a, "NoticeTheseBlocks", 600, 5_500));
+  // each way, and each peer, has its own cap
+  assert!(traffic.recv(a, "NoticeTheseBlocks", 600, 5_500));
+  assert!(traffic.send(b, "NoticeTheseBlocks", 600, 5_500));
+  // the cap resets every second
+  assert!(traffic.send(a, "NoticeTheseBlocks", 600, 6_000));
+  let peer = traffic.get_peer(&a);
+  assert_eq!((peer.sent_count, peer.sent_bytes, peer.dropped), (2, 1200, 1));
+}
+
+#[test]
+fn traffic_forgets_inactive_peers() {
+  let (a, b) = (read_address("10.0.0.1:42000"), read_address("10.0.0.2:42000"));
+  let mut traffic = TrafficStore::new(Some(1000));
+  assert!(traffic.send(a, "NoticeTheseBlocks", 600, 1_000));
+  assert!(traffic.send(b, "NoticeTheseBlocks", 600, PEER_TIMEOUT));
+  traffic.evict(PEER_TIMEOUT + 2_000);
+  assert_eq!(traffic.get_peer(&a), Traffic::default());
+  assert_eq!(traffic.get_peer(&b).sent_bytes, 600);
+  // the totals still count the forgotten peer
+  assert_eq!(traffic.get_total().sent_bytes, 1200);
+  // spoofed senders can't grow it past the cap, and the oldest are forgotten first
+  for i in 0 .. MAX_TRAFFIC_PEERS as u128 + 8 {
+    let addr = Address::IPv6 { segs: [0xfd00, 0, 0, 0, 0, 0, (i >> 16) as u16, i as u16], port: 42000 };
+    traffic.recv(addr, "NoticeTheseBlocks", 1, PEER_TIMEOUT + 3_000 + i);
+  }
+  assert_eq!(traffic.get_peer_count(), MAX_TRAFFIC_PEERS);
+  assert_eq!(traffic.get_peer(&b), Traffic::default());
+}
+
+proptest! {
+  #[test]
+  fn address_roundtrip(addr in address()) {
+    assert_eq!(deserialized_address(&serialized_address(&addr)), Some(addr));
+    assert_eq!(read_address(&addr.to_string()), addr);
+  }
+}
+
+#[test]
+fn read_addresses() {
+  assert_eq!(read_address("10.0.0.1"), Address::IPv4 { val0: 10, val1: 0, val2: 0, val3: 1, port: 42000 });
+  assert_eq!(read_address("[::1]:42001"), Address::IPv6 { segs: [0, 0, 0, 0, 0, 0, 0, 1], port: 42001 });
+  assert_eq!(read_address("2001:db8::7"), Address::IPv6 { segs: [0x2001, 0xdb8, 0, 0, 0, 0, 0, 7], port: 42000 });
+  // as received by dual-stack sockets
+  assert_eq!(read_address("[::ffff:10.0.0.1]:42000"), read_address("10.0.0.1:42000"));
+  assert_eq!(read_address("[::1]:42001").family(), AddressFamily::IPv6);
+}
+
+#[test]
+fn udp_over_ipv6() {
+  let Ok(mut a) = udp_bind("[::1]:0".parse::<SocketAddr>().unwrap()) else {
+    eprintln!("IPv6 loopback unavailable, skipping");
+    return;
+  };
+  let mut b = udp_bind("[::1]:0".parse::<SocketAddr>().unwrap()).unwrap();
+  let to = Address::from(b.local_addr().unwrap());
+  udp_send(&mut a, vec![to], &Message::GiveMeThatBlock { bhash: 7.into() });
+  std::thread::sleep(std::time::Duration::from_millis(50));
+  let received = udp_recv(&mut b);
+  assert_eq!(received.len(), 1);
+  assert_eq!(received[0].0, Address::from(a.local_addr().unwrap()));
+  assert!(matches!(received[0].1, Message::GiveMeThatBlock { .. }));
+}
+
+#[test]
+fn prefer_address_family() {
+  let mut peers = PeersStore::new(Some(AddressFamily::IPv6));
+  for addr in ["10.0.0.1", "10.0.0.2", "[2001:db8::1]:42000", "10.0.0.3"] {
+    peers.see_peer(Peer { address: read_address(addr), seen_at: 0 });
+  }
+  let mut rng = test_rng();
+  for _ in 0 .. 10 {
+    let picked = peers.get_random_active(2, &mut rng);
+    assert_eq!(picked.len(), 2);
+    assert_eq!(picked[0].address.family(), AddressFamily::IPv6);
+  }
+  assert_eq!(peers.get_random_active(10, &mut rng).len(), 4);
+}
+
+#[test]
+fn same_seed_same_choices() {
+  let mut peers = PeersStore::new(None);
+  for port in 0 .. 20 {
+    peers.see_peer(Peer { address: read_address(&format!("10.0.0.{}:42000", port)), seen_at: 0 });
+  }
+  let run = |seed: u64| {
+    let mut rng = NodeRng::seed_from_u64(seed);
+    let picked: Vec<Address> = peers.get_random_active(5, &mut rng).iter().map(|peer| peer.address).collect();
+    let block = try_mine(ZERO_HASH(), Body { data: vec![0] }, INITIAL_TARGET(), 1, &[], 1, &mut rng).map(|block| block.hash);
+    (picked, block)
+  };
+  let seed = rand::random();
+  assert_eq!(run(seed), run(seed));
+}
+
+#[test]
+fn chaos_drops_and_delays_messages() {
+  let dir = temp_dir();
+  let net = NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+  let chaos = Chaos { max_delay: 500, drop_rate: 50, disk_fail: 0 };
+  let (_, mut node) = Node::new(dir.path.clone(), &None, Some(chaos), net);
+  let addrs: Vec<Address> = (0 .. 200).map(|i| read_address(&format!("10.0.0.{}:42000", i))).collect();
+  let now = node.clock.now();
+  node.send(addrs, &Message::PleaseMineThisTransaction { trans: Transaction::new(vec![1]) });
+  // about half are dropped, and the others held back for up to the maximum delay
+  assert!((50 .. 150).contains(&node.delayed.len()));
+  assert!(node.delayed.iter().all(|(time, _, _)| (now ..= now + 500).contains(time)));
+}
+
+#[test]
+fn chaos_is_reproducible_from_the_seed() {
+  let chaos = Chaos { max_delay: 500, drop_rate: 50, disk_fail: 0 };
+  let run = |seed: u64| {
+    let dir = temp_dir();
+    let net = NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+    let (_, mut node) = Node::new(dir.path.clone(), &None, Some(chaos), net);
+    node.set_seed(seed);
+    node.clock = std::sync::Arc::new(ManualClock::new(1_000_000));
+    let addrs: Vec<Address> = (0 .. 200).map(|i| read_address(&format!("10.0.0.{}:42000", i))).collect();
+    node.send(addrs, &Message::PleaseMineThisTransaction { trans: Transaction::new(vec![1]) });
+    node.delayed.iter().map(|(time, addr, _)| (*time, *addr)).collect::<Vec<_>>()
+  };
+  let seed = rand::random();
+  assert_eq!(run(seed), run(seed));
+}
+
+#[test]
+fn mined_blocks_carry_extra_data() {
+  assert_eq!(extract_extra_data(block_meta(b"kindel1", 42)), Some(b"kindel1".to_vec()));
+  assert_eq!(extract_extra_data(block_meta(b"v1\0", 42)), Some(b"v1".to_vec()));
+  assert_eq!(extract_extra_data(block_meta(&[], 42)), Some(vec![]));
+  assert_eq!(block_meta(b"kindel1", 42) as u64, 42);
+  assert_eq!(MAX_EXTRA_DATA, b"kindel1".len());
+
+  // the nonce changes while mining, but the extra data stays
+  let mut rng = test_rng();
+  for _ in 0 .. 8 {
+    let tagged = try_mine(ZERO_HASH(), Body { data: vec![0] }, INITIAL_TARGET(), 1, b"miner", 256 * 16, &mut rng).unwrap();
+    assert_eq!(extract_extra_data(tagged.meta), Some(b"miner".to_vec()));
+    let untagged = try_mine(ZERO_HASH(), Body { data: vec![0] }, INITIAL_TARGET(), 1, &[], 256 * 16, &mut rng).unwrap();
+    assert_eq!(extract_extra_data(untagged.meta), None);
+  }
+}
+
+#[test]
+fn connect_only_allowlist() {
+  let allowed = read_address("10.0.0.1:42000");
+  let other = read_address("10.0.0.2:42000");
+  let mut peers = PeersStore::new(None);
+  peers.see_peer(Peer { address: other, seen_at: 0 });
+  peers.allow_only(&[allowed]);
+  // peers outside the allowlist are forgotten, and never seen again
+  assert!(!peers.is_active(&other));
+  peers.see_peer(Peer { address: other, seen_at: 0 });
+  peers.see_peer(Peer { address: allowed, seen_at: 0 });
+  assert!(peers.is_allowed(&allowed) && !peers.is_allowed(&other));
+  assert_eq!(peers.get_all_seen().iter().map(|peer| peer.address).collect::<Vec<_>>(), vec![allowed]);
+  assert!(PeersStore::new(None).is_allowed(&other));
+}
+
+#[test]
+fn miner_throttled_and_paused() {
+  let mut mc = MinerCommunication::new();
+  let miner = mc.clone();
+  let rng = test_rng();
+  std::thread::spawn(move || miner_loop(miner, rng));
+  // any hash meets a zero target, so the first batch mines a block
+  let body = Body { data: vec![0] };
+  mc.write(MinerMessage::Request { prev: u256(0), body, targ: u256(0), time: 1, extra: vec![], intensity: 1 });
+  let start = std::time::Instant::now();
+  while !matches!(mc.read(), MinerMessage::Answer { .. }) {
+    assert!(start.elapsed() < std::time::Duration::from_secs(5));
+    std::thread::sleep(std::time::Duration::from_millis(1));
+  }
+  // a paused miner leaves the shared state alone
+  mc.write(MinerMessage::Stop);
+  std::thread::sleep(std::time::Duration::from_millis(50));
+  assert!(matches!(mc.read(), MinerMessage::Stop));
+}
+
+#[test]
+fn block_index_follows_the_chain() {
+  let dir = temp_dir();
+  let net = || NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+  let (_, mut node) = Node::new(dir.path.clone(), &None, None, net());
+  node.load_blocks();
+  let clock = ManualClock::new(1);
+  let mut rng = test_rng();
+  let mut mine = |prev: U256| {
+    clock.advance(1);
+    loop {
+      if let Some(block) = try_mine(prev, Body { data: vec![0] }, INITIAL_TARGET(), clock.now(), &[], 1, &mut rng) {
+        return block;
+      }
+    }
+  };
+  let mut branch = |node: &mut Node, mut prev: U256, count: usize| {
+    let mut hashes = vec![];
+    for _ in 0 .. count {
+      let block = mine(prev);
+      node.add_block(&block);
+      prev = block.hash;
+      hashes.push(prev);
+    }
+    hashes
+  };
+
+  let first = branch(&mut node, ZERO_HASH(), 3);
+  assert_eq!(node.chain[1 ..], first[..]);
+  assert_eq!((node.get_hash_at(0), node.get_hash_at(2), node.get_hash_at(4)), (None, Some(first[1]), None));
+  assert_eq!(node.get_longest_chain(Some(2)), first[1 ..].to_vec());
+  assert_eq!(read_block_index(&node.get_block_index_path()).unwrap(), first);
+
+  // a reorg rewrites the index from the fork on
+  let mut second = branch(&mut node, first[0], 1);
+  while node.tip != second[second.len() - 1] {
+    second.extend(branch(&mut node, second[second.len() - 1], 1));
+  }
+  let chain = [&first[.. 1], &second[..]].concat();
+  assert_eq!(node.chain[1 ..], chain[..]);
+  assert_eq!(read_block_index(&node.get_block_index_path()).unwrap(), chain);
+
+  // the index is rebuilt when the blocks are loaded, even if it was damaged
+  std::fs::write(node.get_block_index_path(), [7; 40]).unwrap();
+  let (_, mut node) = Node::new(dir.path.clone(), &None, None, net());
+  node.load_blocks();
+  assert_eq!(node.chain[1 ..], chain[..]);
+  assert_eq!(read_block_index(&node.get_block_index_path()).unwrap(), chain);
+}
+
+#[test]
+fn mining_stats_follow_block_times() {
+  let dir = temp_dir();
+  let net = NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+  let (_, mut node) = Node::new(dir.path.clone(), &None, None, net);
+  let clock = ManualClock::new(1_000_000);
+  node.clock = std::sync::Arc::new(clock.clone());
+  let mut rng = test_rng();
+  let mut extend = |node: &mut Node, count: u128, interval: u128| {
+    for _ in 0 .. count {
+      clock.advance(interval);
+      let target = node.target[&node.tip];
+      let block = loop {
+        if let Some(block) = try_mine(node.tip, Body { data: vec![0] }, target, clock.now(), &[], 1, &mut rng) {
+          break block;
+        }
+      };
+      node.add_block(&block);
+    }
+  };
+
+  // without blocks, the network is assumed to mine at the expected pace
+  let stats = node.get_mining_stats();
+  assert_eq!((stats.height, stats.window, stats.block_time), (0, 0, TIME_PER_BLOCK));
+  assert_eq!(stats.hash_rate, INITIAL_DIFFICULTY * 1000 / TIME_PER_BLOCK);
+  assert_eq!((stats.retarget.height, stats.retarget.blocks_left), (BLOCKS_PER_PERIOD as u64 + 1, BLOCKS_PER_PERIOD as u64 + 1));
+  assert_eq!(stats.retarget.difficulty, INITIAL_DIFFICULTY);
+
+  // a period twice as fast doubles the difficulty, and is predicted to double it again
+  extend(&mut node, BLOCKS_PER_PERIOD + 1, TIME_PER_BLOCK / 2);
+  let stats = node.get_mining_stats();
+  assert_eq!((stats.height, stats.window, stats.block_time), (21, 20, TIME_PER_BLOCK / 2));
+  assert_eq!(stats.difficulty, INITIAL_DIFFICULTY * 2);
+  assert_eq!(stats.hash_rate, INITIAL_DIFFICULTY * 20 * 1000 / (TIME_PER_BLOCK * 10));
+  assert_eq!((stats.retarget.height, stats.retarget.blocks_left, stats.retarget.period_time), (41, 20, TIME_PER_PERIOD / 2));
+  assert_eq!(stats.retarget.difficulty, INITIAL_DIFFICULTY * 4);
+
+  // slower blocks slow the estimates down
+  extend(&mut node, 10, TIME_PER_BLOCK * 2);
+  let stats = node.get_mining_stats();
+  assert_eq!((stats.height, stats.window, stats.block_time), (31, 30, TIME_PER_BLOCK));
+  assert_eq!(stats.hash_rate, INITIAL_DIFFICULTY * 40 * 1000 / (TIME_PER_BLOCK * 30));
+  let period_time = TIME_PER_BLOCK * 20 + TIME_PER_BLOCK * 10;
+  assert_eq!((stats.retarget.blocks_left, stats.retarget.period_time), (10, period_time));
+  assert_eq!(u256(stats.retarget.difficulty), target_to_difficulty(compute_period_target(node.target[&node.tip], period_time)));
+}
+
+#[test]
+fn miner_template_refreshes_on_new_tips_and_fuller_pools() {
+  let dir = temp_dir();
+  let net = NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+  let (_, mut node) = Node::new(dir.path.clone(), &None, None, net);
+  let clock = ManualClock::new(1_000_000);
+  node.clock = std::sync::Arc::new(clock.clone());
+  node.mining.active = true;
+  let mut mc = MinerCommunication::new();
+  let request = |mc: &MinerCommunication| match mc.read() {
+    MinerMessage::Request { prev, body, .. } => (prev, extract_transactions(&body).len()),
+    _ => panic!("expected a request"),
+  };
+  // nothing is refreshed while the miner isn't asked for anything
+  node.refresh_template(&mut mc);
+  assert!(matches!(mc.read(), MinerMessage::Stop));
+  node.ask_mine_or_stop(&mut mc);
+  assert_eq!(request(&mc), (ZERO_HASH(), 0));
+
+  // new statements are picked up once they make a fuller block, and not too often
+  let pool = |node: &mut Node, from: u128, count: u128| {
+    for i in from .. from + count {
+      let (_, statements) = read_statements(&format!("run {{ (Done #{}) }}", i)).unwrap();
+      let trans = Transaction::new(bitvec_to_bytes(&serialized_statement(&statements[0])));
+      node.pool.push(trans.clone(), trans.hash.low_u64());
+    }
+  };
+  pool(&mut node, 0, 1);
+  clock.advance(TEMPLATE_REFRESH_DELAY);
+  node.refresh_template(&mut mc);
+  assert_eq!(request(&mc), (ZERO_HASH(), 0));
+  pool(&mut node, 1, 20);
+  node.refresh_template(&mut mc);
+  assert_eq!(request(&mc), (ZERO_HASH(), 21));
+  pool(&mut node, 21, 20);
+  node.refresh_template(&mut mc);
+  assert_eq!(request(&mc), (ZERO_HASH(), 21));
+
+  // a new tip is mined on right away
+  let mut rng = test_rng();
+  let block = loop {
+    if let Some(block) = try_mine(ZERO_HASH(), Body { data: vec![0] }, INITIAL_TARGET(), 1, &[], 1, &mut rng) {
+      break block;
+    }
+  };
+  node.add_block(&block);
+  node.refresh_template(&mut mc);
+  assert_eq!(request(&mc).0, block.hash);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn tune_miner_thread() {
+  let handle = std::thread::spawn(|| {
+    let before = unsafe { libc::getpriority(libc::PRIO_PROCESS, libc::syscall(libc::SYS_gettid) as libc::id_t) };
+    tune_thread(&ThreadTuning { cores: vec![0], nice: Some(before + 1) }).unwrap();
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    unsafe { libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) };
+    let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, libc::syscall(libc::SYS_gettid) as libc::id_t) };
+    (unsafe { libc::CPU_ISSET(0, &set) && !libc::CPU_ISSET(1, &set) }, nice - before)
+  });
+  assert_eq!(handle.join().unwrap(), (true, 1));
+  assert!(tune_thread(&ThreadTuning { cores: vec![1 << 20], nice: None }).is_err());
+}
+
+#[test]
+fn fork_stats() {
+  let mut forks = ForkStats::default();
+  let mut heights = u256map_new();
+  let (a, b, invalid) = (u256(1), u256(2), u256(3));
+  heights.insert(a, 5);
+  heights.insert(invalid, 0); // blocks that miss the target or go back in time are at height 0
+  forks.see_block(5, &[a, invalid], &heights);
+  assert!(forks.competing.is_empty());
+  heights.insert(b, 5);
+  forks.see_block(5, &[a, invalid, b], &heights);
+  assert_eq!(forks.competing.get(&5), Some(&vec![a, b]));
+
+  // extending the tip isn't a reorg
+  forks.see_tip_change(0);
+  forks.see_tip_change(2);
+  forks.see_tip_change(1);
+  assert_eq!((forks.reorgs, forks.deepest_reorg), (2, 2));
+}
+
+// Builds a tree of blocks from `(hash, parent, own work)`, in the order they're seen, where the
+// genesis block is `0`. Blocks with no work are invalid, so they get none, as on `add_block`.
+fn block_tree(blocks: &[(u128, u128, u128)]) -> (U256Map<Vec<U256>>, U256Map<U256>) {
+  let mut children = u256map_from([(ZERO_HASH(), vec![])]);
+  let mut work = u256map_from([(ZERO_HASH(), u256(0))]);
+  for &(hash, parent, own) in blocks {
+    let (hash, parent) = (u256(hash), if parent == 0 { ZERO_HASH() } else { u256(parent) });
+    let total = if own == 0 { u256(0) } else { work[&parent] + u256(own) };
+    work.insert(hash, total);
+    children.insert(hash, vec![]);
+    children.get_mut(&parent).unwrap().push(hash);
+  }
+  (children, work)
+}
+
+// Feeds the blocks of a tree to a rule one at a time, as a node sees them
+fn follow(rule: &dyn ForkChoice, blocks: &[(u128, u128, u128)]) -> U256 {
+  let mut tip = ZERO_HASH();
+  for seen in 1 ..= blocks.len() {
+    let (children, work) = block_tree(&blocks[.. seen]);
+    let tree = BlockTree { children: &children, work: &work };
+    let added = u256(blocks[seen - 1].0);
+    if work[&added] > u256(0) {
+      tip = rule.choose_tip(&tree, tip, added);
+    }
+  }
+  tip
+}
+
+#[test]
+fn fork_choice_rules_over_block_trees() {
+  // 0 -> 1 -> 2 -> 3, a chain with 9 of work, and 0 -> 4 -> {5, 6, 7}, a bushier subtree with 10,
+  // whose chains have 6. Block 8 is invalid, so its child 9 is out of the tree, whatever its work.
+  let blocks = [(1, 0, 3), (4, 0, 4), (2, 1, 3), (5, 4, 2), (3, 2, 3), (6, 4, 2), (8, 0, 0), (7, 4, 2), (9, 8, 50)];
+  let (children, work) = block_tree(&blocks);
+  let tree = BlockTree { children: &children, work: &work };
+  assert_eq!(tree.valid_children(ZERO_HASH()).collect::<Vec<_>>(), vec![u256(1), u256(4)]);
+  let weight = tree.subtree_work();
+  assert_eq!((weight[&ZERO_HASH()], weight[&u256(1)], weight[&u256(4)]), (u256(19), u256(9), u256(10)));
+  assert!(!weight.contains_key(&u256(9)));
+
+  assert_eq!(follow(&MostWork, &blocks[.. 8]), u256(3));
+  // A block with as much work as the tip doesn't take its place
+  assert_eq!(follow(&MostWork, &[(1, 0, 5), (2, 0, 5)]), u256(1));
+  // GHOST follows 4, for its stale children, and then the first of them seen
+  assert_eq!(follow(&HeaviestSubtree, &blocks), u256(5));
+  assert_eq!(follow(&HeaviestSubtree, &blocks[.. 6]), u256(3));
+
+  assert_eq!("heaviest-subtree".parse(), Ok(ForkChoiceRule::HeaviestSubtree));
+  assert_eq!(ForkChoiceRule::MostWork.build().name(), "most-work");
+  assert!("ghost".parse::<ForkChoiceRule>().is_err());
+}
+
+#[test]
+fn pool_expiry() {
+  let mut expiry = PoolExpiry::new(10);
+  let (old, soon, mined) = (u256(1), u256(2), u256(3));
+  expiry.add(old, 0, None);
+  expiry.add(soon, 5, Some(7)); // the sender's expiry, being sooner than the TTL, wins
+  expiry.add(mined, 5, Some(100));
+  expiry.add(old, 9, None); // seeing it again doesn't extend its life
+  assert_eq!(expiry.status(&mined), Some(PoolStatus::Pending { expires: 15 }));
+  expiry.remove(&mined);
+
+  assert_eq!(expiry.expire(7), vec![]);
+  assert_eq!(expiry.expire(8), vec![soon]);
+  assert_eq!(expiry.expire(11), vec![old]);
+  assert_eq!(expiry.status(&soon), Some(PoolStatus::Expired { at: 8 }));
+  assert_eq!(expiry.status(&old), Some(PoolStatus::Expired { at: 11 }));
+  assert_eq!(expiry.status(&mined), None);
+
+  // only the latest evictions are remembered
+  for i in 0 .. EVICTED_LIMIT as u64 {
+    expiry.add(u256(100 + i as u128), 11, Some(11));
+  }
+  expiry.expire(12);
+  assert_eq!(expiry.status(&soon), None);
+}
+
+#[test]
+fn local_transactions_rebroadcast() {
+  let mut local = LocalPool::default();
+  let a = Transaction::new(vec![1, 2, 3, 4, 5]);
+  let b = Transaction::new(vec![6, 7, 8, 9, 10]);
+  assert!(local.add(a.clone()));
+  assert!(!local.add(a.clone()));
+  local.add(b.clone());
+
+  // new transactions go out at once, then wait for the delay
+  assert_eq!(local.due(1_000).len(), 2);
+  assert!(local.due(1_000 + REBROADCAST_DELAY - 1).is_empty());
+  assert!(local.remove(&b.hash));
+  assert_eq!(local.due(1_000 + REBROADCAST_DELAY), vec![a.clone()]);
+  let sent = local.get(&a.hash).unwrap();
+  assert_eq!((sent.broadcasts, sent.last_broadcast), (2, 1_000 + REBROADCAST_DELAY));
+
+  // they survive a restart
+  assert_eq!(LocalPool::from_text(&local.to_text()), vec![a]);
+  assert!(LocalPool::from_text("not hex\n\n").is_empty());
+}
+
+#[test]
+fn reorg_returns_statements_to_pool() {
+  let dir = temp_dir();
+  let net = NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+  let (_, mut node) = Node::new(dir.path.clone(), &None, None, net);
+  let mut events = node.events.subscribe();
+  let clock = ManualClock::new(1);
+  let mut rng = test_rng();
+  let mut mine = |prev: U256, code: &str| {
+    clock.advance(1); // blocks must advance time
+    loop {
+      if let Some(block) = try_mine(prev, code_to_body(code), INITIAL_TARGET(), clock.now(), &[], 1, &mut rng) {
+        return block;
+      }
+    }
+  };
+
+  let dropped = mine(ZERO_HASH(), "ctr {Dropped}");
+  node.add_block(&dropped);
+  assert_eq!(node.tip, dropped.hash);
+
+  // a competing branch takes over once it has more work
+  let mut prev = ZERO_HASH();
+  while node.tip == dropped.hash {
+    let block = mine(prev, "");
+    node.add_block(&block);
+    prev = block.hash;
+  }
+
+  // every new tip is announced, and the reorg right before the tip it led to
+  let events: Vec<_> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+  let NodeEvent::Tip { hash, .. } = &*events[events.len() - 1] else {
+    panic!("expected a tip event");
+  };
+  assert_eq!(Into::<U256>::into(hash.clone()), node.tip);
+  let NodeEvent::Reorg { disconnected, connected, returned, fork_height, .. } = &*events[events.len() - 2] else {
+    panic!("expected a reorg event");
+  };
+  assert_eq!(*fork_height, 0);
+  assert_eq!(disconnected.len(), 1);
+  assert_eq!(connected.len() as u128, node.height[&node.tip]);
+  assert_eq!(returned.iter().map(view_statement).collect::<Vec<_>>(), vec!["ctr {Dropped}".to_string()]);
+  assert_eq!(node.pool.len(), 1);
+}
+
+#[test]
+fn computed_blocks_announce_their_terms() {
+  let dir = temp_dir();
+  let net = NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+  let (_, mut node) = Node::new(dir.path.clone(), &None, None, net);
+  let mut events = node.events.subscribe();
+  let mut rng = test_rng();
+  let mut prev = ZERO_HASH();
+  for (time, code) in [PRE_HOOKS, "ctr {Rang x} run { ask (Call 'Bell' [{Bell_Ring #5}]); (Done {Rang #9}) }"].into_iter().enumerate() {
+    let block = loop {
+      if let Some(block) = try_mine(prev, code_to_body(code), INITIAL_TARGET(), time as u128 + 1, &[], 1, &mut rng) {
+        break block;
+      }
+    };
+    node.add_block(&block);
+    prev = block.hash;
+  }
+  let terms: Vec<_> = std::iter::from_fn(|| events.try_recv().ok())
+    .filter_map(|event| match &*event {
+      NodeEvent::Terms { height, results, emitted, .. } => {
+        let results = results.iter().map(|(position, term)| format!("{} {}", position, view_term(term))).collect::<Vec<_>>();
+        let emitted = emitted.iter().map(|(name, term)| format!("{} {}", name, view_term(term))).collect::<Vec<_>>();
+        Some((*height, results, emitted))
+      }
+      _ => None,
+    })
+    .collect();
+  assert_eq!(terms, vec![
+    (1, vec!["5 #0".to_string()], vec![]),
+    (2, vec!["1 {Rang #9}".to_string()], vec!["Bell #5".to_string()]),
+  ]);
+
+  // the blooms of the blocks tell which may concern a name
+  let heights = |name: &str| node.get_blooms((1, 2), Some(name_to_u128(name)), None).iter().map(|entry| entry.height).collect::<Vec<_>>();
+  assert_eq!(heights("Rang"), vec![2]);
+  assert_eq!(heights("Bell"), vec![1, 2]);
+  assert_eq!(heights("Casino"), Vec::<u64>::new());
+}
+
+#[test]
+fn policies_keep_statements_off_the_pool() {
+  let dir = temp_dir();
+  let net = NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+  let (_, mut node) = Node::new(dir.path.clone(), &None, None, net);
+  let config = PolicyConfig { deny_names: vec!["Casino".to_string()], ..PolicyConfig::default() };
+  node.policies.push(Box::new(LocalPolicy::new(&config).unwrap()));
+  let peer = read_address("10.0.0.1:42000");
+  let (_, statements) = read_statements("ctr {Casino} ctr {Chip}").unwrap();
+  for statement in &statements {
+    let trans = Transaction::new(bitvec_to_bytes(&serialized_statement(statement)));
+    node.handle_message(peer, &Message::PleaseMineThisTransaction { trans });
+  }
+  let pooled: Vec<_> = node.get_pool(None).iter().map(|entry| view_statement(&entry.statement)).collect();
+  assert_eq!(pooled, vec!["ctr {Chip}".to_string()]);
+}
+
+#[test]
+fn pool_is_listed_by_signer() {
+  let dir = temp_dir();
+  let net = NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+  let (_, mut node) = Node::new(dir.path.clone(), &None, None, net);
+  let alice = Account::from_private_key(&[1; 32]);
+  let (_, statements) = read_statements("run { (Done #1) } run { (Done #2) }").unwrap();
+  let signed = set_sign(&statements[0], alice.sign(&sign_hash(&statements[0])));
+  for statement in [&signed, &statements[1]] {
+    let tx = Transaction::new(bitvec_to_bytes(&serialized_statement(statement)));
+    node.expiry.add(tx.hash, 0, Some(5));
+    node.pool.push(tx.clone(), tx.hash.low_u64());
+  }
+
+  assert_eq!(node.get_pool(None).len(), 2);
+  let pool = node.get_pool(Some(alice.name.0));
+  assert_eq!(pool.len(), 1);
+  assert_eq!(pool[0].signer, Some(alice.name.show()));
+  assert_eq!(pool[0].status, PoolStatus::Pending { expires: 5 });
+  assert!(!pool[0].local);
+  assert!(node.get_pool(Some(alice.name.0 + 1)).is_empty());
+}
+
+#[test]
+fn candidate_blocks_leave_out_failing_transactions() {
+  let dir = temp_dir();
+  let net = NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+  let (_, mut node) = Node::new(dir.path.clone(), &None, None, net);
+  let (_, statements) = read_statements("ctr {Fine} fun (Done x) { (Done x) = x }").unwrap();
+  let mut transactions: Vec<_> = statements.iter().map(|statement| Transaction::new(bitvec_to_bytes(&serialized_statement(statement)))).collect();
+  transactions.push(Transaction::new(vec![0xff; 8]));
+  for tx in &transactions {
+    node.pool.push(tx.clone(), tx.hash.low_u64());
+  }
+  let state_hash = node.runtime.get_state_hash();
+
+  let body = node.build_checked_body();
+  let kept: Vec<_> = extract_transactions(&body).iter().filter_map(Transaction::to_statement).map(|statement| view_statement(&statement)).collect();
+  assert_eq!(kept, vec!["ctr {Fine}".to_string()]);
+  assert_eq!((node.precheck.checks, node.precheck.excluded), (1, 2));
+  assert!(node.precheck.failures[0].err.contains("redefine") || node.precheck.failures[1].err.contains("redefine"));
+  // trying it left the state as it was
+  assert_eq!(node.runtime.get_state_hash(), state_hash);
+  // and the same candidate isn't run again
+  assert_eq!(node.build_checked_body().data, body.data);
+  assert_eq!(node.precheck.checks, 1);
+}
+
+#[test]
+fn usage_stats_keep_the_slowest_statements() {
+  let (_, statements) = read_statements("run { (Done #0) } fun (Bar) { (Bar) = #0 } ctr {Foo}").unwrap();
+  let usage = |millis: u64| StatementUsage { rwts: 2, mana: 10, size: 1, time: std::time::Duration::from_millis(millis) };
+  let mut stats = UsageStats::default();
+  for height in 1 ..= 10 {
+    stats.see_block(height, &statements, &[usage(height), usage(height * 3), usage(0)]);
+  }
+  assert_eq!((stats.blocks, stats.statements, stats.rwts, stats.mana), (10, 30, 60, 300));
+  assert_eq!(stats.time, std::time::Duration::from_millis(55 * 4));
+  assert_eq!(stats.slowest.len(), SLOWEST_STATEMENTS);
+  let slowest: Vec<_> = stats.slowest.iter().take(3).map(|slow| (slow.height, slow.position)).collect();
+  assert_eq!(slowest, vec![(10, 1), (9, 1), (8, 1)]);
+  assert!(stats.slowest.windows(2).all(|pair| pair[0].usage.time >= pair[1].usage.time));
+  assert_eq!(stats.slowest[0].funs, vec![crate::hvm::name_to_u128("Bar")]);
+}
+
+#[test]
+fn replay_matches_live_state() {
+  let dir = temp_dir();
+  let net = NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+  let (_, mut node) = Node::new(dir.path.clone(), &None, None, net);
+  let mut rng = test_rng();
+  let codes = ["ctr {Pair a b}", "fun (Swap p) { (Swap {Pair a b}) = {Pair b a} }", "run { (Done (Swap {Pair #1 #2})) }"];
+  let mut prev = ZERO_HASH();
+  for (time, code) in codes.into_iter().enumerate() {
+    let block = loop {
+      if let Some(block) = try_mine(prev, code_to_body(code), INITIAL_TARGET(), time as u128 + 1, &[], 1, &mut rng) {
+        break block;
+      }
+    };
+    node.add_block(&block);
+    prev = block.hash;
+  }
+  let chain: Vec<_> = node.get_longest_chain(None).iter().map(|hash| node.block[hash].clone()).collect();
+  assert_eq!(chain.len(), codes.len());
+  let live = node.runtime.get_state_hash();
+
+  let check = replay_blocks(node.runtime.fork(0), &chain, live);
+  assert_eq!((check.from, check.to), (0, codes.len() as u128));
+  assert!(!check.diverged());
+
+  // a replay that misses a block ends somewhere else
+  let check = replay_blocks(node.runtime.fork(0), &chain[1 ..], live);
+  assert!(check.diverged());
+
+  let mut verifier = ReplayVerifier::default();
+  assert!(verifier.record(check));
+  assert_eq!((verifier.checks, verifier.divergences), (1, 1));
+}
+
+#[test]
+fn past_states_are_rebuilt_off_the_node_thread() {
+  let dir = temp_dir();
+  let net = NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+  let (_, mut node) = Node::new(dir.path.clone(), &None, None, net);
+  let mut rng = test_rng();
+  let mut prev = ZERO_HASH();
+  for time in 1 ..= 60 {
+    let code = if time == 1 { "fun (Keep) { (Keep) = #0 } with { #7 }" } else { "" };
+    let block = loop {
+      if let Some(block) = try_mine(prev, code_to_body(code), node.get_tip_target(), time * TIME_PER_BLOCK, &[], 1, &mut rng) {
+        break block;
+      }
+    };
+    node.add_block(&block);
+    prev = block.hash;
+  }
+  let state_at = |node: &mut Node, height: u64| {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    node.handle_request(NodeRequest::GetState { name: name_to_u128("Keep"), at: Some(height), tx });
+    rx.blocking_recv().unwrap().map(|state| state.map(|state| view_term(&state)))
+  };
+  // each query is answered once its rebuild is done, so the next one finds a free slot, however
+  // many run one after the other
+  for _ in 0 .. MAX_HISTORY_QUERIES + 1 {
+    assert_eq!(state_at(&mut node, 60), Ok(Some("#7".to_string())));
+  }
+  assert_eq!(state_at(&mut node, 30), Ok(Some("#7".to_string())));
+  assert_eq!(state_at(&mut node, 0), Ok(None));
+  assert!(state_at(&mut node, 61).is_err());
+}
+
+#[test]
+fn damaged_saved_states_are_moved_aside() {
+  let dir = temp_dir();
+  let heaps = dir.path.join("state").join("heaps");
+  let mut rt = init_runtime(Some(&heaps));
+  advance(&mut rt, 20, None);
+  assert!(!discard_corrupted_state(&heaps));
+  // the size counter drifts, and is saved that way
+  rt.set_size(rt.get_size() + 1);
+  advance(&mut rt, 40, None);
+  drop(rt);
+  assert!(!check_saved_state(&heaps).unwrap().is_empty());
+  let net = NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+  let (_, node) = Node::new(dir.path.clone(), &None, None, net);
+  assert!(heaps.with_extension("corrupted").is_dir());
+  assert_eq!(node.runtime.get_status().tick, 0);
+}
+
+#[test]
+fn pool_takes_higher_bids_first() {
+  let dir = temp_dir();
+  let net = NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+  let (_, mut node) = Node::new(dir.path.clone(), &None, None, net);
+  let base = node.runtime.get_status().mana_price;
+  let codes = [
+    format!("run {{ (Done #1) }} price {{ #{} }}", base),
+    format!("run {{ (Done #2) }} price {{ #{} }}", base * 2),
+    format!("run {{ (Done #3) }} price {{ #{} }}", base - 1),
+    "ctr {Pair a b}".to_string(),
+  ];
+  for code in &codes {
+    let statement = read_statements(code).unwrap().1.remove(0);
+    let transaction = Transaction::new(bitvec_to_bytes(&serialized_statement(&statement)));
+    node.pool.push(transaction.clone(), transaction.priority());
+  }
+  let mined: Vec<String> = extract_transactions(&node.build_body()).iter().map(|tx| view_statement(&tx.to_statement().unwrap())).collect();
+  let view = |code: &str| view_statement(&read_statements(code).unwrap().1[0]);
+  // the highest bid goes first, and the one under the base price is left out
+  assert_eq!(mined.len(), 3);
+  assert_eq!(mined[0], view(&codes[1]));
+  assert!(!mined.contains(&view(&codes[2])));
+}
+
+#[test]
+fn statements_are_reexecuted_from_the_index() {
+  let dir = temp_dir();
+  let net = NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+  let (_, mut node) = Node::new(dir.path.clone(), &None, None, net);
+  let mut rng = test_rng();
+  let codes = [
+    "fun (Keep) { (Keep) = #0 } with { #7 }",
+    "run { (Done #1) }",
+    "run { (Done #2) }",
+  ];
+  let mut prev = ZERO_HASH();
+  for (time, code) in codes.into_iter().enumerate() {
+    let block = loop {
+      if let Some(block) = try_mine(prev, code_to_body(code), INITIAL_TARGET(), time as u128 + 1, &[], 1, &mut rng) {
+        break block;
+      }
+    };
+    node.add_block(&block);
+    prev = block.hash;
+  }
+  let reexecute = |node: &mut Node, code: &str| {
+    let statement = read_statements(code).unwrap().1.remove(0);
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    node.handle_request(NodeRequest::Reexecute { hash: hash_statement(&statement), tx });
+    rx.blocking_recv().unwrap()
+  };
+  let reexecution = reexecute(&mut node, codes[2]).unwrap().unwrap();
+  assert_eq!(reexecution.height, 3);
+  assert_eq!(view_statement(&reexecution.statement), view_statement(&read_statements(codes[2]).unwrap().1[0]));
+  assert!(reexecution.result.is_ok());
+  assert_eq!(node.index.locate(&hash_statement(&reexecution.statement)), Some((3, 0)));
+  assert!(matches!(reexecute(&mut node, "run { (Done #42) }"), Ok(None)));
+}
+
+#[rstest]
+#[case(Candidate::Same)]
+#[case(Candidate::Linear)]
+fn shadow_matches_live_state(#[case] candidate: Candidate) {
+  let dir = temp_dir();
+  let net = NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+  let (_, mut node) = Node::new(dir.path.clone(), &None, None, net);
+  let mut rng = test_rng();
+  let codes = [
+    "ctr {Pair a b}",
+    "fun (Swap p) { (Swap {Pair a b}) = {Pair b a} (Swap x) = x }",
+    "run { (Done (Swap {Pair #1 #2})) }",
+    "fun (Keep) { (Keep) = #0 } with { (Swap {Pair #3 #4}) }",
+  ];
+  let mut prev = ZERO_HASH();
+  for (time, code) in codes.into_iter().enumerate() {
+    let block = loop {
+      if let Some(block) = try_mine(prev, code_to_body(code), INITIAL_TARGET(), time as u128 + 1, &[], 1, &mut rng) {
+        break block;
+      }
+    };
+    node.add_block(&block);
+    prev = block.hash;
+  }
+  let chain: Vec<_> = node.get_longest_chain(None).iter().map(|hash| (node.block[hash].clone(), node.state_hash[hash])).collect();
+  assert_eq!(chain.len(), codes.len());
+
+  let mut shadow = node.runtime.fork(0);
+  candidate.apply(&mut shadow);
+  let checks = shadow_blocks(&mut shadow, &chain);
+  assert_eq!(checks.iter().map(|check| check.height).collect::<Vec<_>>(), vec![1, 2, 3, 4]);
+  assert!(checks.iter().all(|check| !check.diverged()));
+
+  // a shadow that misses a block diverges, and stops there
+  let mut shadow = node.runtime.fork(0);
+  candidate.apply(&mut shadow);
+  let checks = shadow_blocks(&mut shadow, &chain[1 ..]);
+  assert_eq!(checks.len(), 1);
+  assert!(checks[0].diverged());
+
+  let mut execution = ShadowExecution::default();
+  assert!(execution.record(checks[0].clone()));
+  assert_eq!((execution.blocks, execution.divergences, execution.height), (1, 1, Some(1)));
+}
+
+#[test]
+fn pool_statements_are_grouped_and_preexecuted() {
+  let (_, statements) = read_statements("
+    fun (Bump x) { (Bump x) = (+ x #1) }
+    run { (Done (Sink #1)) }
+    fun (Sink x) { (Sink x) = (- x #1) }
+    run { (Done (Bump #1)) }
+    ctr {Loose}
+  ").unwrap();
+  let deps: Vec<StatementDeps> = statements.iter().map(StatementDeps::new).collect();
+  let groups = group_statements(&deps);
+  assert_eq!(groups, vec![vec![0, 3], vec![1, 2], vec![4]]);
+
+  // each group runs on its own copy of the state, in order, so the call before `Sink` fails
+  let dir = temp_dir();
+  let runtime = init_runtime(Some(&dir.path));
+  let block = new_block(ZERO_HASH(), 1, 0, Body { data: vec![0] });
+  let estimates: Vec<_> = preexecute(runtime, &statements, &groups, &block).into_iter().map(|estimate| estimate.unwrap()).collect();
+  assert_eq!(estimates.iter().map(|estimate| estimate.ok).collect::<Vec<_>>(), vec![true, false, true, true, true]);
+  assert!(estimates[3].mana > 0);
+
+  // namespaces are touched by the names under them
+  let (_, named) = read_statements("fun (Foo.Bar x) { (Foo.Bar x) = x }").unwrap();
+  assert!(StatementDeps::new(&named[0]).names.contains(&name_to_u128("Foo")));
+
+  // calls by computed names may touch anything, so the pool runs as one group
+  let (_, dynamic) = read_statements("run { ask x = (Call (Bump #0) []); (Done x) }").unwrap();
+  let mut deps = deps;
+  deps.push(StatementDeps::new(&dynamic[0]));
+  assert!(deps[5].dynamic);
+  assert_eq!(group_statements(&deps), vec![(0 .. 6).collect::<Vec<_>>()]);
+}
+
+#[test]
+fn nodes_agree_on_state_hash() {
+  let new_node = |dir: &std::path::PathBuf| {
+    let net = NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+    Node::new(dir.clone(), &None, None, net).1
+  };
+  let (dir_a, dir_b) = (temp_dir(), temp_dir());
+  let (mut a, mut b) = (new_node(&dir_a.path), new_node(&dir_b.path));
+  assert_eq!(a.state_hash[&ZERO_HASH()], b.state_hash[&ZERO_HASH()]);
+
+  let mut rng = test_rng();
+  let mut prev = ZERO_HASH();
+  for (time, code) in ["ctr {Box x}", "fun (Keep) { (Keep) = #0 } with { {Box #7} }"].into_iter().enumerate() {
+    let block = loop {
+      if let Some(block) = try_mine(prev, code_to_body(code), INITIAL_TARGET(), time as u128 + 1, &[], 1, &mut rng) {
+        break block;
+      }
+    };
+    a.add_block(&block);
+    b.add_block(&block);
+    assert_eq!(a.state_hash[&block.hash], b.state_hash[&block.hash]);
+    assert_ne!(a.state_hash[&block.hash], a.state_hash[&prev]);
+    prev = block.hash;
+  }
+  assert_eq!(a.get_block_info(&prev).unwrap().state_hash.map(|hash| hash.into()), Some(b.state_hash[&prev]));
+}
+
+#[test]
+fn low_disk_space_pauses_taking_blocks() {
+  let mut disk = DiskMonitor::new(100);
+  assert!(!disk.see(Some(200)));
+  assert!(disk.see(Some(50)) && disk.low);
+  assert!(!disk.see(Some(60)));
+  // space that can't be read doesn't stop the node
+  assert!(disk.see(None) && !disk.low);
+  assert!(!DiskMonitor::new(0).see(Some(0)));
+
+  let dir = temp_dir();
+  let net = NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+  let (_, mut node) = Node::new(dir.path.clone(), &None, None, net);
+  let mut rng = test_rng();
+  let block = loop {
+    if let Some(block) = try_mine(ZERO_HASH(), Body { data: vec![0] }, INITIAL_TARGET(), 1, &[], 1, &mut rng) {
+      break block;
+    }
+  };
+  let notice = Message::NoticeTheseBlocks { gossip: false, blocks: vec![block.clone()], peers: vec![] };
+  let peer = read_address("10.0.0.1:42000");
+  node.disk.min_free = u64::MAX;
+  node.check_disk();
+  assert!(node.disk.low && node.disk.free.is_some());
+  node.handle_message(peer, &notice);
+  assert!(!node.block.contains_key(&block.hash));
+  node.disk.min_free = 0;
+  node.check_disk();
+  node.handle_message(peer, &notice);
+  assert_eq!(node.tip, block.hash);
+}
+
+#[test]
+fn header_first_sync() {
+  let (dir_a, dir_b) = (temp_dir(), temp_dir());
+  let net = || NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+  let (_, mut a) = Node::new(dir_a.path.clone(), &None, None, net());
+  let (_, mut b) = Node::new(dir_b.path.clone(), &None, None, net());
+  let clock = ManualClock::new(1_000_000);
+  b.clock = std::sync::Arc::new(clock.clone());
+  let mut rng = test_rng();
+  let mut prev = ZERO_HASH();
+  for height in 1 ..= 100 {
+    let block = loop {
+      if let Some(block) = try_mine(prev, Body { data: vec![0; MAX_BODY_SIZE] }, a.target[&prev], height * TIME_PER_BLOCK, &[], 1, &mut rng) {
+        break block;
+      }
+    };
+    a.add_block(&block);
+    prev = block.hash;
+  }
+  assert_eq!(a.height[&a.tip], 100);
+  assert!(b.is_far_behind(a.block[&a.tip].time));
+
+  // headers are asked from the tip back, until they reach a block `b` has
+  let newest = a.get_headers(a.tip);
+  assert_eq!(newest.len(), HEADERS_PER_MESSAGE);
+  let older = b.add_headers(&newest);
+  assert_eq!(older, Some(newest[HEADERS_PER_MESSAGE - 1].prev));
+  assert!(!b.sync.is_syncing());
+  assert_eq!(b.add_headers(&a.get_headers(older.unwrap())), None);
+  assert_eq!(b.sync.best, Some(a.tip));
+  assert_eq!(b.sync.height[&a.tip], 100);
+  assert_eq!(b.sync.target[&a.tip], a.target[&a.tip]);
+  b.progress.peer_tips.insert(read_address("10.0.0.1:42000"), a.tip);
+  let sync = b.get_sync_summary();
+  assert_eq!((sync.syncing, sync.headers, sync.bodies, sync.executed, sync.peers_best), (true, 100, 0, 0, Some(100)));
+  assert_eq!(sync.eta_secs, None);
+
+  // a restart carries on with them
+  b.save_headers();
+  let (_, mut restarted) = Node::new(dir_b.path.clone(), &None, None, net());
+  restarted.load_headers();
+  assert_eq!(restarted.get_synced_chain(), b.get_synced_chain());
+
+  // then bodies are asked, a window at a time, each request bringing a run of blocks
+  let asked = b.get_body_requests();
+  assert_eq!(asked.len(), SYNC_WINDOW / BODIES_PER_REQUEST);
+  assert!(b.get_body_requests().is_empty());
+  for bhash in asked {
+    for block in a.get_block_chunk(bhash) {
+      b.add_block(&block);
+    }
+  }
+  assert_eq!(b.height[&b.tip], SYNC_WINDOW as u128);
+  b.progress.sample(0, 0);
+  b.progress.sample(4_000, SYNC_WINDOW as u128);
+  let sync = b.get_sync_summary();
+  assert_eq!((sync.executed, sync.blocks_per_sec, sync.eta_secs), (64, Some(16.0), Some(3)));
+  for bhash in b.get_body_requests() {
+    for block in a.get_block_chunk(bhash) {
+      b.add_block(&block);
+    }
+  }
+  assert_eq!(b.tip, a.tip);
+  assert!(b.get_body_requests().is_empty());
+  assert!(!b.sync.is_syncing());
+  assert_eq!(b.get_sync_summary().eta_secs, Some(0));
+
+  // headers that miss their target aren't linked, and a body that can't be had drops the headers
+  let tip = b.block[&b.tip].clone();
+  let missed = BlockHeader { hash: u256(1), prev: tip.hash, time: tip.time + 1, meta: 0 };
+  let claimed = BlockHeader { hash: U256::MAX - 1, prev: tip.hash, time: tip.time + 1, meta: 0 };
+  assert_eq!(b.add_headers(&[missed]), None);
+  assert!(!b.sync.is_syncing());
+  b.add_headers(&[claimed]);
+  assert_eq!(b.sync.best, Some(claimed.hash));
+  for _ in 0 .. SYNC_MAX_ATTEMPTS {
+    assert_eq!(b.get_body_requests(), vec![claimed.hash]);
+    clock.advance(SYNC_REQUEST_TIMEOUT);
+  }
+  assert!(b.get_body_requests().is_empty());
+  assert!(!b.sync.is_syncing());
+}
+
+#[test]
+fn difficulty_follows_block_times() {
+  let dir = temp_dir();
+  let net = NetConfig { listen: vec!["127.0.0.1:0".parse().unwrap()], ..NetConfig::default() };
+  let (_, mut node) = Node::new(dir.path.clone(), &None, None, net);
+  let clock = ManualClock::new(1_000_000);
+  node.clock = std::sync::Arc::new(clock.clone());
+  let mut rng = test_rng();
+  let mut mine = |prev: U256, time: u128| loop {
+    if let Some(block) = try_mine(prev, Body { data: vec![0] }, INITIAL_TARGET(), time, &[], 1, &mut rng) {
+      return block;
+    }
+  };
+
+  // a period of blocks twice as fast as expected doubles the difficulty
+  let mut prev = ZERO_HASH();
+  for _ in 0 .. BLOCKS_PER_PERIOD + 1 {
+    clock.advance(TIME_PER_BLOCK / 2);
+    let block = mine(prev, clock.now());
+    node.add_block(&block);
+    prev = block.hash;
+  }
+  assert_eq!(node.tip, prev);
+  assert_eq!(target_to_difficulty(node.target[&node.block[&prev].prev]), u256(INITIAL_DIFFICULTY));
+  assert_eq!(target_to_difficulty(node.target[&prev]), u256(INITIAL_DIFFICULTY * 2));
+
+  // blocks too far ahead of the node's clock are ignored, until it catches up
+  let early = mine(ZERO_HASH(), clock.now() + DELAY_TOLERANCE);
+  node.add_block(&early);
+  assert!(!node.block.contains_key(&early.hash));
+  clock.advance(1);
+  node.add_block(&early);
+  assert!(node.block.contains_key(&early.hash));
+}
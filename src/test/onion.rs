@@ -0,0 +1,31 @@
+use crate::{
+  bits::{deserialized_address, serialized_address},
+  node::{read_address, Address, AddressFamily},
+  onion::{onion_host, read_onion_host},
+};
+
+// The onion service of torproject.org
+const TORPROJECT : &str = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion";
+
+#[test]
+fn onion_hosts() {
+  let key = read_onion_host(TORPROJECT).unwrap();
+  assert_eq!(onion_host(&key), TORPROJECT);
+  assert_eq!(read_onion_host(&TORPROJECT.to_uppercase().replace(".ONION", ".onion")), Some(key));
+  // a wrong checksum, or a shorter host, isn't an onion address
+  assert_eq!(read_onion_host(&TORPROJECT.replacen('2', "3", 1)), None);
+  assert_eq!(read_onion_host("expyuzz4wqqyqhjn.onion"), None);
+}
+
+#[test]
+fn onion_addresses() {
+  let addr = read_address(&format!("{}:42001", TORPROJECT));
+  assert_eq!(addr.family(), AddressFamily::Onion);
+  assert_eq!(addr.to_string(), format!("{}:42001", TORPROJECT));
+  assert_eq!(addr.to_socket_addr(), None);
+  assert_eq!(read_address(TORPROJECT).port(), 42000);
+  assert_eq!(deserialized_address(&serialized_address(&addr)), Some(addr));
+  // peers without an onion service aren't shared
+  assert!(addr.is_shareable());
+  assert!(!Address::Onion { key: [7; 32], port: 0 }.is_shareable());
+}
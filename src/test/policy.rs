@@ -0,0 +1,38 @@
+use crate::{
+  crypto::Account,
+  hvm::{read_statements, set_sign, sign_hash, Statement},
+  policy::{LocalPolicy, PolicyConfig, StatementPolicy},
+};
+
+fn statement(code: &str) -> Statement {
+  read_statements(code).unwrap().1.remove(0)
+}
+
+#[test]
+fn local_policy_refuses_names_signers_and_large_states() {
+  let banned = Account::from_private_key(&[3; 32]);
+  let config = PolicyConfig {
+    deny_names: vec!["Casino".to_string()],
+    deny_signers: vec![banned.name.show()],
+    max_state_size: Some(4),
+  };
+  let policy = LocalPolicy::new(&config).unwrap();
+  assert!(policy.check(&statement("ctr {Chip}")).is_ok());
+  assert!(policy.check(&statement("fun (Bet x) { (Bet x) = #0 } with { {Pair #1 #2} }")).is_ok());
+  // denied names, along with the names under them, whether defined or called
+  assert!(policy.check(&statement("ctr {Casino}")).is_err());
+  assert!(policy.check(&statement("fun (Casino.Slots x) { (Casino.Slots x) = #0 }")).is_err());
+  assert!(policy.check(&statement("run { (Done (Casino.Play #1)) }")).is_err());
+  assert!(policy.check(&statement("ctr {Casinos}")).is_ok());
+  // states over the limit
+  let large = policy.check(&statement("fun (Big x) { (Big x) = #0 } with { {Pair {Pair #1 #2} #3} }"));
+  assert_eq!(large, Err("The initial state has 5 nodes, but this node takes at most 4.".to_string()));
+  // denied signers
+  let run = statement("run { (Done #1) }");
+  let signed = set_sign(&run, banned.sign(&sign_hash(&run)));
+  assert!(policy.check(&run).is_ok());
+  assert!(policy.check(&signed).is_err());
+
+  assert!(LocalPolicy::new(&PolicyConfig { deny_names: vec!["not a name".to_string()], ..config.clone() }).is_err());
+  assert!(LocalPolicy::new(&PolicyConfig { deny_signers: vec!["alice".to_string()], ..config }).is_err());
+}
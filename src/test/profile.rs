@@ -0,0 +1,39 @@
+use crate::profile::Profile;
+use crate::test::util::temp_dir;
+
+#[test]
+fn profiles_keep_their_files_apart() {
+  let dir = temp_dir();
+  let default = Profile::load(&dir.path, None).unwrap();
+  assert_eq!(default.path, dir.path);
+  assert!(default.config.api.is_empty() && !default.config.testnet);
+
+  let testnet = dir.path.join("profiles").join("testnet");
+  std::fs::create_dir_all(testnet.join("keys")).unwrap();
+  std::fs::write(testnet.join("config.json"), r#"{ "api": "http://127.0.0.1:8001", "testnet": true }"#).unwrap();
+  let profile = Profile::load(&dir.path, Some("testnet".to_string())).unwrap();
+  assert_eq!(profile.path, testnet);
+  assert_eq!(profile.config.api, vec!["http://127.0.0.1:8001".to_string()]);
+  assert!(profile.config.testnet);
+  // keys are found by name, unless the path exists
+  assert_eq!(profile.key_path("alice"), testnet.join("keys").join("alice"));
+  let file = dir.path.join("skey");
+  std::fs::write(&file, "00").unwrap();
+  assert_eq!(profile.key_path(file.to_str().unwrap()), file);
+
+  // or a list of nodes
+  std::fs::write(testnet.join("config.json"), r#"{ "api": ["http://a:8000", "http://b:8000"] }"#).unwrap();
+  let profile = Profile::load(&dir.path, Some("testnet".to_string())).unwrap();
+  assert_eq!(profile.config.api, vec!["http://a:8000".to_string(), "http://b:8000".to_string()]);
+
+  assert_eq!(Profile::list(&dir.path), vec!["testnet".to_string()]);
+  assert!(Profile::load(&dir.path, Some("../main".to_string())).is_err());
+  std::fs::write(testnet.join("config.json"), r#"{ "backup": { "every": 1000, "compress": true } }"#).unwrap();
+  let backup = Profile::load(&dir.path, Some("testnet".to_string())).unwrap().config.backup.unwrap();
+  assert_eq!((backup.every, backup.keep, backup.compress, backup.level, backup.dir), (1000, 3, true, 3, None));
+  std::fs::write(testnet.join("config.json"), r#"{ "policy": { "deny_names": ["Casino"], "max_state_size": 64 } }"#).unwrap();
+  let policy = Profile::load(&dir.path, Some("testnet".to_string())).unwrap().config.policy.unwrap();
+  assert_eq!((policy.deny_names, policy.deny_signers, policy.max_state_size), (vec!["Casino".to_string()], vec![], Some(64)));
+  std::fs::write(testnet.join("config.json"), r#"{ "apu": "typo" }"#).unwrap();
+  assert!(Profile::load(&dir.path, Some("testnet".to_string())).is_err());
+}
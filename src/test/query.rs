@@ -0,0 +1,120 @@
+use crate::{
+  crypto::Account,
+  hvm::{name_to_u128, read_statements, read_term, set_sign, sign_hash, view_term},
+  query::{find_matches, parse_filter, parse_pattern, BlockBloom, CmpOp, Filter, StatementIndex, StatementKind},
+};
+
+fn index(blocks: &[&str]) -> StatementIndex {
+  let mut index = StatementIndex::default();
+  for (height, code) in blocks.iter().enumerate() {
+    let (_, statements) = read_statements(code).unwrap();
+    index.index_block(height as u64 + 1, &statements);
+  }
+  index
+}
+
+fn query(index: &StatementIndex, filter: &str) -> Vec<(u64, usize)> {
+  index.query(&parse_filter(filter).unwrap(), usize::MAX)
+}
+
+const BLOCKS: [&str; 3] = [
+  "ctr {Inc} fun (Count action) { (Count {Inc}) = #1 } with { #0 }",
+  "run { ask (Count {Inc}); (Done #0) } fun (Bank x) { (Bank x) = x } with { #0 }",
+  "run { ask (Bank #1); (Done #0) } run { ask (Count {Inc}); (Done #0) }",
+];
+
+#[test]
+fn and_binds_tighter_than_or() {
+  let kind = |kind| Box::new(Filter::Kind(CmpOp::Eq, kind));
+  let fun = Box::new(Filter::Fun(CmpOp::Eq, name_to_u128("Bank")));
+  let expected = Filter::Or(kind(StatementKind::Ctr), Box::new(Filter::And(kind(StatementKind::Run), fun)));
+  assert_eq!(parse_filter("kind == 'ctr' || kind == 'run' && fun == 'Bank'").unwrap(), expected);
+  assert_eq!(parse_filter("kind=='ctr' or (kind=='run' and fun==\"Bank\")").unwrap(), expected);
+  assert_eq!(parse_filter("height > 10").unwrap(), Filter::Height(CmpOp::Gt, 10));
+}
+
+#[test]
+fn bad_filters_are_rejected() {
+  for filter in ["", "kind", "kind == run", "kind == 'jump'", "height == '1'", "fun < 'Bank'", "size == 1", "(kind == 'run'", "kind == 'run' &&", "name == 'a-b'"] {
+    assert!(parse_filter(filter).is_err(), "accepted {:?}", filter);
+  }
+}
+
+#[test]
+fn queries_use_the_index() {
+  let index = index(&BLOCKS);
+  assert_eq!(index.len(), 6);
+  assert_eq!(query(&index, "kind == 'run'"), vec![(2, 0), (3, 0), (3, 1)]);
+  assert_eq!(query(&index, "kind == 'run' && fun == 'Count'"), vec![(2, 0), (3, 1)]);
+  assert_eq!(query(&index, "fun == 'Count'"), vec![(1, 1), (2, 0), (3, 1)]);
+  assert_eq!(query(&index, "name == 'Bank' || kind == 'ctr'"), vec![(1, 0), (2, 1)]);
+  assert_eq!(query(&index, "fun == 'Bank' && height >= 3"), vec![(3, 0)]);
+  assert_eq!(query(&index, "kind != 'run' && height < 2"), vec![(1, 0), (1, 1)]);
+  assert_eq!(index.query(&parse_filter("kind == 'run'").unwrap(), 2), vec![(2, 0), (3, 0)]);
+}
+
+#[test]
+fn reorgs_replace_indexed_blocks() {
+  let mut index = index(&BLOCKS);
+  index.truncate(3);
+  assert_eq!(query(&index, "kind == 'run'"), vec![(2, 0)]);
+  let (_, statements) = read_statements("run { ask (Bank #2); (Done #0) }").unwrap();
+  index.index_block(2, &statements);
+  assert_eq!(index.len(), 3);
+  assert_eq!(query(&index, "fun == 'Bank'"), vec![(2, 0)]);
+  assert_eq!(query(&index, "fun == 'Count'"), vec![(1, 1)]);
+}
+
+#[test]
+fn statements_are_found_by_signer() {
+  let alice = Account::from_private_key(&[1; 32]);
+  let bob = Account::from_private_key(&[2; 32]);
+  let (_, statements) = read_statements("run { (Done #1) } run { (Done #2) } run { (Done #3) }").unwrap();
+  let signed: Vec<_> = statements.iter().zip([Some(&alice), Some(&bob), None])
+    .map(|(statement, signer)| match signer {
+      Some(signer) => set_sign(statement, signer.sign(&sign_hash(statement))),
+      None => statement.clone(),
+    })
+    .collect();
+  let mut index = StatementIndex::default();
+  index.index_block(1, &signed);
+  assert_eq!(query(&index, &format!("signer == '{}'", alice.name.show())), vec![(1, 0)]);
+  assert_eq!(query(&index, &format!("signer != '{}'", alice.name.show())), vec![(1, 1), (1, 2)]);
+  assert!(parse_filter("signer == 'alice'").is_err());
+}
+
+#[test]
+fn patterns_match_anywhere_in_terms() {
+  let pattern = parse_pattern("{Transfer from to #5}").unwrap();
+  let (_, term) = read_term("{Ok {Transfer #1 {Acc #2} #5} {Transfer #3 #4 #6} {Transfer #7 #8 #5}}").unwrap();
+  let found: Vec<_> = find_matches(&pattern, &term)
+    .into_iter()
+    .map(|(term, binds)| (view_term(&term), binds.iter().map(|(name, term)| format!("{}={}", name, view_term(term))).collect::<Vec<_>>()))
+    .collect();
+  assert_eq!(found, vec![
+    ("{Transfer #1 {Acc #2} #5}".to_string(), vec!["from=#1".to_string(), "to={Acc #2}".to_string()]),
+    ("{Transfer #7 #8 #5}".to_string(), vec!["from=#7".to_string(), "to=#8".to_string()]),
+  ]);
+  // a variable used twice matches equal terms
+  let pattern = parse_pattern("{Pair x x}").unwrap();
+  let (_, term) = read_term("{List {Pair #1 #2} {Pair #3 #3}}").unwrap();
+  assert_eq!(find_matches(&pattern, &term).len(), 1);
+  assert!(parse_pattern("{Pair (Add a b) c}").is_err());
+  assert!(parse_pattern("{Pair a b} extra").is_err());
+}
+
+#[test]
+fn blooms_hold_touched_names_and_emitted_events() {
+  let (_, statements) = read_statements("ctr {Coin_Sent x} fun (Coin_Send x) { (Coin_Send x) = {Coin_Sent x} } run { (Done (Coin_Send #1)) }").unwrap();
+  let emitted = vec![(name_to_u128("Coin_Send"), read_term("{Coin_Sent #1}").unwrap().1)];
+  let bloom = BlockBloom::new(&statements, &emitted);
+  for name in ["Coin_Sent", "Coin_Send", "Done"] {
+    assert!(bloom.names.contains(name_to_u128(name)), "missing {}", name);
+  }
+  assert!(bloom.events.contains(name_to_u128("Coin_Sent")));
+  assert!(!bloom.events.contains(name_to_u128("Coin_Send")));
+  for name in ["Bank", "Token", "Coin"] {
+    assert!(!bloom.names.contains(name_to_u128(name)), "false positive {}", name);
+  }
+  assert_eq!(BlockBloom::new(&[], &[]), BlockBloom::default());
+}
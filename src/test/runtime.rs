@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+use crate::{
+  hvm::{init_runtime, name_to_u128, read_statements, view_term, StatementInfo},
+  node::{code_to_body, new_block, ZERO_HASH},
+  runtime::RuntimeHandle,
+  test::util::temp_dir,
+};
+
+#[test]
+fn runtime_thread_runs_and_rolls_back() {
+  let dir = temp_dir();
+  let runtime = RuntimeHandle::spawn(init_runtime(Some(&dir.path)));
+  let genesis = runtime.get_state_hash();
+  let codes = ["ctr {Box x}", "fun (Keep) { (Keep) = #0 } with { {Box #7} }"];
+  let mut prev = ZERO_HASH();
+  let mut hashes = vec![];
+  for (time, code) in codes.into_iter().enumerate() {
+    let block = new_block(prev, time as u128 + 1, 0, code_to_body(code));
+    let (results, _, hash, _) = runtime.run_block(&block);
+    assert!(results.iter().all(|result| result.is_ok()));
+    hashes.push(hash);
+    prev = block.hash;
+  }
+  assert_eq!(runtime.get_tick(), 2);
+  assert_eq!(runtime.get_state_hash(), hashes[1]);
+  let keep = name_to_u128("Keep");
+  assert_eq!(runtime.read_state(keep).map(|term| view_term(&term)), Some("{Box #7}".to_string()));
+
+  // testing statements leaves the state alone
+  let (_, statements) = read_statements("run { (Done #1) }").unwrap();
+  assert!(runtime.test_statements(statements)[0].is_ok());
+  assert_eq!(runtime.get_state_hash(), hashes[1]);
+
+  // a fork is a copy, advanced on its own
+  assert_eq!(runtime.fork(0).get_tick(), 0);
+  assert_eq!(runtime.rollback(0), 0);
+  assert_eq!(runtime.get_state_hash(), genesis);
+  assert!(runtime.read_state(keep).is_none());
+}
+
+#[test]
+fn state_views_are_snapshots() {
+  let dir = temp_dir();
+  let runtime = RuntimeHandle::spawn(init_runtime(Some(&dir.path)));
+  let reader = runtime.reader();
+  let (count, keep) = (name_to_u128("Count"), name_to_u128("Keep"));
+  let codes = ["fun (Keep) { (Keep) = #0 } with { #7 }", "run { ask (Call 'Count' [{Inc}]); (Done #0) }"];
+  let mut prev = ZERO_HASH();
+  let mut views = vec![reader.view()];
+  for (time, code) in codes.into_iter().enumerate() {
+    let block = new_block(prev, time as u128 + 1, 0, code_to_body(code));
+    runtime.run_block(&block);
+    views.push(reader.view());
+    prev = block.hash;
+  }
+  // a view taken earlier keeps its state, while newer ones follow the blocks
+  assert_eq!(views.iter().map(|view| view.tick).collect::<Vec<_>>(), vec![0, 1, 2]);
+  assert!(views[0].get_state(keep).is_none() && views[0].get_func(keep).is_none());
+  assert_eq!(views[1].get_state(keep).map(|term| view_term(&term)), Some("#7".to_string()));
+  assert!(views[1].get_func(keep).is_some() && views[1].get_functions().contains(&keep));
+  assert_eq!(views[0].get_state(count), views[1].get_state(count));
+  assert_ne!(views[1].get_state(count), views[2].get_state(count));
+  assert_eq!(views[2].get_state(count), runtime.read_state(count));
+
+  // a rollback reads the whole state back
+  runtime.rollback(0);
+  assert_eq!(reader.view().get_state(count), views[0].get_state(count));
+  assert!(reader.view().get_state(keep).is_none());
+}
+
+#[test]
+fn state_views_index_code_by_hash() {
+  let dir = temp_dir();
+  let runtime = RuntimeHandle::spawn(init_runtime(Some(&dir.path)));
+  let reader = runtime.reader();
+  let (keep, hold, drop) = (name_to_u128("Keep"), name_to_u128("Hold"), name_to_u128("Drop"));
+  let code = "
+    fun (Keep n) { (Keep #0) = #0 (Keep n) = (Keep (- n #1)) }
+    fun (Hold n) { (Hold #0) = #0 (Hold n) = (Hold (- n #1)) }
+    fun (Drop n) { (Drop #0) = #1 (Drop n) = (Drop (- n #1)) }
+  ";
+  let block = new_block(ZERO_HASH(), 1, 0, code_to_body(code));
+  runtime.run_block(&block);
+
+  // copies under other names hash the same, even where they call themselves
+  let view = reader.view();
+  let hash = view.get_code_hash(keep).unwrap();
+  assert_eq!(view.get_code_hash(hold), Some(hash));
+  assert_ne!(view.get_code_hash(drop), Some(hash));
+  assert_eq!(view.get_code_names(&hash), vec![hold, keep]);
+
+  runtime.rollback(0);
+  assert!(reader.view().get_code_names(&hash).is_empty());
+}
+
+#[test]
+fn state_views_page_functions_in_name_order() {
+  let dir = temp_dir();
+  let runtime = RuntimeHandle::spawn(init_runtime(Some(&dir.path)));
+  let view = runtime.reader().view();
+  let mut all: Vec<u128> = view.get_functions().into_iter().filter(|name| view.get_func(*name).is_some()).collect();
+  all.sort_unstable();
+  // following the pages visits every function once
+  let mut paged = vec![];
+  let mut after = None;
+  loop {
+    let page = view.get_function_page(after, 7);
+    assert!(page.len() <= 7);
+    if page.is_empty() {
+      break;
+    }
+    after = page.last().copied();
+    paged.extend(page);
+  }
+  assert_eq!(paged, all);
+  assert!(view.get_function_page(all.last().copied(), 7).is_empty());
+}
+
+#[test]
+fn blocks_report_statement_usage() {
+  let dir = temp_dir();
+  let runtime = RuntimeHandle::spawn(init_runtime(Some(&dir.path)));
+  let code = "
+    fun (Spin n) { (Spin #0) = #0 (Spin n) = (Spin (- n #1)) }
+    run { (Done (Spin #100)) }
+  ";
+  let block = new_block(ZERO_HASH(), 1, 0, code_to_body(code));
+  let (results, usage, _, _) = runtime.run_block(&block);
+  assert_eq!(usage.len(), results.len());
+  assert_eq!(usage[0].rwts, 0);
+  assert!(usage[1].rwts > 100);
+  match &results[1] {
+    Ok(StatementInfo::Run { used_mana, size_diff, .. }) => {
+      assert_eq!(usage[1].mana, *used_mana);
+      assert_eq!(usage[1].size, *size_diff);
+    }
+    other => panic!("Unexpected result: {:?}", other),
+  }
+}
+
+#[test]
+fn blocks_over_the_time_budget_are_aborted() {
+  let dir = temp_dir();
+  let runtime = RuntimeHandle::spawn(init_runtime(Some(&dir.path)));
+  let genesis = runtime.get_state_hash();
+  let code = "
+    fun (Spin n) { (Spin #0) = #0 (Spin n) = (Spin (- n #1)) }
+    run { (Done (Spin #100000)) }
+  ";
+  let block = new_block(ZERO_HASH(), 1, 0, code_to_body(code));
+  assert_eq!(runtime.run_block_within(&block, Duration::ZERO).err(), Some(0));
+  assert_eq!(runtime.get_tick(), 0);
+  assert_eq!(runtime.get_state_hash(), genesis);
+  assert!(runtime.read_func(name_to_u128("Spin")).is_none());
+
+  // given time, the same block runs as usual
+  let (results, _, _, _) = runtime.run_block_within(&block, Duration::from_secs(60)).unwrap();
+  assert!(results.iter().all(|result| result.is_ok()));
+  assert_eq!(runtime.get_tick(), 1);
+}
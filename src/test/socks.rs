@@ -0,0 +1,77 @@
+use crate::{
+  bits::serialized_message,
+  node::{udp_bind, udp_recv_with, Address, Message},
+  socks::Socks5Relay,
+  util::bitvec_to_bytes,
+};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+
+// A SOCKS5 proxy that accepts one UDP association, answering `reply` to it, and relaying through
+// `relay`
+fn fake_proxy(reply: u8, relay: SocketAddr) -> SocketAddr {
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap();
+  std::thread::spawn(move || {
+    let (mut stream, _) = listener.accept().unwrap();
+    let mut greeting = [0; 3];
+    stream.read_exact(&mut greeting).unwrap();
+    assert_eq!(greeting, [5, 1, 0]);
+    stream.write_all(&[5, 0]).unwrap();
+    let mut request = [0; 10];
+    stream.read_exact(&mut request).unwrap();
+    assert_eq!(&request[.. 4], &[5, 3, 0, 1]);
+    let mut answer = vec![5, reply, 0, 1];
+    answer.extend_from_slice(&[0, 0, 0, 0]); // on the proxy's own host
+    answer.extend_from_slice(&relay.port().to_be_bytes());
+    stream.write_all(&answer).unwrap();
+    // keeps the association open until the client leaves
+    stream.read_exact(&mut [0; 1]).ok();
+  });
+  return addr;
+}
+
+fn local_udp() -> UdpSocket {
+  udp_bind("127.0.0.1:0".parse().unwrap()).unwrap()
+}
+
+#[test]
+fn socks5_udp_relay() {
+  let relay = local_udp();
+  let proxy = fake_proxy(0, relay.local_addr().unwrap());
+  let mut client = local_udp();
+  let socks = Socks5Relay::associate(proxy, client.local_addr().unwrap()).unwrap();
+  assert_eq!(socks.relay, relay.local_addr().unwrap());
+
+  // outbound datagrams reach the relay, addressed to the peer
+  let peer: SocketAddr = "[2001:db8::1]:42000".parse().unwrap();
+  let message = bitvec_to_bytes(&serialized_message(&Message::GiveMeThatBlock { bhash: 7.into() }));
+  client.send_to(&socks.wrap(peer, &message), socks.relay).unwrap();
+  let mut buffer = [0; 1024];
+  let (len, from) = relay.recv_from(&mut buffer).unwrap();
+  assert_eq!(from, client.local_addr().unwrap());
+  assert_eq!(Socks5Relay::unwrap(&buffer[.. len]), Some((peer, message.as_slice())));
+
+  // inbound datagrams from the relay are unwrapped, and come from the peer
+  relay.send_to(&buffer[.. len], client.local_addr().unwrap()).unwrap();
+  std::thread::sleep(std::time::Duration::from_millis(50));
+  let received = udp_recv_with(&mut client, Some(&socks));
+  assert_eq!(received.len(), 1);
+  assert_eq!(received[0].0, Address::from(peer));
+}
+
+#[test]
+fn socks5_refused() {
+  let relay = local_udp();
+  let proxy = fake_proxy(7, relay.local_addr().unwrap());
+  let client = local_udp();
+  assert!(Socks5Relay::associate(proxy, client.local_addr().unwrap()).is_err());
+}
+
+#[test]
+fn socks5_fragments_dropped() {
+  let mut datagram = vec![0, 0, 1, 1, 127, 0, 0, 1, 0xa4, 0x10];
+  assert!(Socks5Relay::unwrap(&datagram).is_none());
+  datagram[2] = 0;
+  assert_eq!(Socks5Relay::unwrap(&datagram), Some(("127.0.0.1:42000".parse().unwrap(), &[][..])));
+}
@@ -0,0 +1,114 @@
+use crate::{
+  hvm::{init_runtime, read_statements, read_term, u128_to_name, view_term, Runtime, Statement, StatementInfo, STDLIB},
+  test::util::{temp_dir, TempDir},
+};
+use rstest::rstest;
+
+#[rstest]
+#[case("(If #0 #1 #2)", "#2")]
+#[case("(If #5 #1 #2)", "#1")]
+#[case("(MathMin #3 #5)", "#3")]
+#[case("(MathMax #3 #5)", "#5")]
+#[case("(MathDiff #3 #5)", "#2")]
+#[case("(MathDiff #5 #3)", "#2")]
+#[case("(MathPow #3 #0)", "#1")]
+#[case("(MathPow #3 #5)", "#243")]
+#[case("(MathPow #2 #100)", "#1267650600228229401496703205376")]
+#[case("(MathSqrt #0)", "#0")]
+#[case("(MathSqrt #1)", "#1")]
+#[case("(MathSqrt #99)", "#9")]
+#[case("(MathSqrt #100)", "#10")]
+#[case("(MathSqrt (* #4 #4))", "#4")]
+#[case("(MathSqrt #1329227995784915872903807060280344575)", "#1152921504606846975")]
+#[case("(MathLog2 #0)", "#0")]
+#[case("(MathLog2 #1023)", "#9")]
+#[case("(MathLog2 #1024)", "#10")]
+#[case("(MathPowMod #3 #5 #7)", "#5")]
+#[case("(MathPowMod #2 #200 #1000000007)", "#499445072")]
+#[case("(MathPowMod #3 #0 #1)", "#0")]
+#[case("(MathPowMod #3 #5 #0)", "#0")]
+#[case("(MathMulDiv #1000000000000000000000000000000 #1000000000000000000000000000000 #1000000000000000000000000000000)", "#1000000000000000000000000000000")]
+#[case("(MathMulDiv #7 #3 #2)", "#10")]
+#[case("(MathMulDiv #7 #3 #0)", "#0")]
+#[case("(ListLength (ListRange #4))", "#4")]
+#[case("(ListFold @acc @x (+ (* acc #10) x) #1 (ListRange #3))", "#1012")]
+#[case("(ListSum (ListRange #10))", "#45")]
+#[case("(ListFold @acc @x (+ (* acc #10) x) #1 (ListReverse (ListRange #3)))", "#1210")]
+#[case("(ListFold @acc @x (+ (* acc #10) x) #1 (ListConcat (ListRange #2) (ListRange #1)))", "#1010")]
+#[case("(ListGet #2 (ListRange #5))", "{Some #2}")]
+#[case("(ListGet #5 (ListRange #5))", "{None}")]
+#[case("(ListFold @acc @x (+ (* acc #10) x) #1 (ListMap @x (* x #2) (ListRange #3)))", "#1024")]
+#[case("(ListFilter @x (% x #2) (ListRange #5))", "{Cons #1 {Cons #3 {Nil}}}")]
+#[case("(ListFold @acc @x (- acc x) #10 (ListRange #4))", "#4")]
+#[case("(MapGet #2 (MapSet #2 #20 (MapSet #1 #10 {Empty})))", "{Some #20}")]
+#[case("(MapGet #2 (MapSet #2 #30 (MapSet #2 #20 {Empty})))", "{Some #30}")]
+#[case("(MapGet #3 (MapSet #1 #10 {Empty}))", "{None}")]
+#[case("(MapHas #1 (MapSet #1 #10 {Empty}))", "#1")]
+#[case("(MapHas #1 (MapDel #1 (MapSet #2 #20 (MapSet #1 #10 {Empty}))))", "#0")]
+#[case("(MapDel #1 (MapSet #2 #20 (MapSet #1 #10 {Empty})))", "{Entry #2 #20 {Empty}}")]
+#[case("(StrLength 'Kindelia')", "#8")]
+#[case("(== (StrConcat 'Kind' 'elia') 'Kindelia')", "#1")]
+#[case("(== (StrConcat 'Kind' #0) 'Kind')", "#1")]
+#[case("(== (StrFromNum #42) 'x42')", "#0")]
+#[case("(StrLength (StrFromNum #1024))", "#4")]
+#[case("(StrFromNum #0)", "#1")]
+fn stdlib_functions(#[case] expr: &str, #[case] expected: &str, temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let code = format!("run {{ (Done {}) }}", expr);
+  let results = rt.run_statements_from_code(&code, true);
+  match &results[0] {
+    Ok(StatementInfo::Run { done_term, .. }) => assert_eq!(view_term(done_term), expected),
+    other => panic!("{} failed: {:?}", expr, other),
+  }
+}
+
+#[rstest]
+fn stdlib_is_defined_at_genesis(temp_dir: TempDir) {
+  let rt = init_runtime(Some(&temp_dir.path));
+  let (_, statements) = read_statements(STDLIB).unwrap();
+  for statement in statements {
+    match statement {
+      Statement::Fun { name, args, .. } | Statement::Ctr { name, args, .. } => {
+        assert!(name < 1 << 72, "{} is too long to be called directly", u128_to_name(name));
+        assert_eq!(rt.get_arity(name), args.len() as u128);
+      }
+      _ => panic!("The standard library only defines functions and constructors."),
+    }
+  }
+  assert_eq!(rt.get_tick(), 0);
+}
+
+#[rstest]
+fn math_builtins_are_priced_and_need_numbers(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let used_mana = |rt: &mut Runtime, expr: &str| {
+    match &rt.run_statements_from_code(&format!("run {{ (Done {}) }}", expr), true)[0] {
+      Ok(StatementInfo::Run { used_mana, .. }) => *used_mana,
+      other => panic!("{} failed: {:?}", expr, other),
+    }
+  };
+  let base = used_mana(&mut rt, "#0");
+  assert_eq!(used_mana(&mut rt, "(MathSqrt #1000000)") - base, 8);
+  assert_eq!(used_mana(&mut rt, "(MathLog2 #1000000)") - base, 4);
+  assert_eq!(used_mana(&mut rt, "(MathMulDiv #3 #4 #5)") - base, 8);
+  assert_eq!(used_mana(&mut rt, "(MathPowMod #3 #255 #7)") - base, 4 + 4 * 8);
+  assert!(rt.run_statements_from_code("run { (Done (MathSqrt {Nil})) }", true)[0].is_err());
+}
+
+#[rstest]
+fn packed_fields(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let (_, term) = read_term("#[#1:8 #2:16]").unwrap();
+  assert_eq!(view_term(&term), "(PackSet (PackSet #0 #0 #8 #1) #8 #16 #2)");
+  assert!(read_term("#[#1:100 #2:21]").is_err());
+  let code = "run { dup a b = #[#300:8 #7:16 #5:96]; (Done (+ (* (PackGet a #0 #8) #1000) (PackGet b #8 #16))) }";
+  match &rt.run_statements_from_code(code, true)[0] {
+    Ok(StatementInfo::Run { done_term, .. }) => assert_eq!(view_term(done_term), "#44007"),
+    other => panic!("Packing failed: {:?}", other),
+  }
+  let code = "run { (Done (PackGet (PackSet #x1ff #4 #4 #0) #0 #12)) }";
+  match &rt.run_statements_from_code(code, true)[0] {
+    Ok(StatementInfo::Run { done_term, .. }) => assert_eq!(view_term(done_term), "#271"),
+    other => panic!("Packing failed: {:?}", other),
+  }
+}
@@ -3,10 +3,10 @@ use std::{collections::HashMap, fmt::Debug, sync::Arc};
 use crate::{
   crypto,
   hvm::{
-    init_map, name_to_u128, Arits, CompFunc, CompRule, Func, Funcs, Heap, Map, Nodes, Ownrs,
-    Rollback, Rule, Runtime, SerializedHeap, Statement, Store, Term, Var,
+    init_map, name_to_u128, Arits, CompFunc, CompRule, Func, Funcs, Heap, Map, Nodes, Ownrs, Auths, Schds, Hooks, Limits, Frozen, Rotations,
+    Rollback, Rule, Runtime, SerializedHeap, Statement, Store, Term, Var, BSW,
   },
-  node::{hash_bytes, Address, Block, Body, Message, Peer, Transaction},
+  node::{hash_bytes, Address, Block, BlockHeader, Body, Message, Peer, Transaction},
 };
 use primitive_types::U256;
 use proptest::{
@@ -56,7 +56,7 @@ pub fn term() -> impl Strategy<Value = Term> {
           .prop_map(|(n, v)| { Term::Ctr { name: n, args: v } }),
         (fun_name(), vec(inner.clone(), 0..10))
           .prop_map(|(n, v)| { Term::Fun { name: n, args: v } }),
-        (0..15_u128, inner.clone(), inner).prop_map(|(o, v0, v1)| {
+        (0..=BSW, inner.clone(), inner).prop_map(|(o, v0, v1)| {
           Term::Op2 { oper: o, val0: Box::new(v0), val1: Box::new(v1) }
         }),
       ]
@@ -85,19 +85,28 @@ pub fn sign() -> impl Strategy<Value = crypto::Signature> {
 // generate statements
 pub fn statement() -> impl Strategy<Value = Statement> {
   prop_oneof![
-    (fun_name(), vec(name(), 0..10), func(), term(), option::of(sign())).prop_map(
-      |(name, args, func, init, sign)| { Statement::Fun { name, args, func, init, sign } }
+    (fun_name(), vec(name(), 0..10), func(), term(), option::of(any::<u128>()), any::<u16>(), any::<bool>(), vec((fun_name(), u256()), 0..3), option::of(sign())).prop_map(
+      |(name, args, func, init, mana, mask, pure, uses, sign)| {
+        let strict = (0 .. args.len() as u128).filter(|i| mask >> i & 1 == 1).collect();
+        Statement::Fun { name, args, func, init, mana, strict, pure, uses, sign }
+      }
     ),
     (fun_name(), vec(name(), 0..10), option::of(sign()))
       .prop_map(|(name, args, sign)| { Statement::Ctr { name, args, sign } }),
-    (term(), option::of(sign())).prop_map(|(t, s)| { Statement::Run { expr: t, sign: s } }),
+    (term(), option::of(any::<u128>()), option::of(sign())).prop_map(|(t, p, s)| { Statement::Run { expr: t, price: p, sign: s } }),
     (name(), name(), option::of(sign()))
       .prop_map(|(name, ownr, sign)| { Statement::Reg { name, ownr, sign } }),
+    (name(), name(), any::<u128>(), option::of(sign()))
+      .prop_map(|(name, succ, delay, sign)| { Statement::Rot { name, succ, delay, sign } }),
   ]
 }
 
 pub fn nodes() -> impl Strategy<Value = Nodes> {
-  (map(any::<u128>())).prop_map(|m| Nodes { nodes: m })
+  (map(any::<u128>())).prop_map(|m| Nodes {
+    nodes: m,
+    #[cfg(feature = "mmap")]
+    mapped: None,
+  })
 }
 
 pub fn map<A: std::fmt::Debug>(s: impl Strategy<Value = A>) -> impl Strategy<Value = Map<A>> {
@@ -132,13 +141,13 @@ pub fn var() -> impl Strategy<Value = Var> {
 }
 
 pub fn comp_rule() -> impl Strategy<Value = CompRule> {
-  (vec(any::<u128>(), 0..32), vec(var(), 0..32), vec((any::<u128>(), any::<u128>()), 0..32), term())
-    .prop_map(|(c, v, e, b)| CompRule { cond: c, vars: v, eras: e, body: b })
+  (vec(any::<u128>(), 0..32), vec(var(), 0..32), vec((any::<u128>(), any::<u128>()), 0..32), term(), any::<u128>())
+    .prop_map(|(c, v, e, b, m)| CompRule { cond: c, vars: v, eras: e, body: b, mana: m })
 }
 
 pub fn comp_func() -> impl Strategy<Value = CompFunc> {
   (func(), any::<u128>(), vec(any::<u128>(), 0..32), vec(comp_rule(), 0..32))
-    .prop_map(|(f, a, r, s)| CompFunc { func: f, arity: a, redux: r, rules: s })
+    .prop_map(|(f, a, r, s)| CompFunc { func: f, arity: a, redux: r, rules: s, table: None, strict: vec![], pure: false })
 }
 
 pub fn funcs() -> impl Strategy<Value = Funcs> {
@@ -149,12 +158,14 @@ pub fn heap() -> impl Strategy<Value = Heap> {
   let tuple_strategy =
     (any::<u128>(), any::<u128>(), any::<u128>(), any::<u128>(), any::<u128>(), any::<u128>());
 
-  (tuple_strategy, tuple_strategy, any::<i128>(), nodes(), store(), arits(), ownrs(), funcs())
+  (tuple_strategy, tuple_strategy, any::<i128>(), any::<u128>(), (any::<u128>(), any::<u128>()), nodes(), store(), arits(), ownrs(), funcs())
     .prop_map(
       |(
         (uuid, mcap, tick, funs, dups, rwts),
         (mana, next, meta, hax1, hax0, time),
         size,
+        rand,
+        (pric, burn),
         memo,
         disk,
         arit,
@@ -165,6 +176,12 @@ pub fn heap() -> impl Strategy<Value = Heap> {
         disk,
         arit,
         ownr,
+        auth: Auths { auths: init_map() },
+        schd: Schds { schds: init_map() },
+        hook: Hooks { hooks: init_map() },
+        lmit: Limits { limits: init_map() },
+        frzn: Frozen { frozen: init_map() },
+        rotn: Rotations { rotations: init_map() },
         file: Funcs { funcs: init_map() }, // TODO, fix?
         uuid,
         memo,
@@ -178,7 +195,11 @@ pub fn heap() -> impl Strategy<Value = Heap> {
         meta,
         hax0,
         hax1,
+        rand,
+        pric,
+        burn,
         time,
+        base: vec![],
       },
     )
 }
@@ -197,8 +218,12 @@ pub fn block() -> impl Strategy<Value = Block> {
 }
 
 pub fn address() -> impl Strategy<Value = Address> {
-  (any::<u8>(), any::<u8>(), any::<u8>(), any::<u8>(), any::<u16>())
-    .prop_map(|(a, b, c, d, e)| Address::IPv4 { val0: a, val1: b, val2: c, val3: d, port: e })
+  prop_oneof![
+    (any::<u8>(), any::<u8>(), any::<u8>(), any::<u8>(), any::<u16>())
+      .prop_map(|(a, b, c, d, e)| Address::IPv4 { val0: a, val1: b, val2: c, val3: d, port: e }),
+    (any::<[u16; 8]>(), any::<u16>()).prop_map(|(segs, port)| Address::IPv6 { segs, port }),
+    (any::<[u8; 32]>(), any::<u16>()).prop_map(|(key, port)| Address::Onion { key, port }),
+  ]
 }
 
 pub fn peer() -> impl Strategy<Value = Peer> {
@@ -209,11 +234,17 @@ pub fn transaction() -> impl Strategy<Value = Transaction> {
   vec(any::<u8>(), 1..128).prop_map(|d| Transaction::new(d))
 }
 
+pub fn header() -> impl Strategy<Value = BlockHeader> {
+  (u256(), u256(), any::<u128>(), any::<u128>()).prop_map(|(hash, prev, time, meta)| BlockHeader { hash, prev, time, meta })
+}
+
 pub fn message() -> impl Strategy<Value = Message> {
   prop_oneof![
     (any::<bool>(), vec(block(), 0..10), vec(peer(), 0..10))
       .prop_map(|(g, b, p)| Message::NoticeTheseBlocks { gossip: g, blocks: b, peers: p }),
     (u256()).prop_map(|h| Message::GiveMeThatBlock { bhash: h }),
-    (transaction()).prop_map(|t| Message::PleaseMineThisTransaction { trans: t })
+    (transaction()).prop_map(|t| Message::PleaseMineThisTransaction { trans: t }),
+    (u256()).prop_map(|h| Message::GiveMeTheseHeaders { bhash: h }),
+    vec(header(), 0..10).prop_map(|h| Message::NoticeTheseHeaders { headers: h }),
   ]
 }
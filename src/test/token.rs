@@ -0,0 +1,141 @@
+use crate::{
+  crypto::Account,
+  hvm::{init_runtime, read_statements, set_sign, sign_hash, Runtime, StatementInfo, Term},
+  test::util::{temp_dir, TempDir},
+};
+use rstest::rstest;
+use rstest_reuse::{apply, template};
+
+// Conformance tests of the token standard (see the standard library). To check another token,
+// add a case with its name and the code that deploys it.
+
+const TOKEN : &str = include_str!("../../example/token.kdl");
+
+#[template]
+#[rstest]
+#[case("Token", TOKEN)]
+fn tokens(#[case] name: &str, #[case] code: &str) {}
+
+const MAX_AMOUNT : u128 = (1 << 120) - 1;
+
+fn alice() -> Account {
+  Account::from_private_key(&[2; 32])
+}
+
+fn bob() -> Account {
+  Account::from_private_key(&[3; 32])
+}
+
+// A deployed token
+struct Token {
+  rt: Runtime,
+  name: &'static str,
+}
+
+impl Token {
+  fn deploy(name: &'static str, code: &str, temp_dir: &TempDir) -> Token {
+    let mut rt = init_runtime(Some(&temp_dir.path));
+    for result in rt.run_statements_from_code(code, true) {
+      result.expect("The token failed to deploy.");
+    }
+    Token { rt, name }
+  }
+
+  // Calls the token with an action, as an account if given, and answers what it returned. Each
+  // call runs in a block of its own, as the state can only grow so much per block.
+  fn call(&mut self, account: Option<&Account>, action: &str) -> u128 {
+    let code = format!("run {{ ask x = (Call '{}' [{}]); (Done x) }}", self.name, action);
+    let (_, statements) = read_statements(&code).unwrap();
+    let mut statement = statements[0].clone();
+    if let Some(account) = account {
+      statement = set_sign(&statement, account.sign(&sign_hash(&statement)));
+    }
+    let result = self.rt.run_statement(&statement, true);
+    self.rt.tick();
+    match result {
+      Ok(StatementInfo::Run { done_term: Term::Num { numb }, .. }) => numb,
+      other => panic!("{} failed: {:?}", action, other),
+    }
+  }
+
+  fn mint(&mut self, account: &Account, amount: u128) -> u128 {
+    self.call(Some(account), &format!("{{TokenMint #{}}}", amount))
+  }
+
+  fn send(&mut self, account: &Account, to: &Account, amount: u128) -> u128 {
+    self.call(Some(account), &format!("{{TokenSend #x{:0>30x} #{}}}", to.name.0, amount))
+  }
+
+  fn balance(&mut self, of: &Account) -> u128 {
+    self.call(None, &format!("{{TokenBalance #x{:0>30x}}}", of.name.0))
+  }
+
+  fn supply(&mut self) -> u128 {
+    self.call(None, "{TokenSupply}")
+  }
+}
+
+#[apply(tokens)]
+fn token_starts_empty(name: &'static str, code: &str, temp_dir: TempDir) {
+  let mut token = Token::deploy(name, code, &temp_dir);
+  assert_eq!(token.supply(), 0);
+  assert_eq!(token.balance(&alice()), 0);
+}
+
+#[apply(tokens)]
+fn token_mints_to_the_minter(name: &'static str, code: &str, temp_dir: TempDir) {
+  let mut token = Token::deploy(name, code, &temp_dir);
+  assert_eq!(token.mint(&alice(), 100), 1);
+  assert_eq!(token.mint(&alice(), 50), 1);
+  assert_eq!(token.balance(&alice()), 150);
+  assert_eq!(token.supply(), 150);
+}
+
+#[apply(tokens)]
+fn token_mints_only_to_the_minter(name: &'static str, code: &str, temp_dir: TempDir) {
+  let mut token = Token::deploy(name, code, &temp_dir);
+  assert_eq!(token.mint(&alice(), 100), 1);
+  assert_eq!(token.mint(&bob(), 100), 0);
+  assert_eq!(token.balance(&bob()), 0);
+  assert_eq!(token.supply(), 100);
+}
+
+#[apply(tokens)]
+fn token_supply_does_not_overflow(name: &'static str, code: &str, temp_dir: TempDir) {
+  let mut token = Token::deploy(name, code, &temp_dir);
+  assert_eq!(token.mint(&alice(), MAX_AMOUNT), 1);
+  assert_eq!(token.mint(&alice(), 1), 0);
+  assert_eq!(token.balance(&alice()), MAX_AMOUNT);
+  assert_eq!(token.supply(), MAX_AMOUNT);
+}
+
+#[apply(tokens)]
+fn token_sends(name: &'static str, code: &str, temp_dir: TempDir) {
+  let mut token = Token::deploy(name, code, &temp_dir);
+  token.mint(&alice(), 100);
+  assert_eq!(token.send(&alice(), &bob(), 30), 1);
+  assert_eq!(token.send(&bob(), &alice(), 10), 1);
+  assert_eq!(token.balance(&alice()), 80);
+  assert_eq!(token.balance(&bob()), 20);
+  assert_eq!(token.supply(), 100);
+}
+
+#[apply(tokens)]
+fn token_sends_only_what_it_has(name: &'static str, code: &str, temp_dir: TempDir) {
+  let mut token = Token::deploy(name, code, &temp_dir);
+  token.mint(&alice(), 100);
+  assert_eq!(token.send(&alice(), &bob(), 101), 0);
+  assert_eq!(token.send(&bob(), &alice(), 1), 0);
+  assert_eq!(token.balance(&alice()), 100);
+  assert_eq!(token.balance(&bob()), 0);
+}
+
+#[apply(tokens)]
+fn token_sends_to_self_and_nothing(name: &'static str, code: &str, temp_dir: TempDir) {
+  let mut token = Token::deploy(name, code, &temp_dir);
+  token.mint(&alice(), 100);
+  assert_eq!(token.send(&alice(), &alice(), 100), 1);
+  assert_eq!(token.send(&bob(), &alice(), 0), 1);
+  assert_eq!(token.balance(&alice()), 100);
+  assert_eq!(token.balance(&bob()), 0);
+}
@@ -0,0 +1,82 @@
+use crate::{
+  hvm::{init_runtime, name_to_u128, state_size, StatementInfo, Term},
+  node::{block_meta, extract_extra_data},
+  test::{hvm::PRE_COUNTER, util::{temp_dir, TempDir}},
+  upgrade::{read_activation, signal_extra_data, signals, Activations, Feature, SIGNAL_TAG},
+};
+use rstest::rstest;
+
+#[test]
+fn activations_and_signals() {
+  let mut activations = Activations::default();
+  assert!(activations.is_mainnet());
+  assert_eq!(read_activation("state-size-limit=10"), Ok((Feature::StateSizeLimit, 10)));
+  assert!(read_activation("state-size-limit").is_err());
+  assert!(read_activation("warp-drive=10").is_err());
+  activations.set(Feature::StateSizeLimit, 10);
+  assert!(!activations.is_mainnet());
+  assert!(!activations.is_active(Feature::StateSizeLimit, 9));
+  assert!(activations.is_active(Feature::StateSizeLimit, 10));
+
+  // signals go first in the extra data, followed by the miner's tag
+  let extra = signal_extra_data(&[Feature::StateSizeLimit], b"pool").unwrap();
+  assert_eq!(extra, vec![SIGNAL_TAG, 1, b'p', b'o', b'o', b'l']);
+  let read = extract_extra_data(block_meta(&extra, 7)).unwrap();
+  assert!(signals(&read, Feature::StateSizeLimit));
+  assert!(!signals(b"pool", Feature::StateSizeLimit));
+  assert!(!signals(&signal_extra_data(&[], b"").unwrap(), Feature::StateSizeLimit));
+  assert!(signal_extra_data(&[Feature::StateSizeLimit], b"kindel1").is_err());
+}
+
+#[rstest]
+fn state_size_limit_applies_from_its_height(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  rt.run_statements_from_code(PRE_COUNTER, true);
+  let store = name_to_u128("Store");
+  let add = "run { ask (Call 'Store' [{StoreAdd}]); (Done #0) }";
+  let initial = state_size(&rt.read_disk_as_term(store).unwrap());
+  rt.set_state_limit(initial);
+  let height = rt.get_tick() + 4;
+  let mut activations = Activations::default();
+  activations.set(Feature::StateSizeLimit, height);
+  rt.set_activations(activations);
+  // blocks before the height save states over the limit, and the ones from it don't
+  loop {
+    rt.tick();
+    let result = rt.run_statements_from_code(add, true).pop().unwrap();
+    assert_eq!(result.is_ok(), rt.get_tick() + 1 < height);
+    if result.is_err() {
+      break;
+    }
+  }
+}
+
+#[rstest]
+fn effect_results_apply_from_their_height(temp_dir: TempDir) {
+  let mut rt = init_runtime(Some(&temp_dir.path));
+  let code = "
+    ctr {Whoami_Get}
+    ctr {Asker_Get}
+    fun (Whoami action) {
+      (Whoami {Whoami_Get}) = ask from = (From); (Done from)
+    }
+    fun (Asker action) {
+      (Asker {Asker_Get}) = ask from = (Call 'Whoami' [{Whoami_Get}]); (Done from)
+    }
+  ";
+  assert!(rt.run_statements_from_code(code, true).iter().all(|r| r.is_ok()));
+  let height = rt.get_tick() + 3;
+  let mut activations = Activations::default();
+  activations.set(Feature::EffectResults, height);
+  rt.set_activations(activations);
+  // FROM passes the subject before the height, and the caller from it
+  let ask = "run { ask x = (Call 'Asker' [{Asker_Get}]); (Done x) }";
+  for _ in 0 .. 4 {
+    rt.tick();
+    let expected = if rt.get_tick() + 1 < height { "Whoami" } else { "Asker" };
+    match rt.run_statements_from_code(ask, true).pop().unwrap() {
+      Ok(StatementInfo::Run { done_term, .. }) => assert_eq!(done_term, Term::Num { numb: name_to_u128(expected) }),
+      other => panic!("Unexpected result: {:?}", other),
+    }
+  }
+}
@@ -1,6 +1,8 @@
+use rand::SeedableRng;
 use rstest::fixture;
 
-use crate::hvm::{init_runtime, name_to_u128, show_term, Rollback, Runtime, U128_NONE};
+use crate::hvm::{diff_terms, init_runtime, name_to_u128, show_term, view_term_change, Rollback, Runtime, Term, U128_NONE};
+use crate::node::NodeRng;
 use std::{
   collections::{hash_map::DefaultHasher, HashMap},
   hash::{Hash, Hasher},
@@ -23,6 +25,15 @@ pub fn are_all_elemenets_equal<E: PartialEq>(vec: &[E]) -> bool {
   true
 }
 
+// Asserts two terms are equal, showing where they differ instead of both whole
+pub fn assert_terms_eq(found: &Term, expected: &Term) {
+  let changes = diff_terms(expected, found);
+  if !changes.is_empty() {
+    let changes: Vec<_> = changes.iter().map(|change| view_term_change(expected, change)).collect();
+    panic!("terms differ from the expected:\n{}", changes.join("\n"));
+  }
+}
+
 pub fn view_rollback_ticks(rt: &Runtime) -> String {
   fn view_rollback_ticks_go(rt: &Runtime, back: &Arc<Rollback>) -> Vec<Option<u128>> {
     match &**back {
@@ -237,3 +248,11 @@ pub fn temp_dir() -> TempDir {
   println!("Temp dir: {:?}", temp_dir.path);
   temp_dir
 }
+
+// Random generator for tests, from `KINDELIA_TEST_SEED` or a fresh seed.
+// The seed is printed, so a failing run can be reproduced.
+pub fn test_rng() -> NodeRng {
+  let seed = std::env::var("KINDELIA_TEST_SEED").ok().and_then(|seed| seed.parse().ok()).unwrap_or_else(rand::random);
+  println!("Test seed: {} (rerun with KINDELIA_TEST_SEED={})", seed, seed);
+  NodeRng::seed_from_u64(seed)
+}
@@ -0,0 +1,41 @@
+use crate::{
+  crypto::Account,
+  test::util::temp_dir,
+  wallet::{read_account, Wallet},
+};
+
+#[test]
+fn accounts_are_read_as_subjects_addresses_or_public_keys() {
+  let account = Account::from_private_key(&[7; 32]);
+  let subject = account.name.0;
+  assert_eq!(read_account(&account.name.show()), Ok(subject));
+  assert_eq!(read_account(&account.address.show()), Ok(subject));
+  assert_eq!(read_account(&hex::encode(account.public_key.serialize())), Ok(subject));
+  assert_eq!(read_account(&format!("0x{}", hex::encode(account.public_key.serialize_uncompressed()))), Ok(subject));
+  for bad in ["", "alice", "#x", "0x1234", "#x1000000000000000000000000000000"] {
+    assert!(read_account(bad).is_err(), "accepted {:?}", bad);
+  }
+}
+
+#[test]
+fn wallets_watch_accounts_by_label() {
+  let dir = temp_dir();
+  let mut wallet = Wallet::load(&dir.path).unwrap();
+  assert!(wallet.watched.is_empty());
+  wallet.watch("cold".to_string(), 0x1234).unwrap();
+  wallet.watch("other".to_string(), 0x5678).unwrap();
+  assert!(wallet.watch("again".to_string(), 0x1234).is_err());
+  assert!(wallet.watch("cold".to_string(), 0x9abc).is_err());
+  wallet.save(&dir.path).unwrap();
+
+  let mut wallet = Wallet::load(&dir.path).unwrap();
+  assert_eq!(wallet.watched.len(), 2);
+  assert_eq!(wallet.find("cold").map(|watched| watched.subject), Some(0x1234));
+  assert_eq!(wallet.find("#x5678").map(|watched| watched.label.as_str()), Some("other"));
+  assert_eq!(wallet.unwatch("cold").unwrap().subject, 0x1234);
+  assert!(wallet.unwatch("cold").is_err());
+  assert_eq!(wallet.watched.len(), 1);
+
+  std::fs::write(dir.path.join("wallet.json"), r#"{ "watched": [{ "label": "x", "subject": "alice" }] }"#).unwrap();
+  assert!(Wallet::load(&dir.path).is_err());
+}
@@ -0,0 +1,131 @@
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use primitive_types::U256;
+use tokio::sync::broadcast;
+use warp::Filter as _;
+
+use crate::{
+  api::{NodeEvent, NodeRequest, StatementEntry},
+  hvm::read_statements,
+  query::{CmpOp, Filter, StatementKind},
+  test::util::temp_dir,
+  webhook::{read_webhooks, sign_body, start, Webhook},
+};
+
+// (event header, signature header, body) of each request
+type Received = Arc<Mutex<Vec<(String, Option<String>, String)>>>;
+
+// Serves webhooks on a local port, failing the first request
+fn receiver() -> (String, Received) {
+  let received: Received = Arc::default();
+  let log = received.clone();
+  let route = warp::post()
+    .and(warp::header::<String>("x-kindelia-event"))
+    .and(warp::header::optional::<String>("x-kindelia-signature"))
+    .and(warp::body::bytes())
+    .map(move |event: String, signature: Option<String>, body: warp::hyper::body::Bytes| {
+      let mut log = log.lock().unwrap();
+      log.push((event, signature, String::from_utf8(body.to_vec()).unwrap()));
+      let status = if log.len() == 1 { 500 } else { 200 };
+      warp::reply::with_status("", warp::http::StatusCode::from_u16(status).unwrap())
+    });
+  let (addr_tx, addr_rx) = mpsc::channel();
+  std::thread::spawn(move || {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    runtime.block_on(async move {
+      let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+      addr_tx.send(addr).unwrap();
+      server.await;
+    });
+  });
+  (format!("http://{}/hook", addr_rx.recv().unwrap()), received)
+}
+
+// Answers the requests of the webhooks as a node at height 1 would, recording the statement filters
+fn node(filters: Arc<Mutex<Vec<Filter>>>) -> mpsc::SyncSender<NodeRequest> {
+  let (node_query_tx, requests) = mpsc::sync_channel(16);
+  std::thread::spawn(move || {
+    for request in requests {
+      match request {
+        NodeRequest::GetBlocks { tx, .. } => tx.send(vec![]).unwrap(),
+        NodeRequest::GetStatements { filter, tx, .. } => {
+          filters.lock().unwrap().push(filter.unwrap());
+          let (_, statements) = read_statements("run { (Done #0) }").unwrap();
+          let entry = StatementEntry { height: 2, block: U256::from(2).into(), position: 0, hash: U256::from(3).into(), statement: statements[0].clone() };
+          tx.send(vec![entry]).unwrap();
+        }
+        _ => panic!("unexpected request"),
+      }
+    }
+  });
+  node_query_tx
+}
+
+fn wait_for(received: &Received, count: usize) {
+  let start = Instant::now();
+  while received.lock().unwrap().len() < count {
+    assert!(start.elapsed() < Duration::from_secs(10), "webhook not posted");
+    std::thread::sleep(Duration::from_millis(10));
+  }
+}
+
+#[test]
+fn reads_webhooks_file() {
+  let dir = temp_dir();
+  std::fs::create_dir_all(&dir.path).unwrap();
+  let path = dir.path.join("webhooks.json");
+  let write = |text: &str| std::fs::write(&path, text).unwrap();
+
+  write(r#"[{ "url": "http://a", "blocks": true, "secret": "s" }, { "url": "http://b", "statements": "fun == 'Bank'" }]"#);
+  let webhooks = read_webhooks(&path).unwrap();
+  assert_eq!(webhooks.len(), 2);
+  assert!(webhooks[0].blocks && !webhooks[0].reorgs && webhooks[0].statements.is_none());
+  assert_eq!(webhooks[0].secret.as_deref(), Some("s"));
+  assert!(matches!(webhooks[1].statements, Some(Filter::Fun(CmpOp::Eq, _))));
+
+  for bad in [r#"[{ "url": "http://a" }]"#, r#"[{ "url": "http://a", "statements": "fun ==" }]"#, r#"[{ "url": "http://a", "block": true }]"#] {
+    write(bad);
+    assert!(read_webhooks(&path).is_err(), "accepted {}", bad);
+  }
+}
+
+#[test]
+fn signs_with_hmac_sha256() {
+  // RFC 4231, test case 2
+  let signature = sign_body("Jefe", b"what do ya want for nothing?");
+  assert_eq!(signature, "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+}
+
+#[test]
+fn posts_events_and_retries() {
+  let (url, received) = receiver();
+  let filters = Arc::default();
+  let webhook = |blocks, statements| Webhook { url: url.clone(), blocks, reorgs: false, statements, secret: Some("key".to_string()) };
+  let webhooks = vec![webhook(true, None), webhook(false, Some(Filter::Kind(CmpOp::Eq, StatementKind::Run)))];
+  let events = broadcast::channel(16).0;
+  start(webhooks, node(Arc::clone(&filters)), events.clone());
+  let start = Instant::now();
+  while events.receiver_count() == 0 {
+    assert!(start.elapsed() < Duration::from_secs(10));
+    std::thread::sleep(Duration::from_millis(10));
+  }
+
+  let tip = NodeEvent::Tip { hash: U256::from(2).into(), height: 2, state_hash: U256::from(9).into() };
+  events.send(Arc::new(tip)).unwrap();
+  wait_for(&received, 3);
+  let received = received.lock().unwrap();
+  let events: Vec<&str> = received.iter().map(|(event, _, _)| event.as_str()).collect();
+  assert!(events.contains(&"Tip") && events.contains(&"Statements"));
+  // the first request was failed, and posted again after a second
+  let retries: Vec<_> = received.iter().filter(|request| **request == received[0]).collect();
+  assert_eq!(retries.len(), 2);
+  for (_, signature, body) in received.iter() {
+    assert_eq!(signature.as_deref(), Some(sign_body("key", body.as_bytes()).as_str()));
+  }
+
+  // statements are looked up over the heights the tip added
+  let filters = filters.lock().unwrap();
+  let expected = Filter::And(Box::new(Filter::Height(CmpOp::Gt, 0)), Box::new(Filter::Height(CmpOp::Le, 2)));
+  assert!(matches!(&filters[0], Filter::And(heights, _) if **heights == expected));
+}
@@ -0,0 +1,126 @@
+// Upgrades
+// ========
+
+// Consensus rule changes ship in releases ahead of time, each gated by a feature that activates
+// at a block height. Blocks before it follow the old rules, so upgraded nodes keep agreeing with
+// the rest until then, and the network switches to the new rules all at once, without a flag-day
+// restart. The features are listed in `UPGRADES`, with their heights on mainnet. Devnets and
+// testnets can move them with `--activate <feature>=<height>`, e.g. to try an upgrade early.
+//
+// The framework covers the rule changes made from its introduction on. The ones made before it,
+// in the same release, aren't features, and apply from genesis. Among them are mana refunds for
+// freed memory, the SCHD, HOOK and EMIT effects, and the heap compaction every COMPACT_INTERVAL
+// ticks. Networks that ran the rules before them have to restart from a new genesis. The
+// features listed here apply from genesis on mainnet too, and are gated so testnets can try them
+// apart.
+//
+// Miners signal they're ready for features with the extra data of their blocks (see
+// `node::EXTRA_DATA_TAG`): `SIGNAL_TAG`, then a byte with the bits of the features, then their
+// own tag, if any. `GET /upgrades` counts the signals of the last `SIGNAL_WINDOW` blocks, so
+// releases can pick the heights of features once most of the network runs them.
+
+use serde::Serialize;
+
+use crate::node::MAX_EXTRA_DATA;
+
+// First byte of the extra data of blocks that signal features
+pub const SIGNAL_TAG : u8 = 0x01;
+
+// Blocks the signals are counted over
+pub const SIGNAL_WINDOW : usize = 1000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+pub enum Feature {
+  StateSizeLimit,
+  ManaMarket,
+  EffectResults,
+}
+
+// A consensus rule change, and when it activates
+#[derive(Debug)]
+pub struct Upgrade {
+  pub feature: Feature,
+  pub name: &'static str,
+  pub about: &'static str,
+  pub bit: u8,      // bit of the signal byte miners set when ready for it
+  pub height: u128, // first block it applies to, on mainnet
+}
+
+// Every feature, indexed by `Feature as usize`
+pub const UPGRADES : &[Upgrade] = &[
+  Upgrade { feature: Feature::StateSizeLimit, name: "state-size-limit", about: "Saved states must fit the state size limit", bit: 0, height: 0 },
+  Upgrade { feature: Feature::ManaMarket, name: "mana-market", about: "Runs must bid the base mana price, which follows block usage, and burn it", bit: 1, height: 0 },
+  Upgrade { feature: Feature::EffectResults, name: "effect-results", about: "FROM passes the caller, block info effects pass their value and keep the caller, CALL computes its name, and erased dups read back as `~`", bit: 2, height: 0 },
+];
+
+// Heights the features activate at
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Activations {
+  heights: [u128; UPGRADES.len()],
+}
+
+impl Default for Activations {
+  // The mainnet heights
+  fn default() -> Self {
+    let mut heights = [0; UPGRADES.len()];
+    for (i, upgrade) in UPGRADES.iter().enumerate() {
+      heights[i] = upgrade.height;
+    }
+    Activations { heights }
+  }
+}
+
+impl Activations {
+  pub fn set(&mut self, feature: Feature, height: u128) {
+    self.heights[feature as usize] = height;
+  }
+
+  pub fn get(&self, feature: Feature) -> u128 {
+    self.heights[feature as usize]
+  }
+
+  // Whether the rules of a feature apply to the block at a height
+  pub fn is_active(&self, feature: Feature, height: u128) -> bool {
+    height >= self.get(feature)
+  }
+
+  // Whether these are the mainnet heights
+  pub fn is_mainnet(&self) -> bool {
+    *self == Activations::default()
+  }
+}
+
+pub fn read_feature(name: &str) -> Result<Feature, String> {
+  match UPGRADES.iter().find(|upgrade| upgrade.name == name) {
+    Some(upgrade) => Ok(upgrade.feature),
+    None => Err(format!("Unknown feature: `{}`.", name)),
+  }
+}
+
+// Reads a `<feature>=<height>` activation
+pub fn read_activation(text: &str) -> Result<(Feature, u128), String> {
+  let Some((name, height)) = text.split_once('=') else {
+    return Err(format!("Invalid activation: `{}`. Expected `<feature>=<height>`.", text));
+  };
+  let height = height.parse().map_err(|_| format!("Invalid activation height: `{}`.", height))?;
+  Ok((read_feature(name)?, height))
+}
+
+// The extra data of blocks signaling features, followed by the miner's own tag
+pub fn signal_extra_data(features: &[Feature], tag: &[u8]) -> Result<Vec<u8>, String> {
+  let bits = features.iter().fold(0, |bits, feature| bits | 1 << UPGRADES[*feature as usize].bit);
+  let mut extra = vec![SIGNAL_TAG, bits];
+  extra.extend_from_slice(tag);
+  if extra.len() > MAX_EXTRA_DATA {
+    return Err(format!("Extra data takes {} bytes with the signals, over the limit of {}.", extra.len(), MAX_EXTRA_DATA));
+  }
+  Ok(extra)
+}
+
+// Whether the extra data of a block signals a feature
+pub fn signals(extra: &[u8], feature: Feature) -> bool {
+  match extra {
+    [SIGNAL_TAG, bits, ..] => bits & 1 << UPGRADES[feature as usize].bit != 0,
+    _ => false,
+  }
+}
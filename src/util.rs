@@ -91,7 +91,7 @@ pub fn u128s_to_u8s(u128s: &[u128]) -> Vec<u8> {
 
 pub fn u8s_to_u128s(u8s: &[u8]) -> Vec<u128> {
   let mut u8s = u8s.to_vec();
-  u8s.resize((u8s.len() + 15) / 16 * 16, 0);
+  u8s.resize(u8s.len().div_ceil(16) * 16, 0);
   let mut u128s : Vec<u128> = vec![];
   for i in 0 .. u8s.len() / 16 {
     u128s.push(u128::from_le_bytes(u8s[i * 16 .. i * 16 + 16].try_into().unwrap()));
@@ -143,11 +143,52 @@ pub fn u256map_from<T, const N: usize>(a: [(U256, T); N]) -> U256Map<T> {
 
 /// Gets current timestamp in milliseconds
 pub fn get_time() -> u128 {
-  return std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u128;
+  return std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis();
+}
+
+/// Source of the current time, in milliseconds. The node reads time through it, so that tests can
+/// move time by hand with a `ManualClock`.
+pub trait Clock: Send + Sync {
+  fn now(&self) -> u128;
+}
+
+/// The system's wall clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> u128 {
+    get_time()
+  }
+}
+
+/// A clock that only moves when told to. Clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock {
+  time: std::sync::Arc<std::sync::Mutex<u128>>,
+}
+
+impl ManualClock {
+  pub fn new(time: u128) -> Self {
+    ManualClock { time: std::sync::Arc::new(std::sync::Mutex::new(time)) }
+  }
+
+  pub fn set(&self, time: u128) {
+    *self.time.lock().unwrap() = time;
+  }
+
+  pub fn advance(&self, millis: u128) {
+    *self.time.lock().unwrap() += millis;
+  }
+}
+
+impl Clock for ManualClock {
+  fn now(&self) -> u128 {
+    *self.time.lock().unwrap()
+  }
 }
 
 pub fn get_time_micro() -> u128 {
-  return std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_micros() as u128;
+  return std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_micros();
 }
 
 #[macro_export] macro_rules! print_with_timestamp {
@@ -0,0 +1,147 @@
+// Wallet
+// ======
+
+// A watch-only wallet keeps track of accounts whose secret keys are elsewhere, e.g. on an offline
+// machine. It's kept in the profile, as `wallet.json`:
+//
+//   { "watched": [ { "label": "cold", "subject": "#x..." } ] }
+//
+// `kindelia wallet status` asks a node what the watched accounts have waiting on its pool and
+// included in the chain. `kindelia wallet prepare` serializes statements unsigned, with the digest
+// each signature is made over, so they can be carried to the offline machine, signed there with
+// `kindelia sign`, and brought back to be posted with `kindelia post`.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::api::client::{ApiClient, PoolView, StatementView};
+use crate::crypto;
+use crate::query;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Wallet {
+  #[serde(default)]
+  pub watched: Vec<Watched>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Watched {
+  pub label: String,
+  #[serde(serialize_with = "show_subject", deserialize_with = "read_subject")]
+  pub subject: u128,
+}
+
+fn show_subject<S: Serializer>(subject: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+  serializer.serialize_str(&crypto::Name(*subject).show())
+}
+
+fn read_subject<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+  let text = String::deserialize(deserializer)?;
+  crypto::Name::read(&text).map(|name| name.0).ok_or_else(|| serde::de::Error::custom(format!("invalid subject: '{}'", text)))
+}
+
+// Reads an account given as a subject (`#x...`), an address (`0x` and 40 hex digits) or a public
+// key (33 or 65 bytes, in hex), returning its subject
+pub fn read_account(text: &str) -> Result<u128, String> {
+  if let Some(name) = crypto::Name::read(text) {
+    return Ok(name.0);
+  }
+  let digits = text.strip_prefix("0x").unwrap_or(text);
+  if digits.len() == 40 {
+    if let Some(addr) = crypto::Address::from_hex(digits) {
+      return Ok(crypto::Name::from_address(&addr).0);
+    }
+  }
+  if let Some(pubk) = hex::decode(digits).ok().and_then(|bytes| secp256k1::PublicKey::from_slice(&bytes).ok()) {
+    return Ok(crypto::Name::from_public_key(&pubk).0);
+  }
+  Err(format!("Invalid account: '{}'. Expected a subject (#x...), an address (0x...) or a public key, in hex.", text))
+}
+
+impl Wallet {
+  fn file(path: &Path) -> PathBuf {
+    path.join("wallet.json")
+  }
+
+  // Reads the wallet of a profile, which is empty until something is watched
+  pub fn load(path: &Path) -> Result<Wallet, String> {
+    let file = Wallet::file(path);
+    match std::fs::read_to_string(&file) {
+      Ok(text) => serde_json::from_str(&text).map_err(|err| format!("Invalid wallet {:?}: {}", file, err)),
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Wallet::default()),
+      Err(err) => Err(format!("Couldn't read wallet {:?}: {}", file, err)),
+    }
+  }
+
+  pub fn save(&self, path: &Path) -> Result<(), String> {
+    let file = Wallet::file(path);
+    let text = serde_json::to_string_pretty(self).expect("wallet is serializable");
+    std::fs::create_dir_all(path).and_then(|_| std::fs::write(&file, text + "\n")).map_err(|err| format!("Couldn't write wallet {:?}: {}", file, err))
+  }
+
+  // Finds a watched account by its label or subject
+  pub fn find(&self, account: &str) -> Option<&Watched> {
+    let subject = crypto::Name::read(account).map(|name| name.0);
+    self.watched.iter().find(|watched| watched.label == account || Some(watched.subject) == subject)
+  }
+
+  pub fn watch(&mut self, label: String, subject: u128) -> Result<&Watched, String> {
+    if let Some(watched) = self.watched.iter().find(|watched| watched.subject == subject) {
+      return Err(format!("{} is already watched, as '{}'.", crypto::Name(subject).show(), watched.label));
+    }
+    if self.watched.iter().any(|watched| watched.label == label) {
+      return Err(format!("The label '{}' is taken.", label));
+    }
+    self.watched.push(Watched { label, subject });
+    Ok(self.watched.last().unwrap())
+  }
+
+  pub fn unwatch(&mut self, account: &str) -> Result<Watched, String> {
+    let watched = self.find(account).ok_or_else(|| format!("'{}' isn't watched.", account))?.clone();
+    self.watched.retain(|other| *other != watched);
+    Ok(watched)
+  }
+}
+
+// What a node knows of a watched account
+pub struct AccountStatus {
+  pub pending: Vec<PoolView>,       // statements it signed waiting on the pool
+  pub included: Vec<StatementView>, // statements it signed on the longest chain, oldest first
+}
+
+impl AccountStatus {
+  pub fn fetch(client: &ApiClient, subject: u128) -> Result<AccountStatus, String> {
+    let subject = crypto::Name(subject).show();
+    let pending = client.get_pool(Some(&subject))?.unwrap_or_default();
+    // Read a page at a time. A page that's full may end in the middle of a block, so that block is
+    // read again with the next one.
+    let mut included = vec![];
+    let mut after = 0;
+    loop {
+      let filter = format!("signer == '{}' && height > {}", subject, after);
+      let page = client.get_statements(&filter, query::MAX_QUERY_LIMIT)?.unwrap_or_default();
+      let last = match page.last() {
+        Some(last) if page.len() == query::MAX_QUERY_LIMIT && last.height > after + 1 => last.height,
+        _ => {
+          included.extend(page);
+          return Ok(AccountStatus { pending, included });
+        }
+      };
+      included.extend(page.into_iter().filter(|entry| entry.height < last));
+      after = last - 1;
+    }
+  }
+}
+
+// Describes a statement, as read back from the API, by its kind and name, e.g. `fun Foo`
+pub fn describe(statement: &serde_json::Value) -> String {
+  let Some((kind, fields)) = statement.as_object().and_then(|variant| variant.iter().next()) else {
+    return "?".to_string();
+  };
+  match fields.get("name").and_then(|name| name.as_str()) {
+    Some(name) => format!("{} {}", kind.to_lowercase(), name),
+    None => kind.to_lowercase(),
+  }
+}
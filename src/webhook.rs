@@ -0,0 +1,213 @@
+// Webhooks
+// ========
+
+// The node can POST to URLs when the chain changes, for integrations that can't hold the `/events`
+// WebSocket open. Webhooks are listed in a JSON file, given with `--webhooks`:
+//
+//   [
+//     { "url": "https://example.com/chain", "blocks": true, "reorgs": true, "secret": "..." },
+//     { "url": "https://example.com/bank", "statements": "fun == 'Bank'" }
+//   ]
+//
+// - `blocks`: posts each new tip, as the `Tip` event of `/events`
+// - `reorgs`: posts each reorg, as the `Reorg` event
+// - `statements`: posts the statements that reach the longest chain matching this filter (see
+//   `query.rs`), as a `Statements` event
+// - `secret`: signs each body with HMAC-SHA256, sent as `X-Kindelia-Signature: sha256=<hex>`
+//
+// Each URL gets its deliveries in order. Failed ones (errors, or answers other than 2xx) are
+// retried with growing delays, and dropped after the last attempt. A URL that falls too far behind
+// misses deliveries, which is logged.
+
+use std::path::Path;
+use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::api::{ask, Hash, NodeEvent, NodeRequest, StatementEntry};
+use crate::query::{self, CmpOp, Filter};
+
+// Deliveries waiting per URL
+pub const WEBHOOK_QUEUE : usize = 256;
+
+// Times a delivery is tried, waiting twice as long after each failure
+pub const WEBHOOK_ATTEMPTS : u32 = 5;
+const FIRST_RETRY : Duration = Duration::from_secs(1);
+
+const WEBHOOK_TIMEOUT : Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebhookConfig {
+  url: String,
+  #[serde(default)]
+  blocks: bool,
+  #[serde(default)]
+  reorgs: bool,
+  statements: Option<String>,
+  secret: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Webhook {
+  pub url: String,
+  pub blocks: bool,
+  pub reorgs: bool,
+  pub statements: Option<Filter>,
+  pub secret: Option<String>,
+}
+
+// Posted to `statements` webhooks
+#[derive(Debug, Serialize)]
+#[serde(tag = "event")]
+enum WebhookEvent {
+  Statements {
+    tip: Hash,
+    height: u64,
+    statements: Vec<StatementEntry>, // oldest first, up to `MAX_QUERY_LIMIT`
+  },
+}
+
+// A body to post, and the event it holds
+struct Delivery {
+  event: &'static str,
+  body: Arc<Vec<u8>>,
+}
+
+pub fn read_webhooks(path: &Path) -> Result<Vec<Webhook>, String> {
+  let text = std::fs::read_to_string(path).map_err(|err| format!("Couldn't read webhooks file {:?}: {}", path, err))?;
+  let configs: Vec<WebhookConfig> = serde_json::from_str(&text).map_err(|err| format!("Invalid webhooks file {:?}: {}", path, err))?;
+  let mut webhooks = vec![];
+  for config in configs {
+    let statements = config.statements.as_deref().map(query::parse_filter).transpose();
+    let statements = statements.map_err(|err| format!("Invalid statement filter of webhook {}: {}", config.url, err))?;
+    if !config.blocks && !config.reorgs && statements.is_none() {
+      return Err(format!("Webhook {} has nothing to post: set `blocks`, `reorgs` or `statements`.", config.url));
+    }
+    webhooks.push(Webhook { url: config.url, blocks: config.blocks, reorgs: config.reorgs, statements, secret: config.secret });
+  }
+  Ok(webhooks)
+}
+
+// Value of the `X-Kindelia-Signature` header of a body
+pub fn sign_body(secret: &str, body: &[u8]) -> String {
+  let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+  mac.update(body);
+  format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+// Posts to the webhooks from a thread of their own, as the node's events come
+pub fn start(webhooks: Vec<Webhook>, node_query_tx: SyncSender<NodeRequest>, events: broadcast::Sender<Arc<NodeEvent>>) {
+  let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("Couldn't start the webhooks runtime.");
+  std::thread::Builder::new()
+    .name("webhooks".to_string())
+    .spawn(move || runtime.block_on(serve(webhooks, node_query_tx, events)))
+    .expect("Couldn't start the webhooks thread.");
+}
+
+async fn serve(webhooks: Vec<Webhook>, node_query_tx: SyncSender<NodeRequest>, events: broadcast::Sender<Arc<NodeEvent>>) {
+  let client = reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build().expect("Couldn't build the webhooks client.");
+  let queues: Vec<_> = webhooks.iter().map(|webhook| {
+    let (queue, deliveries) = mpsc::channel(WEBHOOK_QUEUE);
+    tokio::spawn(deliver(client.clone(), webhook.clone(), deliveries));
+    queue
+  }).collect();
+
+  // The node answers once it has loaded its chain, so the blocks loaded from disk aren't posted
+  let tip = ask(node_query_tx.clone(), |tx| NodeRequest::GetBlocks { range: (-1, -1), tx }).await;
+  let mut seen = tip.first().map(|block| block.height).unwrap_or(0); // statements up to this height were posted
+  let mut events = events.subscribe();
+
+  loop {
+    let event = match events.recv().await {
+      Ok(event) => event,
+      Err(broadcast::error::RecvError::Lagged(missed)) => {
+        eprintln!("Webhooks missed {} node events.", missed);
+        continue;
+      }
+      Err(broadcast::error::RecvError::Closed) => return,
+    };
+    // Terms are only sent to subscribers of `/events`, as matches of their patterns
+    if let NodeEvent::Terms { .. } = &*event {
+      continue;
+    }
+    let body = Arc::new(serde_json::to_vec(&*event).unwrap());
+    for (webhook, queue) in webhooks.iter().zip(&queues) {
+      let posted = match &*event {
+        NodeEvent::Tip { .. } if webhook.blocks => Some("Tip"),
+        NodeEvent::Reorg { .. } if webhook.reorgs => Some("Reorg"),
+        _ => None,
+      };
+      if let Some(name) = posted {
+        enqueue(webhook, queue, Delivery { event: name, body: body.clone() });
+      }
+    }
+    match &*event {
+      // Statements of the dropped blocks won't come back, but those that replace them will
+      NodeEvent::Reorg { fork_height, .. } => {
+        seen = seen.min(*fork_height);
+      }
+      NodeEvent::Tip { hash, height, .. } => {
+        let new = Filter::And(Box::new(Filter::Height(CmpOp::Gt, seen)), Box::new(Filter::Height(CmpOp::Le, *height)));
+        for (webhook, queue) in webhooks.iter().zip(&queues) {
+          let Some(statements) = &webhook.statements else { continue };
+          let filter = Filter::And(Box::new(new.clone()), Box::new(statements.clone()));
+          let limit = query::MAX_QUERY_LIMIT;
+          let statements = ask(node_query_tx.clone(), |tx| NodeRequest::GetStatements { filter: Some(filter.clone()), limit, tx }).await;
+          if !statements.is_empty() {
+            let event = WebhookEvent::Statements { tip: hash.clone(), height: *height, statements };
+            let body = Arc::new(serde_json::to_vec(&event).unwrap());
+            enqueue(webhook, queue, Delivery { event: "Statements", body });
+          }
+        }
+        seen = *height;
+      }
+      NodeEvent::ReplayDivergence { .. } | NodeEvent::ShadowDivergence { .. } | NodeEvent::BlockTimeout { .. } | NodeEvent::Terms { .. } => {}
+    }
+  }
+}
+
+fn enqueue(webhook: &Webhook, queue: &mpsc::Sender<Delivery>, delivery: Delivery) {
+  if queue.try_send(delivery).is_err() {
+    eprintln!("Webhook {} is too far behind, dropping a delivery.", webhook.url);
+  }
+}
+
+async fn deliver(client: reqwest::Client, webhook: Webhook, mut deliveries: mpsc::Receiver<Delivery>) {
+  while let Some(delivery) = deliveries.recv().await {
+    let mut delay = FIRST_RETRY;
+    for attempt in 1 ..= WEBHOOK_ATTEMPTS {
+      match post(&client, &webhook, &delivery).await {
+        Ok(()) => break,
+        Err(err) if attempt == WEBHOOK_ATTEMPTS => {
+          eprintln!("Webhook {} failed {} times, dropping a delivery: {}", webhook.url, attempt, err);
+        }
+        Err(_) => {
+          tokio::time::sleep(delay).await;
+          delay *= 2;
+        }
+      }
+    }
+  }
+}
+
+async fn post(client: &reqwest::Client, webhook: &Webhook, delivery: &Delivery) -> Result<(), String> {
+  let mut request = client
+    .post(&webhook.url)
+    .header("Content-Type", "application/json")
+    .header("X-Kindelia-Event", delivery.event)
+    .body((*delivery.body).clone());
+  if let Some(secret) = &webhook.secret {
+    request = request.header("X-Kindelia-Signature", sign_body(secret, &delivery.body));
+  }
+  let response = request.send().await.map_err(|err| err.to_string())?;
+  if !response.status().is_success() {
+    return Err(format!("answered {}", response.status()));
+  }
+  Ok(())
+}